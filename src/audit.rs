@@ -0,0 +1,218 @@
+//! the audit trail, `[audit]`: the events logged under the `axonmq::audit` target are also
+//! kept as JSON lines in daily files, `retention_days` of them, and published on `topic`
+//!
+//! the events are client connects, refused CONNECTs and TLS handshakes, disconnects, denied
+//! subscriptions, session takeovers, RESTful API calls changing something and TLS
+//! certificate reloads
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{self, LevelFilter};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::CONFIG;
+use crate::config::AuditConfig;
+use crate::mqtt::QoS;
+use crate::mqtt::protocol::publish::PublishOptions;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::utils::time::now_milliseconds;
+
+pub const TARGET: &str = "axonmq::audit";
+
+const DEFAULT_RETENTION_DAYS: usize = 90;
+const QUEUE_SIZE: usize = 4096;
+// the client identifier of the events published on `topic`
+const PUBLISHER: &str = "axonmq-audit";
+
+static EVENTS: OnceLock<mpsc::Sender<Value>> = OnceLock::new();
+// events lost because the audit task did not keep up
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+struct AuditLayer;
+
+impl<S> Layer<S> for AuditLayer
+where
+    S: Subscriber,
+{
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let Some(events) = EVENTS.get() else {
+            return;
+        };
+        let mut fields = Fields::default();
+        fields
+            .0
+            .insert("timestamp".to_string(), Value::from(now_milliseconds()));
+        fields.0.insert(
+            "level".to_string(),
+            Value::from(event.metadata().level().as_str()),
+        );
+        event.record(&mut fields);
+        if events.try_send(Value::Object(fields.0)).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// hands the `axonmq::audit` events to the audit task, whatever the log filter lets through
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    AuditLayer.with_filter(
+        filter::filter_fn(|meta| meta.target() == TARGET).with_max_level_hint(LevelFilter::INFO),
+    )
+}
+
+fn appender(config: &AuditConfig) -> anyhow::Result<Option<RollingFileAppender>> {
+    let Some(dir) = &config.dir else {
+        return Ok(None);
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("audit")
+        .filename_suffix("log");
+    // one file a day
+    let retention_days = config.retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+    if retention_days > 0 {
+        builder = builder.max_log_files(retention_days);
+    }
+    Ok(Some(builder.build(dir)?))
+}
+
+/// takes the events of the audit layer until the server stops
+pub(crate) async fn run(operator_helper: OperatorHelper) {
+    let config = CONFIG.get().unwrap();
+    let Some(audit) = config.audit.as_ref() else {
+        return;
+    };
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+    if EVENTS.set(tx).is_err() {
+        return;
+    }
+
+    let (mut file, _guard) = match appender(audit) {
+        Ok(Some(appender)) => {
+            // audit lines wait for the writer instead of being dropped
+            let (writer, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+                .lossy(false)
+                .finish(appender);
+            (Some(writer), Some(guard))
+        }
+        Ok(None) => (None, None),
+        Err(e) => {
+            warn!(
+                "failed to open the audit directory: {}, audit events are only published",
+                e
+            );
+            (None, None)
+        }
+    };
+
+    while let Some(mut event) = rx.recv().await {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "{} audit events were dropped, the audit queue was full",
+                dropped
+            );
+        }
+        if let Value::Object(fields) = &mut event {
+            fields.insert("node".to_string(), Value::from(config.node.id.clone()));
+        }
+        let line = event.to_string();
+        if let Some(file) = file.as_mut()
+            && let Err(e) = writeln!(file, "{}", line)
+        {
+            warn!("failed to write an audit event: {}", e);
+        }
+        if let Some(topic) = &audit.topic {
+            let _ = operator_helper
+                .publish(
+                    PUBLISHER.to_string(),
+                    None,
+                    false,
+                    QoS::AtLeastOnce,
+                    topic.clone(),
+                    Bytes::from(line),
+                    vec![],
+                    PublishOptions::default(),
+                )
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::Registry;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn test_layer() {
+        let (tx, mut rx) = mpsc::channel(8);
+        EVENTS.set(tx).unwrap();
+        let subscriber = Registry::default().with(layer());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                target: "axonmq::audit",
+                event = "client_connect",
+                client_id = "c1",
+                code = 0u64,
+                accepted = true,
+                address = %"127.0.0.1:5000",
+                "connected"
+            );
+            // not an audit event
+            tracing::info!(target: "axonmq::mqtt", "connected");
+        });
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event["event"], "client_connect");
+        assert_eq!(event["client_id"], "c1");
+        assert_eq!(event["code"], 0);
+        assert_eq!(event["accepted"], true);
+        assert_eq!(event["address"], "127.0.0.1:5000");
+        assert_eq!(event["message"], "connected");
+        assert_eq!(event["level"], "INFO");
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::operator::sink::{Sink, error::SinkError};
+use crate::processor::message::Message;
+
+/// faults injected into the packets clients send
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenerFaults {
+    pub latency_ms: u64,
+    // random extra latency, up to this many milliseconds
+    pub jitter_ms: u64,
+    // probability a packet is dropped before the broker handles it
+    pub drop_rate: f64,
+    // probability the connection is closed instead of handling a packet
+    pub disconnect_rate: f64,
+    // only clients whose id starts with this prefix are affected, all clients when not set
+    pub client_id_prefix: Option<String>,
+}
+
+/// faults injected into the deliveries of processor chains to their sinks
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SinkFaults {
+    pub latency_ms: u64,
+    pub jitter_ms: u64,
+    pub drop_rate: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChaosConfig {
+    pub listener: ListenerFaults,
+    pub sink: SinkFaults,
+}
+
+impl ChaosConfig {
+    fn validate(&self) -> Result<(), String> {
+        let rates = [
+            ("listener.drop_rate", self.listener.drop_rate),
+            ("listener.disconnect_rate", self.listener.disconnect_rate),
+            ("sink.drop_rate", self.sink.drop_rate),
+        ];
+        for (name, rate) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    delayed: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> FaultStats {
+        FaultStats {
+            delayed: self.delayed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FaultStats {
+    pub delayed: u64,
+    pub dropped: u64,
+    pub disconnected: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChaosStats {
+    pub listener: FaultStats,
+    pub sink: FaultStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChaosInfo {
+    #[serde(flatten)]
+    pub config: ChaosConfig,
+    // faults injected since the process started
+    pub stats: ChaosStats,
+}
+
+/// what to do with a packet a client sent
+pub enum Fault {
+    Pass,
+    Drop,
+    Disconnect,
+}
+
+static CHAOS: LazyLock<RwLock<ChaosConfig>> = LazyLock::new(Default::default);
+static LISTENER: LazyLock<Counters> = LazyLock::new(Default::default);
+static SINK: LazyLock<Counters> = LazyLock::new(Default::default);
+
+pub fn info() -> ChaosInfo {
+    ChaosInfo {
+        config: CHAOS.read().unwrap().clone(),
+        stats: ChaosStats {
+            listener: LISTENER.stats(),
+            sink: SINK.stats(),
+        },
+    }
+}
+
+pub fn set(config: ChaosConfig) -> Result<ChaosInfo, String> {
+    config.validate()?;
+    tracing::warn!("chaos faults set: {:?}", config);
+    *CHAOS.write().unwrap() = config;
+    Ok(info())
+}
+
+fn delay(latency_ms: u64, jitter_ms: u64) -> Option<Duration> {
+    if latency_ms == 0 && jitter_ms == 0 {
+        return None;
+    }
+    Some(Duration::from_millis(
+        latency_ms + rand::random_range(0..=jitter_ms),
+    ))
+}
+
+fn happens(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+/// called for every packet a client sends, waits for the injected latency
+pub async fn listener(client_id: &str) -> Fault {
+    let (delay, fault) = {
+        let config = CHAOS.read().unwrap();
+        let faults = &config.listener;
+        if faults
+            .client_id_prefix
+            .as_ref()
+            .is_some_and(|prefix| !client_id.starts_with(prefix.as_str()))
+        {
+            return Fault::Pass;
+        }
+
+        let fault = if happens(faults.disconnect_rate) {
+            Fault::Disconnect
+        } else if happens(faults.drop_rate) {
+            Fault::Drop
+        } else {
+            Fault::Pass
+        };
+        (delay(faults.latency_ms, faults.jitter_ms), fault)
+    };
+
+    match fault {
+        Fault::Disconnect => {
+            LISTENER.disconnected.fetch_add(1, Ordering::Relaxed);
+        }
+        Fault::Drop => {
+            LISTENER.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        Fault::Pass => {
+            if let Some(delay) = delay {
+                LISTENER.delayed.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+    fault
+}
+
+/// hand a message to a chain sink after the injected latency, a dropped delivery fails
+pub async fn deliver(sink: &dyn Sink, message: Message) -> Result<(), SinkError> {
+    let (delay, drop) = {
+        let faults = &CHAOS.read().unwrap().sink;
+        (
+            delay(faults.latency_ms, faults.jitter_ms),
+            happens(faults.drop_rate),
+        )
+    };
+
+    if drop {
+        SINK.dropped.fetch_add(1, Ordering::Relaxed);
+        return Err(SinkError::ChaosDropped);
+    }
+    if let Some(delay) = delay {
+        SINK.delayed.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(delay).await;
+    }
+    sink.deliver(message, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = ChaosConfig::default();
+        assert!(config.validate().is_ok());
+        config.listener.disconnect_rate = 1.0;
+        assert!(config.validate().is_ok());
+        config.sink.drop_rate = 1.5;
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "sink.drop_rate must be between 0 and 1"
+        );
+
+        let config: ChaosConfig =
+            serde_json::from_str(r#"{"listener": {"latency_ms": 5}}"#).unwrap();
+        assert_eq!(config.listener.latency_ms, 5);
+        assert!(config.listener.client_id_prefix.is_none());
+        assert!(delay(0, 0).is_none());
+        assert!(!happens(0.0) && happens(1.0));
+    }
+}
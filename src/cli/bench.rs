@@ -0,0 +1,350 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rumqttc::Outgoing;
+use rumqttc::v5::mqttbytes::v5::{Packet, SubscribeReasonCode};
+use rumqttc::v5::mqttbytes::{QoS, qos};
+use rumqttc::v5::{AsyncClient, Event, EventLoop};
+use serde::Serialize;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::commands::{Bench, MqttArgs};
+use crate::mqtt::{connect, disconnect, random_client_id};
+
+// how long the subscribers keep receiving after the publishers are done
+const DRAIN: Duration = Duration::from_secs(2);
+// how long a publisher waits for the acknowledgements of its last messages
+const ACK_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Counters {
+    // PUBLISH packets written to the connection
+    sent: AtomicU64,
+    // QoS 1 and 2 messages sent and acknowledged
+    confirmable: AtomicU64,
+    acked: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// a connection which got its CONNACK
+async fn connected(args: &MqttArgs, client_id: String) -> Result<(AsyncClient, EventLoop)> {
+    let (client, mut eventloop) = connect(args, client_id.clone())?;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => return Ok((client, eventloop)),
+            Ok(_) => {}
+            Err(e) => anyhow::bail!("{} failed to connect: {}", client_id, e),
+        }
+    }
+}
+
+/// polls the connection of a publisher until it is closed
+async fn drive_publisher(mut eventloop: EventLoop, counters: Arc<Counters>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Publish(_))) => {
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Event::Incoming(Packet::PubAck(_))) | Ok(Event::Incoming(Packet::PubComp(_))) => {
+                counters.acked.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(_) => {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+struct Publisher {
+    index: usize,
+    client: AsyncClient,
+    topics: Arc<Vec<String>>,
+    qos: Arc<Vec<QoS>>,
+    size: usize,
+    // time between two messages, none to publish as fast as possible
+    period: Option<Duration>,
+}
+
+impl Publisher {
+    async fn run(self, end: Instant, counters: Arc<Counters>) {
+        let mut ticker = self.period.map(|period| {
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+            ticker
+        });
+        let mut count = 0usize;
+        while Instant::now() < end {
+            if let Some(ticker) = ticker.as_mut() {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = tokio::time::sleep_until(end) => break,
+                }
+            }
+            let topic = &self.topics[(self.index + count) % self.topics.len()];
+            let qos = self.qos[count % self.qos.len()];
+            let mut payload = vec![0u8; self.size];
+            payload[..8].copy_from_slice(&now_micros().to_be_bytes());
+            if self
+                .client
+                .publish(topic.clone(), qos, false, payload)
+                .await
+                .is_err()
+            {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            if qos != QoS::AtMostOnce {
+                counters.confirmable.fetch_add(1, Ordering::Relaxed);
+            }
+            count += 1;
+        }
+    }
+}
+
+/// receives until `end`, the latencies are in microseconds
+async fn subscribe(
+    client: AsyncClient,
+    mut eventloop: EventLoop,
+    end: Instant,
+) -> Result<Vec<u64>> {
+    let mut latencies = vec![];
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event?,
+            _ = tokio::time::sleep_until(end) => break,
+        };
+        if let Event::Incoming(Packet::Publish(publish)) = event
+            && let Some(sent) = publish.payload.get(..8)
+        {
+            let sent = u64::from_be_bytes(sent.try_into().unwrap());
+            latencies.push(now_micros().saturating_sub(sent));
+        }
+    }
+    disconnect(&client, &mut eventloop).await?;
+    Ok(latencies)
+}
+
+/// waits for the SUBACK of the subscription to `filter`
+async fn subscribed(
+    client: &AsyncClient,
+    eventloop: &mut EventLoop,
+    filter: &str,
+    qos: QoS,
+) -> Result<()> {
+    client.subscribe(filter, qos).await?;
+    loop {
+        if let Event::Incoming(Packet::SubAck(ack)) = eventloop.poll().await? {
+            return match ack.return_codes.first() {
+                Some(SubscribeReasonCode::Success(_)) => Ok(()),
+                code => anyhow::bail!("Subscription to {} refused: {:?}", filter, code),
+            };
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Latency {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    p999: f64,
+    max: f64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    publishers: usize,
+    subscribers: usize,
+    size: u32,
+    qos: Vec<u8>,
+    topics: usize,
+    duration_secs: f64,
+    sent: u64,
+    sent_per_sec: f64,
+    confirmable: u64,
+    acked: u64,
+    received: u64,
+    // every subscriber receives every message
+    expected: u64,
+    received_per_sec: f64,
+    errors: u64,
+    latency_ms: Latency,
+}
+
+impl Report {
+    fn print(&self) {
+        println!(
+            "{} publishers, {} subscribers, {} bytes, QoS {:?}, {} topics, {:.1} s",
+            self.publishers, self.subscribers, self.size, self.qos, self.topics, self.duration_secs
+        );
+        println!("sent       {} ({:.1} msg/s)", self.sent, self.sent_per_sec);
+        println!("acked      {} of {} QoS 1/2", self.acked, self.confirmable);
+        println!(
+            "received   {} of {} ({:.1} msg/s)",
+            self.received, self.expected, self.received_per_sec
+        );
+        let latency = &self.latency_ms;
+        println!(
+            "latency ms p50 {:.3}, p90 {:.3}, p99 {:.3}, p99.9 {:.3}, max {:.3}",
+            latency.p50, latency.p90, latency.p99, latency.p999, latency.max
+        );
+        println!("errors     {}", self.errors);
+    }
+}
+
+// the latency under which `p` of the messages arrived, in milliseconds
+fn percentile(sorted: &[u64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index] as f64 / 1000.0
+}
+
+pub async fn handle_bench(args: Bench) -> Result<()> {
+    if args.publishers == 0 || args.topics == 0 {
+        anyhow::bail!("At least one publisher and one topic are needed");
+    }
+    if !(args.rate >= 0.0 && args.rate.is_finite()) {
+        anyhow::bail!("Invalid rate: {}", args.rate);
+    }
+    let qos_mix = args
+        .qos
+        .iter()
+        .map(|q| qos(*q).ok_or_else(|| anyhow::anyhow!("Invalid QoS: {}", q)))
+        .collect::<Result<Vec<_>>>()?;
+    let max_qos = qos_mix
+        .iter()
+        .copied()
+        .fold(QoS::AtMostOnce, |max, q| if q > max { q } else { max });
+    let prefix = args
+        .mqtt
+        .client_id
+        .clone()
+        .unwrap_or_else(|| random_client_id("axonmq-bench"));
+    let topic_prefix = args
+        .topic_prefix
+        .clone()
+        .unwrap_or_else(|| format!("axonmq-bench/{}", prefix));
+    let topics: Vec<String> = (0..args.topics)
+        .map(|i| format!("{}/{}", topic_prefix, i))
+        .collect();
+    let filter = format!("{}/#", topic_prefix);
+
+    // every connection is up and every subscription acknowledged before the clock starts
+    let mut subscribers = vec![];
+    for i in 0..args.subscribers {
+        let (client, mut eventloop) =
+            connected(&args.mqtt, format!("{}-sub-{}", prefix, i)).await?;
+        subscribed(&client, &mut eventloop, &filter, max_qos).await?;
+        subscribers.push((client, eventloop));
+    }
+    let mut publishers = vec![];
+    for i in 0..args.publishers {
+        publishers.push(connected(&args.mqtt, format!("{}-pub-{}", prefix, i)).await?);
+    }
+
+    let duration = Duration::from_secs(args.duration);
+    let start = Instant::now();
+    let end = start + duration;
+    let mut receivers = JoinSet::new();
+    for (client, eventloop) in subscribers {
+        receivers.spawn(subscribe(client, eventloop, end + DRAIN));
+    }
+    let counters = Arc::new(Counters::default());
+    let topics = Arc::new(topics);
+    let qos_mix = Arc::new(qos_mix);
+    let period = (args.rate > 0.0).then(|| Duration::from_secs_f64(1.0 / args.rate));
+    let mut connections = JoinSet::new();
+    let mut senders = JoinSet::new();
+    for (index, (client, eventloop)) in publishers.into_iter().enumerate() {
+        connections.spawn(drive_publisher(eventloop, counters.clone()));
+        let publisher = Publisher {
+            index,
+            client: client.clone(),
+            topics: topics.clone(),
+            qos: qos_mix.clone(),
+            size: args.size as usize,
+            period,
+        };
+        let counters = counters.clone();
+        senders.spawn(async move {
+            publisher.run(end, counters).await;
+            client
+        });
+    }
+
+    let mut clients = vec![];
+    while let Some(client) = senders.join_next().await {
+        clients.push(client?);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    // the acknowledgements still on their way
+    let deadline = Instant::now() + ACK_WAIT;
+    while counters.acked.load(Ordering::Relaxed) < counters.confirmable.load(Ordering::Relaxed)
+        && Instant::now() < deadline
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for client in clients {
+        let _ = client.disconnect().await;
+    }
+    while connections.join_next().await.is_some() {}
+
+    let mut latencies = vec![];
+    while let Some(received) = receivers.join_next().await {
+        match received? {
+            Ok(received) => latencies.extend(received),
+            Err(e) => {
+                eprintln!("Subscriber failed: {}", e);
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    latencies.sort_unstable();
+
+    let sent = counters.sent.load(Ordering::Relaxed);
+    let received = latencies.len() as u64;
+    let report = Report {
+        publishers: args.publishers,
+        subscribers: args.subscribers,
+        size: args.size,
+        qos: args.qos,
+        topics: args.topics,
+        duration_secs: elapsed,
+        sent,
+        sent_per_sec: sent as f64 / elapsed,
+        confirmable: counters.confirmable.load(Ordering::Relaxed),
+        acked: counters.acked.load(Ordering::Relaxed),
+        received,
+        expected: sent * args.subscribers as u64,
+        received_per_sec: received as f64 / elapsed,
+        errors: counters.errors.load(Ordering::Relaxed),
+        latency_ms: Latency {
+            p50: percentile(&latencies, 0.5),
+            p90: percentile(&latencies, 0.9),
+            p99: percentile(&latencies, 0.99),
+            p999: percentile(&latencies, 0.999),
+            max: percentile(&latencies, 1.0),
+        },
+    };
+    if args.json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        report.print();
+    }
+    Ok(())
+}
@@ -22,3 +22,13 @@ pub async fn make_put_request<T: Serialize>(client: &Client, url: &str, body: &T
     let json: Value = response.json().await?;
     Ok(json)
 }
+
+pub async fn make_post_request(client: &Client, url: &str) -> Result<Value> {
+    let response = client.post(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("Cannot connect to AxonMQ"));
+    }
+    let json: Value = response.json().await?;
+    Ok(json)
+}
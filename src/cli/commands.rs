@@ -3,9 +3,9 @@ use clap::{Parser, Subcommand};
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// The base URL of the AxonMQ broker
-    #[arg(long, global = true, default_value = "http://127.0.0.1:1107")]
-    pub host: String,
+    /// The base URL of the AxonMQ broker, defaults to the shell profile in use or http://127.0.0.1:1107
+    #[arg(long, global = true)]
+    pub host: Option<String>,
 
     #[command(subcommand)]
     pub command: Commands,
@@ -15,6 +15,32 @@ pub struct Cli {
 pub enum Commands {
     /// Access Sparkplug B data
     Spb(Spb),
+    /// Publish a message over MQTT
+    Pub(Pub),
+    /// Subscribe over MQTT and print the messages
+    Sub(Sub),
+    /// Load the broker with MQTT publishers and subscribers and report the throughput and latency
+    Bench(Bench),
+    /// Check a broker configuration
+    Config(ConfigCmd),
+    /// Start an interactive shell with completion and history
+    Shell,
+}
+
+#[derive(Parser)]
+pub struct ConfigCmd {
+    #[command(subcommand)]
+    pub command: ConfigCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Check the configuration in a directory and print it with the defaults filled in
+    Validate {
+        /// The directory holding config.toml
+        #[arg(default_value = "./")]
+        dir: String,
+    },
 }
 
 #[derive(Parser)]
@@ -29,10 +55,24 @@ pub enum SpbCommands {
     Get(Get),
     /// Set Sparkplug B resources
     Set(Set),
+    /// Write metrics to a node, or to one of its devices, as an NCMD or a DCMD
+    Write(Write),
+    /// Ask a node to publish its NBIRTH again
+    Rebirth {
+        #[arg(required = true)]
+        group_id: String,
+        #[arg(required = true)]
+        node_id: String,
+    },
+    /// Act as a Sparkplug B edge node publishing simulated metrics, until Ctrl+C
+    Simulate(Simulate),
 }
 
 #[derive(Parser)]
 pub struct Get {
+    /// Keep printing the Sparkplug B events of the resource, until Ctrl+C
+    #[arg(short, long, global = true)]
+    pub watch: bool,
     #[command(subcommand)]
     pub resource: GetResource,
 }
@@ -107,3 +147,145 @@ pub enum SetResource {
         metrics: Vec<String>,
     },
 }
+
+#[derive(Parser)]
+pub struct Write {
+    #[arg(required = true)]
+    pub group_id: String,
+    #[arg(required = true)]
+    pub node_id: String,
+    /// Device of the node the metrics belong to
+    #[arg(short, long)]
+    pub device: Option<String>,
+    /// Metrics to write, in key=value format
+    #[arg(required = true)]
+    pub metrics: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct Simulate {
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+    #[arg(required = true)]
+    pub group_id: String,
+    #[arg(required = true)]
+    pub node_id: String,
+    /// Metric of the node and of each device, name:type with type int, float, bool or string,
+    /// repeatable, defaults to temperature:float, counter:int and running:bool
+    #[arg(long = "metric")]
+    pub metrics: Vec<String>,
+    /// Number of devices of the node, device-0, device-1...
+    #[arg(long, default_value_t = 0)]
+    pub devices: usize,
+    /// Number of instances of the Motor template on the node
+    #[arg(long, default_value_t = 1)]
+    pub motors: usize,
+    /// Milliseconds between two NDATA and DDATA
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+}
+
+/// the connection to the MQTT listener, MQTT 5
+#[derive(Parser)]
+pub struct MqttArgs {
+    /// Host of the MQTT listener
+    #[arg(short = 'H', long, default_value = "127.0.0.1")]
+    pub mqtt_host: String,
+    /// Port of the MQTT listener, defaults to 1883, or 8883 with --tls
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Client identifier, defaults to a random one
+    #[arg(short = 'i', long)]
+    pub client_id: Option<String>,
+    /// User name of the CONNECT
+    #[arg(short, long)]
+    pub username: Option<String>,
+    /// Password of the CONNECT
+    #[arg(short = 'P', long, requires = "username")]
+    pub password: Option<String>,
+    /// Connect over TLS, the server certificate is checked against the system roots or --cafile
+    #[arg(long)]
+    pub tls: bool,
+    /// CA certificate of the server, PEM
+    #[arg(long, requires = "tls")]
+    pub cafile: Option<String>,
+    /// Client certificate, PEM
+    #[arg(long, requires_all = ["tls", "key", "cafile"])]
+    pub cert: Option<String>,
+    /// Client private key, PEM
+    #[arg(long, requires = "cert")]
+    pub key: Option<String>,
+}
+
+#[derive(Parser)]
+pub struct Pub {
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+    /// Topic to publish to
+    #[arg(short, long)]
+    pub topic: String,
+    /// Payload, read from the standard input when missing
+    #[arg(short, long)]
+    pub message: Option<String>,
+    /// QoS of the message
+    #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub qos: u8,
+    /// Retain the message
+    #[arg(short, long)]
+    pub retain: bool,
+    /// User property, in key=value format, repeatable
+    #[arg(long = "user-property")]
+    pub user_properties: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct Sub {
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+    /// Topic filter, repeatable
+    #[arg(short, long = "topic", required = true)]
+    pub topics: Vec<String>,
+    /// Maximum QoS of the messages
+    #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub qos: u8,
+    /// Print each message as a JSON line
+    #[arg(long)]
+    pub json: bool,
+    /// Exit after this many messages
+    #[arg(short = 'C', long)]
+    pub count: Option<usize>,
+}
+
+#[derive(Parser)]
+pub struct Bench {
+    /// Connection options, --client-id is the prefix of the client identifiers
+    #[command(flatten)]
+    pub mqtt: MqttArgs,
+    /// Number of publishing connections
+    #[arg(short = 'c', long, default_value_t = 10)]
+    pub publishers: usize,
+    /// Number of subscribing connections, each receiving every message
+    #[arg(short, long, default_value_t = 1)]
+    pub subscribers: usize,
+    /// Payload size in bytes, the first 8 carry the send time
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(8..))]
+    pub size: u32,
+    /// Messages per second of each publisher, 0 publishes as fast as the broker takes them
+    #[arg(short, long, default_value_t = 100.0)]
+    pub rate: f64,
+    /// QoS of the messages, a list such as 0,1 is used in turn
+    #[arg(short, long, default_value = "0", value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..=2))]
+    pub qos: Vec<u8>,
+    /// Number of topics the messages are spread over
+    #[arg(long, default_value_t = 1)]
+    pub topics: usize,
+    /// Prefix of the topics, defaults to one unique to the run
+    #[arg(long)]
+    pub topic_prefix: Option<String>,
+    /// Seconds of publishing
+    #[arg(short, long, default_value_t = 10)]
+    pub duration: u64,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use axonmq::config::{Config, check};
+
+use crate::commands::{ConfigCmd, ConfigCommands};
+
+pub fn handle_config_command(config: ConfigCmd) -> Result<()> {
+    match config.command {
+        ConfigCommands::Validate { dir } => validate(&dir),
+    }
+}
+
+// the effective configuration on stdout, the problems on stderr
+fn validate(dir: &str) -> Result<()> {
+    let config = Config::from_file(dir)?;
+    print!("{}", toml::to_string(&config)?);
+    let problems = check::problems(&config);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems found in the configuration", problems.len());
+    }
+    eprintln!("Configuration OK");
+    Ok(())
+}
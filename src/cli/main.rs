@@ -2,19 +2,36 @@ use anyhow::Result;
 use clap::Parser;
 use reqwest::Client;
 
+mod bench;
 mod client;
 mod commands;
+mod config;
+mod mqtt;
+mod profile;
+// the generated Sparkplug B protobuf types, shared with the server
+#[allow(dead_code)]
+#[path = "../service/sparkplug_b/proto.rs"]
+mod proto;
+mod shell;
+mod simulate;
 mod spb;
 
 use commands::{Cli, Commands};
+use profile::Profiles;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    // the same provider as the broker, ring and aws-lc-rs are both compiled in and rustls
+    // cannot pick one on its own for pub, sub, simulate and watch over TLS
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
     let client = Client::new();
+    let profiles = Profiles::load();
+    let host = cli.host.unwrap_or_else(|| profiles.host());
 
     let result = match cli.command {
-        Commands::Spb(spb) => spb::handle_spb_command(spb, &cli.host, &client).await,
+        Commands::Shell => shell::run(host, profiles, &client).await,
+        command => run_command(command, &host, &client).await,
     };
 
     if let Err(e) = result {
@@ -23,4 +40,15 @@ async fn main() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub async fn run_command(command: Commands, host: &str, client: &Client) -> Result<()> {
+    match command {
+        Commands::Spb(spb) => spb::handle_spb_command(spb, host, client).await,
+        Commands::Pub(args) => mqtt::handle_pub(args).await,
+        Commands::Sub(args) => mqtt::handle_sub(args).await,
+        Commands::Bench(args) => bench::handle_bench(args).await,
+        Commands::Config(config) => config::handle_config_command(config),
+        Commands::Shell => Err(anyhow::anyhow!("Already in a shell")),
+    }
+}
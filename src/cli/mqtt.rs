@@ -0,0 +1,194 @@
+use std::io::Read;
+use std::time::Duration;
+
+use anyhow::Result;
+use base64::Engine;
+use rumqttc::v5::mqttbytes::v5::{Filter, Packet, Publish, PublishProperties, SubscribeReasonCode};
+use rumqttc::v5::mqttbytes::{QoS, qos};
+use rumqttc::v5::{AsyncClient, Event, EventLoop, MqttOptions};
+use rumqttc::{Outgoing, TlsConfiguration, Transport};
+use serde_json::{Map, Value, json};
+
+use crate::commands::{MqttArgs, Pub, Sub};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// a random identifier for the clients of one command, `<prefix>-<8 hex digits>`
+pub fn random_client_id(prefix: &str) -> String {
+    format!(
+        "{}-{}",
+        prefix,
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    )
+}
+
+fn client_id(args: &MqttArgs) -> String {
+    args.client_id
+        .clone()
+        .unwrap_or_else(|| random_client_id("axonmq-cli"))
+}
+
+pub fn options(args: &MqttArgs, client_id: String) -> Result<MqttOptions> {
+    let port = args.port.unwrap_or(if args.tls { 8883 } else { 1883 });
+    let mut options = MqttOptions::new(client_id, args.mqtt_host.clone(), port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &args.username {
+        options.set_credentials(username, args.password.clone().unwrap_or_default());
+    }
+    if args.tls {
+        let tls = match &args.cafile {
+            Some(cafile) => {
+                let client_auth = match (&args.cert, &args.key) {
+                    (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+                    _ => None,
+                };
+                TlsConfiguration::Simple {
+                    ca: std::fs::read(cafile)?,
+                    alpn: None,
+                    client_auth,
+                }
+            }
+            None => TlsConfiguration::default(),
+        };
+        options.set_transport(Transport::tls_with_config(tls));
+    }
+    Ok(options)
+}
+
+pub fn connect(args: &MqttArgs, client_id: String) -> Result<(AsyncClient, EventLoop)> {
+    Ok(AsyncClient::new(options(args, client_id)?, 16))
+}
+
+fn parse_user_properties(properties: &[String]) -> Result<Vec<(String, String)>> {
+    properties
+        .iter()
+        .map(|property| {
+            property
+                .split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Invalid user property format: {}. Expected key=value",
+                        property
+                    )
+                })
+        })
+        .collect()
+}
+
+pub async fn handle_pub(args: Pub) -> Result<()> {
+    let qos = qos(args.qos).ok_or_else(|| anyhow::anyhow!("Invalid QoS: {}", args.qos))?;
+    let payload = match args.message {
+        Some(message) => message.into_bytes(),
+        None => {
+            let mut payload = Vec::new();
+            std::io::stdin().read_to_end(&mut payload)?;
+            payload
+        }
+    };
+    let properties = PublishProperties {
+        user_properties: parse_user_properties(&args.user_properties)?,
+        ..Default::default()
+    };
+
+    let (client, mut eventloop) = connect(&args.mqtt, client_id(&args.mqtt))?;
+    // the request is refused before it is sent when the topic is not valid
+    client
+        .publish_with_properties(args.topic.clone(), qos, args.retain, payload, properties)
+        .await
+        .map_err(|_| anyhow::anyhow!("Invalid topic: {}", args.topic))?;
+    // done once the broker has the message, PUBACK for QoS 1 and PUBCOMP for QoS 2
+    loop {
+        match eventloop.poll().await? {
+            Event::Outgoing(Outgoing::Publish(_)) if qos == QoS::AtMostOnce => break,
+            Event::Incoming(Packet::PubAck(_)) | Event::Incoming(Packet::PubComp(_)) => break,
+            _ => {}
+        }
+    }
+    disconnect(&client, &mut eventloop).await
+}
+
+pub async fn disconnect(client: &AsyncClient, eventloop: &mut EventLoop) -> Result<()> {
+    client.disconnect().await?;
+    loop {
+        if let Event::Outgoing(Outgoing::Disconnect) = eventloop.poll().await? {
+            return Ok(());
+        }
+    }
+}
+
+/// a message as printed with --json, a payload which is not UTF-8 is base64 encoded
+fn message_json(publish: &Publish) -> Value {
+    let mut message = Map::new();
+    message.insert(
+        "topic".to_string(),
+        Value::from(String::from_utf8_lossy(&publish.topic)),
+    );
+    message.insert("qos".to_string(), Value::from(publish.qos as u8));
+    message.insert("retain".to_string(), Value::from(publish.retain));
+    match std::str::from_utf8(&publish.payload) {
+        Ok(payload) => message.insert("payload".to_string(), Value::from(payload)),
+        Err(_) => message.insert(
+            "payload_base64".to_string(),
+            Value::from(base64::engine::general_purpose::STANDARD.encode(&publish.payload)),
+        ),
+    };
+    if let Some(properties) = &publish.properties
+        && !properties.user_properties.is_empty()
+    {
+        let user_properties: Vec<Value> = properties
+            .user_properties
+            .iter()
+            .map(|(key, value)| json!([key, value]))
+            .collect();
+        message.insert("user_properties".to_string(), Value::from(user_properties));
+    }
+    Value::Object(message)
+}
+
+pub async fn handle_sub(args: Sub) -> Result<()> {
+    let qos = qos(args.qos).ok_or_else(|| anyhow::anyhow!("Invalid QoS: {}", args.qos))?;
+    let (client, mut eventloop) = connect(&args.mqtt, client_id(&args.mqtt))?;
+    client
+        .subscribe_many(
+            args.topics
+                .iter()
+                .map(|topic| Filter::new(topic.clone(), qos)),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Invalid topic filter in: {}", args.topics.join(" ")))?;
+
+    let mut received = 0;
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        match event {
+            Event::Incoming(Packet::SubAck(ack)) => {
+                for (topic, code) in args.topics.iter().zip(&ack.return_codes) {
+                    if !matches!(code, SubscribeReasonCode::Success(_)) {
+                        eprintln!("Subscription to {} refused: {:?}", topic, code);
+                    }
+                }
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                if args.json {
+                    println!("{}", message_json(&publish));
+                } else {
+                    println!(
+                        "{} {}",
+                        String::from_utf8_lossy(&publish.topic),
+                        String::from_utf8_lossy(&publish.payload)
+                    );
+                }
+                received += 1;
+                if args.count.is_some_and(|count| received >= count) {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    disconnect(&client, &mut eventloop).await
+}
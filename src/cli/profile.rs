@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_HOST: &str = "http://127.0.0.1:1107";
+
+/// broker URLs saved by the shell, kept in the user configuration directory
+#[derive(Default, Serialize, Deserialize)]
+pub struct Profiles {
+    // profile selected with `profile use`, takes precedence over host
+    pub active: Option<String>,
+    // URL selected with `host <url>`
+    pub host: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, String>,
+}
+
+impl Profiles {
+    /// directory holding the profiles and the shell history
+    pub fn dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("axonmq"))
+    }
+
+    fn path() -> Option<PathBuf> {
+        Self::dir().map(|dir| dir.join("cli.toml"))
+    }
+
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                eprintln!("Ignoring invalid {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow::anyhow!("No configuration directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// URL of the active profile, or the selected host, or the default one
+    pub fn host(&self) -> String {
+        self.active
+            .as_ref()
+            .and_then(|name| self.profiles.get(name))
+            .or(self.host.as_ref())
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_HOST.to_string())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles.keys().cloned().collect()
+    }
+}
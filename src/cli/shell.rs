@@ -0,0 +1,248 @@
+use anyhow::Result;
+use clap::{CommandFactory, Parser};
+use reqwest::Client;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::commands::Cli;
+use crate::profile::Profiles;
+use crate::run_command;
+
+const BUILTINS: &[(&str, &str)] = &[
+    ("help", "Show this help"),
+    (
+        "host",
+        "Show the broker URL, or `host <url>` to use another one",
+    ),
+    (
+        "profile",
+        "list | add <name> <url> | use <name> | remove <name>",
+    ),
+    ("exit", "Leave the shell"),
+];
+
+const PROFILE_COMMANDS: &[&str] = &["list", "add", "use", "remove"];
+
+struct ShellHelper {
+    command: clap::Command,
+    profiles: Vec<String>,
+}
+
+impl ShellHelper {
+    /// words that can follow the complete words already typed
+    fn candidates(&self, words: &[&str]) -> Vec<String> {
+        match words {
+            [] => BUILTINS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .chain(Self::subcommands(&self.command))
+                .collect(),
+            ["profile"] => PROFILE_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["profile", "use" | "remove"] => self.profiles.clone(),
+            _ => {
+                let mut command = &self.command;
+                for word in words.iter().filter(|w| !w.starts_with('-')) {
+                    match command.find_subcommand(word) {
+                        Some(sub) => command = sub,
+                        None => return vec![],
+                    }
+                }
+                Self::subcommands(command).collect()
+            }
+        }
+    }
+
+    fn subcommands(command: &clap::Command) -> impl Iterator<Item = String> + '_ {
+        command
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .filter(|name| name != "help" && name != "shell")
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let prefix = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let pairs = self
+            .candidates(&words)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(prefix))
+            .map(|candidate| Pair {
+                replacement: format!("{} ", candidate),
+                display: candidate,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// interactive shell running the CLI commands against the selected broker
+pub async fn run(mut host: String, mut profiles: Profiles, client: &Client) -> Result<()> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper {
+        command: Cli::command(),
+        profiles: profiles.names(),
+    }));
+    let history = Profiles::dir().map(|dir| dir.join("history.txt"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    println!(
+        "AxonMQ shell on {}, type help for the commands, Tab completes",
+        host
+    );
+    loop {
+        let prompt = match &profiles.active {
+            Some(name) => format!("axonmq({})> ", name),
+            None => "axonmq> ".to_string(),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line)?;
+
+        let Some(words) = shlex::split(line) else {
+            eprintln!("Error: unbalanced quotes");
+            continue;
+        };
+        match words[0].as_str() {
+            "exit" | "quit" => break,
+            "help" => print_help(),
+            "host" => match words.get(1) {
+                Some(url) => {
+                    host = url.clone();
+                    profiles.active = None;
+                    profiles.host = Some(url.clone());
+                    if let Err(e) = profiles.save() {
+                        eprintln!("Error: {}", e);
+                    }
+                    println!("Using {}", host);
+                }
+                None => println!("{}", host),
+            },
+            "profile" => {
+                if let Err(e) = profile_command(&words[1..], &mut profiles, &mut host) {
+                    eprintln!("Error: {}", e);
+                }
+                if let Some(helper) = editor.helper_mut() {
+                    helper.profiles = profiles.names();
+                }
+            }
+            _ => {
+                let args = std::iter::once("axonmq-cli".to_string()).chain(words);
+                match Cli::try_parse_from(args) {
+                    Ok(cli) => {
+                        let host = cli.host.unwrap_or_else(|| host.clone());
+                        if let Err(e) = run_command(cli.command, &host, client).await {
+                            eprintln!("Error: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        let _ = e.print();
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Some(dir) = history.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+fn print_help() {
+    println!("Shell commands:");
+    for (name, about) in BUILTINS {
+        println!("  {:<10} {}", name, about);
+    }
+    println!("CLI commands, `<command> --help` for details:");
+    for sub in Cli::command().get_subcommands() {
+        if matches!(sub.get_name(), "help" | "shell") {
+            continue;
+        }
+        let about = sub.get_about().map(|a| a.to_string()).unwrap_or_default();
+        println!("  {:<10} {}", sub.get_name(), about);
+    }
+}
+
+fn profile_command(args: &[String], profiles: &mut Profiles, host: &mut String) -> Result<()> {
+    match args {
+        [] => return Err(anyhow::anyhow!("Usage: profile list | add | use | remove")),
+        [cmd] if cmd == "list" => {
+            for (name, url) in &profiles.profiles {
+                let mark = if profiles.active.as_ref() == Some(name) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{} {:<16} {}", mark, name, url);
+            }
+            return Ok(());
+        }
+        [cmd, name, url] if cmd == "add" => {
+            profiles.profiles.insert(name.clone(), url.clone());
+        }
+        [cmd, name] if cmd == "use" => {
+            if !profiles.profiles.contains_key(name) {
+                return Err(anyhow::anyhow!("Unknown profile: {}", name));
+            }
+            profiles.active = Some(name.clone());
+            *host = profiles.host();
+            println!("Using {} ({})", name, host);
+        }
+        [cmd, name] if cmd == "remove" => {
+            if profiles.profiles.remove(name).is_none() {
+                return Err(anyhow::anyhow!("Unknown profile: {}", name));
+            }
+            if profiles.active.as_ref() == Some(name) {
+                profiles.active = None;
+                *host = profiles.host();
+                println!("Using {}", host);
+            }
+        }
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Usage: profile list | add <name> <url> | use <name> | remove <name>"
+            ));
+        }
+    }
+    profiles.save()
+}
@@ -0,0 +1,624 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use prost::Message;
+use rand::Rng;
+use rumqttc::Outgoing;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::{Filter, LastWill, Packet};
+use rumqttc::v5::{AsyncClient, Event};
+use tokio::sync::mpsc;
+
+use crate::commands::Simulate;
+use crate::mqtt::{options, random_client_id};
+use crate::proto::payload::{Metric, Template, metric::Value};
+use crate::proto::{DataType, Payload};
+
+const NAMESPACE: &str = "spBv1.0";
+const REBIRTH: &str = "Node Control/Rebirth";
+// the template of the motor instances
+const MOTOR: &str = "Motor";
+const DEFAULT_METRICS: &[&str] = &["temperature:float", "counter:int", "running:bool"];
+
+fn now_milliseconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Reading {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+}
+
+impl Reading {
+    fn parse(spec: &str) -> Result<(String, Reading)> {
+        let (name, kind) = spec.rsplit_once(':').unwrap_or((spec, "float"));
+        let reading = match kind {
+            "int" => Reading::Int(0),
+            "float" => Reading::Float(20.0),
+            "bool" => Reading::Bool(false),
+            "string" => Reading::String(String::new()),
+            _ => anyhow::bail!(
+                "Invalid metric type: {}. Expected int, float, bool or string",
+                kind
+            ),
+        };
+        if name.is_empty() || name == REBIRTH || name == "bdSeq" {
+            anyhow::bail!("Invalid metric name: {}", name);
+        }
+        Ok((name.to_string(), reading))
+    }
+
+    fn datatype(&self) -> DataType {
+        match self {
+            Reading::Int(_) => DataType::Int64,
+            Reading::Float(_) => DataType::Double,
+            Reading::Bool(_) => DataType::Boolean,
+            Reading::String(_) => DataType::String,
+        }
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            Reading::Int(v) => Value::LongValue(*v as u64),
+            Reading::Float(v) => Value::DoubleValue(*v),
+            Reading::Bool(v) => Value::BooleanValue(*v),
+            Reading::String(v) => Value::StringValue(v.clone()),
+        }
+    }
+
+    /// the value written by a command, of the type of this reading
+    fn written(&self, value: &Value) -> Option<Reading> {
+        match (self, value) {
+            (Reading::Int(_), Value::IntValue(v)) => Some(Reading::Int(*v as i32 as i64)),
+            (Reading::Int(_), Value::LongValue(v)) => Some(Reading::Int(*v as i64)),
+            (Reading::Float(_), Value::FloatValue(v)) => Some(Reading::Float(*v as f64)),
+            (Reading::Float(_), Value::DoubleValue(v)) => Some(Reading::Float(*v)),
+            (Reading::Bool(_), Value::BooleanValue(v)) => Some(Reading::Bool(*v)),
+            (Reading::String(_), Value::StringValue(v)) => Some(Reading::String(v.clone())),
+            _ => None,
+        }
+    }
+
+    /// the next simulated value, strings only change when written
+    fn step(&mut self, rng: &mut impl Rng) -> bool {
+        match self {
+            Reading::Int(v) => {
+                let delta = rng.random_range(0..=2);
+                *v += delta;
+                delta != 0
+            }
+            Reading::Float(v) => {
+                *v = ((*v + rng.random_range(-0.5..0.5)) * 100.0).round() / 100.0;
+                true
+            }
+            Reading::Bool(v) => {
+                let flip = rng.random_bool(0.1);
+                *v ^= flip;
+                flip
+            }
+            Reading::String(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Reading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reading::Int(v) => write!(f, "{}", v),
+            Reading::Float(v) => write!(f, "{}", v),
+            Reading::Bool(v) => write!(f, "{}", v),
+            Reading::String(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+struct SimMetric {
+    name: String,
+    alias: u64,
+    reading: Reading,
+    changed: bool,
+}
+
+impl SimMetric {
+    fn birth(&self, timestamp: u64) -> Metric {
+        metric(
+            Some(&self.name),
+            Some(self.alias),
+            self.reading.datatype(),
+            self.reading.value(),
+            timestamp,
+        )
+    }
+
+    fn data(&self, timestamp: u64) -> Metric {
+        metric(
+            None,
+            Some(self.alias),
+            self.reading.datatype(),
+            self.reading.value(),
+            timestamp,
+        )
+    }
+}
+
+/// an instance of the Motor template, its rpm follows whether it runs
+struct Motor {
+    name: String,
+    alias: u64,
+    rpm: f64,
+    running: bool,
+}
+
+impl Motor {
+    fn members(&self, timestamp: u64) -> Vec<Metric> {
+        vec![
+            metric(
+                Some("rpm"),
+                None,
+                DataType::Double,
+                Value::DoubleValue(self.rpm),
+                timestamp,
+            ),
+            metric(
+                Some("running"),
+                None,
+                DataType::Boolean,
+                Value::BooleanValue(self.running),
+                timestamp,
+            ),
+        ]
+    }
+
+    fn instance(&self, name: Option<&str>, timestamp: u64) -> Metric {
+        let template = Template {
+            version: None,
+            metrics: self.members(timestamp),
+            parameters: vec![],
+            template_ref: Some(MOTOR.to_string()),
+            is_definition: Some(false),
+        };
+        metric(
+            name,
+            Some(self.alias),
+            DataType::Template,
+            Value::TemplateValue(template),
+            timestamp,
+        )
+    }
+
+    fn step(&mut self, rng: &mut impl Rng) {
+        if rng.random_bool(0.02) {
+            self.running = !self.running;
+        }
+        let target = if self.running { 1500.0 } else { 0.0 };
+        self.rpm = (self.rpm + (target - self.rpm) * 0.2 + rng.random_range(-5.0..5.0)).max(0.0);
+        self.rpm = self.rpm.round();
+    }
+}
+
+fn metric(
+    name: Option<&str>,
+    alias: Option<u64>,
+    datatype: DataType,
+    value: Value,
+    timestamp: u64,
+) -> Metric {
+    Metric {
+        name: name.map(str::to_string),
+        alias,
+        timestamp: Some(timestamp),
+        datatype: Some(datatype as u32),
+        is_historical: None,
+        is_transient: None,
+        is_null: None,
+        metadata: None,
+        properties: None,
+        value: Some(value),
+    }
+}
+
+/// the Motor template definition of the NBIRTH
+fn motor_definition(timestamp: u64) -> Metric {
+    let members = Motor {
+        name: String::new(),
+        alias: 0,
+        rpm: 0.0,
+        running: false,
+    }
+    .members(timestamp);
+    let template = Template {
+        version: None,
+        metrics: members,
+        parameters: vec![],
+        template_ref: None,
+        is_definition: Some(true),
+    };
+    metric(
+        Some(MOTOR),
+        None,
+        DataType::Template,
+        Value::TemplateValue(template),
+        timestamp,
+    )
+}
+
+fn ndeath(bd_seq: u64) -> Vec<u8> {
+    Payload {
+        timestamp: Some(now_milliseconds()),
+        metrics: vec![metric(
+            Some("bdSeq"),
+            None,
+            DataType::UInt64,
+            Value::LongValue(bd_seq),
+            now_milliseconds(),
+        )],
+        seq: None,
+        uuid: None,
+        body: None,
+    }
+    .encode_to_vec()
+}
+
+struct Device {
+    name: String,
+    metrics: Vec<SimMetric>,
+}
+
+/// the state of the simulated edge node, the aliases are unique across the node and its devices
+struct EdgeNode {
+    group_id: String,
+    node_id: String,
+    bd_seq: u64,
+    seq: u8,
+    metrics: Vec<SimMetric>,
+    motors: Vec<Motor>,
+    devices: Vec<Device>,
+}
+
+impl EdgeNode {
+    fn new(args: &Simulate, bd_seq: u64) -> Result<Self> {
+        let specs: Vec<(String, Reading)> = if args.metrics.is_empty() {
+            DEFAULT_METRICS
+                .iter()
+                .map(|spec| Reading::parse(spec))
+                .collect::<Result<_>>()?
+        } else {
+            args.metrics
+                .iter()
+                .map(|spec| Reading::parse(spec))
+                .collect::<Result<_>>()?
+        };
+        // 0 is Node Control/Rebirth
+        let mut alias = 0;
+        let mut next_alias = || {
+            alias += 1;
+            alias
+        };
+        let new_metrics = |next_alias: &mut dyn FnMut() -> u64| {
+            specs
+                .iter()
+                .map(|(name, reading)| SimMetric {
+                    name: name.clone(),
+                    alias: next_alias(),
+                    reading: reading.clone(),
+                    changed: false,
+                })
+                .collect::<Vec<_>>()
+        };
+        let metrics = new_metrics(&mut next_alias);
+        let motors = (0..args.motors)
+            .map(|i| Motor {
+                name: format!("motor-{}", i),
+                alias: next_alias(),
+                rpm: 0.0,
+                running: true,
+            })
+            .collect();
+        let devices = (0..args.devices)
+            .map(|i| Device {
+                name: format!("device-{}", i),
+                metrics: new_metrics(&mut next_alias),
+            })
+            .collect();
+        Ok(EdgeNode {
+            group_id: args.group_id.clone(),
+            node_id: args.node_id.clone(),
+            bd_seq,
+            seq: 0,
+            metrics,
+            motors,
+            devices,
+        })
+    }
+
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        match device {
+            Some(device) => format!(
+                "{}/{}/{}/{}/{}",
+                NAMESPACE, self.group_id, message_type, self.node_id, device
+            ),
+            None => format!(
+                "{}/{}/{}/{}",
+                NAMESPACE, self.group_id, message_type, self.node_id
+            ),
+        }
+    }
+
+    fn payload(&mut self, metrics: Vec<Metric>) -> Vec<u8> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        Payload {
+            timestamp: Some(now_milliseconds()),
+            metrics,
+            seq: Some(seq as u64),
+            uuid: None,
+            body: None,
+        }
+        .encode_to_vec()
+    }
+
+    /// the NBIRTH and the DBIRTHs, the sequence starts over
+    fn births(&mut self) -> Vec<(String, Vec<u8>)> {
+        self.seq = 0;
+        let timestamp = now_milliseconds();
+        let mut metrics = vec![
+            metric(
+                Some("bdSeq"),
+                None,
+                DataType::UInt64,
+                Value::LongValue(self.bd_seq),
+                timestamp,
+            ),
+            metric(
+                Some(REBIRTH),
+                Some(0),
+                DataType::Boolean,
+                Value::BooleanValue(false),
+                timestamp,
+            ),
+        ];
+        if !self.motors.is_empty() {
+            metrics.push(motor_definition(timestamp));
+        }
+        metrics.extend(self.metrics.iter().map(|m| m.birth(timestamp)));
+        metrics.extend(
+            self.motors
+                .iter()
+                .map(|motor| motor.instance(Some(&motor.name), timestamp)),
+        );
+        let mut births = vec![(self.topic("NBIRTH", None), self.payload(metrics))];
+
+        for i in 0..self.devices.len() {
+            let metrics = self.devices[i]
+                .metrics
+                .iter()
+                .map(|m| m.birth(timestamp))
+                .collect();
+            let topic = self.topic("DBIRTH", Some(&self.devices[i].name));
+            births.push((topic, self.payload(metrics)));
+        }
+        births
+    }
+
+    /// moves the simulated values
+    fn step(&mut self, rng: &mut impl Rng) {
+        let devices = self.devices.iter_mut().flat_map(|d| d.metrics.iter_mut());
+        for metric in self.metrics.iter_mut().chain(devices) {
+            if metric.reading.step(rng) {
+                metric.changed = true;
+            }
+        }
+        for motor in self.motors.iter_mut() {
+            motor.step(rng);
+        }
+    }
+
+    /// the NDATA and DDATAs of the metrics changed since the last ones, by alias
+    fn data(&mut self, with_motors: bool) -> Vec<(String, Vec<u8>)> {
+        let timestamp = now_milliseconds();
+        let mut messages = vec![];
+
+        let mut metrics: Vec<Metric> = self
+            .metrics
+            .iter_mut()
+            .filter_map(|m| std::mem::take(&mut m.changed).then(|| m.data(timestamp)))
+            .collect();
+        if with_motors {
+            metrics.extend(
+                self.motors
+                    .iter()
+                    .map(|motor| motor.instance(None, timestamp)),
+            );
+        }
+        if !metrics.is_empty() {
+            messages.push((self.topic("NDATA", None), self.payload(metrics)));
+        }
+
+        for i in 0..self.devices.len() {
+            let metrics: Vec<Metric> = self.devices[i]
+                .metrics
+                .iter_mut()
+                .filter_map(|m| std::mem::take(&mut m.changed).then(|| m.data(timestamp)))
+                .collect();
+            if !metrics.is_empty() {
+                let topic = self.topic("DDATA", Some(&self.devices[i].name));
+                messages.push((topic, self.payload(metrics)));
+            }
+        }
+        messages
+    }
+
+    /// applies an NCMD, or a DCMD of `device`, returns whether a rebirth is asked
+    fn command(&mut self, device: Option<&str>, payload: Payload) -> bool {
+        let mut rebirth = false;
+        let metrics = match device {
+            Some(name) => match self.devices.iter_mut().find(|d| d.name == name) {
+                Some(device) => &mut device.metrics,
+                None => {
+                    eprintln!("DCMD for unknown device {}", name);
+                    return false;
+                }
+            },
+            None => &mut self.metrics,
+        };
+        for command in payload.metrics {
+            let Some(value) = command.value else {
+                continue;
+            };
+            let is_rebirth = device.is_none()
+                && (command.name.as_deref() == Some(REBIRTH) || command.alias == Some(0));
+            if is_rebirth {
+                rebirth |= value == Value::BooleanValue(true);
+                continue;
+            }
+            let target = metrics.iter_mut().find(|m| match &command.name {
+                Some(name) => &m.name == name,
+                None => command.alias == Some(m.alias),
+            });
+            let Some(target) = target else {
+                eprintln!(
+                    "Write to unknown metric {:?}",
+                    command.name.or(command.alias.map(|a| a.to_string()))
+                );
+                continue;
+            };
+            match target.reading.written(&value) {
+                Some(reading) => {
+                    println!("write {} = {}", target.name, reading);
+                    target.reading = reading;
+                    target.changed = true;
+                }
+                None => eprintln!("Write to {} of a value of another type", target.name),
+            }
+        }
+        rebirth
+    }
+}
+
+pub async fn handle_simulate(args: Simulate) -> Result<()> {
+    // a new connection gets the next bdSeq, the simulator connects once
+    let bd_seq = 0;
+    let mut node = EdgeNode::new(&args, bd_seq)?;
+    let client_id = args
+        .mqtt
+        .client_id
+        .clone()
+        .unwrap_or_else(|| random_client_id("axonmq-sim"));
+    let mut options = options(&args.mqtt, client_id)?;
+    options.set_last_will(LastWill::new(
+        node.topic("NDEATH", None),
+        ndeath(bd_seq),
+        QoS::AtLeastOnce,
+        false,
+        None,
+    ));
+    let capacity = 16 + 2 * (args.devices + 1);
+    let (client, mut eventloop) = AsyncClient::new(options, capacity);
+
+    // the connection is polled on its own so that publishing never waits on it
+    let (tx, mut rx) = mpsc::channel(64);
+    let connection = tokio::spawn(async move {
+        loop {
+            let event = eventloop.poll().await;
+            let closed =
+                event.is_err() || matches!(event, Ok(Event::Outgoing(Outgoing::Disconnect)));
+            if tx.send(event).await.is_err() || closed {
+                return;
+            }
+        }
+    });
+    loop {
+        match rx.recv().await {
+            Some(Ok(Event::Incoming(Packet::ConnAck(_)))) => break,
+            Some(Ok(_)) => {}
+            Some(Err(e)) => anyhow::bail!("Failed to connect: {}", e),
+            None => anyhow::bail!("Failed to connect"),
+        }
+    }
+    // NCMD and DCMD of this node
+    client
+        .subscribe_many([
+            Filter::new(node.topic("NCMD", None), QoS::AtLeastOnce),
+            Filter::new(node.topic("DCMD", Some("+")), QoS::AtLeastOnce),
+        ])
+        .await?;
+    let ncmd = node.topic("NCMD", None);
+    let dcmd = node.topic("DCMD", Some(""));
+
+    let mut rng = rand::rng();
+    let publish = async |messages: Vec<(String, Vec<u8>)>| -> Result<()> {
+        for (topic, payload) in messages {
+            client
+                .publish(topic, QoS::AtMostOnce, false, payload)
+                .await?;
+        }
+        Ok(())
+    };
+    publish(node.births()).await?;
+    println!(
+        "{} online with bdSeq {}, {} metrics, {} motors and {} devices",
+        node.topic("NBIRTH", None),
+        bd_seq,
+        node.metrics.len(),
+        node.motors.len(),
+        node.devices.len()
+    );
+
+    let mut ticker = tokio::time::interval(Duration::from_millis(args.interval));
+    ticker.tick().await;
+    let result = loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                node.step(&mut rng);
+                if let Err(e) = publish(node.data(true)).await {
+                    break Err(e);
+                }
+            }
+            event = rx.recv() => match event {
+                Some(Ok(Event::Incoming(Packet::Publish(message)))) => {
+                    let topic = String::from_utf8_lossy(&message.topic).to_string();
+                    let device = topic.strip_prefix(&dcmd);
+                    if topic != ncmd && device.is_none() {
+                        continue;
+                    }
+                    let Ok(payload) = Payload::decode(message.payload) else {
+                        eprintln!("Invalid payload on {}", topic);
+                        continue;
+                    };
+                    let messages = if node.command(device, payload) {
+                        println!("rebirth requested");
+                        node.births()
+                    } else {
+                        node.data(false)
+                    };
+                    if let Err(e) = publish(messages).await {
+                        break Err(e);
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            },
+            _ = tokio::signal::ctrl_c() => break Ok(()),
+        }
+    };
+
+    // the will is only sent by the broker when the connection is lost
+    let _ = client
+        .publish(
+            node.topic("NDEATH", None),
+            QoS::AtLeastOnce,
+            false,
+            ndeath(bd_seq),
+        )
+        .await;
+    let _ = client.disconnect().await;
+    let _ = connection.await;
+    result
+}
@@ -1,10 +1,13 @@
 use anyhow::Result;
+use futures::StreamExt;
 use reqwest::Client;
 use serde::Serialize;
 use serde_json::Value;
+use tokio_tungstenite::tungstenite::Message;
 
-use crate::commands::{GetResource, SetResource, Spb, SpbCommands};
-use crate::client::{make_request, make_put_request};
+use crate::commands::{GetResource, SetResource, Spb, SpbCommands, Write};
+use crate::client::{make_post_request, make_request, make_put_request};
+use crate::simulate;
 
 #[derive(Serialize)]
 struct CliKV {
@@ -39,7 +42,62 @@ fn parse_metrics(metrics: &[String]) -> Result<Vec<CliKV>> {
     Ok(kvs)
 }
 
+// the filters of the event stream matching a resource
+fn stream_query(resource: &GetResource) -> Vec<(&'static str, String)> {
+    let (group_id, node_id, device_id) = match resource {
+        GetResource::Groups => (None, None, None),
+        GetResource::Group { group_id } | GetResource::Nodes { group_id } => (Some(group_id), None, None),
+        GetResource::Node { group_id, node_id } | GetResource::Devices { group_id, node_id } => {
+            (Some(group_id), Some(node_id), None)
+        }
+        GetResource::Device { group_id, node_id, device_id } => (Some(group_id), Some(node_id), Some(device_id)),
+    };
+    [("group_id", group_id), ("node_id", node_id), ("device_id", device_id)]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value.clone())))
+        .collect()
+}
+
+/// prints the events of the Sparkplug B stream, one JSON line each, until Ctrl+C
+async fn watch(host: &str, query: &[(&str, String)]) -> Result<()> {
+    let mut url = reqwest::Url::parse(&format!("{}/api/v1/spb/stream", host))?;
+    url.query_pairs_mut().extend_pairs(query);
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    url.set_scheme(scheme).map_err(|_| anyhow::anyhow!("Invalid host: {}", host))?;
+
+    let (mut stream, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot watch the Sparkplug B events: {}", e))?;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        };
+        match message {
+            Some(Ok(Message::Text(event))) => println!("{}", event),
+            Some(Ok(Message::Close(_))) | None => return Ok(()),
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+async fn write(write: Write, host: &str, client: &Client) -> Result<()> {
+    let mut url = format!("{}/api/v1/services/sparkplug_b/groups/{}/nodes/{}", host, write.group_id, write.node_id);
+    if let Some(device) = &write.device {
+        url = format!("{}/devices/{}", url, device);
+    }
+    let kvs = parse_metrics(&write.metrics)?;
+    let json = make_put_request(client, &url, &kvs).await?;
+    println!("{}", serde_json::to_string_pretty(&json)?);
+    Ok(())
+}
+
 pub async fn handle_spb_command(spb: Spb, host: &str, client: &Client) -> Result<()> {
+    let watch_query = match &spb.command {
+        SpbCommands::Get(get) if get.watch => Some(stream_query(&get.resource)),
+        _ => None,
+    };
     match spb.command {
         SpbCommands::Get(get) => match get.resource {
             GetResource::Groups => {
@@ -95,6 +153,16 @@ pub async fn handle_spb_command(spb: Spb, host: &str, client: &Client) -> Result
                 println!("{}", pretty_json);
             }
         },
+        SpbCommands::Write(args) => write(args, host, client).await?,
+        SpbCommands::Rebirth { group_id, node_id } => {
+            let url = format!("{}/api/v1/services/sparkplug_b/groups/{}/nodes/{}/rebirth", host, group_id, node_id);
+            let json = make_post_request(client, &url).await?;
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        SpbCommands::Simulate(args) => simulate::handle_simulate(args).await?,
+    }
+    if let Some(query) = watch_query {
+        watch(host, &query).await?;
     }
     Ok(())
 }
@@ -0,0 +1,496 @@
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::mqtt::retain_store::{
+    decode_body, encode_body, read_opt_str, read_str, write_opt_str, write_str,
+};
+use crate::mqtt::retain_trie::RetainedMessage;
+use crate::processor::message::Message;
+
+use super::raft::{self, Entry};
+
+// largest MQTT packet plus framing
+const MAX_FRAME_LEN: u32 = 268_435_456 + 1024;
+
+const FRAME_HELLO: u8 = 1;
+const FRAME_SUBSCRIBE: u8 = 2;
+const FRAME_UNSUBSCRIBE: u8 = 3;
+const FRAME_PUBLISH: u8 = 4;
+const FRAME_CLIENT_CONNECTED: u8 = 5;
+const FRAME_MEMBERS: u8 = 6;
+const FRAME_RAFT: u8 = 7;
+const FRAME_AUTH: u8 = 8;
+
+const RAFT_VOTE: u8 = 1;
+const RAFT_VOTE_REPLY: u8 = 2;
+const RAFT_APPEND: u8 = 3;
+const RAFT_APPEND_REPLY: u8 = 4;
+const RAFT_SNAPSHOT: u8 = 5;
+const RAFT_PROPOSE: u8 = 6;
+
+/// inter-node message, sent as `len: u32, type: u8, body`
+#[derive(Clone)]
+pub enum Frame {
+    Hello {
+        node_id: String,
+        advertise: String,
+        peers: Vec<String>,
+        // the challenge of the sender, answered with an HMAC of the cluster secret
+        nonce: Vec<u8>,
+        // the answer to the challenge of the dialer, empty from the dialer
+        proof: Vec<u8>,
+    },
+    // the answer of the dialer to the challenge in the hello of the accepting node
+    Auth {
+        proof: Vec<u8>,
+    },
+    Subscribe {
+        share_group: Option<String>,
+        filter: String,
+    },
+    Unsubscribe {
+        share_group: Option<String>,
+        filter: String,
+    },
+    Publish {
+        // delivered to one member of the group, or to every non-shared subscriber
+        share_group: Option<String>,
+        message: Box<Message>,
+    },
+    ClientConnected {
+        client_id: String,
+    },
+    // the local members of a shared subscription, sent on every change
+    Members {
+        share_group: String,
+        filter: String,
+        members: Vec<String>,
+    },
+    // between the members of `[node.cluster.replication]`
+    Raft(raft::Message),
+}
+
+impl Frame {
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; 4];
+        match self {
+            Frame::Hello {
+                node_id,
+                advertise,
+                peers,
+                nonce,
+                proof,
+            } => {
+                buf.write_u8(FRAME_HELLO)?;
+                write_str(&mut buf, node_id)?;
+                write_str(&mut buf, advertise)?;
+                buf.write_u16::<BigEndian>(peers.len() as u16)?;
+                for peer in peers {
+                    write_str(&mut buf, peer)?;
+                }
+                write_bytes(&mut buf, nonce)?;
+                write_bytes(&mut buf, proof)?;
+            }
+            Frame::Auth { proof } => {
+                buf.write_u8(FRAME_AUTH)?;
+                write_bytes(&mut buf, proof)?;
+            }
+            Frame::Subscribe {
+                share_group,
+                filter,
+            } => {
+                buf.write_u8(FRAME_SUBSCRIBE)?;
+                write_opt_str(&mut buf, share_group)?;
+                write_str(&mut buf, filter)?;
+            }
+            Frame::Unsubscribe {
+                share_group,
+                filter,
+            } => {
+                buf.write_u8(FRAME_UNSUBSCRIBE)?;
+                write_opt_str(&mut buf, share_group)?;
+                write_str(&mut buf, filter)?;
+            }
+            Frame::Publish {
+                share_group,
+                message,
+            } => {
+                buf.write_u8(FRAME_PUBLISH)?;
+                write_opt_str(&mut buf, share_group)?;
+                write_str(&mut buf, &message.client_id)?;
+                write_str(&mut buf, &message.topic)?;
+                buf.write_u8(message.retain as u8)?;
+                buf.write_u64::<BigEndian>(message.options.message_expiry_at.unwrap_or(0))?;
+                buf.write_all(&encode_body(&RetainedMessage {
+                    topic: String::new(),
+                    qos: message.qos,
+                    payload: message.payload.clone(),
+                    user_properties: message.user_properties.clone(),
+                    options: message.options.clone(),
+                    client_id: None,
+                })?)?;
+            }
+            Frame::ClientConnected { client_id } => {
+                buf.write_u8(FRAME_CLIENT_CONNECTED)?;
+                write_str(&mut buf, client_id)?;
+            }
+            Frame::Members {
+                share_group,
+                filter,
+                members,
+            } => {
+                buf.write_u8(FRAME_MEMBERS)?;
+                write_str(&mut buf, share_group)?;
+                write_str(&mut buf, filter)?;
+                buf.write_u32::<BigEndian>(members.len() as u32)?;
+                for member in members {
+                    write_str(&mut buf, member)?;
+                }
+            }
+            Frame::Raft(message) => {
+                buf.write_u8(FRAME_RAFT)?;
+                write_raft(&mut buf, message)?;
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        Ok(buf)
+    }
+
+    fn decode(body: &[u8]) -> io::Result<Self> {
+        let mut rdr = Cursor::new(body);
+        let frame = match rdr.read_u8()? {
+            FRAME_HELLO => {
+                let node_id = read_str(&mut rdr)?;
+                let advertise = read_str(&mut rdr)?;
+                let count = rdr.read_u16::<BigEndian>()?;
+                let mut peers = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    peers.push(read_str(&mut rdr)?);
+                }
+                Frame::Hello {
+                    node_id,
+                    advertise,
+                    peers,
+                    nonce: read_bytes(&mut rdr)?,
+                    proof: read_bytes(&mut rdr)?,
+                }
+            }
+            FRAME_AUTH => Frame::Auth {
+                proof: read_bytes(&mut rdr)?,
+            },
+            FRAME_SUBSCRIBE => Frame::Subscribe {
+                share_group: read_opt_str(&mut rdr)?,
+                filter: read_str(&mut rdr)?,
+            },
+            FRAME_UNSUBSCRIBE => Frame::Unsubscribe {
+                share_group: read_opt_str(&mut rdr)?,
+                filter: read_str(&mut rdr)?,
+            },
+            FRAME_PUBLISH => {
+                let share_group = read_opt_str(&mut rdr)?;
+                let client_id = read_str(&mut rdr)?;
+                let topic = read_str(&mut rdr)?;
+                let retain = rdr.read_u8()? == 1;
+                let expiry_at = rdr.read_u64::<BigEndian>()?;
+                let mut rest = Vec::new();
+                rdr.read_to_end(&mut rest)?;
+                let body = decode_body(
+                    &topic,
+                    if expiry_at == 0 {
+                        None
+                    } else {
+                        Some(expiry_at)
+                    },
+                    &rest,
+                )?;
+                Frame::Publish {
+                    share_group,
+                    message: Box::new(
+                        Message::new(
+                            client_id,
+                            body.topic,
+                            body.qos,
+                            retain,
+                            body.payload,
+                            body.user_properties,
+                        )
+                        .with_options(body.options),
+                    ),
+                }
+            }
+            FRAME_CLIENT_CONNECTED => Frame::ClientConnected {
+                client_id: read_str(&mut rdr)?,
+            },
+            FRAME_MEMBERS => {
+                let share_group = read_str(&mut rdr)?;
+                let filter = read_str(&mut rdr)?;
+                let count = rdr.read_u32::<BigEndian>()?;
+                let mut members = Vec::with_capacity(count.min(1024) as usize);
+                for _ in 0..count {
+                    members.push(read_str(&mut rdr)?);
+                }
+                Frame::Members {
+                    share_group,
+                    filter,
+                    members,
+                }
+            }
+            FRAME_RAFT => Frame::Raft(read_raft(&mut rdr)?),
+            t => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown frame type {}", t),
+                ));
+            }
+        };
+        Ok(frame)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, w: &mut W) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt as _;
+        w.write_all(&self.encode()?).await
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Self> {
+        use tokio::io::AsyncReadExt as _;
+        let len = r.read_u32().await?;
+        if len == 0 || len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid frame length {}", len),
+            ));
+        }
+        let mut body = vec![0u8; len as usize];
+        r.read_exact(&mut body).await?;
+        Self::decode(&body)
+    }
+}
+
+fn write_bytes<W: Write>(w: &mut W, v: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(v.len() as u32)?;
+    w.write_all(v)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>()?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_raft<W: Write>(w: &mut W, message: &raft::Message) -> io::Result<()> {
+    use raft::Message::*;
+    match message {
+        Vote {
+            term,
+            last_index,
+            last_term,
+        } => {
+            w.write_u8(RAFT_VOTE)?;
+            w.write_u64::<BigEndian>(*term)?;
+            w.write_u64::<BigEndian>(*last_index)?;
+            w.write_u64::<BigEndian>(*last_term)?;
+        }
+        VoteReply { term, granted } => {
+            w.write_u8(RAFT_VOTE_REPLY)?;
+            w.write_u64::<BigEndian>(*term)?;
+            w.write_u8(*granted as u8)?;
+        }
+        Append {
+            term,
+            prev_index,
+            prev_term,
+            entries,
+            commit,
+        } => {
+            w.write_u8(RAFT_APPEND)?;
+            w.write_u64::<BigEndian>(*term)?;
+            w.write_u64::<BigEndian>(*prev_index)?;
+            w.write_u64::<BigEndian>(*prev_term)?;
+            w.write_u64::<BigEndian>(*commit)?;
+            w.write_u32::<BigEndian>(entries.len() as u32)?;
+            for entry in entries {
+                w.write_u64::<BigEndian>(entry.term)?;
+                w.write_u64::<BigEndian>(entry.index)?;
+                write_bytes(w, &entry.data)?;
+            }
+        }
+        AppendReply {
+            term,
+            success,
+            last_index,
+        } => {
+            w.write_u8(RAFT_APPEND_REPLY)?;
+            w.write_u64::<BigEndian>(*term)?;
+            w.write_u8(*success as u8)?;
+            w.write_u64::<BigEndian>(*last_index)?;
+        }
+        Snapshot {
+            term,
+            index,
+            snapshot_term,
+            data,
+        } => {
+            w.write_u8(RAFT_SNAPSHOT)?;
+            w.write_u64::<BigEndian>(*term)?;
+            w.write_u64::<BigEndian>(*index)?;
+            w.write_u64::<BigEndian>(*snapshot_term)?;
+            write_bytes(w, data)?;
+        }
+        Propose { data } => {
+            w.write_u8(RAFT_PROPOSE)?;
+            write_bytes(w, data)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_raft<R: Read>(r: &mut R) -> io::Result<raft::Message> {
+    use raft::Message::*;
+    let message = match r.read_u8()? {
+        RAFT_VOTE => Vote {
+            term: r.read_u64::<BigEndian>()?,
+            last_index: r.read_u64::<BigEndian>()?,
+            last_term: r.read_u64::<BigEndian>()?,
+        },
+        RAFT_VOTE_REPLY => VoteReply {
+            term: r.read_u64::<BigEndian>()?,
+            granted: r.read_u8()? == 1,
+        },
+        RAFT_APPEND => {
+            let term = r.read_u64::<BigEndian>()?;
+            let prev_index = r.read_u64::<BigEndian>()?;
+            let prev_term = r.read_u64::<BigEndian>()?;
+            let commit = r.read_u64::<BigEndian>()?;
+            let count = r.read_u32::<BigEndian>()?;
+            let mut entries = Vec::with_capacity(count.min(1024) as usize);
+            for _ in 0..count {
+                entries.push(Entry {
+                    term: r.read_u64::<BigEndian>()?,
+                    index: r.read_u64::<BigEndian>()?,
+                    data: read_bytes(r)?,
+                });
+            }
+            Append {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            }
+        }
+        RAFT_APPEND_REPLY => AppendReply {
+            term: r.read_u64::<BigEndian>()?,
+            success: r.read_u8()? == 1,
+            last_index: r.read_u64::<BigEndian>()?,
+        },
+        RAFT_SNAPSHOT => Snapshot {
+            term: r.read_u64::<BigEndian>()?,
+            index: r.read_u64::<BigEndian>()?,
+            snapshot_term: r.read_u64::<BigEndian>()?,
+            data: read_bytes(r)?,
+        },
+        RAFT_PROPOSE => Propose {
+            data: read_bytes(r)?,
+        },
+        t => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown raft message type {}", t),
+            ));
+        }
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::mqtt::{QoS, protocol::property::PropertyUser};
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let message = Message::new(
+            "c1".to_string(),
+            "a/b".to_string(),
+            QoS::AtLeastOnce,
+            true,
+            Bytes::from_static(b"payload"),
+            vec![PropertyUser {
+                key: "k".to_string(),
+                value: "v".to_string(),
+            }],
+        );
+        let frame = Frame::Publish {
+            share_group: Some("g".to_string()),
+            message: Box::new(message),
+        };
+        let buf = frame.encode().unwrap();
+        let Frame::Publish {
+            share_group,
+            message,
+        } = Frame::decode(&buf[4..]).unwrap()
+        else {
+            panic!("unexpected frame");
+        };
+        assert_eq!(share_group.as_deref(), Some("g"));
+        assert_eq!(message.client_id, "c1");
+        assert_eq!(message.topic, "a/b");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert!(message.retain);
+        assert_eq!(&message.payload[..], b"payload");
+        assert_eq!(message.user_properties[0].value, "v");
+
+        let buf = Frame::Hello {
+            node_id: "n1".to_string(),
+            advertise: "127.0.0.1:1108".to_string(),
+            peers: vec!["127.0.0.1:1109".to_string()],
+            nonce: vec![1; 16],
+            proof: vec![],
+        }
+        .encode()
+        .unwrap();
+        assert!(matches!(
+            Frame::decode(&buf[4..]).unwrap(),
+            Frame::Hello { node_id, peers, nonce, proof, .. }
+                if node_id == "n1" && peers.len() == 1 && nonce == [1; 16] && proof.is_empty()
+        ));
+
+        let buf = Frame::Auth { proof: vec![2; 32] }.encode().unwrap();
+        assert!(matches!(
+            Frame::decode(&buf[4..]).unwrap(),
+            Frame::Auth { proof } if proof == [2; 32]
+        ));
+
+        let buf = Frame::Members {
+            share_group: "g".to_string(),
+            filter: "a/#".to_string(),
+            members: vec!["c1".to_string(), "c2".to_string()],
+        }
+        .encode()
+        .unwrap();
+        assert!(matches!(
+            Frame::decode(&buf[4..]).unwrap(),
+            Frame::Members { share_group, members, .. } if share_group == "g" && members == ["c1", "c2"]
+        ));
+
+        let append = raft::Message::Append {
+            term: 3,
+            prev_index: 7,
+            prev_term: 2,
+            entries: vec![Entry {
+                term: 3,
+                index: 8,
+                data: b"entry".to_vec(),
+            }],
+            commit: 7,
+        };
+        let buf = Frame::Raft(append.clone()).encode().unwrap();
+        assert!(matches!(Frame::decode(&buf[4..]).unwrap(), Frame::Raft(m) if m == append));
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// a subscription as seen by the other nodes: share group and topic filter
+pub type Filter = (Option<String>, String);
+
+/// the subscriptions of local clients, counted per filter so peers only hear
+/// about the first subscriber and the last unsubscriber
+#[derive(Default)]
+pub struct Interest {
+    clients: HashMap<String, HashSet<Filter>>,
+    filters: HashMap<Filter, usize>,
+    // the local members of the shared filters, peers hear about each change
+    members: HashMap<Filter, BTreeSet<String>>,
+}
+
+impl Interest {
+    /// returns true when no other local client had subscribed to the filter
+    pub fn subscribe(&mut self, client_id: &str, filter: Filter) -> bool {
+        if !self
+            .clients
+            .entry(client_id.to_string())
+            .or_default()
+            .insert(filter.clone())
+        {
+            return false;
+        }
+        if filter.0.is_some() {
+            self.members
+                .entry(filter.clone())
+                .or_default()
+                .insert(client_id.to_string());
+        }
+        let count = self.filters.entry(filter).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// returns true when the client was the last local subscriber of the filter
+    pub fn unsubscribe(&mut self, client_id: &str, filter: &Filter) -> bool {
+        let Some(filters) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+        if !filters.remove(filter) {
+            return false;
+        }
+        if filters.is_empty() {
+            self.clients.remove(client_id);
+        }
+        self.leave(client_id, filter);
+        self.release(filter)
+    }
+
+    /// returns the filters no local client subscribes to any more
+    pub fn remove_client(&mut self, client_id: &str) -> Vec<Filter> {
+        let Some(filters) = self.clients.remove(client_id) else {
+            return vec![];
+        };
+        filters
+            .into_iter()
+            .filter(|filter| {
+                self.leave(client_id, filter);
+                self.release(filter)
+            })
+            .collect()
+    }
+
+    pub fn filters(&self) -> impl Iterator<Item = &Filter> {
+        self.filters.keys()
+    }
+
+    /// the shared filters a local client subscribes to
+    pub fn shared_filters(&self, client_id: &str) -> Vec<Filter> {
+        self.clients
+            .get(client_id)
+            .map(|filters| filters.iter().filter(|f| f.0.is_some()).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// the local members of a shared filter, empty once the last one left
+    pub fn members(&self, filter: &Filter) -> Vec<String> {
+        self.members
+            .get(filter)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn leave(&mut self, client_id: &str, filter: &Filter) {
+        if let Some(members) = self.members.get_mut(filter) {
+            members.remove(client_id);
+            if members.is_empty() {
+                self.members.remove(filter);
+            }
+        }
+    }
+
+    fn release(&mut self, filter: &Filter) -> bool {
+        match self.filters.get_mut(filter) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                self.filters.remove(filter);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interest_refcount() {
+        let mut interest = Interest::default();
+        let filter: Filter = (None, "a/#".to_string());
+        let shared: Filter = (Some("g".to_string()), "a/#".to_string());
+
+        assert!(interest.subscribe("c1", filter.clone()));
+        assert!(!interest.subscribe("c1", filter.clone()));
+        assert!(!interest.subscribe("c2", filter.clone()));
+        assert!(interest.subscribe("c2", shared.clone()));
+        assert!(!interest.subscribe("c1", shared.clone()));
+        assert_eq!(interest.filters().count(), 2);
+        assert_eq!(interest.members(&shared), ["c1", "c2"]);
+        assert_eq!(interest.shared_filters("c1"), [shared.clone()]);
+        assert!(!interest.unsubscribe("c1", &shared));
+        assert_eq!(interest.members(&shared), ["c2"]);
+
+        assert!(!interest.unsubscribe("c1", &filter));
+        assert!(!interest.unsubscribe("c3", &filter));
+        let mut removed = interest.remove_client("c2");
+        removed.sort();
+        assert_eq!(removed, vec![filter, shared.clone()]);
+        assert_eq!(interest.filters().count(), 0);
+        assert!(interest.members(&shared).is_empty());
+    }
+}
@@ -0,0 +1,636 @@
+mod frame;
+mod interest;
+mod peer;
+mod raft;
+pub(crate) mod replicated;
+pub(crate) mod share;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::CONFIG;
+use crate::config::ReplicationConfig;
+use crate::mqtt::{QoS, helper::BrokerHelper};
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::operator::sink::remote::RemoteNodeSink;
+use crate::processor::message::Message;
+
+use frame::Frame;
+use interest::{Filter, Interest};
+use replicated::Replicated;
+
+const DEFAULT_RECONNECT_INTERVAL_SECS: u64 = 5;
+
+#[derive(Debug, Error)]
+pub enum ClusterError {
+    #[error("Channel send error")]
+    ChannelSendError,
+    #[error("cluster listening on {0} without a secret, set node.cluster.secret")]
+    NoSecret(String),
+}
+
+pub(crate) enum ClusterCommand {
+    LocalSubscribe {
+        client_id: String,
+        share_group: Option<String>,
+        filter: String,
+    },
+    LocalUnsubscribe {
+        client_id: String,
+        share_group: Option<String>,
+        filter: String,
+    },
+    LocalRemoveClient {
+        client_id: String,
+    },
+    ClientConnected {
+        client_id: String,
+    },
+    Forward {
+        node_id: String,
+        share_group: Option<String>,
+        message: Box<Message>,
+    },
+    PeerUp {
+        node_id: String,
+        addr: String,
+        advertise: String,
+        peers: Vec<String>,
+        tx: mpsc::Sender<Frame>,
+        accepted: oneshot::Sender<bool>,
+    },
+    PeerDown {
+        node_id: String,
+    },
+    DialStopped {
+        addr: String,
+    },
+    InboundOpen {
+        node_id: String,
+        conn_id: u64,
+        advertise: String,
+        peers: Vec<String>,
+    },
+    Inbound {
+        node_id: String,
+        frame: Frame,
+    },
+    InboundClosed {
+        node_id: String,
+        conn_id: u64,
+    },
+    // a change of the broker state for the replicated store
+    Replicate(replicated::Command),
+}
+
+/// whether only this host reaches `addr`, a host:port or an ip:port
+pub fn is_loopback(addr: &str) -> bool {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+/// client id the subscriptions of a peer are registered under in the matcher
+pub fn remote_client_id(node_id: &str) -> String {
+    format!("$cluster/{}", node_id)
+}
+
+#[derive(Clone)]
+pub struct ClusterHelper {
+    cluster_tx: mpsc::Sender<ClusterCommand>,
+}
+
+impl ClusterHelper {
+    pub async fn local_subscribe(
+        &self,
+        client_id: String,
+        share_group: Option<String>,
+        filter: String,
+    ) -> Result<(), ClusterError> {
+        self.cluster_tx
+            .send(ClusterCommand::LocalSubscribe {
+                client_id,
+                share_group,
+                filter,
+            })
+            .await
+            .map_err(|_| ClusterError::ChannelSendError)
+    }
+
+    pub async fn local_unsubscribe(
+        &self,
+        client_id: String,
+        share_group: Option<String>,
+        filter: String,
+    ) -> Result<(), ClusterError> {
+        self.cluster_tx
+            .send(ClusterCommand::LocalUnsubscribe {
+                client_id,
+                share_group,
+                filter,
+            })
+            .await
+            .map_err(|_| ClusterError::ChannelSendError)
+    }
+
+    pub async fn local_remove_client(&self, client_id: String) -> Result<(), ClusterError> {
+        self.cluster_tx
+            .send(ClusterCommand::LocalRemoveClient { client_id })
+            .await
+            .map_err(|_| ClusterError::ChannelSendError)
+    }
+
+    /// a client connected to this node, its sessions on other nodes are taken over
+    pub async fn client_connected(&self, client_id: String) -> Result<(), ClusterError> {
+        self.cluster_tx
+            .send(ClusterCommand::ClientConnected { client_id })
+            .await
+            .map_err(|_| ClusterError::ChannelSendError)
+    }
+
+    /// forward a message to the node owning the subscriber, dropped when the queue is full
+    pub fn forward(
+        &self,
+        node_id: &str,
+        share_group: Option<String>,
+        message: Message,
+    ) -> Result<(), ClusterError> {
+        self.cluster_tx
+            .try_send(ClusterCommand::Forward {
+                node_id: node_id.to_string(),
+                share_group,
+                message: Box::new(message),
+            })
+            .map_err(|_| ClusterError::ChannelSendError)
+    }
+}
+
+/// Cluster mode: nodes exchange the subscriptions of their clients and forward
+/// publishes to the nodes with matching subscribers.
+///
+/// Every node dials each peer and only writes to that connection, the frames a
+/// node receives arrive on the connections its peers dialed.
+pub struct Cluster {
+    node_id: String,
+    listen: String,
+    advertise: String,
+    peers: Vec<String>,
+    gossip: bool,
+    reconnect_interval: u64,
+    replication: Option<ReplicationConfig>,
+    handshake: peer::Handshake,
+
+    cluster_tx: mpsc::Sender<ClusterCommand>,
+    cluster_rx: Option<mpsc::Receiver<ClusterCommand>>,
+}
+
+struct State {
+    interest: Interest,
+    // node id -> (dialed address, frame queue)
+    outbound: HashMap<String, (String, mpsc::Sender<Frame>)>,
+    dialing: HashSet<String>,
+    // node id -> current inbound connection
+    inbound: HashMap<String, u64>,
+    replicated: Option<Replicated>,
+}
+
+impl Cluster {
+    /// fails when the cluster port is reachable from other hosts and has no secret
+    pub fn new() -> Result<Self, ClusterError> {
+        let config = &CONFIG.get().unwrap().node;
+        let cluster = config.cluster.as_ref().unwrap();
+        if cluster.secret.is_none() && !is_loopback(&cluster.listen) {
+            return Err(ClusterError::NoSecret(cluster.listen.clone()));
+        }
+        let (cluster_tx, cluster_rx) = mpsc::channel(4096);
+        let advertise = cluster
+            .advertise
+            .clone()
+            .unwrap_or_else(|| cluster.listen.clone());
+
+        Ok(Cluster {
+            node_id: config.id.clone(),
+            listen: cluster.listen.clone(),
+            handshake: peer::Handshake::new(
+                config.id.clone(),
+                advertise.clone(),
+                cluster.secret.as_deref(),
+            ),
+            advertise,
+            peers: cluster.peers.clone(),
+            gossip: cluster.gossip.unwrap_or(false),
+            reconnect_interval: cluster
+                .reconnect_interval
+                .unwrap_or(DEFAULT_RECONNECT_INTERVAL_SECS),
+            replication: cluster.replication.clone(),
+            cluster_tx,
+            cluster_rx: Some(cluster_rx),
+        })
+    }
+
+    pub fn helper(&self) -> ClusterHelper {
+        ClusterHelper {
+            cluster_tx: self.cluster_tx.clone(),
+        }
+    }
+
+    fn hello(&self, peers: Vec<String>) -> Frame {
+        Frame::Hello {
+            node_id: self.node_id.clone(),
+            advertise: self.advertise.clone(),
+            peers,
+            nonce: vec![],
+            proof: vec![],
+        }
+    }
+
+    fn dial(&self, state: &mut State, addr: &str) {
+        if addr == self.advertise || addr == self.listen || !state.dialing.insert(addr.to_string())
+        {
+            return;
+        }
+        tokio::spawn(peer::dial(
+            addr.to_string(),
+            self.handshake.clone(),
+            self.cluster_tx.clone(),
+            self.reconnect_interval,
+        ));
+    }
+
+    /// returns the peer listener and the cluster loop tasks
+    pub fn run(
+        mut self,
+        operator_helper: OperatorHelper,
+        broker_helper: BrokerHelper,
+    ) -> Vec<JoinHandle<()>> {
+        let mut cluster_rx = self.cluster_rx.take().unwrap();
+        info!(
+            "cluster node {} advertising {}, {} static peers",
+            self.node_id,
+            self.advertise,
+            self.peers.len()
+        );
+
+        let listener = tokio::spawn(peer::listen(
+            self.listen.clone(),
+            self.handshake.clone(),
+            self.cluster_tx.clone(),
+        ));
+
+        let replicated = self.replication.as_ref().and_then(|config| {
+            Replicated::open(&self.node_id, config, self.cluster_tx.clone())
+                .inspect_err(|e| {
+                    warn!(
+                        "failed to open replicated store {}: {}, broker state kept on this node only",
+                        config.path, e
+                    )
+                })
+                .ok()
+        });
+        let heartbeat = self.replication.as_ref().map_or(1000, |c| c.heartbeat_ms);
+
+        let cluster = tokio::spawn(async move {
+            let mut state = State {
+                interest: Interest::default(),
+                outbound: HashMap::new(),
+                dialing: HashSet::new(),
+                inbound: HashMap::new(),
+                replicated,
+            };
+            for addr in self.peers.clone() {
+                self.dial(&mut state, &addr);
+            }
+            let mut raft_tick = tokio::time::interval(Duration::from_millis(heartbeat.max(1)));
+
+            loop {
+                tokio::select! {
+                    cmd = cluster_rx.recv() => {
+                        let Some(cmd) = cmd else {
+                            break;
+                        };
+                        self.handle_command(&mut state, cmd, &operator_helper, &broker_helper)
+                            .await;
+                    }
+                    _ = raft_tick.tick(), if state.replicated.is_some() => {
+                        if let Some(replicated) = state.replicated.as_mut() {
+                            replicated.tick();
+                        }
+                    }
+                }
+                Self::replicate(&mut state, &broker_helper).await;
+            }
+        });
+
+        vec![listener, cluster]
+    }
+
+    async fn broadcast(state: &State, frame: Frame) {
+        for (_, tx) in state.outbound.values() {
+            let _ = tx.send(frame.clone()).await;
+        }
+    }
+
+    // what the raft member has to send and apply once its changes are on disk
+    async fn replicate(state: &mut State, broker_helper: &BrokerHelper) {
+        let Some(replicated) = state.replicated.as_mut() else {
+            return;
+        };
+        let (messages, commands) = replicated.ready().await;
+        for (node_id, message) in messages {
+            if let Some((_, tx)) = state.outbound.get(&node_id)
+                && tx.try_send(Frame::Raft(message)).is_err()
+            {
+                debug!("cluster peer {} queue full, dropping raft message", node_id);
+            }
+        }
+        for command in commands {
+            replicated::apply(command, broker_helper).await;
+        }
+    }
+
+    // the local members of a shared filter, peers balance their messages over them
+    async fn broadcast_members(state: &State, filter: &Filter) {
+        let (Some(share_group), topic) = filter else {
+            return;
+        };
+        Self::broadcast(
+            state,
+            Frame::Members {
+                share_group: share_group.clone(),
+                filter: topic.clone(),
+                members: state.interest.members(filter),
+            },
+        )
+        .await;
+    }
+
+    async fn handle_command(
+        &self,
+        state: &mut State,
+        cmd: ClusterCommand,
+        operator_helper: &OperatorHelper,
+        broker_helper: &BrokerHelper,
+    ) {
+        use ClusterCommand::*;
+        match cmd {
+            LocalSubscribe {
+                client_id,
+                share_group,
+                filter,
+            } => {
+                let key = (share_group, filter);
+                if state.interest.subscribe(&client_id, key.clone()) {
+                    Self::broadcast(
+                        state,
+                        Frame::Subscribe {
+                            share_group: key.0.clone(),
+                            filter: key.1.clone(),
+                        },
+                    )
+                    .await;
+                }
+                Self::broadcast_members(state, &key).await;
+            }
+            LocalUnsubscribe {
+                client_id,
+                share_group,
+                filter,
+            } => {
+                let key = (share_group, filter);
+                let released = state.interest.unsubscribe(&client_id, &key);
+                Self::broadcast_members(state, &key).await;
+                if released {
+                    let (share_group, filter) = key;
+                    Self::broadcast(
+                        state,
+                        Frame::Unsubscribe {
+                            share_group,
+                            filter,
+                        },
+                    )
+                    .await;
+                }
+            }
+            LocalRemoveClient { client_id } => {
+                let shared = state.interest.shared_filters(&client_id);
+                let released = state.interest.remove_client(&client_id);
+                for filter in &shared {
+                    Self::broadcast_members(state, filter).await;
+                }
+                for (share_group, filter) in released {
+                    Self::broadcast(
+                        state,
+                        Frame::Unsubscribe {
+                            share_group,
+                            filter,
+                        },
+                    )
+                    .await;
+                }
+            }
+            ClientConnected { client_id } => {
+                Self::broadcast(state, Frame::ClientConnected { client_id }).await;
+            }
+            Forward {
+                node_id,
+                share_group,
+                message,
+            } => {
+                if let Some((_, tx)) = state.outbound.get(&node_id)
+                    && tx
+                        .try_send(Frame::Publish {
+                            share_group,
+                            message,
+                        })
+                        .is_err()
+                {
+                    debug!("cluster peer {} queue full, dropping message", node_id);
+                }
+            }
+            PeerUp {
+                node_id,
+                addr,
+                advertise,
+                peers,
+                tx,
+                accepted,
+            } => {
+                let duplicate = state
+                    .outbound
+                    .get(&node_id)
+                    .is_some_and(|(current, _)| *current != addr);
+                if node_id == self.node_id || duplicate {
+                    debug!("cluster ignores {} at {}", node_id, addr);
+                    let _ = accepted.send(false);
+                    return;
+                }
+                let _ = accepted.send(true);
+
+                // snapshot of the local subscriptions, deltas follow through broadcast
+                for (share_group, filter) in state.interest.filters() {
+                    let _ = tx
+                        .send(Frame::Subscribe {
+                            share_group: share_group.clone(),
+                            filter: filter.clone(),
+                        })
+                        .await;
+                }
+                for (share_group, filter) in state.interest.filters() {
+                    if let Some(share_group) = share_group {
+                        let _ = tx
+                            .send(Frame::Members {
+                                share_group: share_group.clone(),
+                                filter: filter.clone(),
+                                members: state
+                                    .interest
+                                    .members(&(Some(share_group.clone()), filter.clone())),
+                            })
+                            .await;
+                    }
+                }
+                state.outbound.insert(node_id, (addr, tx));
+
+                let known: Vec<String> = state
+                    .outbound
+                    .values()
+                    .map(|(addr, _)| addr.clone())
+                    .collect();
+                Self::broadcast(state, self.hello(known)).await;
+
+                if self.gossip {
+                    self.dial(state, &advertise);
+                    for addr in peers {
+                        self.dial(state, &addr);
+                    }
+                }
+            }
+            PeerDown { node_id } => {
+                state.outbound.remove(&node_id);
+            }
+            DialStopped { addr } => {
+                state.dialing.remove(&addr);
+            }
+            InboundOpen {
+                node_id,
+                conn_id,
+                advertise,
+                peers,
+            } => {
+                if state.inbound.insert(node_id.clone(), conn_id).is_some() {
+                    // interest of the previous connection is resent on the new one
+                    share::remove_node(&node_id);
+                    let _ = operator_helper
+                        .remove_client(remote_client_id(&node_id))
+                        .await;
+                }
+                // the peer dialed us, dial back so our subscriptions reach it
+                self.dial(state, &advertise);
+                if self.gossip {
+                    for addr in peers {
+                        self.dial(state, &addr);
+                    }
+                }
+            }
+            Inbound { node_id, frame } => {
+                self.handle_frame(state, node_id, frame, operator_helper, broker_helper)
+                    .await;
+            }
+            InboundClosed { node_id, conn_id } => {
+                if state.inbound.get(&node_id) == Some(&conn_id) {
+                    state.inbound.remove(&node_id);
+                    share::remove_node(&node_id);
+                    let _ = operator_helper
+                        .remove_client(remote_client_id(&node_id))
+                        .await;
+                }
+            }
+            Replicate(command) => {
+                if let Some(replicated) = state.replicated.as_mut() {
+                    replicated.propose(command);
+                }
+            }
+        }
+    }
+
+    async fn handle_frame(
+        &self,
+        state: &mut State,
+        node_id: String,
+        frame: Frame,
+        operator_helper: &OperatorHelper,
+        broker_helper: &BrokerHelper,
+    ) {
+        match frame {
+            Frame::Hello { peers, .. } => {
+                if self.gossip {
+                    for addr in peers {
+                        self.dial(state, &addr);
+                    }
+                }
+            }
+            // answered once, while the connection opens
+            Frame::Auth { .. } => {}
+            Frame::Subscribe {
+                share_group,
+                filter,
+            } => {
+                debug!("cluster peer {} subscribed {}", node_id, filter);
+                let _ = operator_helper
+                    .subscribe(
+                        remote_client_id(&node_id),
+                        share_group.clone(),
+                        filter,
+                        QoS::ExactlyOnce,
+                        false,
+                        None,
+                        false,
+                        RemoteNodeSink::new(node_id, share_group, self.helper()),
+                    )
+                    .await;
+            }
+            Frame::Unsubscribe {
+                share_group,
+                filter,
+            } => {
+                debug!("cluster peer {} unsubscribed {}", node_id, filter);
+                if let Some(share_group) = &share_group {
+                    share::set_members(&node_id, share_group.clone(), filter.clone(), vec![]);
+                }
+                let _ = operator_helper
+                    .unsubscribe(remote_client_id(&node_id), share_group, filter)
+                    .await;
+            }
+            Frame::Publish {
+                share_group,
+                message,
+            } => {
+                let _ = operator_helper.cluster_publish(share_group, *message).await;
+            }
+            Frame::Members {
+                share_group,
+                filter,
+                members,
+            } => {
+                share::set_members(&node_id, share_group, filter, members);
+            }
+            Frame::ClientConnected { client_id } => {
+                if let Err(e) = broker_helper.take_over(&client_id).await {
+                    warn!("cluster take over of {} failed: {}", client_id, e);
+                }
+            }
+            Frame::Raft(message) => {
+                if let Some(replicated) = state.replicated.as_mut() {
+                    replicated.step(&node_id, message);
+                }
+            }
+        }
+    }
+}
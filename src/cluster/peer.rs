@@ -0,0 +1,322 @@
+use std::time::Duration;
+
+use ring::hmac;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+use super::ClusterCommand;
+use super::frame::Frame;
+
+const HELLO_TIMEOUT_SECS: u64 = 5;
+const PEER_QUEUE: usize = 1024;
+const NONCE_LEN: usize = 16;
+
+/// this node in the handshake of a peer connection. With a cluster secret each end answers
+/// the challenge of the other with an HMAC of it, tagged with its side so that an answer
+/// cannot be played back from the other side
+#[derive(Clone)]
+pub(super) struct Handshake {
+    node_id: String,
+    advertise: String,
+    secret: Option<hmac::Key>,
+}
+
+impl Handshake {
+    pub(super) fn new(node_id: String, advertise: String, secret: Option<&str>) -> Self {
+        Handshake {
+            node_id,
+            advertise,
+            secret: secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+        }
+    }
+
+    fn hello(&self, nonce: &[u8], proof: Vec<u8>) -> Frame {
+        Frame::Hello {
+            node_id: self.node_id.clone(),
+            advertise: self.advertise.clone(),
+            peers: vec![],
+            nonce: nonce.to_vec(),
+            proof,
+        }
+    }
+
+    fn signed(side: &str, nonce: &[u8], node_id: &str) -> Vec<u8> {
+        [side.as_bytes(), nonce, node_id.as_bytes()].concat()
+    }
+
+    // the answer of this node to a challenge, empty without a secret
+    fn sign(&self, side: &str, nonce: &[u8]) -> Vec<u8> {
+        self.secret.as_ref().map_or_else(Vec::new, |key| {
+            hmac::sign(key, &Self::signed(side, nonce, &self.node_id))
+                .as_ref()
+                .to_vec()
+        })
+    }
+
+    fn verify(&self, side: &str, nonce: &[u8], node_id: &str, proof: &[u8]) -> std::io::Result<()> {
+        let Some(key) = &self.secret else {
+            return Ok(());
+        };
+        hmac::verify(key, &Self::signed(side, nonce, node_id), proof).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("node {} failed the cluster secret check", node_id),
+            )
+        })
+    }
+}
+
+/// keeps an outbound connection to a peer, this node only writes to it
+pub(super) async fn dial(
+    addr: String,
+    handshake: Handshake,
+    cluster_tx: mpsc::Sender<ClusterCommand>,
+    reconnect_interval: u64,
+) {
+    loop {
+        match connect(&addr, &handshake).await {
+            Ok((stream, node_id, advertise, peers)) => {
+                let (tx, mut rx) = mpsc::channel::<Frame>(PEER_QUEUE);
+                let (accept_tx, accept_rx) = oneshot::channel();
+                if cluster_tx
+                    .send(ClusterCommand::PeerUp {
+                        node_id: node_id.clone(),
+                        addr: addr.clone(),
+                        advertise,
+                        peers,
+                        tx,
+                        accepted: accept_tx,
+                    })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                if !accept_rx.await.unwrap_or(false) {
+                    let _ = cluster_tx
+                        .send(ClusterCommand::DialStopped { addr: addr.clone() })
+                        .await;
+                    return;
+                }
+                info!("cluster peer {} connected: {}", node_id, addr);
+
+                let (mut rd, mut wr) = stream.into_split();
+                let mut buf = [0u8; 1];
+                loop {
+                    tokio::select! {
+                        frame = rx.recv() => {
+                            let Some(frame) = frame else {
+                                break;
+                            };
+                            if let Err(e) = frame.write(&mut wr).await {
+                                warn!("cluster peer {} write error: {}", node_id, e);
+                                break;
+                            }
+                        }
+                        // the peer never writes after its hello, a read only returns on close
+                        _ = rd.read(&mut buf) => {
+                            break;
+                        }
+                    }
+                }
+                info!("cluster peer {} disconnected: {}", node_id, addr);
+                let _ = cluster_tx.send(ClusterCommand::PeerDown { node_id }).await;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                warn!("cluster peer {} rejected: {}", addr, e);
+            }
+            Err(e) => {
+                debug!("cluster peer {} unreachable: {}", addr, e);
+            }
+        }
+        time::sleep(Duration::from_secs(reconnect_interval)).await;
+    }
+}
+
+async fn connect(
+    addr: &str,
+    handshake: &Handshake,
+) -> std::io::Result<(TcpStream, String, String, Vec<String>)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let challenge = rand::random::<[u8; NONCE_LEN]>();
+    handshake
+        .hello(&challenge, vec![])
+        .write(&mut stream)
+        .await?;
+    let reply = time::timeout(
+        Duration::from_secs(HELLO_TIMEOUT_SECS),
+        Frame::read(&mut stream),
+    )
+    .await??;
+    match reply {
+        Frame::Hello {
+            node_id,
+            advertise,
+            peers,
+            nonce,
+            proof,
+        } => {
+            handshake.verify("accept", &challenge, &node_id, &proof)?;
+            Frame::Auth {
+                proof: handshake.sign("dial", &nonce),
+            }
+            .write(&mut stream)
+            .await?;
+            Ok((stream, node_id, advertise, peers))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected hello",
+        )),
+    }
+}
+
+/// accepts connections from peers, this node only reads from them
+pub(super) async fn listen(
+    addr: String,
+    handshake: Handshake,
+    cluster_tx: mpsc::Sender<ClusterCommand>,
+) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("failed to bind cluster listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("cluster listening on {}", addr);
+
+    let mut conn_id = 0u64;
+    loop {
+        let Ok((stream, remote)) = listener.accept().await else {
+            continue;
+        };
+        conn_id += 1;
+        let handshake = handshake.clone();
+        let cluster_tx = cluster_tx.clone();
+        tokio::spawn(async move {
+            match serve(stream, conn_id, handshake, cluster_tx).await {
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    warn!("cluster connection from {} rejected: {}", remote, e);
+                }
+                Err(e) => debug!("cluster connection from {} closed: {}", remote, e),
+                Ok(()) => {}
+            }
+        });
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    conn_id: u64,
+    handshake: Handshake,
+    cluster_tx: mpsc::Sender<ClusterCommand>,
+) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    let first = time::timeout(
+        Duration::from_secs(HELLO_TIMEOUT_SECS),
+        Frame::read(&mut stream),
+    )
+    .await??;
+    let Frame::Hello {
+        node_id,
+        advertise,
+        peers,
+        nonce,
+        ..
+    } = first
+    else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected hello",
+        ));
+    };
+    let challenge = rand::random::<[u8; NONCE_LEN]>();
+    let proof = handshake.sign("accept", &nonce);
+    handshake
+        .hello(&challenge, proof)
+        .write(&mut stream)
+        .await?;
+    // nothing from the peer is taken before it answered the challenge
+    let auth = time::timeout(
+        Duration::from_secs(HELLO_TIMEOUT_SECS),
+        Frame::read(&mut stream),
+    )
+    .await??;
+    let Frame::Auth { proof } = auth else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected auth",
+        ));
+    };
+    handshake.verify("dial", &challenge, &node_id, &proof)?;
+
+    let _ = cluster_tx
+        .send(ClusterCommand::InboundOpen {
+            node_id: node_id.clone(),
+            conn_id,
+            advertise,
+            peers,
+        })
+        .await;
+
+    let result = loop {
+        match Frame::read(&mut stream).await {
+            Ok(frame) => {
+                if cluster_tx
+                    .send(ClusterCommand::Inbound {
+                        node_id: node_id.clone(),
+                        frame,
+                    })
+                    .await
+                    .is_err()
+                {
+                    break Ok(());
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    let _ = cluster_tx
+        .send(ClusterCommand::InboundClosed { node_id, conn_id })
+        .await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        let n1 = Handshake::new("n1".to_string(), String::new(), Some("s3cret"));
+        let n2 = Handshake::new("n2".to_string(), String::new(), Some("s3cret"));
+        let other = Handshake::new("n3".to_string(), String::new(), Some("other"));
+        let open = Handshake::new("n4".to_string(), String::new(), None);
+        let nonce = [7u8; NONCE_LEN];
+
+        let proof = n1.sign("dial", &nonce);
+        assert!(n2.verify("dial", &nonce, "n1", &proof).is_ok());
+        // another side, node, challenge or secret does not pass
+        assert!(n2.verify("accept", &nonce, "n1", &proof).is_err());
+        assert!(n2.verify("dial", &nonce, "n3", &proof).is_err());
+        assert!(n2.verify("dial", &[8u8; NONCE_LEN], "n1", &proof).is_err());
+        assert!(
+            n2.verify("dial", &nonce, "n3", &other.sign("dial", &nonce))
+                .is_err()
+        );
+        assert!(
+            n2.verify("dial", &nonce, "n4", &open.sign("dial", &nonce))
+                .is_err()
+        );
+
+        // without a secret there is nothing to check
+        assert!(open.sign("dial", &nonce).is_empty());
+        assert!(open.verify("dial", &nonce, "n1", &[]).is_ok());
+    }
+}
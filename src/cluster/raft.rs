@@ -0,0 +1,702 @@
+//! a small raft: leader election and log replication between the members of
+//! `[node.cluster.replication]`. The core only steps messages and ticks, the caller persists
+//! what `take_changes` returns before sending what `take_messages` returns, then applies what
+//! `take_committed` returns
+
+use std::collections::{HashMap, HashSet};
+
+// entries sent in one append, a follower far behind catches up over several heartbeats
+const MAX_APPEND_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    // empty for the entry a new leader appends to commit the entries of the previous terms
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Vote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    // last_index is what the follower matches on success, where to retry from otherwise
+    AppendReply {
+        term: u64,
+        success: bool,
+        last_index: u64,
+    },
+    // the state up to index, sent to a follower missing entries compacted away
+    Snapshot {
+        term: u64,
+        index: u64,
+        snapshot_term: u64,
+        data: Vec<u8>,
+    },
+    // forwarded by a follower to the leader
+    Propose {
+        data: Vec<u8>,
+    },
+}
+
+impl Message {
+    fn term(&self) -> Option<u64> {
+        match self {
+            Message::Vote { term, .. }
+            | Message::VoteReply { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendReply { term, .. }
+            | Message::Snapshot { term, .. } => Some(*term),
+            Message::Propose { .. } => None,
+        }
+    }
+}
+
+/// what has to be on disk before the messages go out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    HardState {
+        term: u64,
+        voted_for: Option<String>,
+    },
+    Append(Entry),
+    // the entries from this index on are dropped, a new leader overwrote them
+    Truncate(u64),
+    // installed from the leader, the state is replaced and the log is empty
+    Snapshot {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct Raft {
+    id: String,
+    peers: Vec<String>,
+
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+
+    // the entries after the snapshot
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: Vec<u8>,
+    commit: u64,
+    applied: u64,
+
+    next: HashMap<String, u64>,
+    matched: HashMap<String, u64>,
+    votes: HashSet<String>,
+
+    elapsed: u64,
+    timeout: u64,
+    election_ticks: u64,
+
+    changes: Vec<Change>,
+    messages: Vec<(String, Message)>,
+}
+
+impl Raft {
+    /// `election_ticks` without hearing from a leader start an election, randomized up to twice
+    /// as many so that the members do not all stand at once
+    pub fn new(id: String, members: &[String], election_ticks: u64) -> Self {
+        let mut raft = Raft {
+            peers: members.iter().filter(|m| **m != id).cloned().collect(),
+            id,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            log: vec![],
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: vec![],
+            commit: 0,
+            applied: 0,
+            next: HashMap::new(),
+            matched: HashMap::new(),
+            votes: HashSet::new(),
+            elapsed: 0,
+            timeout: 0,
+            election_ticks: election_ticks.max(1),
+            changes: vec![],
+            messages: vec![],
+        };
+        raft.reset_timeout();
+        raft
+    }
+
+    /// the persisted state, read back on start
+    pub fn restore(
+        &mut self,
+        term: u64,
+        voted_for: Option<String>,
+        snapshot: Option<(u64, u64, Vec<u8>)>,
+        log: Vec<Entry>,
+    ) {
+        self.term = term;
+        self.voted_for = voted_for;
+        if let Some((index, term, data)) = snapshot {
+            self.snapshot_index = index;
+            self.snapshot_term = term;
+            self.snapshot = data;
+            self.commit = index;
+            self.applied = index;
+        }
+        self.log = log;
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn commit(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_index, |e| e.index)
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    // None once compacted away
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot_index - 1) as usize)
+            .map(|e| e.term)
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn reset_timeout(&mut self) {
+        self.elapsed = 0;
+        self.timeout = rand::random_range(self.election_ticks..self.election_ticks * 2 + 1);
+    }
+
+    fn hard_state(&mut self) {
+        self.changes.push(Change::HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+        });
+    }
+
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(String, Message)> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// the entries committed since the last call, to apply in order
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        if self.applied >= self.commit {
+            return vec![];
+        }
+        let from = (self.applied - self.snapshot_index) as usize;
+        let to = (self.commit - self.snapshot_index) as usize;
+        self.applied = self.commit;
+        self.log[from..to].to_vec()
+    }
+
+    /// drop the applied entries up to `index`, `data` being the state they lead to
+    pub fn compact(&mut self, index: u64, data: Vec<u8>) {
+        if index <= self.snapshot_index || index > self.applied {
+            return;
+        }
+        let Some(term) = self.term_at(index) else {
+            return;
+        };
+        self.log.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.snapshot = data;
+    }
+
+    /// the entries kept, what a rewrite of the log writes after the snapshot
+    pub fn entries(&self) -> &[Entry] {
+        &self.log
+    }
+
+    pub fn hard_state_of(&self) -> (u64, Option<String>) {
+        (self.term, self.voted_for.clone())
+    }
+
+    pub fn snapshot(&self) -> (u64, u64, &[u8]) {
+        (self.snapshot_index, self.snapshot_term, &self.snapshot)
+    }
+
+    pub fn tick(&mut self) {
+        if self.role == Role::Leader {
+            self.broadcast_append();
+            return;
+        }
+        self.elapsed += 1;
+        if self.elapsed >= self.timeout {
+            self.campaign();
+        }
+    }
+
+    /// false when there is no leader to take it
+    pub fn propose(&mut self, data: Vec<u8>) -> bool {
+        match (self.role, self.leader.clone()) {
+            (Role::Leader, _) => {
+                self.append(data);
+                self.broadcast_append();
+                true
+            }
+            (_, Some(leader)) => {
+                self.messages.push((leader, Message::Propose { data }));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn append(&mut self, data: Vec<u8>) {
+        let entry = Entry {
+            term: self.term,
+            index: self.last_index() + 1,
+            data,
+        };
+        self.changes.push(Change::Append(entry.clone()));
+        self.log.push(entry);
+        self.advance_commit();
+    }
+
+    fn campaign(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id.clone());
+        self.votes = HashSet::from([self.id.clone()]);
+        self.hard_state();
+        self.reset_timeout();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for peer in &self.peers {
+            self.messages.push((
+                peer.clone(),
+                Message::Vote {
+                    term: self.term,
+                    last_index,
+                    last_term,
+                },
+            ));
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.hard_state();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        let next = self.last_index() + 1;
+        for peer in &self.peers {
+            self.next.insert(peer.clone(), next);
+            self.matched.insert(peer.clone(), 0);
+        }
+        // entries of the previous terms only commit along with one of this term
+        self.append(vec![]);
+        self.broadcast_append();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let next = self.next.get(peer).copied().unwrap_or(1);
+        let message = match self.term_at(next - 1) {
+            Some(prev_term) => {
+                let from = (next - 1 - self.snapshot_index) as usize;
+                let to = self.log.len().min(from + MAX_APPEND_ENTRIES);
+                Message::Append {
+                    term: self.term,
+                    prev_index: next - 1,
+                    prev_term,
+                    entries: self.log[from..to].to_vec(),
+                    commit: self.commit,
+                }
+            }
+            None => Message::Snapshot {
+                term: self.term,
+                index: self.snapshot_index,
+                snapshot_term: self.snapshot_term,
+                data: self.snapshot.clone(),
+            },
+        };
+        self.messages.push((peer.to_string(), message));
+    }
+
+    fn advance_commit(&mut self) {
+        let mut matched: Vec<u64> = self.matched.values().copied().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.term_at(index) == Some(self.term) {
+            self.commit = index;
+        }
+    }
+
+    pub fn step(&mut self, from: &str, message: Message) {
+        if let Some(term) = message.term() {
+            if term > self.term {
+                let leader = match message {
+                    Message::Append { .. } | Message::Snapshot { .. } => Some(from.to_string()),
+                    _ => None,
+                };
+                self.become_follower(term, leader);
+            } else if term < self.term {
+                // a stale leader or candidate learns the term from the reply
+                match message {
+                    Message::Vote { .. } => self.reply_vote(from, false),
+                    Message::Append { .. } | Message::Snapshot { .. } => {
+                        self.reply_append(from, false, self.last_index())
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        }
+
+        match message {
+            Message::Vote {
+                last_index,
+                last_term,
+                ..
+            } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = up_to_date
+                    && self.leader.is_none()
+                    && self.voted_for.as_ref().is_none_or(|v| v == from);
+                if granted {
+                    self.voted_for = Some(from.to_string());
+                    self.hard_state();
+                    self.reset_timeout();
+                }
+                self.reply_vote(from, granted);
+            }
+            Message::VoteReply { granted, .. } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(from.to_string());
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            Message::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                ..
+            } => {
+                self.become_follower(self.term, Some(from.to_string()));
+                if prev_index > self.last_index() {
+                    self.reply_append(from, false, self.last_index());
+                    return;
+                }
+                if let Some(term) = self.term_at(prev_index)
+                    && term != prev_term
+                {
+                    self.reply_append(from, false, prev_index - 1);
+                    return;
+                }
+                let last = prev_index + entries.len() as u64;
+                for entry in entries {
+                    if entry.index <= self.snapshot_index {
+                        continue;
+                    }
+                    match self.term_at(entry.index) {
+                        Some(term) if term == entry.term => continue,
+                        Some(_) => {
+                            self.log
+                                .truncate((entry.index - self.snapshot_index - 1) as usize);
+                            self.changes.push(Change::Truncate(entry.index));
+                        }
+                        None => {}
+                    }
+                    self.changes.push(Change::Append(entry.clone()));
+                    self.log.push(entry);
+                }
+                if commit > self.commit {
+                    self.commit = commit.min(last).max(self.commit);
+                }
+                self.reply_append(from, true, last);
+            }
+            Message::AppendReply {
+                success,
+                last_index,
+                ..
+            } => {
+                if self.role != Role::Leader {
+                    return;
+                }
+                let next = self.next.get(from).copied().unwrap_or(1);
+                if success {
+                    let matched = self.matched.entry(from.to_string()).or_insert(0);
+                    *matched = (*matched).max(last_index);
+                    self.next.insert(from.to_string(), *matched + 1);
+                    self.advance_commit();
+                    if *self.next.get(from).unwrap() <= self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    self.next
+                        .insert(from.to_string(), (last_index + 1).min(next - 1).max(1));
+                    self.send_append(from);
+                }
+            }
+            Message::Snapshot {
+                index,
+                snapshot_term,
+                data,
+                ..
+            } => {
+                self.become_follower(self.term, Some(from.to_string()));
+                if index > self.commit {
+                    self.log.clear();
+                    self.snapshot_index = index;
+                    self.snapshot_term = snapshot_term;
+                    self.snapshot = data.clone();
+                    self.commit = index;
+                    self.applied = index;
+                    self.changes.push(Change::Snapshot {
+                        index,
+                        term: snapshot_term,
+                        data,
+                    });
+                }
+                self.reply_append(from, true, self.commit);
+            }
+            Message::Propose { data } => {
+                if self.role == Role::Leader {
+                    self.append(data);
+                    self.broadcast_append();
+                }
+            }
+        }
+    }
+
+    fn reply_vote(&mut self, to: &str, granted: bool) {
+        self.messages.push((
+            to.to_string(),
+            Message::VoteReply {
+                term: self.term,
+                granted,
+            },
+        ));
+    }
+
+    fn reply_append(&mut self, to: &str, success: bool, last_index: u64) {
+        self.messages.push((
+            to.to_string(),
+            Message::AppendReply {
+                term: self.term,
+                success,
+                last_index,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Net {
+        nodes: HashMap<String, Raft>,
+        down: HashSet<String>,
+        applied: HashMap<String, Vec<Vec<u8>>>,
+    }
+
+    impl Net {
+        fn new(ids: &[&str]) -> Self {
+            let members: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+            Net {
+                nodes: members
+                    .iter()
+                    .map(|id| (id.clone(), Raft::new(id.clone(), &members, 5)))
+                    .collect(),
+                down: HashSet::new(),
+                applied: HashMap::new(),
+            }
+        }
+
+        // one tick of every node up, then the messages until there are none left
+        fn run(&mut self, ticks: usize) {
+            for _ in 0..ticks {
+                for (id, raft) in self.nodes.iter_mut() {
+                    if !self.down.contains(id) {
+                        raft.tick();
+                    }
+                }
+                self.deliver();
+            }
+        }
+
+        fn deliver(&mut self) {
+            loop {
+                let mut queue = vec![];
+                for (id, raft) in self.nodes.iter_mut() {
+                    raft.take_changes();
+                    for entry in raft.take_committed() {
+                        if !entry.data.is_empty() {
+                            self.applied.entry(id.clone()).or_default().push(entry.data);
+                        }
+                    }
+                    for (to, message) in raft.take_messages() {
+                        queue.push((id.clone(), to, message));
+                    }
+                }
+                if queue.is_empty() {
+                    return;
+                }
+                for (from, to, message) in queue {
+                    if !self.down.contains(&from) && !self.down.contains(&to) {
+                        self.nodes.get_mut(&to).unwrap().step(&from, message);
+                    }
+                }
+            }
+        }
+
+        // ticks until a leader is up, a split vote takes another election timeout
+        fn elect(&mut self) -> String {
+            for _ in 0..200 {
+                self.run(1);
+                if let Some(leader) = self.leader() {
+                    return leader;
+                }
+            }
+            panic!("no leader elected");
+        }
+
+        fn leader(&self) -> Option<String> {
+            let leaders: Vec<&String> = self
+                .nodes
+                .iter()
+                .filter(|(id, raft)| !self.down.contains(*id) && raft.role() == Role::Leader)
+                .map(|(id, _)| id)
+                .collect();
+            assert!(leaders.len() <= 1);
+            leaders.first().map(|id| id.to_string())
+        }
+    }
+
+    #[test]
+    fn test_replicate() {
+        let mut net = Net::new(&["a", "b", "c"]);
+        let leader = net.elect();
+        assert!(net.nodes.get_mut(&leader).unwrap().propose(b"1".to_vec()));
+        // a follower hands its proposal to the leader
+        let follower = ["a", "b", "c"].iter().find(|id| **id != leader).unwrap();
+        assert!(net.nodes.get_mut(*follower).unwrap().propose(b"2".to_vec()));
+        // the followers learn the commit from the next heartbeat
+        net.deliver();
+        net.run(1);
+        for id in ["a", "b", "c"] {
+            assert_eq!(net.applied[id], [b"1".to_vec(), b"2".to_vec()]);
+        }
+
+        // the leader goes down, the others elect one of them and keep what was committed
+        net.down.insert(leader.clone());
+        let next = net.elect();
+        assert_ne!(next, leader);
+        assert!(net.nodes.get_mut(&next).unwrap().propose(b"3".to_vec()));
+        net.deliver();
+
+        // the old leader catches up from a snapshot once the others compacted their log
+        for (id, raft) in net.nodes.iter_mut() {
+            if *id != leader {
+                let applied = raft.applied();
+                raft.compact(applied, b"state".to_vec());
+            }
+        }
+        net.down.clear();
+        net.run(5);
+        let old = &net.nodes[&leader];
+        assert_eq!(old.role(), Role::Follower);
+        assert_eq!(old.snapshot(), net.nodes[&next].snapshot());
+        assert_eq!(old.snapshot().2, b"state");
+        assert_eq!(old.commit(), net.nodes[&next].commit());
+    }
+
+    #[test]
+    fn test_single_member() {
+        let mut raft = Raft::new("a".to_string(), &["a".to_string()], 1);
+        assert!(!raft.propose(b"lost".to_vec()));
+        raft.tick();
+        raft.tick();
+        assert_eq!(raft.role(), Role::Leader);
+        assert!(raft.propose(b"1".to_vec()));
+        let committed: Vec<Vec<u8>> = raft.take_committed().into_iter().map(|e| e.data).collect();
+        assert_eq!(committed, [vec![], b"1".to_vec()]);
+    }
+}
@@ -0,0 +1,868 @@
+//! the broker state replicated through raft between the members of `[node.cluster.replication]`:
+//! retained messages, the subscriptions of persistent sessions and the Sparkplug B births. Every
+//! member applies the same entries in the same order, so a standby taking over from the active
+//! node has them already
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
+
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::{debug, info, warn};
+
+use crate::config::ReplicationConfig;
+use crate::mqtt::QoS;
+use crate::mqtt::helper::BrokerHelper;
+use crate::mqtt::protocol::subscribe::SubscribeOption;
+use crate::mqtt::retain_store::{
+    decode_body, encode_body, read_opt_str, read_str, write_opt_str, write_str,
+};
+use crate::mqtt::retain_trie::RetainedMessage;
+use crate::operator::utils::topic_match;
+use crate::service::sparkplug_b::helper::SparkPlugBApplicationHelper;
+
+use super::ClusterCommand;
+use super::raft::{Change, Entry, Message, Raft, Role};
+
+const REC_HARD_STATE: u8 = 1;
+const REC_ENTRY: u8 = 2;
+const REC_TRUNCATE: u8 = 3;
+const REC_SNAPSHOT: u8 = 4;
+
+// the file of the raft log in the `path` directory
+const LOG_FILE: &str = "raft.log";
+// the changes kept while there is no leader to take them
+const MAX_PENDING: usize = 10_000;
+
+const CMD_RETAIN: u8 = 1;
+const CMD_REMOVE_RETAINED: u8 = 2;
+const CMD_SESSION: u8 = 3;
+const CMD_SPARKPLUG_B: u8 = 4;
+
+#[derive(Clone)]
+pub enum Command {
+    Retain(RetainedMessage),
+    // the retained messages matching a filter, a topic removes its own
+    RemoveRetained(String),
+    // the subscriptions of a persistent session, none ends it
+    Session {
+        client_id: String,
+        subscriptions: Vec<(String, SubscribeOption)>,
+    },
+    // NBIRTH and DBIRTH, and the deaths removing them
+    SparkplugB {
+        topic: String,
+        qos: QoS,
+        payload: Bytes,
+    },
+}
+
+fn write_bytes<W: Write>(w: &mut W, v: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(v.len() as u32)?;
+    w.write_all(v)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>()?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {}", what))
+}
+
+impl Command {
+    // the node it comes from first, only the other members apply it to their broker
+    fn encode(&self, origin: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        write_str(&mut buf, origin)?;
+        match self {
+            Command::Retain(message) => {
+                buf.write_u8(CMD_RETAIN)?;
+                write_str(&mut buf, &message.topic)?;
+                buf.write_u64::<BigEndian>(message.options.message_expiry_at.unwrap_or(0))?;
+                write_bytes(&mut buf, &encode_body(message)?)?;
+            }
+            Command::RemoveRetained(filter) => {
+                buf.write_u8(CMD_REMOVE_RETAINED)?;
+                write_str(&mut buf, filter)?;
+            }
+            Command::Session {
+                client_id,
+                subscriptions,
+            } => {
+                buf.write_u8(CMD_SESSION)?;
+                write_str(&mut buf, client_id)?;
+                buf.write_u16::<BigEndian>(subscriptions.len() as u16)?;
+                for (topic, option) in subscriptions {
+                    write_str(&mut buf, topic)?;
+                    buf.write_u8(option.qos as u8)?;
+                    buf.write_u8(option.no_local as u8 | (option.retain_as_published as u8) << 1)?;
+                    buf.write_u8(option.retain_handling)?;
+                    buf.write_u32::<BigEndian>(option.subscription_identifier.unwrap_or(0))?;
+                }
+            }
+            Command::SparkplugB {
+                topic,
+                qos,
+                payload,
+            } => {
+                buf.write_u8(CMD_SPARKPLUG_B)?;
+                write_str(&mut buf, topic)?;
+                buf.write_u8(*qos as u8)?;
+                write_bytes(&mut buf, payload)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn decode(data: &[u8]) -> io::Result<(String, Self)> {
+        let mut rdr = Cursor::new(data);
+        let origin = read_str(&mut rdr)?;
+        let qos = |v: u8| QoS::try_from(v).map_err(|_| invalid("qos"));
+        let command = match rdr.read_u8()? {
+            CMD_RETAIN => {
+                let topic = read_str(&mut rdr)?;
+                let expiry_at = rdr.read_u64::<BigEndian>()?;
+                let body = read_bytes(&mut rdr)?;
+                Command::Retain(decode_body(
+                    &topic,
+                    (expiry_at != 0).then_some(expiry_at),
+                    &body,
+                )?)
+            }
+            CMD_REMOVE_RETAINED => Command::RemoveRetained(read_str(&mut rdr)?),
+            CMD_SESSION => {
+                let client_id = read_str(&mut rdr)?;
+                let count = rdr.read_u16::<BigEndian>()?;
+                let mut subscriptions = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let topic = read_str(&mut rdr)?;
+                    let qos = qos(rdr.read_u8()?)?;
+                    let flags = rdr.read_u8()?;
+                    let retain_handling = rdr.read_u8()?;
+                    let subscription_identifier = rdr.read_u32::<BigEndian>()?;
+                    subscriptions.push((
+                        topic,
+                        SubscribeOption {
+                            qos,
+                            no_local: flags & 1 != 0,
+                            retain_as_published: flags & 2 != 0,
+                            retain_handling,
+                            subscription_identifier: (subscription_identifier != 0)
+                                .then_some(subscription_identifier),
+                        },
+                    ));
+                }
+                Command::Session {
+                    client_id,
+                    subscriptions,
+                }
+            }
+            CMD_SPARKPLUG_B => Command::SparkplugB {
+                topic: read_str(&mut rdr)?,
+                qos: qos(rdr.read_u8()?)?,
+                payload: Bytes::from(read_bytes(&mut rdr)?),
+            },
+            _ => return Err(invalid("command")),
+        };
+        Ok((origin, command))
+    }
+}
+
+/// what the committed entries add up to, the same on every member
+#[derive(Default)]
+pub struct State {
+    retained: BTreeMap<String, RetainedMessage>,
+    // client id -> (the node the session was last updated on, subscriptions)
+    sessions: HashMap<String, (String, Vec<(String, SubscribeOption)>)>,
+    // NBIRTH and DBIRTH by topic
+    births: BTreeMap<String, (QoS, Bytes)>,
+}
+
+impl State {
+    fn apply(&mut self, origin: &str, command: &Command) {
+        match command {
+            Command::Retain(message) => {
+                self.retained.insert(message.topic.clone(), message.clone());
+            }
+            Command::RemoveRetained(filter) => {
+                self.retained.retain(|topic, _| !topic_match(filter, topic));
+            }
+            Command::Session {
+                client_id,
+                subscriptions,
+            } => {
+                if !subscriptions.is_empty() {
+                    self.sessions.insert(
+                        client_id.clone(),
+                        (origin.to_string(), subscriptions.clone()),
+                    );
+                } else if self
+                    .sessions
+                    .get(client_id)
+                    .is_some_and(|(owner, _)| owner == origin)
+                {
+                    // a session expiring on a node the client left behind is not the one it has now
+                    self.sessions.remove(client_id);
+                }
+            }
+            Command::SparkplugB {
+                topic,
+                qos,
+                payload,
+            } => {
+                let parts: Vec<&str> = topic.split('/').collect();
+                match parts.as_slice() {
+                    [_, _, "NBIRTH", ..] | [_, _, "DBIRTH", ..] => {
+                        self.births.insert(topic.clone(), (*qos, payload.clone()));
+                    }
+                    [namespace, group, "NDEATH", node] => {
+                        let devices = format!("{}/{}/DBIRTH/{}/", namespace, group, node);
+                        self.births
+                            .remove(&format!("{}/{}/NBIRTH/{}", namespace, group, node));
+                        self.births.retain(|topic, _| !topic.starts_with(&devices));
+                    }
+                    [namespace, group, "DDEATH", node, device] => {
+                        self.births.remove(&format!(
+                            "{}/{}/DBIRTH/{}/{}",
+                            namespace, group, node, device
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // the commands rebuilding the state, expired retained messages left out
+    fn commands(&self, now: u64) -> Vec<(String, Command)> {
+        let retained = self
+            .retained
+            .values()
+            .filter(|m| m.options.message_expiry_at.is_none_or(|at| at > now))
+            .map(|m| (String::new(), Command::Retain(m.clone())));
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|(client_id, (owner, subscriptions))| {
+                (
+                    owner.clone(),
+                    Command::Session {
+                        client_id: client_id.clone(),
+                        subscriptions: subscriptions.clone(),
+                    },
+                )
+            });
+        let births = self.births.iter().map(|(topic, (qos, payload))| {
+            (
+                String::new(),
+                Command::SparkplugB {
+                    topic: topic.clone(),
+                    qos: *qos,
+                    payload: payload.clone(),
+                },
+            )
+        });
+        retained.chain(sessions).chain(births).collect()
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let commands = self.commands(coarsetime::Clock::now_since_epoch().as_secs());
+        let mut buf = Vec::new();
+        buf.write_u32::<BigEndian>(commands.len() as u32)?;
+        for (origin, command) in commands {
+            write_bytes(&mut buf, &command.encode(&origin)?)?;
+        }
+        Ok(buf)
+    }
+
+    fn decode(data: &[u8]) -> io::Result<Self> {
+        let mut state = State::default();
+        if data.is_empty() {
+            return Ok(state);
+        }
+        let mut rdr = Cursor::new(data);
+        for _ in 0..rdr.read_u32::<BigEndian>()? {
+            let (origin, command) = Command::decode(&read_bytes(&mut rdr)?)?;
+            state.apply(&origin, &command);
+        }
+        Ok(state)
+    }
+}
+
+static STATE: LazyLock<RwLock<State>> = LazyLock::new(Default::default);
+// set when replication is on, the broker proposes through it
+static PROPOSALS: OnceLock<mpsc::Sender<ClusterCommand>> = OnceLock::new();
+static SPARKPLUG_B: OnceLock<SparkPlugBApplicationHelper> = OnceLock::new();
+
+/// the Sparkplug B application the births of the other members are replayed to
+pub fn set_sparkplug_b(helper: SparkPlugBApplicationHelper) {
+    let _ = SPARKPLUG_B.set(helper);
+}
+
+fn propose(command: impl FnOnce() -> Command) {
+    let Some(tx) = PROPOSALS.get() else {
+        return;
+    };
+    if tx.try_send(ClusterCommand::Replicate(command())).is_err() {
+        debug!("cluster queue full, replicated change dropped");
+    }
+}
+
+pub fn retained(message: &RetainedMessage) {
+    propose(|| Command::Retain(message.clone()));
+}
+
+pub fn retained_removed(filter: &str) {
+    propose(|| Command::RemoveRetained(filter.to_string()));
+}
+
+/// the subscriptions of a persistent session changed, or it moved to this node
+pub fn subscriptions(client_id: &str, subscribes: &HashMap<String, SubscribeOption>) {
+    if subscribes.is_empty() {
+        return session_ended(client_id);
+    }
+    propose(|| Command::Session {
+        client_id: client_id.to_string(),
+        subscriptions: subscribes
+            .iter()
+            .map(|(topic, option)| (topic.clone(), option.clone()))
+            .collect(),
+    });
+}
+
+pub fn session_ended(client_id: &str) {
+    if PROPOSALS.get().is_none() || !STATE.read().unwrap().sessions.contains_key(client_id) {
+        return;
+    }
+    propose(|| Command::Session {
+        client_id: client_id.to_string(),
+        subscriptions: vec![],
+    });
+}
+
+/// the subscriptions of a session kept by another member, for a client resuming it here
+pub fn restore_session(client_id: &str) -> Option<HashMap<String, SubscribeOption>> {
+    PROPOSALS.get()?;
+    let state = STATE.read().unwrap();
+    let (_, subscriptions) = state.sessions.get(client_id)?;
+    Some(subscriptions.iter().cloned().collect())
+}
+
+/// births and deaths, the data messages are left to the rebirth of the standby
+pub fn sparkplug_b(topic: &str, qos: QoS, payload: &Bytes) {
+    let kind = topic.split('/').nth(2);
+    if matches!(kind, Some("NBIRTH" | "DBIRTH" | "NDEATH" | "DDEATH")) {
+        propose(|| Command::SparkplugB {
+            topic: topic.to_string(),
+            qos,
+            payload: payload.clone(),
+        });
+    }
+}
+
+/// the raft log on disk: hard state, entries, truncations and snapshots, rewritten from the
+/// last snapshot once it is compacted
+struct Log {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+struct Restored {
+    term: u64,
+    voted_for: Option<String>,
+    snapshot: Option<(u64, u64, Vec<u8>)>,
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    fn open(path: &Path) -> io::Result<(Self, Restored)> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let (restored, end) = Self::scan(&file)?;
+        // drop a truncated tail left by a crash
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+        }
+        Ok((
+            Log {
+                path: path.to_path_buf(),
+                writer: BufWriter::new(file),
+            },
+            restored,
+        ))
+    }
+
+    fn scan(file: &File) -> io::Result<(Restored, u64)> {
+        let mut rdr = BufReader::new(file);
+        rdr.seek(SeekFrom::Start(0))?;
+        let mut restored = Restored {
+            term: 0,
+            voted_for: None,
+            snapshot: None,
+            entries: vec![],
+        };
+        let mut end = 0;
+        while let Ok(record) = read_bytes(&mut rdr) {
+            let mut rdr = Cursor::new(record.as_slice());
+            let Ok(()) = Self::replay(&mut rdr, &mut restored) else {
+                break;
+            };
+            end += 4 + record.len() as u64;
+        }
+        Ok((restored, end))
+    }
+
+    fn replay(rdr: &mut Cursor<&[u8]>, restored: &mut Restored) -> io::Result<()> {
+        match rdr.read_u8()? {
+            REC_HARD_STATE => {
+                restored.term = rdr.read_u64::<BigEndian>()?;
+                restored.voted_for = read_opt_str(rdr)?;
+            }
+            REC_ENTRY => {
+                let entry = Entry {
+                    term: rdr.read_u64::<BigEndian>()?,
+                    index: rdr.read_u64::<BigEndian>()?,
+                    data: read_bytes(rdr)?,
+                };
+                restored.entries.push(entry);
+            }
+            REC_TRUNCATE => {
+                let index = rdr.read_u64::<BigEndian>()?;
+                restored.entries.retain(|e| e.index < index);
+            }
+            REC_SNAPSHOT => {
+                let index = rdr.read_u64::<BigEndian>()?;
+                let term = rdr.read_u64::<BigEndian>()?;
+                let data = read_bytes(rdr)?;
+                restored.entries.retain(|e| e.index > index);
+                restored.snapshot = Some((index, term, data));
+            }
+            _ => return Err(invalid("record")),
+        }
+        Ok(())
+    }
+
+    fn record<W: Write>(w: &mut W, change: &Change) -> io::Result<()> {
+        let mut buf = Vec::new();
+        match change {
+            Change::HardState { term, voted_for } => {
+                buf.write_u8(REC_HARD_STATE)?;
+                buf.write_u64::<BigEndian>(*term)?;
+                write_opt_str(&mut buf, voted_for)?;
+            }
+            Change::Append(entry) => {
+                buf.write_u8(REC_ENTRY)?;
+                buf.write_u64::<BigEndian>(entry.term)?;
+                buf.write_u64::<BigEndian>(entry.index)?;
+                write_bytes(&mut buf, &entry.data)?;
+            }
+            Change::Truncate(index) => {
+                buf.write_u8(REC_TRUNCATE)?;
+                buf.write_u64::<BigEndian>(*index)?;
+            }
+            Change::Snapshot { index, term, data } => {
+                buf.write_u8(REC_SNAPSHOT)?;
+                buf.write_u64::<BigEndian>(*index)?;
+                buf.write_u64::<BigEndian>(*term)?;
+                write_bytes(&mut buf, data)?;
+            }
+        }
+        write_bytes(w, &buf)
+    }
+
+    fn write(&mut self, changes: &[Change]) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in changes {
+            Self::record(&mut self.writer, change)?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    // the snapshot, the hard state and the entries after the snapshot
+    fn compacted(raft: &Raft) -> Vec<Change> {
+        let (index, term, data) = raft.snapshot();
+        let mut changes = vec![Change::Snapshot {
+            index,
+            term,
+            data: data.to_vec(),
+        }];
+        let (term, voted_for) = raft.hard_state_of();
+        changes.push(Change::HardState { term, voted_for });
+        changes.extend(raft.entries().iter().cloned().map(Change::Append));
+        changes
+    }
+
+    fn rewrite(&mut self, changes: &[Change]) -> io::Result<()> {
+        self.writer.flush()?;
+        let tmp_path = self.path.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for change in changes {
+            Self::record(&mut writer, change)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        Ok(())
+    }
+}
+
+/// the raft member of this node, driven by the cluster loop
+pub(super) struct Replicated {
+    node_id: String,
+    raft: Raft,
+    // written on a blocking thread, the cluster loop waits for it without holding a worker
+    log: Arc<Mutex<Log>>,
+    // the changes proposed while there was no leader, proposed again once there is one
+    pending: VecDeque<Vec<u8>>,
+    snapshot_entries: u64,
+    // the role and leader last logged
+    seen: (Role, Option<String>),
+    // the snapshot read on start, applied to the broker on the first ready
+    restored: Vec<Command>,
+}
+
+impl Replicated {
+    pub fn open(
+        node_id: &str,
+        config: &ReplicationConfig,
+        cluster_tx: mpsc::Sender<ClusterCommand>,
+    ) -> io::Result<Self> {
+        if !config.members.contains(&node_id.to_string()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("node {} is not among the members", node_id),
+            ));
+        }
+        // a majority of two is both of them
+        if config.members.len() < 3 {
+            warn!(
+                "replication with {} members stops committing when one is down, three are needed to keep going",
+                config.members.len()
+            );
+        }
+        let (log, restored) = Log::open(&Path::new(&config.path).join(LOG_FILE))?;
+        let election_ticks = config.election_timeout_ms / config.heartbeat_ms.max(1);
+        let mut raft = Raft::new(node_id.to_string(), &config.members, election_ticks);
+        let state = match &restored.snapshot {
+            Some((_, _, data)) => State::decode(data)?,
+            None => State::default(),
+        };
+        info!(
+            "replicated store {} opened, term {}, {} entries after the snapshot",
+            config.path,
+            restored.term,
+            restored.entries.len()
+        );
+        raft.restore(
+            restored.term,
+            restored.voted_for,
+            restored.snapshot,
+            restored.entries,
+        );
+        let restored = state.commands(0).into_iter().map(|(_, c)| c).collect();
+        *STATE.write().unwrap() = state;
+        let _ = PROPOSALS.set(cluster_tx);
+
+        Ok(Replicated {
+            node_id: node_id.to_string(),
+            raft,
+            log: Arc::new(Mutex::new(log)),
+            pending: VecDeque::new(),
+            snapshot_entries: config.snapshot_entries.max(1),
+            seen: (Role::Follower, None),
+            restored,
+        })
+    }
+
+    pub fn tick(&mut self) {
+        self.raft.tick();
+    }
+
+    pub fn step(&mut self, from: &str, message: Message) {
+        self.raft.step(from, message);
+    }
+
+    pub fn propose(&mut self, command: Command) {
+        match command.encode(&self.node_id) {
+            Ok(data) => {
+                self.pending.push_back(data);
+                if self.pending.len() > MAX_PENDING {
+                    self.pending.pop_front();
+                    warn!("no replication leader for too long, oldest pending change dropped");
+                }
+                self.propose_pending();
+            }
+            Err(e) => warn!("replicated change not encoded: {}", e),
+        }
+    }
+
+    // in order, once there is a leader to take them
+    fn propose_pending(&mut self) {
+        if self.raft.leader().is_none() {
+            return;
+        }
+        while let Some(data) = self.pending.pop_front() {
+            self.raft.propose(data);
+        }
+    }
+
+    // the log I/O runs on a blocking thread
+    async fn with_log<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Log) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let log = self.log.clone();
+        task::spawn_blocking(move || f(&mut log.lock().unwrap()))
+            .await
+            .expect("replicated store task panicked")
+    }
+
+    /// persists what changed, then returns the messages to send and the commands of the other
+    /// members to apply to the broker
+    pub async fn ready(&mut self) -> (Vec<(String, Message)>, Vec<Command>) {
+        self.propose_pending();
+        let changes = self.raft.take_changes();
+        let (changes, written) = if changes.is_empty() {
+            (changes, Ok(()))
+        } else {
+            self.with_log(move |log| {
+                let written = log.write(&changes);
+                (changes, written)
+            })
+            .await
+        };
+        if let Err(e) = written {
+            // nothing goes out which the log does not have
+            warn!("replicated store write failed: {}", e);
+            self.raft.take_messages();
+            return (vec![], vec![]);
+        }
+        let mut commands = std::mem::take(&mut self.restored);
+        for change in &changes {
+            if let Change::Snapshot { data, .. } = change {
+                match State::decode(data) {
+                    Ok(state) => {
+                        commands.extend(state.commands(0).into_iter().map(|(_, c)| c));
+                        *STATE.write().unwrap() = state;
+                    }
+                    Err(e) => warn!("replicated snapshot not decoded: {}", e),
+                }
+            }
+        }
+
+        for entry in self.raft.take_committed() {
+            if entry.data.is_empty() {
+                continue;
+            }
+            match Command::decode(&entry.data) {
+                Ok((origin, command)) => {
+                    STATE.write().unwrap().apply(&origin, &command);
+                    if origin != self.node_id {
+                        commands.push(command);
+                    }
+                }
+                Err(e) => warn!("replicated entry {} not decoded: {}", entry.index, e),
+            }
+        }
+
+        if self.raft.applied() - self.raft.snapshot_index() >= self.snapshot_entries {
+            let encoded = STATE.read().unwrap().encode();
+            let compacted = match encoded {
+                Ok(data) => {
+                    self.raft.compact(self.raft.applied(), data);
+                    let changes = Log::compacted(&self.raft);
+                    self.with_log(move |log| log.rewrite(&changes)).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = compacted {
+                warn!("replicated store compaction failed: {}", e);
+            }
+        }
+
+        if self.raft.role() != self.seen.0 || self.raft.leader() != self.seen.1.as_deref() {
+            self.seen = (self.raft.role(), self.raft.leader().map(str::to_string));
+            info!(
+                "replication term {}: {:?}, leader {}, commit {}",
+                self.raft.term(),
+                self.seen.0,
+                self.seen.1.as_deref().unwrap_or("-"),
+                self.raft.commit()
+            );
+        }
+        (self.raft.take_messages(), commands)
+    }
+}
+
+/// what a command of another member changes on this broker, sessions are only read when their
+/// client connects
+pub(super) async fn apply(command: Command, broker_helper: &BrokerHelper) {
+    match command {
+        Command::Retain(message) => {
+            let topic = message.topic.clone();
+            broker_helper.retain_trie.insert(&topic, message);
+        }
+        Command::RemoveRetained(filter) => {
+            broker_helper.retain_trie.remove_matches(&filter);
+        }
+        Command::Session { .. } => {}
+        Command::SparkplugB {
+            topic,
+            qos,
+            payload,
+        } => {
+            if let Some(helper) = SPARKPLUG_B.get() {
+                helper.replay(topic, qos, payload).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::protocol::publish::PublishOptions;
+
+    #[test]
+    fn test_state() {
+        let retain = |topic: &str| {
+            Command::Retain(RetainedMessage {
+                topic: topic.to_string(),
+                qos: QoS::AtLeastOnce,
+                payload: Bytes::from_static(b"v"),
+                user_properties: vec![],
+                options: PublishOptions::default(),
+                client_id: None,
+            })
+        };
+        let session = |subscriptions: Vec<(String, SubscribeOption)>| Command::Session {
+            client_id: "c1".to_string(),
+            subscriptions,
+        };
+        let birth = |topic: &str| Command::SparkplugB {
+            topic: topic.to_string(),
+            qos: QoS::AtMostOnce,
+            payload: Bytes::new(),
+        };
+        let option = SubscribeOption {
+            qos: QoS::AtLeastOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: 2,
+            subscription_identifier: Some(7),
+        };
+
+        let mut state = State::default();
+        for (origin, command) in [
+            ("a", retain("t/1")),
+            ("a", retain("t/2")),
+            ("a", Command::RemoveRetained("t/+".to_string())),
+            ("a", retain("t/3")),
+            ("a", session(vec![("s/#".to_string(), option)])),
+            ("b", birth("spBv1.0/g/NBIRTH/n")),
+            ("b", birth("spBv1.0/g/DBIRTH/n/d1")),
+            ("b", birth("spBv1.0/g/DBIRTH/n/d2")),
+            ("b", birth("spBv1.0/g/DDEATH/n/d1")),
+        ] {
+            let data = command.encode(origin).unwrap();
+            let (origin, command) = Command::decode(&data).unwrap();
+            state.apply(&origin, &command);
+        }
+        assert_eq!(state.retained.keys().collect::<Vec<_>>(), ["t/3"]);
+        assert_eq!(
+            state.births.keys().collect::<Vec<_>>(),
+            ["spBv1.0/g/DBIRTH/n/d2", "spBv1.0/g/NBIRTH/n"]
+        );
+
+        // a snapshot rebuilds the same state
+        let state = State::decode(&state.encode().unwrap()).unwrap();
+        let (owner, subscriptions) = &state.sessions["c1"];
+        assert_eq!(owner, "a");
+        assert_eq!(subscriptions[0].1.subscription_identifier, Some(7));
+        assert!(subscriptions[0].1.no_local);
+        assert_eq!(state.retained.len(), 1);
+
+        // only the node the session was last updated on ends it
+        let mut state = state;
+        state.apply("b", &session(vec![]));
+        assert!(state.sessions.contains_key("c1"));
+        state.apply("a", &session(vec![]));
+        assert!(state.sessions.is_empty());
+
+        state.apply("b", &birth("spBv1.0/g/NDEATH/n"));
+        assert!(state.births.is_empty());
+    }
+
+    #[test]
+    fn test_log() {
+        let path = std::env::temp_dir().join(format!("axonmq-raft-{}.log", uuid::Uuid::new_v4()));
+        let entry = |index: u64, term: u64| Entry {
+            term,
+            index,
+            data: vec![index as u8],
+        };
+
+        let (mut log, restored) = Log::open(&path).unwrap();
+        assert_eq!(restored.term, 0);
+        log.write(&[
+            Change::HardState {
+                term: 2,
+                voted_for: Some("a".to_string()),
+            },
+            Change::Append(entry(1, 1)),
+            Change::Append(entry(2, 1)),
+            Change::Append(entry(3, 1)),
+            Change::Truncate(3),
+            Change::Append(entry(3, 2)),
+        ])
+        .unwrap();
+        drop(log);
+
+        let (mut log, restored) = Log::open(&path).unwrap();
+        assert_eq!(restored.term, 2);
+        assert_eq!(restored.voted_for.as_deref(), Some("a"));
+        assert_eq!(restored.entries, [entry(1, 1), entry(2, 1), entry(3, 2)]);
+
+        let mut raft = Raft::new("a".to_string(), &["a".to_string()], 1);
+        raft.restore(
+            restored.term,
+            restored.voted_for,
+            restored.snapshot,
+            restored.entries,
+        );
+        raft.tick();
+        raft.tick();
+        raft.take_committed();
+        raft.compact(2, b"state".to_vec());
+        log.rewrite(&Log::compacted(&raft)).unwrap();
+        drop(log);
+
+        let (_, restored) = Log::open(&path).unwrap();
+        assert_eq!(restored.snapshot, Some((2, 1, b"state".to_vec())));
+        assert_eq!(restored.entries.len(), 2);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,141 @@
+//! share groups with members on several nodes: every node tells the others the members of its
+//! shared subscriptions, so that a message published on any node is given to one member out of
+//! all of them, the same way whichever node it is published on
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, RwLock, RwLockReadGuard};
+
+use crate::CONFIG;
+use crate::config::ShareStrategy;
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// the members of the shared subscriptions of the other nodes
+#[derive(Default)]
+pub struct Members {
+    // node id -> share group -> filter -> client ids
+    nodes: HashMap<String, HashMap<String, HashMap<String, Vec<String>>>>,
+}
+
+impl Members {
+    pub fn get(&self, node_id: &str, share_group: &str, filter: &str) -> Option<&[String]> {
+        self.nodes
+            .get(node_id)?
+            .get(share_group)?
+            .get(filter)
+            .map(Vec::as_slice)
+    }
+}
+
+static MEMBERS: LazyLock<RwLock<Members>> = LazyLock::new(Default::default);
+
+/// the strategy of the cluster, None without `[node.cluster]`
+pub fn strategy() -> Option<ShareStrategy> {
+    let cluster = CONFIG.get()?.node.cluster.as_ref()?;
+    Some(cluster.share_strategy.unwrap_or_default())
+}
+
+pub fn members() -> RwLockReadGuard<'static, Members> {
+    MEMBERS.read().unwrap()
+}
+
+/// the members a peer has for a shared subscription, none left removes it
+pub(super) fn set_members(
+    node_id: &str,
+    share_group: String,
+    filter: String,
+    members: Vec<String>,
+) {
+    let mut nodes = MEMBERS.write().unwrap();
+    let node = nodes.nodes.entry(node_id.to_string()).or_default();
+    if !members.is_empty() {
+        node.entry(share_group).or_default().insert(filter, members);
+    } else if let Some(filters) = node.get_mut(&share_group) {
+        filters.remove(&filter);
+        if filters.is_empty() {
+            node.remove(&share_group);
+        }
+    }
+}
+
+/// forget the members of a peer whose connection closed, it sends them again
+pub(super) fn remove_node(node_id: &str) {
+    MEMBERS.write().unwrap().nodes.remove(node_id);
+}
+
+// FNV-1a, the same on every node unlike the std hasher
+fn score(key: &str, member: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in key.bytes().chain([0]).chain(member.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// the index of the member getting a message out of `members`, `members` is not empty. A
+/// hashing strategy picks the member with the highest score for the key, so that a node
+/// knowing only some of the members picks the same one when it is among them
+pub fn pick(strategy: ShareStrategy, publisher: &str, topic: &str, members: &[&str]) -> usize {
+    let key = match strategy {
+        ShareStrategy::RoundRobin => {
+            return NEXT_INDEX.fetch_add(1, Ordering::Relaxed) % members.len();
+        }
+        ShareStrategy::Topic => topic,
+        ShareStrategy::ClientId => publisher,
+    };
+    members
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, member)| (score(key, member), **member))
+        .map_or(0, |(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let all = ["a1", "a2", "b1", "b2", "b3"];
+        let mut seen = [0; 5];
+        for _ in 0..all.len() {
+            seen[pick(ShareStrategy::RoundRobin, "p", "t", &all)] += 1;
+        }
+        assert_eq!(seen, [1; 5]);
+
+        for topic in ["t/1", "t/2", "t/3", "t/4"] {
+            let member = all[pick(ShareStrategy::Topic, "p", topic, &all)];
+            assert_eq!(all[pick(ShareStrategy::Topic, "q", topic, &all)], member);
+            // the node of the member only knows its own members and picks the same one
+            let local: Vec<&str> = all
+                .iter()
+                .copied()
+                .filter(|m| m.starts_with(&member[..1]))
+                .collect();
+            assert_eq!(
+                local[pick(ShareStrategy::Topic, "p", topic, &local)],
+                member
+            );
+        }
+        assert_eq!(
+            pick(ShareStrategy::ClientId, "p", "t/1", &all),
+            pick(ShareStrategy::ClientId, "p", "t/2", &all)
+        );
+
+        set_members(
+            "n2",
+            "g".to_string(),
+            "a/#".to_string(),
+            vec!["b1".to_string()],
+        );
+        assert_eq!(
+            members().get("n2", "g", "a/#"),
+            Some(&["b1".to_string()][..])
+        );
+        set_members("n2", "g".to_string(), "a/#".to_string(), vec![]);
+        assert_eq!(members().get("n2", "g", "a/#"), None);
+        remove_node("n2");
+    }
+}
@@ -1,8 +1,100 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use crate::mqtt::utils::pub_topic_valid;
+
+use super::schedule::Schedule;
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Chain {
     pub name: String,
-    pub processors: Vec<String>,
+    pub processors: Vec<ProcessorStep>,
     pub delivery: bool,
+    // names of the sinks that receive the messages passing the chain
+    #[serde(default)]
+    pub sinks: Vec<String>,
+    // QoS 1 publishers get their PUBACK once the chain is through with the message, refused
+    // when it failed on it
+    #[serde(default)]
+    pub sink_ack: bool,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub schedule: Option<Schedule>,
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    // metadata keys set by the processors that are delivered as MQTT 5 user properties
+    #[serde(default)]
+    pub metadata: Vec<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// a step of a chain, a processor uuid or a list of them run together on the message
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ProcessorStep {
+    Single(String),
+    Parallel(Vec<String>),
+}
+
+impl ProcessorStep {
+    pub fn uuids(&self) -> &[String] {
+        match self {
+            ProcessorStep::Single(uuid) => std::slice::from_ref(uuid),
+            ProcessorStep::Parallel(uuids) => uuids,
+        }
+    }
+}
+
+/// what becomes of a message once a processor or a sink failed on it for good
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorAction {
+    // the message leaves the chain
+    #[default]
+    Abort,
+    // the message goes on to the next processor as the failing one got it, a failing sink is
+    // left out
+    Skip,
+    // the message is published on dead_letter_topic and leaves the chain
+    DeadLetter,
+}
+
+/// how a chain handles a processor or a sink returning an error
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ErrorPolicy {
+    // calls repeated before the action applies
+    pub retries: u32,
+    // wait before the first retry, doubled for each next one
+    pub backoff_ms: u64,
+    pub action: ErrorAction,
+    pub dead_letter_topic: Option<String>,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        ErrorPolicy {
+            retries: 0,
+            backoff_ms: 100,
+            action: ErrorAction::Abort,
+            dead_letter_topic: None,
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// a dead_letter action needs a topic to publish on
+    pub fn check(&self) -> Result<(), String> {
+        match (&self.action, &self.dead_letter_topic) {
+            (ErrorAction::DeadLetter, None) => {
+                Err("on_error dead_letter without dead_letter_topic".to_string())
+            }
+            (_, Some(topic)) if !pub_topic_valid(topic) => {
+                Err(format!("invalid dead_letter_topic {}", topic))
+            }
+            _ => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,454 @@
+//! the checks of `axonmq --check-config` and `axonmq-cli config validate`, what parsing alone
+//! lets through and the server would only find out about while starting
+
+use std::collections::HashSet;
+
+use super::Config;
+use super::router::condition_check;
+use crate::mqtt::listener::{TlsOptions, tcp::load_tls_config};
+use crate::mqtt::utils::validate::{topic_filter_valid, topic_name_valid};
+use crate::operator::sink::config::SinkConfig;
+
+fn tls_options(
+    cert_path: &str,
+    key_path: &str,
+    ca_path: &Option<String>,
+    require_client_cert: Option<bool>,
+) -> TlsOptions {
+    TlsOptions {
+        cert_path: cert_path.to_string(),
+        key_path: key_path.to_string(),
+        ca_path: ca_path.clone(),
+        require_client_cert: require_client_cert.unwrap_or(false),
+        cert_as_client_id: false,
+        reload_interval: 0,
+    }
+}
+
+fn is_wildcard(host: &str) -> bool {
+    matches!(host, "0.0.0.0" | "::" | "[::]" | "")
+}
+
+/// the address a socket of the server binds to, `what` names it in the problems
+struct Bind<'a> {
+    what: &'static str,
+    host: &'a str,
+    port: u16,
+}
+
+impl Bind<'_> {
+    fn conflicts(&self, other: &Bind) -> bool {
+        self.port == other.port
+            && (self.host == other.host || is_wildcard(self.host) || is_wildcard(other.host))
+    }
+}
+
+fn binds(config: &Config) -> Vec<Bind<'_>> {
+    let mut binds = vec![];
+    let listener = &config.mqtt.listener;
+    if let Some(tcp) = listener.tcp.as_ref().filter(|tcp| tcp.enable) {
+        binds.push(Bind {
+            what: "mqtt.listener.tcp",
+            host: &tcp.host,
+            port: tcp.port,
+        });
+    }
+    if let Some(tls) = listener.tcp_tls.as_ref().filter(|tls| tls.enable) {
+        binds.push(Bind {
+            what: "mqtt.listener.tcp_tls",
+            host: &tls.host,
+            port: tls.port,
+        });
+    }
+    if let Some(ws) = listener.ws.as_ref().filter(|ws| ws.enable) {
+        binds.push(Bind {
+            what: "mqtt.listener.ws",
+            host: &ws.host,
+            port: ws.port,
+        });
+    }
+    if let Some(wss) = listener.wss.as_ref().filter(|wss| wss.enable) {
+        binds.push(Bind {
+            what: "mqtt.listener.wss",
+            host: &wss.host,
+            port: wss.port,
+        });
+    }
+    if let Some(restful) = &config.service.restful {
+        binds.push(Bind {
+            what: "service.restful",
+            host: &restful.ip,
+            port: restful.port,
+        });
+    }
+    if let Some(cluster) = &config.node.cluster
+        && let Some((host, port)) = cluster.listen.rsplit_once(':')
+        && let Ok(port) = port.parse()
+    {
+        binds.push(Bind {
+            what: "node.cluster",
+            host,
+            port,
+        });
+    }
+    binds
+}
+
+/// what would keep the server from starting, or start it without some of the configured
+/// routes, chains or listeners, empty when the configuration is fine
+pub fn problems(config: &Config) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut processors = HashSet::new();
+    for processor in &config.processor {
+        if uuid::Uuid::parse_str(&processor.uuid).is_err() {
+            problems.push(format!("processor {}: invalid uuid", processor.uuid));
+        }
+        if !processors.insert(processor.uuid.as_str()) {
+            problems.push(format!("processor {}: defined twice", processor.uuid));
+        }
+    }
+    let sinks: HashSet<&str> = config.sink.iter().map(|sink| sink.name.as_str()).collect();
+    let mut chains = HashSet::new();
+    for chain in &config.chain {
+        if !chains.insert(chain.name.as_str()) {
+            problems.push(format!("chain {}: defined twice", chain.name));
+        }
+        if chain.processors.iter().any(|step| step.uuids().is_empty()) {
+            problems.push(format!("chain {}: empty parallel step", chain.name));
+        }
+        for processor in chain.processors.iter().flat_map(|step| step.uuids()) {
+            if !processors.contains(processor.as_str()) {
+                problems.push(format!(
+                    "chain {}: unknown processor {}",
+                    chain.name, processor
+                ));
+            }
+        }
+        for sink in &chain.sinks {
+            if !sinks.contains(sink.as_str()) {
+                problems.push(format!("chain {}: unknown sink {}", chain.name, sink));
+            }
+        }
+        if let Err(e) = chain.on_error.check() {
+            problems.push(format!("chain {}: {}", chain.name, e));
+        }
+    }
+    for (index, router) in config.router.iter().enumerate() {
+        let name = router
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("route-{}", index));
+        if let Some(condition) = &router.condition
+            && let Err(e) = condition_check(condition)
+        {
+            problems.push(format!("route {}: {}", name, e));
+        }
+        for chain in &router.chain {
+            if !chains.contains(chain.as_str()) {
+                problems.push(format!("route {}: unknown chain {}", name, chain));
+            }
+        }
+    }
+
+    for policy in &config.topic_policy {
+        if !topic_filter_valid(&policy.topic, config.mqtt.settings.max_topic_length) {
+            problems.push(format!(
+                "topic_policy {}: invalid topic filter",
+                policy.topic
+            ));
+        }
+        if policy.max_qos.is_some_and(|qos| qos > 2) {
+            problems.push(format!("topic_policy {}: max_qos over 2", policy.topic));
+        }
+    }
+
+    for hook in &config.hook {
+        let url = reqwest::Url::parse(&hook.url);
+        if !url.is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+            problems.push(format!("hook {}: not an http or https url", hook.url));
+        }
+        if let Err(e) = crate::hook::headers(hook) {
+            problems.push(format!("hook {}: invalid header: {}", hook.url, e));
+        }
+    }
+
+    let mut spools = HashSet::new();
+    for sink in &config.sink {
+        let Some(spool) = &sink.spool else {
+            continue;
+        };
+        if !matches!(
+            sink.config,
+            SinkConfig::Kafka { .. } | SinkConfig::InfluxDb { .. }
+        ) {
+            problems.push(format!(
+                "sink {}: spool only kept by kafka and influxdb sinks",
+                sink.name
+            ));
+        }
+        if !spools.insert(spool.path.as_str()) {
+            problems.push(format!(
+                "sink {}: spool {} used twice",
+                sink.name, spool.path
+            ));
+        }
+    }
+    if let Some(spool) = config
+        .service
+        .sparkplug_b
+        .bridge
+        .as_ref()
+        .and_then(|bridge| bridge.spool.as_ref())
+        && !spools.insert(spool.path.as_str())
+    {
+        problems.push(format!(
+            "service.sparkplug_b.bridge: spool {} used twice",
+            spool.path
+        ));
+    }
+
+    if let Some(bridge) = config
+        .service
+        .sparkplug_b
+        .bridge
+        .as_ref()
+        .filter(|bridge| bridge.enable)
+    {
+        if bridge.host.is_empty() {
+            problems.push("service.sparkplug_b.bridge: no host".to_string());
+        }
+        if let Some(ca_path) = &bridge.ca_path
+            && let Err(e) = std::fs::metadata(ca_path)
+        {
+            problems.push(format!(
+                "service.sparkplug_b.bridge: failed to read {}: {}",
+                ca_path, e
+            ));
+        }
+    }
+
+    if let Some(cluster) = &config.node.cluster
+        && cluster.secret.is_none()
+        && !crate::cluster::is_loopback(&cluster.listen)
+    {
+        problems.push(format!(
+            "node.cluster: secret required to listen on {}",
+            cluster.listen
+        ));
+    }
+
+    if let Some(replication) = config
+        .node
+        .cluster
+        .as_ref()
+        .and_then(|cluster| cluster.replication.as_ref())
+    {
+        if !replication.members.contains(&config.node.id) {
+            problems.push(format!(
+                "node.cluster.replication: members without node {}",
+                config.node.id
+            ));
+        }
+        if replication.heartbeat_ms >= replication.election_timeout_ms {
+            problems.push(
+                "node.cluster.replication: heartbeat_ms not below election_timeout_ms".to_string(),
+            );
+        }
+    }
+
+    let mut tls = vec![];
+    if let Some(tcp_tls) = config.mqtt.listener.tcp_tls.as_ref().filter(|t| t.enable) {
+        tls.push((
+            "mqtt.listener.tcp_tls",
+            tls_options(
+                &tcp_tls.cert_path,
+                &tcp_tls.key_path,
+                &tcp_tls.ca_path,
+                tcp_tls.require_client_cert,
+            ),
+        ));
+    }
+    if let Some(wss) = config.mqtt.listener.wss.as_ref().filter(|w| w.enable) {
+        tls.push((
+            "mqtt.listener.wss",
+            tls_options(
+                &wss.cert_path,
+                &wss.key_path,
+                &wss.ca_path,
+                wss.require_client_cert,
+            ),
+        ));
+    }
+    if let Some(restful) = config
+        .service
+        .restful
+        .as_ref()
+        .and_then(|restful| restful.tls.as_ref())
+    {
+        tls.push((
+            "service.restful.tls",
+            tls_options(
+                &restful.cert_path,
+                &restful.key_path,
+                &restful.ca_path,
+                restful.require_client_cert,
+            ),
+        ));
+    }
+    if !tls.is_empty() {
+        // as the server does, see Server::start
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    }
+    for (what, options) in tls {
+        if let Err(e) = load_tls_config(&options) {
+            problems.push(format!("{}: failed to load the TLS files: {}", what, e));
+        }
+    }
+
+    for (what, common) in config.mqtt.listener.commons() {
+        if let Some(mountpoint) = &common.mountpoint
+            && (!topic_name_valid(mountpoint, config.mqtt.settings.max_topic_length)
+                || mountpoint.starts_with('$'))
+        {
+            problems.push(format!("{}: invalid mountpoint {}", what, mountpoint));
+        }
+        if common
+            .protocol_versions
+            .as_ref()
+            .is_some_and(|versions| versions.is_empty())
+        {
+            problems.push(format!("{}: protocol_versions accepts no version", what));
+        }
+    }
+
+    let binds = binds(config);
+    for (i, bind) in binds.iter().enumerate() {
+        for other in &binds[i + 1..] {
+            if bind.conflicts(other) {
+                problems.push(format!(
+                    "{} and {} both listen on port {}",
+                    bind.what, other.what, bind.port
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems() {
+        let config = Config::parse(
+            r#"
+            profile = "edge-small"
+            [node]
+            id = "n1"
+            [mqtt.listener.tcp]
+            host = "0.0.0.0"
+            [mqtt.listener.ws]
+            port = 1883
+            mountpoint = "tenant/+/"
+            protocol_versions = []
+            [mqtt.listener.tcp_tls]
+            cert_path = "missing.pem"
+            key_path = "missing.key"
+            [node.cluster]
+            listen = "0.0.0.0:1108"
+            [node.cluster.replication]
+            members = ["n2", "n3"]
+            path = "raft"
+            heartbeat_ms = 1000
+            [service.restful]
+            ip = "127.0.0.1"
+            port = 8883
+            [[processor]]
+            uuid = "not-a-uuid"
+            config = { type = "logger", level = "info" }
+            [[chain]]
+            name = "c1"
+            processors = ["not-a-uuid", ["00000000-0000-0000-0000-000000000001"]]
+            delivery = true
+            sinks = ["archive"]
+            on_error = { retries = 2, action = "dead_letter" }
+            [[router]]
+            topic = "a/#"
+            condition = "qos =="
+            chain = ["c1", "c2"]
+            [[topic_policy]]
+            topic = "a/#/b"
+            max_qos = 3
+            [[hook]]
+            url = "localhost:9000"
+            headers = { "X Token" = "secret" }
+            [[sink]]
+            name = "db"
+            config = { type = "influxdb", url = "http://127.0.0.1:8086", org = "o", bucket = "b", token = "t" }
+            spool = { path = "spool" }
+            [[sink]]
+            name = "raw"
+            config = { type = "s3", bucket = "raw" }
+            spool = { path = "spool" }
+            "#,
+            ".",
+        )
+        .unwrap();
+
+        let problems = problems(&config);
+        assert_eq!(problems.len(), 20, "{:?}", problems);
+        assert_eq!(problems[0], "processor not-a-uuid: invalid uuid");
+        assert_eq!(
+            problems[1],
+            "chain c1: unknown processor 00000000-0000-0000-0000-000000000001"
+        );
+        assert_eq!(problems[2], "chain c1: unknown sink archive");
+        assert_eq!(
+            problems[3],
+            "chain c1: on_error dead_letter without dead_letter_topic"
+        );
+        assert!(problems[4].starts_with("route route-0: invalid condition"));
+        assert_eq!(problems[5], "route route-0: unknown chain c2");
+        assert_eq!(problems[6], "topic_policy a/#/b: invalid topic filter");
+        assert_eq!(problems[7], "topic_policy a/#/b: max_qos over 2");
+        assert_eq!(problems[8], "hook localhost:9000: not an http or https url");
+        assert!(problems[9].starts_with("hook localhost:9000: invalid header"));
+        assert_eq!(
+            problems[10],
+            "sink raw: spool only kept by kafka and influxdb sinks"
+        );
+        assert_eq!(problems[11], "sink raw: spool ./spool used twice");
+        assert_eq!(
+            problems[12],
+            "node.cluster: secret required to listen on 0.0.0.0:1108"
+        );
+        assert_eq!(
+            problems[13],
+            "node.cluster.replication: members without node n1"
+        );
+        assert_eq!(
+            problems[14],
+            "node.cluster.replication: heartbeat_ms not below election_timeout_ms"
+        );
+        assert!(problems[15].starts_with("mqtt.listener.tcp_tls: failed to load the TLS files"));
+        assert_eq!(
+            problems[16],
+            "mqtt.listener.ws: invalid mountpoint tenant/+/"
+        );
+        assert_eq!(
+            problems[17],
+            "mqtt.listener.ws: protocol_versions accepts no version"
+        );
+        assert_eq!(
+            problems[18],
+            "mqtt.listener.tcp and mqtt.listener.ws both listen on port 1883"
+        );
+        assert_eq!(
+            problems[19],
+            "mqtt.listener.tcp_tls and service.restful both listen on port 8883"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use toml::{Table, Value};
+
+// AXONMQ__MQTT__LISTENER__TCP__PORT=1884 sets mqtt.listener.tcp.port
+const OVERRIDE_PREFIX: &str = "AXONMQ__";
+// password_file = "/run/secrets/db" sets password to the content of the file
+const FILE_SUFFIX: &str = "_file";
+
+/// apply the process environment to a parsed configuration, relative secret files are read
+/// from `dir`
+pub fn apply(config: &mut Table, dir: &str) -> Result<()> {
+    let vars: BTreeMap<String, String> = std::env::vars().collect();
+    apply_vars(config, &vars, Path::new(dir))
+}
+
+/// `AXONMQ__` overrides first, then `${VAR}` in string values, then the `*_file` keys
+fn apply_vars(config: &mut Table, vars: &BTreeMap<String, String>, dir: &Path) -> Result<()> {
+    for (name, value) in vars {
+        if let Some(path) = name.strip_prefix(OVERRIDE_PREFIX) {
+            let path: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+            set(config, &path, parse_value(value))
+                .with_context(|| format!("invalid override {}", name))?;
+        }
+    }
+    resolve(config, vars, dir, "")
+}
+
+/// a TOML value when it parses as one, `1884`, `true` or `["a", "b"]`, a string otherwise
+fn parse_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn set(table: &mut Table, path: &[String], value: Value) -> Result<()> {
+    let [key, rest @ ..] = path else {
+        bail!("empty key");
+    };
+    if rest.is_empty() {
+        table.insert(key.clone(), value);
+        return Ok(());
+    }
+    let entry = table
+        .entry(key.clone())
+        .or_insert_with(|| Value::Table(Table::new()));
+    match entry {
+        Value::Table(table) => set(table, rest, value),
+        // [[router]] and [[chain]] entries are addressed by index, ROUTER__0__TOPIC
+        Value::Array(array) => {
+            let [index, rest @ ..] = rest else {
+                unreachable!()
+            };
+            let index: usize = index
+                .parse()
+                .with_context(|| format!("{} is an array, {} is not an index", key, index))?;
+            match array.get_mut(index) {
+                Some(Value::Table(table)) if !rest.is_empty() => set(table, rest, value),
+                Some(item) if rest.is_empty() => {
+                    *item = value;
+                    Ok(())
+                }
+                _ => bail!("{}[{}] does not exist", key, index),
+            }
+        }
+        _ => bail!("{} is not a table", key),
+    }
+}
+
+fn resolve(
+    table: &mut Table,
+    vars: &BTreeMap<String, String>,
+    dir: &Path,
+    parent: &str,
+) -> Result<()> {
+    let file_keys: Vec<String> = table
+        .keys()
+        .filter(|key| key.len() > FILE_SUFFIX.len() && key.ends_with(FILE_SUFFIX))
+        .cloned()
+        .collect();
+
+    for (key, value) in table.iter_mut() {
+        let name = if parent.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", parent, key)
+        };
+        resolve_value(value, vars, dir, &name)?;
+    }
+
+    for key in file_keys {
+        let name = key.trim_end_matches(FILE_SUFFIX).to_string();
+        let Some(Value::String(path)) = table.remove(&key) else {
+            bail!("{} must be a file path", key);
+        };
+        let secret = std::fs::read_to_string(dir.join(&path))
+            .with_context(|| format!("failed to read {} from {}", key, path))?;
+        // secrets mounted by container runtimes usually end with a newline
+        table.insert(name, Value::String(secret.trim_end().to_string()));
+    }
+    Ok(())
+}
+
+fn resolve_value(
+    value: &mut Value,
+    vars: &BTreeMap<String, String>,
+    dir: &Path,
+    name: &str,
+) -> Result<()> {
+    match value {
+        Value::String(s) => *s = interpolate(s, vars).with_context(|| format!("in {}", name))?,
+        Value::Table(table) => resolve(table, vars, dir, name)?,
+        Value::Array(array) => {
+            for item in array {
+                resolve_value(item, vars, dir, name)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// replace `${VAR}` and `${VAR:-default}`, `$${` is a literal `${`
+fn interpolate(s: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').context("unterminated ${")?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match vars.get(name).map(String::as_str).or(default) {
+                Some(value) => out.push_str(value),
+                None => bail!("environment variable {} is not set", name),
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_env() {
+        let secret = std::env::temp_dir().join(format!("axonmq-secret-{}", std::process::id()));
+        std::fs::write(&secret, "s3cret\n").unwrap();
+
+        let mut config: Table = toml::from_str(
+            r#"
+            [mqtt.listener.tcp]
+            host = "${HOST:-0.0.0.0}"
+            port = 1883
+            [sink]
+            url = "http://${INFLUX}:8086/$${literal}"
+            [[router]]
+            topic = "a/b"
+            "#,
+        )
+        .unwrap();
+        let vars: BTreeMap<String, String> = [
+            ("INFLUX", "influx.local"),
+            ("AXONMQ__MQTT__LISTENER__TCP__PORT", "1884"),
+            ("AXONMQ__SINK__TOKEN_FILE", secret.to_str().unwrap()),
+            ("AXONMQ__ROUTER__0__TOPIC", "c/d"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        apply_vars(&mut config, &vars, Path::new(".")).unwrap();
+        std::fs::remove_file(&secret).ok();
+
+        let tcp = &config["mqtt"]["listener"]["tcp"];
+        assert_eq!(tcp["host"].as_str(), Some("0.0.0.0"));
+        assert_eq!(tcp["port"].as_integer(), Some(1884));
+        let sink = &config["sink"];
+        assert_eq!(
+            sink["url"].as_str(),
+            Some("http://influx.local:8086/${literal}")
+        );
+        assert_eq!(sink["token"].as_str(), Some("s3cret"));
+        assert!(sink.get("token_file").is_none());
+        assert_eq!(config["router"][0]["topic"].as_str(), Some("c/d"));
+
+        let mut config: Table = toml::from_str(r#"host = "${MISSING}""#).unwrap();
+        assert!(apply_vars(&mut config, &BTreeMap::new(), Path::new(".")).is_err());
+    }
+}
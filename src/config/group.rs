@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientGroup {
+    pub name: String,
+    // clients whose identifier starts with this prefix join the group
+    pub client_id_prefix: Option<String>,
+    pub username: Option<String>,
+    // maximum PUBLISH packets per second accepted from each member
+    pub publish_rate: Option<u32>,
+}
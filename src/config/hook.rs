@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Hook {
+    // the events are POSTed to this URL as JSON, one request each
+    pub url: String,
+    // the events sent, all of them when empty
+    #[serde(default)]
+    pub events: Vec<HookEvent>,
+    // only the client and subscription events of the clients whose identifier starts with it
+    pub client_id_prefix: Option<String>,
+    // only the node events of this Sparkplug B group
+    pub group_id: Option<String>,
+    // added to each request, an Authorization header for instance
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    // default is 5000
+    pub timeout_ms: Option<u64>,
+    // attempts after a failed one, with backoff, default is 3
+    pub max_retries: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EventsConfig {
+    #[serde(default)]
+    pub enable: bool,
+    // the events published on their $events/... topic, all of them when empty
+    #[serde(default)]
+    pub events: Vec<HookEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    ClientConnected,
+    ClientDisconnected,
+    // a disconnected session removed at the end of its expiry interval
+    SessionExpired,
+    SubscriptionCreated,
+    // NBIRTH and NDEATH of a Sparkplug B edge node
+    NodeBirth,
+    NodeDeath,
+}
@@ -1,155 +1,967 @@
 pub mod chain;
+pub mod check;
+pub mod env;
+pub mod group;
+pub mod hook;
+pub mod preset;
 pub mod processor;
+pub mod property_route;
 pub mod router;
+pub mod schedule;
+pub mod sink;
+pub mod topic_policy;
+
+use std::collections::BTreeMap;
 
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 use toml;
 
-use crate::processor::config::ProcessorConfig;
+use crate::mqtt::MqttProtocolVersion;
+use crate::processor::config::{LookupSource, ProcessorConfig};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    // named preset filling the settings left out, "edge-small", "gateway" or "cloud"
+    pub profile: Option<String>,
+    #[serde(default)]
     pub common: CommonConfig,
+    #[serde(default)]
+    pub log: LogConfig,
     pub node: NodeConfig,
     pub mqtt: MqttConfig,
+    #[serde(default)]
     pub router: Vec<router::Router>,
+    #[serde(default)]
     pub chain: Vec<chain::Chain>,
+    #[serde(default)]
     pub processor: Vec<processor::Processor>,
+    #[serde(default)]
+    pub sink: Vec<sink::Sink>,
+    #[serde(default)]
+    pub client_group: Vec<group::ClientGroup>,
+    #[serde(default)]
+    pub property_route: Vec<property_route::PropertyRoute>,
+    #[serde(default)]
+    pub topic_policy: Vec<topic_policy::TopicPolicy>,
+    #[serde(default)]
     pub service: ServiceConfig,
+    // OTLP export, the `telemetry` feature, nothing is exported without the section
+    pub telemetry: Option<TelemetryConfig>,
+    // security-relevant events, see audit
+    pub audit: Option<AuditConfig>,
+    // webhooks told about the broker lifecycle events, see hook
+    #[serde(default)]
+    pub hook: Vec<hook::Hook>,
+    // the same events published on the $events/... topics
+    pub events: Option<hook::EventsConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct RestfulConfig {
     pub ip: String,
     pub port: u16,
+    // HTTPS for the API and the dashboard, plain HTTP without the section
+    pub tls: Option<RestfulTlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestfulTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // CA of the client certificates, asked for but optional unless require_client_cert
+    pub ca_path: Option<String>,
+    pub require_client_cert: Option<bool>,
+    // seconds between checks of cert_path, key_path and ca_path for a renewal, 0 disables, default 60
+    pub cert_reload_interval: Option<u64>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct SpbRebirthConfig {
     pub on_seq_mismatch: bool,
     pub on_malformed_payload: bool,
+    // wait before asking the same node again, doubled on each request up to max_interval_secs
+    #[serde(default = "default_rebirth_min_interval")]
+    pub min_interval_secs: u64,
+    #[serde(default = "default_rebirth_max_interval")]
+    pub max_interval_secs: u64,
+    // rebirths before a node is escalated and no longer asked, 0 asks forever
+    #[serde(default = "default_rebirth_max_attempts")]
+    pub max_attempts: u32,
+    // topic the escalation is published on, it is only logged without one
+    pub alert_topic: Option<String>,
+}
+
+fn default_rebirth_min_interval() -> u64 {
+    5
+}
+
+fn default_rebirth_max_interval() -> u64 {
+    300
+}
+
+fn default_rebirth_max_attempts() -> u32 {
+    5
+}
+
+impl Default for SpbRebirthConfig {
+    fn default() -> Self {
+        SpbRebirthConfig {
+            on_seq_mismatch: false,
+            on_malformed_payload: true,
+            min_interval_secs: default_rebirth_min_interval(),
+            max_interval_secs: default_rebirth_max_interval(),
+            max_attempts: default_rebirth_max_attempts(),
+            alert_topic: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SpbHistoryConfig {
+    // values kept per metric, 0 turns the history off
+    pub depth: usize,
+    pub max_age_secs: u64,
+}
+
+impl Default for SpbHistoryConfig {
+    fn default() -> Self {
+        SpbHistoryConfig {
+            depth: 100,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+/// glob patterns of group ids, `*` and `?`
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct SpbGroupsConfig {
+    // the groups tracked, all of them when empty
+    pub allow: Vec<String>,
+    // the groups ignored, even when allowed
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SpbSeqConfig {
+    // check the seq of NDATA, DBIRTH, DDEATH and DDATA against the NBIRTH
+    pub validate: bool,
+    // messages ahead of the expected seq held back for the missing ones, 0 holds none
+    pub reorder_window: u8,
+    // gaps before rebirth_on_error.on_seq_mismatch requests a rebirth
+    pub gap_threshold: u32,
+}
+
+impl Default for SpbSeqConfig {
+    fn default() -> Self {
+        SpbSeqConfig {
+            validate: true,
+            reorder_window: 0,
+            gap_threshold: 1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SpbProjectionConfig {
+    pub enable: bool,
+    // {group_id}, {node_id} and {metric} are replaced, {device_id} too for device metrics
+    pub node_topic: String,
+    pub device_topic: String,
+    pub retain: bool,
+}
+
+impl Default for SpbProjectionConfig {
+    fn default() -> Self {
+        SpbProjectionConfig {
+            enable: false,
+            node_topic: "spb/json/{group_id}/{node_id}/{metric}".to_string(),
+            device_topic: "spb/json/{group_id}/{node_id}/{device_id}/{metric}".to_string(),
+            retain: false,
+        }
+    }
+}
+
+/// the central broker the edge node traffic is forwarded to
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SpbBridgeConfig {
+    #[serde(default)]
+    pub enable: bool,
+    pub host: String,
+    // 1883, 8883 with tls
+    pub port: Option<u16>,
+    // axonmq-bridge-{node.id} when not set
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    // the CA of the central broker, the system roots when not set
+    pub ca_path: Option<String>,
+    // the DATA messages received while the central broker is away, sent once it is back
+    pub spool: Option<SpoolConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+/// store and forward of a sink or of the Sparkplug B bridge: what the remote could not take is
+/// kept on disk and replayed in order once it is back
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SpoolConfig {
+    pub path: String,
+    // records are refused past this size on disk
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_spool_max_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct SpbConfig {
     pub enable: bool,
     pub application_id: String,
     pub rebirth_on_error: SpbRebirthConfig,
+    pub history: SpbHistoryConfig,
+    pub seq: SpbSeqConfig,
+    pub projection: SpbProjectionConfig,
+    // seconds a node stays online after its NDEATH, an NBIRTH within them hides the flap, 0 is
+    // no delay
+    pub offline_delay_secs: u64,
+    pub groups: SpbGroupsConfig,
+    // the bridge is not started without its section
+    pub bridge: Option<SpbBridgeConfig>,
+}
+
+impl Default for SpbConfig {
+    fn default() -> Self {
+        SpbConfig {
+            enable: false,
+            application_id: "axonmq_sparkplug_b_application".to_string(),
+            rebirth_on_error: SpbRebirthConfig::default(),
+            history: SpbHistoryConfig::default(),
+            seq: SpbSeqConfig::default(),
+            projection: SpbProjectionConfig::default(),
+            offline_delay_secs: 0,
+            groups: SpbGroupsConfig::default(),
+            bridge: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReplicaConfig {
+    pub enable: bool,
+    pub source: String,
+    pub sync_interval: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct ServiceConfig {
-    pub restful: RestfulConfig,
+    // the RESTful API is not started without its section
+    pub restful: Option<RestfulConfig>,
+    #[serde(default)]
     pub sparkplug_b: SpbConfig,
+    pub replica: Option<ReplicaConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct LogConfig {
+    // level of the axonmq targets, default is info
+    pub level: Option<String>,
+    // levels by target prefix, "axonmq::mqtt" = "debug", other crates are off unless listed
+    pub targets: BTreeMap<String, String>,
+    pub format: Option<LogFormat>,
+    // one output or a list of them
+    pub output: Option<LogOutputs>,
+    // directory of the daily log files, the platform default when not set
+    pub dir: Option<String>,
+    // unix datagram socket of the syslog output, default is /dev/log
+    pub syslog_socket: Option<String>,
+}
+
+impl LogConfig {
+    /// the outputs to write to, `both` is stdout and file
+    pub fn outputs(&self) -> Vec<LogOutput> {
+        let outputs = match &self.output {
+            None => vec![LogOutput::Both],
+            Some(LogOutputs::One(output)) => vec![*output],
+            Some(LogOutputs::Many(outputs)) => outputs.clone(),
+        };
+        let mut expanded = vec![];
+        for output in outputs {
+            let parts = match output {
+                LogOutput::Both => vec![LogOutput::Stdout, LogOutput::File],
+                output => vec![output],
+            };
+            for part in parts {
+                if !expanded.contains(&part) {
+                    expanded.push(part);
+                }
+            }
+        }
+        expanded
+    }
+}
+
+/// how a log line is written
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// time, level, target, spans and fields on one line
+    #[default]
+    Full,
+    /// the same without the span names
+    Compact,
+    /// each event over several lines, for reading on a terminal
+    Pretty,
+    /// one JSON object per line, for Loki, Elasticsearch and the like
+    Json,
+}
+
+/// where the log lines go
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOutput {
+    Stdout,
+    /// daily files in `dir`
+    File,
+    /// stdout and file
+    Both,
+    /// the local syslog daemon, unix only
+    Syslog,
+    /// the systemd journal with the fields of the events, Linux only
+    Journald,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum LogOutputs {
+    One(LogOutput),
+    Many(Vec<LogOutput>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TelemetryConfig {
+    #[serde(default)]
+    pub enable: bool,
+    // collector address, http://localhost:4317 for grpc and http://localhost:4318 for
+    // http_protobuf by default, /v1/traces and /v1/metrics are appended for http_protobuf
+    pub endpoint: Option<String>,
+    pub protocol: Option<OtlpProtocol>,
+    // service.name of the resource, default is axonmq
+    pub service_name: Option<String>,
+    // share of the traces started here which are exported, default is 1.0, a publish carrying
+    // a traceparent user property follows the decision of its trace
+    pub sample_ratio: Option<f64>,
+    pub traces: Option<bool>,
+    pub metrics: Option<bool>,
+    // how often the metrics are exported, default is 60
+    pub metrics_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub enable: bool,
+    // directory of the daily audit.YYYY-MM-DD.log files, no files without it
+    pub dir: Option<String>,
+    // days of audit files kept, default 90, 0 keeps them all
+    pub retention_days: Option<usize>,
+    // topic each audit event is also published on
+    pub topic: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// how the OTLP exporter talks to the collector
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OtlpProtocol {
+    /// gRPC, port 4317
+    #[default]
+    Grpc,
+    /// protobuf over HTTP, port 4318
+    HttpProtobuf,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct CommonConfig {
     pub core_threads: Option<usize>,
+    // optional subsystems turned off in this deployment, see features::Feature
+    #[serde(default)]
+    pub disabled_features: Vec<String>,
+    // seconds clients and processor chains get to drain on shutdown, default 5
+    pub shutdown_grace_secs: Option<u64>,
+    // tasks matching publishes against the subscriptions, default the number of CPU cores
+    pub matcher_workers: Option<usize>,
+    // commands the matcher and router drain from their queue at once, default 64, at most 256
+    pub operator_batch_size: Option<usize>,
+    // topics whose matching routes the router keeps, least recently used first out, default 10000
+    pub route_cache_size: Option<usize>,
+    // topics whose subscribers each matcher worker keeps, least recently used first out,
+    // default 10000
+    pub match_cache_size: Option<usize>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct NodeConfig {
     pub id: String,
+    pub cluster: Option<ClusterConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClusterConfig {
+    pub listen: String,
+    // proves each end of a peer connection belongs to the cluster, required off loopback
+    pub secret: Option<String>,
+    // address other nodes use to reach this one, defaults to listen
+    pub advertise: Option<String>,
+    #[serde(default)]
+    pub peers: Vec<String>,
+    pub gossip: Option<bool>,
+    pub reconnect_interval: Option<u64>,
+    pub share_strategy: Option<ShareStrategy>,
+    // raft replicated retained messages, sessions and Sparkplug B births, off when not set
+    pub replication: Option<ReplicationConfig>,
+}
+
+/// the raft group keeping the broker state on several nodes, so that a standby taking over
+/// has it already
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReplicationConfig {
+    // node ids of the voters, this node among them
+    pub members: Vec<String>,
+    // the raft log and snapshots
+    pub path: String,
+    #[serde(default = "default_replication_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+    #[serde(default = "default_replication_election_timeout_ms")]
+    pub election_timeout_ms: u64,
+    // applied entries kept in the log before it is compacted into a snapshot
+    #[serde(default = "default_replication_snapshot_entries")]
+    pub snapshot_entries: u64,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_replication_heartbeat_ms() -> u64 {
+    200
+}
+
+fn default_replication_election_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_replication_snapshot_entries() -> u64 {
+    10_000
+}
+
+/// how a share group with members on several nodes picks the member getting a message
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareStrategy {
+    /// round robin over the members of all the nodes, each member gets the same share
+    #[default]
+    RoundRobin,
+    /// the same member for all the messages on a topic, whichever node they are published on
+    Topic,
+    /// the same member for all the messages of a publisher
+    ClientId,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttConfig {
+    #[serde(default)]
     pub listener: MqttListenerConfig,
     pub settings: MqttSettings,
 }
 
-#[derive(Debug, Deserialize)]
+/// a listener is started when its section is present and not `enable = false`
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct MqttListenerConfig {
-    pub tcp: MqttListenerTcpConfig,
-    pub tcp_tls: MqttListenerTcpTlsConfig,
-    pub ws: MqttListenerWsConfig,
-    pub wss: MqttListenerWsTlsConfig,
+    pub tcp: Option<MqttListenerTcpConfig>,
+    pub tcp_tls: Option<MqttListenerTcpTlsConfig>,
+    pub ws: Option<MqttListenerWsConfig>,
+    pub wss: Option<MqttListenerWsTlsConfig>,
+}
+
+impl MqttListenerConfig {
+    /// the section and the shared settings of each listener in the configuration
+    pub fn commons(&self) -> impl Iterator<Item = (&'static str, &ListenerCommonConfig)> {
+        [
+            ("mqtt.listener.tcp", self.tcp.as_ref().map(|l| &l.common)),
+            (
+                "mqtt.listener.tcp_tls",
+                self.tcp_tls.as_ref().map(|l| &l.common),
+            ),
+            ("mqtt.listener.ws", self.ws.as_ref().map(|l| &l.common)),
+            ("mqtt.listener.wss", self.wss.as_ref().map(|l| &l.common)),
+        ]
+        .into_iter()
+        .filter_map(|(what, common)| Some((what, common?)))
+    }
+}
+
+/// the settings every kind of listener has, flattened into its section
+#[derive(Debug, Deserialize, Serialize, Default)]
+pub struct ListenerCommonConfig {
+    // expect a PROXY protocol v1/v2 header from a load balancer ahead of each connection
+    pub proxy_protocol: Option<bool>,
+    // connections open at once, the ones over it get CONNACK Server Busy
+    pub max_connections: Option<usize>,
+    // new connections per second, the ones over it are closed right away
+    pub max_connect_rate: Option<u32>,
+    // source networks let in, all of them when empty
+    #[serde(default)]
+    pub allow_cidrs: Vec<IpNet>,
+    // source networks closed right away, even when allowed
+    #[serde(default)]
+    pub deny_cidrs: Vec<IpNet>,
+    // overrides [mqtt.settings.client_limits] for the clients of this listener
+    #[serde(default)]
+    pub limits: ClientLimitsConfig,
+    // prefixed to the topics the clients of this listener publish and subscribe to, stripped
+    // from the ones they receive
+    pub mountpoint: Option<String>,
+    // the MQTT versions the clients of this listener may connect with, all when not set
+    pub protocol_versions: Option<Vec<MqttProtocolVersion>>,
+}
+
+fn default_enable() -> bool {
+    true
+}
+
+fn default_listener_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_tcp_port() -> u16 {
+    1883
+}
+
+fn default_tls_port() -> u16 {
+    8883
+}
+
+fn default_ws_port() -> u16 {
+    8081
+}
+
+fn default_wss_port() -> u16 {
+    8082
+}
+
+fn default_ws_path() -> String {
+    "/mqtt".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttListenerTcpConfig {
+    #[serde(default = "default_enable")]
+    pub enable: bool,
+    #[serde(default = "default_listener_host")]
     pub host: String,
+    #[serde(default = "default_tcp_port")]
     pub port: u16,
+    #[serde(flatten)]
+    pub common: ListenerCommonConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttListenerTcpTlsConfig {
+    #[serde(default = "default_enable")]
+    pub enable: bool,
+    #[serde(default = "default_listener_host")]
     pub host: String,
+    #[serde(default = "default_tls_port")]
     pub port: u16,
     pub cert_path: String,
     pub key_path: String,
+    pub ca_path: Option<String>,
+    pub require_client_cert: Option<bool>,
+    pub cert_as_client_id: Option<bool>,
+    // seconds between checks of cert_path, key_path and ca_path for a renewal, 0 disables, default 60
+    pub cert_reload_interval: Option<u64>,
+    #[serde(flatten)]
+    pub common: ListenerCommonConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttListenerWsConfig {
+    #[serde(default = "default_enable")]
+    pub enable: bool,
+    #[serde(default = "default_listener_host")]
     pub host: String,
+    #[serde(default = "default_ws_port")]
     pub port: u16,
+    #[serde(default = "default_ws_path")]
     pub path: String,
+    #[serde(flatten)]
+    pub common: ListenerCommonConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttListenerWsTlsConfig {
+    #[serde(default = "default_enable")]
+    pub enable: bool,
+    #[serde(default = "default_listener_host")]
     pub host: String,
+    #[serde(default = "default_wss_port")]
     pub port: u16,
+    #[serde(default = "default_ws_path")]
     pub path: String,
     pub cert_path: String,
     pub key_path: String,
+    pub ca_path: Option<String>,
+    pub require_client_cert: Option<bool>,
+    pub cert_as_client_id: Option<bool>,
+    // seconds between checks of cert_path, key_path and ca_path for a renewal, 0 disables, default 60
+    pub cert_reload_interval: Option<u64>,
+    #[serde(flatten)]
+    pub common: ListenerCommonConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct MqttSettings {
     pub max_topic_length: usize,
     pub session_expiry_interval: u32,
     pub keep_alive: u16,
+    pub max_keep_alive: Option<u16>,
+    pub zero_keep_alive: Option<ZeroKeepAlive>,
     pub max_receive_queue: u16,
     pub max_packet_size: u32,
     pub resend_interval: u64,
     pub max_store_msgs_per_client: usize,
+    pub max_store_bytes_per_client: Option<usize>,
+    pub store_overflow_policy: Option<StoreOverflowPolicy>,
     pub retain_cleanup_interval: u64,
     pub session_cleanup_interval: u64,
+    pub max_sessions: Option<usize>,
+    pub session_takeover: Option<SessionTakeover>,
     pub topic_alias_maximum: u16,
+    pub response_topic_prefix: Option<String>,
+    pub retain_store_path: Option<String>,
+    pub spill_dir: Option<String>,
+    pub spill_memory_threshold: Option<usize>,
+    pub spill_max_bytes: Option<u64>,
+    pub ws_send_queue: Option<usize>,
+    pub slow_consumer_policy: Option<SlowConsumerPolicy>,
+    #[serde(default)]
+    pub client_limits: ClientLimitsConfig,
+    // off when not set
+    pub auto_ban: Option<AutoBanConfig>,
+}
+
+/// bans the clients and addresses that misbehave `max_offenses` times within `window_secs`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AutoBanConfig {
+    #[serde(default = "default_auto_ban_max_offenses")]
+    pub max_offenses: u32,
+    #[serde(default = "default_auto_ban_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_auto_ban_ban_secs")]
+    pub ban_secs: u64,
+}
+
+fn default_auto_ban_max_offenses() -> u32 {
+    10
+}
+
+fn default_auto_ban_window_secs() -> u64 {
+    60
+}
+
+fn default_auto_ban_ban_secs() -> u64 {
+    600
+}
+
+/// what happens to a message for a client whose queue is full, or a WebSocket client whose
+/// outbound queue is full
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// drop the message
+    #[default]
+    Drop,
+    /// keep QoS 1/2 messages in the client's store queue until it catches up, QoS 0 dropped
+    Buffer,
+    /// disconnect the client with Quota Exceeded
+    Disconnect,
+}
+
+/// what happens when a client connects with the identifier of a connected client
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionTakeover {
+    /// disconnect the connected client with Session Taken Over
+    #[default]
+    KickOld,
+    /// refuse the new connection with Client Identifier Not Valid
+    RejectNew,
+    /// connect the new client as `{client_id}~{n}`, assigned to V5 clients in CONNACK
+    Suffix,
+}
+
+/// what a keep alive of 0, no keep alive at all, from a client turns into
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ZeroKeepAlive {
+    /// the client gets `keep_alive`, sent to V5 clients in CONNACK
+    #[default]
+    Override,
+    /// the connection is never closed for being idle
+    Allow,
+}
+
+/// what happens when a message for an offline client does not fit in its queue
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreOverflowPolicy {
+    /// drop the oldest queued messages
+    #[default]
+    DropOldest,
+    /// drop the new message
+    DropNewest,
+    /// drop the oldest queued messages of the lowest QoS, the new one when its QoS is lower
+    DropLowestQos,
+    /// end the session as if it expired, the client reconnects without a session present
+    DisconnectNotify,
+}
+
+/// limits on what a client publishes, unset fields are unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ClientLimitsConfig {
+    pub messages_per_sec: Option<u32>,
+    // payload bytes
+    pub bytes_per_sec: Option<u64>,
+    // QoS 2 publishes waiting for PUBREL, advertised to V5 clients as Receive Maximum
+    pub max_inflight: Option<u16>,
+    pub on_exceed: Option<QuotaPolicy>,
+}
+
+impl ClientLimitsConfig {
+    /// these limits with the fields set in `overrides` replaced
+    pub fn with_overrides(&self, overrides: &ClientLimitsConfig) -> ClientLimitsConfig {
+        ClientLimitsConfig {
+            messages_per_sec: overrides.messages_per_sec.or(self.messages_per_sec),
+            bytes_per_sec: overrides.bytes_per_sec.or(self.bytes_per_sec),
+            max_inflight: overrides.max_inflight.or(self.max_inflight),
+            on_exceed: overrides.on_exceed.or(self.on_exceed),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPolicy {
+    /// stop reading from the client until the next second
+    #[default]
+    Throttle,
+    /// drop the message, QoS 1/2 publishers get Quota Exceeded
+    Reject,
+    /// disconnect the client with Quota Exceeded
+    Disconnect,
 }
 
 impl Config {
     pub fn from_file(dir: &str) -> Result<Self> {
         let path = std::path::Path::new(dir).join("config.toml");
         let content = std::fs::read_to_string(path).context("failed to read config file")?;
-        let mut raw: Config = toml::from_str(&content).context("failed to parse config file")?;
-
-        raw.mqtt.listener.tcp_tls.cert_path = std::path::Path::new(dir)
-            .join(raw.mqtt.listener.tcp_tls.cert_path.as_str())
-            .to_str()
-            .unwrap()
-            .to_string();
-        raw.mqtt.listener.tcp_tls.key_path = std::path::Path::new(dir)
-            .join(raw.mqtt.listener.tcp_tls.key_path.as_str())
-            .to_str()
-            .unwrap()
-            .to_string();
-        raw.mqtt.listener.wss.cert_path = std::path::Path::new(dir)
-            .join(raw.mqtt.listener.wss.cert_path.as_str())
-            .to_str()
-            .unwrap()
-            .to_string();
-        raw.mqtt.listener.wss.key_path = std::path::Path::new(dir)
-            .join(raw.mqtt.listener.wss.key_path.as_str())
-            .to_str()
-            .unwrap()
-            .to_string();
-
-        raw.processor.iter_mut().for_each(|p| {
-            if let ProcessorConfig::Wasm { path, .. } = &mut p.config {
-                *path = std::path::Path::new(dir)
-                    .join(path.as_str())
-                    .to_str()
-                    .unwrap()
-                    .to_string();
+        Self::parse(&content, dir)
+    }
+
+    /// parse a configuration, relative paths are resolved against `dir`
+    pub fn parse(content: &str, dir: &str) -> Result<Self> {
+        let mut table: toml::Table =
+            toml::from_str(content).context("failed to parse config file")?;
+        env::apply(&mut table, dir)?;
+        preset::apply(&mut table)?;
+        let mut raw: Config = toml::Value::Table(table)
+            .try_into()
+            .context("failed to parse config file")?;
+        crate::features::validate(&raw.common.disabled_features)?;
+        crate::logging::LogLevels::from_config(&raw.log)?;
+
+        let resolve = |path: &mut String| {
+            *path = std::path::Path::new(dir)
+                .join(path.as_str())
+                .to_str()
+                .unwrap()
+                .to_string();
+        };
+        if let Some(tls) = raw.mqtt.listener.tcp_tls.as_mut() {
+            resolve(&mut tls.cert_path);
+            resolve(&mut tls.key_path);
+            if let Some(ca_path) = tls.ca_path.as_mut() {
+                resolve(ca_path);
+            }
+        }
+        if let Some(wss) = raw.mqtt.listener.wss.as_mut() {
+            resolve(&mut wss.cert_path);
+            resolve(&mut wss.key_path);
+            if let Some(ca_path) = wss.ca_path.as_mut() {
+                resolve(ca_path);
+            }
+        }
+        if let Some(tls) = raw
+            .service
+            .restful
+            .as_mut()
+            .and_then(|restful| restful.tls.as_mut())
+        {
+            resolve(&mut tls.cert_path);
+            resolve(&mut tls.key_path);
+            if let Some(ca_path) = tls.ca_path.as_mut() {
+                resolve(ca_path);
             }
+        }
+
+        if let Some(path) = raw.mqtt.settings.retain_store_path.as_mut() {
+            *path = std::path::Path::new(dir)
+                .join(path.as_str())
+                .to_str()
+                .unwrap()
+                .to_string();
+        }
+        if let Some(path) = raw.log.dir.as_mut() {
+            resolve(path);
+        }
+        if let Some(path) = raw.audit.as_mut().and_then(|audit| audit.dir.as_mut()) {
+            resolve(path);
+        }
+        if let Some(replication) = raw
+            .node
+            .cluster
+            .as_mut()
+            .and_then(|cluster| cluster.replication.as_mut())
+        {
+            resolve(&mut replication.path);
+        }
+        if let Some(spool) = raw
+            .service
+            .sparkplug_b
+            .bridge
+            .as_mut()
+            .and_then(|bridge| bridge.spool.as_mut())
+        {
+            resolve(&mut spool.path);
+        }
+        raw.sink
+            .iter_mut()
+            .filter_map(|sink| sink.spool.as_mut())
+            .for_each(|spool| resolve(&mut spool.path));
+        if let Some(path) = raw.mqtt.settings.spill_dir.as_mut() {
+            *path = std::path::Path::new(dir)
+                .join(path.as_str())
+                .to_str()
+                .unwrap()
+                .to_string();
+        }
+
+        raw.processor.iter_mut().for_each(|p| match &mut p.config {
+            ProcessorConfig::Wasm { path, .. } => resolve(path),
+            ProcessorConfig::Enrich {
+                source: LookupSource::File { path },
+                ..
+            } => resolve(path),
+            _ => {}
         });
 
         Ok(raw)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_optional_sections() {
+        let config = Config::parse(
+            r#"
+            profile = "edge-small"
+            [node]
+            id = "n1"
+            [mqtt.listener.tcp]
+            [mqtt.listener.ws]
+            enable = false
+            max_connections = 100
+            allow_cidrs = ["10.0.0.0/8"]
+            protocol_versions = ["5.0"]
+            limits = { messages_per_sec = 10 }
+            "#,
+            ".",
+        )
+        .unwrap();
+
+        let listeners = &config.mqtt.listener;
+        let tcp = listeners.tcp.as_ref().unwrap();
+        assert!(tcp.enable);
+        assert_eq!((tcp.host.as_str(), tcp.port), ("127.0.0.1", 1883));
+        assert!(!listeners.ws.as_ref().unwrap().enable);
+        assert!(listeners.tcp_tls.is_none() && listeners.wss.is_none());
+        // the settings shared by the listeners are flattened into each section
+        let commons: Vec<_> = listeners.commons().collect();
+        assert_eq!(commons.len(), 2);
+        assert_eq!(commons[0].0, "mqtt.listener.tcp");
+        assert!(commons[0].1.max_connections.is_none());
+        let (what, ws) = commons[1];
+        assert_eq!(what, "mqtt.listener.ws");
+        assert_eq!(ws.max_connections, Some(100));
+        assert_eq!(ws.allow_cidrs, ["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+        assert_eq!(ws.protocol_versions, Some(vec![MqttProtocolVersion::V5]));
+        assert_eq!(ws.limits.messages_per_sec, Some(10));
+
+        assert!(config.service.restful.is_none());
+        assert!(!config.service.sparkplug_b.enable);
+        assert!(
+            config
+                .service
+                .sparkplug_b
+                .rebirth_on_error
+                .on_malformed_payload
+        );
+        assert!(config.router.is_empty() && config.processor.is_empty());
+        assert_eq!(config.log.outputs(), [LogOutput::Stdout, LogOutput::File]);
+    }
+
+    #[test]
+    fn test_log_outputs() {
+        let log: LogConfig = toml::from_str(r#"output = "journald""#).unwrap();
+        assert_eq!(log.outputs(), [LogOutput::Journald]);
+        let log: LogConfig = toml::from_str(r#"output = ["syslog", "both", "stdout"]"#).unwrap();
+        assert_eq!(
+            log.outputs(),
+            [LogOutput::Syslog, LogOutput::Stdout, LogOutput::File]
+        );
+        assert!(toml::from_str::<LogConfig>(r#"output = "kafka""#).is_err());
+    }
+
+    #[test]
+    fn test_restful_tls() {
+        let config = Config::parse(
+            r#"
+            profile = "edge-small"
+            [node]
+            id = "n1"
+            [service.restful]
+            ip = "127.0.0.1"
+            port = 1107
+            [service.restful.tls]
+            cert_path = "certs/server.crt"
+            key_path = "certs/server.key"
+            "#,
+            "etc",
+        )
+        .unwrap();
+
+        let tls = config.service.restful.unwrap().tls.unwrap();
+        assert_eq!(tls.cert_path, "etc/certs/server.crt");
+        assert_eq!(tls.key_path, "etc/certs/server.key");
+        assert!(tls.ca_path.is_none() && tls.require_client_cert.is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use toml::{Table, Value};
+
+// small devices: few threads, short queues, retained messages kept on disk across power loss
+const EDGE_SMALL: &str = r#"
+[common]
+core_threads = 2
+
+[mqtt.settings]
+keep_alive = 60
+max_topic_length = 256
+session_expiry_interval = 86400
+max_receive_queue = 32
+max_packet_size = 262144
+resend_interval = 5
+max_store_msgs_per_client = 64
+retain_cleanup_interval = 30
+session_cleanup_interval = 300
+max_sessions = 1000
+topic_alias_maximum = 10
+retain_store_path = "data/retained.log"
+ws_send_queue = 64
+"#;
+
+// site gateways: a few thousand devices, offline queues spill to disk
+const GATEWAY: &str = r#"
+[common]
+core_threads = 4
+
+[mqtt.settings]
+keep_alive = 60
+max_topic_length = 256
+session_expiry_interval = 604800
+max_receive_queue = 128
+max_packet_size = 1048576
+resend_interval = 2
+max_store_msgs_per_client = 1024
+retain_cleanup_interval = 5
+session_cleanup_interval = 60
+max_sessions = 10000
+topic_alias_maximum = 30
+retain_store_path = "data/retained.log"
+spill_dir = "data/spill"
+spill_memory_threshold = 1024
+spill_max_bytes = 67108864
+ws_send_queue = 256
+"#;
+
+// cloud nodes: one worker per CPU core, large queues and sessions
+const CLOUD: &str = r#"
+[mqtt.settings]
+keep_alive = 60
+max_topic_length = 1024
+session_expiry_interval = 604800
+max_receive_queue = 1024
+max_packet_size = 8388608
+resend_interval = 2
+max_store_msgs_per_client = 10000
+retain_cleanup_interval = 5
+session_cleanup_interval = 60
+max_sessions = 1000000
+topic_alias_maximum = 100
+retain_store_path = "data/retained.log"
+spill_dir = "data/spill"
+spill_memory_threshold = 4096
+spill_max_bytes = 268435456
+ws_send_queue = 1024
+"#;
+
+pub const PRESETS: &[&str] = &["edge-small", "gateway", "cloud"];
+
+fn preset(name: &str) -> Option<&'static str> {
+    match name {
+        "edge-small" => Some(EDGE_SMALL),
+        "gateway" => Some(GATEWAY),
+        "cloud" => Some(CLOUD),
+        _ => None,
+    }
+}
+
+/// fill the keys missing from `config` with the defaults of its `profile`, explicit keys win
+pub fn apply(config: &mut Table) -> Result<()> {
+    let Some(name) = config.get("profile") else {
+        return Ok(());
+    };
+    let name = name.as_str().context("profile must be a string")?;
+    let defaults = preset(name).with_context(|| {
+        format!(
+            "unknown profile {}, expected one of {}",
+            name,
+            PRESETS.join(", ")
+        )
+    })?;
+    let defaults: Table = toml::from_str(defaults).expect("invalid built-in profile");
+    merge(config, defaults);
+    Ok(())
+}
+
+fn merge(config: &mut Table, defaults: Table) {
+    for (key, default) in defaults {
+        match (config.get_mut(&key), default) {
+            (None, default) => {
+                config.insert(key, default);
+            }
+            (Some(Value::Table(table)), Value::Table(default)) => merge(table, default),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_profile() {
+        let mut config: Table = toml::from_str(
+            r#"
+            profile = "gateway"
+            [mqtt.settings]
+            max_receive_queue = 16
+            "#,
+        )
+        .unwrap();
+        apply(&mut config).unwrap();
+
+        let settings = &config["mqtt"]["settings"];
+        assert_eq!(settings["max_receive_queue"].as_integer(), Some(16));
+        assert_eq!(settings["max_sessions"].as_integer(), Some(10000));
+        assert_eq!(config["common"]["core_threads"].as_integer(), Some(4));
+
+        let mut config: Table = toml::from_str(r#"profile = "huge""#).unwrap();
+        assert!(apply(&mut config).is_err());
+    }
+}
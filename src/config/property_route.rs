@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PropertyRoute {
+    // topic filter the rule applies to, all topics when omitted
+    pub topic: Option<String>,
+    // user property the message must carry, with this value when set
+    pub property: String,
+    pub value: Option<String>,
+    // the only share group receiving the matching messages
+    pub share_group: String,
+}
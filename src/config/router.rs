@@ -1,8 +1,32 @@
-use serde::Deserialize;
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize)]
+use super::schedule::Schedule;
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Router {
+    // used to switch the route over the RESTful API, defaults to route-<index>
+    pub name: Option<String>,
     pub topic: String,
     pub client_id: Option<String>,
+    // TLS SNI the publisher connected with
+    pub server_name: Option<String>,
+    // minijinja expression over the message, the route only applies when it is true
+    pub condition: Option<String>,
     pub chain: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub schedule: Option<Schedule>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// checks the syntax of a route condition
+pub fn condition_check(condition: &str) -> Result<(), String> {
+    Environment::new()
+        .compile_expression(condition)
+        .map(|_| ())
+        .map_err(|e| format!("invalid condition: {}", e))
 }
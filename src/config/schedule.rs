@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// daily time window a route or chain is active in
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Schedule {
+    // "mon", "tue", ... all days when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    // "HH:MM", an end before the start wraps past midnight
+    pub start: String,
+    pub end: String,
+    // "+02:00", the window is in UTC by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utc_offset: Option<String>,
+}
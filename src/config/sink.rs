@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+use super::SpoolConfig;
+use crate::operator::sink::config::SinkConfig;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Sink {
+    pub name: String,
+    pub config: SinkConfig,
+    // kafka and influxdb sinks keep what they could not deliver here
+    pub spool: Option<SpoolConfig>,
+}
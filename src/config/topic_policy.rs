@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// limits on what clients publish on the topics matching `topic`, every matching policy applies
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TopicPolicy {
+    // topic filter the policy applies to
+    pub topic: String,
+    // largest payload in bytes
+    pub max_payload_size: Option<usize>,
+    // highest QoS, 0 to 2
+    pub max_qos: Option<u8>,
+    // refuse retained messages
+    #[serde(default)]
+    pub no_retain: bool,
+}
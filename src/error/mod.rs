@@ -16,4 +16,7 @@ pub enum AxonError {
 
     #[error("Oneshot Receive Error: {0}")]
     OneshotRecvError(#[from] oneshot::error::RecvError),
+
+    #[error("HTTP Request Error: {0}")]
+    HttpRequestError(#[from] reqwest::Error),
 }
@@ -0,0 +1,132 @@
+//! optional subsystems: a feature can be left out of the build with cargo features, or turned
+//! off at runtime with `common.disabled_features`, whatever needs it then fails with the reason
+
+use serde::Serialize;
+
+use crate::CONFIG;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Cluster,
+    SparkplugB,
+    Replica,
+    KafkaSink,
+    S3Sink,
+    InfluxDbSink,
+    Chaos,
+    Telemetry,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FeatureInfo {
+    pub name: &'static str,
+    pub compiled: bool,
+    pub enabled: bool,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 8] = [
+        Feature::Cluster,
+        Feature::SparkplugB,
+        Feature::Replica,
+        Feature::KafkaSink,
+        Feature::S3Sink,
+        Feature::InfluxDbSink,
+        Feature::Chaos,
+        Feature::Telemetry,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Cluster => "cluster",
+            Feature::SparkplugB => "sparkplug_b",
+            Feature::Replica => "replica",
+            Feature::KafkaSink => "kafka_sink",
+            Feature::S3Sink => "s3_sink",
+            Feature::InfluxDbSink => "influxdb_sink",
+            Feature::Chaos => "chaos",
+            Feature::Telemetry => "telemetry",
+        }
+    }
+
+    pub fn compiled(&self) -> bool {
+        // the features cargo can leave out, the others are always built
+        let optional = [
+            (Feature::KafkaSink, cfg!(feature = "kafka")),
+            (Feature::S3Sink, cfg!(feature = "s3")),
+            (Feature::Chaos, cfg!(feature = "chaos")),
+            (Feature::Telemetry, cfg!(feature = "telemetry")),
+        ];
+        optional
+            .iter()
+            .find(|(feature, _)| feature == self)
+            .is_none_or(|(_, compiled)| *compiled)
+    }
+
+    fn disabled(&self) -> bool {
+        CONFIG.get().is_some_and(|config| {
+            config
+                .common
+                .disabled_features
+                .iter()
+                .any(|name| name == self.name())
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.compiled() && !self.disabled()
+    }
+
+    /// why the feature cannot be used, None when it is enabled
+    pub fn unavailable(&self) -> Option<String> {
+        if !self.compiled() {
+            Some(format!("{} is not compiled into this build", self.name()))
+        } else if self.disabled() {
+            Some(format!(
+                "{} is disabled by common.disabled_features",
+                self.name()
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn info(&self) -> FeatureInfo {
+        FeatureInfo {
+            name: self.name(),
+            compiled: self.compiled(),
+            enabled: self.enabled(),
+        }
+    }
+}
+
+pub fn list() -> Vec<FeatureInfo> {
+    Feature::ALL.iter().map(Feature::info).collect()
+}
+
+/// reject unknown names in `common.disabled_features`
+pub fn validate(names: &[String]) -> anyhow::Result<()> {
+    for name in names {
+        if !Feature::ALL.iter().any(|feature| feature.name() == name) {
+            let known = Feature::ALL.map(|feature| feature.name()).join(", ");
+            anyhow::bail!("unknown feature {}, expected one of {}", name, known);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&["cluster".to_string(), "kafka_sink".to_string()]).is_ok());
+        let err = validate(&["opcua".to_string()]).unwrap_err();
+        assert!(err.to_string().starts_with("unknown feature opcua"));
+
+        assert!(Feature::Cluster.compiled());
+        assert_eq!(Feature::Chaos.compiled(), cfg!(feature = "chaos"));
+        assert_eq!(list().len(), Feature::ALL.len());
+    }
+}
@@ -0,0 +1,331 @@
+//! the event hooks, `[[hook]]`: client connects and disconnects, expired sessions, new
+//! subscriptions and Sparkplug B node births and deaths are POSTed as JSON to webhooks, and
+//! published on `$events/...` topics with `[events]`, see `Event::topic`
+//!
+//! the clients cannot publish on the `$` topics, and `#` or `+/...` subscriptions do not
+//! receive them, a `$events/#` subscription or route does
+//!
+//! each hook has its own queue and sends its events one at a time, in order, retrying the
+//! failed requests with backoff, a hook which does not keep up loses the events past its queue
+
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::CONFIG;
+use crate::config::hook::{Hook, HookEvent};
+use crate::mqtt::QoS;
+use crate::mqtt::protocol::publish::PublishOptions;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::utils::time::now_milliseconds;
+
+const QUEUE_SIZE: usize = 4096;
+// events waiting for each hook
+const HOOK_QUEUE_SIZE: usize = 1024;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+// the client identifier of the events published on $events/...
+const PUBLISHER: &str = "axonmq-events";
+
+static EVENTS: OnceLock<mpsc::Sender<Event>> = OnceLock::new();
+// events lost because the hook task did not keep up
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ClientConnected {
+        client_id: String,
+        address: String,
+        version: String,
+        clean_start: bool,
+        session_expiry_interval: u32,
+    },
+    ClientDisconnected {
+        client_id: String,
+        address: String,
+        code: String,
+        session_kept: bool,
+    },
+    SessionExpired {
+        client_id: String,
+    },
+    SubscriptionCreated {
+        client_id: String,
+        topic: String,
+        qos: u8,
+    },
+    NodeBirth {
+        group_id: String,
+        node_id: String,
+    },
+    NodeDeath {
+        group_id: String,
+        node_id: String,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> HookEvent {
+        match self {
+            Event::ClientConnected { .. } => HookEvent::ClientConnected,
+            Event::ClientDisconnected { .. } => HookEvent::ClientDisconnected,
+            Event::SessionExpired { .. } => HookEvent::SessionExpired,
+            Event::SubscriptionCreated { .. } => HookEvent::SubscriptionCreated,
+            Event::NodeBirth { .. } => HookEvent::NodeBirth,
+            Event::NodeDeath { .. } => HookEvent::NodeDeath,
+        }
+    }
+
+    /// where `[events]` publishes it
+    fn topic(&self) -> &'static str {
+        match self {
+            Event::ClientConnected { .. } => "$events/client/connected",
+            Event::ClientDisconnected { .. } => "$events/client/disconnected",
+            Event::SessionExpired { .. } => "$events/session/expired",
+            Event::SubscriptionCreated { .. } => "$events/subscription/created",
+            Event::NodeBirth { .. } => "$events/spb/node/online",
+            Event::NodeDeath { .. } => "$events/spb/node/offline",
+        }
+    }
+
+    fn client_id(&self) -> Option<&str> {
+        match self {
+            Event::ClientConnected { client_id, .. }
+            | Event::ClientDisconnected { client_id, .. }
+            | Event::SessionExpired { client_id }
+            | Event::SubscriptionCreated { client_id, .. } => Some(client_id),
+            Event::NodeBirth { .. } | Event::NodeDeath { .. } => None,
+        }
+    }
+
+    fn group_id(&self) -> Option<&str> {
+        match self {
+            Event::NodeBirth { group_id, .. } | Event::NodeDeath { group_id, .. } => Some(group_id),
+            _ => None,
+        }
+    }
+}
+
+/// whether `hook` is told about `event`, the client filter leaves the node events alone and
+/// the group filter the client events
+fn wanted(hook: &Hook, event: &Event) -> bool {
+    (hook.events.is_empty() || hook.events.contains(&event.kind()))
+        && event.client_id().is_none_or(|client_id| {
+            hook.client_id_prefix
+                .as_deref()
+                .is_none_or(|prefix| client_id.starts_with(prefix))
+        })
+        && event
+            .group_id()
+            .is_none_or(|group_id| hook.group_id.as_deref().is_none_or(|g| g == group_id))
+}
+
+/// the JSON body of a request
+#[derive(Serialize)]
+struct Body<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: u64,
+    node: &'a str,
+}
+
+fn body(event: &Event, node: &str) -> serde_json::Result<String> {
+    serde_json::to_string(&Body {
+        event,
+        timestamp: now_milliseconds(),
+        node,
+    })
+}
+
+/// hands an event to the hook task, nothing is done without `[[hook]]` or `[events]`
+pub(crate) fn emit(event: Event) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    if events.try_send(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn headers(hook: &Hook) -> anyhow::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &hook.headers {
+        headers.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+    }
+    Ok(headers)
+}
+
+async fn post(client: &reqwest::Client, hook: &Hook, headers: &HeaderMap, body: String) {
+    let max_retries = hook.max_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+    for attempt in 0..=max_retries {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_millis(500 << (attempt - 1).min(5))).await;
+        }
+
+        let result = client
+            .post(&hook.url)
+            .headers(headers.clone())
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp)
+                if resp.status().is_server_error()
+                    || resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                debug!(
+                    "hook {} failed with {}, attempt {}",
+                    hook.url,
+                    resp.status(),
+                    attempt + 1
+                );
+            }
+            Ok(resp) => {
+                warn!("hook {} rejected an event with {}", hook.url, resp.status());
+                return;
+            }
+            Err(e) => {
+                debug!("hook {} failed: {}, attempt {}", hook.url, e, attempt + 1);
+            }
+        }
+    }
+    warn!("hook {} dropped an event after retries", hook.url);
+}
+
+async fn deliver(hook: Hook, headers: HeaderMap, mut events: mpsc::Receiver<Event>) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(
+            hook.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+        ))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("hook {} disabled: {}", hook.url, e);
+            return;
+        }
+    };
+    let node = &CONFIG.get().unwrap().node.id;
+    while let Some(event) = events.recv().await {
+        match body(&event, node) {
+            Ok(body) => post(&client, &hook, &headers, body).await,
+            Err(e) => warn!("failed to serialize a hook event: {}", e),
+        }
+    }
+}
+
+/// takes the emitted events until the server stops and passes them to the hooks wanting them
+pub(crate) async fn run(operator_helper: OperatorHelper) {
+    let config = CONFIG.get().unwrap();
+    let published = config.events.as_ref().filter(|events| events.enable);
+    let mut hooks = vec![];
+    for hook in &config.hook {
+        let headers = match headers(hook) {
+            Ok(headers) => headers,
+            Err(e) => {
+                warn!("hook {} disabled, invalid header: {}", hook.url, e);
+                continue;
+            }
+        };
+        let (tx, rx) = mpsc::channel(HOOK_QUEUE_SIZE);
+        tokio::spawn(deliver(hook.clone(), headers, rx));
+        hooks.push((hook, tx));
+    }
+    if hooks.is_empty() && published.is_none() {
+        return;
+    }
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+    if EVENTS.set(tx).is_err() {
+        return;
+    }
+
+    while let Some(event) = rx.recv().await {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "{} hook events were dropped, the hook queue was full",
+                dropped
+            );
+        }
+        for (hook, tx) in &hooks {
+            if wanted(hook, &event) && tx.try_send(event.clone()).is_err() {
+                warn!("hook {} dropped an event, it does not keep up", hook.url);
+            }
+        }
+        if let Some(published) = published
+            && (published.events.is_empty() || published.events.contains(&event.kind()))
+            && let Ok(body) = body(&event, &config.node.id)
+        {
+            let _ = operator_helper
+                .publish(
+                    PUBLISHER.to_string(),
+                    None,
+                    false,
+                    QoS::AtMostOnce,
+                    event.topic().to_string(),
+                    Bytes::from(body),
+                    vec![],
+                    PublishOptions::default(),
+                )
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wanted() {
+        let hook: Hook = toml::from_str(
+            r#"
+            url = "http://127.0.0.1:9000/events"
+            events = ["client_connected", "node_birth"]
+            client_id_prefix = "plc-"
+            group_id = "plant"
+            "#,
+        )
+        .unwrap();
+        let connected = |client_id: &str| Event::ClientConnected {
+            client_id: client_id.to_string(),
+            address: "127.0.0.1:5000".to_string(),
+            version: "V5".to_string(),
+            clean_start: true,
+            session_expiry_interval: 0,
+        };
+        let birth = |group_id: &str| Event::NodeBirth {
+            group_id: group_id.to_string(),
+            node_id: "line1".to_string(),
+        };
+        assert!(wanted(&hook, &connected("plc-1")));
+        assert!(!wanted(&hook, &connected("app-1")));
+        assert!(wanted(&hook, &birth("plant")));
+        assert!(!wanted(&hook, &birth("lab")));
+        let expired = Event::SessionExpired {
+            client_id: "plc-1".to_string(),
+        };
+        assert!(!wanted(&hook, &expired));
+
+        let body = serde_json::to_value(Body {
+            event: &connected("plc-1"),
+            timestamp: 1,
+            node: "n1",
+        })
+        .unwrap();
+        assert_eq!(body["event"], "client_connected");
+        assert_eq!(body["client_id"], "plc-1");
+        assert_eq!(body["clean_start"], true);
+        assert_eq!(body["node"], "n1");
+    }
+}
@@ -0,0 +1,39 @@
+pub mod audit;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod cluster;
+pub mod config;
+mod error;
+mod features;
+mod hook;
+pub mod logging;
+mod mqtt;
+mod operator;
+mod processor;
+mod server;
+mod service;
+mod spool;
+mod supervisor;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+mod utils;
+
+pub use mqtt::QoS;
+pub use mqtt::helper::BrokerHelper;
+pub use operator::helper::Helper as OperatorHelper;
+pub use server::{Listener, Server, ServerBuilder};
+pub use supervisor::{SubsystemInfo, SubsystemState};
+
+static CONFIG: std::sync::OnceLock<config::Config> = std::sync::OnceLock::new();
+
+pub fn get_default_log_dir() -> &'static str {
+    if cfg!(windows) {
+        format!(r"{}\\AxonMQ\\logs\\", std::env::var("ProgramData").unwrap()).leak()
+    } else if cfg!(target_os = "macos") {
+        "logs"
+    } else if cfg!(target_os = "linux") {
+        "/var/log/axonmq/"
+    } else {
+        "logs"
+    }
+}
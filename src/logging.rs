@@ -0,0 +1,257 @@
+//! the log filter of the process, `[log]`, and its changes at runtime through
+//! `PUT /api/v1/admin/log-level`, and the syslog writer of the `syslog` output
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use serde::Serialize;
+use tracing::{Level, Metadata};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::reload;
+
+use crate::config::LogConfig;
+
+const DEFAULT_LEVEL: &str = "info";
+
+/// the level of the axonmq targets and the levels by target prefix
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogLevels {
+    pub level: String,
+    pub targets: BTreeMap<String, String>,
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level).map_err(|_| {
+        format!(
+            "invalid log level {}, expected off, error, warn, info, debug or trace",
+            level
+        )
+    })
+}
+
+impl LogLevels {
+    pub fn from_config(config: &LogConfig) -> anyhow::Result<Self> {
+        let levels = LogLevels {
+            level: config
+                .level
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LEVEL.to_string())
+                .to_lowercase(),
+            targets: config
+                .targets
+                .iter()
+                .map(|(target, level)| (target.clone(), level.to_lowercase()))
+                .collect(),
+        };
+        levels.filter().map_err(|e| anyhow::anyhow!(e))?;
+        Ok(levels)
+    }
+
+    /// the filter of the log layers, a target listed in `targets` overrides `level`
+    pub fn filter(&self) -> Result<Targets, String> {
+        let mut filter = Targets::new().with_target("axonmq", parse_level(&self.level)?);
+        for (target, level) in &self.targets {
+            filter = filter.with_target(target.clone(), parse_level(level)?);
+        }
+        Ok(filter)
+    }
+}
+
+type Apply = Box<dyn Fn(Targets) -> Result<(), String> + Send + Sync>;
+
+/// changes the filter installed with a `reload` layer, handed to the server with
+/// `ServerBuilder::log_control`
+#[derive(Clone)]
+pub struct LogControl {
+    levels: Arc<Mutex<LogLevels>>,
+    apply: Arc<Apply>,
+}
+
+impl LogControl {
+    pub fn new<S: 'static>(handle: reload::Handle<Targets, S>, levels: LogLevels) -> Self {
+        LogControl {
+            levels: Arc::new(Mutex::new(levels)),
+            apply: Arc::new(Box::new(move |filter| {
+                handle.reload(filter).map_err(|e| e.to_string())
+            })),
+        }
+    }
+
+    pub fn levels(&self) -> LogLevels {
+        self.levels.lock().unwrap().clone()
+    }
+
+    /// sets the level of `target`, of the axonmq targets without one, a target without a
+    /// level goes back to the level of the axonmq targets
+    pub fn set(&self, target: Option<String>, level: Option<String>) -> Result<LogLevels, String> {
+        let mut levels = self.levels.lock().unwrap();
+        let mut updated = levels.clone();
+        match (target, level) {
+            (None, Some(level)) => updated.level = level.to_lowercase(),
+            (Some(target), Some(level)) => {
+                updated.targets.insert(target, level.to_lowercase());
+            }
+            (Some(target), None) => {
+                updated.targets.remove(&target);
+            }
+            (None, None) => return Err("a level or a target is required".to_string()),
+        }
+        (self.apply)(updated.filter()?)?;
+        *levels = updated;
+        Ok(levels.clone())
+    }
+}
+
+pub const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+
+// daemon
+#[cfg(unix)]
+const SYSLOG_FACILITY: u8 = 3;
+
+/// sends each log line as a datagram to the local syslog daemon, its severity from the level
+#[cfg(unix)]
+pub struct Syslog {
+    socket: UnixDatagram,
+    // <PRI> is prepended for each line
+    tag: String,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    pub fn connect(path: &str, identifier: &str) -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Syslog {
+            socket,
+            tag: format!("{}[{}]: ", identifier, std::process::id()),
+        })
+    }
+
+    fn line(&self, level: Option<&Level>) -> SyslogLine<'_> {
+        let severity = match level {
+            Some(&Level::ERROR) => 3,
+            Some(&Level::WARN) => 4,
+            Some(&Level::INFO) | None => 6,
+            Some(_) => 7,
+        };
+        let mut buf = format!("<{}>", SYSLOG_FACILITY * 8 + severity).into_bytes();
+        buf.extend_from_slice(self.tag.as_bytes());
+        SyslogLine {
+            syslog: self,
+            header: buf.len(),
+            buf,
+        }
+    }
+}
+
+/// one event, sent when the formatter is done with it
+#[cfg(unix)]
+pub struct SyslogLine<'a> {
+    syslog: &'a Syslog,
+    header: usize,
+    buf: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl std::io::Write for SyslogLine<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SyslogLine<'_> {
+    fn drop(&mut self) {
+        while self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        if self.buf.len() > self.header {
+            // nowhere to report a lost log line
+            let _ = self.syslog.socket.send(&self.buf);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(None)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.line(Some(meta.level()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::Registry;
+
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let config: LogConfig = toml::from_str("[targets]\n\"axonmq::mqtt\" = \"DEBUG\"").unwrap();
+        let levels = LogLevels::from_config(&config).unwrap();
+        assert_eq!(levels.level, "info");
+        let filter = levels.filter().unwrap();
+        assert!(filter.would_enable("axonmq::mqtt::listener", &Level::DEBUG));
+        assert!(!filter.would_enable("axonmq::operator", &Level::DEBUG));
+        assert!(!filter.would_enable("warp", &Level::ERROR));
+
+        let (filter, handle) = reload::Layer::<Targets, Registry>::new(levels.filter().unwrap());
+        let control = LogControl::new(handle, levels);
+        control.set(None, Some("trace".to_string())).unwrap();
+        control.set(Some("axonmq::mqtt".to_string()), None).unwrap();
+        assert!(
+            control
+                .set(Some("warp".to_string()), Some("loud".to_string()))
+                .is_err()
+        );
+        let levels = control.levels();
+        assert_eq!((levels.level.as_str(), levels.targets.len()), ("trace", 0));
+        drop(filter);
+
+        let config: LogConfig = toml::from_str("level = \"verbose\"").unwrap();
+        assert!(LogLevels::from_config(&config).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_syslog() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("axonmq-syslog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::connect(path.to_str().unwrap(), "axonmq").unwrap();
+
+        let mut line = syslog.line(Some(&Level::WARN));
+        line.write_all(b"queue full\n").unwrap();
+        drop(line);
+        // nothing is sent for an empty line
+        drop(syslog.make_writer());
+
+        let mut buf = [0u8; 256];
+        let n = daemon.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..n]).unwrap(),
+            format!("<28>axonmq[{}]: queue full", std::process::id())
+        );
+        daemon.set_nonblocking(true).unwrap();
+        assert!(daemon.recv(&mut buf).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,82 +1,127 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use coarsetime;
 use tokio::runtime::Builder;
-use tracing::Level;
-use tracing::{info, warn};
+use tracing::info;
 use tracing_appender;
-use tracing_subscriber::filter::Targets;
-use tracing_subscriber::{Layer, Registry, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    Layer, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
-mod config;
-mod error;
-mod mqtt;
-mod operator;
-mod processor;
-mod service;
-mod utils;
+use axonmq::config::{LogFormat, LogOutput};
+#[cfg(unix)]
+use axonmq::logging::{DEFAULT_SYSLOG_SOCKET, Syslog};
+use axonmq::logging::{LogControl, LogLevels};
+#[cfg(feature = "telemetry")]
+use axonmq::telemetry::Telemetry;
+use axonmq::{Server, config, get_default_log_dir};
 
-use crate::mqtt::{listener, server};
-
-static CONFIG: std::sync::OnceLock<config::Config> = std::sync::OnceLock::new();
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Cmd {
     #[arg(short, long, value_name = "config directory", default_value = "./")]
     config_dir: String,
+    /// Check the configuration and print it with the defaults filled in, without starting
+    #[arg(long)]
+    check_config: bool,
 }
 
-pub fn get_default_log_dir() -> &'static str {
-    if cfg!(windows) {
-        format!(r"{}\\AxonMQ\\logs\\", std::env::var("ProgramData").unwrap()).leak()
-    } else if cfg!(target_os = "macos") {
-        "logs"
-    } else if cfg!(target_os = "linux") {
-        "/var/log/axonmq/"
-    } else {
-        "logs"
+fn fmt_layer<W>(format: LogFormat, ansi: bool, writer: W) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_ansi(ansi).with_writer(writer);
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
     }
 }
 
+// the daemon stamps the time and takes the level as the severity
+#[cfg(unix)]
+fn syslog_layer(format: LogFormat, syslog: Syslog) -> BoxedLayer {
+    let layer = fmt::layer()
+        .with_ansi(false)
+        .without_time()
+        .with_level(false)
+        .with_writer(syslog);
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+// the effective configuration on stdout, the problems on stderr
+fn check_config(config: &config::Config) -> Result<()> {
+    print!("{}", toml::to_string(config)?);
+    let problems = config::check::problems(config);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems found in the configuration", problems.len());
+    }
+    eprintln!("Configuration OK");
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let cli = Cmd::parse();
 
     let config = config::Config::from_file(&cli.config_dir)?;
-    CONFIG.set(config).unwrap();
-
-    let filter = Targets::new()
-        .with_target("axonmq", Level::INFO)
-        .with_target("axonmq::service", Level::INFO)
-        .with_target("axonmq::service::sparkplug_b", Level::INFO)
-        .with_target("axonmq::mqtt", Level::INFO)
-        .with_target("axonmq::operator::matcher", Level::INFO)
-        .with_target("axonmq::processor::wasm", Level::INFO)
-        .with_target("axonmq::processor::processors::logger", Level::INFO);
-
-    let file_appender = tracing_appender::rolling::daily(get_default_log_dir(), "axonmq.log");
-    let (nb, _guard) = tracing_appender::non_blocking(file_appender);
-    let file_layer = fmt::layer()
-        .with_ansi(false)
-        .with_writer(nb)
-        .with_filter(filter.clone());
-
-    let stdout_layer = fmt::layer()
-        .with_ansi(true)
-        .with_writer(std::io::stdout)
-        .with_filter(filter.clone());
-
-    Registry::default()
-        .with(file_layer)
-        .with(stdout_layer)
-        .init();
+    if cli.check_config {
+        return check_config(&config);
+    }
 
-    let config = CONFIG.get().unwrap();
-    info!(
-        "Hello, AxonMQ v{}: {}!",
-        env!("CARGO_PKG_VERSION"),
-        config.node.id
-    );
+    // the filter of the log layers, changed at runtime through the RESTful API
+    let levels = LogLevels::from_config(&config.log)?;
+    let (filter, handle) = reload::Layer::new(levels.filter().map_err(anyhow::Error::msg)?);
+    let log_control = LogControl::new(handle, levels);
+
+    let format = config.log.format.unwrap_or_default();
+    let mut log_layers = vec![];
+    let mut _guard = None;
+    for output in config.log.outputs() {
+        match output {
+            LogOutput::Stdout => {
+                // escape codes would end up in whatever ingests the JSON
+                let ansi = format != LogFormat::Json;
+                log_layers.push(fmt_layer(format, ansi, std::io::stdout));
+            }
+            LogOutput::File | LogOutput::Both => {
+                let dir = config.log.dir.as_deref().unwrap_or(get_default_log_dir());
+                let file_appender = tracing_appender::rolling::daily(dir, "axonmq.log");
+                let (nb, guard) = tracing_appender::non_blocking(file_appender);
+                _guard = Some(guard);
+                log_layers.push(fmt_layer(format, false, nb));
+            }
+            #[cfg(unix)]
+            LogOutput::Syslog => {
+                let socket = config
+                    .log
+                    .syslog_socket
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SYSLOG_SOCKET);
+                let syslog = Syslog::connect(socket, "axonmq")
+                    .with_context(|| format!("failed to connect to syslog at {}", socket))?;
+                log_layers.push(syslog_layer(format, syslog));
+            }
+            #[cfg(target_os = "linux")]
+            LogOutput::Journald => {
+                let journald = tracing_journald::layer()
+                    .context("failed to connect to journald")?
+                    .with_syslog_identifier("axonmq".to_string());
+                log_layers.push(journald.boxed());
+            }
+            #[allow(unreachable_patterns)]
+            output => anyhow::bail!("log output {:?} is not supported on this platform", output),
+        }
+    }
 
     let runtime = if let Some(core_threads) = config.common.core_threads {
         Builder::new_multi_thread()
@@ -87,78 +132,45 @@ fn main() -> Result<()> {
         Builder::new_multi_thread().enable_all().build()?
     };
 
-    runtime.block_on(async {
-        coarsetime::Updater::new(100).start().unwrap();
-
-        let mut spb_service = if CONFIG.get().unwrap().service.sparkplug_b.enable {
-            Some(service::sparkplug_b::SparkPlugBApplication::new())
-        } else {
-            None
-        };
-        let spb_helper = spb_service.as_ref().map(|s| s.helper());
-        let spb_in_helper = spb_service.as_mut().map(|s| s.in_helper().clone());
-
-        let mut operator = operator::Operator::new().await;
-        let operator_helper = operator.helper();
-        operator.run(spb_helper.clone());
-
-        if let Some(spb_service) = spb_service.as_mut() {
-            spb_service.run(operator_helper.clone()).await;
+    // the gRPC exporter needs the runtime to connect
+    #[cfg(feature = "telemetry")]
+    let (telemetry, telemetry_layer) = {
+        let _runtime = runtime.enter();
+        match Telemetry::init(&config)? {
+            Some((telemetry, layer)) => (Some(telemetry), layer),
+            None => (None, None),
         }
+    };
+    #[cfg(not(feature = "telemetry"))]
+    let telemetry_layer: Option<BoxedLayer> = None;
 
-        let mut broker = server::Broker::new().await;
-        let broker_helper = broker.get_helper();
-        broker.run(operator_helper.clone()).await;
-
-        let tcp_listener_config = &config.mqtt.listener.tcp;
-        listener::spawn_tcp_listener(
-            tcp_listener_config.host.clone(),
-            tcp_listener_config.port,
-            broker_helper.clone(),
-            operator_helper.clone(),
-        );
-
-        let tls_listener_config = &config.mqtt.listener.tcp_tls;
-        listener::spawn_tls_listener(
-            tls_listener_config.host.clone(),
-            tls_listener_config.port,
-            tls_listener_config.cert_path.clone(),
-            tls_listener_config.key_path.clone(),
-            broker_helper.clone(),
-            operator_helper.clone(),
-        );
-
-        let ws_listener_config = &config.mqtt.listener.ws;
-        listener::spawn_ws_listener(
-            ws_listener_config.host.clone(),
-            ws_listener_config.port,
-            ws_listener_config.path.clone(),
-            broker_helper.clone(),
-            operator_helper.clone(),
-        );
-
-        let wss_listener_config = &config.mqtt.listener.wss;
-        listener::spawn_wss_listener(
-            wss_listener_config.host.clone(),
-            wss_listener_config.port,
-            wss_listener_config.path.clone(),
-            wss_listener_config.cert_path.clone(),
-            wss_listener_config.key_path.clone(),
-            broker_helper.clone(),
-            operator_helper.clone(),
-        );
-
-        if let Ok(restful) =
-            service::restful::RESTful::new(&config.service.restful.ip, config.service.restful.port)
-        {
-            info!("AxonMQ started. Press Ctrl+C to exit.");
-            restful.run(spb_in_helper).await;
-        } else {
-            warn!("Failed to start RESTful service.");
-            return Err(anyhow::anyhow!("Failed to start RESTful service."));
-        }
+    let mut layers = vec![log_layers.with_filter(filter).boxed()];
+    layers.extend(telemetry_layer);
+    // audit events skip the log filter, the layer is idle unless [audit] is enabled
+    layers.push(axonmq::audit::layer().boxed());
+    Registry::default().with(layers).init();
 
-        tokio::signal::ctrl_c().await?;
-        Ok(())
-    })
+    info!(
+        "Hello, AxonMQ v{}: {}!",
+        env!("CARGO_PKG_VERSION"),
+        config.node.id
+    );
+    if let Some(profile) = &config.profile {
+        info!("using the {} profile", profile);
+    }
+
+    let result = runtime.block_on(async {
+        let server = Server::builder(config)
+            .log_control(log_control)
+            .start()
+            .await?;
+        info!("Press Ctrl+C to exit.");
+        server.wait().await
+    });
+
+    #[cfg(feature = "telemetry")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    result
 }
@@ -0,0 +1,244 @@
+//! addresses and client ids banned at runtime, through `/api/v1/bans` or by
+//! `[mqtt.settings.auto_ban]`. Banned addresses are closed by the listeners before the MQTT
+//! handshake, banned client ids get CONNACK Banned
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex, RwLock};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::warn;
+
+use crate::CONFIG;
+use crate::config::AutoBanConfig;
+
+// offenders tracked before the ones without a recent offense are forgotten
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    // an address or a network
+    Ip(#[serde(deserialize_with = "ip_or_net")] IpNet),
+    ClientId(String),
+}
+
+impl BanTarget {
+    pub fn ip(ip: IpAddr) -> Self {
+        BanTarget::Ip(IpNet::from(ip.to_canonical()))
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Ip(net) => write!(f, "ip {}", net),
+            BanTarget::ClientId(client_id) => write!(f, "client {}", client_id),
+        }
+    }
+}
+
+/// what `[mqtt.settings.auto_ban]` counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    // a failed TLS handshake, a client certificate refused by require_client_cert among them
+    AuthFailure,
+    // a packet that does not decode or is over max_packet_size
+    MalformedPacket,
+    // a publish over the client_limits of the client
+    RateLimit,
+}
+
+impl fmt::Display for Offense {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offense::AuthFailure => write!(f, "auth failure"),
+            Offense::MalformedPacket => write!(f, "malformed packet"),
+            Offense::RateLimit => write!(f, "rate limit"),
+        }
+    }
+}
+
+/// `10.0.0.7` as well as `10.0.0.0/24`
+pub fn parse_ip_or_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{} is neither an address nor a network", s))
+}
+
+fn ip_or_net<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpNet, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_ip_or_net(&s).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    #[serde(flatten)]
+    pub target: BanTarget,
+    pub reason: String,
+    pub created_at: u64,
+    // seconds since the epoch, banned until removed when not set
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    fn active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+static BANS: LazyLock<RwLock<Vec<Ban>>> = LazyLock::new(Default::default);
+
+fn now() -> u64 {
+    coarsetime::Clock::now_since_epoch().as_secs()
+}
+
+/// ban for `ttl_secs` when set, a target banned already gets the new expiry and reason
+pub fn ban(target: BanTarget, ttl_secs: Option<u64>, reason: String) -> Ban {
+    let now = now();
+    let ban = Ban {
+        target,
+        reason,
+        created_at: now,
+        expires_at: ttl_secs.map(|ttl| now + ttl),
+    };
+    let mut bans = BANS.write().unwrap();
+    bans.retain(|b| b.active(now) && b.target != ban.target);
+    bans.push(ban.clone());
+    ban
+}
+
+/// false when the target was not banned
+pub fn unban(target: &BanTarget) -> bool {
+    let mut bans = BANS.write().unwrap();
+    let len = bans.len();
+    bans.retain(|b| b.target != *target);
+    bans.len() != len
+}
+
+/// the bans in place, expired ones are dropped
+pub fn list() -> Vec<Ban> {
+    let now = now();
+    let mut bans = BANS.write().unwrap();
+    bans.retain(|b| b.active(now));
+    bans.clone()
+}
+
+fn banned(matches: impl Fn(&BanTarget) -> bool) -> bool {
+    let bans = BANS.read().unwrap();
+    if bans.is_empty() {
+        return false;
+    }
+    let now = now();
+    bans.iter().any(|b| b.active(now) && matches(&b.target))
+}
+
+pub fn ip_banned(ip: IpAddr) -> bool {
+    banned(|target| matches!(target, BanTarget::Ip(net) if net.contains(&ip)))
+}
+
+pub fn client_banned(client_id: &str) -> bool {
+    banned(|target| matches!(target, BanTarget::ClientId(id) if id == client_id))
+}
+
+static OFFENSES: LazyLock<Mutex<HashMap<BanTarget, VecDeque<u64>>>> =
+    LazyLock::new(Default::default);
+
+/// count an offense of a client, or of an address before CONNECT, true when it got the
+/// offender banned
+pub fn offense(offense: Offense, target: BanTarget) -> bool {
+    let Some(config) = CONFIG.get().and_then(|c| c.mqtt.settings.auto_ban.as_ref()) else {
+        return false;
+    };
+    if !count(&mut OFFENSES.lock().unwrap(), &target, config, now()) {
+        return false;
+    }
+    warn!(
+        target: "axonmq::audit",
+        event = "auto_ban",
+        %target,
+        %offense,
+        ban_secs = config.ban_secs,
+        "banned after {} offenses in {}s",
+        config.max_offenses,
+        config.window_secs
+    );
+    ban(target, Some(config.ban_secs), format!("auto: {}", offense));
+    true
+}
+
+// the offenses of the target within the window, true when they reach max_offenses
+fn count(
+    offenses: &mut HashMap<BanTarget, VecDeque<u64>>,
+    target: &BanTarget,
+    config: &AutoBanConfig,
+    now: u64,
+) -> bool {
+    let recent = |at: &u64| at + config.window_secs > now;
+    if offenses.len() >= MAX_TRACKED {
+        offenses.retain(|_, times| times.back().is_some_and(recent));
+    }
+    let times = offenses.entry(target.clone()).or_default();
+    while times.front().is_some_and(|at| !recent(at)) {
+        times.pop_front();
+    }
+    times.push_back(now);
+    if times.len() < config.max_offenses as usize {
+        return false;
+    }
+    offenses.remove(target);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban() {
+        let target: BanTarget = serde_json::from_str(r#"{"ip": "198.51.100.7"}"#).unwrap();
+        assert_eq!(target, BanTarget::Ip("198.51.100.7/32".parse().unwrap()));
+        assert!(serde_json::from_str::<BanTarget>(r#"{"ip": "not an address"}"#).is_err());
+
+        ban(
+            BanTarget::Ip(parse_ip_or_net("203.0.113.0/24").unwrap()),
+            None,
+            "test".to_string(),
+        );
+        ban(
+            BanTarget::ClientId("rogue".to_string()),
+            Some(60),
+            "test".to_string(),
+        );
+        assert!(ip_banned("203.0.113.9".parse().unwrap()));
+        assert!(!ip_banned("203.0.114.9".parse().unwrap()));
+        assert!(client_banned("rogue"));
+        assert!(!client_banned("rogue-2"));
+
+        assert!(unban(&BanTarget::ClientId("rogue".to_string())));
+        assert!(!unban(&BanTarget::ClientId("rogue".to_string())));
+        assert!(!client_banned("rogue"));
+    }
+
+    #[test]
+    fn test_count() {
+        let config = AutoBanConfig {
+            max_offenses: 3,
+            window_secs: 10,
+            ban_secs: 60,
+        };
+        let mut offenses = HashMap::new();
+        let target = BanTarget::ip("::ffff:192.0.2.1".parse().unwrap());
+        assert_eq!(target, BanTarget::Ip("192.0.2.1/32".parse().unwrap()));
+
+        assert!(!count(&mut offenses, &target, &config, 100));
+        assert!(!count(&mut offenses, &target, &config, 105));
+        // the first one is out of the window
+        assert!(!count(&mut offenses, &target, &config, 110));
+        assert!(count(&mut offenses, &target, &config, 111));
+        // counted from scratch once banned
+        assert!(!count(&mut offenses, &target, &config, 112));
+    }
+}
@@ -28,6 +28,7 @@ pub enum ReturnCode {
     ServerUnavailableV5 = 136,
     ServerBusy = 137,
     Banned = 138,
+    ServerShuttingDown = 139,
     BadAuthMethod = 140,
     KeepAliveTimeout = 141,
     SessionTakenOver = 142,
@@ -72,6 +73,8 @@ impl TryFrom<u8> for ReturnCode {
             5 => Ok(ReturnCode::NotAuthorized),
             16 => Ok(ReturnCode::NoMatchSubscription),
             17 => Ok(ReturnCode::NoSubscriptionExisted),
+            24 => Ok(ReturnCode::ContinueAuth),
+            25 => Ok(ReturnCode::ReAuth),
             128 => Ok(ReturnCode::UnspecifiedError),
             129 => Ok(ReturnCode::MalformedPacket),
             130 => Ok(ReturnCode::ProtocolError),
@@ -83,19 +86,30 @@ impl TryFrom<u8> for ReturnCode {
             136 => Ok(ReturnCode::ServerUnavailableV5),
             137 => Ok(ReturnCode::ServerBusy),
             138 => Ok(ReturnCode::Banned),
+            139 => Ok(ReturnCode::ServerShuttingDown),
             140 => Ok(ReturnCode::BadAuthMethod),
+            141 => Ok(ReturnCode::KeepAliveTimeout),
             142 => Ok(ReturnCode::SessionTakenOver),
             143 => Ok(ReturnCode::TopicFilterInvalid),
             144 => Ok(ReturnCode::TopicNameInvalid),
+            145 => Ok(ReturnCode::PacketIdInUse),
             146 => Ok(ReturnCode::PacketIdentifierNotFound),
+            147 => Ok(ReturnCode::ReceiveMaximumExceeded),
+            148 => Ok(ReturnCode::TopicAliasInvalid),
             149 => Ok(ReturnCode::PacketTooLarge),
+            150 => Ok(ReturnCode::MessageRateTooHigh),
             151 => Ok(ReturnCode::QuotaExceeded),
+            152 => Ok(ReturnCode::AdministrativeAction),
             153 => Ok(ReturnCode::PayloadFormatInvalid),
             154 => Ok(ReturnCode::RetainNotSupported),
             155 => Ok(ReturnCode::QoSNotSupported),
             156 => Ok(ReturnCode::UseAnotherServer),
             157 => Ok(ReturnCode::ServerMoved),
+            158 => Ok(ReturnCode::SharedSubscriptionNotSupported),
             159 => Ok(ReturnCode::ConnectionRateExceeded),
+            160 => Ok(ReturnCode::MaxmumConnectTime),
+            161 => Ok(ReturnCode::SubscriptionIdentifiersNotSupported),
+            162 => Ok(ReturnCode::WildcardSubscriptionsNotSupported),
             _ => Err(MqttProtocolError::InvalidReturnCode(value)),
         }
     }
@@ -137,6 +151,7 @@ impl std::fmt::Display for ReturnCode {
             ReturnCode::ServerUnavailableV5 => write!(f, "136: Server Unavailable"),
             ReturnCode::ServerBusy => write!(f, "137: Server Busy"),
             ReturnCode::Banned => write!(f, "138: Banned"),
+            ReturnCode::ServerShuttingDown => write!(f, "139: Server Shutting Down"),
             ReturnCode::BadAuthMethod => write!(f, "140: Bad Authentication Method"),
             ReturnCode::KeepAliveTimeout => write!(f, "141: Keep Alive Timeout"),
             ReturnCode::SessionTakenOver => write!(f, "142: Session Taken Over"),
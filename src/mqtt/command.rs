@@ -1,25 +1,165 @@
+use std::net::SocketAddr;
+
 use bytes::Bytes;
+use serde::Serialize;
 use tokio::sync::{mpsc, oneshot};
 
 use super::protocol::{
     conn::{ConnAck, Connect},
     property::PropertyUser,
-    publish::PublishOptions,
-    subscribe::{SubAck, Subscribe, UnsubAck, Unsubscribe},
+    publish::{PublishOptions, SharedPublish},
+    subscribe::{SubAck, Subscribe, SubscribeOption, UnsubAck, Unsubscribe},
 };
 use super::{QoS, code::ReturnCode};
 
+use super::helper::ClientHelper;
 use super::listener::store::Store;
 
 pub(crate) enum BrokerAck {
     ConnAck(ConnAck, Option<Store>),
-    SubAck(SubAck),
+    SubAck(SubAck, Option<RetainDelivery>),
     UnsubAck(UnsubAck),
+    Clients(Vec<ClientInfo>),
+    Client(Option<ClientDetail>),
+    // the helper of a connected client, to ask for its store
+    Session(Option<(ClientSession, Option<ClientHelper>)>),
+    // the messages dropped by the broker and the helper of a connected client
+    Purged(Option<(usize, Option<ClientHelper>)>),
+    Connection(Result<ClientHelper, KickError>),
+    Kicked(Result<(), KickError>),
+    RetainedRemoved(usize),
+    Tagged(bool),
+    Groups(Vec<GroupInfo>),
+    GroupKicked(usize),
+    GroupUpdated(usize),
+    AllDisconnected(usize),
+    RetainedSynced,
+}
+
+/// retained messages to send after SUBACK, looked up by the subscriber's listener task
+pub(crate) struct RetainDelivery {
+    pub client_id: String,
+    pub client_helper: ClientHelper,
+    pub filters: Vec<(String, SubscribeOption)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub version: String,
+    pub connected: bool,
+    pub clean_start: bool,
+    pub session_expiry_interval: u32,
+    pub subscriptions: usize,
+    pub connected_at: u64,
+    pub disconnected_at: Option<u64>,
+    pub groups: Vec<String>,
+    // messages which found the queue of the client full since it connected
+    pub slow_consumer: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientDetail {
+    #[serde(flatten)]
+    pub info: ClientInfo,
+    pub topics: Vec<String>,
+    pub peer_cert: Option<String>,
+    pub server_name: Option<String>,
+    // where the client connected from, the real client behind a PROXY protocol load balancer
+    pub address: String,
+    pub store_msgs: usize,
+    pub store_bytes: usize,
+    // messages dropped by the overflow policy while the client was offline
+    pub store_dropped: u64,
+}
+
+/// the session of a client, with the packets its connection holds
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientSession {
+    pub client_id: String,
+    pub connected: bool,
+    pub subscriptions: Vec<SubscriptionInfo>,
+    // messages the broker keeps while the client is offline or its queue is full
+    pub offline_msgs: usize,
+    pub offline_bytes: usize,
+    #[serde(flatten)]
+    pub store: StoreInfo,
+    // the last packet read from the client, or when it disconnected
+    pub last_activity: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriptionInfo {
+    pub topic: String,
+    pub qos: u8,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: u8,
+    pub subscription_identifier: Option<u32>,
+}
+
+/// what the per-connection `Store` of a client holds
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoreInfo {
+    // waiting for a packet identifier, in memory then spilled to disk
+    pub queued_msgs: usize,
+    pub queued_bytes: usize,
+    pub spilled_msgs: usize,
+    pub spilled_bytes: u64,
+    pub inflight: Vec<InflightInfo>,
+    // QoS 2 publishes received from the client, waiting for its PUBREL
+    pub pubrel_pending: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InflightInfo {
+    pub packet_id: u16,
+    pub qos: u8,
+    // not known once PUBREC arrived
+    pub topic: Option<String>,
+    // seconds since it was last sent, 0 when queued for the next resend
+    pub age: u64,
+    // waiting for PUBCOMP
+    pub released: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetainedInfo {
+    pub topic: String,
+    pub qos: u8,
+    pub payload_size: usize,
+    pub expiry_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetainedDetail {
+    #[serde(flatten)]
+    pub info: RetainedInfo,
+    // base64 encoded
+    pub payload: String,
+    pub content_type: Option<String>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupInfo {
+    pub name: String,
+    pub members: usize,
+    pub connected: usize,
+    pub publish_rate: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KickError {
+    NotFound,
+    NotConnected,
 }
 
 pub(crate) enum BrokerCommand {
     Connect {
         connect: Connect,
+        // the real client behind a PROXY protocol load balancer
+        peer_addr: SocketAddr,
         resp: oneshot::Sender<BrokerAck>,
         client_tx: mpsc::Sender<ClientCommand>,
     },
@@ -36,6 +176,7 @@ pub(crate) enum BrokerCommand {
     Disconnected(String, ReturnCode, Option<u32>, Store),
     WillPublish {
         client_id: String,
+        server_name: Option<String>,
         retain: bool,
         qos: QoS,
         topic: String,
@@ -44,6 +185,7 @@ pub(crate) enum BrokerCommand {
         options: PublishOptions,
     },
     RetainMessage {
+        client_id: Option<String>,
         topic: String,
         qos: QoS,
         payload: Bytes,
@@ -54,17 +196,79 @@ pub(crate) enum BrokerCommand {
         client_id: String,
         msg: ClientCommand,
     },
+    // a message for a connected client whose queue is full
+    SlowConsumer {
+        client_id: String,
+        msg: ClientCommand,
+    },
+    ListClients {
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    GetClient {
+        client_id: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    GetSession {
+        client_id: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    PurgeQueue {
+        client_id: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    // the helper of a connected client
+    GetConnection {
+        client_id: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    KickClient {
+        client_id: String,
+        reason: ReturnCode,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    RemoveRetained {
+        filter: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    TagClient {
+        client_id: String,
+        group: String,
+        tagged: bool,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    ListGroups {
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    KickGroup {
+        group: String,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    SetGroupRate {
+        group: String,
+        publish_rate: Option<u32>,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    DisconnectAll {
+        reason: ReturnCode,
+        resp: oneshot::Sender<BrokerAck>,
+    },
+    SyncRetained {
+        resp: oneshot::Sender<BrokerAck>,
+    },
 }
 
 #[derive(Clone)]
 pub enum ClientCommand {
     Disconnect(ReturnCode),
-    Publish {
-        topic: String,
-        qos: QoS,
-        retain: bool,
-        payload: Bytes,
-        user_properties: Vec<PropertyUser>,
-        options: PublishOptions,
-    },
+    // a new connection took the session over, the broker already dropped this one
+    TakenOver,
+    // PUBLISH packets per second accepted from the client, None for no limit
+    PublishRate(Option<u32>),
+    Publish(SharedPublish),
+    // the store of the connection and the time of the last packet read from the client
+    Inspect(mpsc::Sender<(StoreInfo, u64)>),
+    // drop the queued messages, answers how many
+    PurgeQueue(mpsc::Sender<usize>),
+    // send the inflight messages again, answers how many
+    Resend(mpsc::Sender<usize>),
 }
@@ -38,3 +38,21 @@ pub enum MqttProtocolError {
     #[error("Invalid return code: {0}")]
     InvalidReturnCode(u8),
 }
+
+impl MqttProtocolError {
+    /// the peer sent something that is not MQTT, as opposed to a closed connection
+    pub fn malformed(&self) -> bool {
+        matches!(
+            self,
+            MqttProtocolError::InvalidFixedHeader
+                | MqttProtocolError::InvalidMessageType
+                | MqttProtocolError::InvalidQoS
+                | MqttProtocolError::InvalidProtocolName
+                | MqttProtocolError::InvalidProtocolVersion
+                | MqttProtocolError::MalformedPayload
+                | MqttProtocolError::InvalidProperty
+                | MqttProtocolError::InvalidTopicFilter
+                | MqttProtocolError::InvalidReturnCode(_)
+        )
+    }
+}
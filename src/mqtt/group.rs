@@ -0,0 +1,139 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::group::ClientGroup;
+
+use super::protocol::conn::Connect;
+
+/// CONNECT user property a client uses to join a group
+pub const GROUP_PROPERTY: &str = "group";
+
+/// group rules from the config and the current per-group publish rates
+pub struct ClientGroups {
+    rules: Vec<GroupRule>,
+    rates: HashMap<String, u32>,
+}
+
+struct GroupRule {
+    name: String,
+    client_id_prefix: Option<String>,
+    username: Option<String>,
+}
+
+impl GroupRule {
+    fn matches(&self, client_id: &str, username: Option<&str>) -> bool {
+        if self.client_id_prefix.is_none() && self.username.is_none() {
+            return false;
+        }
+        self.client_id_prefix
+            .as_deref()
+            .is_none_or(|prefix| client_id.starts_with(prefix))
+            && self
+                .username
+                .as_deref()
+                .is_none_or(|name| Some(name) == username)
+    }
+}
+
+impl ClientGroups {
+    pub fn new(groups: &[ClientGroup]) -> Self {
+        ClientGroups {
+            rules: groups
+                .iter()
+                .map(|group| GroupRule {
+                    name: group.name.clone(),
+                    client_id_prefix: group.client_id_prefix.clone(),
+                    username: group.username.clone(),
+                })
+                .collect(),
+            rates: groups
+                .iter()
+                .filter_map(|group| group.publish_rate.map(|rate| (group.name.clone(), rate)))
+                .collect(),
+        }
+    }
+
+    /// groups a connecting client joins by config rules and CONNECT properties
+    pub fn groups_for(&self, connect: &Connect) -> BTreeSet<String> {
+        let mut groups: BTreeSet<String> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(&connect.client_id, connect.username.as_deref()))
+            .map(|rule| rule.name.clone())
+            .collect();
+        groups.extend(connect.groups.iter().cloned());
+        groups
+    }
+
+    /// the lowest publish rate of the given groups
+    pub fn publish_rate<'a>(&self, groups: impl IntoIterator<Item = &'a String>) -> Option<u32> {
+        groups
+            .into_iter()
+            .filter_map(|group| self.rates.get(group).copied())
+            .min()
+    }
+
+    pub fn group_rate(&self, group: &str) -> Option<u32> {
+        self.rates.get(group).copied()
+    }
+
+    pub fn set_publish_rate(&mut self, group: &str, rate: Option<u32>) {
+        match rate {
+            Some(rate) => {
+                self.rates.insert(group.to_string(), rate);
+            }
+            None => {
+                self.rates.remove(group);
+            }
+        }
+    }
+
+    pub fn limited_groups(&self) -> impl Iterator<Item = &String> {
+        self.rates.keys()
+    }
+}
+
+/// per connection PUBLISH limit over one second windows
+#[derive(Default)]
+pub struct PublishRateLimiter {
+    rate: Option<u32>,
+    window: u64,
+    count: u32,
+}
+
+impl PublishRateLimiter {
+    pub fn set_rate(&mut self, rate: Option<u32>) {
+        self.rate = rate;
+    }
+
+    pub fn allow(&mut self, now: u64) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        if now != self.window {
+            self.window = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = PublishRateLimiter::default();
+        assert!(limiter.allow(1));
+
+        limiter.set_rate(Some(2));
+        assert!(limiter.allow(1));
+        assert!(limiter.allow(1));
+        assert!(!limiter.allow(1));
+        assert!(limiter.allow(2));
+
+        limiter.set_rate(None);
+        assert!(limiter.allow(2));
+    }
+}
@@ -1,20 +1,35 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::Engine as _;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task;
 
 use super::QoS;
 use super::code::ReturnCode;
-use super::command::{BrokerAck, BrokerCommand, ClientCommand};
+use super::command::{
+    BrokerAck, BrokerCommand, ClientCommand, ClientDetail, ClientInfo, ClientSession, GroupInfo,
+    KickError, RetainDelivery, RetainedDetail, RetainedInfo,
+};
 use super::error::MqttProtocolError;
 use super::listener::store::Store;
 use super::protocol::{
     conn::{ConnAck, Connect},
     property::PropertyUser,
-    publish::PublishOptions,
+    publish::{PublishBody, PublishOptions, SharedPublish},
     subscribe::{SubAck, Subscribe, UnsubAck, Unsubscribe},
+    will::Will,
 };
+use super::retain_trie::{RetainedMessage, SharedRetainedTrie};
+use super::utils;
+
+// how long the connection of a client has to answer a command
+const ASK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct BrokerHelper {
-    pub broker_tx: mpsc::Sender<BrokerCommand>,
+    pub(crate) broker_tx: mpsc::Sender<BrokerCommand>,
+    pub retain_trie: SharedRetainedTrie,
 }
 
 #[derive(Clone)]
@@ -26,12 +41,14 @@ impl BrokerHelper {
     pub async fn connect(
         &self,
         connect: Connect,
+        peer_addr: SocketAddr,
         client_tx: mpsc::Sender<ClientCommand>,
     ) -> Result<(ConnAck, Option<Store>), MqttProtocolError> {
         let (resp_tx, resp_rx) = oneshot::channel();
         self.broker_tx
             .send(BrokerCommand::Connect {
                 connect,
+                peer_addr,
                 resp: resp_tx,
                 client_tx,
             })
@@ -62,13 +79,115 @@ impl BrokerHelper {
             .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
 
         let result = resp_rx.await?;
-        if let BrokerAck::SubAck(ack) = result {
+        if let BrokerAck::SubAck(ack, delivery) = result {
+            if let Some(delivery) = delivery {
+                self.deliver_retained(delivery).await;
+            }
             Ok(ack)
         } else {
             Err(MqttProtocolError::InternalError)
         }
     }
 
+    pub async fn list_retained(
+        &self,
+        filter: String,
+    ) -> Result<Vec<RetainedInfo>, MqttProtocolError> {
+        if !utils::sub_topic_valid(&filter) {
+            return Err(MqttProtocolError::InvalidTopicFilter);
+        }
+        let retain_trie = self.retain_trie.clone();
+        let msgs = task::spawn_blocking(move || retain_trie.find_matches_for_filter(&filter))
+            .await
+            .map_err(|_| MqttProtocolError::InternalError)?;
+        Ok(msgs.iter().map(RetainedInfo::new).collect())
+    }
+
+    pub async fn get_retained(
+        &self,
+        topic: String,
+    ) -> Result<Option<RetainedDetail>, MqttProtocolError> {
+        if !utils::pub_topic_valid(&topic) {
+            return Err(MqttProtocolError::InvalidTopicFilter);
+        }
+        let retain_trie = self.retain_trie.clone();
+        let msg = task::spawn_blocking(move || retain_trie.find_matches_for_filter(&topic).pop())
+            .await
+            .map_err(|_| MqttProtocolError::InternalError)?;
+        Ok(msg.map(|msg| RetainedDetail {
+            info: RetainedInfo::new(&msg),
+            payload: base64::engine::general_purpose::STANDARD.encode(&msg.payload),
+            content_type: msg.options.content_type.clone(),
+            user_properties: msg
+                .user_properties
+                .into_iter()
+                .map(|prop| (prop.key, prop.value))
+                .collect(),
+        }))
+    }
+
+    /// remove a retained message, or all of them under a topic filter
+    pub async fn remove_retained(&self, filter: String) -> Result<usize, MqttProtocolError> {
+        if !utils::sub_topic_valid(&filter) {
+            return Err(MqttProtocolError::InvalidTopicFilter);
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::RemoveRetained {
+                filter,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::RetainedRemoved(removed) = result {
+            Ok(removed)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    async fn deliver_retained(&self, delivery: RetainDelivery) {
+        let retain_trie = self.retain_trie.clone();
+        let RetainDelivery {
+            client_id,
+            client_helper,
+            filters,
+        } = delivery;
+        // broad filters over a large retained set can take a while, keep them off the runtime
+        task::spawn_blocking(move || {
+            for (filter, options) in filters {
+                for msg in retain_trie.find_matches_for_filter(&filter) {
+                    if options.no_local && msg.client_id.as_deref() == Some(client_id.as_str()) {
+                        continue;
+                    }
+                    if utils::response_topic_owner(&msg.topic)
+                        .is_some_and(|owner| owner != client_id)
+                    {
+                        continue;
+                    }
+                    let qos = options.qos.min(msg.qos);
+                    let body = PublishBody {
+                        topic: msg.topic,
+                        payload: msg.payload,
+                        user_properties: msg.user_properties,
+                        options: msg.options,
+                    };
+                    client_helper
+                        .send(ClientCommand::Publish(SharedPublish::new(
+                            qos,
+                            options.retain_as_published,
+                            Arc::new(body),
+                        )))
+                        .ok();
+                }
+            }
+        })
+        .await
+        .ok();
+    }
+
     pub async fn unsubscribe(
         &self,
         client_id: &str,
@@ -111,25 +230,22 @@ impl BrokerHelper {
         Ok(())
     }
 
-    pub async fn will_publish(
+    pub(crate) async fn will_publish(
         &self,
         client_id: &str,
-        topic: String,
-        payload: bytes::Bytes,
-        qos: QoS,
-        retain: bool,
-        user_properties: Vec<PropertyUser>,
-        options: PublishOptions,
+        server_name: Option<String>,
+        will: Will,
     ) -> Result<(), MqttProtocolError> {
         self.broker_tx
             .send(BrokerCommand::WillPublish {
                 client_id: client_id.to_string(),
-                topic,
-                payload,
-                qos,
-                retain,
-                user_properties,
-                options,
+                server_name,
+                topic: will.topic,
+                payload: will.payload,
+                qos: will.qos,
+                retain: will.retain,
+                user_properties: will.user_properties,
+                options: will.options.options,
             })
             .await
             .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
@@ -138,6 +254,7 @@ impl BrokerHelper {
 
     pub async fn retain_message(
         &self,
+        client_id: Option<String>,
         topic: String,
         qos: QoS,
         payload: bytes::Bytes,
@@ -146,6 +263,7 @@ impl BrokerHelper {
     ) -> Result<(), MqttProtocolError> {
         self.broker_tx
             .send(BrokerCommand::RetainMessage {
+                client_id,
                 topic,
                 qos,
                 payload,
@@ -164,6 +282,309 @@ impl BrokerHelper {
         });
         Ok(())
     }
+
+    /// hands a message the client's queue had no room for to `slow_consumer_policy`
+    pub fn slow_consumer(&self, client_id: &str, msg: ClientCommand) {
+        let _ = self.broker_tx.try_send(BrokerCommand::SlowConsumer {
+            client_id: client_id.to_string(),
+            msg,
+        });
+    }
+
+    pub async fn list_clients(&self) -> Result<Vec<ClientInfo>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::ListClients { resp: resp_tx })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::Clients(clients) = result {
+            Ok(clients)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    pub async fn get_client(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ClientDetail>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::GetClient {
+                client_id: client_id.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::Client(client) = result {
+            Ok(client)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// the session of a client, the store of a connected client is asked to its connection
+    pub async fn get_session(
+        &self,
+        client_id: &str,
+    ) -> Result<Option<ClientSession>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::GetSession {
+                client_id: client_id.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        let BrokerAck::Session(session) = result else {
+            return Err(MqttProtocolError::InternalError);
+        };
+        let Some((mut session, client_helper)) = session else {
+            return Ok(None);
+        };
+        session.subscriptions.sort_by(|a, b| a.topic.cmp(&b.topic));
+
+        // a connection busy writing to the client answers late, the broker's view is kept
+        if let Some(client_helper) = client_helper
+            && let Some((store, last_activity)) = client_helper.ask(ClientCommand::Inspect).await
+        {
+            session.store = store;
+            session.last_activity = last_activity;
+        }
+        Ok(Some(session))
+    }
+
+    /// drop the messages queued for a client, by the broker and by its connection, the
+    /// inflight ones are kept
+    pub async fn purge_queue(&self, client_id: &str) -> Result<Option<usize>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::PurgeQueue {
+                client_id: client_id.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        let BrokerAck::Purged(purged) = result else {
+            return Err(MqttProtocolError::InternalError);
+        };
+        let Some((mut purged, client_helper)) = purged else {
+            return Ok(None);
+        };
+        if let Some(client_helper) = client_helper {
+            purged += client_helper
+                .ask(ClientCommand::PurgeQueue)
+                .await
+                .unwrap_or(0);
+        }
+        Ok(Some(purged))
+    }
+
+    /// send the inflight messages of a connected client again now, the number resent
+    pub async fn resend_inflight(
+        &self,
+        client_id: &str,
+    ) -> Result<Result<usize, KickError>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::GetConnection {
+                client_id: client_id.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        let BrokerAck::Connection(client_helper) = result else {
+            return Err(MqttProtocolError::InternalError);
+        };
+        match client_helper {
+            Ok(client_helper) => client_helper
+                .ask(ClientCommand::Resend)
+                .await
+                .map(Ok)
+                .ok_or(MqttProtocolError::InternalError),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    pub async fn kick_client(
+        &self,
+        client_id: &str,
+    ) -> Result<Result<(), KickError>, MqttProtocolError> {
+        self.disconnect_client(client_id, ReturnCode::AdministrativeAction)
+            .await
+    }
+
+    /// the client connected to another cluster node, drop its connection here
+    pub async fn take_over(
+        &self,
+        client_id: &str,
+    ) -> Result<Result<(), KickError>, MqttProtocolError> {
+        self.disconnect_client(client_id, ReturnCode::SessionTakenOver)
+            .await
+    }
+
+    async fn disconnect_client(
+        &self,
+        client_id: &str,
+        reason: ReturnCode,
+    ) -> Result<Result<(), KickError>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::KickClient {
+                client_id: client_id.to_string(),
+                reason,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::Kicked(result) = result {
+            Ok(result)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// add a client to a group or remove it, false when the client is unknown
+    pub async fn tag_client(
+        &self,
+        client_id: &str,
+        group: &str,
+        tagged: bool,
+    ) -> Result<bool, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::TagClient {
+                client_id: client_id.to_string(),
+                group: group.to_string(),
+                tagged,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::Tagged(found) = result {
+            Ok(found)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<GroupInfo>, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::ListGroups { resp: resp_tx })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::Groups(groups) = result {
+            Ok(groups)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// disconnect every connected member of a group, returns how many were kicked
+    pub async fn kick_group(&self, group: &str) -> Result<usize, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::KickGroup {
+                group: group.to_string(),
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::GroupKicked(kicked) = result {
+            Ok(kicked)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// set or clear the publish rate of a group, returns how many connected members were updated
+    pub async fn set_group_rate(
+        &self,
+        group: &str,
+        publish_rate: Option<u32>,
+    ) -> Result<usize, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::SetGroupRate {
+                group: group.to_string(),
+                publish_rate,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::GroupUpdated(updated) = result {
+            Ok(updated)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// disconnect every connected client with Server Shutting Down, returns how many were connected
+    pub async fn shutdown_clients(&self) -> Result<usize, MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::DisconnectAll {
+                reason: ReturnCode::ServerShuttingDown,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::AllDisconnected(disconnected) = result {
+            Ok(disconnected)
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+
+    /// write the persisted retained messages through to disk
+    pub async fn sync_retained(&self) -> Result<(), MqttProtocolError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.broker_tx
+            .send(BrokerCommand::SyncRetained { resp: resp_tx })
+            .await
+            .map_err(|_| MqttProtocolError::BrokerChannelSendError)?;
+
+        let result = resp_rx.await?;
+        if let BrokerAck::RetainedSynced = result {
+            Ok(())
+        } else {
+            Err(MqttProtocolError::InternalError)
+        }
+    }
+}
+
+impl RetainedInfo {
+    fn new(msg: &RetainedMessage) -> Self {
+        RetainedInfo {
+            topic: msg.topic.clone(),
+            qos: msg.qos as u8,
+            payload_size: msg.payload.len(),
+            expiry_at: msg.options.message_expiry_at,
+        }
+    }
 }
 
 impl ClientHelper {
@@ -174,6 +595,11 @@ impl ClientHelper {
         Ok(())
     }
 
+    pub async fn take_over(&self) -> Result<(), MqttProtocolError> {
+        self.client_tx.send(ClientCommand::TakenOver).await?;
+        Ok(())
+    }
+
     pub fn new(stack_tx: mpsc::Sender<ClientCommand>) -> Self {
         ClientHelper {
             client_tx: stack_tx,
@@ -189,4 +615,14 @@ impl ClientHelper {
             .try_send(cmd)
             .map_err(|_| MqttProtocolError::InternalError)
     }
+
+    /// a command answered by the connection, None when it is gone or does not answer in time
+    pub async fn ask<T>(&self, cmd: impl FnOnce(mpsc::Sender<T>) -> ClientCommand) -> Option<T> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let ask = async {
+            self.client_tx.send(cmd(tx)).await.ok()?;
+            rx.recv().await
+        };
+        tokio::time::timeout(ASK_TIMEOUT, ask).await.ok().flatten()
+    }
 }
@@ -0,0 +1,361 @@
+//! connections a listener lets in, `max_connections`, `max_connect_rate`, `allow_cidrs`,
+//! `deny_cidrs` and `protocol_versions` of its config
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+
+use ipnet::IpNet;
+use serde::Serialize;
+
+use crate::config::{ClientLimitsConfig, ListenerCommonConfig};
+use crate::mqtt::{MqttProtocolVersion, ban};
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicU64,
+    accepted: AtomicU64,
+    rejected_busy: AtomicU64,
+    rejected_rate: AtomicU64,
+    rejected_denied: AtomicU64,
+    rejected_version: AtomicU64,
+}
+
+/// the source networks of a listener
+#[derive(Debug, Default)]
+struct Access {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Access {
+    fn allows(&self, ip: IpAddr) -> bool {
+        // an IPv4 client of a dual stack listener shows up as an IPv4-mapped IPv6 address
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            && !ban::ip_banned(ip)
+    }
+}
+
+struct Entry {
+    max_connections: Option<usize>,
+    max_connect_rate: Option<u32>,
+    counters: Arc<Counters>,
+}
+
+// kept across listener restarts, the counters add up since the process started
+static LISTENERS: LazyLock<RwLock<BTreeMap<&'static str, Entry>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerStats {
+    pub name: &'static str,
+    pub max_connections: Option<usize>,
+    pub max_connect_rate: Option<u32>,
+    pub active: u64,
+    pub accepted: u64,
+    pub rejected_busy: u64,
+    pub rejected_rate: u64,
+    // closed by allow_cidrs, deny_cidrs or a ban
+    pub rejected_denied: u64,
+    // connected with a version left out of protocol_versions
+    pub rejected_version: u64,
+}
+
+/// the connection counters of the listeners started so far
+pub fn stats() -> Vec<ListenerStats> {
+    LISTENERS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, entry)| ListenerStats {
+            name,
+            max_connections: entry.max_connections,
+            max_connect_rate: entry.max_connect_rate,
+            active: entry.counters.active.load(Ordering::Relaxed),
+            accepted: entry.counters.accepted.load(Ordering::Relaxed),
+            rejected_busy: entry.counters.rejected_busy.load(Ordering::Relaxed),
+            rejected_rate: entry.counters.rejected_rate.load(Ordering::Relaxed),
+            rejected_denied: entry.counters.rejected_denied.load(Ordering::Relaxed),
+            rejected_version: entry.counters.rejected_version.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// owned by the accept loop of a listener, checks every connection it accepts
+pub struct Admission {
+    max_connections: Option<usize>,
+    max_connect_rate: Option<u32>,
+    client_limits: ClientLimitsConfig,
+    mountpoint: Option<String>,
+    proxy_protocol: bool,
+    access: Arc<Access>,
+    // all when empty
+    versions: Arc<Vec<MqttProtocolVersion>>,
+    counters: Arc<Counters>,
+    window: u64,
+    connects: u32,
+}
+
+impl Admission {
+    pub fn new(
+        name: &'static str,
+        max_connections: Option<usize>,
+        max_connect_rate: Option<u32>,
+        client_limits: ClientLimitsConfig,
+    ) -> Self {
+        let mut listeners = LISTENERS.write().unwrap();
+        let counters = listeners
+            .get(name)
+            .map(|entry| entry.counters.clone())
+            .unwrap_or_default();
+        listeners.insert(
+            name,
+            Entry {
+                max_connections,
+                max_connect_rate,
+                counters: counters.clone(),
+            },
+        );
+        Admission {
+            max_connections,
+            max_connect_rate,
+            client_limits,
+            mountpoint: None,
+            proxy_protocol: false,
+            access: Default::default(),
+            versions: Default::default(),
+            counters,
+            window: 0,
+            connects: 0,
+        }
+    }
+
+    /// the admission of a listener section, its client limits over the global `limits`
+    pub fn from_config(
+        name: &'static str,
+        common: &ListenerCommonConfig,
+        limits: &ClientLimitsConfig,
+    ) -> Self {
+        Self::new(
+            name,
+            common.max_connections,
+            common.max_connect_rate,
+            limits.with_overrides(&common.limits),
+        )
+        .with_proxy_protocol(common.proxy_protocol.unwrap_or(false))
+        .with_cidrs(common.allow_cidrs.clone(), common.deny_cidrs.clone())
+        .with_mountpoint(common.mountpoint.clone())
+        .with_protocol_versions(common.protocol_versions.clone())
+    }
+
+    /// connections start with a PROXY header carrying the client address
+    pub fn with_proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// the topic prefix of the clients, see `MessageCodec::with_mountpoint`
+    pub fn with_mountpoint(mut self, mountpoint: Option<String>) -> Self {
+        self.mountpoint = mountpoint;
+        self
+    }
+
+    /// the source networks let in, all when `allow` is empty, `deny` wins
+    pub fn with_cidrs(mut self, allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        self.access = Arc::new(Access { allow, deny });
+        self
+    }
+
+    /// the MQTT versions the clients may connect with, all when None
+    pub fn with_protocol_versions(mut self, versions: Option<Vec<MqttProtocolVersion>>) -> Self {
+        self.versions = Arc::new(versions.unwrap_or_default());
+        self
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// None when the connection goes over `max_connect_rate` and is to be closed right away
+    pub fn admit(&mut self, now: u64) -> Option<Ticket> {
+        if now != self.window {
+            self.window = now;
+            self.connects = 0;
+        }
+        self.connects += 1;
+        if self
+            .max_connect_rate
+            .is_some_and(|rate| self.connects > rate)
+        {
+            self.counters.rejected_rate.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let active = self.counters.active.load(Ordering::Relaxed);
+        if self.max_connections.is_some_and(|max| active >= max as u64) {
+            self.counters.rejected_busy.fetch_add(1, Ordering::Relaxed);
+            return Some(Ticket {
+                client_limits: self.client_limits.clone(),
+                mountpoint: self.mountpoint.clone(),
+                access: self.access.clone(),
+                versions: self.versions.clone(),
+                counters: self.counters.clone(),
+                busy: true,
+            });
+        }
+
+        self.counters.active.fetch_add(1, Ordering::Relaxed);
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        Some(Ticket {
+            client_limits: self.client_limits.clone(),
+            mountpoint: self.mountpoint.clone(),
+            access: self.access.clone(),
+            versions: self.versions.clone(),
+            counters: self.counters.clone(),
+            busy: false,
+        })
+    }
+}
+
+/// held by a connection for as long as it is open
+pub struct Ticket {
+    pub client_limits: ClientLimitsConfig,
+    pub mountpoint: Option<String>,
+    access: Arc<Access>,
+    versions: Arc<Vec<MqttProtocolVersion>>,
+    counters: Arc<Counters>,
+    // the listener is at max_connections, the client gets Server Busy
+    busy: bool,
+}
+
+impl Ticket {
+    pub fn busy(&self) -> bool {
+        self.busy
+    }
+
+    /// whether the client address passes the source networks of the listener and the bans,
+    /// checked once the address behind a PROXY header is known
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.access.allows(ip) {
+            return true;
+        }
+        self.counters
+            .rejected_denied
+            .fetch_add(1, Ordering::Relaxed);
+        if !self.busy {
+            self.counters.accepted.fetch_sub(1, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /// whether the listener takes clients of this MQTT version, the others get Unsupported
+    /// Protocol Version
+    pub fn accepts(&self, version: MqttProtocolVersion) -> bool {
+        if self.versions.is_empty() || self.versions.contains(&version) {
+            return true;
+        }
+        self.counters
+            .rejected_version
+            .fetch_add(1, Ordering::Relaxed);
+        if !self.busy {
+            self.counters.accepted.fetch_sub(1, Ordering::Relaxed);
+        }
+        false
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if !self.busy {
+            self.counters.active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let mut admission = Admission::new(
+            "listener.test",
+            Some(2),
+            Some(3),
+            ClientLimitsConfig::default(),
+        );
+        let first = admission.admit(1).unwrap();
+        let second = admission.admit(1).unwrap();
+        let third = admission.admit(1).unwrap();
+        assert!(!first.busy() && !second.busy());
+        assert!(third.busy());
+        assert!(admission.admit(1).is_none());
+
+        // a closed connection frees its slot
+        drop(first);
+        assert!(!admission.admit(2).unwrap().busy());
+
+        let stats = stats();
+        let stats = stats.iter().find(|s| s.name == "listener.test").unwrap();
+        assert_eq!(
+            (
+                stats.active,
+                stats.accepted,
+                stats.rejected_busy,
+                stats.rejected_rate
+            ),
+            (1, 3, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_cidrs() {
+        let mut admission = Admission::new(
+            "listener.test_cidrs",
+            None,
+            None,
+            ClientLimitsConfig::default(),
+        )
+        .with_cidrs(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.0.9.0/24".parse().unwrap()],
+        );
+        let ticket = admission.admit(1).unwrap();
+        assert!(ticket.allows("10.0.0.1".parse().unwrap()));
+        assert!(ticket.allows("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!ticket.allows("10.0.9.1".parse().unwrap()));
+        assert!(!ticket.allows("192.0.2.1".parse().unwrap()));
+
+        let stats = stats();
+        let stats = stats
+            .iter()
+            .find(|s| s.name == "listener.test_cidrs")
+            .unwrap();
+        assert_eq!(stats.rejected_denied, 2);
+    }
+
+    #[test]
+    fn test_protocol_versions() {
+        let mut admission = Admission::new(
+            "listener.test_versions",
+            None,
+            None,
+            ClientLimitsConfig::default(),
+        );
+        assert!(admission.admit(1).unwrap().accepts(MqttProtocolVersion::V3));
+
+        let mut admission = admission.with_protocol_versions(Some(vec![MqttProtocolVersion::V5]));
+        let ticket = admission.admit(1).unwrap();
+        assert!(ticket.accepts(MqttProtocolVersion::V5));
+        assert!(!ticket.accepts(MqttProtocolVersion::V3_1_1));
+
+        let stats = stats();
+        let stats = stats
+            .iter()
+            .find(|s| s.name == "listener.test_versions")
+            .unwrap();
+        assert_eq!((stats.accepted, stats.rejected_version), (1, 1));
+    }
+}
@@ -1,7 +1,14 @@
+mod admission;
+mod proxy;
+mod quota;
 mod shared;
+mod spill;
 pub mod store;
 pub mod tcp;
+mod topic_policy;
 pub mod ws;
 
-pub use tcp::{spawn_tcp_listener, spawn_tls_listener};
+pub use admission::{Admission, stats};
+pub use spill::dropped_total as spill_dropped_total;
+pub use tcp::{TlsOptions, spawn_tcp_listener, spawn_tls_listener};
 pub use ws::{spawn_ws_listener, spawn_wss_listener};
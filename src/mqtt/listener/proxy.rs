@@ -0,0 +1,154 @@
+//! PROXY protocol v1/v2 header sent by a load balancer ahead of the client bytes, it carries the
+//! address the client connected from, `proxy_protocol` of the listener config
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY TCP6 " with two full IPv6 addresses, two ports and CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// the address of the client, read from the PROXY header when the listener expects one
+pub async fn client_addr<S>(
+    stream: &mut S,
+    addr: SocketAddr,
+    proxy_protocol: bool,
+) -> io::Result<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    if !proxy_protocol {
+        return Ok(addr);
+    }
+    let source = time::timeout(time::Duration::from_secs(3), read_header(stream))
+        .await
+        .map_err(|_| invalid("no PROXY header within 3 seconds"))??;
+    // health checks of the load balancer and unknown address families keep the socket address
+    Ok(source.unwrap_or(addr))
+}
+
+async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 16];
+    stream.read_exact(&mut head[..8]).await?;
+
+    if head.starts_with(b"PROXY ") {
+        // read up to CRLF only, the bytes after it belong to the client
+        let mut line = head[..8].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+
+    stream.read_exact(&mut head[8..]).await?;
+    if head[..12] != V2_SIGNATURE {
+        return Err(invalid("missing PROXY header"));
+    }
+    let length = u16::from_be_bytes([head[14], head[15]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(&head, &addresses)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?
+        .trim_end_matches("\r\n");
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(head: &[u8; 16], addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if head[12] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY v2 version"));
+    }
+    match head[12] & 0x0f {
+        // LOCAL, sent by the load balancer itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    match head[13] >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 => {
+            let Some(a) = addresses.get(..12) else {
+                return Err(invalid("truncated PROXY v2 addresses"));
+            };
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let port = u16::from_be_bytes([a[8], a[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            let Some(a) = addresses.get(..36) else {
+                return Err(invalid("truncated PROXY v2 addresses"));
+            };
+            let ip: [u8; 16] = a[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([a[32], a[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.10 10.0.0.1 51234 1883\r\n").unwrap(),
+            Some("192.0.2.10:51234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1883\r\n").unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.10 10.0.0.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut head = [0u8; 16];
+        head[..12].copy_from_slice(&V2_SIGNATURE);
+        head[12] = 0x21;
+        head[13] = 0x11;
+        let addresses = [192, 0, 2, 10, 10, 0, 0, 1, 0xc8, 0x22, 0x07, 0x5b];
+        assert_eq!(
+            parse_v2(&head, &addresses).unwrap(),
+            Some("192.0.2.10:51234".parse().unwrap())
+        );
+        assert!(parse_v2(&head, &addresses[..8]).is_err());
+
+        head[12] = 0x20;
+        assert_eq!(parse_v2(&head, &[]).unwrap(), None);
+    }
+}
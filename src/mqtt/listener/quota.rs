@@ -0,0 +1,87 @@
+//! per client publish limits, `[mqtt.settings.client_limits]` overridden by the listener `limits`
+
+use crate::CONFIG;
+use crate::config::{ClientLimitsConfig, QuotaPolicy};
+
+/// PUBLISH messages and payload bytes a client sent over one second windows
+#[derive(Default)]
+pub struct ClientQuota {
+    messages_per_sec: Option<u32>,
+    bytes_per_sec: Option<u64>,
+    policy: QuotaPolicy,
+    window: u64,
+    messages: u32,
+    bytes: u64,
+}
+
+impl ClientQuota {
+    pub fn new(limits: &ClientLimitsConfig) -> Self {
+        ClientQuota {
+            messages_per_sec: limits.messages_per_sec,
+            bytes_per_sec: limits.bytes_per_sec,
+            policy: limits.on_exceed.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> QuotaPolicy {
+        self.policy
+    }
+
+    /// counts a PUBLISH carrying `size` payload bytes, false when it goes over a limit
+    pub fn allow(&mut self, now: u64, size: usize) -> bool {
+        if self.messages_per_sec.is_none() && self.bytes_per_sec.is_none() {
+            return true;
+        }
+        if now != self.window {
+            self.window = now;
+            self.messages = 0;
+            self.bytes = 0;
+        }
+        self.messages += 1;
+        self.bytes += size as u64;
+        // a payload larger than bytes_per_sec still gets through as the first one of its window
+        self.messages_per_sec.is_none_or(|m| self.messages <= m)
+            && self
+                .bytes_per_sec
+                .is_none_or(|b| self.bytes <= b || self.messages == 1)
+    }
+}
+
+/// QoS 2 publishes the client may have waiting for PUBREL, never above max_receive_queue
+pub fn receive_maximum(limits: &ClientLimitsConfig) -> u16 {
+    let max_receive_queue = CONFIG.get().unwrap().mqtt.settings.max_receive_queue;
+    limits
+        .max_inflight
+        .map_or(max_receive_queue, |m| m.min(max_receive_queue))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota() {
+        let mut quota = ClientQuota::default();
+        assert!(quota.allow(1, 1 << 20));
+
+        let mut quota = ClientQuota::new(&ClientLimitsConfig {
+            messages_per_sec: Some(3),
+            bytes_per_sec: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(quota.policy(), QuotaPolicy::Throttle);
+        assert!(quota.allow(1, 40));
+        assert!(quota.allow(1, 60));
+        assert!(!quota.allow(1, 1));
+
+        // a new window, the oversized first payload passes alone
+        assert!(quota.allow(2, 500));
+        assert!(!quota.allow(2, 0));
+
+        assert!(quota.allow(3, 0));
+        assert!(quota.allow(3, 0));
+        assert!(quota.allow(3, 0));
+        assert!(!quota.allow(3, 0));
+    }
+}
@@ -1,24 +1,55 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 
 use coarsetime;
 use futures_util::{SinkExt, stream::StreamExt as _};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::{sync::mpsc, time};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
 use tokio_util::codec::Framed;
 use tracing::{Instrument, debug, info, warn};
 
 use crate::CONFIG;
+use crate::config::QuotaPolicy;
 use crate::operator::helper::Helper as OperatorHelper;
 use crate::utils as g_utils;
 
-use crate::mqtt::protocol::{codec::MessageCodec, conn::Disconnect, message::Message, publish};
+use crate::mqtt::protocol::{
+    codec::MessageCodec,
+    conn::{ConnAck, Connect, Disconnect},
+    message::Message,
+    publish,
+};
 use crate::mqtt::{
-    MqttProtocolVersion, QoS, code::ReturnCode, command::ClientCommand, error::MqttProtocolError,
-    helper::BrokerHelper, utils,
+    MqttProtocolVersion, QoS,
+    ban::{self, BanTarget, Offense},
+    code::ReturnCode,
+    command::ClientCommand,
+    error::MqttProtocolError,
+    group::PublishRateLimiter,
+    helper::BrokerHelper,
+    utils,
 };
 
+use super::admission::Ticket;
+use super::quota::{self, ClientQuota};
+use super::spill::SpillOptions;
 use super::store::Store;
+use super::tcp::TlsInfo;
+use super::topic_policy;
+
+/// attach what the TLS handshake told to CONNECT, and optionally take the certificate identity as client id
+pub fn apply_tls_info(conn: &mut Connect, tls_info: TlsInfo, cert_as_client_id: bool) {
+    if cert_as_client_id
+        && let Some(identity) = tls_info.peer_cert.as_ref().and_then(|c| c.identity())
+    {
+        conn.client_id = identity.to_string();
+    }
+    conn.peer_cert = tls_info.peer_cert;
+    conn.server_name = tls_info.server_name;
+}
 
 struct ClientStream<S: AsyncRead + AsyncWrite + Unpin> {
     framed: Framed<S, MessageCodec>,
@@ -27,6 +58,9 @@ struct ClientStream<S: AsyncRead + AsyncWrite + Unpin> {
 pub async fn process_client<S>(
     client_stream: S,
     addr: SocketAddr,
+    tls_info: TlsInfo,
+    cert_as_client_id: bool,
+    ticket: Ticket,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
 ) where
@@ -36,6 +70,10 @@ pub async fn process_client<S>(
     let mut async_client = ClientStream {
         framed: tokio_util::codec::Framed::new(client_stream, MessageCodec::default()),
     };
+    async_client
+        .framed
+        .codec_mut()
+        .with_mountpoint(ticket.mountpoint.clone());
 
     let mut resend_tk = time::interval(time::Duration::from_secs(
         CONFIG.get().unwrap().mqtt.settings.resend_interval,
@@ -43,29 +81,59 @@ pub async fn process_client<S>(
     resend_tk.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
     let mut version = MqttProtocolVersion::V3_1_1;
-    let mut keep_alive = CONFIG.get().unwrap().mqtt.settings.keep_alive;
+    let mut keep_alive = 0;
     let mut client_id = String::new();
+    let mut server_name = None;
     let mut client_rx = None;
     let mut inflight_maximum = 128u16;
     let mut pre_store = None;
 
     let mut client_topic_alias: HashMap<u16, String> = HashMap::new();
+    let mut rate_limiter = PublishRateLimiter::default();
+    let mut quota = ClientQuota::new(&ticket.client_limits);
+    let receive_maximum = quota::receive_maximum(&ticket.client_limits);
     let mut client_topic_alias_maximum: u16 = 0;
 
     let result = time::timeout(time::Duration::from_secs(3), async {
         let msg = async_client.framed.next().await;
         if msg.is_none() || msg.as_ref().unwrap().is_err() {
+            if let Some(Err(e)) = &msg && e.malformed() {
+                ban::offense(Offense::MalformedPacket, BanTarget::ip(addr.ip()));
+            }
             debug!(parent: &span, "disconnected before CONNECT" );
             async_client.framed.close().await.ok();
             return Err(());
         }
         let msg = msg.unwrap().unwrap();
-        if let Message::Connect(conn) = msg {
+        if let Message::Connect(mut conn) = msg {
+            apply_tls_info(&mut conn, tls_info, cert_as_client_id);
             span = tracing::info_span!("client", %addr, client = %g_utils::TruncateDisplay::new(&conn.client_id, 24));
+            let refused = if ticket.busy() {
+                debug!(parent: &span, "listener at max connections, connection rejected");
+                Some(busy_ack(conn.version))
+            } else if !ticket.accepts(conn.version) {
+                debug!(parent: &span, "MQTT {} not accepted by the listener, connection rejected", conn.version);
+                Some(unsupported_version_ack(conn.version))
+            } else {
+                None
+            };
+            if let Some(ack) = refused {
+                if conn.version == MqttProtocolVersion::V5 {
+                    async_client.framed.codec_mut().with_v5();
+                }
+                utils::audit_connect(&conn.client_id, addr, conn.version, conn.peer_cert.as_ref(), ack.return_code);
+                async_client
+                    .framed
+                    .send(Message::ConnAck(ack))
+                    .await
+                    .ok();
+                async_client.framed.close().await.ok();
+                return Err(());
+            }
             let (client_tx, c_rx) = mpsc::channel::<ClientCommand>(128);
             client_rx = Some(c_rx);
 
-            if let Ok((ack, old_store)) = broker_helper.connect(conn.clone(), client_tx).await {
+            if let Ok((mut ack, old_store)) = broker_helper.connect(conn.clone(), addr, client_tx).await {
                 if ack.return_code != ReturnCode::Success {
                     debug!(parent: &span, "connection rejected: {}", ack.return_code);
                     async_client
@@ -77,10 +145,10 @@ pub async fn process_client<S>(
                     return Err(());
                 }
                 version = conn.version;
-                if conn.keep_alive > keep_alive {
-                    keep_alive = conn.keep_alive;
-                }
-                client_id = conn.client_id.clone();
+                keep_alive = utils::keep_alive(conn.keep_alive, conn.version);
+                // a suffixed identifier when the one in CONNECT is taken
+                client_id = ack.assigned_client_id().unwrap_or(&conn.client_id).to_string();
+                server_name = conn.server_name.clone();
                 pre_store = old_store;
 
                 if conn.version == MqttProtocolVersion::V5 {
@@ -89,6 +157,8 @@ pub async fn process_client<S>(
                 }
                 inflight_maximum = conn.options.inflight_maximum;
                 async_client.framed.codec_mut().with_packet_size(conn.options.packet_maximum);
+                ack.options.receive_maximum = Some(receive_maximum);
+                ack.options.server_keep_alive = Some(keep_alive);
 
                 async_client
                     .framed
@@ -99,6 +169,8 @@ pub async fn process_client<S>(
             }
             return Ok(());
         } else {
+            // anything but CONNECT first is a protocol violation
+            ban::offense(Offense::MalformedPacket, BanTarget::ip(addr.ip()));
             async_client.framed.close().await.ok();
             return Err(());
         }
@@ -110,24 +182,35 @@ pub async fn process_client<S>(
         return;
     }
 
-    let mut message_store = Store::new(
-        inflight_maximum as usize,
-        CONFIG.get().unwrap().mqtt.settings.max_receive_queue as usize,
-    );
+    let mut message_store = Store::new(inflight_maximum as usize, receive_maximum as usize)
+        .with_spill(SpillOptions::from_settings(
+            &CONFIG.get().unwrap().mqtt.settings,
+        ));
     if let Some(pre_store) = pre_store {
         message_store.extend(pre_store);
+        // a resumed session continues where it stopped, unacknowledged PUBLISH and PUBREL first
+        let now = coarsetime::Clock::now_since_epoch().as_secs();
+        for (pkid, msg) in message_store.resume_messages(now) {
+            let msg = match msg {
+                Some(msg) => Message::SharedPublish(msg),
+                None => Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success)),
+            };
+            let _ = async_client.framed.send(msg).await;
+        }
     }
 
     let mut packet_id = 1;
+    let mut pending_acks = VecDeque::new();
     let mut client_rx = client_rx.unwrap();
     let resend_time = CONFIG.get().unwrap().mqtt.settings.resend_interval;
     let mut client_msg_tm = coarsetime::Clock::now_since_epoch().as_secs();
-    let mut keepalive_tk = time::interval(time::Duration::from_secs(keep_alive as u64));
+    // a keep alive of 0 is never checked
+    let mut keepalive_tk = time::interval(time::Duration::from_secs(keep_alive.max(1) as u64));
     keepalive_tk.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
     loop {
         tokio::select! {
-            _ = keepalive_tk.tick(), if coarsetime::Clock::now_since_epoch().as_secs() - client_msg_tm > (keep_alive * 3 / 2) as u64 => {
+            _ = keepalive_tk.tick(), if keep_alive > 0 && coarsetime::Clock::now_since_epoch().as_secs() - client_msg_tm > keep_alive as u64 * 3 / 2 => {
                 warn!(parent: &span, "keep alive timeout, disconnecting");
                 broker_helper.disconnected(client_id.as_str(), ReturnCode::KeepAliveTimeout, None, message_store).await.ok();
                 async_client.framed.close().await.ok();
@@ -137,48 +220,77 @@ pub async fn process_client<S>(
                 let now = coarsetime::Clock::now_since_epoch().as_secs();
                 for (pkid, msg) in message_store.get_inflight_messages(now, resend_time).into_iter() {
                     if let Some(msg) = msg {
-                        let msg = Message::Publish(msg);
+                        let msg = Message::SharedPublish(msg);
                         let _ = async_client.framed.send(msg).await;
                     } else {
                         let _ = async_client.framed.send(Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success))).await;
                     }
                 }
             }
+            took = next_sink_ack(&mut pending_acks), if !pending_acks.is_empty() => {
+                let (pkid, _) = pending_acks.pop_front().unwrap();
+                if let Some(ack) = sink_ack(version, pkid, took) {
+                    let _ = async_client.framed.send(ack).await;
+                } else {
+                    warn!(parent: &span, "a chain with sink_ack failed on a message, disconnecting");
+                    broker_helper.disconnected(client_id.as_str(), ReturnCode::ImSpecificError, None, message_store).await.ok();
+                    async_client.framed.close().await.ok();
+                    break;
+                }
+            }
             Some(command) = client_rx.recv() => {
                 match command {
+                    ClientCommand::PublishRate(rate) => {
+                        rate_limiter.set_rate(rate);
+                    }
+                    ClientCommand::Inspect(resp) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        resp.try_send((message_store.info(now), client_msg_tm)).ok();
+                    }
+                    ClientCommand::PurgeQueue(resp) => {
+                        resp.try_send(message_store.purge()).ok();
+                    }
+                    ClientCommand::Resend(resp) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        let msgs = message_store.resume_messages(now);
+                        resp.try_send(msgs.len()).ok();
+                        for (pkid, msg) in msgs {
+                            let msg = match msg {
+                                Some(msg) => Message::SharedPublish(msg),
+                                None => Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success)),
+                            };
+                            let _ = async_client.framed.send(msg).await;
+                        }
+                    }
                     ClientCommand::Disconnect(code) => {
+                        // reported before the client sees the DISCONNECT and can come back
+                        broker_helper.disconnected(client_id.as_str(), code, None, message_store).await.ok();
                         async_client.framed.send(Message::Disconnect(Disconnect::new(code))).await.ok();
                         async_client.framed.close().await.ok();
                         break;
                     }
-                    ClientCommand::Publish{qos, retain, topic, payload, user_properties, options}=> {
-                        if let Some(expiry_at) = options.message_expiry_at {
-                            if expiry_at != 0 && expiry_at <= coarsetime::Clock::now_since_epoch().as_secs() {
-                                continue;
-                            }
+                    ClientCommand::TakenOver => {
+                        async_client.framed.send(Message::Disconnect(Disconnect::new(ReturnCode::SessionTakenOver))).await.ok();
+                        async_client.framed.close().await.ok();
+                        break;
+                    }
+                    ClientCommand::Publish(mut publish) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        if publish.expired(now) {
+                            continue;
                         }
-                        let pid = if qos != QoS::AtMostOnce {
-                            packet_id = get_packet_id(packet_id);
-                            Some(packet_id)
-                        } else {
-                            None
-                        };
-                        let publish = publish::Publish::new(
-                            false,
-                            qos,
-                            retain,
-                            topic,
-                            pid,
-                            payload,
-                            user_properties,
-                        ).with_options(options);
-                        if qos != QoS::AtMostOnce && (publish.options.message_expiry_interval.is_none() || publish.options.message_expiry_interval.unwrap() != 0) {
+                        if publish.qos != QoS::AtMostOnce {
+                            packet_id = message_store.next_packet_id(packet_id);
+                            publish.packet_id = Some(packet_id);
+                        }
+                        let publish = publish.with_remaining_expiry(now);
+                        if publish.qos != QoS::AtMostOnce && publish.message_expiry_interval != Some(0) {
                             if message_store.inflight_insert(publish.clone()) {
-                                let msg = Message::Publish(publish);
+                                let msg = Message::SharedPublish(publish);
                                 let _ = async_client.framed.send(msg).await;
                             }
                         } else {
-                            let msg = Message::Publish(publish);
+                            let msg = Message::SharedPublish(publish);
                             let _ = async_client.framed.send(msg).await;
                         }
                     }
@@ -186,8 +298,11 @@ pub async fn process_client<S>(
             }
             msg = async_client.framed.next() => {
                 if msg.is_none() || msg.as_ref().unwrap().is_err() {
-                    if msg.is_some() && msg.as_ref().unwrap().is_err() {
-                        warn!(parent: &span, "error reading message: {:?}", msg.unwrap().err());
+                    if let Some(Err(e)) = msg {
+                        if e.malformed() {
+                            ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
+                        }
+                        warn!(parent: &span, "error reading message: {:?}", e);
                     } else {
                         info!(parent: &span, "disconnected");
                     }
@@ -198,15 +313,52 @@ pub async fn process_client<S>(
                 let msg = msg.unwrap().unwrap();
                 if let Message::PacketTooLarge = msg {
                     warn!(parent: &span, "packet too large, disconnecting");
+                    ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
                     async_client.framed.send(Message::Disconnect(Disconnect::new(ReturnCode::PacketTooLarge))).await.ok();
                     broker_helper.disconnected(client_id.as_str(), ReturnCode::PacketTooLarge, None, message_store).await.ok();
                     async_client.framed.close().await.ok();
                     break;
                 }
 
+                #[cfg(feature = "chaos")]
+                match crate::chaos::listener(client_id.as_str()).await {
+                    crate::chaos::Fault::Pass => {}
+                    crate::chaos::Fault::Drop => continue,
+                    crate::chaos::Fault::Disconnect => {
+                        warn!(parent: &span, "chaos disconnect");
+                        broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store).await.ok();
+                        async_client.framed.close().await.ok();
+                        break;
+                    }
+                }
+
                 client_msg_tm = coarsetime::Clock::now_since_epoch().as_secs();
+                if !check_publish_rate(&mut rate_limiter, client_msg_tm, &msg) {
+                    debug!(parent: &span, "publish rate exceeded, message dropped");
+                    if let Some(e) = rate_offense(client_id.as_str()) {
+                        warn!(parent: &span, "banned for exceeding the publish rate, disconnecting");
+                        if let Some(notice) = disconnect_notice(version, &e) {
+                            let _ = async_client.framed.send(notice).await;
+                        }
+                        broker_helper.disconnected(client_id.as_str(), ReturnCode::QuotaExceeded, None, message_store).await.ok();
+                        async_client.framed.close().await.ok();
+                        break;
+                    }
+                    if let Some(resp) = quota_exceeded_ack(&msg) {
+                        let _ = async_client.framed.send(resp).await;
+                    }
+                    continue;
+                }
 
-                let result = handle_message(broker_helper.clone(), operator_helper.clone(), &mut message_store, &mut client_topic_alias, client_topic_alias_maximum, client_id.as_str(), msg).instrument(span.clone()).await;
+                let result = handle_message(broker_helper.clone(), operator_helper.clone(), ClientState {
+                    client_id: client_id.as_str(),
+                    server_name: server_name.as_deref(),
+                    message_store: &mut message_store,
+                    quota: &mut quota,
+                    topic_alias: &mut client_topic_alias,
+                    topic_alias_maximum: client_topic_alias_maximum,
+                    pending_acks: &mut pending_acks,
+                }, msg).instrument(span.clone()).await;
                 match result {
                     Ok(Some(resp)) => {
                         let _ = async_client.framed.send(resp).await;
@@ -214,6 +366,12 @@ pub async fn process_client<S>(
                     Ok(None) => {}
                     Err(e) => {
                         warn!(parent: &span, "connection error : {}", e);
+                        if e.malformed() {
+                            ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
+                        }
+                        if let Some(notice) = disconnect_notice(version, &e) {
+                            let _ = async_client.framed.send(notice).await;
+                        }
                         if let MqttProtocolError::Disconnected(code, session_expiry_interval) = e {
                             broker_helper.disconnected(client_id.as_str(), code, session_expiry_interval, message_store).await.ok();
                         } else {
@@ -228,15 +386,127 @@ pub async fn process_client<S>(
     }
 }
 
+/// count a publish over the limits of the client, the error to disconnect it with once that
+/// got it banned
+pub fn rate_offense(client_id: &str) -> Option<MqttProtocolError> {
+    ban::offense(
+        Offense::RateLimit,
+        BanTarget::ClientId(client_id.to_string()),
+    )
+    .then_some(MqttProtocolError::Disconnected(
+        ReturnCode::QuotaExceeded,
+        None,
+    ))
+}
+
+/// whether a message passes the client's group rate limit, only PUBLISH is counted
+pub fn check_publish_rate(limiter: &mut PublishRateLimiter, now: u64, msg: &Message) -> bool {
+    !matches!(msg, Message::Publish(_)) || limiter.allow(now)
+}
+
+/// answers a PUBLISH dropped by the rate limit, QoS 1/2 publishers get Quota Exceeded
+pub fn quota_exceeded_ack(msg: &Message) -> Option<Message> {
+    let Message::Publish(publish) = msg else {
+        return None;
+    };
+    refused_ack(publish, ReturnCode::QuotaExceeded)
+}
+
+/// the PUBACK or PUBREC refusing a message, nothing for QoS 0
+fn refused_ack(publish: &publish::Publish, code: ReturnCode) -> Option<Message> {
+    let packet_id = publish.packet_id.unwrap_or(0);
+    match publish.qos {
+        QoS::AtMostOnce => None,
+        QoS::AtLeastOnce => Some(Message::PubAck(publish::PubAck::new(packet_id, code))),
+        QoS::ExactlyOnce => Some(Message::PubRec(publish::PubRec::new(packet_id, code))),
+    }
+}
+
+/// answers the CONNECT of a client the listener has no room for
+pub fn busy_ack(version: MqttProtocolVersion) -> ConnAck {
+    let code = if version == MqttProtocolVersion::V5 {
+        ReturnCode::ServerBusy
+    } else {
+        ReturnCode::ServerUnavailable
+    };
+    ConnAck::new(false, code, None)
+}
+
+/// answers the CONNECT of a client whose MQTT version the listener does not take
+pub fn unsupported_version_ack(version: MqttProtocolVersion) -> ConnAck {
+    let code = if version == MqttProtocolVersion::V5 {
+        ReturnCode::UnsupProtoVersion
+    } else {
+        ReturnCode::UnsupportedProtocolVersion
+    };
+    ConnAck::new(false, code, None)
+}
+
+/// tells the client why the broker closes the connection, for the limits it went over and
+/// the protocol errors it made, V3.1.1 has no DISCONNECT from the server and the connection
+/// is just closed
+pub fn disconnect_notice(version: MqttProtocolVersion, e: &MqttProtocolError) -> Option<Message> {
+    match e {
+        MqttProtocolError::Disconnected(
+            code @ (ReturnCode::QuotaExceeded
+            | ReturnCode::ReceiveMaximumExceeded
+            | ReturnCode::ProtocolError),
+            _,
+        ) if version == MqttProtocolVersion::V5 => {
+            Some(Message::Disconnect(Disconnect::new(*code)))
+        }
+        _ => None,
+    }
+}
+
+/// the state of a connection `handle_message` reads and updates
+pub struct ClientState<'a> {
+    pub client_id: &'a str,
+    pub server_name: Option<&'a str>,
+    pub message_store: &'a mut Store,
+    pub quota: &'a mut ClientQuota,
+    pub topic_alias: &'a mut HashMap<u16, String>,
+    pub topic_alias_maximum: u16,
+    pub pending_acks: &'a mut VecDeque<PendingAck>,
+}
+
+/// a QoS 1 PUBLISH whose PUBACK waits for the chains with `sink_ack`, or behind one that does
+pub type PendingAck = (u16, Option<oneshot::Receiver<bool>>);
+
+/// whether the chains with `sink_ack` took the oldest PUBLISH waiting for its PUBACK
+pub async fn next_sink_ack(pending_acks: &mut VecDeque<PendingAck>) -> bool {
+    match pending_acks.front_mut() {
+        Some((_, Some(acked))) => acked.await.unwrap_or(false),
+        _ => true,
+    }
+}
+
+/// the PUBACK of a message the chains with `sink_ack` are through with, None when a chain
+/// failed on it and the publisher is V3.1.1, whose PUBACK cannot tell so and is disconnected
+pub fn sink_ack(version: MqttProtocolVersion, packet_id: u16, took: bool) -> Option<Message> {
+    let code = match (took, version) {
+        (true, _) => ReturnCode::Success,
+        (false, MqttProtocolVersion::V5) => ReturnCode::ImSpecificError,
+        (false, _) => return None,
+    };
+    Some(Message::PubAck(publish::PubAck::new(packet_id, code)))
+}
+
 pub async fn handle_message(
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
-    message_store: &mut Store,
-    client_topic_alias: &mut HashMap<u16, String>,
-    client_topic_alias_maximum: u16,
-    client_id: &str,
+    client: ClientState<'_>,
     msg: Message,
 ) -> Result<Option<Message>, MqttProtocolError> {
+    let ClientState {
+        client_id,
+        server_name,
+        message_store,
+        quota,
+        topic_alias: client_topic_alias,
+        topic_alias_maximum: client_topic_alias_maximum,
+        pending_acks,
+    } = client;
     match msg {
         Message::Connect(_) => {
             debug!("sent CONNECT after initial CONNECT");
@@ -254,6 +524,13 @@ pub async fn handle_message(
                     g_utils::TruncateDisplay::new(topic, 128),
                     options
                 );
+                // No Local on a shared subscription is a Protocol Error [MQTT-3.8.3-4]
+                if options.no_local && utils::is_shared_subscription(topic) {
+                    return Err(MqttProtocolError::Disconnected(
+                        ReturnCode::ProtocolError,
+                        None,
+                    ));
+                }
             }
             let ack = broker_helper.subscribe(client_id, sub).await?;
             Ok(Some(Message::SubAck(ack)))
@@ -269,6 +546,32 @@ pub async fn handle_message(
             Ok(Some(Message::UnsubAck(ack)))
         }
         Message::Publish(mut publish) => {
+            let now = coarsetime::Clock::now_since_epoch();
+            if !quota.allow(now.as_secs(), publish.payload.len()) {
+                if let Some(e) = rate_offense(client_id) {
+                    return Err(e);
+                }
+                match quota.policy() {
+                    QuotaPolicy::Throttle => {
+                        // holding the connection back until the next window slows the client down
+                        debug!("publish quota exceeded, throttling");
+                        time::sleep(time::Duration::from_millis(1000 - now.as_millis() % 1000))
+                            .await;
+                        quota.allow(now.as_secs() + 1, publish.payload.len());
+                    }
+                    QuotaPolicy::Reject => {
+                        debug!("publish quota exceeded, message dropped");
+                        return Ok(quota_exceeded_ack(&Message::Publish(publish)));
+                    }
+                    QuotaPolicy::Disconnect => {
+                        return Err(MqttProtocolError::Disconnected(
+                            ReturnCode::QuotaExceeded,
+                            None,
+                        ));
+                    }
+                }
+            }
+
             if let Some(topic_alias) = publish.options.topic_alias {
                 if topic_alias == 0 {
                     return Err(MqttProtocolError::Disconnected(
@@ -282,7 +585,7 @@ pub async fn handle_message(
                         publish.topic = client_topic_alias.get(&topic_alias).unwrap().clone();
                     }
                 } else {
-                    if utils::pub_topic_valid(&publish.topic) {
+                    if utils::client_pub_topic_valid(&publish.topic) {
                         if client_topic_alias.contains_key(&topic_alias) {
                             client_topic_alias.insert(topic_alias, publish.topic.clone());
                         } else {
@@ -302,7 +605,7 @@ pub async fn handle_message(
             }
 
             if publish.options.topic_alias.is_some() && publish.topic.is_empty()
-                || !utils::pub_topic_valid(&publish.topic)
+                || !utils::client_pub_topic_valid(&publish.topic)
             {
                 if publish.qos == QoS::AtLeastOnce {
                     let pub_ack = publish::PubAck::new(
@@ -321,12 +624,27 @@ pub async fn handle_message(
                 }
             }
 
+            if let Some(code) = topic_policy::check(&publish) {
+                debug!(
+                    "publish on {} refused by a topic policy: {}",
+                    g_utils::TruncateDisplay::new(&publish.topic, 128),
+                    code
+                );
+                return Ok(refused_ack(&publish, code));
+            }
+
             if publish.qos == QoS::AtLeastOnce {
-                let pub_ack =
-                    publish::PubAck::new(publish.packet_id.unwrap_or(0), ReturnCode::Success);
+                let packet_id = publish.packet_id.unwrap_or(0);
+                if message_store.receive_maximum_reached(pending_acks.len()) {
+                    return Err(MqttProtocolError::Disconnected(
+                        ReturnCode::ReceiveMaximumExceeded,
+                        None,
+                    ));
+                }
                 if publish.retain {
                     broker_helper
                         .retain_message(
+                            Some(client_id.to_string()),
                             publish.topic.clone(),
                             publish.qos,
                             publish.payload.clone(),
@@ -337,9 +655,10 @@ pub async fn handle_message(
                         .ok();
                 }
 
-                operator_helper
+                let acked = operator_helper
                     .publish(
                         client_id.to_string(),
+                        server_name.map(str::to_string),
                         false,
                         publish.qos,
                         publish.topic.clone(),
@@ -348,8 +667,18 @@ pub async fn handle_message(
                         publish.options,
                     )
                     .await
-                    .ok();
-                Ok(Some(Message::PubAck(pub_ack)))
+                    .ok()
+                    .flatten();
+                // answered by the connection loop once the chains with sink_ack are through,
+                // the PUBACKs keep the order of the PUBLISHes
+                if acked.is_some() || !pending_acks.is_empty() {
+                    pending_acks.push_back((packet_id, acked));
+                    return Ok(None);
+                }
+                Ok(Some(Message::PubAck(publish::PubAck::new(
+                    packet_id,
+                    ReturnCode::Success,
+                ))))
             } else if publish.qos == QoS::ExactlyOnce {
                 if message_store.qos2_contains(publish.packet_id.unwrap()) {
                     let pub_rec =
@@ -360,7 +689,9 @@ pub async fn handle_message(
                 let pub_rec =
                     publish::PubRec::new(publish.packet_id.unwrap_or(0), ReturnCode::Success);
 
-                if !message_store.qos2_insert(publish) {
+                if message_store.receive_maximum_reached(pending_acks.len())
+                    || !message_store.qos2_insert(publish)
+                {
                     Err(MqttProtocolError::Disconnected(
                         ReturnCode::ReceiveMaximumExceeded,
                         None,
@@ -372,6 +703,7 @@ pub async fn handle_message(
                 if publish.retain {
                     broker_helper
                         .retain_message(
+                            Some(client_id.to_string()),
                             publish.topic.clone(),
                             publish.qos,
                             publish.payload.clone(),
@@ -384,6 +716,7 @@ pub async fn handle_message(
                 operator_helper
                     .publish(
                         client_id.to_string(),
+                        server_name.map(str::to_string),
                         false,
                         publish.qos,
                         publish.topic.clone(),
@@ -423,6 +756,7 @@ pub async fn handle_message(
                 if publish.retain {
                     broker_helper
                         .retain_message(
+                            Some(client_id.to_string()),
                             publish.topic.clone(),
                             publish.qos,
                             publish.payload.clone(),
@@ -436,6 +770,7 @@ pub async fn handle_message(
                 operator_helper
                     .publish(
                         client_id.to_string(),
+                        server_name.map(str::to_string),
                         false,
                         publish.qos,
                         publish.topic,
@@ -453,10 +788,150 @@ pub async fn handle_message(
     }
 }
 
-pub fn get_packet_id(packet_id: u16) -> u16 {
-    if packet_id == u16::MAX {
-        1
-    } else {
-        packet_id + 1
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::mqtt::protocol::subscribe::{Subscribe, SubscribeOption};
+    use crate::mqtt::retain_trie::SharedRetainedTrie;
+
+    /// helpers whose broker and operator are gone, for messages refused before reaching them
+    fn helpers() -> (BrokerHelper, OperatorHelper) {
+        // the topic checks read the broker limits
+        CONFIG.get_or_init(|| Config::parse(include_str!("../../../config.toml"), ".").unwrap());
+        let broker_helper = BrokerHelper {
+            broker_tx: mpsc::channel(1).0,
+            retain_trie: SharedRetainedTrie::new(),
+        };
+        let operator_helper = OperatorHelper::new(mpsc::channel(1).0, mpsc::channel(1).0, None);
+        (broker_helper, operator_helper)
+    }
+
+    #[tokio::test]
+    async fn test_no_local_shared() {
+        let (broker_helper, operator_helper) = helpers();
+        let mut message_store = Store::new(10, 10);
+        let mut quota = ClientQuota::default();
+        let mut topic_alias = HashMap::new();
+        let mut pending_acks = VecDeque::new();
+        let no_local = SubscribeOption {
+            qos: QoS::AtLeastOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: 0,
+            subscription_identifier: None,
+        };
+        let sub = Subscribe {
+            packet_id: 1,
+            topics: vec![("$share/g/t/1".to_string(), no_local)],
+        };
+        let client = ClientState {
+            client_id: "c",
+            server_name: None,
+            message_store: &mut message_store,
+            quota: &mut quota,
+            topic_alias: &mut topic_alias,
+            topic_alias_maximum: 0,
+            pending_acks: &mut pending_acks,
+        };
+        let e = handle_message(
+            broker_helper,
+            operator_helper,
+            client,
+            Message::Subscribe(sub),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(
+            e,
+            MqttProtocolError::Disconnected(ReturnCode::ProtocolError, None)
+        ));
+        let Some(Message::Disconnect(notice)) = disconnect_notice(MqttProtocolVersion::V5, &e)
+        else {
+            panic!("no DISCONNECT");
+        };
+        assert_eq!(notice.reason, ReturnCode::ProtocolError);
+    }
+
+    #[tokio::test]
+    async fn test_receive_maximum_exceeded() {
+        let (broker_helper, operator_helper) = helpers();
+        let mut message_store = Store::new(10, 2);
+        let mut quota = ClientQuota::default();
+        let mut topic_alias = HashMap::new();
+        // one QoS 1 publish waiting for the chains with sink_ack, one QoS 2 for PUBREL
+        let mut pending_acks: VecDeque<PendingAck> = VecDeque::new();
+        pending_acks.push_back((1, Some(oneshot::channel().1)));
+        let qos2 = |packet_id| {
+            publish::Publish::new(
+                false,
+                QoS::ExactlyOnce,
+                false,
+                "a/b".to_string(),
+                Some(packet_id),
+                Bytes::from_static(b"payload"),
+                vec![],
+            )
+        };
+        assert!(message_store.qos2_insert(qos2(2)));
+
+        for qos in [QoS::AtLeastOnce, QoS::ExactlyOnce] {
+            let mut publish = qos2(3);
+            publish.qos = qos;
+            let client = ClientState {
+                client_id: "c",
+                server_name: None,
+                message_store: &mut message_store,
+                quota: &mut quota,
+                topic_alias: &mut topic_alias,
+                topic_alias_maximum: 0,
+                pending_acks: &mut pending_acks,
+            };
+            let result = handle_message(
+                broker_helper.clone(),
+                operator_helper.clone(),
+                client,
+                Message::Publish(publish),
+            )
+            .await;
+            assert!(matches!(
+                result,
+                Err(MqttProtocolError::Disconnected(
+                    ReturnCode::ReceiveMaximumExceeded,
+                    None
+                ))
+            ));
+        }
+        assert!(!message_store.qos2_contains(3));
+    }
+
+    #[tokio::test]
+    async fn test_sink_ack() {
+        let (first_tx, first_rx) = oneshot::channel();
+        let mut pending_acks: VecDeque<PendingAck> = VecDeque::new();
+        pending_acks.push_back((1, Some(first_rx)));
+        pending_acks.push_back((2, None));
+
+        // the oldest PUBLISH is answered first, whatever comes after it
+        let waiting = time::timeout(
+            time::Duration::from_millis(20),
+            next_sink_ack(&mut pending_acks),
+        );
+        assert!(waiting.await.is_err());
+        first_tx.send(false).unwrap();
+        assert!(!next_sink_ack(&mut pending_acks).await);
+        assert_eq!(pending_acks.pop_front().unwrap().0, 1);
+        assert!(next_sink_ack(&mut pending_acks).await);
+
+        let Some(Message::PubAck(ack)) = sink_ack(MqttProtocolVersion::V5, 1, false) else {
+            panic!("no PUBACK");
+        };
+        assert_eq!(ack.reason_code, ReturnCode::ImSpecificError);
+        assert!(sink_ack(MqttProtocolVersion::V3_1_1, 1, false).is_none());
+        assert!(sink_ack(MqttProtocolVersion::V3_1_1, 1, true).is_some());
     }
 }
@@ -0,0 +1,274 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use tracing::warn;
+
+use crate::config::MqttSettings;
+use crate::mqtt::protocol::publish::{PublishBody, SharedPublish};
+use crate::mqtt::retain_store::{decode_body, encode_body};
+use crate::mqtt::retain_trie::RetainedMessage;
+
+const DEFAULT_SPILL_MEMORY_THRESHOLD: usize = 1024;
+const DEFAULT_SPILL_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+// at most one warning about full segments in this many seconds
+const DROPPED_WARN_INTERVAL_SECS: u64 = 10;
+
+static NEXT_SEGMENT: AtomicU64 = AtomicU64::new(0);
+// messages dropped because their segment was full, since the process started
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// dropped since the last warning, and the second it was logged at
+static DROPPED_UNREPORTED: AtomicU64 = AtomicU64::new(0);
+static DROPPED_WARNED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// messages dropped from the queues of connections because their spill segment was full
+pub fn dropped_total() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// counts a message a full segment did not take, warns about the drops every
+/// `DROPPED_WARN_INTERVAL_SECS` at most
+pub fn record_dropped(max_bytes: u64) {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+    DROPPED_UNREPORTED.fetch_add(1, Ordering::Relaxed);
+    let now = coarsetime::Clock::now_since_epoch().as_secs();
+    let warned_at = DROPPED_WARNED_AT.load(Ordering::Relaxed);
+    if now >= warned_at + DROPPED_WARN_INTERVAL_SECS
+        && DROPPED_WARNED_AT
+            .compare_exchange(warned_at, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        warn!(
+            "{} queued messages were dropped, their spill segment was full ({} bytes, spill_max_bytes)",
+            DROPPED_UNREPORTED.swap(0, Ordering::Relaxed),
+            max_bytes
+        );
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpillOptions {
+    pub dir: PathBuf,
+    // queued messages kept in memory before spilling to disk
+    pub memory_threshold: usize,
+    // maximum size of a connection's segment file
+    pub max_bytes: u64,
+}
+
+impl SpillOptions {
+    pub fn from_settings(settings: &MqttSettings) -> Option<Self> {
+        settings.spill_dir.as_ref().map(|dir| SpillOptions {
+            dir: PathBuf::from(dir),
+            memory_threshold: settings
+                .spill_memory_threshold
+                .unwrap_or(DEFAULT_SPILL_MEMORY_THRESHOLD),
+            max_bytes: settings.spill_max_bytes.unwrap_or(DEFAULT_SPILL_MAX_BYTES),
+        })
+    }
+}
+
+/// FIFO of queued publishes in a per-connection file, removed when dropped.
+///
+/// Each record is `len, flags, packet_id, subscription_id, expiry_at, topic, body`, the file is truncated
+/// every time the queue drains.
+pub struct SpillSegment {
+    path: PathBuf,
+    file: File,
+    read_pos: u64,
+    write_pos: u64,
+    len: usize,
+    max_bytes: u64,
+}
+
+impl SpillSegment {
+    pub fn create(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "{}-{}.spill",
+            std::process::id(),
+            NEXT_SEGMENT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(SpillSegment {
+            path,
+            file,
+            read_pos: 0,
+            write_pos: 0,
+            len: 0,
+            max_bytes,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// bytes of the records still to be read
+    pub fn bytes(&self) -> u64 {
+        self.write_pos - self.read_pos
+    }
+
+    /// append a message, returns false when the segment is full
+    pub fn push(&mut self, msg: &SharedPublish) -> io::Result<bool> {
+        let body = encode_body(&RetainedMessage {
+            topic: msg.body.topic.clone(),
+            qos: msg.qos,
+            payload: msg.body.payload.clone(),
+            user_properties: msg.body.user_properties.clone(),
+            options: msg.body.options.clone(),
+            client_id: None,
+        })?;
+
+        let topic = &msg.body.topic;
+        let mut record = Vec::with_capacity(body.len() + topic.len() + 21);
+        record.write_u32::<BigEndian>(0)?;
+        record.write_u8(msg.retain as u8 | (msg.dup as u8) << 1)?;
+        record.write_u16::<BigEndian>(msg.packet_id.unwrap_or(0))?;
+        record.write_u32::<BigEndian>(msg.subscription_identifier.unwrap_or(0))?;
+        record.write_u64::<BigEndian>(msg.body.options.message_expiry_at.unwrap_or(0))?;
+        record.write_u16::<BigEndian>(topic.len() as u16)?;
+        record.write_all(topic.as_bytes())?;
+        record.write_all(&body)?;
+        let record_len = (record.len() - 4) as u32;
+        record[..4].copy_from_slice(&record_len.to_be_bytes());
+
+        if self.write_pos + record.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+
+        self.file.seek(SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(&record)?;
+        self.write_pos += record.len() as u64;
+        self.len += 1;
+        Ok(true)
+    }
+
+    pub fn pop(&mut self) -> io::Result<Option<SharedPublish>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+
+        self.file.seek(SeekFrom::Start(self.read_pos))?;
+        let record_len = self.file.read_u32::<BigEndian>()?;
+        let mut record = vec![0u8; record_len as usize];
+        self.file.read_exact(&mut record)?;
+        self.read_pos += 4 + record_len as u64;
+        self.len -= 1;
+        if self.len == 0 {
+            self.file.set_len(0)?;
+            self.read_pos = 0;
+            self.write_pos = 0;
+        }
+
+        let mut rdr = io::Cursor::new(record.as_slice());
+        let flags = rdr.read_u8()?;
+        let packet_id = rdr.read_u16::<BigEndian>()?;
+        let subscription_id = rdr.read_u32::<BigEndian>()?;
+        let expiry_at = rdr.read_u64::<BigEndian>()?;
+        let topic_len = rdr.read_u16::<BigEndian>()?;
+        let mut topic = vec![0u8; topic_len as usize];
+        rdr.read_exact(&mut topic)?;
+        let topic =
+            String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let pos = rdr.position() as usize;
+        let message = decode_body(
+            &topic,
+            if expiry_at == 0 {
+                None
+            } else {
+                Some(expiry_at)
+            },
+            &record[pos..],
+        )?;
+
+        let body = PublishBody {
+            topic: message.topic,
+            payload: message.payload,
+            user_properties: message.user_properties,
+            options: message.options,
+        };
+        let mut publish = SharedPublish::new(message.qos, flags & 0x01 != 0, Arc::new(body))
+            .with_subscription_identifier(if subscription_id == 0 {
+                None
+            } else {
+                Some(subscription_id)
+            });
+        publish.dup = flags & 0x02 != 0;
+        publish.packet_id = if packet_id == 0 {
+            None
+        } else {
+            Some(packet_id)
+        };
+        Ok(Some(publish))
+    }
+}
+
+impl Drop for SpillSegment {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::SpillSegment;
+    use crate::mqtt::{
+        QoS,
+        protocol::publish::{PublishBody, PublishOptions, SharedPublish},
+    };
+
+    fn publish(topic: &str, packet_id: u16) -> SharedPublish {
+        let body = PublishBody {
+            topic: topic.to_string(),
+            payload: Bytes::from_static(b"payload"),
+            user_properties: vec![],
+            options: PublishOptions::default(),
+        };
+        let mut publish = SharedPublish::new(QoS::AtLeastOnce, false, Arc::new(body))
+            .with_subscription_identifier(Some(5));
+        publish.packet_id = Some(packet_id);
+        publish
+    }
+
+    #[test]
+    fn test_push_pop_order() {
+        let dir = std::env::temp_dir().join(format!("axonmq-spill-{}", std::process::id()));
+        let mut segment = SpillSegment::create(&dir, 1024).unwrap();
+
+        assert!(segment.push(&publish("a/b", 1)).unwrap());
+        assert!(segment.push(&publish("a/c", 2)).unwrap());
+        let msg = segment.pop().unwrap().unwrap();
+        assert_eq!(msg.body.topic, "a/b");
+        assert_eq!(msg.packet_id, Some(1));
+        assert_eq!(msg.subscription_identifier, Some(5));
+        assert_eq!(segment.pop().unwrap().unwrap().body.topic, "a/c");
+        assert!(segment.is_empty());
+        assert!(segment.pop().unwrap().is_none());
+
+        // full segment rejects the message
+        while segment.push(&publish("a/b", 3)).unwrap() {}
+        assert!(!segment.is_empty());
+
+        drop(segment);
+        let _ = std::fs::remove_dir(&dir);
+    }
+}
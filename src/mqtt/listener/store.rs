@@ -1,31 +1,71 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
+use tracing::{debug, warn};
+
+use super::super::command::{InflightInfo, StoreInfo};
 use super::super::protocol::publish;
+use super::spill::{self, SpillOptions, SpillSegment};
+
+// an outbound QoS 1 or 2 message waiting for its acknowledgement, `msg` is None once PUBREC
+// arrived and PUBREL is pending
+struct Inflight {
+    // the order the message was first sent, kept across reconnects
+    seq: u64,
+    tm: u64,
+    msg: Option<publish::SharedPublish>,
+}
 
-#[derive(Clone)]
 pub struct Store {
-    backup_store: Vec<publish::Publish>,
+    backup_store: VecDeque<publish::SharedPublish>,
+    spill_options: Option<SpillOptions>,
+    spill_segment: Option<SpillSegment>,
     inflight_size: usize,
-    inflight_store: HashMap<u16, (u64, Option<publish::Publish>)>,
+    inflight_store: HashMap<u16, Inflight>,
+    inflight_seq: u64,
 
-    qos2_size: usize,
+    // the Receive Maximum sent in CONNACK, held by the QoS 2 publishes waiting for PUBREL and
+    // the QoS 1 publishes whose PUBACK waits for the chains with sink_ack
+    receive_maximum: usize,
     qos2_recv_store: HashMap<u16, Option<publish::Publish>>,
 }
 
 impl Store {
-    pub fn new(inflight_size: usize, qos2_size: usize) -> Self {
+    pub fn new(inflight_size: usize, receive_maximum: usize) -> Self {
         Store {
             inflight_size: inflight_size,
-            qos2_size: qos2_size,
-            backup_store: Vec::new(),
+            receive_maximum,
+            backup_store: VecDeque::new(),
+            spill_options: None,
+            spill_segment: None,
             inflight_store: HashMap::new(),
+            inflight_seq: 0,
             qos2_recv_store: HashMap::new(),
         }
     }
 
-    pub fn extend(&mut self, other: Store) {
-        self.backup_store.extend(other.backup_store);
+    /// spill queued messages past the memory threshold to a per-connection file
+    pub fn with_spill(mut self, options: Option<SpillOptions>) -> Self {
+        self.spill_options = options;
+        self
+    }
+
+    /// hand over the messages, leaving an empty store with the same limits
+    pub fn take(&mut self) -> Store {
+        let empty = Store::new(self.inflight_size, self.receive_maximum);
+        std::mem::replace(self, empty.with_spill(self.spill_options.clone()))
+    }
+
+    pub fn extend(&mut self, mut other: Store) {
+        self.backup_store.extend(other.backup_store.drain(..));
+        if self.spill_segment.is_none() {
+            self.spill_segment = other.spill_segment.take();
+        } else {
+            while let Some(msg) = other.backup_pop() {
+                self.backup_push(msg);
+            }
+        }
         self.inflight_store.extend(other.inflight_store);
+        self.inflight_seq = self.inflight_seq.max(other.inflight_seq);
         self.qos2_recv_store.extend(other.qos2_recv_store);
     }
 
@@ -33,75 +73,227 @@ impl Store {
         self.inflight_store.len()
     }
 
+    /// drop the messages waiting for a packet identifier, how many there were
+    pub fn purge(&mut self) -> usize {
+        let purged = self.backup_store.len() + self.spill_segment.as_ref().map_or(0, |s| s.len());
+        self.backup_store.clear();
+        // the spill file is removed with its segment
+        self.spill_segment = None;
+        purged
+    }
+
+    /// what the store holds at `now`, inflight messages in the order they were first sent
+    pub fn info(&self, now: u64) -> StoreInfo {
+        let mut inflight: Vec<(u64, InflightInfo)> = self
+            .inflight_store
+            .iter()
+            .map(|(pkid, inflight)| {
+                let info = InflightInfo {
+                    packet_id: *pkid,
+                    qos: inflight.msg.as_ref().map_or(2, |msg| msg.qos as u8),
+                    topic: inflight.msg.as_ref().map(|msg| msg.body.topic.clone()),
+                    age: if inflight.tm == 0 {
+                        0
+                    } else {
+                        now.saturating_sub(inflight.tm)
+                    },
+                    released: inflight.msg.is_none(),
+                };
+                (inflight.seq, info)
+            })
+            .collect();
+        inflight.sort_unstable_by_key(|(seq, _)| *seq);
+
+        let mut pubrel_pending: Vec<u16> = self.qos2_recv_store.keys().copied().collect();
+        pubrel_pending.sort_unstable();
+
+        StoreInfo {
+            queued_msgs: self.backup_store.len(),
+            queued_bytes: self
+                .backup_store
+                .iter()
+                .map(|msg| msg.body.payload.len())
+                .sum(),
+            spilled_msgs: self.spill_segment.as_ref().map_or(0, |s| s.len()),
+            spilled_bytes: self.spill_segment.as_ref().map_or(0, |s| s.bytes()),
+            inflight: inflight.into_iter().map(|(_, info)| info).collect(),
+            pubrel_pending,
+        }
+    }
+
+    /// the messages not acknowledged within `resend_interval`, PUBLISH or PUBREL when None,
+    /// in the order they were first sent
     pub fn get_inflight_messages(
         &mut self,
         now: u64,
         resend_interval: u64,
-    ) -> Vec<(u16, Option<publish::Publish>)> {
-        let mut msgs = Vec::new();
-        for (pkid, (tm, msg)) in self.inflight_store.iter_mut() {
-            if *tm + resend_interval < now {
-                if msgs.len() < self.inflight_size {
-                    msgs.push((*pkid, msg.clone()));
-                    *tm = now;
-                } else {
-                    break;
+    ) -> Vec<(u16, Option<publish::SharedPublish>)> {
+        let mut due: Vec<(u64, u16)> = self
+            .inflight_store
+            .iter()
+            .filter(|(_, inflight)| inflight.tm + resend_interval < now)
+            .map(|(pkid, inflight)| (inflight.seq, *pkid))
+            .collect();
+        due.sort_unstable();
+        due.truncate(self.inflight_size);
+        self.resend(due, now)
+    }
+
+    /// everything not acknowledged when a persistent session is resumed, PUBLISH with DUP
+    /// and pending PUBREL, in the order they were first sent
+    pub fn resume_messages(&mut self, now: u64) -> Vec<(u16, Option<publish::SharedPublish>)> {
+        let mut all: Vec<(u64, u16)> = self
+            .inflight_store
+            .iter()
+            .map(|(pkid, inflight)| (inflight.seq, *pkid))
+            .collect();
+        all.sort_unstable();
+        self.resend(all, now)
+    }
+
+    fn resend(
+        &mut self,
+        pkids: Vec<(u64, u16)>,
+        now: u64,
+    ) -> Vec<(u16, Option<publish::SharedPublish>)> {
+        let mut msgs = Vec::with_capacity(pkids.len());
+        for (_, pkid) in pkids {
+            if let Some(inflight) = self.inflight_store.get_mut(&pkid) {
+                msgs.push((pkid, inflight.msg.clone()));
+                inflight.tm = now;
+                // DUP is unset only on the first send of a message promoted from the backup queue
+                if let Some(msg) = inflight.msg.as_mut() {
+                    msg.dup = true;
                 }
             }
         }
         msgs
     }
 
-    pub fn inflight_ack(&mut self, pkid: u16) {
-        self.inflight_store.remove(&pkid).map(|(_, msg)| msg);
-        if self.inflight_store.len() < self.inflight_size {
-            if let Some(msg) = self.backup_store.pop() {
-                self.inflight_store.insert(pkid, (0, Some(msg)));
+    /// the packet identifier after `packet_id` which no inflight message holds
+    pub fn next_packet_id(&self, packet_id: u16) -> u16 {
+        let mut pkid = packet_id;
+        loop {
+            pkid = if pkid == u16::MAX { 1 } else { pkid + 1 };
+            if !self.inflight_store.contains_key(&pkid) || pkid == packet_id {
+                return pkid;
             }
         }
     }
 
+    pub fn inflight_ack(&mut self, pkid: u16) {
+        self.inflight_store.remove(&pkid);
+        self.promote(pkid);
+    }
+
     pub fn inflight_rec(&mut self, pkid: u16) {
-        let msg = self.inflight_store.get_mut(&pkid);
-        if let Some((tm, m)) = msg {
-            *m = None;
-            *tm = coarsetime::Clock::now_since_epoch().as_secs();
+        if let Some(inflight) = self.inflight_store.get_mut(&pkid) {
+            inflight.msg = None;
+            inflight.tm = coarsetime::Clock::now_since_epoch().as_secs();
         }
     }
 
     pub fn inflight_cmp(&mut self, pkid: u16) {
         self.inflight_store.remove(&pkid);
-        if self.inflight_store.len() < self.inflight_size {
-            if let Some(msg) = self.backup_store.pop() {
-                self.inflight_store.insert(pkid, (0, Some(msg)));
-            }
+        self.promote(pkid);
+    }
+
+    // a queued message takes the freed packet identifier, sent on the next resend tick
+    fn promote(&mut self, pkid: u16) {
+        if self.inflight_store.len() < self.inflight_size
+            && let Some(mut msg) = self.backup_pop()
+        {
+            msg.packet_id = Some(pkid);
+            msg.dup = false;
+            let seq = self.next_seq();
+            self.inflight_store.insert(
+                pkid,
+                Inflight {
+                    seq,
+                    tm: 0,
+                    msg: Some(msg),
+                },
+            );
         }
     }
 
-    pub fn inflight_insert(&mut self, mut msg: publish::Publish) -> bool {
+    pub fn inflight_insert(&mut self, mut msg: publish::SharedPublish) -> bool {
         if self.inflight_store.len() < self.inflight_size {
             msg.dup = true;
+            let seq = self.next_seq();
             self.inflight_store.insert(
                 msg.packet_id.unwrap_or(0),
-                (coarsetime::Clock::now_since_epoch().as_secs(), Some(msg)),
+                Inflight {
+                    seq,
+                    tm: coarsetime::Clock::now_since_epoch().as_secs(),
+                    msg: Some(msg),
+                },
             );
             true
         } else {
-            self.backup_store.push(msg);
+            self.backup_push(msg);
             false
         }
     }
 
+    fn next_seq(&mut self) -> u64 {
+        self.inflight_seq += 1;
+        self.inflight_seq
+    }
+
+    fn backup_push(&mut self, msg: publish::SharedPublish) {
+        if let Some(options) = self.spill_options.as_ref() {
+            // once spilling, everything goes to disk to keep the order
+            if self.backup_store.len() >= options.memory_threshold
+                || self.spill_segment.as_ref().is_some_and(|s| !s.is_empty())
+            {
+                if self.spill_segment.is_none() {
+                    match SpillSegment::create(&options.dir, options.max_bytes) {
+                        Ok(segment) => self.spill_segment = Some(segment),
+                        Err(e) => warn!("failed to create spill segment: {}", e),
+                    }
+                }
+                if let Some(segment) = self.spill_segment.as_mut() {
+                    match segment.push(&msg) {
+                        Ok(true) => return,
+                        Ok(false) => {
+                            debug!("spill segment full, message {} dropped", msg.body.topic);
+                            spill::record_dropped(options.max_bytes);
+                            return;
+                        }
+                        Err(e) => warn!("failed to spill message: {}", e),
+                    }
+                }
+            }
+        }
+        self.backup_store.push_back(msg);
+    }
+
+    fn backup_pop(&mut self) -> Option<publish::SharedPublish> {
+        if let Some(msg) = self.backup_store.pop_front() {
+            return Some(msg);
+        }
+        match self.spill_segment.as_mut()?.pop() {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("failed to read spilled message: {}", e);
+                None
+            }
+        }
+    }
+
     pub fn qos2_contains(&self, pkid: u16) -> bool {
         self.qos2_recv_store.contains_key(&pkid)
     }
 
+    /// holds a QoS 2 publish until PUBREL, false when the client is over the Receive Maximum,
+    /// a retransmission of a held packet identifier takes no more quota
     pub fn qos2_insert(&mut self, msg: publish::Publish) -> bool {
         if self.qos2_recv_store.contains_key(&msg.packet_id.unwrap()) {
             return true;
         }
 
-        if self.qos2_recv_store.len() >= self.qos2_size {
+        if self.qos2_recv_store.len() >= self.receive_maximum {
             return false;
         }
 
@@ -110,7 +302,181 @@ impl Store {
         true
     }
 
+    /// whether the client already has the Receive Maximum of publishes unacknowledged,
+    /// `pending_acks` QoS 1 publishes waiting for their PUBACK besides those held for PUBREL
+    pub fn receive_maximum_reached(&self, pending_acks: usize) -> bool {
+        self.qos2_recv_store.len() + pending_acks >= self.receive_maximum
+    }
+
     pub fn qos2_rel(&mut self, pkid: u16) -> Option<publish::Publish> {
         self.qos2_recv_store.remove(&pkid).and_then(|msg| msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::super::spill::{self, SpillOptions};
+    use super::Store;
+    use crate::mqtt::{
+        QoS,
+        protocol::publish::{Publish, PublishBody, PublishOptions, SharedPublish},
+    };
+
+    fn publish(packet_id: u16) -> Publish {
+        Publish::new(
+            false,
+            QoS::ExactlyOnce,
+            false,
+            "a/b".to_string(),
+            Some(packet_id),
+            Bytes::from_static(b"payload"),
+            vec![],
+        )
+    }
+
+    fn outgoing(packet_id: u16) -> SharedPublish {
+        let body = PublishBody {
+            topic: "a/b".to_string(),
+            payload: Bytes::from_static(b"payload"),
+            user_properties: vec![],
+            options: PublishOptions::default(),
+        };
+        let mut publish = SharedPublish::new(QoS::ExactlyOnce, false, Arc::new(body));
+        publish.packet_id = Some(packet_id);
+        publish
+    }
+
+    #[test]
+    fn test_receive_maximum() {
+        let mut store = Store::new(10, 2);
+        assert!(store.qos2_insert(publish(1)));
+        assert!(store.qos2_insert(publish(2)));
+        // a retransmission is not a new publish
+        assert!(store.qos2_insert(publish(2)));
+        assert!(!store.qos2_insert(publish(3)));
+
+        assert_eq!(store.qos2_rel(1).unwrap().packet_id, Some(1));
+        assert!(store.qos2_rel(1).is_none());
+        assert!(store.qos2_insert(publish(3)));
+
+        // the quota held by a resumed session carries over
+        let mut resumed = Store::new(10, 2);
+        resumed.extend(store.take());
+        assert!(!resumed.qos2_insert(publish(4)));
+        assert!(store.qos2_insert(publish(4)));
+
+        // QoS 1 publishes waiting for their PUBACK take the quota too
+        let mut store = Store::new(10, 2);
+        assert!(!store.receive_maximum_reached(1));
+        assert!(store.qos2_insert(publish(1)));
+        assert!(store.receive_maximum_reached(1));
+    }
+
+    #[test]
+    fn test_spill_segment_full() {
+        let dir = std::env::temp_dir().join(format!("axonmq-store-{}", std::process::id()));
+        let mut store = Store::new(0, 10).with_spill(Some(SpillOptions {
+            dir: dir.clone(),
+            memory_threshold: 1,
+            max_bytes: 256,
+        }));
+        let dropped = spill::dropped_total();
+        for pkid in 1..=20 {
+            assert!(!store.inflight_insert(outgoing(pkid)));
+        }
+
+        // one message in memory, as many as fit in the segment, the rest dropped and counted
+        let info = store.info(u64::MAX);
+        assert_eq!(info.queued_msgs, 1);
+        assert!(info.spilled_msgs > 0 && info.spilled_msgs < 19);
+        let lost = (20 - info.queued_msgs - info.spilled_msgs) as u64;
+        assert!(spill::dropped_total() - dropped >= lost);
+
+        drop(store);
+        let _ = std::fs::remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut store = Store::new(2, 10);
+        for pkid in [7, 3] {
+            assert!(store.inflight_insert(outgoing(pkid)));
+        }
+        // over the inflight window, queued until a packet identifier is freed
+        assert!(!store.inflight_insert(outgoing(9)));
+        store.inflight_rec(7);
+
+        let mut resumed = Store::new(2, 10);
+        resumed.extend(store.take());
+        let msgs = resumed.resume_messages(8);
+        assert_eq!(msgs.len(), 2);
+        // the PUBREL of 7 first, then 3 again with DUP
+        assert!(msgs[0].0 == 7 && msgs[0].1.is_none());
+        assert!(msgs[1].0 == 3 && msgs[1].1.as_ref().is_some_and(|m| m.dup));
+
+        // identifiers in flight are not given to new messages
+        assert_eq!(resumed.next_packet_id(2), 4);
+        assert_eq!(resumed.next_packet_id(6), 8);
+
+        // the queued message takes the identifier of the completed one, DUP only once resent
+        resumed.inflight_cmp(7);
+        let msgs = resumed.get_inflight_messages(10, 5);
+        assert_eq!(msgs.len(), 1);
+        let msg = msgs[0].1.as_ref().unwrap();
+        assert_eq!((msgs[0].0, msg.packet_id, msg.dup), (7, Some(7), false));
+        let msgs = resumed.resume_messages(20);
+        assert_eq!(msgs.iter().map(|m| m.0).collect::<Vec<_>>(), [3, 7]);
+        assert!(msgs.iter().all(|m| m.1.as_ref().unwrap().dup));
+
+        resumed.inflight_ack(3);
+        resumed.inflight_cmp(7);
+        assert_eq!(resumed.inflight_size(), 0);
+    }
+
+    #[test]
+    fn test_info() {
+        let mut store = Store::new(2, 10);
+        for pkid in [7, 3, 9] {
+            store.inflight_insert(outgoing(pkid));
+        }
+        store.inflight_rec(7);
+        assert!(store.qos2_insert(publish(5)));
+
+        let info = store.info(u64::MAX);
+        assert_eq!((info.queued_msgs, info.queued_bytes), (1, 7));
+        let inflight: Vec<(u16, bool)> = info
+            .inflight
+            .iter()
+            .map(|i| (i.packet_id, i.released))
+            .collect();
+        assert_eq!(inflight, [(7, true), (3, false)]);
+        assert!(info.inflight[0].topic.is_none());
+        assert_eq!(info.pubrel_pending, [5]);
+
+        // the inflight messages stay
+        assert_eq!(store.purge(), 1);
+        assert_eq!(store.info(0).queued_msgs, 0);
+        assert_eq!(store.inflight_size(), 2);
+    }
+
+    #[test]
+    fn test_qos2_exactly_once() {
+        let mut store = Store::new(10, 10);
+        assert!(store.qos2_insert(publish(5)));
+        assert!(store.qos2_contains(5));
+
+        // the client reconnects before PUBREL, retransmits the PUBLISH then releases it
+        let mut resumed = Store::new(10, 10);
+        resumed.extend(store.take());
+        assert!(resumed.qos2_contains(5));
+        assert!(resumed.qos2_insert(publish(5)));
+        assert!(resumed.qos2_rel(5).is_some());
+        // a retransmitted PUBREL does not deliver it twice
+        assert!(resumed.qos2_rel(5).is_none());
+        assert!(!resumed.qos2_contains(5));
+    }
+}
@@ -1,23 +1,36 @@
 use std::fs::File;
 use std::io::BufReader;
 use std::sync::Arc;
+use std::time::SystemTime;
 
+use rustls::{
+    RootCertStore,
+    pki_types::CertificateDer,
+    server::{Acceptor, WebPkiClientVerifier},
+};
 use rustls_pemfile::{certs, pkcs8_private_keys};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
-use tracing::{debug, error, info};
+use tokio::task::JoinHandle;
+use tokio_rustls::{LazyConfigAcceptor, rustls::ServerConfig, server::TlsStream};
+use tracing::{debug, error, info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
 
+use crate::mqtt::ban::{self, BanTarget, Offense};
 use crate::mqtt::helper::BrokerHelper;
 use crate::operator::helper::Helper as OperatorHelper;
 
+use super::admission::Admission;
+use super::proxy;
 use super::shared::process_client;
 
 pub fn spawn_tcp_listener(
     host: String,
     port: u16,
+    mut admission: Admission,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
-) {
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let addr = format!("{}:{}", host, port);
         let listener = match TcpListener::bind(&addr).await {
@@ -30,26 +43,160 @@ pub fn spawn_tcp_listener(
         info!("MQTT TCP listening on {}", addr);
 
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
+            let (mut stream, addr) = listener.accept().await.unwrap();
+            let Some(ticket) = admission.admit(coarsetime::Clock::now_since_epoch().as_secs())
+            else {
+                debug!("connection rate exceeded, closing {}", addr);
+                continue;
+            };
+            let proxy_protocol = admission.proxy_protocol();
             let broker_helper = broker_helper.clone();
             let operator_helper = operator_helper.clone();
-            tokio::spawn(process_client(stream, addr, broker_helper, operator_helper));
+            tokio::spawn(async move {
+                let addr = match proxy::client_addr(&mut stream, addr, proxy_protocol).await {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        debug!("PROXY header error from {}: {}", addr, e);
+                        return;
+                    }
+                };
+                if !ticket.allows(addr.ip()) {
+                    debug!("connection from {} denied", addr);
+                    return;
+                }
+                process_client(
+                    stream,
+                    addr,
+                    TlsInfo::default(),
+                    false,
+                    ticket,
+                    broker_helper,
+                    operator_helper,
+                )
+                .await;
+            });
+        }
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert_path: String,
+    pub key_path: String,
+    pub ca_path: Option<String>,
+    pub require_client_cert: bool,
+    pub cert_as_client_id: bool,
+    // seconds between checks of the certificate files for a renewal, 0 never checks
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerCertificate {
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+}
+
+impl PeerCertificate {
+    pub fn from_der(der: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der.as_ref()).ok()?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_string());
+
+        let mut subject_alt_names = vec![];
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                match name {
+                    GeneralName::DNSName(v) | GeneralName::RFC822Name(v) | GeneralName::URI(v) => {
+                        subject_alt_names.push(v.to_string())
+                    }
+                    _ => {}
+                }
+            }
         }
-    });
+
+        Some(PeerCertificate {
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    /// identity used as client id, CN first, then the first SAN entry
+    pub fn identity(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or_else(|| self.subject_alt_names.first().map(|s| s.as_str()))
+    }
+}
+
+impl std::fmt::Display for PeerCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{ cn: {}, san: [{}] }}",
+            self.common_name.as_deref().unwrap_or("-"),
+            self.subject_alt_names.join(", ")
+        )
+    }
+}
+
+/// what the TLS handshake tells about the client
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    // SNI sent in ClientHello, multi-tenant deployments select the tenant with it
+    pub server_name: Option<String>,
+    pub peer_cert: Option<PeerCertificate>,
+}
+
+pub fn peer_certificate<S>(tls_stream: &TlsStream<S>) -> Option<PeerCertificate> {
+    tls_stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(PeerCertificate::from_der)
+}
+
+/// accept a TLS connection, reading ClientHello first to capture SNI
+pub async fn accept_tls<S>(
+    config: Arc<ServerConfig>,
+    stream: S,
+) -> std::io::Result<(TlsStream<S>, TlsInfo)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+    let server_name = start
+        .client_hello()
+        .server_name()
+        .map(|name| name.to_string());
+    let tls_stream = start.into_stream(config).await?;
+    let peer_cert = peer_certificate(&tls_stream);
+    Ok((
+        tls_stream,
+        TlsInfo {
+            server_name,
+            peer_cert,
+        },
+    ))
 }
 
 pub fn spawn_tls_listener(
     host: String,
     port: u16,
-    cert_path: String,
-    key_path: String,
+    tls_options: TlsOptions,
+    mut admission: Admission,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
-) {
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let addr = format!("{}:{}", host, port);
-        let tls_acceptor = match load_tls_acceptor(&cert_path, &key_path) {
-            Ok(acceptor) => acceptor,
+        let mut tls_config = match TlsReloader::new(tls_options.clone()) {
+            Ok(reloader) => reloader,
             Err(e) => {
                 error!("failed to load TCP/TLS config for {}: {}", addr, e);
                 return;
@@ -63,38 +210,152 @@ pub fn spawn_tls_listener(
                 return;
             }
         };
-        info!("MQTT TCP/TLS listening on {}", addr);
+        info!(
+            "MQTT TCP/TLS listening on {}, client certificate: {}",
+            addr,
+            client_auth_mode(&tls_options)
+        );
+        let cert_as_client_id = tls_options.cert_as_client_id;
 
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
-            let acceptor = tls_acceptor.clone();
+            let (mut stream, addr) = listener.accept().await.unwrap();
+            let Some(ticket) = admission.admit(coarsetime::Clock::now_since_epoch().as_secs())
+            else {
+                debug!("connection rate exceeded, closing {}", addr);
+                continue;
+            };
+            let proxy_protocol = admission.proxy_protocol();
+            let tls_config = tls_config.config(coarsetime::Clock::now_since_epoch().as_secs());
             let broker_helper = broker_helper.clone();
             let operator_helper = operator_helper.clone();
 
             tokio::spawn(async move {
-                match acceptor.accept(stream).await {
-                    Ok(tls_stream) => {
-                        process_client(tls_stream, addr, broker_helper, operator_helper).await;
+                let addr = match proxy::client_addr(&mut stream, addr, proxy_protocol).await {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        debug!("PROXY header error from {}: {}", addr, e);
+                        return;
+                    }
+                };
+                if !ticket.allows(addr.ip()) {
+                    debug!("connection from {} denied", addr);
+                    return;
+                }
+                match accept_tls(tls_config, stream).await {
+                    Ok((tls_stream, tls_info)) => {
+                        process_client(
+                            tls_stream,
+                            addr,
+                            tls_info,
+                            cert_as_client_id,
+                            ticket,
+                            broker_helper,
+                            operator_helper,
+                        )
+                        .await;
                     }
                     Err(e) => {
-                        debug!("TLS handshake error from {}: {}", addr, e);
+                        info!(
+                            target: "axonmq::audit",
+                            event = "tls_handshake_failed",
+                            address = %addr,
+                            error = %e,
+                            "TLS handshake error"
+                        );
+                        ban::offense(Offense::AuthFailure, BanTarget::ip(addr.ip()));
                     }
                 }
             });
         }
-    });
+    })
+}
+
+pub fn client_auth_mode(tls_options: &TlsOptions) -> &'static str {
+    match (&tls_options.ca_path, tls_options.require_client_cert) {
+        (None, _) => "disabled",
+        (Some(_), false) => "optional",
+        (Some(_), true) => "required",
+    }
 }
 
-pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> std::io::Result<TlsAcceptor> {
-    let certs_file =
-        File::open(cert_path).map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+/// server config of a TLS listener, loaded again when its certificate, key or CA files change,
+/// connections already open keep the config they were accepted with
+pub struct TlsReloader {
+    options: TlsOptions,
+    config: Arc<ServerConfig>,
+    modified: Vec<Option<SystemTime>>,
+    checked_at: u64,
+}
+
+impl TlsReloader {
+    pub fn new(options: TlsOptions) -> std::io::Result<Self> {
+        let modified = Self::modified(&options);
+        let config = load_tls_config(&options)?;
+        Ok(TlsReloader {
+            options,
+            config,
+            modified,
+            checked_at: coarsetime::Clock::now_since_epoch().as_secs(),
+        })
+    }
+
+    fn modified(options: &TlsOptions) -> Vec<Option<SystemTime>> {
+        [
+            Some(&options.cert_path),
+            Some(&options.key_path),
+            options.ca_path.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+    }
+
+    /// the config for a new connection, the files are checked at most once per reload_interval
+    pub fn config(&mut self, now: u64) -> Arc<ServerConfig> {
+        let interval = self.options.reload_interval;
+        if interval > 0 && now >= self.checked_at + interval {
+            self.checked_at = now;
+            let modified = Self::modified(&self.options);
+            if modified != self.modified {
+                // a renewal caught half written fails to load, it is tried again next check
+                match load_tls_config(&self.options) {
+                    Ok(config) => {
+                        info!(
+                            target: "axonmq::audit",
+                            event = "config_reload",
+                            reloaded = true,
+                            path = %self.options.cert_path,
+                            "TLS certificate reloaded"
+                        );
+                        self.config = config;
+                        self.modified = modified;
+                    }
+                    Err(e) => warn!(
+                        target: "axonmq::audit",
+                        event = "config_reload",
+                        reloaded = false,
+                        path = %self.options.cert_path,
+                        error = %e,
+                        "failed to reload TLS certificate, keeping the current one"
+                    ),
+                }
+            }
+        }
+        self.config.clone()
+    }
+}
+
+pub fn load_tls_config(tls_options: &TlsOptions) -> std::io::Result<Arc<ServerConfig>> {
+    let certs_file = File::open(&tls_options.cert_path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
     let mut certs_reader = BufReader::new(certs_file);
     let certs = certs(&mut certs_reader)
         .collect::<Result<Vec<_>, _>>()
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
-    let key_file =
-        File::open(key_path).map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+    let key_file = File::open(&tls_options.key_path)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
     let mut key_reader = BufReader::new(key_file);
 
     let key = pkcs8_private_keys(&mut key_reader).next().ok_or_else(|| {
@@ -104,10 +365,37 @@ pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> std::io::Result<Tls
         )
     })??;
 
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
+    let builder = ServerConfig::builder();
+    let builder = if let Some(ca_path) = &tls_options.ca_path {
+        let ca_file = File::open(ca_path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::NotFound, e))?;
+        let mut ca_reader = BufReader::new(ca_file);
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut ca_reader) {
+            let cert =
+                cert.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            roots
+                .add(cert)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        }
+
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+        let verifier = if tls_options.require_client_cert {
+            verifier
+        } else {
+            verifier.allow_unauthenticated()
+        };
+        let verifier = verifier
+            .build()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let config = builder
         .with_single_cert(certs, rustls::pki_types::PrivateKeyDer::Pkcs8(key))
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-    Ok(TlsAcceptor::from(Arc::new(config)))
+    Ok(Arc::new(config))
 }
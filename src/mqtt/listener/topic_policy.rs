@@ -0,0 +1,101 @@
+//! the `[[topic_policy]]` limits checked on every PUBLISH of a client
+
+use crate::CONFIG;
+use crate::config::topic_policy::TopicPolicy;
+use crate::mqtt::{code::ReturnCode, protocol::publish::Publish};
+use crate::operator::utils::topic_match;
+
+fn violation(policy: &TopicPolicy, publish: &Publish) -> Option<ReturnCode> {
+    if !topic_match(&policy.topic, &publish.topic) {
+        return None;
+    }
+    if policy
+        .max_payload_size
+        .is_some_and(|max| publish.payload.len() > max)
+    {
+        return Some(ReturnCode::QuotaExceeded);
+    }
+    if policy.max_qos.is_some_and(|max| publish.qos as u8 > max)
+        || policy.no_retain && publish.retain
+    {
+        return Some(ReturnCode::NotAuthorizedV5);
+    }
+    None
+}
+
+fn check_with(policies: &[TopicPolicy], publish: &Publish) -> Option<ReturnCode> {
+    policies
+        .iter()
+        .find_map(|policy| violation(policy, publish))
+}
+
+/// the reason code to refuse a message with, Quota Exceeded for a payload over the size and
+/// Not Authorized for a QoS over the maximum or a retained message where retain is refused
+pub fn check(publish: &Publish) -> Option<ReturnCode> {
+    check_with(&CONFIG.get()?.topic_policy, publish)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::mqtt::QoS;
+
+    fn publish(topic: &str, qos: QoS, retain: bool, payload: &'static [u8]) -> Publish {
+        Publish::new(
+            false,
+            qos,
+            retain,
+            topic.to_string(),
+            None,
+            Bytes::from_static(payload),
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_check() {
+        let policies = vec![
+            TopicPolicy {
+                topic: "telemetry/#".to_string(),
+                max_payload_size: Some(4),
+                max_qos: Some(1),
+                no_retain: true,
+            },
+            TopicPolicy {
+                topic: "telemetry/+/alarm".to_string(),
+                max_payload_size: None,
+                max_qos: Some(0),
+                no_retain: false,
+            },
+        ];
+        let check = |p: &Publish| check_with(&policies, p);
+
+        assert_eq!(
+            check(&publish("telemetry/a", QoS::AtLeastOnce, false, b"1234")),
+            None
+        );
+        assert_eq!(
+            check(&publish("telemetry/a", QoS::AtMostOnce, false, b"12345")),
+            Some(ReturnCode::QuotaExceeded)
+        );
+        assert_eq!(
+            check(&publish("telemetry/a", QoS::ExactlyOnce, false, b"")),
+            Some(ReturnCode::NotAuthorizedV5)
+        );
+        assert_eq!(
+            check(&publish("telemetry/a", QoS::AtMostOnce, true, b"")),
+            Some(ReturnCode::NotAuthorizedV5)
+        );
+        // both policies apply
+        assert_eq!(
+            check(&publish("telemetry/a/alarm", QoS::AtLeastOnce, false, b"")),
+            Some(ReturnCode::NotAuthorizedV5)
+        );
+        assert_eq!(
+            check(&publish("other/a", QoS::ExactlyOnce, true, b"12345")),
+            None
+        );
+    }
+}
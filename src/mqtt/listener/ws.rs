@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
 
 use bytes::BytesMut;
 use coarsetime;
-use futures_util::{SinkExt, stream::StreamExt as _};
+use futures_util::{SinkExt, stream::SplitSink, stream::StreamExt as _};
 use http::StatusCode;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
 use tokio::time;
+use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::{
     Message as WsMessage,
     handshake::server::{Request, Response},
@@ -16,30 +18,44 @@ use tokio_tungstenite::tungstenite::{
 use tracing::{Instrument, debug, error, info, warn};
 
 use crate::CONFIG;
+use crate::config::SlowConsumerPolicy;
 use crate::mqtt::{
     MqttProtocolVersion, QoS,
+    ban::{self, BanTarget, Offense},
     code::ReturnCode,
     command::ClientCommand,
     error::MqttProtocolError,
+    group::PublishRateLimiter,
     helper::BrokerHelper,
     protocol::{codec::MessageCodec, conn::Disconnect, message::Message, publish},
+    slow_consumer, utils,
 };
 use crate::operator::helper::Helper as OperatorHelper;
 use crate::utils as g_utils;
 
-use super::shared::{get_packet_id, handle_message};
+use super::admission::{Admission, Ticket};
+use super::proxy;
+use super::quota::{self, ClientQuota};
+use super::shared::{
+    ClientState, apply_tls_info, busy_ack, check_publish_rate, disconnect_notice, handle_message,
+    next_sink_ack, quota_exceeded_ack, rate_offense, sink_ack, unsupported_version_ack,
+};
+use super::spill::SpillOptions;
 use super::store::Store;
-use super::tcp::load_tls_acceptor;
+use super::tcp::{TlsInfo, TlsOptions, TlsReloader, accept_tls, client_auth_mode};
 
 use tokio_util::codec::{Decoder, Encoder};
 
+const DEFAULT_WS_SEND_QUEUE: usize = 256;
+
 pub fn spawn_ws_listener(
     host: String,
     port: u16,
     path: String,
+    mut admission: Admission,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
-) {
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let addr = format!("{}:{}", host, port);
         let listener = match TcpListener::bind(&addr).await {
@@ -52,7 +68,13 @@ pub fn spawn_ws_listener(
         info!("MQTT WebSocket listening on {}", addr);
 
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
+            let (mut stream, addr) = listener.accept().await.unwrap();
+            let Some(ticket) = admission.admit(coarsetime::Clock::now_since_epoch().as_secs())
+            else {
+                debug!("connection rate exceeded, closing {}", addr);
+                continue;
+            };
+            let proxy_protocol = admission.proxy_protocol();
             let broker_helper = broker_helper.clone();
             let operator_helper = operator_helper.clone();
             let path = path.clone();
@@ -87,11 +109,25 @@ pub fn spawn_ws_listener(
             };
 
             tokio::spawn(async move {
+                let addr = match proxy::client_addr(&mut stream, addr, proxy_protocol).await {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        debug!("PROXY header error from {}: {}", addr, e);
+                        return;
+                    }
+                };
+                if !ticket.allows(addr.ip()) {
+                    debug!("connection from {} denied", addr);
+                    return;
+                }
                 match tokio_tungstenite::accept_hdr_async(stream, callback).await {
                     Ok(ws_stream) => {
                         handle_websocket_connection(
                             ws_stream,
                             addr,
+                            TlsInfo::default(),
+                            false,
+                            ticket,
                             broker_helper,
                             operator_helper,
                         )
@@ -103,22 +139,22 @@ pub fn spawn_ws_listener(
                 }
             });
         }
-    });
+    })
 }
 
 pub fn spawn_wss_listener(
     host: String,
     port: u16,
     path: String,
-    cert_path: String,
-    key_path: String,
+    tls_options: TlsOptions,
+    mut admission: Admission,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
-) {
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let addr = format!("{}:{}", host, port);
-        let tls_acceptor = match load_tls_acceptor(&cert_path, &key_path) {
-            Ok(acceptor) => acceptor,
+        let mut tls_config = match TlsReloader::new(tls_options.clone()) {
+            Ok(reloader) => reloader,
             Err(e) => {
                 error!("failed to load TLS config for {}: {}", addr, e);
                 return;
@@ -132,11 +168,22 @@ pub fn spawn_wss_listener(
                 return;
             }
         };
-        info!("MQTT Secure WebSocket listening on {}", addr);
+        info!(
+            "MQTT Secure WebSocket listening on {}, client certificate: {}",
+            addr,
+            client_auth_mode(&tls_options)
+        );
+        let cert_as_client_id = tls_options.cert_as_client_id;
 
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
-            let acceptor = tls_acceptor.clone();
+            let (mut stream, addr) = listener.accept().await.unwrap();
+            let tls_config = tls_config.config(coarsetime::Clock::now_since_epoch().as_secs());
+            let Some(ticket) = admission.admit(coarsetime::Clock::now_since_epoch().as_secs())
+            else {
+                debug!("connection rate exceeded, closing {}", addr);
+                continue;
+            };
+            let proxy_protocol = admission.proxy_protocol();
             let broker_helper = broker_helper.clone();
             let operator_helper = operator_helper.clone();
             let path = path.clone();
@@ -169,13 +216,27 @@ pub fn spawn_wss_listener(
             };
 
             tokio::spawn(async move {
-                match acceptor.accept(stream).await {
-                    Ok(tls_stream) => {
+                let addr = match proxy::client_addr(&mut stream, addr, proxy_protocol).await {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        debug!("PROXY header error from {}: {}", addr, e);
+                        return;
+                    }
+                };
+                if !ticket.allows(addr.ip()) {
+                    debug!("connection from {} denied", addr);
+                    return;
+                }
+                match accept_tls(tls_config, stream).await {
+                    Ok((tls_stream, tls_info)) => {
                         match tokio_tungstenite::accept_hdr_async(tls_stream, callback).await {
                             Ok(ws_stream) => {
                                 handle_websocket_connection(
                                     ws_stream,
                                     addr,
+                                    tls_info,
+                                    cert_as_client_id,
+                                    ticket,
                                     broker_helper,
                                     operator_helper,
                                 )
@@ -187,17 +248,27 @@ pub fn spawn_wss_listener(
                         }
                     }
                     Err(e) => {
-                        debug!("TLS handshake error from {}: {}", addr, e);
+                        info!(
+                            target: "axonmq::audit",
+                            event = "tls_handshake_failed",
+                            address = %addr,
+                            error = %e,
+                            "TLS handshake error"
+                        );
+                        ban::offense(Offense::AuthFailure, BanTarget::ip(addr.ip()));
                     }
                 }
             });
         }
-    });
+    })
 }
 
 async fn handle_websocket_connection<S>(
     mut ws_stream: tokio_tungstenite::WebSocketStream<S>,
     addr: SocketAddr,
+    tls_info: TlsInfo,
+    cert_as_client_id: bool,
+    ticket: Ticket,
     broker_helper: BrokerHelper,
     operator_helper: OperatorHelper,
 ) where
@@ -205,6 +276,7 @@ async fn handle_websocket_connection<S>(
 {
     let mut span = tracing::info_span!("client", %addr, protocol = "ws");
     let mut codec = MessageCodec::default();
+    codec.with_mountpoint(ticket.mountpoint.clone());
     let mut read_buf = BytesMut::new();
 
     let mut resend_tk = time::interval(time::Duration::from_secs(
@@ -213,14 +285,19 @@ async fn handle_websocket_connection<S>(
     resend_tk.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
     let mut version = MqttProtocolVersion::V3_1_1;
-    let mut keep_alive = CONFIG.get().unwrap().mqtt.settings.keep_alive;
+    let mut keep_alive = 0;
     let mut client_id = String::new();
     let mut client_rx = None;
     let mut inflight_maximum = 128u16;
     let mut pre_store = None;
 
     let mut client_topic_alias: HashMap<u16, String> = HashMap::new();
+    let mut rate_limiter = PublishRateLimiter::default();
+    let mut quota = ClientQuota::new(&ticket.client_limits);
+    let receive_maximum = quota::receive_maximum(&ticket.client_limits);
     let mut client_topic_alias_maximum: u16 = 0;
+    let mut tls_info = Some(tls_info);
+    let mut server_name = None;
 
     let result = time::timeout(time::Duration::from_secs(3), async {
         loop {
@@ -229,12 +306,34 @@ async fn handle_websocket_connection<S>(
                     read_buf.extend_from_slice(&data);
                     loop {
                         match codec.decode(&mut read_buf) {
-                            Ok(Some(Message::Connect(conn))) => {
+                            Ok(Some(Message::Connect(mut conn))) => {
+                                apply_tls_info(&mut conn, tls_info.take().unwrap_or_default(), cert_as_client_id);
+                                server_name = conn.server_name.clone();
                                 span = tracing::info_span!("client", %addr, client = %g_utils::TruncateDisplay::new(&conn.client_id, 24));
+                                let refused = if ticket.busy() {
+                                    debug!(parent: &span, "listener at max connections, connection rejected");
+                                    Some(busy_ack(conn.version))
+                                } else if !ticket.accepts(conn.version) {
+                                    debug!(parent: &span, "MQTT {} not accepted by the listener, connection rejected", conn.version);
+                                    Some(unsupported_version_ack(conn.version))
+                                } else {
+                                    None
+                                };
+                                if let Some(ack) = refused {
+                                    if conn.version == MqttProtocolVersion::V5 {
+                                        codec.with_v5();
+                                    }
+                                    utils::audit_connect(&conn.client_id, addr, conn.version, conn.peer_cert.as_ref(), ack.return_code);
+                                    let mut write_buf = BytesMut::new();
+                                    codec.encode(Message::ConnAck(ack), &mut write_buf).unwrap();
+                                    ws_stream.send(WsMessage::Binary(write_buf.freeze())).await.ok();
+                                    ws_stream.close(None).await.ok();
+                                    return Err(());
+                                }
                                 let (client_tx, c_rx) = mpsc::channel::<ClientCommand>(128);
                                 client_rx = Some(c_rx);
 
-                                if let Ok((ack, old_store)) = broker_helper.connect(conn.clone(), client_tx).await {
+                                if let Ok((mut ack, old_store)) = broker_helper.connect(conn.clone(), addr, client_tx).await {
                                     if ack.return_code != ReturnCode::Success {
                                         debug!(parent: &span, "connection rejected: {}", ack.return_code);
                                         let mut write_buf = BytesMut::new();
@@ -244,10 +343,9 @@ async fn handle_websocket_connection<S>(
                                         return Err(());
                                     }
                                     version = conn.version;
-                                    if conn.keep_alive > keep_alive {
-                                        keep_alive = conn.keep_alive;
-                                    }
-                                    client_id = conn.client_id.clone();
+                                    keep_alive = utils::keep_alive(conn.keep_alive, conn.version);
+                                    // a suffixed identifier when the one in CONNECT is taken
+                                    client_id = ack.assigned_client_id().unwrap_or(&conn.client_id).to_string();
                                     pre_store = old_store;
 
                                     if conn.version == MqttProtocolVersion::V5 {
@@ -256,6 +354,8 @@ async fn handle_websocket_connection<S>(
                                     }
                                     inflight_maximum = conn.options.inflight_maximum;
                                     codec.with_packet_size(conn.options.packet_maximum);
+                                    ack.options.receive_maximum = Some(receive_maximum);
+                                    ack.options.server_keep_alive = Some(keep_alive);
 
                                     let mut write_buf = BytesMut::new();
                                     codec.encode(Message::ConnAck(ack), &mut write_buf).unwrap();
@@ -266,6 +366,7 @@ async fn handle_websocket_connection<S>(
                             }
                             Ok(Some(_)) => {
                                 // Received a non-CONNECT message during handshake
+                                ban::offense(Offense::MalformedPacket, BanTarget::ip(addr.ip()));
                                 return Err(());
                             }
                             Ok(None) => {
@@ -274,6 +375,7 @@ async fn handle_websocket_connection<S>(
                             }
                             Err(_) => {
                                 debug!(parent: &span, "failed to decode CONNECT message during handshake");
+                                ban::offense(Offense::MalformedPacket, BanTarget::ip(addr.ip()));
                                 return Err(());
                             }
                         }
@@ -295,92 +397,146 @@ async fn handle_websocket_connection<S>(
         return;
     }
 
-    let mut message_store = Store::new(
-        inflight_maximum as usize,
-        CONFIG.get().unwrap().mqtt.settings.max_receive_queue as usize,
-    );
+    let mut message_store = Store::new(inflight_maximum as usize, receive_maximum as usize)
+        .with_spill(SpillOptions::from_settings(
+            &CONFIG.get().unwrap().mqtt.settings,
+        ));
     if let Some(pre_store) = pre_store {
         message_store.extend(pre_store);
     }
 
+    let (ws_sink, mut ws_stream) = ws_stream.split();
+    let outbound = WsOutbound::spawn(ws_sink);
+
+    // a resumed session continues where it stopped, unacknowledged PUBLISH and PUBREL first
+    let now = coarsetime::Clock::now_since_epoch().as_secs();
+    for (pkid, msg) in message_store.resume_messages(now) {
+        let msg = match msg {
+            Some(msg) => Message::SharedPublish(msg),
+            None => Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success)),
+        };
+        outbound.send(&mut codec, msg).await;
+    }
+
     let mut packet_id = 1;
+    let mut pending_acks = VecDeque::new();
     let mut client_rx = client_rx.unwrap();
     let resend_time = CONFIG.get().unwrap().mqtt.settings.resend_interval;
     let mut client_msg_tm = coarsetime::Clock::now_since_epoch().as_secs();
-    let mut keepalive_tk = time::interval(time::Duration::from_secs(keep_alive as u64));
+    // a keep alive of 0 is never checked
+    let mut keepalive_tk = time::interval(time::Duration::from_secs(keep_alive.max(1) as u64));
     keepalive_tk.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
 
     loop {
         tokio::select! {
-            _ = keepalive_tk.tick(), if coarsetime::Clock::now_since_epoch().as_secs() - client_msg_tm > (keep_alive * 3 / 2) as u64 => {
+            _ = keepalive_tk.tick(), if keep_alive > 0 && coarsetime::Clock::now_since_epoch().as_secs() - client_msg_tm > keep_alive as u64 * 3 / 2 => {
                 warn!(parent: &span, "keep alive timeout, disconnecting");
                 broker_helper.disconnected(client_id.as_str(), ReturnCode::KeepAliveTimeout, None, message_store).await.ok();
-                ws_stream.close(None).await.ok();
+                outbound.close().await;
+                break;
+            }
+            _ = outbound.closed() => {
+                debug!(parent: &span, "websocket write failed, disconnecting");
+                broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store).await.ok();
                 break;
             }
             _ = resend_tk.tick(), if message_store.inflight_size() > 0 => {
                 let now = coarsetime::Clock::now_since_epoch().as_secs();
                 for (pkid, msg) in message_store.get_inflight_messages(now, resend_time).into_iter() {
                     if let Some(msg) = msg {
-                        let mut msg = Message::Publish(msg);
-                        msg.with_dup();
-                        let mut write_buf = BytesMut::new();
-                        codec.encode(msg.clone(), &mut write_buf).unwrap();
-                        let _ = ws_stream.send(WsMessage::Binary(write_buf.freeze())).await;
+                        outbound.send(&mut codec, Message::SharedPublish(msg)).await;
                     } else {
-                        let mut write_buf = BytesMut::new();
-                        codec.encode(Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success)), &mut write_buf).unwrap();
-                        let _ = ws_stream.send(WsMessage::Binary(write_buf.freeze())).await;
+                        outbound.send(&mut codec, Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success))).await;
                     }
                 }
             }
+            took = next_sink_ack(&mut pending_acks), if !pending_acks.is_empty() => {
+                let (pkid, _) = pending_acks.pop_front().unwrap();
+                if let Some(ack) = sink_ack(version, pkid, took) {
+                    outbound.send(&mut codec, ack).await;
+                } else {
+                    warn!(parent: &span, "a chain with sink_ack failed on a message, disconnecting");
+                    broker_helper.disconnected(client_id.as_str(), ReturnCode::ImSpecificError, None, message_store).await.ok();
+                    outbound.close().await;
+                    break;
+                }
+            }
             Some(command) = client_rx.recv() => {
                 match command {
+                    ClientCommand::PublishRate(rate) => {
+                        rate_limiter.set_rate(rate);
+                    }
+                    ClientCommand::Inspect(resp) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        resp.try_send((message_store.info(now), client_msg_tm)).ok();
+                    }
+                    ClientCommand::PurgeQueue(resp) => {
+                        resp.try_send(message_store.purge()).ok();
+                    }
+                    ClientCommand::Resend(resp) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        let msgs = message_store.resume_messages(now);
+                        resp.try_send(msgs.len()).ok();
+                        for (pkid, msg) in msgs {
+                            let msg = match msg {
+                                Some(msg) => Message::SharedPublish(msg),
+                                None => Message::PubRel(publish::PubRel::new(pkid, ReturnCode::Success)),
+                            };
+                            outbound.send(&mut codec, msg).await;
+                        }
+                    }
                     ClientCommand::Disconnect(code) => {
-                        let mut write_buf = BytesMut::new();
-                        codec.encode(Message::Disconnect(Disconnect::new(code)), &mut write_buf).unwrap();
-                        ws_stream.send(WsMessage::Binary(write_buf.freeze())).await.ok();
-                        ws_stream.close(None).await.ok();
+                        // reported before the client sees the DISCONNECT and can come back
+                        broker_helper.disconnected(client_id.as_str(), code, None, message_store).await.ok();
+                        outbound.send(&mut codec, Message::Disconnect(Disconnect::new(code))).await;
+                        outbound.close().await;
                         break;
                     }
-                    ClientCommand::Publish{qos, retain, topic, payload, user_properties, options}=> {
-                        if let Some(expiry_at) = options.message_expiry_at{
-                            if expiry_at <= coarsetime::Clock::now_since_epoch().as_secs() {
-                                continue;
-                            }
+                    ClientCommand::TakenOver => {
+                        outbound.send(&mut codec, Message::Disconnect(Disconnect::new(ReturnCode::SessionTakenOver))).await;
+                        outbound.close().await;
+                        break;
+                    }
+                    ClientCommand::Publish(mut publish) => {
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        if publish.expired(now) {
+                            continue;
                         }
-                        let pid = if qos != QoS::AtMostOnce {
-                            packet_id = get_packet_id(packet_id);
-                            Some(packet_id)
-                        } else {
-                            None
-                        };
-                        let publish = publish::Publish::new(
-                            false,
-                            qos,
-                            retain,
-                            topic.clone(),
-                            pid,
-                            payload.clone(),
-                            user_properties.clone(),
-                        );
-                        if qos != QoS::AtMostOnce {
-                            if message_store.inflight_insert(publish.clone()) {
-                                let msg = Message::Publish(publish);
-                                let mut write_buf = BytesMut::new();
-                                codec.encode(msg, &mut write_buf).unwrap();
-                                let _ = ws_stream.send(WsMessage::Binary(write_buf.freeze())).await;
+                        if publish.qos != QoS::AtMostOnce {
+                            packet_id = message_store.next_packet_id(packet_id);
+                            publish.packet_id = Some(packet_id);
+                        }
+                        let publish = publish.with_remaining_expiry(now);
+                        if publish.qos != QoS::AtMostOnce && !message_store.inflight_insert(publish.clone()) {
+                            continue;
+                        }
+                        // QoS 1/2 messages are kept in flight, a dropped send is resent later
+                        if !outbound.try_send(&mut codec, Message::SharedPublish(publish)) {
+                            slow_consumer::record_event();
+                            match CONFIG.get().unwrap().mqtt.settings.slow_consumer_policy.unwrap_or_default() {
+                                SlowConsumerPolicy::Drop | SlowConsumerPolicy::Buffer => {
+                                    debug!(parent: &span, "outbound queue full, dropping message");
+                                    slow_consumer::record_dropped();
+                                }
+                                SlowConsumerPolicy::Disconnect => {
+                                    slow_consumer::record_dropped();
+                                    slow_consumer::record_disconnect();
+                                    warn!(parent: &span, "outbound queue full, disconnecting slow client");
+                                    broker_helper.disconnected(client_id.as_str(), ReturnCode::QuotaExceeded, None, message_store).await.ok();
+                                    outbound.close().await;
+                                    break;
+                                }
                             }
-                        } else {
-                            let msg = Message::Publish(publish);
-                            let mut write_buf = BytesMut::new();
-                            codec.encode(msg, &mut write_buf).unwrap();
-                            let _ = ws_stream.send(WsMessage::Binary(write_buf.freeze())).await;
                         }
                     }
                 }
             }
-            Some(Ok(ws_msg)) = ws_stream.next() => {
+            ws_msg = ws_stream.next() => {
+                let Some(Ok(ws_msg)) = ws_msg else {
+                    debug!(parent: &span, "disconnected");
+                    broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store).await.ok();
+                    break;
+                };
                 match ws_msg {
                     WsMessage::Binary(data) => {
                         read_buf.extend_from_slice(&data);
@@ -389,32 +545,71 @@ async fn handle_websocket_connection<S>(
                                 Ok(Some(msg)) => {
                                     if let Message::PacketTooLarge = msg {
                                         debug!(parent: &span, "packet too large, disconnecting");
-                                        let mut write_buf = BytesMut::new();
-                                        codec.encode(Message::Disconnect(Disconnect::new(ReturnCode::PacketTooLarge)), &mut write_buf).unwrap();
-                                        ws_stream.send(WsMessage::Binary(write_buf.freeze())).await.ok();
-                                        broker_helper.disconnected(client_id.as_str(), ReturnCode::PacketTooLarge, None, message_store.clone()).await.ok();
-                                        ws_stream.close(None).await.ok();
+                                        ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
+                                        outbound.send(&mut codec, Message::Disconnect(Disconnect::new(ReturnCode::PacketTooLarge))).await;
+                                        broker_helper.disconnected(client_id.as_str(), ReturnCode::PacketTooLarge, None, message_store.take()).await.ok();
+                                        outbound.close().await;
                                         break;
                                     }
 
+                                    #[cfg(feature = "chaos")]
+                                    match crate::chaos::listener(client_id.as_str()).await {
+                                        crate::chaos::Fault::Pass => {}
+                                        crate::chaos::Fault::Drop => continue,
+                                        crate::chaos::Fault::Disconnect => {
+                                            debug!(parent: &span, "chaos disconnect");
+                                            broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store.take()).await.ok();
+                                            outbound.close().await;
+                                            break;
+                                        }
+                                    }
+
                                     client_msg_tm = coarsetime::Clock::now_since_epoch().as_secs();
+                                    if !check_publish_rate(&mut rate_limiter, client_msg_tm, &msg) {
+                                        debug!(parent: &span, "publish rate exceeded, message dropped");
+                                        if let Some(e) = rate_offense(client_id.as_str()) {
+                                            warn!(parent: &span, "banned for exceeding the publish rate, disconnecting");
+                                            if let Some(notice) = disconnect_notice(version, &e) {
+                                                outbound.send(&mut codec, notice).await;
+                                            }
+                                            broker_helper.disconnected(client_id.as_str(), ReturnCode::QuotaExceeded, None, message_store.take()).await.ok();
+                                            outbound.close().await;
+                                            break;
+                                        }
+                                        if let Some(resp) = quota_exceeded_ack(&msg) {
+                                            outbound.send(&mut codec, resp).await;
+                                        }
+                                        continue;
+                                    }
 
-                                    let result = handle_message(broker_helper.clone(), operator_helper.clone(), &mut message_store, &mut client_topic_alias, client_topic_alias_maximum, client_id.as_str(), msg).instrument(span.clone()).await;
+                                    let result = handle_message(broker_helper.clone(), operator_helper.clone(), ClientState {
+                                        client_id: client_id.as_str(),
+                                        server_name: server_name.as_deref(),
+                                        message_store: &mut message_store,
+                                        quota: &mut quota,
+                                        topic_alias: &mut client_topic_alias,
+                                        topic_alias_maximum: client_topic_alias_maximum,
+                                        pending_acks: &mut pending_acks,
+                                    }, msg).instrument(span.clone()).await;
                                     match result {
                                         Ok(Some(resp)) => {
-                                            let mut write_buf = BytesMut::new();
-                                            codec.encode(resp, &mut write_buf).unwrap();
-                                            let _ = ws_stream.send(WsMessage::Binary(write_buf.freeze())).await;
+                                            outbound.send(&mut codec, resp).await;
                                         }
                                         Ok(None) => {}
                                         Err(e) => {
                                             debug!(parent: &span, "error handling message: {}", e);
+                                            if e.malformed() {
+                                                ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
+                                            }
+                                            if let Some(notice) = disconnect_notice(version, &e) {
+                                                outbound.send(&mut codec, notice).await;
+                                            }
                                             if let MqttProtocolError::Disconnected(code, session_expiry_interval) = e {
-                                                broker_helper.disconnected(client_id.as_str(), code, session_expiry_interval, message_store.clone()).await.ok();
+                                                broker_helper.disconnected(client_id.as_str(), code, session_expiry_interval, message_store.take()).await.ok();
                                             } else {
-                                                broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store.clone()).await.ok();
+                                                broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store.take()).await.ok();
                                             }
-                                            ws_stream.close(None).await.ok();
+                                            outbound.close().await;
                                             break;
                                         }
                                     }
@@ -422,7 +617,8 @@ async fn handle_websocket_connection<S>(
                                 Ok(None) => break,
                                 Err(_) => {
                                     // Decode error
-                                    ws_stream.close(None).await.ok();
+                                    ban::offense(Offense::MalformedPacket, BanTarget::ClientId(client_id.clone()));
+                                    outbound.close().await;
                                     break;
                                 }
                             }
@@ -434,15 +630,90 @@ async fn handle_websocket_connection<S>(
                         break;
                     }
                     WsMessage::Ping(data) => {
-                        ws_stream.send(WsMessage::Pong(data)).await.ok();
+                        outbound.send_raw(WsMessage::Pong(data)).await;
                     }
                     _ => {}
                 }
             }
-            else => {
-                broker_helper.disconnected(client_id.as_str(), ReturnCode::UnspecifiedError, None, message_store).await.ok();
-                break;
+        }
+    }
+}
+
+/// Bounded outbound queue of a WebSocket client, drained by a writer task that
+/// flushes queued frames in batches, so a slow client never blocks the select loop.
+struct WsOutbound {
+    tx: mpsc::Sender<WsMessage>,
+}
+
+impl WsOutbound {
+    fn spawn<S>(mut sink: SplitSink<WebSocketStream<S>, WsMessage>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let size = CONFIG
+            .get()
+            .unwrap()
+            .mqtt
+            .settings
+            .ws_send_queue
+            .unwrap_or(DEFAULT_WS_SEND_QUEUE);
+        let (tx, mut rx) = mpsc::channel::<WsMessage>(size.max(1));
+
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let mut close = matches!(msg, WsMessage::Close(_));
+                if sink.feed(msg).await.is_err() {
+                    return;
+                }
+                while !close {
+                    match rx.try_recv() {
+                        Ok(msg) => {
+                            close = matches!(msg, WsMessage::Close(_));
+                            if sink.feed(msg).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if sink.flush().await.is_err() || close {
+                    break;
+                }
             }
+            sink.close().await.ok();
+        });
+
+        WsOutbound { tx }
+    }
+
+    fn encode(codec: &mut MessageCodec, msg: Message) -> WsMessage {
+        let mut write_buf = BytesMut::new();
+        codec.encode(msg, &mut write_buf).unwrap();
+        WsMessage::Binary(write_buf.freeze())
+    }
+
+    /// queue a message, waiting for room in the queue
+    async fn send(&self, codec: &mut MessageCodec, msg: Message) {
+        self.send_raw(Self::encode(codec, msg)).await;
+    }
+
+    async fn send_raw(&self, msg: WsMessage) {
+        let _ = self.tx.send(msg).await;
+    }
+
+    /// queue a message without waiting, false if the queue is full
+    fn try_send(&self, codec: &mut MessageCodec, msg: Message) -> bool {
+        match self.tx.try_send(Self::encode(codec, msg)) {
+            Ok(_) | Err(TrySendError::Closed(_)) => true,
+            Err(TrySendError::Full(_)) => false,
         }
     }
+
+    async fn close(&self) {
+        let _ = self.tx.send(WsMessage::Close(None)).await;
+    }
+
+    async fn closed(&self) {
+        self.tx.closed().await
+    }
 }
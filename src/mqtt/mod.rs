@@ -1,12 +1,19 @@
+pub mod ban;
 mod code;
 pub mod command;
-mod error;
+pub mod error;
+pub mod group;
 pub mod helper;
 pub mod listener;
+pub(crate) mod offline_queue;
 pub mod protocol;
-mod retain_trie;
+pub(crate) mod retain_store;
+pub(crate) mod retain_trie;
 pub mod server;
-mod utils;
+pub(crate) mod slow_consumer;
+pub(crate) mod utils;
+
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum QoS {
@@ -48,10 +55,14 @@ impl TryFrom<u8> for QoS {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// named as in `protocol_versions` of the listeners
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum MqttProtocolVersion {
+    #[serde(rename = "3.1")]
     V3 = 3,
+    #[serde(rename = "3.1.1")]
     V3_1_1 = 4,
+    #[serde(rename = "5.0", alias = "5")]
     V5 = 5,
 }
 
@@ -0,0 +1,341 @@
+//! messages kept for the offline clients of persistent sessions until they reconnect,
+//! `max_store_msgs_per_client`, `max_store_bytes_per_client` and `store_overflow_policy`
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::StoreOverflowPolicy;
+
+use super::command::ClientCommand;
+
+// messages dropped from the queues since the process started
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// messages which expired in the queues since the process started
+static EXPIRED: AtomicU64 = AtomicU64::new(0);
+
+/// messages dropped from offline queues by the overflow policy, ended sessions included
+pub fn dropped_total() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// messages removed from offline queues by the expiry sweep
+pub fn expired_total() -> u64 {
+    EXPIRED.load(Ordering::Relaxed)
+}
+
+/// what happened to a message pushed to a full queue
+#[derive(Debug, PartialEq)]
+pub enum Overflow {
+    // the message was queued, some or no older ones dropped
+    Stored,
+    // the message itself was dropped
+    Dropped,
+    // disconnect_notify, the session is to be ended
+    EndSession,
+}
+
+pub struct OfflineQueue {
+    msgs: VecDeque<ClientCommand>,
+    // payload bytes of msgs
+    bytes: usize,
+    dropped: u64,
+}
+
+fn payload_len(msg: &ClientCommand) -> usize {
+    match msg {
+        ClientCommand::Publish(publish) => publish.body.payload.len(),
+        _ => 0,
+    }
+}
+
+fn qos(msg: &ClientCommand) -> u8 {
+    match msg {
+        ClientCommand::Publish(publish) => publish.qos as u8,
+        _ => u8::MAX,
+    }
+}
+
+impl OfflineQueue {
+    pub fn new() -> Self {
+        OfflineQueue {
+            msgs: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// messages dropped from this queue by the overflow policy
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// `max_msgs` 0 and `max_bytes` None are unlimited
+    pub fn push(
+        &mut self,
+        msg: ClientCommand,
+        max_msgs: usize,
+        max_bytes: Option<usize>,
+        policy: StoreOverflowPolicy,
+    ) -> Overflow {
+        let size = payload_len(&msg);
+        let full = |msgs: usize, bytes: usize| {
+            (max_msgs > 0 && msgs >= max_msgs) || max_bytes.is_some_and(|max| bytes + size > max)
+        };
+        if !full(self.msgs.len(), self.bytes) {
+            self.bytes += size;
+            self.msgs.push_back(msg);
+            return Overflow::Stored;
+        }
+
+        // a payload larger than max_bytes never fits
+        if max_bytes.is_some_and(|max| size > max) {
+            self.drop_count(1);
+            return Overflow::Dropped;
+        }
+
+        match policy {
+            StoreOverflowPolicy::DropNewest => {
+                self.drop_count(1);
+                return Overflow::Dropped;
+            }
+            StoreOverflowPolicy::DisconnectNotify => {
+                self.drop_count(self.msgs.len() as u64 + 1);
+                self.msgs.clear();
+                self.bytes = 0;
+                return Overflow::EndSession;
+            }
+            StoreOverflowPolicy::DropOldest => {
+                while full(self.msgs.len(), self.bytes) {
+                    self.pop_at(0);
+                }
+            }
+            StoreOverflowPolicy::DropLowestQos => {
+                while full(self.msgs.len(), self.bytes) {
+                    // the oldest of the lowest QoS, the new message when its QoS is lower than all
+                    let lowest = self.msgs.iter().map(qos).min().unwrap_or(u8::MAX);
+                    if qos(&msg) < lowest {
+                        self.drop_count(1);
+                        return Overflow::Dropped;
+                    }
+                    let index = self.msgs.iter().position(|m| qos(m) == lowest).unwrap();
+                    self.pop_at(index);
+                }
+            }
+        }
+        self.bytes += size;
+        self.msgs.push_back(msg);
+        Overflow::Stored
+    }
+
+    /// hands the messages in order to `send` until it gives one back, which stays first
+    pub fn flush(&mut self, mut send: impl FnMut(ClientCommand) -> Option<ClientCommand>) {
+        while let Some(msg) = self.msgs.pop_front() {
+            let size = payload_len(&msg);
+            if let Some(msg) = send(msg) {
+                self.msgs.push_front(msg);
+                break;
+            }
+            self.bytes -= size;
+        }
+    }
+
+    /// removes the messages whose expiry interval elapsed at `now`, how many were removed
+    pub fn purge_expired(&mut self, now: u64) -> usize {
+        let before = self.msgs.len();
+        let mut bytes = self.bytes;
+        self.msgs.retain(|msg| match msg {
+            ClientCommand::Publish(publish) if publish.expired(now) => {
+                bytes -= payload_len(msg);
+                false
+            }
+            _ => true,
+        });
+        self.bytes = bytes;
+        let expired = before - self.msgs.len();
+        EXPIRED.fetch_add(expired as u64, Ordering::Relaxed);
+        expired
+    }
+
+    fn pop_at(&mut self, index: usize) {
+        if let Some(msg) = self.msgs.remove(index) {
+            self.bytes -= payload_len(&msg);
+            self.drop_count(1);
+        }
+    }
+
+    fn drop_count(&mut self, n: u64) {
+        self.dropped += n;
+        DROPPED.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::mqtt::QoS;
+    use crate::mqtt::protocol::publish::{PublishBody, PublishOptions, SharedPublish};
+
+    fn publish(topic: &str, qos: QoS, size: usize) -> ClientCommand {
+        expiring(topic, qos, size, None)
+    }
+
+    fn expiring(topic: &str, qos: QoS, size: usize, expiry: Option<u32>) -> ClientCommand {
+        let body = PublishBody {
+            topic: topic.to_string(),
+            payload: Bytes::from(vec![0u8; size]),
+            user_properties: vec![],
+            options: PublishOptions::default().with_expiry(expiry),
+        };
+        ClientCommand::Publish(SharedPublish::new(qos, false, Arc::new(body)))
+    }
+
+    fn topics(mut queue: OfflineQueue) -> Vec<String> {
+        let mut topics = vec![];
+        queue.flush(|m| {
+            if let ClientCommand::Publish(publish) = m {
+                topics.push(publish.body.topic.clone());
+            }
+            None
+        });
+        topics
+    }
+
+    #[test]
+    fn test_overflow() {
+        use StoreOverflowPolicy::*;
+
+        let mut queue = OfflineQueue::new();
+        for t in ["a", "b", "c"] {
+            queue.push(publish(t, QoS::AtLeastOnce, 10), 2, None, DropOldest);
+        }
+        assert_eq!((queue.len(), queue.bytes(), queue.dropped()), (2, 20, 1));
+        assert_eq!(topics(queue), ["b", "c"]);
+
+        let mut queue = OfflineQueue::new();
+        for t in ["a", "b"] {
+            queue.push(publish(t, QoS::AtLeastOnce, 10), 0, Some(25), DropNewest);
+        }
+        assert_eq!(
+            queue.push(publish("c", QoS::AtLeastOnce, 10), 0, Some(25), DropNewest),
+            Overflow::Dropped
+        );
+        assert_eq!(topics(queue), ["a", "b"]);
+
+        let mut queue = OfflineQueue::new();
+        queue.push(publish("a", QoS::ExactlyOnce, 10), 3, None, DropLowestQos);
+        queue.push(publish("b", QoS::AtMostOnce, 10), 3, None, DropLowestQos);
+        queue.push(publish("c", QoS::AtLeastOnce, 10), 3, None, DropLowestQos);
+        queue.push(publish("d", QoS::AtLeastOnce, 10), 3, None, DropLowestQos);
+        queue.push(publish("e", QoS::AtLeastOnce, 10), 3, None, DropLowestQos);
+        assert_eq!(
+            queue.push(publish("f", QoS::AtMostOnce, 10), 3, None, DropLowestQos),
+            Overflow::Dropped
+        );
+        assert_eq!(queue.dropped(), 3);
+        assert_eq!(topics(queue), ["a", "d", "e"]);
+
+        let mut queue = OfflineQueue::new();
+        queue.push(
+            publish("a", QoS::AtLeastOnce, 10),
+            1,
+            None,
+            DisconnectNotify,
+        );
+        assert_eq!(
+            queue.push(
+                publish("b", QoS::AtLeastOnce, 10),
+                1,
+                None,
+                DisconnectNotify
+            ),
+            Overflow::EndSession
+        );
+        assert_eq!((queue.len(), queue.bytes(), queue.dropped()), (0, 0, 2));
+
+        // unlimited, and a payload over max_bytes on its own
+        let mut queue = OfflineQueue::new();
+        for _ in 0..100 {
+            queue.push(publish("a", QoS::AtMostOnce, 1), 0, None, DropNewest);
+        }
+        assert_eq!(queue.len(), 100);
+        assert_eq!(
+            queue.push(publish("b", QoS::AtMostOnce, 500), 0, Some(200), DropOldest),
+            Overflow::Dropped
+        );
+        assert_eq!(queue.len(), 100);
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut queue = OfflineQueue::new();
+        for t in ["a", "b", "c"] {
+            queue.push(
+                publish(t, QoS::AtLeastOnce, 10),
+                0,
+                None,
+                StoreOverflowPolicy::DropOldest,
+            );
+        }
+        // room for one message
+        let mut sent = vec![];
+        queue.flush(|msg| {
+            if sent.is_empty() {
+                sent.push(msg);
+                None
+            } else {
+                Some(msg)
+            }
+        });
+        assert_eq!((sent.len(), queue.len(), queue.bytes()), (1, 2, 20));
+        queue.flush(|msg| {
+            sent.push(msg);
+            None
+        });
+        assert_eq!((queue.len(), queue.bytes()), (0, 0));
+        let mut rest = OfflineQueue::new();
+        for msg in sent {
+            rest.push(msg, 0, None, StoreOverflowPolicy::DropOldest);
+        }
+        assert_eq!(topics(rest), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let now = coarsetime::Clock::now_since_epoch().as_secs();
+        let mut queue = OfflineQueue::new();
+        for (topic, expiry) in [
+            ("a", Some(10)),
+            ("b", None),
+            ("c", Some(100)),
+            ("d", Some(0)),
+        ] {
+            queue.push(
+                expiring(topic, QoS::AtLeastOnce, 10, expiry),
+                0,
+                None,
+                StoreOverflowPolicy::DropOldest,
+            );
+        }
+        assert_eq!(queue.purge_expired(now), 0);
+        assert_eq!(queue.purge_expired(now + 50), 1);
+        assert_eq!((queue.len(), queue.bytes(), queue.dropped()), (3, 30, 0));
+        assert_eq!(queue.purge_expired(now + 200), 1);
+        assert_eq!(topics(queue), ["b", "d"]);
+    }
+}
@@ -7,12 +7,14 @@ use super::super::{MqttProtocolVersion, error::MqttProtocolError};
 use super::{
     fixed::{FixedHeaderCodec, FixedOptions},
     message::Message,
+    mountpoint,
 };
 
 pub struct MessageCodec {
     fixed_codec: FixedHeaderCodec,
     version: MqttProtocolVersion,
     packet_maximum: u32,
+    mountpoint: Option<String>,
 }
 
 impl Default for MessageCodec {
@@ -21,6 +23,7 @@ impl Default for MessageCodec {
             fixed_codec: FixedHeaderCodec::default(),
             version: MqttProtocolVersion::V3_1_1,
             packet_maximum: CONFIG.get().unwrap().mqtt.settings.max_packet_size,
+            mountpoint: None,
         }
     }
 }
@@ -33,6 +36,11 @@ impl MessageCodec {
     pub fn with_packet_size(&mut self, size: u32) {
         self.packet_maximum = size;
     }
+
+    /// prefix the topics read with `mountpoint` and strip it from the ones written
+    pub fn with_mountpoint(&mut self, mountpoint: Option<String>) {
+        self.mountpoint = mountpoint;
+    }
 }
 
 impl Decoder for MessageCodec {
@@ -50,7 +58,10 @@ impl Decoder for MessageCodec {
         }
 
         let msg = Message::try_from((fixed_header, self.version))?;
-        Ok(Some(msg))
+        match &self.mountpoint {
+            Some(mountpoint) => Ok(Some(mountpoint::mount(msg, mountpoint))),
+            None => Ok(Some(msg)),
+        }
     }
 }
 
@@ -58,6 +69,10 @@ impl Encoder<Message> for MessageCodec {
     type Error = MqttProtocolError;
 
     fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let msg = match &self.mountpoint {
+            Some(mountpoint) => mountpoint::unmount(msg, mountpoint),
+            None => msg,
+        };
         let options: FixedOptions = msg.into(self.version);
         let bytes: Bytes = options.into();
 
@@ -6,7 +6,10 @@ use tracing::debug;
 
 use crate::CONFIG;
 
-use super::super::{MqttProtocolVersion, code::ReturnCode, error::MqttProtocolError, utils};
+use super::super::listener::tcp::PeerCertificate;
+use super::super::{
+    MqttProtocolVersion, code::ReturnCode, error::MqttProtocolError, group, utils, utils::validate,
+};
 use super::{message::Message, property::Property, will::Will};
 
 #[derive(Clone)]
@@ -15,6 +18,7 @@ pub struct ConnectOptions {
     pub(crate) packet_maximum: u32,
     pub(crate) topic_alias_maximum: u16,
     pub(crate) inflight_maximum: u16,
+    pub(crate) request_response_information: bool,
 }
 
 impl std::default::Default for ConnectOptions {
@@ -25,6 +29,7 @@ impl std::default::Default for ConnectOptions {
             inflight_maximum: config.mqtt.settings.max_receive_queue,
             packet_maximum: config.mqtt.settings.max_packet_size,
             topic_alias_maximum: 0,
+            request_response_information: false,
         }
     }
 }
@@ -44,6 +49,11 @@ pub struct Connect {
 
     pub(crate) will: Option<Will>,
     pub(crate) options: ConnectOptions,
+
+    pub(crate) peer_cert: Option<PeerCertificate>,
+    pub(crate) server_name: Option<String>,
+    // groups requested through the "group" user property
+    pub(crate) groups: Vec<String>,
 }
 
 impl From<Connect> for Bytes {
@@ -113,6 +123,7 @@ impl Connect {
         }
 
         let mut options = ConnectOptions::default();
+        let mut groups = Vec::new();
         for prop in properties.into_iter() {
             match prop {
                 Property::SessionExpiryInterval(v) => {
@@ -138,6 +149,17 @@ impl Connect {
                             CONFIG.get().unwrap().mqtt.settings.topic_alias_maximum;
                     }
                 }
+                Property::RequestResponseInformation(v) => {
+                    if v > 1 {
+                        return Err(MqttProtocolError::InvalidProperty);
+                    }
+                    options.request_response_information = v == 1;
+                }
+                Property::UserProperty(v) if v.key == group::GROUP_PROPERTY => {
+                    if !v.value.is_empty() {
+                        groups.push(v.value);
+                    }
+                }
                 _ => {
                     debug!("ignore property in CONNECT: {}", prop);
                 }
@@ -147,7 +169,7 @@ impl Connect {
         let client_id_len = rdr.read_u16::<BigEndian>()? as usize;
         let mut client_id_buf = vec![0; client_id_len];
         let _ = rdr.read_exact(&mut client_id_buf)?;
-        let client_id = String::from_utf8_lossy(&client_id_buf).into_owned();
+        let client_id = validate::decode_utf8(client_id_buf)?;
 
         //let mut will_delay_interval = None;
         //let mut will_expiry_interval = None;
@@ -195,10 +217,16 @@ impl Connect {
             let will_topic_len = rdr.read_u16::<BigEndian>()? as usize;
             let mut will_topic_buf = vec![0; will_topic_len];
             let _ = rdr.read_exact(&mut will_topic_buf)?;
-            let will_topic = String::from_utf8_lossy(&will_topic_buf).into_owned();
+            let will_topic = validate::decode_utf8(will_topic_buf)?;
+            if !utils::client_pub_topic_valid(&will_topic) {
+                return Err(MqttProtocolError::InvalidTopicFilter);
+            }
 
             let will_message_len = rdr.read_u16::<BigEndian>()? as usize;
             let end_offset = rdr.position() as usize;
+            if end_offset + will_message_len > rdr.get_ref().len() {
+                return Err(MqttProtocolError::MalformedPayload);
+            }
             let will_msg = rdr
                 .get_ref()
                 .slice(end_offset..end_offset + will_message_len);
@@ -220,7 +248,7 @@ impl Connect {
             let username_len = rdr.read_u16::<BigEndian>()? as usize;
             let mut username_buf = vec![0; username_len];
             let _ = rdr.read_exact(&mut username_buf)?;
-            Some(String::from_utf8_lossy(&username_buf).into_owned())
+            Some(validate::decode_utf8(username_buf)?)
         } else {
             None
         };
@@ -253,6 +281,9 @@ impl Connect {
             will,
             generate_client_id,
             options,
+            peer_cert: None,
+            server_name: None,
+            groups,
         }))
     }
 }
@@ -292,9 +323,14 @@ impl Disconnect {
             }));
         }
 
-        let reason = rdr.read_u8()?;
+        // the reason code and the properties are omitted when the remaining length allows it
+        let reason = rdr.read_u8().unwrap_or(ReturnCode::Success as u8);
         let reason = ReturnCode::try_from(reason)?;
-        let properties = Property::try_from_properties(rdr)?;
+        let properties = if (rdr.position() as usize) < rdr.get_ref().len() {
+            Property::try_from_properties(rdr)?
+        } else {
+            Vec::new()
+        };
         let mut session_expiry_interval = None;
 
         for prop in properties.into_iter() {
@@ -324,6 +360,11 @@ impl Disconnect {
 pub struct ConnAckOptions {
     pub(crate) topic_alias_maximum: u16,
     pub(crate) session_expiry_interval: u32,
+    pub(crate) response_information: Option<String>,
+    // max_receive_queue when not limited for the client
+    pub(crate) receive_maximum: Option<u16>,
+    // the keep alive the client is held to, keep_alive when not set
+    pub(crate) server_keep_alive: Option<u16>,
 }
 
 impl ConnAckOptions {
@@ -336,6 +377,11 @@ impl ConnAckOptions {
         self.session_expiry_interval = v;
         self
     }
+
+    fn with_response_information(mut self, v: Option<String>) -> Self {
+        self.response_information = v;
+        self
+    }
 }
 
 impl std::default::Default for ConnAckOptions {
@@ -343,6 +389,9 @@ impl std::default::Default for ConnAckOptions {
         ConnAckOptions {
             topic_alias_maximum: 0,
             session_expiry_interval: 0,
+            response_information: None,
+            receive_maximum: None,
+            server_keep_alive: None,
         }
     }
 }
@@ -369,6 +418,11 @@ impl ConnAck {
         }
     }
 
+    /// the client identifier the broker gave the client instead of the one in CONNECT
+    pub(crate) fn assigned_client_id(&self) -> Option<&str> {
+        self.generated_client_id.as_deref()
+    }
+
     pub fn with_topic_alias_maximum(mut self, v: u16) -> Self {
         self.options = self.options.with_topic_alias_maximum(v);
         self
@@ -379,6 +433,11 @@ impl ConnAck {
         self
     }
 
+    pub fn with_response_information(mut self, v: Option<String>) -> Self {
+        self.options = self.options.with_response_information(v);
+        self
+    }
+
     pub(crate) fn into(self, version: MqttProtocolVersion) -> Bytes {
         let mut buf = BytesMut::with_capacity(2);
 
@@ -390,8 +449,16 @@ impl ConnAck {
         if version == MqttProtocolVersion::V5 {
             let mut properties = vec![
                 Property::SessionExpiryInterval(self.options.session_expiry_interval),
-                Property::ServerKeepAlive(config.mqtt.settings.keep_alive),
-                Property::ReceiveMaximum(config.mqtt.settings.max_receive_queue),
+                Property::ServerKeepAlive(
+                    self.options
+                        .server_keep_alive
+                        .unwrap_or(config.mqtt.settings.keep_alive),
+                ),
+                Property::ReceiveMaximum(
+                    self.options
+                        .receive_maximum
+                        .unwrap_or(config.mqtt.settings.max_receive_queue),
+                ),
                 Property::TopicAliasMaximum(self.options.topic_alias_maximum),
                 Property::RetainAvailable(1),
                 Property::WildcardSubscriptionAvailable(1),
@@ -403,6 +470,9 @@ impl ConnAck {
             if let Some(client_id) = self.generated_client_id {
                 properties.push(Property::AssignedClientIdentifier(client_id));
             }
+            if let Some(response_information) = self.options.response_information {
+                properties.push(Property::ResponseInformation(response_information));
+            }
 
             let mut prop_bytes = BytesMut::new();
             for prop in properties {
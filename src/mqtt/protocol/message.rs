@@ -55,6 +55,8 @@ pub enum Message {
     Connect(conn::Connect),
     ConnAck(conn::ConnAck),
     Publish(publish::Publish),
+    // an outgoing PUBLISH sharing its body with the other subscribers
+    SharedPublish(publish::SharedPublish),
     PubAck(publish::PubAck),
     PubRec(publish::PubRec),
     PubRel(publish::PubRel),
@@ -72,16 +74,18 @@ pub enum Message {
 
 impl Message {
     pub fn with_dup(&mut self) {
-        if let Message::Publish(publish) = self {
-            publish.dup = true;
+        match self {
+            Message::Publish(publish) => publish.dup = true,
+            Message::SharedPublish(publish) => publish.dup = true,
+            _ => {}
         }
     }
 
-    pub fn to_type(&self) -> MessageType {
+    pub(crate) fn to_type(&self) -> MessageType {
         match self {
             Message::Connect(_) => MessageType::Connect,
             Message::ConnAck(_) => MessageType::ConnAck,
-            Message::Publish(_) => MessageType::Publish,
+            Message::Publish(_) | Message::SharedPublish(_) => MessageType::Publish,
             Message::PubAck(_) => MessageType::PubAck,
             Message::PubRec(_) => MessageType::PubRec,
             Message::PubRel(_) => MessageType::PubRel,
@@ -98,7 +102,7 @@ impl Message {
         }
     }
 
-    pub fn into(self, version: MqttProtocolVersion) -> FixedOptions {
+    pub(crate) fn into(self, version: MqttProtocolVersion) -> FixedOptions {
         let msg_type = self.to_type();
         let mut retain = false;
         let mut dup = false;
@@ -111,6 +115,12 @@ impl Message {
                 qos = publish.qos;
                 publish.into(version)
             }
+            Message::SharedPublish(publish) => {
+                retain = publish.retain;
+                dup = publish.dup;
+                qos = publish.qos;
+                publish.into(version)
+            }
             Message::Connect(conn) => conn.into(),
             Message::ConnAck(connack) => connack.into(version),
             Message::SubAck(suback) => suback.into(version),
@@ -2,7 +2,11 @@ pub mod codec;
 pub mod conn;
 mod fixed;
 pub mod message;
+mod mountpoint;
 pub mod property;
 pub mod publish;
 pub mod subscribe;
 pub mod will;
+
+#[cfg(test)]
+mod tests;
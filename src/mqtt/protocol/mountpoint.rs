@@ -0,0 +1,134 @@
+//! the `mountpoint` of a listener, prefixed to the topics its clients publish and subscribe to
+//! and stripped from the topics of the messages they receive, so that the tenants of a broker
+//! each see a tree of their own
+
+use super::super::utils::parse_shared_subscription;
+use super::message::Message;
+
+fn mount_filter(filter: &mut String, mountpoint: &str) {
+    if filter.is_empty() {
+        return;
+    }
+    if filter.starts_with("$share/") {
+        // an invalid shared subscription is left to be refused as it is
+        if let Ok((group, actual)) = parse_shared_subscription(filter) {
+            *filter = format!("$share/{}/{}{}", group, mountpoint, actual);
+        }
+        return;
+    }
+    filter.insert_str(0, mountpoint);
+}
+
+/// prefix the topics of a message from a client, an empty topic of a PUBLISH using a topic
+/// alias is left empty
+pub fn mount(msg: Message, mountpoint: &str) -> Message {
+    match msg {
+        Message::Connect(mut conn) => {
+            if let Some(will) = conn.will.as_mut() {
+                will.topic.insert_str(0, mountpoint);
+            }
+            Message::Connect(conn)
+        }
+        Message::Publish(mut publish) => {
+            if !publish.topic.is_empty() {
+                publish.topic.insert_str(0, mountpoint);
+            }
+            Message::Publish(publish)
+        }
+        Message::Subscribe(mut sub) => {
+            for (filter, _) in sub.topics.iter_mut() {
+                mount_filter(filter, mountpoint);
+            }
+            Message::Subscribe(sub)
+        }
+        Message::Unsubscribe(mut unsub) => {
+            for filter in unsub.topics.iter_mut() {
+                mount_filter(filter, mountpoint);
+            }
+            Message::Unsubscribe(unsub)
+        }
+        msg => msg,
+    }
+}
+
+/// strip the prefix from the topic of a message to a client
+pub fn unmount(msg: Message, mountpoint: &str) -> Message {
+    match msg {
+        Message::SharedPublish(publish) => Message::SharedPublish(publish.unmounted(mountpoint)),
+        msg => msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::super::publish::{Publish, PublishBody, PublishOptions, SharedPublish};
+    use super::super::subscribe::Unsubscribe;
+    use super::*;
+    use crate::mqtt::{MqttProtocolVersion, QoS};
+
+    #[test]
+    fn test_mount() {
+        let publish = Publish::new(
+            false,
+            QoS::AtMostOnce,
+            false,
+            "a/b".to_string(),
+            None,
+            Bytes::new(),
+            vec![],
+        );
+        let Message::Publish(publish) = mount(Message::Publish(publish), "tenant/") else {
+            unreachable!()
+        };
+        assert_eq!(publish.topic, "tenant/a/b");
+
+        let unsub = Unsubscribe {
+            packet_id: 1,
+            topics: vec![
+                "a/#".to_string(),
+                "$share/g/a/+".to_string(),
+                "$share/g".to_string(),
+                String::new(),
+            ],
+        };
+        let Message::Unsubscribe(unsub) = mount(Message::Unsubscribe(unsub), "tenant/") else {
+            unreachable!()
+        };
+        assert_eq!(
+            unsub.topics,
+            ["tenant/a/#", "$share/g/tenant/a/+", "$share/g", ""]
+        );
+
+        let body = Arc::new(PublishBody {
+            topic: "tenant/a/b".to_string(),
+            payload: Bytes::from_static(b"x"),
+            user_properties: vec![],
+            options: PublishOptions::default(),
+        });
+        let shared = SharedPublish::new(QoS::AtMostOnce, false, body.clone());
+        let Message::SharedPublish(unmounted) = unmount(Message::SharedPublish(shared), "tenant/")
+        else {
+            unreachable!()
+        };
+        let plain = SharedPublish::new(
+            QoS::AtMostOnce,
+            false,
+            Arc::new(PublishBody {
+                topic: "a/b".to_string(),
+                payload: Bytes::from_static(b"x"),
+                user_properties: vec![],
+                options: PublishOptions::default(),
+            }),
+        );
+        assert_eq!(
+            unmounted.into(MqttProtocolVersion::V3_1_1),
+            plain.into(MqttProtocolVersion::V3_1_1)
+        );
+        // the body shared with the other subscribers keeps its topic
+        assert_eq!(body.topic, "tenant/a/b");
+    }
+}
@@ -25,7 +25,7 @@ use std::io::{Cursor, Read};
 use byteorder::{BigEndian, ReadBytesExt as _};
 use bytes::{BufMut, Bytes, BytesMut};
 
-use super::super::error::MqttProtocolError;
+use super::super::{error::MqttProtocolError, utils::validate};
 
 #[derive(Clone)]
 pub struct PropertyUser {
@@ -33,6 +33,17 @@ pub struct PropertyUser {
     pub value: String,
 }
 
+impl PropertyUser {
+    /// written as a User Property without moving it into a `Property`
+    pub(crate) fn put_property(&self, buf: &mut BytesMut) {
+        buf.put_u8(0x26);
+        buf.put_u16(self.key.len() as u16);
+        buf.put_slice(self.key.as_bytes());
+        buf.put_u16(self.value.len() as u16);
+        buf.put_slice(self.value.as_bytes());
+    }
+}
+
 impl std::default::Default for PropertyUser {
     fn default() -> Self {
         PropertyUser {
@@ -293,7 +304,7 @@ impl Property {
                 let len = rdr.read_u16::<BigEndian>()? as usize;
                 let mut buf = vec![0; len];
                 rdr.read_exact(&mut buf)?;
-                *v = String::from_utf8(buf).map_err(|_| MqttProtocolError::InvalidProperty)?;
+                *v = validate::decode_utf8(buf).map_err(|_| MqttProtocolError::InvalidProperty)?;
             }
             CorrelationData(ref mut v) | AuthenticationData(ref mut v) => {
                 let len = rdr.read_u16::<BigEndian>()? as usize;
@@ -322,14 +333,14 @@ impl Property {
                 let key_len = rdr.read_u16::<BigEndian>()? as usize;
                 let mut key_buf = vec![0; key_len];
                 rdr.read_exact(&mut key_buf)?;
-                let key =
-                    String::from_utf8(key_buf).map_err(|_| MqttProtocolError::InvalidProperty)?;
+                let key = validate::decode_utf8(key_buf)
+                    .map_err(|_| MqttProtocolError::InvalidProperty)?;
 
                 let value_len = rdr.read_u16::<BigEndian>()? as usize;
                 let mut value_buf = vec![0; value_len];
                 rdr.read_exact(&mut value_buf)?;
-                let value =
-                    String::from_utf8(value_buf).map_err(|_| MqttProtocolError::InvalidProperty)?;
+                let value = validate::decode_utf8(value_buf)
+                    .map_err(|_| MqttProtocolError::InvalidProperty)?;
 
                 *v = PropertyUser { key, value };
             }
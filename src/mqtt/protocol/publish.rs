@@ -1,9 +1,12 @@
 use std::io::{Cursor, Read};
+use std::sync::Arc;
 
 use byteorder::{BigEndian, ReadBytesExt as _};
 use bytes::{BufMut, Bytes, BytesMut};
 
-use super::super::{MqttProtocolVersion, QoS, code::ReturnCode, error::MqttProtocolError};
+use super::super::{
+    MqttProtocolVersion, QoS, code::ReturnCode, error::MqttProtocolError, utils::validate,
+};
 use super::{
     message::Message,
     property::{Property, PropertyUser},
@@ -20,6 +23,8 @@ pub struct PublishOptions {
     pub(crate) content_type: Option<String>,
     pub(crate) response_topic: Option<String>,
     pub(crate) correlation_data: Option<Bytes>,
+    // set when the topic is traced, never on the wire
+    pub(crate) trace_id: Option<u64>,
 }
 
 impl PublishOptions {
@@ -39,6 +44,23 @@ impl PublishOptions {
         });
         self
     }
+
+    /// whether the message expired at `now`, an expiry interval of 0 never does
+    pub fn expired(&self, now: u64) -> bool {
+        self.message_expiry_at
+            .is_some_and(|expiry_at| expiry_at != 0 && expiry_at <= now)
+    }
+
+    /// the expiry interval counted down to the time left at `now`, forwarded to subscribers
+    /// minus the time the message waited in the server
+    pub fn with_remaining_expiry(mut self, now: u64) -> Self {
+        if let Some(expiry_at) = self.message_expiry_at
+            && expiry_at != 0
+        {
+            self.message_expiry_interval = Some(expiry_at.saturating_sub(now) as u32);
+        }
+        self
+    }
 }
 
 impl std::default::Default for PublishOptions {
@@ -52,6 +74,7 @@ impl std::default::Default for PublishOptions {
             content_type: None,
             response_topic: None,
             correlation_data: None,
+            trace_id: None,
         }
     }
 }
@@ -71,44 +94,45 @@ pub struct Publish {
     pub(crate) user_properties: Vec<PropertyUser>,
 }
 
-#[derive(Clone)]
-pub struct PubAck {
-    pub(crate) packet_id: u16,
-    pub(crate) reason_code: ReturnCode,
+/// what the deliveries of a message to its subscribers have in common, shared by all of them
+pub struct PublishBody {
+    pub(crate) topic: String,
+    pub(crate) payload: Bytes,
+    pub(crate) user_properties: Vec<PropertyUser>,
+    pub(crate) options: PublishOptions,
 }
 
-pub type PubRec = PubAck;
-pub type PubRel = PubAck;
-pub type PubComp = PubAck;
-
-impl Publish {
-    pub fn new(
-        dup: bool,
-        qos: QoS,
-        retain: bool,
-        topic: String,
-        packet_id: Option<u16>,
-        payload: Bytes,
-        user_properties: Vec<PropertyUser>,
-    ) -> Self {
-        Publish {
-            dup,
-            qos,
-            retain,
-            topic,
-            packet_id,
-            payload,
-            user_properties,
-            options: PublishOptions::default(),
-        }
-    }
+/// an outgoing PUBLISH, the body is shared with the other subscribers of the message and only
+/// the header fields belong to the connection
+#[derive(Clone)]
+pub struct SharedPublish {
+    pub(crate) dup: bool,
+    pub(crate) qos: QoS,
+    pub(crate) retain: bool,
+    pub(crate) packet_id: Option<u16>,
+    pub(crate) subscription_identifier: Option<u32>,
+    pub(crate) message_expiry_interval: Option<u32>,
+    pub(crate) body: Arc<PublishBody>,
+    // the length of the mountpoint of the receiving client, left out of the topic on the wire
+    pub(crate) unmount: usize,
+    // the client that published the message, only kept for subscribers on other cluster nodes
+    pub(crate) publisher: Option<Arc<str>>,
+}
 
-    pub fn with_options(mut self, options: PublishOptions) -> Self {
-        self.options = options;
-        self
-    }
+// the fields after the fixed header, borrowed from a Publish or a SharedPublish
+struct PublishFields<'a> {
+    topic: &'a str,
+    qos: QoS,
+    packet_id: Option<u16>,
+    message_expiry_interval: Option<u32>,
+    subscription_identifier: Option<u32>,
+    options: &'a PublishOptions,
+    user_properties: &'a [PropertyUser],
+    payload: &'a Bytes,
+}
 
-    pub fn into(self, version: MqttProtocolVersion) -> Bytes {
+impl PublishFields<'_> {
+    fn encode(&self, version: MqttProtocolVersion) -> Bytes {
         let mut buf = BytesMut::with_capacity(2 + self.topic.len() + self.payload.len());
 
         buf.put_u16(self.topic.len() as u16);
@@ -136,34 +160,33 @@ impl Publish {
             //prop_bytes.put(prop.into_bytes());
             //}
 
-            if let Some(message_expiry_interval) = self.options.message_expiry_interval {
+            if let Some(message_expiry_interval) = self.message_expiry_interval {
                 let prop = Property::MessageExpiryInterval(message_expiry_interval);
                 prop_bytes.put(prop.into_bytes());
             }
 
-            if let Some(subscription_identifier) = self.options.subscription_identifier {
+            if let Some(subscription_identifier) = self.subscription_identifier {
                 let prop = Property::SubscriptionIdentifier(subscription_identifier);
                 prop_bytes.put(prop.into_bytes());
             }
 
-            if let Some(content_type) = self.options.content_type {
-                let prop = Property::ContentType(content_type);
+            if let Some(content_type) = &self.options.content_type {
+                let prop = Property::ContentType(content_type.clone());
                 prop_bytes.put(prop.into_bytes());
             }
 
-            if let Some(response_topic) = self.options.response_topic {
-                let prop = Property::ResponseTopic(response_topic);
+            if let Some(response_topic) = &self.options.response_topic {
+                let prop = Property::ResponseTopic(response_topic.clone());
                 prop_bytes.put(prop.into_bytes());
             }
 
-            if let Some(correlation_data) = self.options.correlation_data {
+            if let Some(correlation_data) = &self.options.correlation_data {
                 let prop = Property::CorrelationData(correlation_data.to_vec());
                 prop_bytes.put(prop.into_bytes());
             }
 
             for prop in self.user_properties {
-                let prop = Property::UserProperty(prop);
-                prop_bytes.put(prop.into_bytes());
+                prop.put_property(&mut prop_bytes);
             }
 
             let prop_len = prop_bytes.len();
@@ -185,10 +208,126 @@ impl Publish {
             buf.put(prop_bytes);
         }
 
-        buf.put(self.payload);
+        buf.put_slice(self.payload);
 
         buf.freeze()
     }
+}
+
+impl SharedPublish {
+    pub fn new(qos: QoS, retain: bool, body: Arc<PublishBody>) -> Self {
+        SharedPublish {
+            dup: false,
+            qos,
+            retain,
+            packet_id: None,
+            subscription_identifier: body.options.subscription_identifier,
+            message_expiry_interval: body.options.message_expiry_interval,
+            body,
+            unmount: 0,
+            publisher: None,
+        }
+    }
+
+    pub fn with_publisher(mut self, publisher: &str) -> Self {
+        self.publisher = Some(publisher.into());
+        self
+    }
+
+    /// the message as a client under `mountpoint` receives it
+    pub fn unmounted(mut self, mountpoint: &str) -> Self {
+        self.unmount = if self.body.topic.starts_with(mountpoint) {
+            mountpoint.len()
+        } else {
+            0
+        };
+        self
+    }
+
+    pub fn with_subscription_identifier(mut self, v: Option<u32>) -> Self {
+        self.subscription_identifier = v;
+        self
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.body.options.expired(now)
+    }
+
+    /// the expiry interval left at `now`, see `PublishOptions::with_remaining_expiry`
+    pub fn with_remaining_expiry(mut self, now: u64) -> Self {
+        if let Some(expiry_at) = self.body.options.message_expiry_at
+            && expiry_at != 0
+        {
+            self.message_expiry_interval = Some(expiry_at.saturating_sub(now) as u32);
+        }
+        self
+    }
+
+    pub fn into(self, version: MqttProtocolVersion) -> Bytes {
+        PublishFields {
+            topic: &self.body.topic[self.unmount..],
+            qos: self.qos,
+            packet_id: self.packet_id,
+            message_expiry_interval: self.message_expiry_interval,
+            subscription_identifier: self.subscription_identifier,
+            options: &self.body.options,
+            user_properties: &self.body.user_properties,
+            payload: &self.body.payload,
+        }
+        .encode(version)
+    }
+}
+
+#[derive(Clone)]
+pub struct PubAck {
+    pub(crate) packet_id: u16,
+    pub(crate) reason_code: ReturnCode,
+}
+
+pub type PubRec = PubAck;
+pub type PubRel = PubAck;
+pub type PubComp = PubAck;
+
+impl Publish {
+    pub fn new(
+        dup: bool,
+        qos: QoS,
+        retain: bool,
+        topic: String,
+        packet_id: Option<u16>,
+        payload: Bytes,
+        user_properties: Vec<PropertyUser>,
+    ) -> Self {
+        Publish {
+            dup,
+            qos,
+            retain,
+            topic,
+            packet_id,
+            payload,
+            user_properties,
+            options: PublishOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: PublishOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn into(self, version: MqttProtocolVersion) -> Bytes {
+        PublishFields {
+            topic: &self.topic,
+            qos: self.qos,
+            packet_id: self.packet_id,
+            message_expiry_interval: self.options.message_expiry_interval,
+            subscription_identifier: self.options.subscription_identifier,
+            options: &self.options,
+            user_properties: &self.user_properties,
+            payload: &self.payload,
+        }
+        .encode(version)
+    }
 
     pub fn publish_try_from(
         rdr: &mut Cursor<Bytes>,
@@ -202,7 +341,7 @@ impl Publish {
 
         let topic = if topic_len > 0 {
             rdr.read_exact(&mut topic)?;
-            String::from_utf8(topic).map_err(|_| MqttProtocolError::InvalidTopicFilter)?
+            validate::decode_utf8(topic)?
         } else {
             String::new()
         };
@@ -289,12 +428,12 @@ impl PubAck {
             }));
         }
 
-        let reason_code = rdr.read_u8()?;
+        // the reason code and the properties are omitted when the remaining length allows it
+        let reason_code = rdr.read_u8().unwrap_or(ReturnCode::Success as u8);
         let reason_code = ReturnCode::try_from(reason_code)?;
 
         let mut _properties = vec![];
-        let properties_len = rdr.read_u8().unwrap_or(0);
-        if properties_len > 0 {
+        if (rdr.position() as usize) < rdr.get_ref().len() {
             _properties = Property::try_from_properties(rdr)?;
         }
 
@@ -320,11 +459,8 @@ impl PubAck {
         let reason_code = ReturnCode::try_from(reason_code)?;
 
         let mut _properties = vec![];
-        if reason_code != ReturnCode::Success {
-            let properties_len = rdr.read_u8().unwrap_or(0);
-            if properties_len > 0 {
-                _properties = Property::try_from_properties(rdr)?;
-            }
+        if (rdr.position() as usize) < rdr.get_ref().len() {
+            _properties = Property::try_from_properties(rdr)?;
         }
 
         Ok(Message::PubRec(PubAck {
@@ -349,11 +485,8 @@ impl PubAck {
         let reason_code = ReturnCode::try_from(reason_code)?;
 
         let mut _properties = vec![];
-        if reason_code != ReturnCode::Success {
-            let properties_len = rdr.read_u8().unwrap_or(0);
-            if properties_len > 0 {
-                _properties = Property::try_from_properties(rdr)?;
-            }
+        if (rdr.position() as usize) < rdr.get_ref().len() {
+            _properties = Property::try_from_properties(rdr)?;
         }
 
         Ok(Message::PubComp(PubAck {
@@ -397,11 +530,8 @@ impl PubAck {
         let reason_code = ReturnCode::try_from(reason_code)?;
 
         let mut _properties = vec![];
-        if reason_code != ReturnCode::Success {
-            let properties_len = rdr.read_u8().unwrap_or(0);
-            if properties_len > 0 {
-                _properties = Property::try_from_properties(rdr)?;
-            }
+        if (rdr.position() as usize) < rdr.get_ref().len() {
+            _properties = Property::try_from_properties(rdr)?;
         }
 
         Ok(Message::PubRel(PubRel {
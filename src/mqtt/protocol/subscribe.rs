@@ -3,7 +3,9 @@ use std::io::{Cursor, Read};
 use byteorder::{BigEndian, ReadBytesExt as _};
 use bytes::{BufMut, Bytes, BytesMut};
 
-use super::super::{MqttProtocolVersion, QoS, code::ReturnCode, error::MqttProtocolError};
+use super::super::{
+    MqttProtocolVersion, QoS, code::ReturnCode, error::MqttProtocolError, utils::validate,
+};
 use super::{message::Message, property::Property};
 
 #[derive(Clone)]
@@ -136,7 +138,7 @@ impl Subscribe {
             let mut topic = vec![0u8; topic_len as usize];
 
             rdr.read_exact(&mut topic)?;
-            let topic = String::from_utf8_lossy(&topic).into_owned();
+            let topic = validate::decode_utf8(topic)?;
 
             let options = rdr.read_u8()?;
             let options = if MqttProtocolVersion::V5 == version {
@@ -185,7 +187,7 @@ impl Unsubscribe {
             let mut topic = vec![0u8; topic_len as usize];
 
             rdr.read_exact(&mut topic)?;
-            let topic = String::from_utf8_lossy(&topic).into_owned();
+            let topic = validate::decode_utf8(topic)?;
 
             topics.push(topic);
         }
@@ -0,0 +1,228 @@
+//! packets as the client command in each comment puts them on the wire
+
+use bytes::Bytes;
+
+use super::super::super::{MqttProtocolVersion, QoS, code::ReturnCode};
+use super::super::{
+    conn::{ConnAck, Disconnect},
+    message::Message,
+    publish::{PubAck, Publish},
+    subscribe::{SubAck, UnsubAck},
+};
+use super::{decode, encode, hex};
+
+const V3_1_1: MqttProtocolVersion = MqttProtocolVersion::V3_1_1;
+const V5: MqttProtocolVersion = MqttProtocolVersion::V5;
+
+// mosquitto_pub -V mqttv311 -i pub-1 -t a/b -m hello -q 1
+const MOSQUITTO_CONNECT: &str = "10 11 00 04 4d 51 54 54 04 02 00 3c 00 05 70 75 62 2d 31";
+const MOSQUITTO_PUBLISH: &str = "32 0c 00 03 61 2f 62 00 01 68 65 6c 6c 6f";
+const MOSQUITTO_DISCONNECT: &str = "e0 00";
+
+// paho, subscribe("sensors/#", qos=1) then ping, v3.1.1
+const PAHO_SUBSCRIBE: &str = "82 0e 00 01 00 09 73 65 6e 73 6f 72 73 2f 23 01";
+const PAHO_PINGREQ: &str = "c0 00";
+
+// paho v5, PUBREC "no matching subscribers" with a reason string, then
+// disconnect with session expiry interval 0
+const PAHO_PUBREC: &str = "50 15 00 09 10 11 1f 00 0e 6e 6f 20 73 75 62 73 63 72 69 62 65 72 73";
+const PAHO_DISCONNECT: &str = "e0 07 00 05 11 00 00 00 00";
+
+// MQTTX (MQTT.js) v5, client id mqttx_1a2b3c4d, session expiry 3600, user property group=fleet1
+const MQTTX_CONNECT: &str = "10 30 00 04 4d 51 54 54 05 02 00 3c 15 11 00 00 0e 10 26 00 05 67 72 \
+     6f 75 70 00 06 66 6c 65 65 74 31 00 0e 6d 71 74 74 78 5f 31 61 32 62 33 63 34 64";
+// MQTTX v5, client id dev-7, username alice, will status/dev-7 "offline" QoS 1 retained,
+// will delay 10 seconds
+const MQTTX_CONNECT_WILL: &str = "10 36 00 04 4d 51 54 54 05 ae 00 1e 00 00 05 64 65 76 2d 37 05 18 \
+     00 00 00 0a 00 0c 73 74 61 74 75 73 2f 64 65 76 2d 37 00 07 6f 66 66 6c 69 6e 65 00 05 61 6c \
+     69 63 65";
+// MQTTX v5, subscribe a/+ QoS 2, no local, retain as published, retain handling 1,
+// subscription identifier 7
+const MQTTX_SUBSCRIBE: &str = "82 0b 00 02 02 0b 07 00 03 61 2f 2b 1e";
+const MQTTX_UNSUBSCRIBE: &str = "a2 08 00 03 00 00 03 61 2f 2b";
+// MQTTX v5, publish {"value":21.5} to plant/line1/temp QoS 1, UTF-8 payload,
+// content type application/json, user property site=north
+const MQTTX_PUBLISH: &str = "32 46 00 10 70 6c 61 6e 74 2f 6c 69 6e 65 31 2f 74 65 6d 70 00 04 23 \
+     01 01 03 00 10 61 70 70 6c 69 63 61 74 69 6f 6e 2f 6a 73 6f 6e 26 00 04 73 69 74 65 00 05 6e \
+     6f 72 74 68 7b 22 76 61 6c 75 65 22 3a 32 31 2e 35 7d";
+// MQTTX v5, PUBACK success without reason code
+const MQTTX_PUBACK: &str = "40 02 00 05";
+
+#[test]
+fn test_golden_connect() {
+    let Ok(Message::Connect(connect)) = decode(&hex(MOSQUITTO_CONNECT), V3_1_1) else {
+        panic!("not a CONNECT");
+    };
+    assert!(connect.version == V3_1_1);
+    assert_eq!(connect.client_id, "pub-1");
+    assert_eq!(connect.keep_alive, 60);
+    assert!(connect.clean_start);
+    assert!(connect.username.is_none() && connect.will.is_none());
+
+    let Ok(Message::Connect(connect)) = decode(&hex(MQTTX_CONNECT), V3_1_1) else {
+        panic!("not a CONNECT");
+    };
+    assert!(connect.version == V5);
+    assert_eq!(connect.client_id, "mqttx_1a2b3c4d");
+    assert_eq!(connect.options.session_expiry_interval, 3600);
+    assert_eq!(connect.groups, vec!["fleet1".to_string()]);
+
+    let Ok(Message::Connect(connect)) = decode(&hex(MQTTX_CONNECT_WILL), V3_1_1) else {
+        panic!("not a CONNECT");
+    };
+    assert_eq!(connect.client_id, "dev-7");
+    assert_eq!(connect.keep_alive, 30);
+    assert_eq!(connect.username.as_deref(), Some("alice"));
+    assert!(connect.password.is_none());
+    let will = connect.will.unwrap();
+    assert_eq!(will.topic, "status/dev-7");
+    assert_eq!(will.payload.as_ref(), b"offline");
+    assert_eq!(will.qos, QoS::AtLeastOnce);
+    assert!(will.retain);
+    assert_eq!(will.options.will_delay_interval, Some(10));
+}
+
+#[test]
+fn test_golden_publish() {
+    let Ok(Message::Publish(publish)) = decode(&hex(MOSQUITTO_PUBLISH), V3_1_1) else {
+        panic!("not a PUBLISH");
+    };
+    assert_eq!(publish.topic, "a/b");
+    assert_eq!(publish.qos, QoS::AtLeastOnce);
+    assert_eq!(publish.packet_id, Some(1));
+    assert_eq!(publish.payload.as_ref(), b"hello");
+
+    let Ok(Message::Publish(publish)) = decode(&hex(MQTTX_PUBLISH), V5) else {
+        panic!("not a PUBLISH");
+    };
+    assert_eq!(publish.topic, "plant/line1/temp");
+    assert_eq!(publish.packet_id, Some(4));
+    assert_eq!(publish.payload.as_ref(), b"{\"value\":21.5}");
+    assert_eq!(publish.options.payload_format_indicator, Some(1));
+    assert_eq!(
+        publish.options.content_type.as_deref(),
+        Some("application/json")
+    );
+    assert_eq!(publish.user_properties.len(), 1);
+    assert_eq!(publish.user_properties[0].key, "site");
+    assert_eq!(publish.user_properties[0].value, "north");
+}
+
+#[test]
+fn test_golden_subscribe() {
+    let Ok(Message::Subscribe(subscribe)) = decode(&hex(PAHO_SUBSCRIBE), V3_1_1) else {
+        panic!("not a SUBSCRIBE");
+    };
+    assert_eq!(subscribe.packet_id, 1);
+    assert_eq!(subscribe.topics.len(), 1);
+    assert_eq!(subscribe.topics[0].0, "sensors/#");
+    assert_eq!(subscribe.topics[0].1.qos, QoS::AtLeastOnce);
+
+    let Ok(Message::Subscribe(subscribe)) = decode(&hex(MQTTX_SUBSCRIBE), V5) else {
+        panic!("not a SUBSCRIBE");
+    };
+    assert_eq!(subscribe.packet_id, 2);
+    let (topic, options) = &subscribe.topics[0];
+    assert_eq!(topic, "a/+");
+    assert_eq!(options.qos, QoS::ExactlyOnce);
+    assert!(options.no_local && options.retain_as_published);
+    assert_eq!(options.retain_handling, 1);
+    assert_eq!(options.subscription_identifier, Some(7));
+
+    let Ok(Message::Unsubscribe(unsubscribe)) = decode(&hex(MQTTX_UNSUBSCRIBE), V5) else {
+        panic!("not an UNSUBSCRIBE");
+    };
+    assert_eq!(unsubscribe.packet_id, 3);
+    assert_eq!(unsubscribe.topics, vec!["a/+".to_string()]);
+}
+
+#[test]
+fn test_golden_acks_and_disconnect() {
+    let Ok(Message::PubAck(ack)) = decode(&hex(MQTTX_PUBACK), V5) else {
+        panic!("not a PUBACK");
+    };
+    assert_eq!(ack.packet_id, 5);
+    assert_eq!(ack.reason_code, ReturnCode::Success);
+
+    let Ok(Message::PubRec(ack)) = decode(&hex(PAHO_PUBREC), V5) else {
+        panic!("not a PUBREC");
+    };
+    assert_eq!(ack.packet_id, 9);
+    assert_eq!(ack.reason_code, ReturnCode::NoMatchSubscription);
+
+    assert!(matches!(
+        decode(&hex(PAHO_PINGREQ), V3_1_1),
+        Ok(Message::PingReq)
+    ));
+
+    for version in [V3_1_1, V5] {
+        let Ok(Message::Disconnect(disconnect)) = decode(&hex(MOSQUITTO_DISCONNECT), version)
+        else {
+            panic!("not a DISCONNECT");
+        };
+        assert_eq!(disconnect.reason, ReturnCode::Success);
+    }
+    let Ok(Message::Disconnect(disconnect)) = decode(&hex(PAHO_DISCONNECT), V5) else {
+        panic!("not a DISCONNECT");
+    };
+    assert_eq!(disconnect.session_expiry_interval, Some(0));
+}
+
+#[test]
+fn test_golden_server_packets() {
+    let cases = [
+        (
+            Message::ConnAck(ConnAck::new(false, ReturnCode::Success, None)),
+            V3_1_1,
+            "20 02 00 00",
+        ),
+        (
+            Message::SubAck(SubAck::new(1, vec![ReturnCode::Success])),
+            V3_1_1,
+            "90 03 00 01 00",
+        ),
+        (
+            Message::SubAck(SubAck::new(2, vec![ReturnCode::TopicFilterInvalid])),
+            V5,
+            "90 04 00 02 00 8f",
+        ),
+        (
+            Message::UnsubAck(UnsubAck::new(3, vec![ReturnCode::NoSubscriptionExisted])),
+            V5,
+            "b0 04 00 03 00 11",
+        ),
+        (
+            Message::PubAck(PubAck::new(5, ReturnCode::Success)),
+            V5,
+            "40 02 00 05",
+        ),
+        (
+            Message::PubRec(PubAck::new(9, ReturnCode::NoMatchSubscription)),
+            V5,
+            "50 04 00 09 10 00",
+        ),
+        (Message::PingResp, V5, "d0 00"),
+        (
+            Message::Disconnect(Disconnect::new(ReturnCode::SessionTakenOver)),
+            V5,
+            "e0 01 8e",
+        ),
+        (
+            Message::Publish(Publish::new(
+                false,
+                QoS::AtMostOnce,
+                true,
+                "a/b".to_string(),
+                None,
+                Bytes::from_static(b"on"),
+                vec![],
+            )),
+            V3_1_1,
+            "31 07 00 03 61 2f 62 6f 6e",
+        ),
+    ];
+
+    for (message, version, expected) in cases {
+        assert_eq!(encode(message, version).as_ref(), &hex(expected)[..]);
+    }
+}
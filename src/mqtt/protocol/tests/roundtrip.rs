@@ -0,0 +1,259 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+use tokio_util::codec::Decoder;
+
+use super::super::super::{MqttProtocolVersion, QoS, code::ReturnCode};
+use super::super::{
+    conn::Disconnect,
+    fixed::{FixedHeaderCodec, FixedOptions},
+    message::{Message, MessageType},
+    property::{Property, PropertyUser},
+    publish::{PubAck, Publish, PublishBody, PublishOptions, SharedPublish},
+};
+use super::{decode, encode, variable_length};
+
+fn version() -> impl Strategy<Value = u8> {
+    select(vec![3u8, 4, 5])
+}
+
+fn string() -> impl Strategy<Value = String> {
+    // MQTT strings carry no control characters
+    "\\PC{0,24}"
+}
+
+fn reason() -> impl Strategy<Value = ReturnCode> {
+    select(vec![
+        ReturnCode::Success,
+        ReturnCode::NoMatchSubscription,
+        ReturnCode::UnspecifiedError,
+        ReturnCode::ImSpecificError,
+        ReturnCode::NotAuthorizedV5,
+        ReturnCode::TopicNameInvalid,
+        ReturnCode::PacketIdInUse,
+        ReturnCode::PacketIdentifierNotFound,
+        ReturnCode::QuotaExceeded,
+        ReturnCode::PayloadFormatInvalid,
+    ])
+}
+
+fn property(kind: u8, number: u32, text: String, data: Vec<u8>) -> Property {
+    match kind % 12 {
+        0 => Property::PayloadFormatIndicator(number as u8 & 1),
+        1 => Property::MessageExpiryInterval(number),
+        2 => Property::ContentType(text),
+        3 => Property::ResponseTopic(text),
+        4 => Property::CorrelationData(data),
+        5 => Property::SubscriptionIdentifier(number % 268_435_456),
+        6 => Property::SessionExpiryInterval(number),
+        7 => Property::ReceiveMaximum(number as u16),
+        8 => Property::TopicAlias(number as u16),
+        9 => Property::ReasonString(text),
+        10 => Property::AuthenticationData(data),
+        _ => Property::UserProperty(PropertyUser {
+            key: text.clone(),
+            value: text,
+        }),
+    }
+}
+
+fn encode_properties(properties: &[Property]) -> Bytes {
+    let mut body = BytesMut::new();
+    for property in properties {
+        body.extend_from_slice(&property.into_bytes());
+    }
+    let mut out = BytesMut::from(&variable_length(body.len())[..]);
+    out.extend_from_slice(&body);
+    out.freeze()
+}
+
+proptest! {
+    #[test]
+    fn test_publish_roundtrip(
+        version in version(),
+        (qos, dup, retain, packet_id) in (0u8..3, any::<bool>(), any::<bool>(), 1u16..),
+        topic in string(),
+        payload in vec(any::<u8>(), 0..512),
+        (format, expiry, content_type, response_topic, correlation) in (
+            proptest::option::of(0u8..2),
+            proptest::option::of(any::<u32>()),
+            proptest::option::of(string()),
+            proptest::option::of(string()),
+            proptest::option::of(vec(any::<u8>(), 0..32)),
+        ),
+        user_properties in vec((string(), string()), 0..4),
+    ) {
+        let version = MqttProtocolVersion::try_from(version).unwrap();
+        let qos = QoS::try_from(qos).unwrap();
+        let options = PublishOptions {
+            payload_format_indicator: format,
+            content_type: content_type.clone(),
+            response_topic: response_topic.clone(),
+            correlation_data: correlation.clone().map(Bytes::from),
+            ..Default::default()
+        }
+        .with_expiry(expiry);
+        let publish = Publish::new(
+            dup,
+            qos,
+            retain,
+            topic.clone(),
+            (qos != QoS::AtMostOnce).then_some(packet_id),
+            Bytes::from(payload.clone()),
+            user_properties
+                .iter()
+                .map(|(key, value)| PropertyUser { key: key.clone(), value: value.clone() })
+                .collect(),
+        )
+        .with_options(options);
+
+        // the same message delivered with a body shared between subscribers
+        let body = PublishBody {
+            topic: publish.topic.clone(),
+            payload: publish.payload.clone(),
+            user_properties: publish.user_properties.clone(),
+            options: publish.options.clone(),
+        };
+        let mut shared = SharedPublish::new(qos, retain, Arc::new(body));
+        shared.dup = dup;
+        shared.packet_id = publish.packet_id;
+        let shared = encode(Message::SharedPublish(shared), version);
+
+        let bytes = encode(Message::Publish(publish), version);
+        prop_assert_eq!(&shared, &bytes);
+        let Message::Publish(decoded) = decode(&bytes, version).unwrap() else {
+            panic!("not a PUBLISH");
+        };
+        prop_assert_eq!(decoded.qos, qos);
+        prop_assert_eq!(decoded.dup, dup);
+        prop_assert_eq!(decoded.retain, retain);
+        prop_assert_eq!(&decoded.topic, &topic);
+        prop_assert_eq!(decoded.payload.as_ref(), &payload[..]);
+        prop_assert_eq!(decoded.packet_id, (qos != QoS::AtMostOnce).then_some(packet_id));
+        if version == MqttProtocolVersion::V5 {
+            prop_assert_eq!(decoded.options.payload_format_indicator, format);
+            prop_assert_eq!(decoded.options.message_expiry_interval, expiry);
+            prop_assert_eq!(&decoded.options.content_type, &content_type);
+            prop_assert_eq!(&decoded.options.response_topic, &response_topic);
+            prop_assert_eq!(
+                decoded.options.correlation_data.as_deref(),
+                correlation.as_deref()
+            );
+            let decoded_users: Vec<(String, String)> = decoded
+                .user_properties
+                .iter()
+                .map(|p| (p.key.clone(), p.value.clone()))
+                .collect();
+            prop_assert_eq!(decoded_users, user_properties);
+        }
+        prop_assert_eq!(encode(Message::Publish(decoded), version), bytes);
+    }
+
+    #[test]
+    fn test_ack_roundtrip(version in version(), kind in 0u8..4, packet_id: u16, reason in reason()) {
+        let version = MqttProtocolVersion::try_from(version).unwrap();
+        let ack = PubAck::new(packet_id, reason);
+        let message = match kind {
+            0 => Message::PubAck(ack),
+            1 => Message::PubRec(ack),
+            2 => Message::PubRel(ack),
+            _ => Message::PubComp(ack),
+        };
+
+        let bytes = encode(message, version);
+        let (decoded_kind, decoded) = match decode(&bytes, version).unwrap() {
+            Message::PubAck(ack) => (0, ack),
+            Message::PubRec(ack) => (1, ack),
+            Message::PubRel(ack) => (2, ack),
+            Message::PubComp(ack) => (3, ack),
+            _ => panic!("not an acknowledgement"),
+        };
+        prop_assert_eq!(decoded_kind, kind);
+        prop_assert_eq!(decoded.packet_id, packet_id);
+        let expected = if version == MqttProtocolVersion::V5 { reason } else { ReturnCode::Success };
+        prop_assert_eq!(decoded.reason_code, expected);
+    }
+
+    #[test]
+    fn test_disconnect_roundtrip(version in version(), reason in reason()) {
+        let version = MqttProtocolVersion::try_from(version).unwrap();
+        let bytes = encode(Message::Disconnect(Disconnect::new(reason)), version);
+        let Message::Disconnect(decoded) = decode(&bytes, version).unwrap() else {
+            panic!("not a DISCONNECT");
+        };
+        let expected = if version == MqttProtocolVersion::V5 { reason } else { ReturnCode::Success };
+        prop_assert_eq!(decoded.reason, expected);
+        prop_assert_eq!(decoded.session_expiry_interval, None);
+    }
+
+    #[test]
+    fn test_properties_roundtrip(
+        properties in vec((any::<u8>(), any::<u32>(), string(), vec(any::<u8>(), 0..32)), 0..12),
+    ) {
+        let properties: Vec<Property> = properties
+            .into_iter()
+            .map(|(kind, number, text, data)| property(kind, number, text, data))
+            .collect();
+        let bytes = encode_properties(&properties);
+
+        let mut rdr = Cursor::new(bytes.clone());
+        let decoded = Property::try_from_properties(&mut rdr).unwrap();
+        prop_assert_eq!(rdr.position() as usize, bytes.len());
+        prop_assert_eq!(encode_properties(&decoded), bytes);
+    }
+
+    #[test]
+    fn test_remaining_length_roundtrip(
+        len in prop_oneof![0usize..200, 16_000usize..16_800, 2_097_000usize..2_097_300],
+        split in any::<prop::sample::Index>(),
+    ) {
+        let options = FixedOptions {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            msg_type: MessageType::Publish,
+            bytes: Bytes::from(vec![0u8; len]),
+        };
+        let bytes: Bytes = options.into();
+        prop_assert_eq!(bytes.len(), 1 + variable_length(len).len() + len);
+
+        // a packet split across reads waits for the rest
+        let split = split.index(bytes.len());
+        let mut src = BytesMut::from(&bytes[..split]);
+        prop_assert!(FixedHeaderCodec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&bytes[split..]);
+        let fixed = FixedHeaderCodec.decode(&mut src).unwrap().unwrap();
+        prop_assert_eq!(fixed.bytes.len(), len);
+        prop_assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_never_panics(
+        version in version(),
+        header in select(vec![0x10u8, 0x30, 0x32, 0x3d, 0x40, 0x50, 0x62, 0x70, 0x82, 0xa2, 0xc0, 0xe0]),
+        body in vec(any::<u8>(), 0..128),
+    ) {
+        let version = MqttProtocolVersion::try_from(version).unwrap();
+        let mut packet = vec![header];
+        packet.extend(variable_length(body.len()));
+        packet.extend(body);
+        let _ = decode(&packet, version);
+    }
+}
+
+#[test]
+fn test_ping_roundtrip() {
+    for version in [
+        MqttProtocolVersion::V3,
+        MqttProtocolVersion::V3_1_1,
+        MqttProtocolVersion::V5,
+    ] {
+        let bytes = encode(Message::PingReq, version);
+        assert_eq!(bytes.as_ref(), &[0xc0, 0x00]);
+        assert!(matches!(decode(&bytes, version), Ok(Message::PingReq)));
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use bytes::Bytes;
+
+use crate::mqtt::{
+    QoS,
+    protocol::{property::PropertyUser, publish::PublishOptions},
+    retain_trie::RetainedMessage,
+};
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const COMPACT_MIN_DEAD: usize = 1024;
+
+struct IndexEntry {
+    offset: u64,
+    len: u32,
+    expiry_at: Option<u64>,
+}
+
+/// Append-only on-disk log of retained messages.
+///
+/// Each record is `op, topic, [expiry_at, body]`. Only record headers are read on open,
+/// message bodies are loaded on demand. The log is rewritten once dead records outnumber live ones.
+pub struct RetainStore {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: File,
+    end: u64,
+    index: HashMap<String, IndexEntry>,
+    dead: usize,
+}
+
+impl RetainStore {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let (index, dead, end) = Self::scan(&file)?;
+        // drop a truncated tail left by a crash
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+        }
+
+        let mut store = RetainStore {
+            path: path.to_path_buf(),
+            reader: file.try_clone()?,
+            writer: BufWriter::new(file),
+            end,
+            index,
+            dead,
+        };
+        if store.dead > store.index.len() {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    fn scan(file: &File) -> io::Result<(HashMap<String, IndexEntry>, usize, u64)> {
+        let len = file.metadata()?.len();
+        let mut rdr = BufReader::new(file);
+        rdr.seek(SeekFrom::Start(0))?;
+
+        let mut index = HashMap::new();
+        let mut dead = 0;
+        let mut pos = 0u64;
+
+        while pos < len {
+            let Ok((op, topic, header_len)) = Self::read_header(&mut rdr) else {
+                break;
+            };
+            let mut record_end = pos + header_len;
+            let entry = if op == OP_PUT {
+                let Ok(expiry_at) = rdr.read_u64::<BigEndian>() else {
+                    break;
+                };
+                let Ok(body_len) = rdr.read_u32::<BigEndian>() else {
+                    break;
+                };
+                let offset = record_end + 12;
+                record_end = offset + body_len as u64;
+                if record_end > len {
+                    break;
+                }
+                rdr.seek_relative(body_len as i64)?;
+                Some(IndexEntry {
+                    offset,
+                    len: body_len,
+                    expiry_at: if expiry_at == 0 {
+                        None
+                    } else {
+                        Some(expiry_at)
+                    },
+                })
+            } else if op == OP_DELETE {
+                None
+            } else {
+                break;
+            };
+
+            if index.remove(&topic).is_some() {
+                dead += 1;
+            }
+            match entry {
+                Some(entry) => {
+                    index.insert(topic, entry);
+                }
+                None => dead += 1,
+            }
+            pos = record_end;
+        }
+
+        Ok((index, dead, pos))
+    }
+
+    fn read_header<R: Read>(rdr: &mut R) -> io::Result<(u8, String, u64)> {
+        let op = rdr.read_u8()?;
+        let topic_len = rdr.read_u16::<BigEndian>()?;
+        let mut topic = vec![0u8; topic_len as usize];
+        rdr.read_exact(&mut topic)?;
+        let topic =
+            String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((op, topic, 3 + topic_len as u64))
+    }
+
+    /// persisted topics with their expiry time, the messages are not loaded
+    pub fn topics(&self) -> impl Iterator<Item = (&String, Option<u64>)> {
+        self.index
+            .iter()
+            .map(|(topic, entry)| (topic, entry.expiry_at))
+    }
+
+    pub fn expiry_at(&self, topic: &str) -> Option<u64> {
+        self.index.get(topic).and_then(|entry| entry.expiry_at)
+    }
+
+    pub fn load(&mut self, topic: &str) -> io::Result<Option<RetainedMessage>> {
+        let Some(entry) = self.index.get(topic) else {
+            return Ok(None);
+        };
+        self.writer.flush()?;
+
+        let mut body = vec![0u8; entry.len as usize];
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        self.reader.read_exact(&mut body)?;
+        decode_body(topic, entry.expiry_at, &body).map(Some)
+    }
+
+    pub fn save(&mut self, message: &RetainedMessage) -> io::Result<()> {
+        let topic = message.topic.as_bytes();
+        if topic.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "topic too long",
+            ));
+        }
+        let body = encode_body(message)?;
+        let expiry_at = message.options.message_expiry_at;
+
+        self.writer.write_u8(OP_PUT)?;
+        self.writer.write_u16::<BigEndian>(topic.len() as u16)?;
+        self.writer.write_all(topic)?;
+        self.writer.write_u64::<BigEndian>(expiry_at.unwrap_or(0))?;
+        self.writer.write_u32::<BigEndian>(body.len() as u32)?;
+        self.writer.write_all(&body)?;
+        self.writer.flush()?;
+
+        let offset = self.end + 3 + topic.len() as u64 + 12;
+        self.end = offset + body.len() as u64;
+        if self
+            .index
+            .insert(
+                message.topic.clone(),
+                IndexEntry {
+                    offset,
+                    len: body.len() as u32,
+                    expiry_at,
+                },
+            )
+            .is_some()
+        {
+            self.dead += 1;
+        }
+        self.maybe_compact()
+    }
+
+    pub fn remove(&mut self, topic: &str) -> io::Result<()> {
+        if self.index.remove(topic).is_none() {
+            return Ok(());
+        }
+
+        self.writer.write_u8(OP_DELETE)?;
+        self.writer.write_u16::<BigEndian>(topic.len() as u16)?;
+        self.writer.write_all(topic.as_bytes())?;
+        self.writer.flush()?;
+
+        self.end += 3 + topic.len() as u64;
+        self.dead += 2;
+        self.maybe_compact()
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+
+    fn maybe_compact(&mut self) -> io::Result<()> {
+        if self.dead >= COMPACT_MIN_DEAD && self.dead > self.index.len() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let tmp_path = self.path.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut index = HashMap::with_capacity(self.index.len());
+        let mut end = 0u64;
+
+        for (topic, entry) in self.index.iter() {
+            let mut body = vec![0u8; entry.len as usize];
+            self.reader.seek(SeekFrom::Start(entry.offset))?;
+            self.reader.read_exact(&mut body)?;
+
+            writer.write_u8(OP_PUT)?;
+            writer.write_u16::<BigEndian>(topic.len() as u16)?;
+            writer.write_all(topic.as_bytes())?;
+            writer.write_u64::<BigEndian>(entry.expiry_at.unwrap_or(0))?;
+            writer.write_u32::<BigEndian>(entry.len)?;
+            writer.write_all(&body)?;
+
+            let offset = end + 3 + topic.len() as u64 + 12;
+            end = offset + entry.len as u64;
+            index.insert(
+                topic.clone(),
+                IndexEntry {
+                    offset,
+                    len: entry.len,
+                    expiry_at: entry.expiry_at,
+                },
+            );
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.reader = file.try_clone()?;
+        self.writer = BufWriter::new(file);
+        self.index = index;
+        self.end = end;
+        self.dead = 0;
+        Ok(())
+    }
+}
+
+pub(crate) fn write_str<W: Write>(w: &mut W, v: &str) -> io::Result<()> {
+    w.write_u16::<BigEndian>(v.len() as u16)?;
+    w.write_all(v.as_bytes())
+}
+
+pub(crate) fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = r.read_u16::<BigEndian>()?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub(crate) fn write_opt_str<W: Write>(w: &mut W, v: &Option<String>) -> io::Result<()> {
+    match v {
+        Some(v) => {
+            w.write_u8(1)?;
+            write_str(w, v)
+        }
+        None => w.write_u8(0),
+    }
+}
+
+pub(crate) fn read_opt_str<R: Read>(r: &mut R) -> io::Result<Option<String>> {
+    if r.read_u8()? == 1 {
+        Ok(Some(read_str(r)?))
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn encode_body(message: &RetainedMessage) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(message.payload.len() + 32);
+    let options = &message.options;
+
+    buf.write_u8(message.qos as u8)?;
+    buf.write_u32::<BigEndian>(message.payload.len() as u32)?;
+    buf.write_all(&message.payload)?;
+
+    buf.write_u16::<BigEndian>(message.user_properties.len() as u16)?;
+    for prop in message.user_properties.iter() {
+        write_str(&mut buf, &prop.key)?;
+        write_str(&mut buf, &prop.value)?;
+    }
+
+    match options.payload_format_indicator {
+        Some(v) => {
+            buf.write_u8(1)?;
+            buf.write_u8(v)?;
+        }
+        None => buf.write_u8(0)?,
+    }
+    match options.message_expiry_interval {
+        Some(v) => {
+            buf.write_u8(1)?;
+            buf.write_u32::<BigEndian>(v)?;
+        }
+        None => buf.write_u8(0)?,
+    }
+    write_opt_str(&mut buf, &options.content_type)?;
+    write_opt_str(&mut buf, &options.response_topic)?;
+    match &options.correlation_data {
+        Some(v) => {
+            buf.write_u8(1)?;
+            buf.write_u16::<BigEndian>(v.len() as u16)?;
+            buf.write_all(v)?;
+        }
+        None => buf.write_u8(0)?,
+    }
+    write_opt_str(&mut buf, &message.client_id)?;
+    Ok(buf)
+}
+
+pub(crate) fn decode_body(
+    topic: &str,
+    expiry_at: Option<u64>,
+    body: &[u8],
+) -> io::Result<RetainedMessage> {
+    let mut rdr = io::Cursor::new(body);
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "invalid qos");
+
+    let qos = QoS::try_from(rdr.read_u8()?).map_err(invalid)?;
+    let payload_len = rdr.read_u32::<BigEndian>()?;
+    let mut payload = vec![0u8; payload_len as usize];
+    rdr.read_exact(&mut payload)?;
+
+    let count = rdr.read_u16::<BigEndian>()?;
+    let mut user_properties = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        user_properties.push(PropertyUser {
+            key: read_str(&mut rdr)?,
+            value: read_str(&mut rdr)?,
+        });
+    }
+
+    let mut options = PublishOptions::default();
+    if rdr.read_u8()? == 1 {
+        options.payload_format_indicator = Some(rdr.read_u8()?);
+    }
+    if rdr.read_u8()? == 1 {
+        options.message_expiry_interval = Some(rdr.read_u32::<BigEndian>()?);
+    }
+    options.message_expiry_at = expiry_at;
+    options.content_type = read_opt_str(&mut rdr)?;
+    options.response_topic = read_opt_str(&mut rdr)?;
+    if rdr.read_u8()? == 1 {
+        let len = rdr.read_u16::<BigEndian>()?;
+        let mut data = vec![0u8; len as usize];
+        rdr.read_exact(&mut data)?;
+        options.correlation_data = Some(Bytes::from(data));
+    }
+    // bodies written before the publisher was recorded end here
+    let client_id = if (rdr.position() as usize) < body.len() {
+        read_opt_str(&mut rdr)?
+    } else {
+        None
+    };
+
+    Ok(RetainedMessage {
+        topic: topic.to_string(),
+        qos,
+        payload: Bytes::from(payload),
+        user_properties,
+        options,
+        client_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetainStore, decode_body, encode_body};
+    use crate::mqtt::{QoS, protocol::publish::PublishOptions, retain_trie::RetainedMessage};
+
+    fn msg(topic: &str, payload: &'static str) -> RetainedMessage {
+        RetainedMessage {
+            qos: QoS::AtLeastOnce,
+            topic: topic.to_string(),
+            payload: payload.into(),
+            user_properties: vec![],
+            options: PublishOptions::default(),
+            client_id: Some("c1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = std::env::temp_dir().join(format!("axonmq-retain-{}.log", uuid::Uuid::new_v4()));
+
+        let mut store = RetainStore::open(&path).unwrap();
+        store.save(&msg("a/b", "msg1")).unwrap();
+        store.save(&msg("a/c", "msg2")).unwrap();
+        store.save(&msg("a/b", "msg3")).unwrap();
+        store.remove("a/c").unwrap();
+        drop(store);
+
+        let mut store = RetainStore::open(&path).unwrap();
+        assert_eq!(store.topics().count(), 1);
+        assert!(store.load("a/c").unwrap().is_none());
+        let loaded = store.load("a/b").unwrap().unwrap();
+        assert_eq!(loaded.payload.as_ref(), b"msg3");
+        assert_eq!(loaded.qos, QoS::AtLeastOnce);
+        assert_eq!(loaded.client_id.as_deref(), Some("c1"));
+
+        std::fs::remove_file(&path).ok();
+
+        // bodies persisted before the publisher was recorded
+        let mut old = msg("a/b", "msg4");
+        old.client_id = None;
+        let mut body = encode_body(&old).unwrap();
+        body.pop();
+        let loaded = decode_body("a/b", None, &body).unwrap();
+        assert_eq!(loaded.payload.as_ref(), b"msg4");
+        assert!(loaded.client_id.is_none());
+    }
+}
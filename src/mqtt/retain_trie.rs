@@ -1,12 +1,19 @@
-use std::collections::{BTreeSet, HashMap};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use bytes::Bytes;
+use tracing::warn;
 
 use crate::mqtt::{
     QoS,
     protocol::{property::PropertyUser, publish::PublishOptions},
+    retain_store::RetainStore,
 };
 
+const RETAIN_SHARDS: usize = 16;
+
 #[derive(Clone)]
 pub struct RetainedMessage {
     pub topic: String,
@@ -14,24 +21,59 @@ pub struct RetainedMessage {
     pub payload: Bytes,
     pub user_properties: Vec<PropertyUser>,
     pub options: PublishOptions,
+    // the publishing client, None when unknown, for No Local on retained replay
+    pub client_id: Option<String>,
 }
 
 #[derive(Default, Clone)]
 pub struct RetainedTrieNode {
     pub message: Option<RetainedMessage>,
+    // persisted in the store but not loaded yet
+    pub stored: bool,
+    // expiry of the message, loaded or persisted, what the expiry heap entries are checked against
+    expiry_at: Option<u64>,
+    // loaded and persisted messages in the subtree, this node included, lookups skip the
+    // subtrees without any of the kind they look for
+    loaded_count: usize,
+    stored_count: usize,
     pub children: HashMap<String, RetainedTrieNode>,
 }
 
 impl RetainedTrieNode {
     fn is_empty(&self) -> bool {
-        self.message.is_none() && self.children.is_empty()
+        self.loaded_count == 0 && self.stored_count == 0
+    }
+
+    fn loaded(&self) -> usize {
+        self.loaded_count
+    }
+
+    fn stored(&self) -> usize {
+        self.stored_count
+    }
+
+    fn retained(&self) -> usize {
+        self.loaded_count + self.stored_count
     }
 }
 
+// stale heap entries are only dropped when popped, the heap is rebuilt when they outnumber
+// the retained messages by this factor
+const EXPIRY_HEAP_SLACK: usize = 2;
+const MIN_EXPIRY_HEAP_COMPACT: usize = 1024;
+
+// whether a node is one looked for, and how many of the subtree are
+type Select = (
+    fn(&RetainedTrieNode) -> bool,
+    fn(&RetainedTrieNode) -> usize,
+);
+
 #[derive(Default)]
 pub struct RetainedTrie {
     root: RetainedTrieNode,
-    expiry_index: BTreeSet<(u64, String)>, // (expire_at, topic)
+    // (expire_at, topic), soonest first, entries of topics removed or set again since are
+    // skipped when popped rather than searched for on every change
+    expiry_heap: BinaryHeap<Reverse<(u64, String)>>,
 }
 
 impl RetainedTrie {
@@ -39,57 +81,203 @@ impl RetainedTrie {
         Self::default()
     }
 
+    /// register a message persisted in the store, it is loaded lazily on first match
+    fn insert_stored(&mut self, topic: &str, expiry_at: Option<u64>) {
+        Self::update(&mut self.root, topic, |node| {
+            node.stored = true;
+            node.expiry_at = expiry_at;
+        });
+        self.index_expiry(topic, expiry_at);
+    }
+
     pub fn insert(&mut self, topic: &str, message: RetainedMessage) {
-        if let Some(old_msg) = self.get_message_mut(topic) {
-            let old_expiry = old_msg.options.message_expiry_at;
-            *old_msg = message;
-            if let Some(old_expire) = old_expiry {
-                self.expiry_index.remove(&(old_expire, topic.to_string()));
-            }
-        } else {
-            let mut current_node = &mut self.root;
-            for part in topic.split('/') {
-                current_node = current_node.children.entry(part.to_string()).or_default();
-            }
-            current_node.message = Some(message);
+        let expiry_at = message.options.message_expiry_at;
+        Self::update(&mut self.root, topic, |node| {
+            node.message = Some(message);
+            node.stored = false;
+            node.expiry_at = expiry_at;
+        });
+        self.index_expiry(topic, expiry_at);
+    }
+
+    pub fn remove(&mut self, topic: &str) {
+        Self::update(&mut self.root, topic, |node| {
+            node.message = None;
+            node.stored = false;
+            node.expiry_at = None;
+        });
+    }
+
+    /// applies `f` to the node of the topic, keeps the counts of the nodes on the path and
+    /// prunes the ones left empty
+    fn update(node: &mut RetainedTrieNode, topic: &str, f: impl FnOnce(&mut RetainedTrieNode)) {
+        let parts: Vec<&str> = topic.split('/').collect();
+        Self::recursive_update(node, &parts, f);
+    }
+
+    fn recursive_update(
+        node: &mut RetainedTrieNode,
+        topic_parts: &[&str],
+        f: impl FnOnce(&mut RetainedTrieNode),
+    ) -> (isize, isize) {
+        let (loaded, stored) =
+            if let Some((current_part, remaining_parts)) = topic_parts.split_first() {
+                let child = node.children.entry(current_part.to_string()).or_default();
+                let delta = Self::recursive_update(child, remaining_parts, f);
+                if child.is_empty() {
+                    node.children.remove(*current_part);
+                }
+                delta
+            } else {
+                let before = (node.message.is_some() as isize, node.stored as isize);
+                f(node);
+                (
+                    node.message.is_some() as isize - before.0,
+                    node.stored as isize - before.1,
+                )
+            };
+        node.loaded_count = node.loaded_count.wrapping_add_signed(loaded);
+        node.stored_count = node.stored_count.wrapping_add_signed(stored);
+        (loaded, stored)
+    }
+
+    fn index_expiry(&mut self, topic: &str, expiry_at: Option<u64>) {
+        let Some(expire_at) = expiry_at else {
+            return;
+        };
+        self.expiry_heap
+            .push(Reverse((expire_at, topic.to_string())));
+        if self.expiry_heap.len()
+            > (self.root.retained() * EXPIRY_HEAP_SLACK).max(MIN_EXPIRY_HEAP_COMPACT)
+        {
+            self.compact_expiry_heap();
+        }
+    }
+
+    /// drop the heap entries no longer matching the expiry of their topic
+    fn compact_expiry_heap(&mut self) {
+        let mut entries = std::mem::take(&mut self.expiry_heap).into_vec();
+        entries.retain(|Reverse((expire_at, topic))| self.expiry_of(topic) == Some(*expire_at));
+        entries.sort_unstable();
+        entries.dedup();
+        self.expiry_heap = BinaryHeap::from(entries);
+    }
+
+    fn node(&self, topic: &str) -> Option<&RetainedTrieNode> {
+        let mut current_node = &self.root;
+        for part in topic.split('/') {
+            current_node = current_node.children.get(part)?;
         }
-        let msg_ref = self.get_message(topic).unwrap();
-        if let Some(expire_at) = msg_ref.options.message_expiry_at {
-            self.expiry_index.insert((expire_at, topic.to_string()));
+        Some(current_node)
+    }
+
+    fn expiry_of(&self, topic: &str) -> Option<u64> {
+        self.node(topic).and_then(|node| node.expiry_at)
+    }
+
+    /// topics of persisted messages matching the filter which are not loaded yet
+    fn stored_matches(&self, filter: &str) -> Vec<String> {
+        self.topics_matching(filter, |node| node.stored, RetainedTrieNode::stored)
+    }
+
+    /// topics of loaded and persisted messages matching the filter
+    fn matching_topics(&self, filter: &str) -> Vec<String> {
+        self.topics_matching(
+            filter,
+            |node| node.stored || node.message.is_some(),
+            RetainedTrieNode::retained,
+        )
+    }
+
+    /// `count` is how many nodes of the subtree `pred` holds for
+    fn topics_matching(
+        &self,
+        filter: &str,
+        pred: fn(&RetainedTrieNode) -> bool,
+        count: fn(&RetainedTrieNode) -> usize,
+    ) -> Vec<String> {
+        let mut topics = Vec::new();
+        if count(&self.root) == 0 {
+            return topics;
         }
+        let filter_parts: Vec<&str> = filter.split('/').collect();
+        Self::recursive_find_topics(
+            &self.root,
+            &filter_parts,
+            (pred, count),
+            &mut vec![],
+            &mut topics,
+        );
+        topics
     }
 
-    fn recursive_remove(node: &mut RetainedTrieNode, topic_parts: &[&str]) -> bool {
-        if let Some((current_part, remaining_parts)) = topic_parts.split_first() {
-            let part_string = current_part.to_string();
-            let mut child_became_empty = false;
+    fn recursive_find_topics<'a>(
+        current_node: &'a RetainedTrieNode,
+        filter_parts: &[&str],
+        select: Select,
+        path: &mut Vec<&'a str>,
+        results: &mut Vec<String>,
+    ) {
+        let (pred, count) = select;
+        if let Some((current_filter, remaining_filter)) = filter_parts.split_first() {
+            if *current_filter == "#" {
+                Self::collect_all_topics(current_node, select, path, results);
+                return;
+            }
 
-            if let Some(mut child) = node.children.get_mut(&part_string) {
-                if Self::recursive_remove(&mut child, remaining_parts) {
-                    child_became_empty = true;
+            if *current_filter == "+" {
+                for (part, child_node) in current_node.children.iter() {
+                    if count(child_node) == 0 {
+                        continue;
+                    }
+                    path.push(part);
+                    Self::recursive_find_topics(
+                        child_node,
+                        remaining_filter,
+                        select,
+                        path,
+                        results,
+                    );
+                    path.pop();
                 }
+            } else if let Some((part, child_node)) =
+                current_node.children.get_key_value(*current_filter)
+                && count(child_node) > 0
+            {
+                path.push(part);
+                Self::recursive_find_topics(child_node, remaining_filter, select, path, results);
+                path.pop();
             }
-            if child_became_empty {
-                node.children.remove(&part_string);
-            }
-        } else {
-            node.message = None;
+        } else if pred(current_node) {
+            results.push(path.join("/"));
         }
-        node.is_empty()
     }
 
-    pub fn remove(&mut self, topic: &str) {
-        if let Some(msg) = self.get_message(topic) {
-            if let Some(expiry) = msg.options.message_expiry_at {
-                self.expiry_index.remove(&(expiry, topic.to_string()));
+    fn collect_all_topics<'a>(
+        node: &'a RetainedTrieNode,
+        select: Select,
+        path: &mut Vec<&'a str>,
+        results: &mut Vec<String>,
+    ) {
+        let (pred, count) = select;
+        if pred(node) {
+            results.push(path.join("/"));
+        }
+        for (part, child) in node.children.iter() {
+            if count(child) == 0 {
+                continue;
             }
+            path.push(part);
+            Self::collect_all_topics(child, select, path, results);
+            path.pop();
         }
-        let parts: Vec<&str> = topic.split('/').collect();
-        Self::recursive_remove(&mut self.root, &parts);
     }
 
     pub fn find_matches_for_filter(&self, filter: &str) -> Vec<&RetainedMessage> {
         let mut results = Vec::new();
+        if self.root.loaded() == 0 {
+            return results;
+        }
         let filter_parts: Vec<&str> = filter.split('/').collect();
         self.recursive_find(&self.root, &filter_parts, &mut results);
         results
@@ -109,10 +297,14 @@ impl RetainedTrie {
 
             if *current_filter == "+" {
                 for child_node in current_node.children.values() {
-                    self.recursive_find(child_node, remaining_filter, results);
+                    if child_node.loaded() > 0 {
+                        self.recursive_find(child_node, remaining_filter, results);
+                    }
                 }
             } else {
-                if let Some(child_node) = current_node.children.get(*current_filter) {
+                if let Some(child_node) = current_node.children.get(*current_filter)
+                    && child_node.loaded() > 0
+                {
                     self.recursive_find(child_node, remaining_filter, results);
                 }
             }
@@ -132,49 +324,216 @@ impl RetainedTrie {
             results.push(msg);
         }
         for child in node.children.values() {
-            self.collect_all_messages(child, results);
+            if child.loaded() > 0 {
+                self.collect_all_messages(child, results);
+            }
         }
     }
 
-    pub fn get_message(&self, topic: &str) -> Option<&RetainedMessage> {
-        let mut current_node = &self.root;
-        for part in topic.split('/') {
-            if let Some(node) = current_node.children.get(part) {
-                current_node = node;
-            } else {
-                return None;
+    /// removes the messages expired at `now`, loaded or persisted, returns their topics
+    pub fn purge_expired(&mut self, now: u64) -> Vec<String> {
+        let mut expired_topics = Vec::new();
+        while let Some(Reverse((expire_at, _))) = self.expiry_heap.peek()
+            && *expire_at <= now
+        {
+            let Some(Reverse((expire_at, topic))) = self.expiry_heap.pop() else {
+                break;
+            };
+            if self.expiry_of(&topic) == Some(expire_at) {
+                self.remove(&topic);
+                expired_topics.push(topic);
             }
         }
-        current_node.message.as_ref()
+        expired_topics
     }
+}
 
-    fn get_message_mut(&mut self, topic: &str) -> Option<&mut RetainedMessage> {
-        let mut current_node = &mut self.root;
-        for part in topic.split('/') {
-            current_node = current_node.children.entry(part.to_string()).or_default();
+/// Retained messages sharded by the first topic level, shared between the broker task,
+/// which applies updates, and the listener tasks, which run the lookups on subscribe.
+///
+/// A filter starting with a wildcard visits every shard one at a time, so a broad lookup
+/// never holds more than one shard lock.
+#[derive(Clone)]
+pub struct SharedRetainedTrie {
+    shards: Arc<[RwLock<RetainedTrie>]>,
+    store: Option<Arc<Mutex<RetainStore>>>,
+}
+
+impl SharedRetainedTrie {
+    pub fn new() -> Self {
+        SharedRetainedTrie {
+            shards: (0..RETAIN_SHARDS)
+                .map(|_| RwLock::new(RetainedTrie::new()))
+                .collect(),
+            store: None,
+        }
+    }
+
+    /// build the shards from a persistent store, messages are loaded lazily on first match
+    pub fn with_store(store: RetainStore) -> Self {
+        let mut trie = Self::new();
+        for (topic, expiry_at) in store.topics() {
+            trie.write(Self::shard_index(topic))
+                .insert_stored(topic, expiry_at);
+        }
+        trie.store = Some(Arc::new(Mutex::new(store)));
+        trie
+    }
+
+    fn shard_index(topic: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        topic
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        hasher.finish() as usize % RETAIN_SHARDS
+    }
+
+    fn shards_for_filter(filter: &str) -> Vec<usize> {
+        match filter.split('/').next() {
+            Some("#") | Some("+") => (0..RETAIN_SHARDS).collect(),
+            _ => vec![Self::shard_index(filter)],
+        }
+    }
+
+    fn read(&self, index: usize) -> RwLockReadGuard<'_, RetainedTrie> {
+        self.shards[index].read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, index: usize) -> RwLockWriteGuard<'_, RetainedTrie> {
+        self.shards[index]
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_store(store: &Mutex<RetainStore>) -> MutexGuard<'_, RetainStore> {
+        store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// flush the persisted messages to disk, nothing to do when they are kept in memory
+    pub fn sync(&self) -> std::io::Result<()> {
+        match self.store.as_deref() {
+            Some(store) => Self::lock_store(store).sync(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn insert(&self, topic: &str, message: RetainedMessage) {
+        let index = Self::shard_index(topic);
+        // the store is always locked before a shard
+        let store = self.store.as_deref().map(Self::lock_store);
+        let mut shard = self.write(index);
+        if let Some(mut store) = store
+            && let Err(e) = store.save(&message)
+        {
+            warn!("failed to persist retained message {}: {}", topic, e);
         }
-        current_node.message.as_mut()
+        shard.insert(topic, message);
     }
 
-    pub fn purge_expired(&mut self) {
-        let expired_topics: Vec<String> = self
-            .expiry_index
-            .iter()
-            .take_while(|(expiry, _)| *expiry <= coarsetime::Clock::now_since_epoch().as_secs())
-            .map(|(_, topic)| topic.clone())
-            .collect();
+    pub fn remove(&self, topic: &str) {
+        let index = Self::shard_index(topic);
+        let store = self.store.as_deref().map(Self::lock_store);
+        let mut shard = self.write(index);
+        if let Some(mut store) = store
+            && let Err(e) = store.remove(topic)
+        {
+            warn!(
+                "failed to remove persisted retained message {}: {}",
+                topic, e
+            );
+        }
+        shard.remove(topic);
+    }
 
-        if !expired_topics.is_empty() {
-            for topic in expired_topics {
+    /// remove the messages matching the filter, returns how many were removed
+    pub fn remove_matches(&self, filter: &str) -> usize {
+        let mut removed = 0;
+        for index in Self::shards_for_filter(filter) {
+            let topics = self.read(index).matching_topics(filter);
+            removed += topics.len();
+            for topic in topics {
                 self.remove(&topic);
             }
         }
+        removed
+    }
+
+    /// collect the messages matching the filter, loading persisted ones from the store.
+    /// may block on disk reads, run it on a blocking thread.
+    pub fn find_matches_for_filter(&self, filter: &str) -> Vec<RetainedMessage> {
+        let mut results = Vec::new();
+        for index in Self::shards_for_filter(filter) {
+            if let Some(store) = self.store.as_deref()
+                && !self.read(index).stored_matches(filter).is_empty()
+            {
+                self.load_stored(store, index, filter);
+            }
+            results.extend(
+                self.read(index)
+                    .find_matches_for_filter(filter)
+                    .into_iter()
+                    .cloned(),
+            );
+        }
+        results
+    }
+
+    fn load_stored(&self, store: &Mutex<RetainStore>, index: usize, filter: &str) {
+        let mut store = Self::lock_store(store);
+        let mut shard = self.write(index);
+
+        let now = coarsetime::Clock::now_since_epoch().as_secs();
+        // collected again under the write lock, another lookup may have loaded them already
+        for topic in shard.stored_matches(filter) {
+            match store.load(&topic) {
+                Ok(Some(message))
+                    if message
+                        .options
+                        .message_expiry_at
+                        .is_none_or(|expiry_at| expiry_at > now) =>
+                {
+                    shard.insert(&topic, message);
+                }
+                result => {
+                    if let Err(e) = result {
+                        warn!("failed to load retained message {}: {}", topic, e);
+                    }
+                    if let Err(e) = store.remove(&topic) {
+                        warn!(
+                            "failed to remove persisted retained message {}: {}",
+                            topic, e
+                        );
+                    }
+                    shard.remove(&topic);
+                }
+            }
+        }
+    }
+
+    pub fn purge_expired(&self) {
+        let now = coarsetime::Clock::now_since_epoch().as_secs();
+        for index in 0..RETAIN_SHARDS {
+            let store = self.store.as_deref().map(Self::lock_store);
+            let expired_topics = self.write(index).purge_expired(now);
+            if let Some(mut store) = store {
+                for topic in expired_topics {
+                    if let Err(e) = store.remove(&topic) {
+                        warn!(
+                            "failed to remove persisted retained message {}: {}",
+                            topic, e
+                        );
+                    }
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{RetainedMessage, RetainedTrie};
+    use super::{RetainedMessage, RetainedTrie, SharedRetainedTrie};
     use crate::mqtt::{QoS, protocol::publish::PublishOptions};
 
     fn msg(message: &'static str) -> RetainedMessage {
@@ -184,6 +543,7 @@ mod tests {
             payload: message.into(),
             user_properties: vec![],
             options: PublishOptions::default(),
+            client_id: None,
         }
     }
 
@@ -232,4 +592,55 @@ mod tests {
         let matches = trie.find_matches_for_filter("a/#");
         assert!(matches.is_empty());
     }
+
+    #[test]
+    fn test_shared_find_matches() {
+        let trie = SharedRetainedTrie::new();
+        trie.insert("a/b", msg("msg1"));
+        trie.insert("x/b", msg("msg2"));
+        trie.insert("y/c", msg("msg3"));
+
+        assert_eq!(trie.find_matches_for_filter("#").len(), 3);
+        assert_eq!(trie.find_matches_for_filter("+/b").len(), 2);
+        assert_eq!(trie.find_matches_for_filter("a/#").len(), 1);
+
+        trie.remove("x/b");
+        assert_eq!(trie.find_matches_for_filter("+/b").len(), 1);
+
+        assert_eq!(trie.remove_matches("+/#"), 2);
+        assert!(trie.find_matches_for_filter("#").is_empty());
+    }
+
+    #[test]
+    fn test_counts_and_expiry() {
+        let expiring = |expiry_at: u64| {
+            let mut m = msg("msg");
+            m.options.message_expiry_at = Some(expiry_at);
+            m
+        };
+        let mut trie = RetainedTrie::new();
+        trie.insert("a/b/c", expiring(100));
+        trie.insert("a/b/d", msg("msg"));
+        trie.insert_stored("a/x", Some(50));
+        trie.insert_stored("z", None);
+        assert_eq!((trie.root.loaded(), trie.root.stored()), (2, 2));
+        assert_eq!(trie.root.children["a"].stored(), 1);
+
+        // the persisted one gets loaded, a later expiry replaces the first one
+        trie.insert("a/x", expiring(50));
+        trie.insert("a/b/c", expiring(300));
+        assert_eq!((trie.root.loaded(), trie.root.stored()), (3, 1));
+        assert!(trie.stored_matches("a/#").is_empty());
+        assert_eq!(trie.stored_matches("#"), ["z"]);
+
+        assert!(trie.purge_expired(49).is_empty());
+        assert_eq!(trie.purge_expired(100), ["a/x"]);
+        assert_eq!(trie.find_matches_for_filter("a/+/c").len(), 1);
+        assert_eq!(trie.purge_expired(300), ["a/b/c"]);
+        assert!(trie.expiry_heap.is_empty());
+
+        trie.remove("a/b/d");
+        trie.remove("z");
+        assert!(trie.root.is_empty() && trie.root.children.is_empty());
+    }
 }
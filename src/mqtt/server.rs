@@ -1,44 +1,90 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
 
-use tokio::{sync::mpsc, task, time};
-use tracing::{debug, info};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task, time,
+};
+use tracing::{debug, info, warn};
 
 use crate::operator::sink::local::LocalClientSink;
 use crate::{
-    CONFIG, mqtt::helper::ClientHelper, operator::helper::Helper as OperatorHelper,
+    CONFIG,
+    cluster::replicated,
+    config::{SessionTakeover, SlowConsumerPolicy},
+    hook,
+    mqtt::helper::ClientHelper,
+    operator::helper::Helper as OperatorHelper,
     utils as g_utils,
 };
 
 use super::{
-    MqttProtocolVersion,
+    MqttProtocolVersion, QoS, ban,
     code::ReturnCode,
-    command::{BrokerAck, BrokerCommand, ClientCommand},
+    command::{
+        BrokerAck, BrokerCommand, ClientCommand, ClientDetail, ClientInfo, ClientSession,
+        GroupInfo, KickError, RetainDelivery, SubscriptionInfo,
+    },
+    group::ClientGroups,
     helper::BrokerHelper,
-    listener::store::Store,
+    listener::{store::Store, tcp::PeerCertificate},
+    offline_queue::{OfflineQueue, Overflow},
     protocol::{
         conn::{ConnAck, ConnectOptions},
         subscribe::{SubAck, SubscribeOption, UnsubAck},
         will::Will,
     },
-    retain_trie::{RetainedMessage, RetainedTrie},
-    utils,
+    retain_store::RetainStore,
+    retain_trie::{RetainedMessage, SharedRetainedTrie},
+    slow_consumer, utils,
 };
 
+fn max_sessions() -> usize {
+    CONFIG
+        .get()
+        .unwrap()
+        .mqtt
+        .settings
+        .max_sessions
+        .unwrap_or(0)
+}
+
 pub struct Client {
     client_id: String,
     version: MqttProtocolVersion,
 
     connected: bool,
+    connected_tm: u64,
     disconnected_tm: u64,
 
     clear_start: bool,
     subscribes: HashMap<String, SubscribeOption>,
     will: Option<Will>,
+    // the delayed will of a disconnected client, aborted when it reconnects in time
+    will_task: Option<task::AbortHandle>,
 
     client_helper: ClientHelper,
     store: Option<Store>,
 
     options: ConnectOptions,
+    peer_cert: Option<PeerCertificate>,
+    server_name: Option<String>,
+    peer_addr: SocketAddr,
+    groups: BTreeSet<String>,
+    // messages which found the queue full since the client connected
+    slow_consumer: u64,
+    slow_disconnecting: bool,
+}
+
+/// the state of the broker loop a command reads and updates
+struct BrokerState<'a> {
+    store_clients: &'a mut HashMap<String, Client>,
+    clean_clients: &'a mut HashMap<String, Client>,
+    store_msgs: &'a mut HashMap<String, OfflineQueue>,
+    retain_trie: &'a SharedRetainedTrie,
+    groups: &'a mut ClientGroups,
+    // what a client connecting with the identifier of a connected one gets
+    session_takeover: SessionTakeover,
 }
 
 pub struct Broker {
@@ -48,7 +94,7 @@ pub struct Broker {
     store_clients: Option<HashMap<String, Client>>,
     clean_clients: Option<HashMap<String, Client>>,
 
-    retain_trie: Option<RetainedTrie>,
+    retain_trie: SharedRetainedTrie,
 }
 
 impl Broker {
@@ -60,139 +106,433 @@ impl Broker {
             broker_rx: Some(broker_rx),
             store_clients: Some(HashMap::new()),
             clean_clients: Some(HashMap::new()),
-            retain_trie: Some(RetainedTrie::new()),
+            retain_trie: Self::open_retain_trie(),
+        }
+    }
+
+    fn open_retain_trie() -> SharedRetainedTrie {
+        let Some(path) = CONFIG
+            .get()
+            .unwrap()
+            .mqtt
+            .settings
+            .retain_store_path
+            .as_ref()
+        else {
+            return SharedRetainedTrie::new();
+        };
+        match RetainStore::open(std::path::Path::new(path)) {
+            Ok(store) => {
+                info!(
+                    "retained message store {} opened, {} messages",
+                    path,
+                    store.topics().count()
+                );
+                SharedRetainedTrie::with_store(store)
+            }
+            Err(e) => {
+                warn!(
+                    "failed to open retained message store {}: {}, retained messages kept in memory only",
+                    path, e
+                );
+                SharedRetainedTrie::new()
+            }
         }
     }
 
     pub fn get_helper(&self) -> BrokerHelper {
         BrokerHelper {
             broker_tx: self.broker_tx.clone(),
+            retain_trie: self.retain_trie.clone(),
         }
     }
 
-    fn prepare_will_message(broker_helper: BrokerHelper, client_id: String, will: Will) {
+    /// publishes the will after `delay` seconds, MQTT 5 publishes it when the Will Delay
+    /// Interval passes or the session ends, whichever comes first
+    fn prepare_will_message(
+        broker_helper: BrokerHelper,
+        client_id: String,
+        server_name: Option<String>,
+        will: Will,
+        delay: u32,
+    ) -> task::AbortHandle {
         task::spawn(async move {
-            if let Some(delay_interval) = will.options.will_delay_interval {
-                if delay_interval > 0 {
-                    time::sleep(time::Duration::from_secs(delay_interval as u64)).await;
-                }
+            if delay > 0 {
+                time::sleep(time::Duration::from_secs(delay as u64)).await;
             }
             broker_helper
-                .will_publish(
-                    client_id.as_str(),
-                    will.topic,
-                    will.payload,
-                    will.qos,
-                    will.retain,
-                    will.user_properties,
-                    will.options.options,
-                )
+                .will_publish(client_id.as_str(), server_name, will)
                 .await
                 .ok();
+        })
+        .abort_handle()
+    }
+
+    /// the session of a disconnected client ended, a will still waiting for its delay is due now
+    fn end_session_will(client: &mut Client, broker_helper: &BrokerHelper) {
+        if let Some(will_task) = client.will_task.take()
+            && !will_task.is_finished()
+        {
+            will_task.abort();
+            if let Some(will) = client.will.take() {
+                Self::prepare_will_message(
+                    broker_helper.clone(),
+                    client.client_id.clone(),
+                    client.server_name.clone(),
+                    will,
+                    0,
+                );
+            }
+        }
+    }
+
+    /// evict the oldest disconnected sessions when persisted sessions exceed `max_sessions`,
+    /// 0 for no limit
+    fn evict_sessions(
+        store_clients: &mut HashMap<String, Client>,
+        max_sessions: usize,
+        broker_helper: &BrokerHelper,
+    ) -> Vec<String> {
+        if max_sessions == 0 || store_clients.len() <= max_sessions {
+            return vec![];
+        }
+
+        let mut disconnected: Vec<(u64, String)> = store_clients
+            .values()
+            .filter(|client| !client.connected)
+            .map(|client| (client.disconnected_tm, client.client_id.clone()))
+            .collect();
+        disconnected.sort_unstable();
+
+        let evict_ids: Vec<String> = disconnected
+            .into_iter()
+            .take(store_clients.len() - max_sessions)
+            .map(|(_, client_id)| client_id)
+            .collect();
+        for client_id in evict_ids.iter() {
+            if let Some(mut client) = store_clients.remove(client_id) {
+                Self::end_session_will(&mut client, broker_helper);
+            }
+        }
+        warn!(
+            "session limit {} exceeded, evicted {} oldest disconnected sessions",
+            max_sessions,
+            evict_ids.len()
+        );
+        evict_ids
+    }
+
+    fn client_info(client: &Client) -> ClientInfo {
+        ClientInfo {
+            client_id: client.client_id.clone(),
+            version: client.version.to_string(),
+            connected: client.connected,
+            clean_start: client.clear_start,
+            session_expiry_interval: client.options.session_expiry_interval,
+            subscriptions: client.subscribes.len(),
+            connected_at: client.connected_tm,
+            disconnected_at: if client.connected {
+                None
+            } else {
+                Some(client.disconnected_tm)
+            },
+            groups: client.groups.iter().cloned().collect(),
+            slow_consumer: client.slow_consumer,
+        }
+    }
+
+    /// delivers the messages buffered for connected clients as far as their queues have room
+    fn flush_buffered(
+        store_clients: &HashMap<String, Client>,
+        clean_clients: &HashMap<String, Client>,
+        store_msgs: &mut HashMap<String, OfflineQueue>,
+    ) {
+        for (client_id, msgs) in store_msgs.iter_mut() {
+            if let Some(client) = clean_clients
+                .get(client_id)
+                .or_else(|| store_clients.get(client_id))
+                && client.connected
+            {
+                Self::flush_to(client, msgs);
+            }
+        }
+        store_msgs.retain(|_, msgs| !msgs.is_empty());
+    }
+
+    fn flush_to(client: &Client, msgs: &mut OfflineQueue) {
+        msgs.flush(|msg| {
+            client
+                .client_helper
+                .client_tx
+                .try_send(msg)
+                .err()
+                .map(TrySendError::into_inner)
         });
     }
 
+    /// a client connected with the identifier of a connected one, `client_id` is the one
+    /// the new connection ends up with
+    fn audit_takeover(held: &Client, peer_addr: SocketAddr, action: &str, client_id: &str) {
+        info!(
+            target: "axonmq::audit",
+            event = "session_takeover",
+            action,
+            client_id = %g_utils::TruncateDisplay::new(&held.client_id, 24),
+            old_address = %held.peer_addr,
+            new_address = %peer_addr,
+            new_client_id = %g_utils::TruncateDisplay::new(client_id, 24),
+            "client identifier already connected"
+        );
+    }
+
     async fn handle_message(
-        store_clients: &mut HashMap<String, Client>,
-        clean_clients: &mut HashMap<String, Client>,
+        state: BrokerState<'_>,
         cmd: BrokerCommand,
         operator_helper: OperatorHelper,
         broker_helper: BrokerHelper,
-        store_msgs: &mut HashMap<String, Vec<ClientCommand>>,
-        retain_trie: &mut RetainedTrie,
     ) {
+        let BrokerState {
+            store_clients,
+            clean_clients,
+            store_msgs,
+            retain_trie,
+            groups,
+            session_takeover,
+        } = state;
         use BrokerCommand::*;
         match cmd {
             Connect {
-                connect,
+                mut connect,
+                peer_addr,
                 resp,
                 client_tx,
             } => {
-                let old_client = clean_clients
+                if ban::client_banned(&connect.client_id) {
+                    let code = if connect.version == MqttProtocolVersion::V5 {
+                        ReturnCode::Banned
+                    } else {
+                        ReturnCode::NotAuthorized
+                    };
+                    utils::audit_connect(
+                        &connect.client_id,
+                        peer_addr,
+                        connect.version,
+                        connect.peer_cert.as_ref(),
+                        code,
+                    );
+                    resp.send(BrokerAck::ConnAck(ConnAck::new(false, code, None), None))
+                        .ok();
+                    return;
+                }
+                let mut assigned_client_id = connect.generate_client_id;
+                if let Some(held) = clean_clients
+                    .get(&connect.client_id)
+                    .or_else(|| store_clients.get(&connect.client_id))
+                    .filter(|client| client.connected)
+                {
+                    match session_takeover {
+                        SessionTakeover::KickOld => {
+                            Self::audit_takeover(held, peer_addr, "kick_old", &connect.client_id);
+                        }
+                        SessionTakeover::RejectNew => {
+                            Self::audit_takeover(held, peer_addr, "reject_new", &connect.client_id);
+                            let code = if connect.version == MqttProtocolVersion::V5 {
+                                ReturnCode::ClientIdNotValid
+                            } else {
+                                ReturnCode::IdentifierRejected
+                            };
+                            utils::audit_connect(
+                                &connect.client_id,
+                                peer_addr,
+                                connect.version,
+                                connect.peer_cert.as_ref(),
+                                code,
+                            );
+                            resp.send(BrokerAck::ConnAck(ConnAck::new(false, code, None), None))
+                                .ok();
+                            return;
+                        }
+                        SessionTakeover::Suffix => {
+                            let connected = |id: &str| {
+                                clean_clients
+                                    .get(id)
+                                    .or_else(|| store_clients.get(id))
+                                    .is_some_and(|client| client.connected)
+                            };
+                            let client_id = (2..)
+                                .map(|n| format!("{}~{}", connect.client_id, n))
+                                .find(|id| !connected(id))
+                                .unwrap();
+                            Self::audit_takeover(held, peer_addr, "suffix", &client_id);
+                            connect.client_id = client_id;
+                            assigned_client_id = true;
+                        }
+                    }
+                }
+
+                let mut old_client = clean_clients
                     .remove(&connect.client_id)
                     .or_else(|| store_clients.remove(&connect.client_id));
 
-                if let Some(ref old_client) = old_client {
+                if let Some(ref mut old_client) = old_client {
                     if old_client.connected {
                         debug!("client connected, disconnect old session",);
-                        old_client
-                            .client_helper
-                            .disconnect(ReturnCode::SessionTakenOver)
-                            .await
-                            .ok();
-                        if let Some(will) = &old_client.will {
-                            Self::prepare_will_message(
-                                broker_helper.clone(),
-                                old_client.client_id.clone(),
-                                will.clone(),
-                            );
+                        old_client.client_helper.take_over().await.ok();
+                        // the will of the old connection is dropped when the session goes on
+                        // and the will was delayed, MQTT 5 3.1.4
+                        if let Some(will) = old_client.will.take() {
+                            let delay = will.options.will_delay_interval.unwrap_or(0);
+                            if connect.clean_start || delay == 0 {
+                                Self::prepare_will_message(
+                                    broker_helper.clone(),
+                                    old_client.client_id.clone(),
+                                    old_client.server_name.clone(),
+                                    will,
+                                    0,
+                                );
+                            }
                         }
                         let _ = operator_helper
                             .remove_client(old_client.client_id.clone())
                             .await;
+                    } else if connect.clean_start {
+                        // the session ends, a will still waiting for its delay is due now
+                        Self::end_session_will(old_client, &broker_helper);
+                    } else if let Some(will_task) = old_client.will_task.take() {
+                        // reconnected within the Will Delay Interval
+                        will_task.abort();
                     }
                 }
 
+                let client_groups = groups.groups_for(&connect);
                 let mut client = if connect.clean_start {
                     Client {
                         client_id: connect.client_id.clone(),
                         version: connect.version,
                         connected: true,
+                        connected_tm: coarsetime::Clock::now_since_epoch().as_secs(),
                         disconnected_tm: 0,
                         clear_start: connect.clean_start,
                         client_helper: ClientHelper::new(client_tx),
                         subscribes: HashMap::new(),
                         will: connect.will,
+                        will_task: None,
                         store: None,
                         options: connect.options.clone(),
+                        peer_cert: connect.peer_cert.clone(),
+                        server_name: connect.server_name.clone(),
+                        peer_addr,
+                        groups: client_groups,
+                        slow_consumer: 0,
+                        slow_disconnecting: false,
                     }
                 } else {
                     Client {
                         client_id: connect.client_id.clone(),
                         version: connect.version,
                         connected: true,
+                        connected_tm: coarsetime::Clock::now_since_epoch().as_secs(),
                         disconnected_tm: 0,
                         clear_start: connect.clean_start,
                         client_helper: ClientHelper::new(client_tx),
-                        subscribes: old_client
-                            .as_ref()
-                            .map(|c| c.subscribes.clone())
-                            .unwrap_or_default(),
+                        // a session kept by another member of the replicated store moves here
+                        subscribes: match old_client.as_ref() {
+                            Some(c) => c.subscribes.clone(),
+                            None => {
+                                replicated::restore_session(&connect.client_id).unwrap_or_default()
+                            }
+                        },
                         will: connect.will,
-                        store: old_client.and_then(|c| c.store),
+                        will_task: None,
+                        store: old_client.as_mut().and_then(|c| c.store.take()),
                         options: connect.options.clone(),
+                        peer_cert: connect.peer_cert.clone(),
+                        server_name: connect.server_name.clone(),
+                        peer_addr,
+                        groups: client_groups,
+                        slow_consumer: 0,
+                        slow_disconnecting: false,
                     }
                 };
 
+                if !connect.clean_start
+                    && let Some(old_client) = old_client
+                {
+                    // groups tagged through the RESTful API stay with the session
+                    client.groups.extend(old_client.groups);
+                }
+                if let Some(rate) = groups.publish_rate(&client.groups) {
+                    let _ = client
+                        .client_helper
+                        .send(ClientCommand::PublishRate(Some(rate)));
+                }
+
                 resp.send(BrokerAck::ConnAck(
                     ConnAck::new(
                         client.options.session_expiry_interval > 0,
                         ReturnCode::Success,
-                        if connect.generate_client_id {
+                        if assigned_client_id {
                             Some(client.client_id.clone())
                         } else {
                             None
                         },
                     )
                     .with_topic_alias_maximum(connect.options.topic_alias_maximum)
-                    .with_session_expiry_interval(connect.options.session_expiry_interval),
+                    .with_session_expiry_interval(connect.options.session_expiry_interval)
+                    .with_response_information(
+                        if connect.options.request_response_information {
+                            utils::response_information(&client.client_id)
+                        } else {
+                            None
+                        },
+                    ),
                     client.store.take(),
                 ))
                 .ok();
+                utils::audit_connect(
+                    &client.client_id,
+                    client.peer_addr,
+                    client.version,
+                    client.peer_cert.as_ref(),
+                    ReturnCode::Success,
+                );
+                hook::emit(hook::Event::ClientConnected {
+                    client_id: client.client_id.clone(),
+                    address: client.peer_addr.to_string(),
+                    version: client.version.to_string(),
+                    clean_start: client.clear_start,
+                    session_expiry_interval: client.options.session_expiry_interval,
+                });
                 debug!(
-                    "accept connected: {} [version: {}, clean: {}, expiry: {}]",
+                    "accept connected: {} [address: {}, version: {}, clean: {}, expiry: {}, sni: {}, cert: {}, groups: {:?}]",
                     g_utils::TruncateDisplay::new(&client.client_id, 24),
+                    client.peer_addr,
                     client.version,
                     client.clear_start,
                     client.options.session_expiry_interval,
+                    client.server_name.as_deref().unwrap_or("-"),
+                    client
+                        .peer_cert
+                        .as_ref()
+                        .map(|c| c.to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    client.groups,
                 );
                 operator_helper
                     .remove_client(client.client_id.clone())
                     .await
                     .ok();
+                operator_helper
+                    .client_connected(client.client_id.clone())
+                    .await
+                    .ok();
                 if client.clear_start {
                     store_msgs.remove(&connect.client_id);
+                    replicated::session_ended(&client.client_id);
                 } else {
+                    if client.options.session_expiry_interval > 0 {
+                        replicated::subscriptions(&client.client_id, &client.subscribes);
+                    }
                     for (topic, options) in &client.subscribes {
                         let (group, actual_topic) = if utils::is_shared_subscription(topic) {
                             utils::parse_shared_subscription(topic).unwrap_or(("", topic.as_str()))
@@ -220,10 +560,9 @@ impl Broker {
                             .await;
                     }
 
-                    if let Some(msgs) = store_msgs.remove(&connect.client_id) {
-                        for msg in msgs {
-                            let _ = client.client_helper.send(msg);
-                        }
+                    // what does not fit the queue of the client is flushed as it catches up
+                    if let Some(msgs) = store_msgs.get_mut(&connect.client_id) {
+                        Self::flush_to(&client, msgs);
                     }
                 }
                 if client.options.session_expiry_interval > 0 {
@@ -247,34 +586,28 @@ impl Broker {
                     .or_else(|| clean_clients.get_mut(&client_id))
                 {
                     let mut codes = vec![];
+                    let mut retain_filters = vec![];
                     for (topic, options) in subscribe.topics {
-                        if utils::sub_topic_valid(&topic)
-                            && (utils::parse_shared_subscription(&topic).is_ok()
-                                || !utils::is_shared_subscription(&topic))
-                        {
+                        if !utils::response_topic_allowed(&client_id, &topic) {
+                            warn!(
+                                target: "axonmq::audit",
+                                parent: &span,
+                                event = "subscribe_denied",
+                                client_id = %g_utils::TruncateDisplay::new(&client_id, 24),
+                                address = %client.peer_addr,
+                                topic = %topic,
+                                "subscribe to response topic of another client denied"
+                            );
+                            codes.push(ReturnCode::NotAuthorizedV5);
+                        } else if utils::subscription_valid(&topic) {
                             info!(parent: &span, "subscribe topic: {}, qos: {}", topic, options.qos);
                             let (group, actual_topic) =
                                 utils::parse_shared_subscription(&topic).unwrap_or(("", &topic));
-                            let msgs = if options.retain_handling == 0
+                            if options.retain_handling == 0
                                 || (options.retain_handling == 1
                                     && !client.subscribes.contains_key(&topic))
                             {
-                                retain_trie.find_matches_for_filter(&topic)
-                            } else {
-                                vec![]
-                            };
-                            for msg in msgs {
-                                client
-                                    .client_helper
-                                    .send(ClientCommand::Publish {
-                                        retain: options.retain_as_published,
-                                        qos: options.qos.min(msg.qos),
-                                        topic: msg.topic.clone(),
-                                        payload: msg.payload.clone(),
-                                        user_properties: msg.user_properties.clone(),
-                                        options: msg.options.clone(),
-                                    })
-                                    .ok();
+                                retain_filters.push((topic.clone(), options.clone()));
                             }
                             if !client.subscribes.contains_key(&topic) {
                                 let _ = operator_helper
@@ -297,6 +630,11 @@ impl Broker {
                                     )
                                     .await;
 
+                                hook::emit(hook::Event::SubscriptionCreated {
+                                    client_id: client_id.clone(),
+                                    topic: topic.clone(),
+                                    qos: options.qos as u8,
+                                });
                                 client.subscribes.insert(topic, options);
                             }
 
@@ -305,14 +643,26 @@ impl Broker {
                             codes.push(ReturnCode::TopicFilterInvalid);
                         }
                     }
+                    if client.options.session_expiry_interval > 0 {
+                        replicated::subscriptions(&client_id, &client.subscribes);
+                    }
                     let ack = SubAck::new(subscribe.packet_id, codes);
-                    resp.send(BrokerAck::SubAck(ack)).ok();
+                    let delivery = if retain_filters.is_empty() {
+                        None
+                    } else {
+                        Some(RetainDelivery {
+                            client_id: client_id.clone(),
+                            client_helper: client.client_helper.clone(),
+                            filters: retain_filters,
+                        })
+                    };
+                    resp.send(BrokerAck::SubAck(ack, delivery)).ok();
                 } else {
                     let ack = SubAck::new(
                         subscribe.packet_id,
                         vec![ReturnCode::UnspecifiedError; subscribe.topics.len()],
                     );
-                    resp.send(BrokerAck::SubAck(ack)).ok();
+                    resp.send(BrokerAck::SubAck(ack, None)).ok();
                 }
             }
             Unsubscribe {
@@ -351,6 +701,9 @@ impl Broker {
                                 .await;
                         }
                     }
+                    if client.options.session_expiry_interval > 0 {
+                        replicated::subscriptions(&client_id, &client.subscribes);
+                    }
                     let ack = UnsubAck::new(unsubscribe.packet_id, vec![ReturnCode::Success; len]);
                     resp.send(BrokerAck::UnsubAck(ack)).ok();
                 } else {
@@ -367,26 +720,62 @@ impl Broker {
                     .or_else(|| store_clients.remove(&client_id));
 
                 if let Some(mut client) = client {
-                    if let Some(ref will) = client.will {
-                        if code != ReturnCode::Success {
-                            Self::prepare_will_message(
-                                broker_helper,
-                                client_id.clone(),
-                                will.clone(),
-                            );
-                        }
-                    }
-
                     if let Some(interval) = session_expiry_interval {
                         client.options.session_expiry_interval = interval;
                     }
+                    info!(
+                        target: "axonmq::audit",
+                        event = "client_disconnect",
+                        client_id = %g_utils::TruncateDisplay::new(&client_id, 24),
+                        address = %client.peer_addr,
+                        %code,
+                        session_kept = client.options.session_expiry_interval > 0,
+                        "client disconnected"
+                    );
+                    hook::emit(hook::Event::ClientDisconnected {
+                        client_id: client_id.clone(),
+                        address: client.peer_addr.to_string(),
+                        code: code.to_string(),
+                        session_kept: client.options.session_expiry_interval > 0,
+                    });
+
+                    if let Some(ref will) = client.will
+                        && code != ReturnCode::Success
+                    {
+                        let delay = will
+                            .options
+                            .will_delay_interval
+                            .unwrap_or(0)
+                            .min(client.options.session_expiry_interval);
+                        let will_task = Self::prepare_will_message(
+                            broker_helper.clone(),
+                            client_id.clone(),
+                            client.server_name.clone(),
+                            will.clone(),
+                            delay,
+                        );
+                        if delay > 0 {
+                            client.will_task = Some(will_task);
+                        }
+                    }
 
                     if client.options.session_expiry_interval > 0 {
                         client.disconnected_tm = coarsetime::Clock::now_since_epoch().as_secs();
                         client.connected = false;
                         client.store = Some(store);
                         store_clients.insert(client_id.clone(), client);
+                        // the cap holds between the cleanup ticks too
+                        for client_id in
+                            Self::evict_sessions(store_clients, max_sessions(), &broker_helper)
+                        {
+                            store_msgs.remove(&client_id);
+                            replicated::session_ended(&client_id);
+                            let _ = operator_helper.remove_client(client_id).await;
+                        }
                     } else {
+                        // messages buffered for a slow client end with its session
+                        store_msgs.remove(&client_id);
+                        replicated::session_ended(&client_id);
                         let _ = operator_helper
                             .remove_client(client.client_id.clone())
                             .await;
@@ -395,6 +784,7 @@ impl Broker {
             }
             WillPublish {
                 client_id,
+                server_name,
                 retain,
                 qos,
                 topic,
@@ -402,57 +792,122 @@ impl Broker {
                 user_properties,
                 options,
             } => {
-                let client = store_clients
-                    .get(&client_id)
-                    .or_else(|| clean_clients.get(&client_id));
-                if client.is_none() || !client.unwrap().connected {
-                    if retain {
-                        broker_helper
-                            .retain_message(
-                                topic.clone(),
-                                qos,
-                                payload.clone(),
-                                user_properties.clone(),
-                                options.clone(),
-                            )
-                            .await
-                            .ok();
-                    }
-
-                    let _ = operator_helper
-                        .publish(
-                            client_id,
-                            false,
+                // a will cancelled by a reconnect never gets here, its task is aborted
+                if retain {
+                    broker_helper
+                        .retain_message(
+                            Some(client_id.clone()),
+                            topic.clone(),
                             qos,
-                            topic,
-                            payload,
-                            user_properties,
-                            options,
+                            payload.clone(),
+                            user_properties.clone(),
+                            options.clone(),
                         )
-                        .await;
+                        .await
+                        .ok();
                 }
+
+                let _ = operator_helper
+                    .publish(
+                        client_id,
+                        server_name,
+                        false,
+                        qos,
+                        topic,
+                        payload,
+                        user_properties,
+                        options,
+                    )
+                    .await;
             }
             StoreMsg { client_id, msg } => {
                 if let Some(client) = store_clients.get(&client_id) {
                     if client.connected {
                         let _ = client.client_helper.send(msg);
                     } else {
-                        let msgs = store_msgs.entry(client_id).or_insert_with(Vec::new);
-                        if msgs.len()
-                            >= CONFIG
-                                .get()
-                                .unwrap()
-                                .mqtt
-                                .settings
-                                .max_store_msgs_per_client
-                        {
-                            msgs.remove(0);
+                        let settings = &CONFIG.get().unwrap().mqtt.settings;
+                        let overflow = store_msgs
+                            .entry(client_id.clone())
+                            .or_insert_with(OfflineQueue::new)
+                            .push(
+                                msg,
+                                settings.max_store_msgs_per_client,
+                                settings.max_store_bytes_per_client,
+                                settings.store_overflow_policy.unwrap_or_default(),
+                            );
+                        if overflow == Overflow::EndSession {
+                            warn!(
+                                "offline queue of client {} is full, session ended",
+                                g_utils::TruncateDisplay::new(&client_id, 24)
+                            );
+                            store_msgs.remove(&client_id);
+                            if let Some(mut client) = store_clients.remove(&client_id) {
+                                Self::end_session_will(&mut client, &broker_helper);
+                            }
+                            let _ = operator_helper.remove_client(client_id).await;
                         }
-                        msgs.push(msg);
                     }
                 }
             }
+            SlowConsumer { client_id, msg } => {
+                let Some(client) = clean_clients
+                    .get_mut(&client_id)
+                    .or_else(|| store_clients.get_mut(&client_id))
+                    .filter(|client| client.connected)
+                else {
+                    return;
+                };
+                slow_consumer::record_event();
+                client.slow_consumer += 1;
+
+                let settings = &CONFIG.get().unwrap().mqtt.settings;
+                let disconnect = match settings.slow_consumer_policy.unwrap_or_default() {
+                    SlowConsumerPolicy::Buffer if !matches!(&msg, ClientCommand::Publish(publish) if publish.qos == QoS::AtMostOnce) =>
+                    {
+                        let overflow = store_msgs
+                            .entry(client_id.clone())
+                            .or_insert_with(OfflineQueue::new)
+                            .push(
+                                msg,
+                                settings.max_store_msgs_per_client,
+                                settings.max_store_bytes_per_client,
+                                settings.store_overflow_policy.unwrap_or_default(),
+                            );
+                        match overflow {
+                            Overflow::Stored => slow_consumer::record_buffered(),
+                            _ => slow_consumer::record_dropped(),
+                        }
+                        overflow == Overflow::EndSession
+                    }
+                    SlowConsumerPolicy::Drop | SlowConsumerPolicy::Buffer => {
+                        slow_consumer::record_dropped();
+                        false
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        slow_consumer::record_dropped();
+                        true
+                    }
+                };
+
+                // the queue is full, the disconnect waits for room in a task of its own
+                if disconnect && !client.slow_disconnecting {
+                    warn!(
+                        "client {} does not keep up with its messages, disconnecting",
+                        g_utils::TruncateDisplay::new(&client_id, 24)
+                    );
+                    slow_consumer::record_disconnect();
+                    client.slow_disconnecting = true;
+                    let client_helper = client.client_helper.clone();
+                    task::spawn(async move {
+                        client_helper
+                            .disconnect(ReturnCode::QuotaExceeded)
+                            .await
+                            .ok();
+                    });
+                }
+            }
             RetainMessage {
+                client_id,
                 topic,
                 qos,
                 payload,
@@ -461,23 +916,290 @@ impl Broker {
             } => {
                 if payload.is_empty() || options.message_expiry_interval == Some(0) {
                     retain_trie.remove(&topic);
+                    replicated::retained_removed(&topic);
                 } else {
-                    retain_trie.insert(
-                        &topic,
-                        RetainedMessage {
-                            qos,
-                            topic: topic.clone(),
-                            payload: payload.clone(),
-                            user_properties: user_properties.clone(),
-                            options,
-                        },
-                    );
+                    let message = RetainedMessage {
+                        qos,
+                        topic: topic.clone(),
+                        payload: payload.clone(),
+                        user_properties: user_properties.clone(),
+                        options,
+                        client_id,
+                    };
+                    replicated::retained(&message);
+                    retain_trie.insert(&topic, message);
                 }
             }
+            ListClients { resp } => {
+                let clients = clean_clients
+                    .values()
+                    .chain(store_clients.values())
+                    .map(Self::client_info)
+                    .collect();
+                resp.send(BrokerAck::Clients(clients)).ok();
+            }
+            GetClient { client_id, resp } => {
+                let client = clean_clients
+                    .get(&client_id)
+                    .or_else(|| store_clients.get(&client_id))
+                    .map(|client| ClientDetail {
+                        info: Self::client_info(client),
+                        topics: client.subscribes.keys().cloned().collect(),
+                        peer_cert: client.peer_cert.as_ref().map(|c| c.to_string()),
+                        server_name: client.server_name.clone(),
+                        address: client.peer_addr.to_string(),
+                        store_msgs: store_msgs.get(&client_id).map_or(0, |msgs| msgs.len()),
+                        store_bytes: store_msgs.get(&client_id).map_or(0, |msgs| msgs.bytes()),
+                        store_dropped: store_msgs.get(&client_id).map_or(0, |msgs| msgs.dropped()),
+                    });
+                resp.send(BrokerAck::Client(client)).ok();
+            }
+            GetSession { client_id, resp } => {
+                let session = clean_clients
+                    .get(&client_id)
+                    .or_else(|| store_clients.get(&client_id))
+                    .map(|client| {
+                        let offline = store_msgs.get(&client_id);
+                        let session = ClientSession {
+                            client_id: client.client_id.clone(),
+                            connected: client.connected,
+                            subscriptions: client
+                                .subscribes
+                                .iter()
+                                .map(|(topic, option)| SubscriptionInfo {
+                                    topic: topic.clone(),
+                                    qos: option.qos as u8,
+                                    no_local: option.no_local,
+                                    retain_as_published: option.retain_as_published,
+                                    retain_handling: option.retain_handling,
+                                    subscription_identifier: option.subscription_identifier,
+                                })
+                                .collect(),
+                            offline_msgs: offline.map_or(0, |msgs| msgs.len()),
+                            offline_bytes: offline.map_or(0, |msgs| msgs.bytes()),
+                            // the connection holds the store until it disconnects
+                            store: client
+                                .store
+                                .as_ref()
+                                .map(|store| {
+                                    store.info(coarsetime::Clock::now_since_epoch().as_secs())
+                                })
+                                .unwrap_or_default(),
+                            last_activity: if client.connected {
+                                client.connected_tm
+                            } else {
+                                client.disconnected_tm
+                            },
+                        };
+                        (
+                            session,
+                            client.connected.then(|| client.client_helper.clone()),
+                        )
+                    });
+                resp.send(BrokerAck::Session(session)).ok();
+            }
+            PurgeQueue { client_id, resp } => {
+                let purged = match clean_clients
+                    .get_mut(&client_id)
+                    .or_else(|| store_clients.get_mut(&client_id))
+                {
+                    Some(client) => {
+                        let mut purged = store_msgs.remove(&client_id).map_or(0, |msgs| msgs.len());
+                        purged += client.store.as_mut().map_or(0, |store| store.purge());
+                        info!(
+                            "purged {} queued messages of client {}",
+                            purged,
+                            g_utils::TruncateDisplay::new(&client_id, 24)
+                        );
+                        Some((
+                            purged,
+                            client.connected.then(|| client.client_helper.clone()),
+                        ))
+                    }
+                    None => None,
+                };
+                resp.send(BrokerAck::Purged(purged)).ok();
+            }
+            GetConnection { client_id, resp } => {
+                let result = match clean_clients
+                    .get(&client_id)
+                    .or_else(|| store_clients.get(&client_id))
+                {
+                    Some(client) if client.connected => Ok(client.client_helper.clone()),
+                    Some(_) => Err(KickError::NotConnected),
+                    None => Err(KickError::NotFound),
+                };
+                resp.send(BrokerAck::Connection(result)).ok();
+            }
+            KickClient {
+                client_id,
+                reason,
+                resp,
+            } => {
+                let result = match clean_clients
+                    .get(&client_id)
+                    .or_else(|| store_clients.get(&client_id))
+                {
+                    Some(client) if client.connected => {
+                        info!(
+                            "kick client: {}, reason: {}",
+                            g_utils::TruncateDisplay::new(&client_id, 24),
+                            reason
+                        );
+                        // the connection reports back as disconnected, a full queue does not hold the broker
+                        let client_helper = client.client_helper.clone();
+                        task::spawn(async move {
+                            client_helper.disconnect(reason).await.ok();
+                        });
+                        Ok(())
+                    }
+                    Some(_) => Err(KickError::NotConnected),
+                    None => Err(KickError::NotFound),
+                };
+                resp.send(BrokerAck::Kicked(result)).ok();
+            }
+            RemoveRetained { filter, resp } => {
+                let removed = retain_trie.remove_matches(&filter);
+                replicated::retained_removed(&filter);
+                info!("removed {} retained messages matching {}", removed, filter);
+                resp.send(BrokerAck::RetainedRemoved(removed)).ok();
+            }
+            TagClient {
+                client_id,
+                group,
+                tagged,
+                resp,
+            } => {
+                let found = match clean_clients
+                    .get_mut(&client_id)
+                    .or_else(|| store_clients.get_mut(&client_id))
+                {
+                    Some(client) => {
+                        let changed = if tagged {
+                            client.groups.insert(group.clone())
+                        } else {
+                            client.groups.remove(&group)
+                        };
+                        if changed && client.connected && groups.group_rate(&group).is_some() {
+                            let _ = client.client_helper.send(ClientCommand::PublishRate(
+                                groups.publish_rate(&client.groups),
+                            ));
+                        }
+                        info!(
+                            "client {} {} group {}",
+                            g_utils::TruncateDisplay::new(&client_id, 24),
+                            if tagged { "joined" } else { "left" },
+                            group
+                        );
+                        true
+                    }
+                    None => false,
+                };
+                resp.send(BrokerAck::Tagged(found)).ok();
+            }
+            ListGroups { resp } => {
+                let mut infos: HashMap<&str, GroupInfo> = groups
+                    .limited_groups()
+                    .map(|group| {
+                        (
+                            group.as_str(),
+                            GroupInfo {
+                                name: group.clone(),
+                                members: 0,
+                                connected: 0,
+                                publish_rate: groups.group_rate(group),
+                            },
+                        )
+                    })
+                    .collect();
+                for client in clean_clients.values().chain(store_clients.values()) {
+                    for group in client.groups.iter() {
+                        let info = infos.entry(group).or_insert_with(|| GroupInfo {
+                            name: group.clone(),
+                            members: 0,
+                            connected: 0,
+                            publish_rate: groups.group_rate(group),
+                        });
+                        info.members += 1;
+                        if client.connected {
+                            info.connected += 1;
+                        }
+                    }
+                }
+                let mut infos: Vec<GroupInfo> = infos.into_values().collect();
+                infos.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+                resp.send(BrokerAck::Groups(infos)).ok();
+            }
+            KickGroup { group, resp } => {
+                let mut kicked = 0;
+                for client in clean_clients
+                    .values()
+                    .chain(store_clients.values())
+                    .filter(|client| client.connected && client.groups.contains(&group))
+                {
+                    // a member with a full queue does not hold the broker
+                    let client_helper = client.client_helper.clone();
+                    task::spawn(async move {
+                        client_helper
+                            .disconnect(ReturnCode::AdministrativeAction)
+                            .await
+                            .ok();
+                    });
+                    kicked += 1;
+                }
+                info!("kick group: {}, {} clients", group, kicked);
+                resp.send(BrokerAck::GroupKicked(kicked)).ok();
+            }
+            DisconnectAll { reason, resp } => {
+                let mut disconnected = 0;
+                for client in clean_clients
+                    .values()
+                    .chain(store_clients.values())
+                    .filter(|client| client.connected)
+                {
+                    // each connection reports back as disconnected, a full queue does not hold the drain
+                    let client_helper = client.client_helper.clone();
+                    task::spawn(async move {
+                        client_helper.disconnect(reason).await.ok();
+                    });
+                    disconnected += 1;
+                }
+                info!("disconnect all: {}, {} clients", reason, disconnected);
+                resp.send(BrokerAck::AllDisconnected(disconnected)).ok();
+            }
+            SyncRetained { resp } => {
+                if let Err(e) = retain_trie.sync() {
+                    warn!("failed to sync retained message store: {}", e);
+                }
+                resp.send(BrokerAck::RetainedSynced).ok();
+            }
+            SetGroupRate {
+                group,
+                publish_rate,
+                resp,
+            } => {
+                groups.set_publish_rate(&group, publish_rate);
+                let mut updated = 0;
+                for client in clean_clients
+                    .values()
+                    .chain(store_clients.values())
+                    .filter(|client| client.connected && client.groups.contains(&group))
+                {
+                    let _ = client.client_helper.send(ClientCommand::PublishRate(
+                        groups.publish_rate(&client.groups),
+                    ));
+                    updated += 1;
+                }
+                info!(
+                    "group {} publish rate set to {:?}, {} clients updated",
+                    group, publish_rate, updated
+                );
+                resp.send(BrokerAck::GroupUpdated(updated)).ok();
+            }
         }
     }
 
-    pub async fn run(&mut self, operator_helper: OperatorHelper) {
+    pub async fn run(&mut self, operator_helper: OperatorHelper) -> task::JoinHandle<()> {
         let mut broker_rx = self.broker_rx.take().unwrap();
         let mut store_clients = self.store_clients.take().unwrap();
         let mut clean_clients = self.clean_clients.take().unwrap();
@@ -488,16 +1210,33 @@ impl Broker {
         let mut retain_clean_tk = time::interval(time::Duration::from_secs(
             CONFIG.get().unwrap().mqtt.settings.retain_cleanup_interval,
         ));
+        let mut flush_tk = time::interval(time::Duration::from_secs(1));
 
         let broker_helper = self.get_helper();
-        let mut store_msgs: HashMap<String, Vec<ClientCommand>> = HashMap::new();
-        let mut retain_trie = self.retain_trie.take().unwrap();
+        let mut store_msgs: HashMap<String, OfflineQueue> = HashMap::new();
+        let retain_trie = self.retain_trie.clone();
+        let mut groups = ClientGroups::new(&CONFIG.get().unwrap().client_group);
+        let session_takeover = CONFIG
+            .get()
+            .unwrap()
+            .mqtt
+            .settings
+            .session_takeover
+            .unwrap_or_default();
 
         tokio::spawn(async move {
             loop {
                 tokio::select! {
                     Some(cmd) = broker_rx.recv() => {
-                        Self::handle_message(&mut store_clients, &mut clean_clients, cmd, operator_helper.clone(), broker_helper.clone(), &mut store_msgs, &mut retain_trie).await;
+                        let state = BrokerState {
+                            store_clients: &mut store_clients,
+                            clean_clients: &mut clean_clients,
+                            store_msgs: &mut store_msgs,
+                            retain_trie: &retain_trie,
+                            groups: &mut groups,
+                            session_takeover,
+                        };
+                        Self::handle_message(state, cmd, operator_helper.clone(), broker_helper.clone()).await;
                     }
                     _ = clean_tk.tick() => {
                         let mut remove_ids = Vec::new();
@@ -506,26 +1245,399 @@ impl Broker {
                             if client.connected {
                                 true
                             } else if client.options.session_expiry_interval== 0 {
+                                Self::end_session_will(client, &broker_helper);
                                 remove_ids.push(client.client_id.clone());
                                 false
                             } else {
                                 let result = now - client.disconnected_tm < client.options.session_expiry_interval as u64;
                                 if !result {
+                                    Self::end_session_will(client, &broker_helper);
+                                    hook::emit(hook::Event::SessionExpired {
+                                        client_id: client.client_id.clone(),
+                                    });
                                     remove_ids.push(client.client_id.clone());
                                 }
                                 result
                             }
                         });
+                        remove_ids.extend(Self::evict_sessions(&mut store_clients, max_sessions(), &broker_helper));
                         for client_id in remove_ids {
                             store_msgs.remove(&client_id);
+                            replicated::session_ended(&client_id);
                             let _ = operator_helper.remove_client(client_id).await;
                         }
                     }
+                    _ = flush_tk.tick(), if !store_msgs.is_empty() => {
+                        Self::flush_buffered(&store_clients, &clean_clients, &mut store_msgs);
+                    }
                     _ = retain_clean_tk.tick() => {
                         retain_trie.purge_expired();
+                        let now = coarsetime::Clock::now_since_epoch().as_secs();
+                        store_msgs.retain(|_, msgs| {
+                            msgs.purge_expired(now);
+                            !msgs.is_empty()
+                        });
                     }
                 }
             }
-        });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::mqtt::protocol::{
+        conn::Connect,
+        publish::{PublishBody, PublishOptions, SharedPublish},
+        will::WillOptions,
+    };
+
+    fn client(client_id: &str, connected: bool, disconnected_tm: u64) -> Client {
+        Client {
+            client_id: client_id.to_string(),
+            version: MqttProtocolVersion::V5,
+            connected,
+            connected_tm: 0,
+            disconnected_tm,
+            clear_start: false,
+            subscribes: HashMap::new(),
+            will: None,
+            will_task: None,
+            client_helper: ClientHelper::new(mpsc::channel(1).0),
+            store: None,
+            options: ConnectOptions {
+                session_expiry_interval: 3600,
+                packet_maximum: 0,
+                topic_alias_maximum: 0,
+                inflight_maximum: 0,
+                request_response_information: false,
+            },
+            peer_cert: None,
+            server_name: None,
+            peer_addr: "127.0.0.1:1883".parse().unwrap(),
+            groups: BTreeSet::new(),
+            slow_consumer: 0,
+            slow_disconnecting: false,
+        }
+    }
+
+    #[test]
+    fn test_evict_sessions() {
+        let broker_helper = BrokerHelper {
+            broker_tx: mpsc::channel(1).0,
+            retain_trie: SharedRetainedTrie::new(),
+        };
+        let mut store_clients = HashMap::new();
+        for (client_id, connected, disconnected_tm) in [
+            ("old", false, 10),
+            ("on", true, 0),
+            ("new", false, 30),
+            ("mid", false, 20),
+        ] {
+            store_clients.insert(
+                client_id.to_string(),
+                client(client_id, connected, disconnected_tm),
+            );
+        }
+
+        assert!(Broker::evict_sessions(&mut store_clients, 0, &broker_helper).is_empty());
+        assert!(Broker::evict_sessions(&mut store_clients, 4, &broker_helper).is_empty());
+        // the oldest disconnected first, connected clients are kept
+        assert_eq!(
+            Broker::evict_sessions(&mut store_clients, 2, &broker_helper),
+            vec!["old", "mid"]
+        );
+        assert_eq!(store_clients.len(), 2);
+        assert!(store_clients.contains_key("on") && store_clients.contains_key("new"));
+    }
+
+    /// the state of a broker loop, the commands it sends itself come out of `broker_rx`
+    struct Harness {
+        store_clients: HashMap<String, Client>,
+        clean_clients: HashMap<String, Client>,
+        store_msgs: HashMap<String, OfflineQueue>,
+        retain_trie: SharedRetainedTrie,
+        groups: ClientGroups,
+        session_takeover: SessionTakeover,
+        broker_helper: BrokerHelper,
+        broker_rx: mpsc::Receiver<BrokerCommand>,
+        // the connections of the clients, kept open
+        client_rxs: Vec<mpsc::Receiver<ClientCommand>>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            CONFIG.get_or_init(|| Config::parse(include_str!("../../config.toml"), ".").unwrap());
+            let (broker_tx, broker_rx) = mpsc::channel(16);
+            let retain_trie = SharedRetainedTrie::new();
+            Harness {
+                store_clients: HashMap::new(),
+                clean_clients: HashMap::new(),
+                store_msgs: HashMap::new(),
+                retain_trie: retain_trie.clone(),
+                groups: ClientGroups::new(&[]),
+                session_takeover: SessionTakeover::KickOld,
+                broker_helper: BrokerHelper {
+                    broker_tx,
+                    retain_trie,
+                },
+                broker_rx,
+                client_rxs: vec![],
+            }
+        }
+
+        async fn handle(&mut self, cmd: BrokerCommand) {
+            let state = BrokerState {
+                store_clients: &mut self.store_clients,
+                clean_clients: &mut self.clean_clients,
+                store_msgs: &mut self.store_msgs,
+                retain_trie: &self.retain_trie,
+                groups: &mut self.groups,
+                session_takeover: self.session_takeover,
+            };
+            // the operator is gone, what the broker tells it is lost
+            let operator_helper = OperatorHelper::new(mpsc::channel(1).0, mpsc::channel(1).0, None);
+            Broker::handle_message(state, cmd, operator_helper, self.broker_helper.clone()).await;
+        }
+
+        /// the CONNACK of a new connection of `client_id`
+        async fn connect(
+            &mut self,
+            client_id: &str,
+            clean_start: bool,
+            will: Option<Will>,
+        ) -> ConnAck {
+            let (client_tx, client_rx) = mpsc::channel(16);
+            self.client_rxs.push(client_rx);
+            let (resp, ack) = oneshot::channel();
+            let connect = Connect {
+                version: MqttProtocolVersion::V5,
+                keep_alive: 60,
+                generate_client_id: false,
+                clean_start,
+                client_id: client_id.to_string(),
+                username: None,
+                password: None,
+                will,
+                options: client(client_id, true, 0).options,
+                peer_cert: None,
+                server_name: None,
+                groups: vec![],
+            };
+            self.handle(BrokerCommand::Connect {
+                connect,
+                peer_addr: "127.0.0.1:1883".parse().unwrap(),
+                resp,
+                client_tx,
+            })
+            .await;
+            match ack.await {
+                Ok(BrokerAck::ConnAck(ack, _)) => ack,
+                _ => panic!("no CONNACK"),
+            }
+        }
+
+        async fn disconnect(&mut self, client_id: &str, session_expiry_interval: u32) {
+            self.disconnect_with(client_id, session_expiry_interval, Store::new(1, 1))
+                .await;
+        }
+
+        async fn disconnect_with(
+            &mut self,
+            client_id: &str,
+            session_expiry_interval: u32,
+            store: Store,
+        ) {
+            self.handle(BrokerCommand::Disconnected(
+                client_id.to_string(),
+                ReturnCode::UnspecifiedError,
+                Some(session_expiry_interval),
+                store,
+            ))
+            .await;
+        }
+
+        /// what the broker answers to the command `request` builds around its resp
+        async fn ask(
+            &mut self,
+            request: impl FnOnce(oneshot::Sender<BrokerAck>) -> BrokerCommand,
+        ) -> BrokerAck {
+            let (resp, ack) = oneshot::channel();
+            self.handle(request(resp)).await;
+            ack.await.unwrap()
+        }
+
+        /// the topic of the next will published within `secs`
+        async fn will(&mut self, secs: f64) -> Option<String> {
+            let next = time::timeout(time::Duration::from_secs_f64(secs), self.broker_rx.recv());
+            match next.await {
+                Ok(Some(BrokerCommand::WillPublish { topic, .. })) => Some(topic),
+                _ => None,
+            }
+        }
+    }
+
+    fn will(delay: u32) -> Option<Will> {
+        Some(Will {
+            topic: "will/c".to_string(),
+            payload: Bytes::from_static(b"gone"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            user_properties: vec![],
+            options: WillOptions {
+                will_delay_interval: Some(delay),
+                ..Default::default()
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_will_cancelled_on_reconnect() {
+        let mut harness = Harness::new();
+        harness.connect("c", false, will(1)).await;
+        harness.disconnect("c", 60).await;
+        // back within the Will Delay Interval, the session goes on
+        harness.connect("c", false, None).await;
+        assert!(harness.will(1.5).await.is_none());
+
+        // published once the delay passes without a reconnect
+        harness.connect("c", false, will(1)).await;
+        harness.disconnect("c", 60).await;
+        assert!(harness.will(0.5).await.is_none());
+        assert_eq!(harness.will(1.5).await.as_deref(), Some("will/c"));
+    }
+
+    #[tokio::test]
+    async fn test_will_on_takeover() {
+        let mut harness = Harness::new();
+        // the session goes on with the new connection, the delayed will is dropped
+        harness.connect("c", false, will(60)).await;
+        harness.connect("c", false, None).await;
+        assert!(harness.will(0.2).await.is_none());
+
+        // a clean start ends the session of the old connection, its will is due
+        harness.connect("c", false, will(60)).await;
+        harness.connect("c", true, None).await;
+        assert_eq!(harness.will(1.0).await.as_deref(), Some("will/c"));
+
+        // so is a will without delay, whatever the new connection does with the session
+        harness.connect("c", false, will(0)).await;
+        harness.connect("c", false, None).await;
+        assert_eq!(harness.will(1.0).await.as_deref(), Some("will/c"));
+    }
+
+    #[tokio::test]
+    async fn test_will_delay_capped_at_session_expiry() {
+        let mut harness = Harness::new();
+        harness.connect("c", false, will(3600)).await;
+        // the session ends after a second, the will goes with it
+        harness.disconnect("c", 1).await;
+        assert_eq!(harness.will(3.0).await.as_deref(), Some("will/c"));
+
+        // no session kept, the will is published right away
+        harness.connect("c", false, will(3600)).await;
+        harness.disconnect("c", 0).await;
+        assert_eq!(harness.will(1.0).await.as_deref(), Some("will/c"));
+    }
+
+    #[tokio::test]
+    async fn test_session_takeover() {
+        let mut harness = Harness::new();
+        harness.connect("c", true, None).await;
+
+        // the old connection is told it was taken over
+        harness.connect("c", true, None).await;
+        assert!(matches!(
+            harness.client_rxs[0].try_recv(),
+            Ok(ClientCommand::TakenOver)
+        ));
+
+        harness.session_takeover = SessionTakeover::RejectNew;
+        let ack = harness.connect("c", true, None).await;
+        assert_eq!(ack.return_code, ReturnCode::ClientIdNotValid);
+        assert!(harness.client_rxs[1].try_recv().is_err());
+        assert!(harness.store_clients["c"].connected);
+
+        // the new connection gets the first free suffix
+        harness.session_takeover = SessionTakeover::Suffix;
+        let ack = harness.connect("c", true, None).await;
+        assert_eq!(ack.assigned_client_id(), Some("c~2"));
+        let ack = harness.connect("c", true, None).await;
+        assert_eq!(ack.assigned_client_id(), Some("c~3"));
+        assert!(harness.client_rxs[1].try_recv().is_err());
+        assert_eq!(harness.store_clients.len(), 3);
+    }
+
+    fn publish(packet_id: u16) -> SharedPublish {
+        let body = PublishBody {
+            topic: "t/1".to_string(),
+            payload: Bytes::from_static(b"payload"),
+            user_properties: vec![],
+            options: PublishOptions::default(),
+        };
+        let mut publish = SharedPublish::new(QoS::AtLeastOnce, false, Arc::new(body));
+        publish.packet_id = Some(packet_id);
+        publish
+    }
+
+    #[tokio::test]
+    async fn test_purge_queue() {
+        let mut harness = Harness::new();
+        let purge = |client_id: &str| {
+            let client_id = client_id.to_string();
+            move |resp| BrokerCommand::PurgeQueue { client_id, resp }
+        };
+        let connection = |client_id: &str| {
+            let client_id = client_id.to_string();
+            move |resp| BrokerCommand::GetConnection { client_id, resp }
+        };
+
+        harness.connect("c", false, None).await;
+        assert!(matches!(
+            harness.ask(connection("c")).await,
+            BrokerAck::Connection(Ok(_))
+        ));
+
+        // one message waiting for a packet identifier in the session store, two offline
+        let mut store = Store::new(0, 10);
+        store.inflight_insert(publish(1));
+        harness.disconnect_with("c", 3600, store).await;
+        for packet_id in [2, 3] {
+            harness
+                .handle(BrokerCommand::StoreMsg {
+                    client_id: "c".to_string(),
+                    msg: ClientCommand::Publish(publish(packet_id)),
+                })
+                .await;
+        }
+        assert!(matches!(
+            harness.ask(connection("c")).await,
+            BrokerAck::Connection(Err(KickError::NotConnected))
+        ));
+        assert!(matches!(
+            harness.ask(purge("c")).await,
+            BrokerAck::Purged(Some((3, None)))
+        ));
+        assert!(harness.store_msgs.is_empty());
+        assert!(matches!(
+            harness.ask(purge("c")).await,
+            BrokerAck::Purged(Some((0, None)))
+        ));
+
+        assert!(matches!(
+            harness.ask(purge("x")).await,
+            BrokerAck::Purged(None)
+        ));
+        assert!(matches!(
+            harness.ask(connection("x")).await,
+            BrokerAck::Connection(Err(KickError::NotFound))
+        ));
     }
 }
@@ -0,0 +1,45 @@
+//! clients whose queue is full when a message is delivered to them, `slow_consumer_policy`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// messages which found the queue of their client full since the process started
+static EVENTS: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static BUFFERED: AtomicU64 = AtomicU64::new(0);
+static DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn record_event() {
+    EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_dropped() {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_buffered() {
+    BUFFERED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_disconnect() {
+    DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// messages delivered to a client whose queue was full
+pub fn events_total() -> u64 {
+    EVENTS.load(Ordering::Relaxed)
+}
+
+/// messages for slow clients dropped, by the drop policy or QoS 0 under the buffer policy
+pub fn dropped_total() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// messages for slow clients kept in their store queue
+pub fn buffered_total() -> u64 {
+    BUFFERED.load(Ordering::Relaxed)
+}
+
+/// slow clients disconnected by the disconnect policy
+pub fn disconnects_total() -> u64 {
+    DISCONNECTS.load(Ordering::Relaxed)
+}
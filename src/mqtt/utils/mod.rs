@@ -0,0 +1,293 @@
+use std::net::SocketAddr;
+
+use tracing::{info, warn};
+
+use crate::CONFIG;
+use crate::config::ZeroKeepAlive;
+use crate::operator::utils::topic_match;
+
+use super::MqttProtocolVersion;
+use super::code::ReturnCode;
+use super::error::MqttProtocolError;
+use super::listener::tcp::PeerCertificate;
+
+pub mod validate;
+
+fn max_topic_length() -> usize {
+    CONFIG.get().unwrap().mqtt.settings.max_topic_length
+}
+
+/// the keep alive a client is held to from the one in its CONNECT, 0 when disabled
+pub fn keep_alive(requested: u16, version: MqttProtocolVersion) -> u16 {
+    let settings = &CONFIG.get().unwrap().mqtt.settings;
+    negotiate_keep_alive(
+        requested,
+        version == MqttProtocolVersion::V5,
+        settings.keep_alive,
+        settings.max_keep_alive,
+        settings.zero_keep_alive.unwrap_or_default(),
+    )
+}
+
+// only V5 clients can be told to ping more often, through Server Keep Alive in CONNACK,
+// V3.1.1 clients over `max` keep the value they asked for
+fn negotiate_keep_alive(
+    requested: u16,
+    v5: bool,
+    min: u16,
+    max: Option<u16>,
+    zero: ZeroKeepAlive,
+) -> u16 {
+    if requested == 0 {
+        return match zero {
+            ZeroKeepAlive::Allow => 0,
+            ZeroKeepAlive::Override => min,
+        };
+    }
+    let keep_alive = requested.max(min);
+    match max {
+        Some(max) if v5 => keep_alive.min(max.max(min)),
+        _ => keep_alive,
+    }
+}
+
+pub fn sub_topic_valid(topic: &str) -> bool {
+    validate::topic_filter_valid(topic, max_topic_length())
+}
+
+pub fn subscription_valid(topic: &str) -> bool {
+    validate::subscription_valid(topic, max_topic_length())
+}
+
+pub fn pub_topic_valid(topic: &str) -> bool {
+    validate::topic_name_valid(topic, max_topic_length())
+}
+
+pub fn client_pub_topic_valid(topic: &str) -> bool {
+    validate::client_topic_name_valid(topic, max_topic_length())
+}
+
+/// the V5 response information assigned to a client, `None` when disabled or
+/// when the client identifier cannot be used as a topic level
+pub fn response_information(client_id: &str) -> Option<String> {
+    let prefix = CONFIG
+        .get()
+        .unwrap()
+        .mqtt
+        .settings
+        .response_topic_prefix
+        .as_deref()?;
+    response_topic(prefix, client_id)
+}
+
+/// whether a client may subscribe to a filter, a filter naming the response topic of another
+/// client is refused, the response topics a wildcard reaches are dropped on delivery
+pub fn response_topic_allowed(client_id: &str, filter: &str) -> bool {
+    match CONFIG
+        .get()
+        .unwrap()
+        .mqtt
+        .settings
+        .response_topic_prefix
+        .as_deref()
+    {
+        Some(prefix) => response_filter_allowed(prefix, client_id, filter),
+        None => true,
+    }
+}
+
+/// the client a topic is the response topic of, `None` outside of the response topics,
+/// checked on delivery so wildcard filters do not reach the response topics of others
+pub fn response_topic_owner(topic: &str) -> Option<&str> {
+    let prefix = CONFIG
+        .get()?
+        .mqtt
+        .settings
+        .response_topic_prefix
+        .as_deref()?;
+    response_owner(prefix, topic)
+}
+
+fn response_owner<'a>(prefix: &str, topic: &'a str) -> Option<&'a str> {
+    if prefix.is_empty() {
+        return None;
+    }
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    rest.split('/').next()
+}
+
+fn response_topic(prefix: &str, client_id: &str) -> Option<String> {
+    if prefix.is_empty() || client_id.is_empty() || client_id.contains(['/', '+', '#']) {
+        return None;
+    }
+    Some(format!("{}/{}", prefix, client_id))
+}
+
+fn response_filter_allowed(prefix: &str, client_id: &str, filter: &str) -> bool {
+    let filter = parse_shared_subscription(filter).map_or(filter, |(_, f)| f);
+    if prefix.is_empty() {
+        return true;
+    }
+    // only a filter naming another client at the owner level is refused, wildcards there are
+    // left to the delivery filter which drops the response topics of others
+    let depth = prefix.split('/').count();
+    let head = filter.split('/').take(depth + 1).collect::<Vec<_>>();
+    match head.get(depth) {
+        Some(&owner) if owner != client_id && owner != "+" && owner != "#" => {
+            !topic_match(&head.join("/"), &format!("{}/{}", prefix, owner))
+        }
+        _ => true,
+    }
+}
+
+pub fn is_shared_subscription(topic: &str) -> bool {
+    topic.starts_with("$share/")
+}
+
+pub fn parse_shared_subscription(topic: &str) -> Result<(&str, &str), MqttProtocolError> {
+    if !topic.starts_with("$share/") {
+        return Err(MqttProtocolError::InvalidTopicFilter);
+    }
+
+    let parts: Vec<&str> = topic.splitn(3, '/').collect();
+    if parts.len() != 3 || parts[1].is_empty() || parts[2].is_empty() {
+        return Err(MqttProtocolError::InvalidTopicFilter);
+    }
+
+    Ok((parts[1], parts[2]))
+}
+
+/// the outcome of a CONNECT, for the audit trail
+pub fn audit_connect(
+    client_id: &str,
+    peer_addr: SocketAddr,
+    version: MqttProtocolVersion,
+    peer_cert: Option<&PeerCertificate>,
+    code: ReturnCode,
+) {
+    let common_name = peer_cert.and_then(|cert| cert.common_name.as_deref());
+    if code == ReturnCode::Success {
+        info!(
+            target: "axonmq::audit",
+            event = "client_connect",
+            accepted = true,
+            client_id = %crate::utils::TruncateDisplay::new(client_id, 24),
+            address = %peer_addr,
+            %version,
+            cert_cn = common_name.unwrap_or("-"),
+            "client connected"
+        );
+    } else {
+        warn!(
+            target: "axonmq::audit",
+            event = "client_connect",
+            accepted = false,
+            client_id = %crate::utils::TruncateDisplay::new(client_id, 24),
+            address = %peer_addr,
+            %version,
+            cert_cn = common_name.unwrap_or("-"),
+            %code,
+            "client connection refused"
+        );
+    }
+}
+
+pub fn generate_random_client_id() -> String {
+    use rand::Rng;
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                abcdefghijklmnopqrstuvwxyz\
+                                0123456789";
+    let mut rng = rand::rng();
+
+    let random_suffix: String = (0..14)
+        .map(|_| {
+            let idx = rng.random_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect();
+
+    format!("inner_{}", random_suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_subscription() {
+        let topic = "$share/group/topic";
+        let result = parse_shared_subscription(topic);
+        assert!(result.is_ok());
+        let (group, actual_topic) = result.unwrap();
+        assert_eq!(group, "group");
+        assert_eq!(actual_topic, "topic");
+
+        let topic2 = "$share/mygroup/sensors/temperature";
+        let result2 = parse_shared_subscription(topic2);
+        assert!(result2.is_ok());
+        let (group2, actual_topic2) = result2.unwrap();
+        assert_eq!(group2, "mygroup");
+        assert_eq!(actual_topic2, "sensors/temperature");
+
+        let invalid_topic = "$share//topic";
+        assert!(parse_shared_subscription(invalid_topic).is_err());
+
+        let invalid_topic2 = "$share/group/";
+        assert!(parse_shared_subscription(invalid_topic2).is_err());
+
+        let invalid_topic3 = "normal/topic";
+        assert!(parse_shared_subscription(invalid_topic3).is_err());
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        use ZeroKeepAlive::*;
+
+        assert_eq!(negotiate_keep_alive(30, true, 60, None, Override), 60);
+        assert_eq!(negotiate_keep_alive(90, false, 60, None, Override), 90);
+        assert_eq!(
+            negotiate_keep_alive(600, true, 60, Some(300), Override),
+            300
+        );
+        assert_eq!(
+            negotiate_keep_alive(600, false, 60, Some(300), Override),
+            600
+        );
+        assert_eq!(negotiate_keep_alive(0, true, 60, Some(300), Override), 60);
+        assert_eq!(negotiate_keep_alive(0, false, 60, Some(300), Allow), 0);
+        // a max below the min is the min
+        assert_eq!(negotiate_keep_alive(90, true, 60, Some(30), Override), 60);
+    }
+
+    #[test]
+    fn test_response_topic() {
+        assert_eq!(response_topic("resp", "c1").as_deref(), Some("resp/c1"));
+        assert!(response_topic("resp", "a/b").is_none());
+        assert!(response_topic("resp", "").is_none());
+
+        assert!(response_filter_allowed("resp", "c1", "resp/c1/#"));
+        assert!(response_filter_allowed("resp", "c1", "other/#"));
+        assert!(response_filter_allowed("resp", "c1", "response/c2"));
+        assert!(!response_filter_allowed("resp", "c1", "resp/c2/x"));
+        assert!(!response_filter_allowed("resp", "c1", "$share/g/resp/c2"));
+        assert!(!response_filter_allowed("resp", "c1", "+/c2/x"));
+        assert!(!response_filter_allowed("a/b", "c1", "a/+/c2"));
+        assert!(response_filter_allowed("resp", "c1", "+/c1/#"));
+        assert!(response_filter_allowed("a/b", "c1", "a/+/c1/x"));
+        // wildcards at the owner level, delivery keeps the response topics of others out
+        assert!(response_filter_allowed("resp", "c1", "resp/+/x"));
+        assert!(response_filter_allowed("resp", "c1", "$share/g/resp/#"));
+        assert!(response_filter_allowed("resp", "c1", "#"));
+        assert!(response_filter_allowed("resp", "c1", "+/+"));
+        assert!(response_filter_allowed("a/b", "c1", "a/#"));
+        // no client id is special
+        assert!(response_filter_allowed("resp", "other", "resp/+"));
+        assert!(!response_filter_allowed("resp", "other", "resp/others"));
+
+        assert_eq!(response_owner("resp", "resp/c1/x"), Some("c1"));
+        assert_eq!(response_owner("resp", "resp/c1"), Some("c1"));
+        assert!(response_owner("resp", "response/c1").is_none());
+        assert!(response_owner("resp", "resp").is_none());
+        assert!(response_owner("", "resp/c1").is_none());
+    }
+}
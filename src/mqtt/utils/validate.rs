@@ -0,0 +1,100 @@
+use super::super::error::MqttProtocolError;
+
+/// checks the rules every MQTT UTF-8 encoded string must follow: no U+0000
+/// and no control characters
+pub fn utf8_string_valid(s: &str) -> bool {
+    s.len() <= u16::MAX as usize && !s.chars().any(char::is_control)
+}
+
+/// decodes a UTF-8 string field read from the wire, rejecting ill-formed
+/// sequences instead of replacing them
+pub fn decode_utf8(buf: Vec<u8>) -> Result<String, MqttProtocolError> {
+    let s = String::from_utf8(buf).map_err(|_| MqttProtocolError::MalformedPayload)?;
+    if utf8_string_valid(&s) {
+        Ok(s)
+    } else {
+        Err(MqttProtocolError::MalformedPayload)
+    }
+}
+
+/// a topic name as carried by PUBLISH or a will message, wildcards are not allowed
+pub fn topic_name_valid(topic: &str, max_len: usize) -> bool {
+    !topic.is_empty()
+        && topic.len() <= max_len
+        && utf8_string_valid(topic)
+        && !topic.contains(['+', '#'])
+}
+
+/// a topic name a client is allowed to publish to, `$` topics are reserved
+/// for the broker
+pub fn client_topic_name_valid(topic: &str, max_len: usize) -> bool {
+    topic_name_valid(topic, max_len) && !topic.starts_with('$')
+}
+
+/// a topic filter as carried by SUBSCRIBE, `+` must occupy a whole level and
+/// `#` must be the last level
+pub fn topic_filter_valid(filter: &str, max_len: usize) -> bool {
+    if filter.is_empty() || filter.len() > max_len || !utf8_string_valid(filter) {
+        return false;
+    }
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('+') && level != "+" {
+            return false;
+        }
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// a subscription filter, which may be a well formed `$share/{group}/{filter}`
+pub fn subscription_valid(filter: &str, max_len: usize) -> bool {
+    if !topic_filter_valid(filter, max_len) {
+        return false;
+    }
+    if !filter.starts_with("$share/") {
+        return true;
+    }
+
+    match filter.splitn(3, '/').collect::<Vec<_>>()[..] {
+        [_, group, actual] => {
+            !group.is_empty() && !group.contains(['+', '#']) && !actual.is_empty()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_validation() {
+        assert!(topic_filter_valid("a/+/b/#", 64));
+        assert!(topic_filter_valid("#", 64));
+        assert!(!topic_filter_valid("a/b#", 64));
+        assert!(!topic_filter_valid("a/#/b", 64));
+        assert!(!topic_filter_valid("a+/b", 64));
+        assert!(!topic_filter_valid("a/\0/b", 64));
+        assert!(!topic_filter_valid("a/b/c", 4));
+
+        assert!(topic_name_valid("a/b", 64));
+        assert!(!topic_name_valid("a/+", 64));
+        assert!(!topic_name_valid("a/\u{7}", 64));
+        assert!(topic_name_valid("$SYS/a", 64));
+        assert!(!client_topic_name_valid("$SYS/a", 64));
+
+        assert!(subscription_valid("$share/g/a/#", 64));
+        assert!(!subscription_valid("$share//a", 64));
+        assert!(!subscription_valid("$share/g/", 64));
+        assert!(!subscription_valid("$share/g+/a", 64));
+
+        assert!(decode_utf8(vec![0xff, 0xfe]).is_err());
+        assert!(decode_utf8(b"a\0b".to_vec()).is_err());
+        assert_eq!(decode_utf8(b"a/b".to_vec()).unwrap(), "a/b");
+    }
+}
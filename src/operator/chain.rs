@@ -1,18 +1,431 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::Rng;
+use serde::Serialize;
+
+use crate::config::chain::{ErrorPolicy, ProcessorStep};
 use crate::processor::ProcessorInstance;
+use crate::processor::config::ProcessorConfig;
 
+use super::sink::Sink;
+use super::switch::{Switch, SwitchInfo};
 use super::trie::ClientId;
 
+/// counters kept per chain version, used to compare a canary with the stable version
+#[derive(Default)]
+pub struct ChainStats {
+    messages: AtomicU64,
+    passed: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    elapsed_us: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainStatsInfo {
+    pub messages: u64,
+    pub passed: u64,
+    pub dropped: u64,
+    pub failed: u64,
+    pub avg_latency_us: u64,
+}
+
+impl ChainStats {
+    pub fn record(&self, outcome: ChainOutcome, elapsed: std::time::Duration) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.elapsed_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let counter = match outcome {
+            ChainOutcome::Passed => &self.passed,
+            ChainOutcome::Dropped => &self.dropped,
+            ChainOutcome::Failed => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ChainStatsInfo {
+        let messages = self.messages.load(Ordering::Relaxed);
+        ChainStatsInfo {
+            messages,
+            passed: self.passed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            avg_latency_us: self
+                .elapsed_us
+                .load(Ordering::Relaxed)
+                .checked_div(messages)
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// upper bounds of the processor latency histogram buckets, in microseconds
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000, 500_000, 1_000_000,
+];
+
+/// counters of one processor in one chain version, a processor shared by several chains
+/// is counted separately in each
+#[derive(Default)]
+pub struct ProcessorStats {
+    passed: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    elapsed_us: AtomicU64,
+    // one more than LATENCY_BUCKETS_US for the calls slower than the last bound
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyBucket {
+    // None for the bucket without upper bound
+    pub le_us: Option<u64>,
+    // calls at most le_us long, the buckets are cumulative
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessorStatsInfo {
+    pub processor: String,
+    pub invocations: u64,
+    pub passed: u64,
+    pub dropped: u64,
+    pub failed: u64,
+    pub total_latency_us: u64,
+    pub avg_latency_us: u64,
+    pub latency: Vec<LatencyBucket>,
+}
+
+impl ProcessorStats {
+    pub fn record(&self, outcome: ChainOutcome, elapsed: std::time::Duration) {
+        let elapsed_us = elapsed.as_micros() as u64;
+        self.elapsed_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS_US.partition_point(|le| *le < elapsed_us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let counter = match outcome {
+            ChainOutcome::Passed => &self.passed,
+            ChainOutcome::Dropped => &self.dropped,
+            ChainOutcome::Failed => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn info(&self, processor: String) -> ProcessorStatsInfo {
+        let mut count = 0;
+        let latency = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                count += bucket.load(Ordering::Relaxed);
+                LatencyBucket {
+                    le_us: LATENCY_BUCKETS_US.get(i).copied(),
+                    count,
+                }
+            })
+            .collect();
+        let total_latency_us = self.elapsed_us.load(Ordering::Relaxed);
+        ProcessorStatsInfo {
+            processor,
+            invocations: count,
+            passed: self.passed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            total_latency_us,
+            avg_latency_us: total_latency_us.checked_div(count).unwrap_or(0),
+            latency,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ChainOutcome {
+    Passed,
+    Dropped,
+    Failed,
+}
+
 #[derive(Clone)]
 pub struct ProcessorChain {
     pub name: String,
+    pub version: u32,
     pub processors: Vec<ProcessorInstance>,
     pub delivery: bool,
+    // by name
+    pub sinks: Vec<(String, Box<dyn Sink>)>,
+    // the publisher waits for the chain before its PUBACK
+    pub sink_ack: bool,
+    pub on_error: Arc<ErrorPolicy>,
+    pub stats: Arc<ChainStats>,
+    // one per processor, in the same order
+    pub processor_stats: Arc<Vec<ProcessorStats>>,
+    // the number of processors of each step, run together when more than one
+    pub stages: Arc<Vec<usize>>,
+    // metadata keys delivered as user properties
+    pub metadata: Arc<Vec<String>>,
+}
+
+impl ProcessorChain {
+    pub fn new(
+        name: String,
+        version: u32,
+        processors: Vec<ProcessorInstance>,
+        delivery: bool,
+    ) -> Self {
+        let processor_stats = processors.iter().map(|_| Default::default()).collect();
+        let stages = vec![1; processors.len()];
+        ProcessorChain {
+            name,
+            version,
+            processors,
+            delivery,
+            sinks: vec![],
+            sink_ack: false,
+            on_error: Arc::new(ErrorPolicy::default()),
+            stats: Arc::new(ChainStats::default()),
+            processor_stats: Arc::new(processor_stats),
+            stages: Arc::new(stages),
+            metadata: Arc::new(vec![]),
+        }
+    }
+
+    /// group the processors in steps of the given sizes, which add up to their number
+    pub fn with_stages(mut self, stages: Vec<usize>) -> Self {
+        debug_assert_eq!(stages.iter().sum::<usize>(), self.processors.len());
+        self.stages = Arc::new(stages);
+        self
+    }
+
+    /// the steps of the chain, as configured
+    pub fn steps(&self) -> Vec<ProcessorStep> {
+        let mut ids = self.processors.iter().map(|p| p.processor.id().to_string());
+        self.stages
+            .iter()
+            .map(|&size| match size {
+                1 => ProcessorStep::Single(ids.next().unwrap_or_default()),
+                _ => ProcessorStep::Parallel(ids.by_ref().take(size).collect()),
+            })
+            .collect()
+    }
+
+    pub fn with_sinks(mut self, sinks: Vec<(String, Box<dyn Sink>)>) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    pub fn with_sink_ack(mut self, sink_ack: bool) -> Self {
+        self.sink_ack = sink_ack;
+        self
+    }
+
+    pub fn with_on_error(mut self, on_error: Arc<ErrorPolicy>) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Arc<Vec<String>>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    fn info(&self) -> ChainVersionInfo {
+        ChainVersionInfo {
+            version: self.version,
+            processors: self.steps(),
+            delivery: self.delivery,
+            on_error: self.on_error.as_ref().clone(),
+            stats: self.stats.info(),
+            processor_stats: self
+                .processors
+                .iter()
+                .zip(self.processor_stats.iter())
+                .map(|(p, stats)| stats.info(p.processor.id().to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// a chain with an optional canary version taking a share of the traffic
+#[derive(Clone)]
+pub struct VersionedChain {
+    pub stable: ProcessorChain,
+    pub canary: Option<(ProcessorChain, u8)>,
+    pub switch: Switch,
+}
+
+impl VersionedChain {
+    pub fn new(stable: ProcessorChain) -> Self {
+        VersionedChain {
+            stable,
+            canary: None,
+            switch: Switch::default(),
+        }
+    }
+
+    pub fn with_switch(mut self, switch: Switch) -> Self {
+        self.switch = switch;
+        self
+    }
+
+    pub fn next_version(&self) -> u32 {
+        let canary = self.canary.as_ref().map_or(0, |(c, _)| c.version);
+        self.stable.version.max(canary) + 1
+    }
+
+    /// pick the version a message goes through
+    pub fn select(&self) -> &ProcessorChain {
+        match &self.canary {
+            Some((canary, percent)) if rand::rng().random_range(0..100) < *percent => canary,
+            _ => &self.stable,
+        }
+    }
+
+    /// the counters of a processor summed over the stable and canary versions, None when
+    /// neither uses it
+    pub fn processor_health(&self, id: &str) -> Option<ProcessorHealth> {
+        let mut health = None;
+        let versions = std::iter::once(&self.stable).chain(self.canary.iter().map(|(c, _)| c));
+        for chain in versions {
+            for (processor, stats) in chain.processors.iter().zip(chain.processor_stats.iter()) {
+                if processor.processor.id().to_string() == id {
+                    health
+                        .get_or_insert_with(ProcessorHealth::default)
+                        .add(&stats.info(id.to_string()));
+                }
+            }
+        }
+        health
+    }
+
+    pub fn info(&self) -> ChainInfo {
+        ChainInfo {
+            name: self.stable.name.clone(),
+            stable: self.stable.info(),
+            canary: self.canary.as_ref().map(|(chain, percent)| CanaryInfo {
+                percent: *percent,
+                version: chain.info(),
+            }),
+            switch: self.switch.info(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainVersionInfo {
+    pub version: u32,
+    pub processors: Vec<ProcessorStep>,
+    pub delivery: bool,
+    pub on_error: ErrorPolicy,
+    pub stats: ChainStatsInfo,
+    pub processor_stats: Vec<ProcessorStatsInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryInfo {
+    pub percent: u8,
+    #[serde(flatten)]
+    pub version: ChainVersionInfo,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainInfo {
+    pub name: String,
+    pub stable: ChainVersionInfo,
+    pub canary: Option<CanaryInfo>,
+    #[serde(flatten)]
+    pub switch: SwitchInfo,
+}
+
+/// the counters of a processor over every chain using it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProcessorHealth {
+    pub invocations: u64,
+    pub passed: u64,
+    pub dropped: u64,
+    pub failed: u64,
+    pub avg_latency_us: u64,
+    #[serde(skip)]
+    total_latency_us: u64,
+}
+
+impl ProcessorHealth {
+    fn add(&mut self, stats: &ProcessorStatsInfo) {
+        self.merge(&ProcessorHealth {
+            invocations: stats.invocations,
+            passed: stats.passed,
+            dropped: stats.dropped,
+            failed: stats.failed,
+            avg_latency_us: stats.avg_latency_us,
+            total_latency_us: stats.total_latency_us,
+        });
+    }
+
+    pub fn merge(&mut self, other: &ProcessorHealth) {
+        self.invocations += other.invocations;
+        self.passed += other.passed;
+        self.dropped += other.dropped;
+        self.failed += other.failed;
+        self.total_latency_us += other.total_latency_us;
+        self.avg_latency_us = self
+            .total_latency_us
+            .checked_div(self.invocations)
+            .unwrap_or(0);
+    }
+}
+
+/// a processor registered with the router, from the configuration or the RESTful API
+#[derive(Debug, Clone, Serialize)]
+pub struct ProcessorInfo {
+    pub uuid: String,
+    pub config: ProcessorConfig,
+    // the chains it is used by, stable or canary version
+    pub chains: Vec<String>,
+    pub health: ProcessorHealth,
+}
+
+/// a route from the configuration, kept by the router to report and switch it
+#[derive(Clone)]
+pub struct Route {
+    pub chain: Chain,
+    pub switch: Switch,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub name: String,
+    pub topic: String,
+    pub client_id: Option<String>,
+    pub server_name: Option<String>,
+    pub condition: Option<String>,
+    pub chains: Vec<String>,
+    #[serde(flatten)]
+    pub switch: SwitchInfo,
+}
+
+impl Route {
+    pub fn info(&self) -> RouteInfo {
+        RouteInfo {
+            name: self.chain.name.clone(),
+            topic: self.chain.topic_filter.clone(),
+            client_id: self.chain.client_id.clone(),
+            server_name: self.chain.server_name.clone(),
+            condition: self.chain.condition.clone(),
+            chains: self.chain.chains.clone(),
+            switch: self.switch.info(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Chain {
+    // name of the route this entry comes from
+    pub name: String,
     pub topic_filter: String,
     pub client_id: Option<String>,
+    pub server_name: Option<String>,
+    // minijinja expression evaluated over each message
+    pub condition: Option<String>,
 
     pub chains: Vec<String>,
 }
@@ -25,7 +438,11 @@ impl ClientId for Chain {
 
 impl PartialEq for Chain {
     fn eq(&self, other: &Self) -> bool {
-        self.client_id == other.client_id && self.topic_filter == other.topic_filter
+        self.name == other.name
+            && self.client_id == other.client_id
+            && self.server_name == other.server_name
+            && self.condition == other.condition
+            && self.topic_filter == other.topic_filter
     }
 }
 
@@ -40,3 +457,72 @@ impl std::hash::Hash for Chain {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_canary_split() {
+        let mut chain = VersionedChain::new(ProcessorChain::new("c".into(), 1, vec![], true));
+        assert!((0..100).all(|_| chain.select().version == 1));
+        assert_eq!(chain.next_version(), 2);
+
+        chain.canary = Some((ProcessorChain::new("c".into(), 2, vec![], true), 50));
+        let canary = (0..1000).filter(|_| chain.select().version == 2).count();
+        assert!(canary > 300 && canary < 700);
+        assert_eq!(chain.next_version(), 3);
+    }
+
+    #[test]
+    fn test_processor_stats() {
+        let stats = ProcessorStats::default();
+        stats.record(ChainOutcome::Passed, Duration::from_micros(50));
+        stats.record(ChainOutcome::Dropped, Duration::from_micros(51));
+        stats.record(ChainOutcome::Failed, Duration::from_secs(2));
+
+        let info = stats.info("p".into());
+        assert_eq!(
+            (info.invocations, info.passed, info.dropped, info.failed),
+            (3, 1, 1, 1)
+        );
+        assert_eq!(info.total_latency_us, 2_000_101);
+        let counts: Vec<u64> = info.latency.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3]);
+        assert_eq!(info.latency.last().unwrap().le_us, None);
+    }
+
+    #[test]
+    fn test_processor_health() {
+        use crate::processor::processors::logger::LoggerProcessor;
+
+        let id = uuid::Uuid::new_v4();
+        let logger = ProcessorConfig::Logger {
+            level: "info".into(),
+        };
+        let instance = || {
+            LoggerProcessor::new_with_id(id, logger.clone())
+                .unwrap()
+                .into()
+        };
+        let mut chain =
+            VersionedChain::new(ProcessorChain::new("c".into(), 1, vec![instance()], true));
+        chain.canary = Some((
+            ProcessorChain::new("c".into(), 2, vec![instance()], true),
+            10,
+        ));
+        chain.stable.processor_stats[0].record(ChainOutcome::Passed, Duration::from_micros(10));
+        let canary = &chain.canary.as_ref().unwrap().0;
+        canary.processor_stats[0].record(ChainOutcome::Failed, Duration::from_micros(30));
+
+        let health = chain.processor_health(&id.to_string()).unwrap();
+        assert_eq!(
+            (health.invocations, health.passed, health.failed),
+            (2, 1, 1)
+        );
+        assert_eq!(health.avg_latency_us, 20);
+        assert!(chain.processor_health("other").is_none());
+    }
+}
@@ -1,14 +1,31 @@
 use bytes::Bytes;
+use tokio::sync::oneshot;
 
 use crate::mqtt::{
     QoS,
     protocol::{property::PropertyUser, publish::PublishOptions},
 };
 
+use crate::processor::Processor;
+use crate::processor::config::ProcessorConfig;
+use crate::processor::message::Message;
+
+use crate::config::chain::{ErrorPolicy, ProcessorStep};
+use crate::config::schedule::Schedule;
+
+use super::chain::{ChainInfo, ProcessorInfo, RouteInfo};
+use super::error::OperatorError;
 use super::sink::Sink;
 
-#[allow(dead_code)]
-pub(crate) enum OperatorAck {}
+pub(crate) enum OperatorAck {
+    Chains(Vec<ChainInfo>),
+    Chain(ChainInfo),
+    Routes(Vec<RouteInfo>),
+    Route(RouteInfo),
+    Processors(Vec<ProcessorInfo>),
+    Processor(ProcessorInfo),
+    Error(OperatorError),
+}
 
 pub(crate) enum OperatorCommand {
     Subscribe {
@@ -31,12 +48,23 @@ pub(crate) enum OperatorCommand {
     },
     Publish {
         client_id: String,
+        server_name: Option<String>,
         retain: bool,
         qos: QoS,
         topic: String,
         payload: Bytes,
         user_properties: Vec<PropertyUser>,
         options: PublishOptions,
+        // the publish from its client to the matcher, disabled unless telemetry traces it
+        span: tracing::Span,
+        // told whether the chains with sink_ack are through with the message, false when one
+        // failed on it
+        ack: Option<oneshot::Sender<bool>>,
+    },
+    // publish forwarded by another cluster node, delivered to local subscribers only
+    ClusterPublish {
+        share_group: Option<String>,
+        message: Message,
     },
     SparkPlugBPublish {
         client_id: String,
@@ -45,6 +73,81 @@ pub(crate) enum OperatorCommand {
         retain: bool,
         qos: QoS,
     },
+    ListChains {
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    // replace a chain, or run the new version as a canary on a share of the traffic
+    UpdateChain {
+        name: String,
+        processors: Vec<ProcessorStep>,
+        delivery: bool,
+        // the policy of the chain is kept when not set
+        on_error: Option<ErrorPolicy>,
+        canary_percent: Option<u8>,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    PromoteCanary {
+        name: String,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    RollbackCanary {
+        name: String,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    // enable or disable a chain, optionally only inside a schedule window
+    SwitchChain {
+        name: String,
+        enabled: bool,
+        schedule: Option<Schedule>,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    ListRoutes {
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    SwitchRoute {
+        name: String,
+        enabled: bool,
+        schedule: Option<Schedule>,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    // create a route, or replace the filter and chains of an existing one
+    UpdateRoute {
+        name: String,
+        topic: String,
+        client_id: Option<String>,
+        server_name: Option<String>,
+        condition: Option<String>,
+        chains: Vec<String>,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    DeleteRoute {
+        name: String,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    DeleteChain {
+        name: String,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    ListProcessors {
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    // build a processor, chains can use it once it is installed
+    CreateProcessor {
+        uuid: String,
+        config: ProcessorConfig,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    // a processor built off the router task, sent back to it by CreateProcessor
+    InstallProcessor {
+        uuid: String,
+        config: ProcessorConfig,
+        processor: Box<dyn Processor>,
+        resp: oneshot::Sender<OperatorAck>,
+    },
+    DeleteProcessor {
+        uuid: String,
+        resp: oneshot::Sender<OperatorAck>,
+    },
 }
 
 impl std::fmt::Display for OperatorCommand {
@@ -68,9 +171,66 @@ impl std::fmt::Display for OperatorCommand {
             } => {
                 write!(f, "Publish: client_id={}, topic={}", client_id, topic)
             }
+            OperatorCommand::ClusterPublish { message, .. } => {
+                write!(
+                    f,
+                    "ClusterPublish: client_id={}, topic={}",
+                    message.client_id, message.topic
+                )
+            }
             OperatorCommand::SparkPlugBPublish { topic, .. } => {
                 write!(f, "SparkPlugBPublish: topic={}", topic)
             }
+            OperatorCommand::ListChains { .. } => {
+                write!(f, "ListChains")
+            }
+            OperatorCommand::UpdateChain {
+                name,
+                canary_percent,
+                ..
+            } => {
+                write!(
+                    f,
+                    "UpdateChain: name={}, canary_percent={:?}",
+                    name, canary_percent
+                )
+            }
+            OperatorCommand::PromoteCanary { name, .. } => {
+                write!(f, "PromoteCanary: name={}", name)
+            }
+            OperatorCommand::RollbackCanary { name, .. } => {
+                write!(f, "RollbackCanary: name={}", name)
+            }
+            OperatorCommand::SwitchChain { name, enabled, .. } => {
+                write!(f, "SwitchChain: name={}, enabled={}", name, enabled)
+            }
+            OperatorCommand::ListRoutes { .. } => {
+                write!(f, "ListRoutes")
+            }
+            OperatorCommand::SwitchRoute { name, enabled, .. } => {
+                write!(f, "SwitchRoute: name={}, enabled={}", name, enabled)
+            }
+            OperatorCommand::UpdateRoute { name, topic, .. } => {
+                write!(f, "UpdateRoute: name={}, topic={}", name, topic)
+            }
+            OperatorCommand::DeleteRoute { name, .. } => {
+                write!(f, "DeleteRoute: name={}", name)
+            }
+            OperatorCommand::DeleteChain { name, .. } => {
+                write!(f, "DeleteChain: name={}", name)
+            }
+            OperatorCommand::ListProcessors { .. } => {
+                write!(f, "ListProcessors")
+            }
+            OperatorCommand::CreateProcessor { uuid, .. } => {
+                write!(f, "CreateProcessor: uuid={}", uuid)
+            }
+            OperatorCommand::InstallProcessor { uuid, .. } => {
+                write!(f, "InstallProcessor: uuid={}", uuid)
+            }
+            OperatorCommand::DeleteProcessor { uuid, .. } => {
+                write!(f, "DeleteProcessor: uuid={}", uuid)
+            }
         }
     }
 }
@@ -10,4 +10,30 @@ pub enum OperatorError {
     ChannelSendError(String),
     #[error("Oneshot receive error: {0}")]
     OneshotReceiveError(#[from] oneshot::error::RecvError),
+    #[error("Chain not found")]
+    ChainNotFound,
+    #[error("Processor not found: {0}")]
+    ProcessorNotFound(String),
+    #[error("Processor already exists: {0}")]
+    ProcessorExists(String),
+    #[error("Processor in use by chains: {0}")]
+    ProcessorInUse(String),
+    #[error("Invalid processor: {0}")]
+    InvalidProcessor(String),
+    #[error("Chain has no canary")]
+    CanaryNotFound,
+    #[error("Invalid error policy: {0}")]
+    InvalidErrorPolicy(String),
+    #[error("Invalid canary percent: {0}")]
+    InvalidCanaryPercent(u8),
+    #[error("Route not found")]
+    RouteNotFound,
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("Invalid topic filter: {0}")]
+    InvalidTopicFilter(String),
+    #[error("Invalid route condition: {0}")]
+    InvalidRouteCondition(String),
+    #[error("WASM engine error: {0}")]
+    WasmEngine(String),
 }
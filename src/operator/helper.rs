@@ -1,20 +1,38 @@
+use std::sync::LazyLock;
+
 use bytes::Bytes;
 use serde::Serialize;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::CONFIG;
+use crate::cluster::ClusterHelper;
+use crate::config::chain::{ErrorPolicy, ProcessorStep};
+use crate::config::schedule::Schedule;
 use crate::mqtt::QoS;
 use crate::mqtt::protocol::{property::PropertyUser, publish::PublishOptions};
+use crate::processor::config::ProcessorConfig;
+use crate::processor::message::Message;
 use crate::utils::time::now_milliseconds;
 
-use super::command::OperatorCommand;
+use super::chain::{ChainInfo, ProcessorInfo, RouteInfo};
+use super::command::{OperatorAck, OperatorCommand};
 use super::error::OperatorError;
+use super::matcher::publishes_queued;
+use super::router::chains_in_flight;
 use super::sink::Sink;
 
+// whether a chain has sink_ack, QoS 1 publishes only wait for the router when one does
+static SINK_ACK: LazyLock<bool> = LazyLock::new(|| {
+    CONFIG
+        .get()
+        .is_some_and(|c| c.chain.iter().any(|c| c.sink_ack))
+});
+
 #[derive(Clone)]
 pub struct Helper {
     matcher_tx: mpsc::Sender<OperatorCommand>,
     router_tx: mpsc::Sender<OperatorCommand>,
+    cluster_helper: Option<ClusterHelper>,
 }
 
 #[derive(Serialize)]
@@ -24,16 +42,25 @@ struct StateMessage {
 }
 
 impl Helper {
-    pub fn new(
+    pub(crate) fn new(
         matcher_tx: mpsc::Sender<OperatorCommand>,
         router_tx: mpsc::Sender<OperatorCommand>,
+        cluster_helper: Option<ClusterHelper>,
     ) -> Self {
         Helper {
             matcher_tx,
             router_tx,
+            cluster_helper,
         }
     }
 
+    /// messages queued in the operator, waiting for a matcher worker or still going through
+    /// processor chains
+    pub fn pending(&self) -> usize {
+        let queued = |tx: &mpsc::Sender<OperatorCommand>| tx.max_capacity() - tx.capacity();
+        queued(&self.matcher_tx) + queued(&self.router_tx) + publishes_queued() + chains_in_flight()
+    }
+
     pub async fn subscribe(
         &self,
         client_id: String,
@@ -83,30 +110,322 @@ impl Helper {
             .map_err(|e| OperatorError::ChannelSendError(e.to_string()))
     }
 
+    /// a QoS 1 message gets a receiver told whether the chains with `sink_ack` took it, when
+    /// some chain has it
     pub async fn publish(
         &self,
         client_id: String,
+        server_name: Option<String>,
         retain: bool,
         qos: QoS,
         topic: String,
         payload: Bytes,
         user_properties: Vec<PropertyUser>,
-        options: PublishOptions,
-    ) -> Result<(), OperatorError> {
+        mut options: PublishOptions,
+    ) -> Result<Option<oneshot::Receiver<bool>>, OperatorError> {
+        let (ack, acked) = match qos {
+            QoS::AtLeastOnce if *SINK_ACK => {
+                let (ack, acked) = oneshot::channel();
+                (Some(ack), Some(acked))
+            }
+            _ => (None, None),
+        };
+        options.trace_id = super::trace::start(&client_id, &topic);
+        // a trace of its own, the client span lives as long as the connection
+        let span = tracing::debug_span!(parent: None, "publish", client_id = %client_id, topic = %topic, qos = qos as u8);
+        #[cfg(feature = "telemetry")]
+        crate::telemetry::link_remote(&span, &user_properties);
         self.router_tx
             .send(OperatorCommand::Publish {
                 client_id,
+                server_name,
                 retain,
                 qos,
                 topic,
                 payload,
                 user_properties,
                 options,
+                span,
+                ack,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+        Ok(acked)
+    }
+
+    /// deliver a message forwarded by another cluster node to local subscribers
+    pub async fn cluster_publish(
+        &self,
+        share_group: Option<String>,
+        message: Message,
+    ) -> Result<(), OperatorError> {
+        self.matcher_tx
+            .send(OperatorCommand::ClusterPublish {
+                share_group,
+                message,
             })
             .await
             .map_err(|e| OperatorError::ChannelSendError(e.to_string()))
     }
 
+    /// a client connected to this node, other cluster nodes drop its session
+    pub async fn client_connected(&self, client_id: String) -> Result<(), OperatorError> {
+        if let Some(cluster_helper) = &self.cluster_helper {
+            cluster_helper
+                .client_connected(client_id)
+                .await
+                .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub async fn list_chains(&self) -> Result<Vec<ChainInfo>, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::ListChains { resp: resp_tx })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        match resp_rx.await? {
+            OperatorAck::Chains(chains) => Ok(chains),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
+    /// install a new chain version, as a canary when `canary_percent` is set
+    pub async fn update_chain(
+        &self,
+        name: String,
+        processors: Vec<ProcessorStep>,
+        delivery: bool,
+        on_error: Option<ErrorPolicy>,
+        canary_percent: Option<u8>,
+    ) -> Result<ChainInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::UpdateChain {
+                name,
+                processors,
+                delivery,
+                on_error,
+                canary_percent,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::chain_ack(resp_rx.await?)
+    }
+
+    /// make the canary the stable version of the chain
+    pub async fn promote_canary(&self, name: String) -> Result<ChainInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::PromoteCanary {
+                name,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::chain_ack(resp_rx.await?)
+    }
+
+    /// drop the canary, all traffic goes back to the stable version
+    pub async fn rollback_canary(&self, name: String) -> Result<ChainInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::RollbackCanary {
+                name,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::chain_ack(resp_rx.await?)
+    }
+
+    /// enable or disable a chain, with a schedule it is only active inside the window
+    pub async fn switch_chain(
+        &self,
+        name: String,
+        enabled: bool,
+        schedule: Option<Schedule>,
+    ) -> Result<ChainInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::SwitchChain {
+                name,
+                enabled,
+                schedule,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::chain_ack(resp_rx.await?)
+    }
+
+    pub async fn list_routes(&self) -> Result<Vec<RouteInfo>, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::ListRoutes { resp: resp_tx })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        match resp_rx.await? {
+            OperatorAck::Routes(routes) => Ok(routes),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
+    /// enable or disable a route, with a schedule it is only active inside the window
+    pub async fn switch_route(
+        &self,
+        name: String,
+        enabled: bool,
+        schedule: Option<Schedule>,
+    ) -> Result<RouteInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::SwitchRoute {
+                name,
+                enabled,
+                schedule,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::route_ack(resp_rx.await?)
+    }
+
+    /// create a route, or replace the topic filter and chains of an existing one
+    pub async fn update_route(
+        &self,
+        name: String,
+        topic: String,
+        client_id: Option<String>,
+        server_name: Option<String>,
+        condition: Option<String>,
+        chains: Vec<String>,
+    ) -> Result<RouteInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::UpdateRoute {
+                name,
+                topic,
+                client_id,
+                server_name,
+                condition,
+                chains,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::route_ack(resp_rx.await?)
+    }
+
+    pub async fn delete_route(&self, name: String) -> Result<RouteInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::DeleteRoute {
+                name,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::route_ack(resp_rx.await?)
+    }
+
+    pub async fn delete_chain(&self, name: String) -> Result<ChainInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::DeleteChain {
+                name,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::chain_ack(resp_rx.await?)
+    }
+
+    pub async fn list_processors(&self) -> Result<Vec<ProcessorInfo>, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::ListProcessors { resp: resp_tx })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        match resp_rx.await? {
+            OperatorAck::Processors(processors) => Ok(processors),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
+    /// build a processor and register it, chains can use it from then on
+    pub async fn create_processor(
+        &self,
+        uuid: String,
+        config: ProcessorConfig,
+    ) -> Result<ProcessorInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::CreateProcessor {
+                uuid,
+                config,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::processor_ack(resp_rx.await?)
+    }
+
+    /// remove a processor no chain uses
+    pub async fn delete_processor(&self, uuid: String) -> Result<ProcessorInfo, OperatorError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.router_tx
+            .send(OperatorCommand::DeleteProcessor {
+                uuid,
+                resp: resp_tx,
+            })
+            .await
+            .map_err(|e| OperatorError::ChannelSendError(e.to_string()))?;
+
+        Self::processor_ack(resp_rx.await?)
+    }
+
+    fn processor_ack(ack: OperatorAck) -> Result<ProcessorInfo, OperatorError> {
+        match ack {
+            OperatorAck::Processor(processor) => Ok(processor),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
+    fn route_ack(ack: OperatorAck) -> Result<RouteInfo, OperatorError> {
+        match ack {
+            OperatorAck::Route(route) => Ok(route),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
+    fn chain_ack(ack: OperatorAck) -> Result<ChainInfo, OperatorError> {
+        match ack {
+            OperatorAck::Chain(chain) => Ok(chain),
+            OperatorAck::Error(e) => Err(e),
+            _ => Err(OperatorError::InternalError),
+        }
+    }
+
     pub async fn sparkplug_b_publish(
         &self,
         topic: String,
@@ -125,12 +444,21 @@ impl Helper {
     }
 
     pub async fn sparkplug_b_state_online(&self) -> Result<(), OperatorError> {
+        self.sparkplug_b_state(true).await
+    }
+
+    /// the host application goes offline, published on shutdown
+    pub async fn sparkplug_b_state_offline(&self) -> Result<(), OperatorError> {
+        self.sparkplug_b_state(false).await
+    }
+
+    async fn sparkplug_b_state(&self, online: bool) -> Result<(), OperatorError> {
         let topic = format!(
             "spBv1.0/STATE/{}",
             CONFIG.get().unwrap().service.sparkplug_b.application_id
         );
         let payload = StateMessage {
-            online: true,
+            online,
             timestamp: now_milliseconds(),
         };
 
@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Values by string key, at most `capacity` of them, the least recently used one goes first
+/// when a new key does not fit. The routes of the router and the subscribers of the matcher
+/// workers by topic, the lookups of the enrich processor by key.
+pub struct LruCache<V> {
+    capacity: usize,
+    // bumped on every use, the key of `order`
+    tick: u64,
+    entries: HashMap<String, (V, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl<V> LruCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        let (value, used) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        if let Some(key) = self.order.remove(used) {
+            self.order.insert(self.tick, key);
+        }
+        *used = self.tick;
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: String, value: V) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        } else if self.entries.len() >= self.capacity
+            && let Some((_, oldest)) = self.order.pop_first()
+        {
+            self.entries.remove(&oldest);
+        }
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// keeps the entries `f` returns true for, it may change their values
+    pub fn retain(&mut self, mut f: impl FnMut(&str, &mut V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (value, used)| {
+            let keep = f(key, value);
+            if !keep {
+                order.remove(used);
+            }
+            keep
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn test_lru() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        // a is used, b goes first
+        assert_eq!(cache.get("a"), Some(&1));
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(&3));
+
+        // replacing a value is a use, not a new entry
+        cache.insert("a".to_string(), 4);
+        cache.insert("d".to_string(), 5);
+        assert_eq!(cache.get("a"), Some(&4));
+        assert_eq!(cache.get("c"), None);
+
+        cache.retain(|key, value| {
+            *value += 1;
+            key != "d"
+        });
+        assert_eq!(cache.get("a"), Some(&5));
+        assert_eq!(cache.get("d"), None);
+        assert_eq!((cache.entries.len(), cache.order.len()), (1, 1));
+
+        cache.clear();
+        assert!(cache.entries.is_empty() && cache.order.is_empty());
+    }
+}
@@ -1,19 +1,34 @@
 use std::collections::HashMap;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tracing::{debug, trace};
 
-use crate::mqtt::QoS;
-use crate::processor::message::Message;
+use crate::CONFIG;
+use crate::cluster::{ClusterHelper, share};
+use crate::config::ShareStrategy;
+use crate::mqtt::{
+    QoS,
+    protocol::publish::{PublishBody, SharedPublish},
+    utils as mqtt_utils,
+};
 use crate::utils as g_utils;
 
 use super::command::OperatorCommand;
+use super::lru::LruCache;
+use super::property_route::PropertyRoutes;
 use super::sink::{DefaultSink, Sink};
+use super::topic_filter::{Interner, TopicFilter};
+use super::trace;
 use super::trie::{ClientId, TopicTrie};
 use super::utils;
 
+// topics whose subscribers a worker keeps, see `common.match_cache_size`
+const DEFAULT_MATCH_CACHE_SIZE: usize = 10_000;
+
 static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone)]
@@ -21,7 +36,7 @@ pub struct Subscriber {
     client_id: String,
 
     share_group: Option<String>,
-    topic: String,
+    topic: Arc<TopicFilter>,
 
     qos: QoS,
     no_local: bool,
@@ -36,7 +51,7 @@ impl Subscriber {
         Subscriber {
             client_id,
             share_group,
-            topic: String::new(),
+            topic: Arc::default(),
             no_local: false,
             subscription_id: None,
             sink: DefaultSink::new(),
@@ -52,6 +67,12 @@ impl Subscriber {
             true
         }
     }
+
+    /// response topics only reach the client they were assigned to, whatever the filter,
+    /// other cluster nodes check their own subscribers
+    pub fn filter_response(&self, owner: Option<&str>) -> bool {
+        owner.is_none_or(|owner| owner == self.client_id || self.sink.remote_node().is_some())
+    }
 }
 
 impl PartialEq for Subscriber {
@@ -74,20 +95,111 @@ impl ClientId for Subscriber {
     }
 }
 
+// publishes handed to the workers and not yet delivered
+static PUBLISHES_QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// number of publishes waiting in the matcher workers
+pub fn publishes_queued() -> usize {
+    PUBLISHES_QUEUED.load(Ordering::Relaxed)
+}
+
+/// a subscription change, the workers drop what it made stale from their caches
+enum Change {
+    Subscribe(Arc<TopicFilter>),
+    Unsubscribe {
+        client_id: String,
+        share_group: Option<String>,
+        topic: String,
+    },
+    RemoveClient(String),
+}
+
+// the subscribers of a topic, normal ones and share group members by group
+type Matches<'a> = (
+    Vec<&'a Arc<Subscriber>>,
+    HashMap<String, Vec<&'a Arc<Subscriber>>>,
+);
+
+enum Job {
+    // a snapshot of the subscriptions and the changes since the previous one
+    Update {
+        trie: TopicTrie<Arc<Subscriber>>,
+        changes: Arc<[Change]>,
+    },
+    Publish(Box<OperatorCommand>),
+}
+
+/// the deliveries of a batch of publishes, kept in order per subscriber so that each one
+/// gets its share with one call to its sink. A subscriber is a client and its share group,
+/// the subscriptions of another node all have the client id of the node but sinks of their own
+#[derive(Default)]
+struct Deliveries {
+    index: HashMap<Arc<Subscriber>, usize>,
+    clients: Vec<(Arc<Subscriber>, Vec<SharedPublish>)>,
+}
+
+impl Deliveries {
+    fn push(&mut self, client: &Arc<Subscriber>, publish: SharedPublish) {
+        let index = match self.index.get(client) {
+            Some(index) => *index,
+            None => {
+                self.index.insert(client.clone(), self.clients.len());
+                self.clients.push((client.clone(), vec![]));
+                self.clients.len() - 1
+            }
+        };
+        self.clients[index].1.push(publish);
+    }
+
+    fn flush(&mut self) {
+        self.index.clear();
+        for (client, publishes) in self.clients.drain(..) {
+            client
+                .sink
+                .deliver_batch(&client.client_id, publishes, client.persist);
+        }
+    }
+}
+
+/// Subscriptions are changed by the matcher task only, publishes are matched and delivered
+/// by the workers against immutable snapshots of the trie. The snapshots go to the workers
+/// through the same queues as the publishes, a publish always sees the subscriptions made
+/// before it, and the publishes of one client go to the same worker and keep their order.
 pub(crate) struct Matcher {
     command_rx: Option<mpsc::Receiver<OperatorCommand>>,
     command_tx: mpsc::Sender<OperatorCommand>,
 
-    trie: Option<TopicTrie<Subscriber>>,
+    trie: Option<TopicTrie<Arc<Subscriber>>>,
+    cluster_helper: Option<ClusterHelper>,
+    property_routes: PropertyRoutes,
+    workers: usize,
+    batch_size: usize,
 }
 
 impl Matcher {
-    pub fn new() -> Self {
+    pub fn new(cluster_helper: Option<ClusterHelper>) -> Self {
         let (tx, rx) = mpsc::channel(1024);
         Matcher {
             command_rx: Some(rx),
             command_tx: tx,
             trie: Some(TopicTrie::new()),
+            cluster_helper,
+            property_routes: PropertyRoutes::new(
+                CONFIG
+                    .get()
+                    .map(|c| c.property_route.clone())
+                    .unwrap_or_default(),
+            ),
+            workers: CONFIG
+                .get()
+                .and_then(|c| c.common.matcher_workers)
+                .unwrap_or_else(|| {
+                    std::thread::available_parallelism()
+                        .map(|n| n.get())
+                        .unwrap_or(1)
+                })
+                .max(1),
+            batch_size: super::batch_size(),
         }
     }
 
@@ -95,23 +207,139 @@ impl Matcher {
         self.command_tx.clone()
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> JoinHandle<()> {
         let mut command_rx = self.command_rx.take().unwrap();
         let mut trie = self.trie.take().unwrap();
-        let mut cache: HashMap<String, Vec<Subscriber>> = HashMap::new();
+        let mut interner = Interner::new();
+        let cluster_helper = self.cluster_helper.clone();
+        let batch_size = self.batch_size;
+
+        let (workers, handles): (Vec<_>, Vec<_>) = (0..self.workers)
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(1024);
+                let property_routes = self.property_routes.clone();
+                (
+                    tx,
+                    tokio::spawn(Self::work(rx, property_routes, batch_size)),
+                )
+            })
+            .unzip();
 
         tokio::spawn(async move {
-            while let Some(cmd) = command_rx.recv().await {
-                Self::process_command(&mut trie, &mut cache, cmd);
+            let hasher = RandomState::new();
+            let mut changes = vec![];
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut queues: Vec<Vec<Job>> = workers.iter().map(|_| vec![]).collect();
+            while command_rx.recv_many(&mut batch, batch_size).await > 0 {
+                for cmd in batch.drain(..) {
+                    let publisher = match &cmd {
+                        OperatorCommand::Publish { client_id, .. } => client_id,
+                        OperatorCommand::ClusterPublish { message, .. } => &message.client_id,
+                        _ => {
+                            if let Some(change) = Self::process_command(
+                                &mut trie,
+                                &mut interner,
+                                cluster_helper.as_ref(),
+                                cmd,
+                            )
+                            .await
+                            {
+                                changes.push(change);
+                            }
+                            continue;
+                        }
+                    };
+
+                    // a new snapshot only when a publish follows changes, bursts of
+                    // subscriptions share one
+                    let worker = hasher.hash_one(publisher) as usize % workers.len();
+                    if !changes.is_empty() {
+                        let changes: Arc<[Change]> = std::mem::take(&mut changes).into();
+                        for queue in &mut queues {
+                            queue.push(Job::Update {
+                                trie: trie.clone(),
+                                changes: changes.clone(),
+                            });
+                        }
+                    }
+                    PUBLISHES_QUEUED.fetch_add(1, Ordering::Relaxed);
+                    queues[worker].push(Job::Publish(Box::new(cmd)));
+                }
+
+                // the jobs of a batch go to each worker with one reservation
+                for (worker, queue) in workers.iter().zip(&mut queues) {
+                    if queue.is_empty() {
+                        continue;
+                    }
+                    match worker.reserve_many(queue.len()).await {
+                        Ok(permits) => {
+                            for (permit, job) in permits.zip(queue.drain(..)) {
+                                permit.send(job);
+                            }
+                        }
+                        Err(_) => {
+                            let lost = queue
+                                .drain(..)
+                                .filter(|job| matches!(job, Job::Publish(_)))
+                                .count();
+                            PUBLISHES_QUEUED.fetch_sub(lost, Ordering::Relaxed);
+                        }
+                    }
+                }
             }
-        });
+
+            drop(workers);
+            for handle in handles {
+                let _ = handle.await;
+            }
+        })
     }
 
-    fn process_command(
-        trie: &mut TopicTrie<Subscriber>,
-        cache: &mut HashMap<String, Vec<Subscriber>>,
-        cmd: OperatorCommand,
+    async fn work(
+        mut jobs: mpsc::Receiver<Job>,
+        property_routes: PropertyRoutes,
+        batch_size: usize,
     ) {
+        let mut trie = TopicTrie::new();
+        let mut cache: LruCache<Vec<Arc<Subscriber>>> = LruCache::new(
+            CONFIG
+                .get()
+                .and_then(|c| c.common.match_cache_size)
+                .unwrap_or(DEFAULT_MATCH_CACHE_SIZE),
+        );
+        let mut deliveries = Deliveries::default();
+        let mut batch = Vec::with_capacity(batch_size);
+        while jobs.recv_many(&mut batch, batch_size).await > 0 {
+            let mut published = 0;
+            for job in batch.drain(..) {
+                match job {
+                    Job::Update {
+                        trie: snapshot,
+                        changes,
+                    } => {
+                        trie = snapshot;
+                        for change in changes.iter() {
+                            Self::invalidate(&mut cache, change);
+                        }
+                    }
+                    Job::Publish(cmd) => {
+                        Self::publish(&mut cache, &trie, &property_routes, &mut deliveries, *cmd);
+                        published += 1;
+                    }
+                }
+            }
+            deliveries.flush();
+            PUBLISHES_QUEUED.fetch_sub(published, Ordering::Relaxed);
+        }
+    }
+
+    /// applies a subscription change to the trie, what the workers have to know about it
+    async fn process_command(
+        trie: &mut TopicTrie<Arc<Subscriber>>,
+        interner: &mut Interner,
+        cluster_helper: Option<&ClusterHelper>,
+        cmd: OperatorCommand,
+    ) -> Option<Change> {
         use OperatorCommand::*;
         match cmd {
             Subscribe {
@@ -129,20 +357,28 @@ impl Matcher {
                     g_utils::TruncateDisplay::new(&client_id, 24),
                     g_utils::TruncateDisplay::new(&topic, 128)
                 );
-                cache.retain(|k, _| !utils::topic_match(&topic, k));
+                if let Some(cluster_helper) = cluster_helper
+                    && sink.remote_node().is_none()
+                {
+                    let _ = cluster_helper
+                        .local_subscribe(client_id.clone(), share_group.clone(), topic.clone())
+                        .await;
+                }
+                let filter = TopicFilter::compile(&topic, interner);
                 trie.insert(
-                    &topic,
-                    Subscriber {
+                    &filter,
+                    Arc::new(Subscriber {
                         client_id,
                         share_group,
-                        topic: topic.clone(),
+                        topic: filter.clone(),
                         qos,
                         no_local,
                         subscription_id,
                         persist,
                         sink,
-                    },
+                    }),
                 );
+                Some(Change::Subscribe(filter))
             }
             Unsubscribe {
                 client_id,
@@ -154,17 +390,100 @@ impl Matcher {
                     g_utils::TruncateDisplay::new(&client_id, 24),
                     g_utils::TruncateDisplay::new(&topic, 128)
                 );
+                let filter = TopicFilter::compile(&topic, interner);
+                trie.remove(
+                    &filter,
+                    &Arc::new(Subscriber::default(client_id.clone(), share_group.clone())),
+                );
+                drop(filter);
+                interner.maybe_prune();
+                if let Some(cluster_helper) = cluster_helper {
+                    let _ = cluster_helper
+                        .local_unsubscribe(client_id.clone(), share_group.clone(), topic.clone())
+                        .await;
+                }
+                Some(Change::Unsubscribe {
+                    client_id,
+                    share_group,
+                    topic,
+                })
+            }
+            RemoveClient { client_id } => {
+                debug!(
+                    "remove client: {}",
+                    g_utils::TruncateDisplay::new(&client_id, 24)
+                );
+                trie.remove_client(&client_id);
+                interner.maybe_prune();
+                if let Some(cluster_helper) = cluster_helper {
+                    let _ = cluster_helper.local_remove_client(client_id.clone()).await;
+                }
+                Some(Change::RemoveClient(client_id))
+            }
+            Publish { .. } | ClusterPublish { .. } => {
+                unreachable!("publishes are handled by the matcher workers");
+            }
+            SparkPlugBPublish { .. } => {
+                unreachable!("SparkPlugBPublish should not be handled in Matcher");
+            }
+            ListChains { .. }
+            | UpdateChain { .. }
+            | PromoteCanary { .. }
+            | RollbackCanary { .. }
+            | SwitchChain { .. }
+            | ListRoutes { .. }
+            | SwitchRoute { .. }
+            | UpdateRoute { .. }
+            | DeleteRoute { .. }
+            | DeleteChain { .. }
+            | ListProcessors { .. }
+            | CreateProcessor { .. }
+            | InstallProcessor { .. }
+            | DeleteProcessor { .. } => {
+                unreachable!(
+                    "chain, route and processor management should not be handled in Matcher"
+                );
+            }
+        }
+    }
+
+    fn invalidate(cache: &mut LruCache<Vec<Arc<Subscriber>>>, change: &Change) {
+        match change {
+            Change::Subscribe(filter) => {
+                cache.retain(|k, _| !utils::topic_match(filter.as_str(), k));
+            }
+            Change::Unsubscribe {
+                client_id,
+                share_group,
+                topic,
+            } => {
                 cache.retain(|_k, v| {
                     v.retain(|info| {
-                        !(info.client_id == client_id
-                            && info.topic == topic
-                            && info.share_group == share_group)
+                        !(info.client_id == *client_id
+                            && info.topic.as_str() == topic
+                            && info.share_group == *share_group)
                     });
                     !v.is_empty()
                 });
-                trie.remove(&topic, &&Subscriber::default(client_id, share_group));
             }
-            Publish {
+            Change::RemoveClient(client_id) => {
+                cache.retain(|_k, v| {
+                    v.retain(|info| info.client_id != *client_id);
+                    !v.is_empty()
+                });
+            }
+        }
+    }
+
+    fn publish(
+        cache: &mut LruCache<Vec<Arc<Subscriber>>>,
+        trie: &TopicTrie<Arc<Subscriber>>,
+        property_routes: &PropertyRoutes,
+        deliveries: &mut Deliveries,
+        cmd: OperatorCommand,
+    ) {
+        match cmd {
+            OperatorCommand::Publish {
                 client_id,
                 retain,
                 qos,
@@ -172,97 +491,146 @@ impl Matcher {
                 payload,
                 user_properties,
                 options,
+                span,
+                ..
             } => {
+                let _span = tracing::debug_span!(parent: &span, "match").entered();
                 let (clients_iters, group_clients_map) =
                     Self::find_clients(cache, trie, &client_id, &topic);
-                let clients_iters = &mut clients_iters.into_iter().peekable();
-
-                for (_group, mut clients) in group_clients_map.into_iter() {
-                    let current_index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
-                    let index = current_index % clients.len();
-                    let client = &mut clients[index];
-
-                    let _ = client.sink.deliver(
-                        Message::new(
-                            client.client_id.clone(),
-                            topic.clone(),
-                            qos.min(client.qos),
-                            retain,
-                            payload.clone(),
-                            user_properties.clone(),
-                        )
-                        .with_options(options.clone())
-                        .with_subscription_identifier(client.subscription_id),
-                        client.persist,
-                    );
+                let group_clients_map =
+                    property_routes.select(&topic, &user_properties, group_clients_map);
+                // one body for all the subscribers, only the header is per delivery
+                let body = Arc::new(PublishBody {
+                    topic,
+                    payload,
+                    user_properties,
+                    options,
+                });
+                let publish = |client: &Subscriber| {
+                    let publish = SharedPublish::new(qos.min(client.qos), retain, body.clone())
+                        .with_subscription_identifier(client.subscription_id);
+                    // the other node filters No Local and picks share group members by it
+                    if client.sink.remote_node().is_some() {
+                        publish.with_publisher(&client_id)
+                    } else {
+                        publish
+                    }
+                };
+                let mut delivered = 0;
+
+                let strategy = share::strategy();
+                for (group, clients) in group_clients_map.into_iter() {
+                    let client =
+                        Self::pick_member(strategy, &group, &clients, &client_id, &body.topic);
+                    deliveries.push(client, publish(client));
+                    delivered += 1;
                 }
 
-                while let Some(client) = clients_iters.next() {
+                for client in clients_iters {
                     trace!(
                         "send to client: {}, topic: {}",
                         g_utils::TruncateDisplay::new(&client.client_id, 24),
-                        g_utils::TruncateDisplay::new(&topic, 128),
+                        g_utils::TruncateDisplay::new(&body.topic, 128),
                     );
-
-                    if clients_iters.peek().is_some() {
-                        let _ = client.sink.deliver(
-                            Message::new(
-                                client.client_id.clone(),
-                                topic.clone(),
-                                qos.min(client.qos),
-                                retain,
-                                payload.clone(),
-                                user_properties.clone(),
-                            )
-                            .with_options(options.clone())
-                            .with_subscription_identifier(client.subscription_id),
-                            client.persist,
-                        );
-                    } else {
-                        let _ = client.sink.deliver(
-                            Message::new(
-                                client.client_id.clone(),
-                                topic,
-                                qos.min(client.qos),
-                                retain,
-                                payload,
-                                user_properties,
-                            )
-                            .with_options(options)
-                            .with_subscription_identifier(client.subscription_id),
-                            client.persist,
-                        );
-                        break;
-                    }
+                    deliveries.push(client, publish(client));
+                    delivered += 1;
                 }
+                trace::hop(body.options.trace_id, "matcher", || {
+                    format!("delivered to {} subscribers on {}", delivered, body.topic)
+                });
             }
-            RemoveClient { client_id } => {
-                debug!(
-                    "remove client: {}",
-                    g_utils::TruncateDisplay::new(&client_id, 24)
-                );
-                trie.remove_client(&client_id);
-                cache.retain(|_k, v| {
-                    v.retain(|info| info.client_id != client_id);
-                    !v.is_empty()
+            OperatorCommand::ClusterPublish {
+                share_group,
+                message,
+            } => {
+                let (clients, mut group_clients_map) =
+                    Self::find_clients(cache, trie, &message.client_id, &message.topic);
+                let local = |client: &&Arc<Subscriber>| client.sink.remote_node().is_none();
+                let targets: Vec<&Arc<Subscriber>> = match share_group {
+                    None => clients.into_iter().filter(local).collect(),
+                    Some(group) => {
+                        let members: Vec<&Arc<Subscriber>> = group_clients_map
+                            .remove(&group)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter(local)
+                            .collect();
+                        if members.is_empty() {
+                            vec![]
+                        } else {
+                            vec![Self::pick_member(
+                                share::strategy(),
+                                &group,
+                                &members,
+                                &message.client_id,
+                                &message.topic,
+                            )]
+                        }
+                    }
+                };
+
+                let body = Arc::new(PublishBody {
+                    topic: message.topic,
+                    payload: message.payload,
+                    user_properties: message.user_properties,
+                    options: message.options,
                 });
+                for client in targets {
+                    let publish = SharedPublish::new(
+                        message.qos.min(client.qos),
+                        message.retain,
+                        body.clone(),
+                    )
+                    .with_subscription_identifier(client.subscription_id);
+                    deliveries.push(client, publish);
+                }
             }
-            SparkPlugBPublish { .. } => {
-                unreachable!("SparkPlugBPublish should not be handled in Matcher");
+            _ => unreachable!("only publishes are handed to the matcher workers"),
+        }
+    }
+
+    // one member of a share group, `clients` is not empty. In a cluster a remote node counts
+    // as the members it told about, so that every member gets its share wherever it is
+    fn pick_member<'a>(
+        strategy: Option<ShareStrategy>,
+        group: &str,
+        clients: &[&'a Arc<Subscriber>],
+        publisher: &str,
+        topic: &str,
+    ) -> &'a Arc<Subscriber> {
+        let Some(strategy) = strategy else {
+            return clients[NEXT_INDEX.fetch_add(1, Ordering::Relaxed) % clients.len()];
+        };
+        let members = share::members();
+        let mut names: Vec<&str> = Vec::with_capacity(clients.len());
+        let mut owners: Vec<usize> = Vec::with_capacity(clients.len());
+        for (index, client) in clients.iter().enumerate() {
+            // a node whose members are not known yet counts as one
+            let remote = client
+                .sink
+                .remote_node()
+                .and_then(|node| members.get(node, group, client.topic.as_str()));
+            match remote {
+                Some(ids) => {
+                    names.extend(ids.iter().map(String::as_str));
+                    owners.extend(std::iter::repeat_n(index, ids.len()));
+                }
+                None => {
+                    names.push(&client.client_id);
+                    owners.push(index);
+                }
             }
         }
+        clients[owners[share::pick(strategy, publisher, topic, &names)]]
     }
 
     fn find_clients<'a>(
-        cache: &'a mut HashMap<String, Vec<Subscriber>>,
-        trie: &'a TopicTrie<Subscriber>,
+        cache: &'a mut LruCache<Vec<Arc<Subscriber>>>,
+        trie: &TopicTrie<Arc<Subscriber>>,
         client_id: &str,
         topic: &str,
-    ) -> (
-        Vec<&'a mut Subscriber>,
-        HashMap<String, Vec<&'a mut Subscriber>>,
-    ) {
-        if !cache.contains_key(topic) {
+    ) -> Matches<'a> {
+        if cache.get(topic).is_none() {
             let clients = trie.find_matches(topic);
             if clients.len() > 0 {
                 let clients = clients.into_iter().cloned().collect::<Vec<_>>();
@@ -270,12 +638,15 @@ impl Matcher {
             }
         }
 
-        let clients = cache.get_mut(topic);
+        let clients = cache.get(topic);
         if let Some(clients) = clients {
-            let clients = clients.iter_mut().filter(|c| c.filter_local(client_id));
+            let owner = mqtt_utils::response_topic_owner(topic);
+            let clients = clients
+                .iter()
+                .filter(|c| c.filter_local(client_id) && c.filter_response(owner));
             let (clients_iters, shared_clients): (Vec<_>, Vec<_>) =
                 clients.partition(|c| c.share_group.is_none());
-            let mut group_clients_map: HashMap<String, Vec<&mut Subscriber>> = HashMap::new();
+            let mut group_clients_map: HashMap<String, Vec<&Arc<Subscriber>>> = HashMap::new();
             for client in shared_clients.into_iter() {
                 if let Some(group) = &client.share_group {
                     group_clients_map
@@ -290,3 +661,265 @@ impl Matcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::mqtt::{
+        command::ClientCommand, helper::BrokerHelper, retain_trie::SharedRetainedTrie,
+    };
+    use crate::operator::sink::local::LocalClientSink;
+
+    fn subscribe(
+        trie: &mut TopicTrie<Arc<Subscriber>>,
+        interner: &mut Interner,
+        client_id: &str,
+        share_group: Option<&str>,
+        no_local: bool,
+    ) {
+        let filter = TopicFilter::compile("t/#", interner);
+        let mut subscriber =
+            Subscriber::default(client_id.to_string(), share_group.map(str::to_string));
+        subscriber.topic = filter.clone();
+        subscriber.no_local = no_local;
+        trie.insert(&filter, Arc::new(subscriber));
+    }
+
+    fn ids(clients: &[&Arc<Subscriber>]) -> Vec<String> {
+        let mut ids: Vec<String> = clients.iter().map(|c| c.client_id.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_no_local() {
+        let mut interner = Interner::new();
+        let mut trie = TopicTrie::new();
+        let mut cache = LruCache::new(16);
+        subscribe(&mut trie, &mut interner, "a", None, true);
+        subscribe(&mut trie, &mut interner, "b", None, false);
+        subscribe(&mut trie, &mut interner, "c", None, false);
+        // No Local is refused on shared subscriptions, see listener::shared
+        subscribe(&mut trie, &mut interner, "a", Some("g"), false);
+
+        // a publishing to its own No Local subscription, its shared one still gets the message
+        let (clients, groups) = Matcher::find_clients(&mut cache, &trie, "a", "t/1");
+        assert_eq!(ids(&clients), ["b", "c"]);
+        assert_eq!(ids(&groups["g"]), ["a"]);
+
+        // b has no No Local subscription, served from the cache this time
+        let (clients, groups) = Matcher::find_clients(&mut cache, &trie, "b", "t/1");
+        assert_eq!(ids(&clients), ["a", "b", "c"]);
+        assert_eq!(ids(&groups["g"]), ["a"]);
+    }
+
+    #[test]
+    fn test_snapshot_cache() {
+        let mut interner = Interner::new();
+        let mut trie = TopicTrie::new();
+        let mut cache = LruCache::new(16);
+        subscribe(&mut trie, &mut interner, "a", None, false);
+
+        let snapshot = trie.clone();
+        let (clients, _) = Matcher::find_clients(&mut cache, &snapshot, "p", "t/1");
+        assert_eq!(ids(&clients), ["a"]);
+
+        // the worker keeps its cache until the change reaches it with the next snapshot
+        subscribe(&mut trie, &mut interner, "b", None, false);
+        let (clients, _) = Matcher::find_clients(&mut cache, &snapshot, "p", "t/1");
+        assert_eq!(ids(&clients), ["a"]);
+        let filter = TopicFilter::compile("t/#", &mut interner);
+        Matcher::invalidate(&mut cache, &Change::Subscribe(filter));
+        let (clients, _) = Matcher::find_clients(&mut cache, &trie, "p", "t/1");
+        assert_eq!(ids(&clients), ["a", "b"]);
+
+        trie.remove_client("a");
+        Matcher::invalidate(&mut cache, &Change::RemoveClient("a".to_string()));
+        let (clients, _) = Matcher::find_clients(&mut cache, &trie, "p", "t/1");
+        assert_eq!(ids(&clients), ["b"]);
+        let mut cache = LruCache::new(16);
+        let (clients, _) = Matcher::find_clients(&mut cache, &snapshot, "p", "t/1");
+        assert_eq!(ids(&clients), ["a"]);
+
+        // past its size the least recently published topic leaves the cache
+        let mut cache = LruCache::new(2);
+        for topic in ["t/1", "t/2", "t/1", "t/3"] {
+            Matcher::find_clients(&mut cache, &trie, "p", topic);
+        }
+        assert!(cache.get("t/2").is_none());
+        assert!(cache.get("t/1").is_some() && cache.get("t/3").is_some());
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<Vec<(String, Vec<String>)>>>);
+
+    #[async_trait::async_trait]
+    impl Sink for Recorder {
+        async fn deliver(
+            &self,
+            _message: crate::processor::message::Message,
+            _persist: bool,
+        ) -> Result<(), crate::operator::sink::error::SinkError> {
+            Ok(())
+        }
+
+        fn deliver_batch(&self, client_id: &str, publishes: Vec<SharedPublish>, _persist: bool) {
+            let topics = publishes.iter().map(|p| p.body.topic.clone()).collect();
+            self.0.lock().unwrap().push((client_id.to_string(), topics));
+        }
+    }
+
+    #[test]
+    fn test_fan_out_shares_body() {
+        let mut interner = Interner::new();
+        let mut trie = TopicTrie::new();
+        let broker_helper = BrokerHelper {
+            broker_tx: mpsc::channel(1).0,
+            retain_trie: SharedRetainedTrie::new(),
+        };
+        let mut rxs = vec![];
+        for (client_id, qos, subscription_id) in [
+            ("a", QoS::AtLeastOnce, Some(1)),
+            ("b", QoS::AtMostOnce, None),
+        ] {
+            let (tx, rx) = mpsc::channel(4);
+            rxs.push(rx);
+            let filter = TopicFilter::compile("t/#", &mut interner);
+            let mut subscriber = Subscriber::default(client_id.to_string(), None);
+            subscriber.topic = filter.clone();
+            subscriber.qos = qos;
+            subscriber.subscription_id = subscription_id;
+            subscriber.sink = LocalClientSink::new(tx, broker_helper.clone());
+            trie.insert(&filter, Arc::new(subscriber));
+        }
+
+        let mut deliveries = Deliveries::default();
+        Matcher::publish(
+            &mut LruCache::new(16),
+            &trie,
+            &PropertyRoutes::default(),
+            &mut deliveries,
+            OperatorCommand::Publish {
+                client_id: "p".to_string(),
+                server_name: None,
+                retain: false,
+                qos: QoS::ExactlyOnce,
+                topic: "t/1".to_string(),
+                payload: Bytes::from_static(b"payload"),
+                user_properties: vec![],
+                options: Default::default(),
+                span: tracing::Span::none(),
+                ack: None,
+            },
+        );
+        deliveries.flush();
+
+        let publishes: Vec<SharedPublish> = rxs
+            .iter_mut()
+            .map(|rx| match rx.try_recv() {
+                Ok(ClientCommand::Publish(publish)) => publish,
+                _ => panic!("no PUBLISH"),
+            })
+            .collect();
+        // one body for both, the header is the subscriber's own
+        assert!(Arc::ptr_eq(&publishes[0].body, &publishes[1].body));
+        assert_eq!(publishes[0].qos, QoS::AtLeastOnce);
+        assert_eq!(publishes[0].subscription_identifier, Some(1));
+        assert_eq!(publishes[1].qos, QoS::AtMostOnce);
+        assert_eq!(publishes[1].subscription_identifier, None);
+    }
+
+    #[test]
+    fn test_deliveries() {
+        let recorder = Recorder::default();
+        let client = |id: &str| {
+            let mut subscriber = Subscriber::default(id.to_string(), None);
+            subscriber.sink = Box::new(recorder.clone());
+            Arc::new(subscriber)
+        };
+        let (a, b) = (client("a"), client("b"));
+        let publish = |topic: &str| {
+            let body = PublishBody {
+                topic: topic.to_string(),
+                payload: Default::default(),
+                user_properties: vec![],
+                options: Default::default(),
+            };
+            SharedPublish::new(QoS::AtMostOnce, false, Arc::new(body))
+        };
+
+        let mut deliveries = Deliveries::default();
+        deliveries.push(&a, publish("1"));
+        deliveries.push(&b, publish("2"));
+        deliveries.push(&a, publish("3"));
+        deliveries.flush();
+        deliveries.push(&b, publish("4"));
+        deliveries.flush();
+
+        let batches = recorder.0.lock().unwrap().clone();
+        let batch = |id: &str, topics: &[&str]| {
+            (
+                id.to_string(),
+                topics.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(
+            batches,
+            [
+                batch("a", &["1", "3"]),
+                batch("b", &["2"]),
+                batch("b", &["4"])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deliveries_share_group() {
+        let (plain, shared) = (Recorder::default(), Recorder::default());
+        let node = |share_group: Option<&str>, recorder: &Recorder| {
+            let mut subscriber =
+                Subscriber::default("$cluster/n2".to_string(), share_group.map(str::to_string));
+            subscriber.sink = Box::new(recorder.clone());
+            Arc::new(subscriber)
+        };
+        let (a, b) = (node(None, &plain), node(Some("g"), &shared));
+        let publish = |topic: &str| {
+            let body = PublishBody {
+                topic: topic.to_string(),
+                payload: Default::default(),
+                user_properties: vec![],
+                options: Default::default(),
+            };
+            SharedPublish::new(QoS::AtMostOnce, false, Arc::new(body))
+        };
+
+        // the same node, each subscription through its own sink
+        let mut deliveries = Deliveries::default();
+        deliveries.push(&a, publish("1"));
+        deliveries.push(&b, publish("2"));
+        deliveries.push(&a, publish("3"));
+        deliveries.flush();
+
+        let topics = |recorder: &Recorder| {
+            recorder
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, topics)| topics.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(topics(&plain), [["1", "3"]]);
+        assert_eq!(topics(&shared), [["2"]]);
+    }
+
+    #[test]
+    fn test_filter_response() {
+        let subscriber = Subscriber::default("c1".to_string(), None);
+        assert!(subscriber.filter_response(None));
+        assert!(subscriber.filter_response(Some("c1")));
+        assert!(!subscriber.filter_response(Some("c2")));
+    }
+}
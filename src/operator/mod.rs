@@ -1,35 +1,70 @@
-mod chain;
+pub mod chain;
 mod command;
 pub mod error;
 mod filter;
 pub mod helper;
+pub(crate) mod lru;
 mod matcher;
+mod property_route;
 mod router;
 pub mod sink;
+mod switch;
+mod topic_filter;
+pub mod trace;
 mod trie;
-mod utils;
+pub(crate) mod utils;
 
+use tokio::task::JoinHandle;
+
+use crate::CONFIG;
+use crate::cluster::ClusterHelper;
+use crate::operator::error::OperatorError;
 use crate::service::sparkplug_b::helper::SparkPlugBApplicationHelper;
 
+const DEFAULT_BATCH_SIZE: usize = 64;
+// bounded by the queue capacity, a batch is forwarded with one reservation
+const MAX_BATCH_SIZE: usize = 256;
+
+/// commands the matcher and router take from their queue per wakeup
+fn batch_size() -> usize {
+    CONFIG
+        .get()
+        .and_then(|c| c.common.operator_batch_size)
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+        .clamp(1, MAX_BATCH_SIZE)
+}
+
 pub struct Operator {
     matcher: matcher::Matcher,
     router: router::Router,
+    cluster_helper: Option<ClusterHelper>,
 }
 
 impl Operator {
-    pub async fn new() -> Self {
-        let matcher = matcher::Matcher::new();
-        let router = router::Router::new(matcher.sender()).await;
+    pub async fn new(cluster_helper: Option<ClusterHelper>) -> Result<Self, OperatorError> {
+        let matcher = matcher::Matcher::new(cluster_helper.clone());
+        let router = router::Router::new(matcher.sender()).await?;
 
-        Operator { matcher, router }
+        Ok(Operator {
+            matcher,
+            router,
+            cluster_helper,
+        })
     }
 
-    pub fn run(&mut self, sparkplug_helper: Option<SparkPlugBApplicationHelper>) {
-        self.matcher.run();
-        self.router.run(sparkplug_helper);
+    /// returns the matcher and router tasks
+    pub fn run(
+        &mut self,
+        sparkplug_helper: Option<SparkPlugBApplicationHelper>,
+    ) -> Vec<JoinHandle<()>> {
+        vec![self.matcher.run(), self.router.run(sparkplug_helper)]
     }
 
     pub fn helper(&self) -> helper::Helper {
-        helper::Helper::new(self.matcher.sender(), self.router.sender())
+        helper::Helper::new(
+            self.matcher.sender(),
+            self.router.sender(),
+            self.cluster_helper.clone(),
+        )
     }
 }
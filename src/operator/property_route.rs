@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::config::property_route::PropertyRoute;
+use crate::mqtt::protocol::property::PropertyUser;
+
+use super::utils::topic_match;
+
+/// sends the messages carrying a user property to a dedicated share group,
+/// a group named by a rule only receives the messages matching one of its rules
+#[derive(Clone, Default)]
+pub struct PropertyRoutes {
+    rules: Vec<PropertyRoute>,
+    reserved: HashSet<String>,
+}
+
+impl PropertyRoutes {
+    pub fn new(rules: Vec<PropertyRoute>) -> Self {
+        let reserved = rules.iter().map(|r| r.share_group.clone()).collect();
+        PropertyRoutes { rules, reserved }
+    }
+
+    fn matches(rule: &PropertyRoute, topic: &str, user_properties: &[PropertyUser]) -> bool {
+        rule.topic
+            .as_deref()
+            .is_none_or(|filter| topic_match(filter, topic))
+            && user_properties.iter().any(|p| {
+                p.key == rule.property && rule.value.as_ref().is_none_or(|v| *v == p.value)
+            })
+    }
+
+    /// keep the share groups a message goes to, the first matching rule whose group has
+    /// members wins, otherwise the message goes to the groups no rule names
+    pub fn select<T>(
+        &self,
+        topic: &str,
+        user_properties: &[PropertyUser],
+        mut groups: HashMap<String, Vec<T>>,
+    ) -> HashMap<String, Vec<T>> {
+        if self.rules.is_empty() || groups.is_empty() {
+            return groups;
+        }
+
+        let target = self
+            .rules
+            .iter()
+            .filter(|rule| Self::matches(rule, topic, user_properties))
+            .find_map(|rule| groups.remove_entry(&rule.share_group));
+        match target {
+            Some((group, members)) => HashMap::from([(group, members)]),
+            None => {
+                groups.retain(|group, _| !self.reserved.contains(group));
+                groups
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(topic: Option<&str>, value: Option<&str>, group: &str) -> PropertyRoute {
+        PropertyRoute {
+            topic: topic.map(|t| t.to_string()),
+            property: "priority".to_string(),
+            value: value.map(|v| v.to_string()),
+            share_group: group.to_string(),
+        }
+    }
+
+    fn groups(names: &[&str]) -> HashMap<String, Vec<u8>> {
+        names.iter().map(|n| (n.to_string(), vec![0])).collect()
+    }
+
+    fn selected(
+        routes: &PropertyRoutes,
+        topic: &str,
+        value: Option<&str>,
+        names: &[&str],
+    ) -> Vec<String> {
+        let props = value
+            .map(|v| {
+                vec![PropertyUser {
+                    key: "priority".to_string(),
+                    value: v.to_string(),
+                }]
+            })
+            .unwrap_or_default();
+        let mut selected: Vec<String> = routes
+            .select(topic, &props, groups(names))
+            .into_keys()
+            .collect();
+        selected.sort();
+        selected
+    }
+
+    #[test]
+    fn test_property_routes() {
+        let routes = PropertyRoutes::new(vec![
+            rule(Some("orders/#"), Some("high"), "urgent"),
+            rule(None, Some("low"), "batch"),
+        ]);
+        let all = ["urgent", "batch", "normal", "audit"];
+
+        assert_eq!(
+            selected(&routes, "orders/1", Some("high"), &all),
+            vec!["urgent"]
+        );
+        assert_eq!(
+            selected(&routes, "orders/1", None, &all),
+            vec!["audit", "normal"]
+        );
+        assert_eq!(
+            selected(&routes, "orders/1", Some("low"), &all),
+            vec!["batch"]
+        );
+        // the rule topic does not match, the urgent pool is kept out
+        assert_eq!(
+            selected(&routes, "events/1", Some("high"), &all),
+            vec!["audit", "normal"]
+        );
+        // no urgent member on this topic, the message falls back to the other groups
+        assert_eq!(
+            selected(&routes, "orders/1", Some("high"), &["normal"]),
+            vec!["normal"]
+        );
+
+        let any = PropertyRoutes::new(vec![rule(None, None, "flagged")]);
+        assert_eq!(
+            selected(&any, "a", Some("x"), &["flagged", "normal"]),
+            vec!["flagged"]
+        );
+    }
+}
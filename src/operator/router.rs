@@ -1,25 +1,110 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use uuid;
+use uuid::{self, Uuid};
 
-use minijinja::{Environment, Value};
-use tokio::sync::mpsc;
-use tokio::task::JoinSet;
-use tracing::{trace, warn};
+use minijinja::{Environment, Value, context};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{Instrument, debug, info, trace, warn};
 use wasmtime::Engine;
 
+use crate::config::chain::{ErrorAction, ErrorPolicy, ProcessorStep};
+use crate::config::router::condition_check;
+use crate::config::schedule::Schedule;
+use crate::mqtt::QoS;
+use crate::mqtt::protocol::property::PropertyUser;
 use crate::mqtt::protocol::publish::PublishOptions;
+use crate::mqtt::utils::sub_topic_valid;
+use crate::processor::config::ProcessorConfig;
+use crate::processor::error::ProcessorError;
 use crate::processor::message::Message;
+use crate::processor::{Processor, ProcessorInstance, WASM_EPOCH_TICK};
 use crate::service::sparkplug_b::helper::SparkPlugBApplicationHelper;
+use crate::spool::Spool;
 use crate::{CONFIG, get_default_log_dir};
 
-use super::chain::{Chain, ProcessorChain};
+use super::chain::{
+    Chain, ChainOutcome, ProcessorChain, ProcessorInfo, ProcessorStats, Route, VersionedChain,
+};
 use super::filter::MinijinjaFilter;
+use super::lru::LruCache;
+use super::sink::{Sink, error::SinkError};
 
-use super::command::OperatorCommand;
+use super::command::{OperatorAck, OperatorCommand};
+use super::error::OperatorError;
+use super::switch::Switch;
+use super::topic_filter::{Interner, TopicFilter};
+use super::trace;
 use super::trie::TopicTrie;
 
+// topics whose routes are kept, see `common.route_cache_size`
+const DEFAULT_ROUTE_CACHE_SIZE: usize = 10_000;
+
+// messages spawned into processor chains that have not come out yet
+static CHAINS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// number of messages still going through processor chains
+pub fn chains_in_flight() -> usize {
+    CHAINS_IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// counts a message as in flight from the moment it is spawned until its chains are done
+struct InFlight;
+
+impl InFlight {
+    fn new() -> Self {
+        CHAINS_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        CHAINS_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// a processor with the configuration it was built from
+#[derive(Clone)]
+struct RegisteredProcessor {
+    config: ProcessorConfig,
+    processor: Box<dyn Processor>,
+}
+
+/// what the routes of a publish are looked up with
+struct RouteQuery<'a> {
+    topic: &'a str,
+    client_id: &'a str,
+    server_name: Option<&'a str>,
+    qos: QoS,
+    retain: bool,
+    payload: &'a [u8],
+    user_properties: &'a [PropertyUser],
+}
+
+impl RouteQuery<'_> {
+    /// the values a route condition is evaluated over, payload is none when it is not JSON
+    fn context(&self) -> Value {
+        let payload = serde_json::from_slice::<serde_json::Value>(self.payload).ok();
+        let user_properties = self
+            .user_properties
+            .iter()
+            .map(|p| (p.key.as_str(), p.value.as_str()))
+            .collect::<HashMap<_, _>>();
+        context! {
+            topic => self.topic,
+            client_id => self.client_id,
+            qos => self.qos as u8,
+            retain => self.retain,
+            payload => payload,
+            user_properties => user_properties,
+        }
+    }
+}
+
 pub struct Router {
     command_rx: Option<mpsc::Receiver<OperatorCommand>>,
     command_tx: mpsc::Sender<OperatorCommand>,
@@ -27,12 +112,13 @@ pub struct Router {
     matcher_sender: mpsc::Sender<OperatorCommand>,
 
     trie: Option<TopicTrie<Chain>>,
+    interner: Option<Interner>,
 
-    chains: HashMap<String, ProcessorChain>,
+    routes: HashMap<String, Route>,
+    chains: HashMap<String, VersionedChain>,
+    processors: HashMap<String, RegisteredProcessor>,
 
-    #[allow(dead_code)]
     engine: Arc<Engine>,
-    #[allow(dead_code)]
     minijinja_env: Arc<Environment<'static>>,
 }
 
@@ -48,13 +134,17 @@ impl Router {
         env
     }
 
-    fn create_engine() -> Engine {
+    // the processor limits need fuel and epochs, no engine without them
+    fn create_engine() -> Result<Engine, OperatorError> {
         use wasmtime::{Cache, CacheConfig, Config};
 
         let mut config = Config::new();
         //config.async_support(true);
         config.cranelift_opt_level(wasmtime::OptLevel::Speed);
         config.wasm_component_model(true);
+        // processor limits, max_fuel and timeout_ms
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
 
         let mut cache_config = CacheConfig::new();
         cache_config.with_cleanup_interval(std::time::Duration::from_secs(24 * 60 * 60)); // 1 day
@@ -64,25 +154,54 @@ impl Router {
         let cache = Cache::new(cache_config).ok();
         config.cache(cache);
 
-        Engine::new(&config).unwrap_or(Engine::default())
+        let engine = Engine::new(&config).map_err(|e| OperatorError::WasmEngine(e.to_string()))?;
+
+        let weak = engine.weak();
+        std::thread::spawn(move || {
+            while let Some(engine) = weak.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(WASM_EPOCH_TICK);
+            }
+        });
+        Ok(engine)
     }
 
-    pub async fn new(matcher_sender: mpsc::Sender<OperatorCommand>) -> Self {
+    pub async fn new(matcher_sender: mpsc::Sender<OperatorCommand>) -> Result<Self, OperatorError> {
         let (tx, rx) = mpsc::channel(1024);
         let mut chains = HashMap::new();
         let mut trie = TopicTrie::new();
-        let engine = Arc::new(Self::create_engine());
+        let mut interner = Interner::new();
+        let engine = Arc::new(Self::create_engine()?);
         let minijinja_env = Arc::new(Self::create_env());
 
+        let mut routes = HashMap::new();
         let router_configs = &CONFIG.get().unwrap().router;
-        for router in router_configs {
+        for (idx, router) in router_configs.iter().enumerate() {
+            let name = router
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("route-{}", idx));
+            if routes.contains_key(&name) {
+                warn!("duplicate route name {}, the route is skipped", name);
+                continue;
+            }
+
             let chain_names = router.chain.clone();
             let chain = Chain {
+                name: name.clone(),
                 topic_filter: router.topic.clone(),
                 client_id: router.client_id.clone(),
+                server_name: router.server_name.clone(),
+                condition: router.condition.clone(),
                 chains: chain_names,
             };
-            trie.insert(&router.topic, chain);
+            let switch = Self::switch(&name, router.enabled, router.schedule.clone());
+            trie.insert(
+                &TopicFilter::compile(&router.topic, &mut interner),
+                chain.clone(),
+            );
+            routes.insert(name, Route { chain, switch });
         }
 
         let mut processor_map = HashMap::new();
@@ -100,7 +219,13 @@ impl Router {
 
             match proc {
                 Ok(p) => {
-                    processor_map.insert(processor.uuid.clone(), p);
+                    processor_map.insert(
+                        processor.uuid.clone(),
+                        RegisteredProcessor {
+                            config: processor.config.clone(),
+                            processor: p,
+                        },
+                    );
                 }
                 Err(e) => {
                     warn!("failed to create processor {}: {}", processor.uuid, e);
@@ -108,150 +233,785 @@ impl Router {
             }
         }
 
+        let mut sink_map = HashMap::new();
+        for sink in &CONFIG.get().unwrap().sink {
+            let spool = match &sink.spool {
+                Some(config) => match Spool::open(&format!("sink:{}", sink.name), config) {
+                    Ok(spool) => Some(spool),
+                    Err(e) => {
+                        warn!(
+                            "failed to open the spool of sink {} at {}: {}",
+                            sink.name, config.path, e
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            match sink.config.new_sink(minijinja_env.clone(), spool) {
+                Ok(s) => {
+                    sink_map.insert(sink.name.clone(), s);
+                }
+                Err(e) => {
+                    warn!("failed to create sink {}: {}", sink.name, e);
+                }
+            }
+        }
+
         let chain_configs = &CONFIG.get().unwrap().chain;
         for chain in chain_configs {
-            let processors = chain
-                .processors
+            // unknown processors are left out, and the steps they empty with them
+            let mut processors = vec![];
+            let mut stages = vec![];
+            for step in &chain.processors {
+                let before = processors.len();
+                processors.extend(
+                    step.uuids()
+                        .iter()
+                        .filter_map(|name| processor_map.get(name))
+                        .map(|p| p.processor.clone().into()),
+                );
+                if processors.len() > before {
+                    stages.push(processors.len() - before);
+                }
+            }
+            let sinks = chain
+                .sinks
                 .iter()
-                .filter_map(|name| processor_map.get(name).cloned())
-                .map(Into::into)
+                .filter_map(|name| Some((name.clone(), sink_map.get(name)?.clone())))
                 .collect::<Vec<_>>();
+            let switch = Self::switch(&chain.name, chain.enabled, chain.schedule.clone());
             chains.insert(
                 chain.name.clone(),
-                ProcessorChain {
-                    name: chain.name.clone(),
-                    processors,
-                    delivery: chain.delivery,
-                },
+                VersionedChain::new(
+                    ProcessorChain::new(chain.name.clone(), 1, processors, chain.delivery)
+                        .with_stages(stages)
+                        .with_sinks(sinks)
+                        .with_sink_ack(chain.sink_ack)
+                        .with_on_error(Arc::new(chain.on_error.clone()))
+                        .with_metadata(Arc::new(chain.metadata.clone())),
+                )
+                .with_switch(switch),
             );
         }
 
-        Router {
+        Ok(Router {
             command_rx: Some(rx),
             command_tx: tx,
             matcher_sender,
             trie: Some(trie),
+            interner: Some(interner),
+            routes,
             chains,
+            processors: processor_map,
             engine,
             minijinja_env,
-        }
+        })
+    }
+
+    // a schedule that does not parse disables the route or chain rather than running it all day
+    fn switch(name: &str, enabled: bool, schedule: Option<Schedule>) -> Switch {
+        Switch::new(enabled, schedule).unwrap_or_else(|e| {
+            warn!("{}, {} is disabled", e, name);
+            Switch::new(false, None).unwrap()
+        })
     }
 
     pub fn sender(&self) -> mpsc::Sender<OperatorCommand> {
         self.command_tx.clone()
     }
 
-    pub fn run(&mut self, sparkplug_helper: Option<SparkPlugBApplicationHelper>) {
+    pub fn run(&mut self, sparkplug_helper: Option<SparkPlugBApplicationHelper>) -> JoinHandle<()> {
         let mut command_rx = self.command_rx.take().unwrap();
         let matcher_sender = self.matcher_sender.clone();
         let mut trie = self.trie.take().unwrap();
-        let mut cache: HashMap<String, Vec<Chain>> = HashMap::new();
-        let chains = self.chains.clone();
+        let mut interner = self.interner.take().unwrap();
+        let mut cache: LruCache<Vec<Chain>> = LruCache::new(
+            CONFIG
+                .get()
+                .and_then(|c| c.common.route_cache_size)
+                .unwrap_or(DEFAULT_ROUTE_CACHE_SIZE),
+        );
+        let mut routes = self.routes.clone();
+        let mut chains = self.chains.clone();
+        let mut processors = self.processors.clone();
+        let command_tx = self.command_tx.clone();
+        let engine = self.engine.clone();
+        let minijinja_env = self.minijinja_env.clone();
+        let batch_size = super::batch_size();
 
         tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    Some(cmd) = command_rx.recv() => {
-                        if let OperatorCommand::Publish{client_id, retain, qos, topic, payload, user_properties, options} = cmd {
-                            if let Some(ref sparkplug_helper) = sparkplug_helper {
-                                if sparkplug_helper.is_sparkplug_b_topic(&topic) {
-                                    sparkplug_helper.publish(
-                                        client_id.clone(),
-                                        retain, qos,
-                                        topic.clone(),
-                                        payload.clone(),
-                                    ).await;
-                                }
-                            }
-
-                            let chains = Self::find_chain(&mut cache, &mut trie, &chains, &topic, &client_id);
-                            if let Some(chains) = chains {
-                                let msg = Message::new(
-                                    client_id,
-                                    topic,
-                                    qos,
-                                    retain,
-                                    payload,
-                                    user_properties,
-                                ).with_options(options);
-
-                                tokio::spawn( Self::chains_process(chains, msg, matcher_sender.clone()));
-                            } else {
-                                matcher_sender.send(OperatorCommand::Publish {
-                                    client_id,
-                                    retain,
-                                    qos,
-                                    topic,
-                                    payload,
-                                    user_properties,
-                                    options,
-                                }).await.ok();
-                            }
-                        } else if let OperatorCommand::SparkPlugBPublish { client_id, topic, payload, retain, qos } = cmd {
-                            let chains = Self::find_chain(&mut cache, &mut trie, &chains, &topic, &client_id);
-                            if let Some(chains) = chains {
-                                let msg = Message::new(
-                                    client_id,
-                                    topic,
-                                    qos,
-                                    retain,
-                                    payload,
-                                    vec![],
-                                );
-
-                                tokio::spawn( Self::chains_process(chains, msg, matcher_sender.clone()));
-                            } else {
-                                matcher_sender.send(OperatorCommand::Publish {
-                                    client_id,
+            let mut batch = Vec::with_capacity(batch_size);
+            // publishes without chains, handed to the matcher together once the batch is done
+            let mut forward = Vec::with_capacity(batch_size);
+            while command_rx.recv_many(&mut batch, batch_size).await > 0 {
+                for cmd in batch.drain(..) {
+                    if let OperatorCommand::Publish {
+                        client_id,
+                        server_name,
+                        retain,
+                        qos,
+                        topic,
+                        payload,
+                        user_properties,
+                        options,
+                        span,
+                        ack,
+                    } = cmd
+                    {
+                        if let Some(ref sparkplug_helper) = sparkplug_helper
+                            && sparkplug_helper.is_sparkplug_b_topic(&topic)
+                        {
+                            sparkplug_helper
+                                .publish(
+                                    client_id.clone(),
                                     retain,
                                     qos,
-                                    topic,
-                                    payload,
-                                    user_properties: vec![],
-                                    options: PublishOptions::default(),
-                                }).await.ok();
+                                    topic.clone(),
+                                    payload.clone(),
+                                )
+                                .await;
+                        }
+
+                        let chains = Self::find_chain(
+                            &mut cache,
+                            &mut trie,
+                            &routes,
+                            &chains,
+                            &minijinja_env,
+                            &RouteQuery {
+                                topic: &topic,
+                                client_id: &client_id,
+                                server_name: server_name.as_deref(),
+                                qos,
+                                retain,
+                                payload: &payload,
+                                user_properties: &user_properties,
+                            },
+                        );
+                        trace::hop(options.trace_id, "router", || match &chains {
+                            Some(chains) => format!(
+                                "routed to chains {}",
+                                chains
+                                    .iter()
+                                    .map(|c| c.name.as_str())
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            None => "no route, forwarded to the matcher".to_string(),
+                        });
+                        if let Some(chains) = chains {
+                            let msg = Message::new(
+                                client_id,
+                                topic,
+                                qos,
+                                retain,
+                                payload,
+                                user_properties,
+                            )
+                            .with_options(options);
+
+                            tokio::spawn(
+                                Self::chains_process(
+                                    chains,
+                                    msg,
+                                    matcher_sender.clone(),
+                                    ack,
+                                    InFlight::new(),
+                                )
+                                .instrument(tracing::debug_span!(parent: &span, "chains")),
+                            );
+                        } else {
+                            if let Some(ack) = ack {
+                                let _ = ack.send(true);
                             }
+                            forward.push(OperatorCommand::Publish {
+                                client_id,
+                                server_name,
+                                retain,
+                                qos,
+                                topic,
+                                payload,
+                                user_properties,
+                                options,
+                                span,
+                                ack: None,
+                            });
+                        }
+                    } else if let OperatorCommand::SparkPlugBPublish {
+                        client_id,
+                        topic,
+                        payload,
+                        retain,
+                        qos,
+                    } = cmd
+                    {
+                        let span = tracing::debug_span!(parent: None, "publish", client_id = %client_id, topic = %topic, qos = qos as u8);
+                        let chains = Self::find_chain(
+                            &mut cache,
+                            &mut trie,
+                            &routes,
+                            &chains,
+                            &minijinja_env,
+                            &RouteQuery {
+                                topic: &topic,
+                                client_id: &client_id,
+                                server_name: None,
+                                qos,
+                                retain,
+                                payload: &payload,
+                                user_properties: &[],
+                            },
+                        );
+                        if let Some(chains) = chains {
+                            let msg = Message::new(client_id, topic, qos, retain, payload, vec![]);
+
+                            tokio::spawn(
+                                Self::chains_process(
+                                    chains,
+                                    msg,
+                                    matcher_sender.clone(),
+                                    None,
+                                    InFlight::new(),
+                                )
+                                .instrument(tracing::debug_span!(parent: &span, "chains")),
+                            );
                         } else {
-                            trace!("router received unsupported command: {}", cmd);
+                            forward.push(OperatorCommand::Publish {
+                                client_id,
+                                server_name: None,
+                                retain,
+                                qos,
+                                topic,
+                                payload,
+                                user_properties: vec![],
+                                options: PublishOptions::default(),
+                                span,
+                                ack: None,
+                            });
                         }
+                    } else if let OperatorCommand::UpdateRoute { .. }
+                    | OperatorCommand::DeleteRoute { .. } = cmd
+                    {
+                        Self::manage_route(&mut trie, &mut interner, &mut cache, &mut routes, cmd);
+                    } else if let OperatorCommand::ListProcessors { .. }
+                    | OperatorCommand::CreateProcessor { .. }
+                    | OperatorCommand::InstallProcessor { .. }
+                    | OperatorCommand::DeleteProcessor { .. } = cmd
+                    {
+                        Self::manage_processor(
+                            &chains,
+                            &mut processors,
+                            &command_tx,
+                            &engine,
+                            &minijinja_env,
+                            cmd,
+                        );
+                    } else {
+                        Self::manage_chain(&mut routes, &mut chains, &processors, cmd);
                     }
                 }
+
+                // one reservation for the whole batch instead of one per publish
+                if !forward.is_empty()
+                    && let Ok(permits) = matcher_sender.reserve_many(forward.len()).await
+                {
+                    for (permit, cmd) in permits.zip(forward.drain(..)) {
+                        permit.send(cmd);
+                    }
+                }
+                forward.clear();
             }
-        });
+        })
+    }
+
+    fn manage_chain(
+        routes: &mut HashMap<String, Route>,
+        chains: &mut HashMap<String, VersionedChain>,
+        processors: &HashMap<String, RegisteredProcessor>,
+        cmd: OperatorCommand,
+    ) {
+        match cmd {
+            OperatorCommand::ListChains { resp } => {
+                let mut list = chains.values().map(|c| c.info()).collect::<Vec<_>>();
+                list.sort_by(|a, b| a.name.cmp(&b.name));
+                resp.send(OperatorAck::Chains(list)).ok();
+            }
+            OperatorCommand::UpdateChain {
+                name,
+                processors: names,
+                delivery,
+                on_error,
+                canary_percent,
+                resp,
+            } => {
+                let ack = match Self::update_chain(
+                    chains,
+                    processors,
+                    name,
+                    names,
+                    delivery,
+                    on_error,
+                    canary_percent,
+                ) {
+                    Ok(chain) => OperatorAck::Chain(chain.info()),
+                    Err(e) => OperatorAck::Error(e),
+                };
+                resp.send(ack).ok();
+            }
+            OperatorCommand::PromoteCanary { name, resp } => {
+                let ack = match chains.get_mut(&name) {
+                    Some(chain) => match chain.canary.take() {
+                        Some((canary, _)) => {
+                            info!("chain {} promoted canary version {}", name, canary.version);
+                            chain.stable = canary;
+                            OperatorAck::Chain(chain.info())
+                        }
+                        None => OperatorAck::Error(OperatorError::CanaryNotFound),
+                    },
+                    None => OperatorAck::Error(OperatorError::ChainNotFound),
+                };
+                resp.send(ack).ok();
+            }
+            OperatorCommand::RollbackCanary { name, resp } => {
+                let ack = match chains.get_mut(&name) {
+                    Some(chain) => match chain.canary.take() {
+                        Some((canary, _)) => {
+                            info!(
+                                "chain {} rolled back canary version {}",
+                                name, canary.version
+                            );
+                            OperatorAck::Chain(chain.info())
+                        }
+                        None => OperatorAck::Error(OperatorError::CanaryNotFound),
+                    },
+                    None => OperatorAck::Error(OperatorError::ChainNotFound),
+                };
+                resp.send(ack).ok();
+            }
+            OperatorCommand::SwitchChain {
+                name,
+                enabled,
+                schedule,
+                resp,
+            } => {
+                let ack = match (chains.get_mut(&name), Switch::new(enabled, schedule)) {
+                    (Some(chain), Ok(switch)) => {
+                        info!("chain {} switched, enabled: {}", name, enabled);
+                        chain.switch = switch;
+                        OperatorAck::Chain(chain.info())
+                    }
+                    (None, _) => OperatorAck::Error(OperatorError::ChainNotFound),
+                    (_, Err(e)) => OperatorAck::Error(e),
+                };
+                resp.send(ack).ok();
+            }
+            OperatorCommand::ListRoutes { resp } => {
+                let mut list = routes.values().map(|r| r.info()).collect::<Vec<_>>();
+                list.sort_by(|a, b| a.name.cmp(&b.name));
+                resp.send(OperatorAck::Routes(list)).ok();
+            }
+            OperatorCommand::DeleteChain { name, resp } => {
+                // routes still naming the chain skip it, as they do for a chain that never existed
+                let ack = match chains.remove(&name) {
+                    Some(chain) => {
+                        info!("chain {} deleted", name);
+                        OperatorAck::Chain(chain.info())
+                    }
+                    None => OperatorAck::Error(OperatorError::ChainNotFound),
+                };
+                resp.send(ack).ok();
+            }
+            OperatorCommand::SwitchRoute {
+                name,
+                enabled,
+                schedule,
+                resp,
+            } => {
+                let ack = match (routes.get_mut(&name), Switch::new(enabled, schedule)) {
+                    (Some(route), Ok(switch)) => {
+                        info!("route {} switched, enabled: {}", name, enabled);
+                        route.switch = switch;
+                        OperatorAck::Route(route.info())
+                    }
+                    (None, _) => OperatorAck::Error(OperatorError::RouteNotFound),
+                    (_, Err(e)) => OperatorAck::Error(e),
+                };
+                resp.send(ack).ok();
+            }
+            cmd => {
+                trace!("router received unsupported command: {}", cmd);
+            }
+        }
+    }
+
+    /// processors are built on a task of their own, a WASM module takes a while to compile,
+    /// and installed once the router gets them back. A processor used by a chain is not
+    /// deleted, the chains keep their own copy of the processors they were built with
+    fn manage_processor(
+        chains: &HashMap<String, VersionedChain>,
+        processors: &mut HashMap<String, RegisteredProcessor>,
+        command_tx: &mpsc::Sender<OperatorCommand>,
+        engine: &Arc<Engine>,
+        minijinja_env: &Arc<Environment<'static>>,
+        cmd: OperatorCommand,
+    ) {
+        match cmd {
+            OperatorCommand::ListProcessors { resp } => {
+                let mut list = processors
+                    .iter()
+                    .map(|(uuid, processor)| Self::processor_info(chains, uuid, processor))
+                    .collect::<Vec<_>>();
+                list.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+                resp.send(OperatorAck::Processors(list)).ok();
+            }
+            OperatorCommand::CreateProcessor { uuid, config, resp } => {
+                let id = match uuid::Uuid::parse_str(&uuid) {
+                    Ok(id) => id,
+                    Err(e) => {
+                        let e = OperatorError::InvalidProcessor(format!("{}: {}", uuid, e));
+                        resp.send(OperatorAck::Error(e)).ok();
+                        return;
+                    }
+                };
+                if processors.contains_key(&uuid) {
+                    resp.send(OperatorAck::Error(OperatorError::ProcessorExists(uuid)))
+                        .ok();
+                    return;
+                }
+                let command_tx = command_tx.clone();
+                let engine = engine.clone();
+                let minijinja_env = minijinja_env.clone();
+                tokio::spawn(async move {
+                    match config.new_processor(id, engine, minijinja_env).await {
+                        Ok(processor) => {
+                            command_tx
+                                .send(OperatorCommand::InstallProcessor {
+                                    uuid,
+                                    config,
+                                    processor,
+                                    resp,
+                                })
+                                .await
+                                .ok();
+                        }
+                        Err(e) => {
+                            resp.send(OperatorAck::Error(OperatorError::InvalidProcessor(e)))
+                                .ok();
+                        }
+                    }
+                });
+            }
+            OperatorCommand::InstallProcessor {
+                uuid,
+                config,
+                processor,
+                resp,
+            } => {
+                // another request may have taken the uuid while this one was built
+                if processors.contains_key(&uuid) {
+                    resp.send(OperatorAck::Error(OperatorError::ProcessorExists(uuid)))
+                        .ok();
+                    return;
+                }
+                info!("processor {} created", uuid);
+                let processor = RegisteredProcessor { config, processor };
+                let info = Self::processor_info(chains, &uuid, &processor);
+                processors.insert(uuid, processor);
+                resp.send(OperatorAck::Processor(info)).ok();
+            }
+            OperatorCommand::DeleteProcessor { uuid, resp } => {
+                let ack = match processors.get(&uuid) {
+                    Some(processor) => {
+                        let info = Self::processor_info(chains, &uuid, processor);
+                        if info.chains.is_empty() {
+                            processors.remove(&uuid);
+                            info!("processor {} deleted", uuid);
+                            OperatorAck::Processor(info)
+                        } else {
+                            OperatorAck::Error(OperatorError::ProcessorInUse(
+                                info.chains.join(", "),
+                            ))
+                        }
+                    }
+                    None => OperatorAck::Error(OperatorError::ProcessorNotFound(uuid)),
+                };
+                resp.send(ack).ok();
+            }
+            cmd => {
+                trace!("router received unsupported command: {}", cmd);
+            }
+        }
+    }
+
+    fn processor_info(
+        chains: &HashMap<String, VersionedChain>,
+        uuid: &str,
+        processor: &RegisteredProcessor,
+    ) -> ProcessorInfo {
+        let id = processor.processor.id().to_string();
+        let mut info = ProcessorInfo {
+            uuid: uuid.to_string(),
+            config: processor.config.clone(),
+            chains: vec![],
+            health: Default::default(),
+        };
+        for (name, chain) in chains {
+            if let Some(health) = chain.processor_health(&id) {
+                info.chains.push(name.clone());
+                info.health.merge(&health);
+            }
+        }
+        info.chains.sort();
+        info
+    }
+
+    /// routes are added to and removed from the trie, the topics cached with the old routes are
+    /// looked up again. The cache holds routes only, their chains are looked up by name for
+    /// every message and changing a chain leaves it as it is
+    fn manage_route(
+        trie: &mut TopicTrie<Chain>,
+        interner: &mut Interner,
+        cache: &mut LruCache<Vec<Chain>>,
+        routes: &mut HashMap<String, Route>,
+        cmd: OperatorCommand,
+    ) {
+        match cmd {
+            OperatorCommand::UpdateRoute {
+                name,
+                topic,
+                client_id,
+                server_name,
+                condition,
+                chains,
+                resp,
+            } => {
+                if !sub_topic_valid(&topic) || topic.starts_with("$share/") {
+                    resp.send(OperatorAck::Error(OperatorError::InvalidTopicFilter(topic)))
+                        .ok();
+                    return;
+                }
+                if let Some(Err(e)) = condition.as_deref().map(condition_check) {
+                    resp.send(OperatorAck::Error(OperatorError::InvalidRouteCondition(e)))
+                        .ok();
+                    return;
+                }
+                let chain = Chain {
+                    name: name.clone(),
+                    topic_filter: topic,
+                    client_id,
+                    server_name,
+                    condition,
+                    chains,
+                };
+                // the switch of a replaced route is kept
+                let switch = match routes.remove(&name) {
+                    Some(old) => {
+                        trie.remove(
+                            &TopicFilter::compile(&old.chain.topic_filter, interner),
+                            &old.chain,
+                        );
+                        info!("route {} replaced, topic: {}", name, chain.topic_filter);
+                        old.switch
+                    }
+                    None => {
+                        info!("route {} created, topic: {}", name, chain.topic_filter);
+                        Switch::default()
+                    }
+                };
+                trie.insert(
+                    &TopicFilter::compile(&chain.topic_filter, interner),
+                    chain.clone(),
+                );
+                interner.maybe_prune();
+                cache.clear();
+
+                let route = Route { chain, switch };
+                let info = route.info();
+                routes.insert(name, route);
+                resp.send(OperatorAck::Route(info)).ok();
+            }
+            OperatorCommand::DeleteRoute { name, resp } => {
+                let ack = match routes.remove(&name) {
+                    Some(route) => {
+                        trie.remove(
+                            &TopicFilter::compile(&route.chain.topic_filter, interner),
+                            &route.chain,
+                        );
+                        interner.maybe_prune();
+                        cache.clear();
+                        info!("route {} deleted", name);
+                        OperatorAck::Route(route.info())
+                    }
+                    None => OperatorAck::Error(OperatorError::RouteNotFound),
+                };
+                resp.send(ack).ok();
+            }
+            cmd => {
+                trace!("router received unsupported command: {}", cmd);
+            }
+        }
+    }
+
+    fn update_chain<'a>(
+        chains: &'a mut HashMap<String, VersionedChain>,
+        processors: &HashMap<String, RegisteredProcessor>,
+        name: String,
+        steps: Vec<ProcessorStep>,
+        delivery: bool,
+        on_error: Option<ErrorPolicy>,
+        canary_percent: Option<u8>,
+    ) -> Result<&'a VersionedChain, OperatorError> {
+        if let Some(percent) = canary_percent
+            && !(1..=99).contains(&percent)
+        {
+            return Err(OperatorError::InvalidCanaryPercent(percent));
+        }
+        if let Some(on_error) = &on_error {
+            on_error
+                .check()
+                .map_err(OperatorError::InvalidErrorPolicy)?;
+        }
+        // the policy of the chain is kept, like its sinks and metadata keys
+        let on_error = |chain: Option<&VersionedChain>| match &on_error {
+            Some(on_error) => Arc::new(on_error.clone()),
+            None => chain.map_or_else(Default::default, |c| c.stable.on_error.clone()),
+        };
+
+        if steps.iter().any(|step| step.uuids().is_empty()) {
+            return Err(OperatorError::InvalidProcessor(
+                "empty parallel step".to_string(),
+            ));
+        }
+        let stages = steps
+            .iter()
+            .map(|step| step.uuids().len())
+            .collect::<Vec<_>>();
+        let instances = steps
+            .iter()
+            .flat_map(|step| step.uuids())
+            .map(|id| {
+                processors
+                    .get(id)
+                    .map(|p| p.processor.clone().into())
+                    .ok_or_else(|| OperatorError::ProcessorNotFound(id.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match (chains.get_mut(&name), canary_percent) {
+            (Some(chain), Some(percent)) => {
+                let version = chain.next_version();
+                info!(
+                    "chain {} canary version {} takes {}% of traffic",
+                    name, version, percent
+                );
+                let sinks = chain.stable.sinks.clone();
+                let sink_ack = chain.stable.sink_ack;
+                let metadata = chain.stable.metadata.clone();
+                let on_error = on_error(Some(&*chain));
+                chain.canary = Some((
+                    ProcessorChain::new(name.clone(), version, instances, delivery)
+                        .with_stages(stages.clone())
+                        .with_sinks(sinks)
+                        .with_sink_ack(sink_ack)
+                        .with_on_error(on_error)
+                        .with_metadata(metadata),
+                    percent,
+                ));
+            }
+            (Some(chain), None) => {
+                let version = chain.next_version();
+                info!("chain {} replaced by version {}", name, version);
+                let sinks = chain.stable.sinks.clone();
+                let sink_ack = chain.stable.sink_ack;
+                let metadata = chain.stable.metadata.clone();
+                let switch = chain.switch.clone();
+                let on_error = on_error(Some(&*chain));
+                *chain = VersionedChain::new(
+                    ProcessorChain::new(name.clone(), version, instances, delivery)
+                        .with_stages(stages.clone())
+                        .with_sinks(sinks)
+                        .with_sink_ack(sink_ack)
+                        .with_on_error(on_error)
+                        .with_metadata(metadata),
+                )
+                .with_switch(switch);
+            }
+            (None, Some(_)) => return Err(OperatorError::ChainNotFound),
+            (None, None) => {
+                info!("chain {} created", name);
+                chains.insert(
+                    name.clone(),
+                    VersionedChain::new(
+                        ProcessorChain::new(name.clone(), 1, instances, delivery)
+                            .with_stages(stages)
+                            .with_on_error(on_error(None)),
+                    ),
+                );
+            }
+        }
+
+        Ok(chains.get(&name).unwrap())
     }
 
     fn find_chain<'a>(
-        cache: &'a mut HashMap<String, Vec<Chain>>,
+        cache: &'a mut LruCache<Vec<Chain>>,
         trie: &'a mut TopicTrie<Chain>,
-        chains: &HashMap<String, ProcessorChain>,
-        topic: &str,
-        client_id: &str,
+        routes: &HashMap<String, Route>,
+        chains: &HashMap<String, VersionedChain>,
+        env: &Environment<'static>,
+        query: &RouteQuery,
     ) -> Option<Vec<ProcessorChain>> {
-        if !cache.contains_key(topic) {
+        if cache.get(query.topic).is_none() {
             let chain = trie
-                .find_matches(topic)
+                .find_matches(query.topic)
                 .into_iter()
                 .map(|c| c.clone())
                 .collect();
-            cache.insert(topic.to_string(), chain);
+            cache.insert(query.topic.to_string(), chain);
         }
 
-        let find_chains = cache.get(topic).unwrap();
+        // built for the first route with a condition
+        let context = std::cell::OnceCell::new();
+        let find_chains = cache.get(query.topic).unwrap();
         let chains_name = find_chains
             .iter()
             .filter(|chain| {
                 if let Some(ref cid) = chain.client_id {
-                    cid == client_id
+                    cid == query.client_id
                 } else {
                     true
                 }
             })
+            .filter(|chain| {
+                chain
+                    .server_name
+                    .as_deref()
+                    .is_none_or(|name| Some(name) == query.server_name)
+            })
+            .filter(|chain| {
+                routes
+                    .get(&chain.name)
+                    .is_none_or(|route| route.switch.is_active())
+            })
+            .filter(|chain| {
+                chain.condition.as_deref().is_none_or(|condition| {
+                    Self::condition_holds(
+                        env,
+                        &chain.name,
+                        condition,
+                        context.get_or_init(|| query.context()),
+                    )
+                })
+            })
             .flat_map(|chain| chain.chains.clone())
             .collect::<Vec<_>>();
 
         let chains = chains_name
             .iter()
-            .filter_map(|name| chains.get(name).cloned())
+            .filter_map(|name| chains.get(name))
+            .filter(|c| c.switch.is_active())
+            .map(|c| c.select().clone())
             .collect::<Vec<_>>();
         if chains.is_empty() {
             None
@@ -260,10 +1020,29 @@ impl Router {
         }
     }
 
+    /// a condition failing to evaluate does not hold, the route is skipped
+    fn condition_holds(
+        env: &Environment<'static>,
+        route: &str,
+        condition: &str,
+        ctx: &Value,
+    ) -> bool {
+        match env
+            .compile_expression(condition)
+            .and_then(|expr| expr.eval(ctx))
+        {
+            Ok(value) => value.is_true(),
+            Err(e) => {
+                trace!("failed to evaluate the condition of route {}: {}", route, e);
+                false
+            }
+        }
+    }
+
     #[allow(dead_code)]
     fn route(
         trie: &mut TopicTrie<Chain>,
-        chains: &HashMap<String, ProcessorChain>,
+        chains: &HashMap<String, VersionedChain>,
         topic: &str,
         client_id: &str,
     ) -> Option<Vec<ProcessorChain>> {
@@ -282,7 +1061,7 @@ impl Router {
 
         let chains = chains_name
             .iter()
-            .filter_map(|name| chains.get(name).cloned())
+            .filter_map(|name| chains.get(name).map(|c| c.select().clone()))
             .collect::<Vec<_>>();
         if chains.is_empty() {
             None
@@ -291,49 +1070,317 @@ impl Router {
         }
     }
 
+    /// runs the processors of a stage on the message, together when there are several. The
+    /// message goes on as the first one returns it, the others run for their side effects,
+    /// and the stage fails when one of them fails
+    async fn run_stage(
+        chain: &str,
+        processors: &[ProcessorInstance],
+        stats: &[ProcessorStats],
+        policy: &ErrorPolicy,
+        msg: Message,
+    ) -> Result<Option<Message>, (Option<Message>, Uuid, ProcessorError)> {
+        if let [processor] = processors {
+            return Self::run_processor(chain, processor, &stats[0], policy, msg).await;
+        }
+        let results =
+            futures::future::join_all(processors.iter().zip(stats).map(|(processor, stats)| {
+                Self::run_processor(chain, processor, stats, policy, msg.clone())
+            }))
+            .await;
+        let mut first = None;
+        for result in results {
+            let result = result?;
+            first.get_or_insert(result);
+        }
+        Ok(first.flatten())
+    }
+
+    async fn run_processor(
+        chain: &str,
+        processor: &ProcessorInstance,
+        stats: &ProcessorStats,
+        policy: &ErrorPolicy,
+        msg: Message,
+    ) -> Result<Option<Message>, (Option<Message>, Uuid, ProcessorError)> {
+        let id = processor.processor.id();
+        trace!(
+            "processing message with processor {} in chain {}",
+            id, chain
+        );
+        let start = std::time::Instant::now();
+        let result = Self::call_processor(processor, policy, msg).await;
+        match &result {
+            Ok(Some(_)) => stats.record(ChainOutcome::Passed, start.elapsed()),
+            Ok(None) => {
+                trace!("processor {} in chain {} dropped the message", id, chain);
+                stats.record(ChainOutcome::Dropped, start.elapsed());
+            }
+            Err((_, e)) => {
+                trace!(
+                    "processor {} in chain {} failed to process message: {}",
+                    id, chain, e
+                );
+                stats.record(ChainOutcome::Failed, start.elapsed());
+            }
+        }
+        result.map_err(|(kept, e)| (kept, id, e))
+    }
+
+    /// calls a processor, again after a backoff while the retries of the policy allow. A
+    /// failed call hands back the message as the processor got it, unless the chain aborts
+    /// without retrying and has no use for it
+    async fn call_processor(
+        processor: &ProcessorInstance,
+        policy: &ErrorPolicy,
+        mut msg: Message,
+    ) -> Result<Option<Message>, (Option<Message>, ProcessorError)> {
+        let keep = policy.retries > 0 || policy.action != ErrorAction::Abort;
+        let mut backoff = Duration::from_millis(policy.backoff_ms);
+        let mut attempt = 0;
+        loop {
+            let kept = keep.then(|| msg.clone());
+            match processor.processor.on_message(msg).await {
+                Ok(result) => return Ok(result),
+                Err(e) => match kept {
+                    Some(kept) if attempt < policy.retries => {
+                        attempt += 1;
+                        trace!(
+                            "processor {} failed, retry {} of {} in {:?}: {}",
+                            processor.processor.id(),
+                            attempt,
+                            policy.retries,
+                            backoff,
+                            e
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                        msg = kept;
+                    }
+                    kept => return Err((kept, e)),
+                },
+            }
+        }
+    }
+
+    /// hands a message to a sink, again after a backoff while the retries of the policy allow
+    async fn call_sink(
+        name: &str,
+        sink: &dyn Sink,
+        policy: &ErrorPolicy,
+        msg: &Message,
+    ) -> Result<(), SinkError> {
+        let mut backoff = Duration::from_millis(policy.backoff_ms);
+        let mut attempt = 0;
+        loop {
+            #[cfg(feature = "chaos")]
+            let result = crate::chaos::deliver(sink, msg.clone()).await;
+            #[cfg(not(feature = "chaos"))]
+            let result = sink.deliver(msg.clone(), false).await;
+            match result {
+                Err(e) if attempt < policy.retries => {
+                    attempt += 1;
+                    trace!(
+                        "sink {} failed, retry {} of {} in {:?}: {}",
+                        name, attempt, policy.retries, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// the message a processor or a sink failed on, for the dead letter topic of the chain,
+    /// `failed` is the user property naming which one
+    fn dead_letter(
+        chain: &str,
+        policy: &ErrorPolicy,
+        failed: (&str, String),
+        mut msg: Message,
+        error: &impl std::fmt::Display,
+    ) -> Option<Message> {
+        let topic = policy.dead_letter_topic.clone()?;
+        let property = |key: &str, value: String| PropertyUser {
+            key: key.to_string(),
+            value,
+        };
+        msg.user_properties.extend([
+            property("axonmq-chain", chain.to_string()),
+            property(failed.0, failed.1),
+            property("axonmq-error", error.to_string()),
+            property("axonmq-topic", std::mem::replace(&mut msg.topic, topic)),
+        ]);
+        Some(msg)
+    }
+
+    /// hands a message a chain delivers or dead letters to the matcher
+    async fn to_matcher(matcher_sender: &mpsc::Sender<OperatorCommand>, msg: Message) {
+        matcher_sender
+            .send(OperatorCommand::Publish {
+                client_id: msg.client_id,
+                server_name: None,
+                retain: msg.retain,
+                qos: msg.qos,
+                topic: msg.topic,
+                payload: msg.payload,
+                user_properties: msg.user_properties,
+                options: msg.options,
+                span: tracing::Span::current(),
+                ack: None,
+            })
+            .await
+            .ok();
+    }
+
+    /// runs a message through the processors of a chain, then hands it to the subscribers and
+    /// the sinks of the chain. False when a processor or a sink failed on it and it was not
+    /// dead lettered
+    async fn run_chain(
+        chain: &ProcessorChain,
+        mut msg: Message,
+        matcher_sender: &mpsc::Sender<OperatorCommand>,
+    ) -> bool {
+        let start = std::time::Instant::now();
+        let trace_id = msg.options.trace_id;
+        let mut processors = &chain.processors[..];
+        let mut stats = &chain.processor_stats[..];
+        for &size in chain.stages.iter() {
+            let (stage, rest) = processors.split_at(size);
+            processors = rest;
+            let (stage_stats, rest) = stats.split_at(size);
+            stats = rest;
+            match Self::run_stage(&chain.name, stage, stage_stats, &chain.on_error, msg).await {
+                Ok(Some(m)) => msg = m,
+                Ok(None) => {
+                    chain.stats.record(ChainOutcome::Dropped, start.elapsed());
+                    trace::hop(trace_id, "chain", || {
+                        format!("{} dropped the message", chain.name)
+                    });
+                    return true;
+                }
+                Err((kept, id, e)) => match (chain.on_error.action, kept) {
+                    (ErrorAction::Skip, Some(kept)) => {
+                        trace::hop(trace_id, "chain", || {
+                            format!("{}: processor {} skipped: {}", chain.name, id, e)
+                        });
+                        msg = kept;
+                    }
+                    (ErrorAction::DeadLetter, Some(kept)) => {
+                        chain.stats.record(ChainOutcome::Failed, start.elapsed());
+                        trace::hop(trace_id, "chain", || {
+                            format!(
+                                "{}: processor {} failed, dead lettered: {}",
+                                chain.name, id, e
+                            )
+                        });
+                        // published whatever the delivery of the chain
+                        let failed = ("axonmq-processor", id.to_string());
+                        if let Some(dead) =
+                            Self::dead_letter(&chain.name, &chain.on_error, failed, kept, &e)
+                        {
+                            Self::to_matcher(matcher_sender, dead).await;
+                        }
+                        return true;
+                    }
+                    _ => {
+                        chain.stats.record(ChainOutcome::Failed, start.elapsed());
+                        trace::hop(trace_id, "chain", || {
+                            format!("{}: processor {} failed: {}", chain.name, id, e)
+                        });
+                        return false;
+                    }
+                },
+            }
+        }
+
+        trace::hop(trace_id, "chain", || {
+            format!(
+                "{} passed in {:?}, {} sinks, delivered: {}",
+                chain.name,
+                start.elapsed(),
+                chain.sinks.len(),
+                chain.delivery
+            )
+        });
+        // the subscribers do not wait for the sinks
+        let sinks = (!chain.sinks.is_empty()).then(|| msg.clone());
+        if chain.delivery {
+            msg.metadata_to_properties(&chain.metadata);
+            Self::to_matcher(matcher_sender, msg).await;
+        }
+
+        let mut took = true;
+        let mut outcome = ChainOutcome::Passed;
+        if let Some(msg) = sinks {
+            let results =
+                futures::future::join_all(chain.sinks.iter().map(|(name, sink)| {
+                    Self::call_sink(name, sink.as_ref(), &chain.on_error, &msg)
+                }))
+                .await;
+            for ((name, _), result) in chain.sinks.iter().zip(results) {
+                let Err(e) = result else {
+                    continue;
+                };
+                match chain.on_error.action {
+                    ErrorAction::Skip => {
+                        trace::hop(trace_id, "chain", || {
+                            format!("{}: sink {} skipped: {}", chain.name, name, e)
+                        });
+                    }
+                    ErrorAction::DeadLetter => {
+                        outcome = ChainOutcome::Failed;
+                        trace::hop(trace_id, "chain", || {
+                            format!("{}: sink {} failed, dead lettered: {}", chain.name, name, e)
+                        });
+                        let failed = ("axonmq-sink", name.clone());
+                        if let Some(dead) =
+                            Self::dead_letter(&chain.name, &chain.on_error, failed, msg.clone(), &e)
+                        {
+                            Self::to_matcher(matcher_sender, dead).await;
+                        }
+                    }
+                    ErrorAction::Abort => {
+                        outcome = ChainOutcome::Failed;
+                        took = false;
+                        trace::hop(trace_id, "chain", || {
+                            format!("{}: sink {} failed: {}", chain.name, name, e)
+                        });
+                    }
+                }
+                debug!(
+                    "sink {} of chain {} failed on a message to {}: {}",
+                    name, chain.name, msg.topic, e
+                );
+            }
+        }
+        chain.stats.record(outcome, start.elapsed());
+        took
+    }
+
+    /// runs the message through its chains, `ack` is told whether the chains with sink_ack
+    /// took it
     async fn chains_process(
         chains: Vec<ProcessorChain>,
         message: Message,
         matcher_sender: mpsc::Sender<OperatorCommand>,
+        ack: Option<oneshot::Sender<bool>>,
+        _in_flight: InFlight,
     ) {
         let mut set = JoinSet::new();
 
         let mut chains_iter = chains.into_iter().peekable();
         while let Some(chain) = chains_iter.next() {
-            let processor = |mut msg: Message| {
-                set.spawn(async move {
-                    for processor in chain.processors {
-                        trace!(
-                            "processing message with processor {} in chain {}",
-                            processor.processor.id(),
-                            chain.name
-                        );
-                        match processor.processor.on_message(msg).await {
-                            Ok(Some(m)) => {
-                                msg = m;
-                            }
-                            Ok(None) => {
-                                trace!(
-                                    "processor {} in chain {} dropped the message",
-                                    processor.processor.id(),
-                                    chain.name
-                                );
-                                return None;
-                            }
-                            Err(e) => {
-                                trace!(
-                                    "processor {} in chain {} failed to process message: {}",
-                                    processor.processor.id(),
-                                    chain.name,
-                                    e
-                                );
-                                return None;
-                            }
-                        }
+            let processor = |msg: Message| {
+                let span = tracing::debug_span!("chain", chain = %chain.name);
+                let matcher_sender = matcher_sender.clone();
+                set.spawn(
+                    async move {
+                        Self::run_chain(&chain, msg, &matcher_sender).await || !chain.sink_ack
                     }
-
-                    if chain.delivery { Some(msg) } else { None }
-                })
+                    .instrument(span),
+                );
             };
 
             if chains_iter.peek().is_none() {
@@ -344,27 +1391,246 @@ impl Router {
             }
         }
 
+        let mut took = true;
         while let Some(result) = set.join_next().await {
             match result {
-                Ok(Some(msg)) => {
-                    matcher_sender
-                        .send(OperatorCommand::Publish {
-                            client_id: msg.client_id,
-                            retain: msg.retain,
-                            qos: msg.qos,
-                            topic: msg.topic,
-                            payload: msg.payload,
-                            user_properties: msg.user_properties,
-                            options: msg.options,
-                        })
-                        .await
-                        .ok();
-                }
-                Ok(None) => {}
+                Ok(chain_took) => took &= chain_took,
                 Err(e) => {
                     trace!("chain processing task failed: {}", e);
+                    took = false;
                 }
             }
         }
+        if let Some(ack) = ack {
+            let _ = ack.send(took);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use std::sync::atomic::AtomicU32;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
+    use crate::mqtt::QoS;
+
+    use super::*;
+
+    // fails its first `failures` calls
+    #[derive(Clone)]
+    struct Flaky {
+        calls: Arc<AtomicU32>,
+        failures: u32,
+    }
+
+    #[async_trait]
+    impl Processor for Flaky {
+        fn id(&self) -> uuid::Uuid {
+            uuid::Uuid::nil()
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        async fn on_message(&self, message: Message) -> Result<Option<Message>, ProcessorError> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(ProcessorError::ProcessorError("flaky".to_string()));
+            }
+            Ok(Some(message))
+        }
+    }
+
+    fn flaky(failures: u32) -> ProcessorInstance {
+        let processor: Box<dyn Processor> = Box::new(Flaky {
+            calls: Arc::new(AtomicU32::new(0)),
+            failures,
+        });
+        processor.into()
+    }
+
+    // fails its first `failures` deliveries
+    #[derive(Clone)]
+    struct FlakySink {
+        calls: Arc<AtomicU32>,
+        failures: u32,
+    }
+
+    #[async_trait]
+    impl Sink for FlakySink {
+        async fn deliver(&self, _message: Message, _persist: bool) -> Result<(), SinkError> {
+            if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                return Err(SinkError::Unavailable("flaky".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    fn sink_chain(failures: u32, policy: &ErrorPolicy) -> ProcessorChain {
+        let sink: Box<dyn Sink> = Box::new(FlakySink {
+            calls: Arc::new(AtomicU32::new(0)),
+            failures,
+        });
+        ProcessorChain::new("c1".to_string(), 1, vec![], false)
+            .with_sinks(vec![("s1".to_string(), sink)])
+            .with_sink_ack(true)
+            .with_on_error(Arc::new(policy.clone()))
+    }
+
+    fn message() -> Message {
+        Message::new(
+            "c".into(),
+            "a/b".into(),
+            QoS::AtMostOnce,
+            false,
+            Bytes::from_static(b"1"),
+            vec![],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() {
+        let policy = ErrorPolicy {
+            retries: 2,
+            backoff_ms: 1,
+            ..Default::default()
+        };
+        let result = Router::call_processor(&flaky(2), &policy, message()).await;
+        assert!(matches!(result, Ok(Some(_))));
+
+        // the message is handed back once the retries are spent
+        let result = Router::call_processor(&flaky(3), &policy, message()).await;
+        assert!(matches!(result, Err((Some(_), _))));
+
+        let result = Router::call_processor(&flaky(1), &ErrorPolicy::default(), message()).await;
+        assert!(matches!(result, Err((None, _))));
+
+        let policy = ErrorPolicy {
+            action: ErrorAction::DeadLetter,
+            dead_letter_topic: Some("dlq".to_string()),
+            ..Default::default()
+        };
+        let error = ProcessorError::ProcessorError("flaky".to_string());
+        let failed = ("axonmq-processor", Uuid::nil().to_string());
+        let dead = Router::dead_letter("c1", &policy, failed, message(), &error).unwrap();
+        assert_eq!(dead.topic, "dlq");
+        let topic = dead
+            .user_properties
+            .iter()
+            .find(|p| p.key == "axonmq-topic");
+        assert_eq!(topic.unwrap().value, "a/b");
+    }
+
+    #[tokio::test]
+    async fn test_run_stage() {
+        let policy = ErrorPolicy::default();
+        let stats: Vec<ProcessorStats> = vec![Default::default(), Default::default()];
+
+        let stage = [flaky(0), flaky(0)];
+        let result = Router::run_stage("c1", &stage, &stats, &policy, message()).await;
+        assert!(matches!(result, Ok(Some(_))));
+
+        // one failing processor fails the step, the others are still called
+        let stage = [flaky(1), flaky(0)];
+        let result = Router::run_stage("c1", &stage, &stats, &policy, message()).await;
+        assert!(matches!(result, Err((None, _, _))));
+        let first = stats[0].info(String::new());
+        let second = stats[1].info(String::new());
+        assert_eq!((first.invocations, first.passed, first.failed), (2, 1, 1));
+        assert_eq!((second.invocations, second.passed), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_sink_ack() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let process = |chain| {
+            let (ack, acked) = oneshot::channel();
+            let process = Router::chains_process(
+                vec![chain],
+                message(),
+                tx.clone(),
+                Some(ack),
+                InFlight::new(),
+            );
+            async move {
+                process.await;
+                acked.await.unwrap()
+            }
+        };
+
+        let policy = ErrorPolicy {
+            retries: 1,
+            backoff_ms: 1,
+            ..Default::default()
+        };
+        assert!(process(sink_chain(1, &policy)).await);
+        // failing after its retries, the publisher is refused
+        assert!(!process(sink_chain(2, &policy)).await);
+
+        // a dead letter keeps the message
+        let policy = ErrorPolicy {
+            action: ErrorAction::DeadLetter,
+            dead_letter_topic: Some("dlq".to_string()),
+            ..policy
+        };
+        assert!(process(sink_chain(2, &policy)).await);
+        let Some(OperatorCommand::Publish {
+            topic,
+            user_properties,
+            ..
+        }) = rx.recv().await
+        else {
+            panic!("no dead letter");
+        };
+        assert_eq!(topic, "dlq");
+        assert!(
+            user_properties
+                .iter()
+                .any(|p| p.key == "axonmq-sink" && p.value == "s1")
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_route_condition() {
+        let env = Environment::new();
+        let user_properties = vec![PropertyUser {
+            key: "site".to_string(),
+            value: "lyon".to_string(),
+        }];
+        let query = RouteQuery {
+            topic: "a/b",
+            client_id: "c",
+            server_name: None,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload: br#"{"temp": 31.5}"#,
+            user_properties: &user_properties,
+        };
+        let ctx = query.context();
+        let holds = |condition| Router::condition_holds(&env, "r1", condition, &ctx);
+        assert!(holds("qos == 1 and not retain"));
+        assert!(holds(
+            "payload.temp > 30 and user_properties.site == 'lyon'"
+        ));
+        assert!(!holds("client_id == 'other'"));
+        // a missing field is undefined, a failing evaluation does not hold
+        assert!(!holds("user_properties.zone == 'z1'"));
+        assert!(!holds("payload.temp > "));
+
+        let query = RouteQuery {
+            payload: b"not json",
+            ..query
+        };
+        let ctx = query.context();
+        assert!(!Router::condition_holds(
+            &env,
+            "r1",
+            "payload.temp > 30",
+            &ctx
+        ));
     }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
+
+use crate::features::Feature;
+use crate::spool::Spool;
+
+#[cfg(feature = "kafka")]
+use super::kafka::KafkaSink;
+#[cfg(feature = "s3")]
+use super::s3::S3Sink;
+use super::{Sink, influxdb::InfluxDbSink};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    /// gzip compressed NDJSON
+    #[default]
+    Jsonl,
+    Parquet,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type")]
+pub enum SinkConfig {
+    #[serde(rename = "kafka")]
+    Kafka {
+        brokers: String,
+        topic: String,
+        key: Option<String>,
+        batch_size: Option<usize>,
+        linger_ms: Option<u64>,
+        #[serde(default)]
+        properties: HashMap<String, String>,
+    },
+    #[serde(rename = "s3")]
+    S3 {
+        bucket: String,
+        endpoint: Option<String>,
+        region: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+        allow_http: Option<bool>,
+        prefix: Option<String>,
+        sample_every: Option<u64>,
+        condition: Option<String>,
+        flush_interval_secs: Option<u64>,
+        max_batch: Option<usize>,
+        max_segment_bytes: Option<usize>,
+        #[serde(default)]
+        format: ArchiveFormat,
+        partition: Option<String>,
+    },
+    #[serde(rename = "influxdb")]
+    InfluxDb {
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+        measurement: Option<String>,
+        #[serde(default)]
+        tags: HashMap<String, String>,
+        batch_size: Option<usize>,
+        flush_interval_ms: Option<u64>,
+        max_retries: Option<u32>,
+        timeout_ms: Option<u64>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl SinkConfig {
+    /// the sink, the kafka and influxdb ones keep what they could not deliver in `spool`
+    pub fn new_sink(
+        &self,
+        env: Arc<Environment<'static>>,
+        spool: Option<Spool>,
+    ) -> Result<Box<dyn Sink>, String> {
+        if let Some(reason) = self.feature().and_then(|feature| feature.unavailable()) {
+            return Err(reason);
+        }
+        match self {
+            #[cfg(feature = "kafka")]
+            SinkConfig::Kafka { .. } => {
+                KafkaSink::new(self.clone(), env, spool).map(|s| s as Box<dyn Sink>)
+            }
+            #[cfg(feature = "s3")]
+            SinkConfig::S3 { .. } => S3Sink::new(self.clone(), env).map(|s| s as Box<dyn Sink>),
+            SinkConfig::InfluxDb { .. } => {
+                InfluxDbSink::new(self.clone(), env, spool).map(|s| s as Box<dyn Sink>)
+            }
+            _ => Err("unsupported sink type".to_string()),
+        }
+    }
+
+    fn feature(&self) -> Option<Feature> {
+        match self {
+            SinkConfig::Kafka { .. } => Some(Feature::KafkaSink),
+            SinkConfig::S3 { .. } => Some(Feature::S3Sink),
+            SinkConfig::InfluxDb { .. } => Some(Feature::InfluxDbSink),
+            SinkConfig::Other => None,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use thiserror::Error;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Error)]
+pub enum SinkError {
+    #[error("Template error: {0}")]
+    TemplateError(String),
+    #[error("Queue full")]
+    QueueFull,
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    #[error("Rejected: {0}")]
+    Rejected(String),
+    #[error("Dropped by chaos faults")]
+    ChaosDropped,
+}
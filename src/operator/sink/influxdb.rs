@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use minijinja::{Environment, context};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde_json::Value as JsonValue;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, trace, warn};
+
+use crate::processor::message::Message;
+use crate::service::sparkplug_b::decode::{ScalarValue, decode_metrics};
+use crate::spool::{AsyncSpool, Spool};
+use crate::utils::time::now_milliseconds;
+
+use super::{Sink, config::SinkConfig, error::SinkError};
+
+const DEFAULT_MEASUREMENT: &str = "mqtt";
+const DEFAULT_BATCH_SIZE: usize = 5000;
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 1000;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// the write API of the bucket
+struct Endpoint {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+/// the lines of a message, told once they are written or spooled
+struct Lines {
+    lines: Vec<String>,
+    done: oneshot::Sender<Result<(), SinkError>>,
+}
+
+/// writes messages to InfluxDB v2 as line protocol, Sparkplug B metrics become one field each
+#[derive(Clone)]
+pub struct InfluxDbSink {
+    sender: mpsc::Sender<Lines>,
+    env: Arc<Environment<'static>>,
+    measurement: String,
+    tags: Vec<(String, String)>,
+    // metric aliases announced in births, keyed by group/node[/device]
+    aliases: Arc<Mutex<HashMap<String, HashMap<u64, String>>>>,
+}
+
+impl InfluxDbSink {
+    pub fn new(
+        config: SinkConfig,
+        env: Arc<Environment<'static>>,
+        spool: Option<Spool>,
+    ) -> Result<Box<Self>, String> {
+        let SinkConfig::InfluxDb {
+            url,
+            org,
+            bucket,
+            token,
+            measurement,
+            tags,
+            batch_size,
+            flush_interval_ms,
+            max_retries,
+            timeout_ms,
+        } = config
+        else {
+            return Err("invalid configuration for InfluxDbSink".to_string());
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(
+                timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+            ))
+            .build()
+            .map_err(|e| format!("failed to create http client: {}", e))?;
+        let write_url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ms",
+            url.trim_end_matches('/'),
+            utf8_percent_encode(&org, NON_ALPHANUMERIC),
+            utf8_percent_encode(&bucket, NON_ALPHANUMERIC)
+        );
+        let batch_size = batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+        let interval = Duration::from_millis(
+            flush_interval_ms
+                .unwrap_or(DEFAULT_FLUSH_INTERVAL_MS)
+                .max(1),
+        );
+
+        let (sender, receiver) = mpsc::channel(batch_size * 4);
+        let endpoint = Endpoint {
+            client,
+            url: write_url,
+            token,
+        };
+        tokio::spawn(Self::write(
+            endpoint,
+            receiver,
+            batch_size,
+            interval,
+            max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            spool.map(AsyncSpool::new),
+        ));
+        info!("influxdb sink writing to bucket {} of org {}", bucket, org);
+
+        let mut tags = tags.into_iter().collect::<Vec<_>>();
+        tags.sort();
+
+        Ok(Box::new(InfluxDbSink {
+            sender,
+            env,
+            measurement: measurement.unwrap_or(DEFAULT_MEASUREMENT.to_string()),
+            tags,
+            aliases: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+
+    fn lines(&self, message: &Message) -> Result<Vec<String>, SinkError> {
+        let ctx = context! {
+            topic => message.topic.clone(),
+            levels => message.topic.split('/').collect::<Vec<_>>(),
+            client_id => message.client_id.clone(),
+            metadata => message.metadata.clone(),
+        };
+        let measurement = self
+            .env
+            .render_str(&self.measurement, &ctx)
+            .map_err(|e| SinkError::TemplateError(e.to_string()))?;
+        let mut tags = self
+            .tags
+            .iter()
+            .filter_map(
+                |(key, template)| match self.env.render_str(template, &ctx) {
+                    Ok(value) if !value.is_empty() => Some((key.clone(), value)),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("failed to render influxdb tag {} template: {}", key, e);
+                        None
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+
+        if message.topic.starts_with("spBv1.0/") {
+            Ok(self.sparkplug_b_lines(message, &measurement, &mut tags))
+        } else {
+            let fields = json_fields(&message.payload);
+            if fields.is_empty() {
+                trace!(
+                    "influxdb sink skipped message without fields on {}",
+                    message.topic
+                );
+                return Ok(vec![]);
+            }
+            Ok(vec![line(&measurement, &tags, &fields, now_milliseconds())])
+        }
+    }
+
+    fn sparkplug_b_lines(
+        &self,
+        message: &Message,
+        measurement: &str,
+        tags: &mut Vec<(String, String)>,
+    ) -> Vec<String> {
+        let parts = message.topic.split('/').collect::<Vec<_>>();
+        if parts.len() != 4 && parts.len() != 5 {
+            return vec![];
+        }
+        let birth = match parts[2] {
+            "NBIRTH" | "DBIRTH" => true,
+            "NDATA" | "DDATA" => false,
+            _ => return vec![],
+        };
+
+        let (timestamp, metrics) = match decode_metrics(&message.payload) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                debug!("influxdb sink failed to decode {}: {}", message.topic, e);
+                return vec![];
+            }
+        };
+
+        tags.push(("group_id".to_string(), parts[1].to_string()));
+        tags.push(("node_id".to_string(), parts[3].to_string()));
+        if let Some(device) = parts.get(4) {
+            tags.push(("device_id".to_string(), device.to_string()));
+        }
+        tags.sort();
+
+        let edge = format!("{}/{}", parts[1], parts[3..].join("/"));
+        let mut aliases = self.aliases.lock().unwrap();
+        if birth {
+            let names = metrics
+                .iter()
+                .filter_map(|m| Some((m.alias?, m.name.clone()?)))
+                .collect();
+            aliases.insert(edge.clone(), names);
+        }
+        let names = aliases.get(&edge);
+
+        let now = now_milliseconds();
+        metrics
+            .into_iter()
+            .map(|m| {
+                let name = m
+                    .name
+                    .or_else(|| m.alias.and_then(|a| names?.get(&a).cloned()))
+                    .unwrap_or_else(|| format!("alias_{}", m.alias.unwrap_or_default()));
+                let ts = m.timestamp.or(timestamp).unwrap_or(now);
+                line(measurement, tags, &[(name, m.value)], ts)
+            })
+            .collect()
+    }
+
+    async fn write(
+        endpoint: Endpoint,
+        mut receiver: mpsc::Receiver<Lines>,
+        batch_size: usize,
+        interval: Duration,
+        max_retries: u32,
+        spool: Option<AsyncSpool>,
+    ) {
+        let mut ticker = tokio::time::interval(interval);
+        let mut batch: Vec<Lines> = Vec::with_capacity(batch_size);
+        let mut lines = 0;
+
+        loop {
+            let closed = tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => {
+                        lines += message.lines.len();
+                        batch.push(message);
+                        if lines < batch_size {
+                            continue;
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                _ = ticker.tick() => false,
+            };
+
+            if !batch.is_empty() {
+                let body = batch
+                    .iter()
+                    .flat_map(|message| message.lines.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let result = match spool.as_ref() {
+                    // behind the batches spooled already, to keep them in order
+                    Some(spool) if !spool.is_empty() => Self::spool(spool, body, lines).await,
+                    spool => match Self::post(&endpoint, &body, lines, max_retries).await {
+                        Err(SinkError::Unavailable(e)) => match spool {
+                            Some(spool) => Self::spool(spool, body, lines).await,
+                            None => {
+                                warn!("influxdb sink dropped {} lines after retries", lines);
+                                Err(SinkError::Unavailable(e))
+                            }
+                        },
+                        result => result,
+                    },
+                };
+                for message in batch.drain(..) {
+                    let _ = message.done.send(result.clone());
+                }
+                lines = 0;
+            }
+            if let Some(spool) = spool.as_ref().filter(|spool| !spool.is_empty()) {
+                Self::replay(&endpoint, spool).await;
+            }
+
+            if closed {
+                break;
+            }
+        }
+    }
+
+    async fn spool(spool: &AsyncSpool, body: String, lines: usize) -> Result<(), SinkError> {
+        match spool.push(body.into_bytes()).await {
+            Ok(true) => {
+                debug!("influxdb sink spooled {} lines", lines);
+                Ok(())
+            }
+            Ok(false) => {
+                warn!("influxdb sink spool full, {} lines dropped", lines);
+                Err(SinkError::Unavailable(
+                    "influxdb sink spool full".to_string(),
+                ))
+            }
+            Err(e) => {
+                warn!("influxdb sink failed to spool {} lines: {}", lines, e);
+                Err(SinkError::Unavailable(e.to_string()))
+            }
+        }
+    }
+
+    /// writes the spooled batches in order, until influxdb fails again
+    async fn replay(endpoint: &Endpoint, spool: &AsyncSpool) {
+        loop {
+            let body = match spool.front().await {
+                Ok(Some(record)) => String::from_utf8_lossy(&record).into_owned(),
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("influxdb sink failed to read its spool: {}", e);
+                    return;
+                }
+            };
+            let lines = body.lines().count();
+            if let Err(SinkError::Unavailable(_)) = Self::post(endpoint, &body, lines, 0).await {
+                return;
+            }
+            if let Err(e) = spool.pop().await {
+                warn!("influxdb sink failed to read its spool: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// `Unavailable` when influxdb could not be reached or kept failing, the lines are worth
+    /// writing again later, rejected ones are not
+    async fn post(
+        endpoint: &Endpoint,
+        body: &str,
+        lines: usize,
+        max_retries: u32,
+    ) -> Result<(), SinkError> {
+        let mut error = String::new();
+        for attempt in 0..=max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_millis(500 << (attempt - 1).min(5))).await;
+            }
+
+            let result = endpoint
+                .client
+                .post(&endpoint.url)
+                .header("Authorization", format!("Token {}", endpoint.token))
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(body.to_string())
+                .send()
+                .await;
+            match result {
+                Ok(resp) if resp.status().is_success() => {
+                    debug!("influxdb sink wrote {} lines", lines);
+                    return Ok(());
+                }
+                Ok(resp)
+                    if resp.status().is_server_error()
+                        || resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    warn!(
+                        "influxdb write failed with {}, attempt {}",
+                        resp.status(),
+                        attempt + 1
+                    );
+                    error = resp.status().to_string();
+                }
+                Ok(resp) => {
+                    let status = resp.status();
+                    let text = resp.text().await.unwrap_or_default();
+                    warn!(
+                        "influxdb rejected {} lines with {}: {}",
+                        lines, status, text
+                    );
+                    return Err(SinkError::Rejected(format!("{}: {}", status, text)));
+                }
+                Err(e) => {
+                    warn!("influxdb write failed: {}, attempt {}", e, attempt + 1);
+                    error = e.to_string();
+                }
+            }
+        }
+        Err(SinkError::Unavailable(error))
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxDbSink {
+    /// done once the lines of the message are written, or spooled
+    async fn deliver(&self, message: Message, _persist: bool) -> Result<(), SinkError> {
+        let lines = self.lines(&message)?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let (done, written) = oneshot::channel();
+        self.sender.try_send(Lines { lines, done }).map_err(|_| {
+            trace!("influxdb sink queue full");
+            SinkError::QueueFull
+        })?;
+        written.await.unwrap_or_else(|_| {
+            Err(SinkError::Unavailable(
+                "influxdb sink writer stopped".to_string(),
+            ))
+        })
+    }
+}
+
+/// scalar leaves of a JSON payload, nested keys joined with '.'
+fn json_fields(payload: &[u8]) -> Vec<(String, ScalarValue)> {
+    fn walk(prefix: &str, value: &JsonValue, fields: &mut Vec<(String, ScalarValue)>) {
+        let scalar = match value {
+            JsonValue::Object(map) => {
+                for (key, value) in map {
+                    let key = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(&key, value, fields);
+                }
+                return;
+            }
+            JsonValue::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(v), _, _) => ScalarValue::Int(v),
+                (_, Some(v), _) => ScalarValue::UInt(v),
+                (_, _, Some(v)) => ScalarValue::Float(v),
+                _ => return,
+            },
+            JsonValue::Bool(v) => ScalarValue::Boolean(*v),
+            JsonValue::String(v) => ScalarValue::String(v.clone()),
+            JsonValue::Null | JsonValue::Array(_) => return,
+        };
+        let key = if prefix.is_empty() { "value" } else { prefix };
+        fields.push((key.to_string(), scalar));
+    }
+
+    let mut fields = vec![];
+    if let Ok(value) = serde_json::from_slice::<JsonValue>(payload) {
+        walk("", &value, &mut fields);
+    }
+    fields
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn line(
+    measurement: &str,
+    tags: &[(String, String)],
+    fields: &[(String, ScalarValue)],
+    timestamp: u64,
+) -> String {
+    const KEY: &[char] = &[',', '=', ' '];
+
+    let mut line = escape(measurement, &[',', ' ']);
+    for (key, value) in tags {
+        line.push(',');
+        line.push_str(&escape(key, KEY));
+        line.push('=');
+        line.push_str(&escape(value, KEY));
+    }
+
+    let fields = fields
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                ScalarValue::Int(v) => format!("{}i", v),
+                ScalarValue::UInt(v) => format!("{}u", v),
+                ScalarValue::Float(v) => v.to_string(),
+                ScalarValue::Boolean(v) => v.to_string(),
+                ScalarValue::String(v) => format!("\"{}\"", escape(v, &['"'])),
+            };
+            format!("{}={}", escape(key, KEY), value)
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("{} {} {}", line, fields, timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_protocol() {
+        let fields =
+            json_fields(br#"{"temp": 21.5, "ok": true, "meta": {"id": 7, "name": "a \"b\""}}"#);
+        let tags = vec![("site".to_string(), "plant 1".to_string())];
+        assert_eq!(
+            line("my,measure", &tags, &fields, 1000),
+            r#"my\,measure,site=plant\ 1 meta.id=7i,meta.name="a \"b\"",ok=true,temp=21.5 1000"#
+        );
+
+        assert_eq!(
+            json_fields(b"42"),
+            vec![("value".to_string(), ScalarValue::Int(42))]
+        );
+        assert!(json_fields(b"not json").is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use minijinja::{Environment, context};
+use rdkafka::error::KafkaError;
+use rdkafka::message::Message as _;
+use rdkafka::producer::{
+    BaseRecord, DeliveryResult, Producer as _, ProducerContext, ThreadedProducer,
+};
+use rdkafka::{ClientConfig, ClientContext};
+use tokio::sync::oneshot;
+use tokio::task;
+use tracing::{debug, warn};
+
+use crate::processor::message::Message;
+use crate::spool::Spool;
+
+use super::{Sink, config::SinkConfig, error::SinkError};
+
+// how often the spool is checked for records to send again
+const REPLAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the delivery opaque of a record
+enum Opaque {
+    // a message of a chain, told once kafka took it or it was spooled
+    Message(oneshot::Sender<Result<(), SinkError>>),
+    // a spooled record sent again
+    Replayed,
+    // the oldest spooled record sent while kafka is unreachable, it stays in the spool until
+    // delivered
+    Probe,
+}
+
+const PROBE_NONE: u8 = 0;
+const PROBE_SENT: u8 = 1;
+const PROBE_DELIVERED: u8 = 2;
+const PROBE_FAILED: u8 = 3;
+
+// topic, key and payload of a spooled record
+type Record<'a> = (String, Option<Vec<u8>>, &'a [u8]);
+
+fn encode(topic: &str, key: Option<&[u8]>, payload: &[u8]) -> Vec<u8> {
+    let key = key.unwrap_or_default();
+    let mut record = Vec::with_capacity(topic.len() + key.len() + payload.len() + 7);
+    record.write_u16::<BigEndian>(topic.len() as u16).unwrap();
+    record.extend_from_slice(topic.as_bytes());
+    record.write_u8(!key.is_empty() as u8).unwrap();
+    record.write_u32::<BigEndian>(key.len() as u32).unwrap();
+    record.extend_from_slice(key);
+    record.extend_from_slice(payload);
+    record
+}
+
+fn decode(record: &[u8]) -> io::Result<Record<'_>> {
+    let mut rdr = io::Cursor::new(record);
+    let mut topic = vec![0u8; rdr.read_u16::<BigEndian>()? as usize];
+    rdr.read_exact(&mut topic)?;
+    let topic =
+        String::from_utf8(topic).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let has_key = rdr.read_u8()? != 0;
+    let mut key = vec![0u8; rdr.read_u32::<BigEndian>()? as usize];
+    rdr.read_exact(&mut key)?;
+    let pos = rdr.position() as usize;
+    Ok((topic, has_key.then_some(key), &record[pos..]))
+}
+
+/// spools the records kafka failed to take, when the sink has a spool
+struct SpoolContext {
+    spool: Option<Mutex<Spool>>,
+    // false from a failed delivery until one goes through
+    up: AtomicBool,
+    probe: AtomicU8,
+}
+
+fn spool(
+    spool: &mut Spool,
+    topic: &str,
+    key: Option<&[u8]>,
+    payload: &[u8],
+) -> Result<(), SinkError> {
+    match spool.push(&encode(topic, key, payload)) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            debug!("kafka sink spool full, message to {} dropped", topic);
+            Err(SinkError::Unavailable("kafka sink spool full".to_string()))
+        }
+        Err(e) => {
+            warn!("kafka sink failed to spool a message to {}: {}", topic, e);
+            Err(SinkError::Unavailable(e.to_string()))
+        }
+    }
+}
+
+impl ClientContext for SpoolContext {}
+
+impl ProducerContext for SpoolContext {
+    type DeliveryOpaque = Box<Opaque>;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, opaque: Box<Opaque>) {
+        let result = match result {
+            Ok(_) => {
+                self.up.store(true, Ordering::Relaxed);
+                if let Opaque::Probe = *opaque {
+                    self.probe.store(PROBE_DELIVERED, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Err((e, message)) => {
+                self.up.store(false, Ordering::Relaxed);
+                if let Opaque::Probe = *opaque {
+                    self.probe.store(PROBE_FAILED, Ordering::Relaxed);
+                    return;
+                }
+                match &self.spool {
+                    Some(locked) => spool(
+                        &mut locked.lock().unwrap(),
+                        message.topic(),
+                        message.key(),
+                        message.payload().unwrap_or_default(),
+                    ),
+                    None => {
+                        debug!(
+                            "failed to deliver message to kafka topic {}: {}",
+                            message.topic(),
+                            e
+                        );
+                        Err(SinkError::Unavailable(e.to_string()))
+                    }
+                }
+            }
+        };
+        if let Opaque::Message(done) = *opaque {
+            let _ = done.send(result);
+        }
+    }
+}
+
+/// produces messages to Kafka, librdkafka batches them in the background
+#[derive(Clone)]
+pub struct KafkaSink {
+    producer: Arc<ThreadedProducer<SpoolContext>>,
+    env: Arc<Environment<'static>>,
+    topic_template: String,
+    key_template: Option<String>,
+}
+
+impl KafkaSink {
+    pub fn new(
+        config: SinkConfig,
+        env: Arc<Environment<'static>>,
+        spool: Option<Spool>,
+    ) -> Result<Box<Self>, String> {
+        let SinkConfig::Kafka {
+            brokers,
+            topic,
+            key,
+            batch_size,
+            linger_ms,
+            properties,
+        } = config
+        else {
+            return Err("invalid configuration for KafkaSink".to_string());
+        };
+
+        let mut client_config = ClientConfig::new();
+        client_config.set("bootstrap.servers", &brokers);
+        if let Some(batch_size) = batch_size {
+            client_config.set("batch.num.messages", batch_size.to_string());
+        }
+        if let Some(linger_ms) = linger_ms {
+            client_config.set("linger.ms", linger_ms.to_string());
+        }
+        for (k, v) in &properties {
+            client_config.set(k, v);
+        }
+
+        let spooled = spool.is_some();
+        let context = SpoolContext {
+            spool: spool.map(Mutex::new),
+            up: AtomicBool::new(true),
+            probe: AtomicU8::new(PROBE_NONE),
+        };
+        let producer = Arc::new(
+            client_config
+                .create_with_context::<_, ThreadedProducer<SpoolContext>>(context)
+                .map_err(|e| format!("failed to create kafka producer: {}", e))?,
+        );
+        if spooled {
+            tokio::spawn(Self::replay(producer.clone()));
+        }
+
+        Ok(Box::new(KafkaSink {
+            producer,
+            env,
+            topic_template: topic,
+            key_template: key,
+        }))
+    }
+
+    fn render(&self, template: &str, message: &Message) -> Result<String, minijinja::Error> {
+        let ctx = context! {
+            topic => message.topic.clone(),
+            levels => message.topic.split('/').collect::<Vec<_>>(),
+            client_id => message.client_id.clone(),
+            qos => message.qos as u8,
+            retain => message.retain,
+            metadata => message.metadata.clone(),
+        };
+        self.env.render_str(template, ctx)
+    }
+
+    fn send(
+        producer: &ThreadedProducer<SpoolContext>,
+        topic: &str,
+        key: Option<&[u8]>,
+        payload: &[u8],
+        opaque: Opaque,
+    ) -> Result<(), KafkaError> {
+        let mut record =
+            BaseRecord::<[u8], [u8], Box<Opaque>>::with_opaque_to(topic, opaque.into())
+                .payload(payload);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        producer.send(record).map_err(|(e, _)| e)
+    }
+
+    /// sends the spool again once kafka takes messages, a probe at a time while it does not
+    async fn replay(producer: Arc<ThreadedProducer<SpoolContext>>) {
+        let mut ticker = tokio::time::interval(REPLAY_INTERVAL);
+        // until the sink is dropped
+        while Arc::strong_count(&producer) > 1 {
+            ticker.tick().await;
+            if producer.context().spool.is_none() {
+                return;
+            }
+            // the spool file I/O blocks
+            let producer = producer.clone();
+            let _ = task::spawn_blocking(move || Self::replay_spool(&producer)).await;
+        }
+    }
+
+    fn replay_spool(producer: &ThreadedProducer<SpoolContext>) {
+        let context = producer.context();
+        let Some(spool) = &context.spool else {
+            return;
+        };
+        let probe = context.probe.load(Ordering::Relaxed);
+        if probe == PROBE_SENT {
+            return;
+        }
+        let mut spool = spool.lock().unwrap();
+        if probe == PROBE_DELIVERED
+            && let Err(e) = spool.pop()
+        {
+            warn!("kafka sink failed to read its spool: {}", e);
+        }
+        context.probe.store(PROBE_NONE, Ordering::Relaxed);
+
+        let up = context.up.load(Ordering::Relaxed);
+        loop {
+            let record = match spool.front() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("kafka sink failed to read its spool: {}", e);
+                    break;
+                }
+            };
+            let Ok((topic, key, payload)) = decode(&record) else {
+                warn!("kafka sink dropped an unreadable spooled message");
+                let _ = spool.pop();
+                continue;
+            };
+            let opaque = if up { Opaque::Replayed } else { Opaque::Probe };
+            if Self::send(producer, &topic, key.as_deref(), payload, opaque).is_err() {
+                break;
+            }
+            if !up {
+                context.probe.store(PROBE_SENT, Ordering::Relaxed);
+                break;
+            }
+            if let Err(e) = spool.pop() {
+                warn!("kafka sink failed to read its spool: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    /// done once kafka acknowledged the message, or it was spooled
+    async fn deliver(&self, message: Message, _persist: bool) -> Result<(), SinkError> {
+        let template_error = |e: minijinja::Error| SinkError::TemplateError(e.to_string());
+        let topic = self
+            .render(&self.topic_template, &message)
+            .map_err(template_error)?;
+        let key = self
+            .key_template
+            .as_deref()
+            .map(|t| self.render(t, &message))
+            .transpose()
+            .map_err(template_error)?;
+
+        let (done, delivered) = oneshot::channel();
+        let opaque = Opaque::Message(done);
+        if self.producer.context().spool.is_some() {
+            // the spool file I/O blocks
+            let producer = self.producer.clone();
+            let spooled = task::spawn_blocking(move || {
+                let context = producer.context();
+                let mut locked = context.spool.as_ref().unwrap().lock().unwrap();
+                let key = key.as_deref().map(str::as_bytes);
+                // in order, behind what is spooled already
+                let sent = context.up.load(Ordering::Relaxed)
+                    && locked.is_empty()
+                    && Self::send(&producer, &topic, key, &message.payload, opaque).is_ok();
+                (!sent).then(|| spool(&mut locked, &topic, key, &message.payload))
+            })
+            .await
+            .expect("kafka spool task panicked");
+            if let Some(result) = spooled {
+                return result;
+            }
+        } else {
+            let key = key.as_deref().map(str::as_bytes);
+            Self::send(&self.producer, &topic, key, &message.payload, opaque).map_err(|e| {
+                warn!("failed to produce message to kafka topic {}: {}", topic, e);
+                SinkError::Unavailable(e.to_string())
+            })?
+        }
+        delivered.await.unwrap_or_else(|_| {
+            Err(SinkError::Unavailable(
+                "kafka producer gone before delivery".to_string(),
+            ))
+        })
+    }
+}
@@ -1,10 +1,18 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
 use tokio::sync::mpsc::{Sender, error::TrySendError};
 use tracing::{debug, trace, warn};
 
-use crate::mqtt::{QoS, command::ClientCommand, helper::BrokerHelper};
+use crate::mqtt::{
+    QoS,
+    command::ClientCommand,
+    helper::BrokerHelper,
+    protocol::publish::{PublishBody, SharedPublish},
+};
 use crate::processor::message::Message;
 
-use super::Sink;
+use super::{Sink, error::SinkError};
 
 #[derive(Clone)]
 pub struct LocalClientSink {
@@ -19,47 +27,59 @@ impl LocalClientSink {
             broker_helper,
         })
     }
+
+    fn send(&self, client_id: &str, publish: SharedPublish, persist: bool) {
+        let qos = publish.qos;
+        match self.sender.try_send(ClientCommand::Publish(publish)) {
+            Ok(_) => {}
+            Err(TrySendError::Full(msg)) => {
+                debug!("message queue full for client {}", client_id);
+                self.broker_helper.slow_consumer(client_id, msg);
+            }
+            Err(TrySendError::Closed(msg)) => {
+                if persist && qos != QoS::AtMostOnce {
+                    if let Err(e) = self.broker_helper.store_msg(client_id, msg) {
+                        warn!("failed to store message for client {}: {}", client_id, e);
+                    }
+                } else {
+                    trace!("client {} disconnected, dropping message", client_id);
+                }
+            }
+        }
+    }
 }
 
+#[async_trait]
 impl Sink for LocalClientSink {
-    fn deliver(&self, message: Message, persist: bool) {
-        let msg = ClientCommand::Publish {
+    async fn deliver(&self, message: Message, persist: bool) -> Result<(), SinkError> {
+        let subscription_identifier = message.options.subscription_identifier;
+        let body = PublishBody {
             topic: message.topic,
-            qos: message.qos,
-            retain: message.retain,
             payload: message.payload,
             user_properties: message.user_properties,
             options: message.options,
         };
+        let publish = SharedPublish::new(message.qos, message.retain, Arc::new(body))
+            .with_subscription_identifier(subscription_identifier);
+        self.send(&message.client_id, publish, persist);
+        Ok(())
+    }
 
-        if persist && message.qos != QoS::AtMostOnce {
-            let result = self.sender.try_send(msg);
-            match result {
-                Ok(_) => {}
-                Err(TrySendError::Full(msg)) | Err(TrySendError::Closed(msg)) => {
-                    if let Err(e) = self.broker_helper.store_msg(&message.client_id, msg) {
-                        warn!(
-                            "failed to store message for client {}: {}",
-                            message.client_id, e
-                        );
-                    }
+    fn deliver_shared(&self, client_id: &str, publish: SharedPublish, persist: bool) {
+        self.send(client_id, publish, persist);
+    }
+
+    /// one reservation for the batch, one at a time when the queue has no room for all of it
+    fn deliver_batch(&self, client_id: &str, publishes: Vec<SharedPublish>, persist: bool) {
+        match self.sender.try_reserve_many(publishes.len()) {
+            Ok(permits) => {
+                for (permit, publish) in permits.zip(publishes) {
+                    permit.send(ClientCommand::Publish(publish));
                 }
             }
-        } else {
-            if let Err(e) = self.sender.try_send(msg) {
-                match e {
-                    TrySendError::Full(_) => {
-                        debug!(
-                            "message queue full for client {}, dropping message",
-                            message.client_id
-                        );
-                    }
-                    TrySendError::Closed(_) => {
-                        trace!(
-                            "client {} disconnected, dropping message",
-                            message.client_id
-                        );
-                    }
+            Err(_) => {
+                for publish in publishes {
+                    self.send(client_id, publish, persist);
                 }
             }
         }
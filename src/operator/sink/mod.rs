@@ -1,15 +1,71 @@
+pub mod config;
+pub mod error;
+pub mod influxdb;
+#[cfg(feature = "kafka")]
+pub mod kafka;
 pub mod local;
+pub mod remote;
+#[cfg(feature = "s3")]
+pub mod s3;
 
+use async_trait::async_trait;
 use dyn_clone::DynClone;
+use tracing::debug;
 
+use crate::mqtt::protocol::publish::SharedPublish;
 use crate::processor::message::Message;
 
-pub trait Sink: Send + Sync + DynClone {
-    fn deliver(&self, message: Message, persist: bool);
+use self::error::SinkError;
+
+#[async_trait]
+pub trait Sink: Send + Sync + DynClone + 'static {
+    /// done once the sink took the message, an error lets the chain retry or dead letter it
+    async fn deliver(&self, message: Message, persist: bool) -> Result<(), SinkError>;
+
+    /// deliver a message fanned out to many subscribers, its body shared by all of them,
+    /// sinks other than the local client get a `Message` of their own, delivered in the
+    /// background
+    fn deliver_shared(&self, client_id: &str, publish: SharedPublish, persist: bool) {
+        let message = message(client_id, &publish);
+        let sink = dyn_clone::clone_box(self);
+        let client_id = client_id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = sink.deliver(message, persist).await {
+                debug!("failed to deliver message to {}: {}", client_id, e);
+            }
+        });
+    }
+
+    /// deliver the messages of a batch of publishes going to the same subscriber, in order
+    fn deliver_batch(&self, client_id: &str, publishes: Vec<SharedPublish>, persist: bool) {
+        for publish in publishes {
+            self.deliver_shared(client_id, publish, persist);
+        }
+    }
+
+    /// the cluster node the subscriber is connected to, None for local subscribers
+    fn remote_node(&self) -> Option<&str> {
+        None
+    }
 }
 
 dyn_clone::clone_trait_object!(Sink);
 
+// the message of a subscriber, out of the body shared by all of them
+fn message(client_id: &str, publish: &SharedPublish) -> Message {
+    let body = &publish.body;
+    Message::new(
+        client_id.to_string(),
+        body.topic.clone(),
+        publish.qos,
+        publish.retain,
+        body.payload.clone(),
+        body.user_properties.clone(),
+    )
+    .with_options(body.options.clone())
+    .with_subscription_identifier(publish.subscription_identifier)
+}
+
 #[derive(Clone)]
 pub struct DefaultSink;
 
@@ -19,6 +75,9 @@ impl DefaultSink {
     }
 }
 
+#[async_trait]
 impl Sink for DefaultSink {
-    fn deliver(&self, _message: Message, _persist: bool) {}
+    async fn deliver(&self, _message: Message, _persist: bool) -> Result<(), SinkError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+use tracing::debug;
+
+use crate::cluster::ClusterHelper;
+use crate::mqtt::protocol::publish::SharedPublish;
+use crate::processor::message::Message;
+
+use super::{Sink, error::SinkError};
+
+/// subscriber on another cluster node, messages are forwarded to that node
+#[derive(Clone)]
+pub struct RemoteNodeSink {
+    node_id: String,
+    share_group: Option<String>,
+    cluster_helper: ClusterHelper,
+}
+
+impl RemoteNodeSink {
+    pub fn new(
+        node_id: String,
+        share_group: Option<String>,
+        cluster_helper: ClusterHelper,
+    ) -> Box<Self> {
+        Box::new(RemoteNodeSink {
+            node_id,
+            share_group,
+            cluster_helper,
+        })
+    }
+}
+
+impl RemoteNodeSink {
+    fn forward(&self, message: Message) -> Result<(), SinkError> {
+        self.cluster_helper
+            .forward(&self.node_id, self.share_group.clone(), message)
+            .map_err(|e| {
+                debug!("failed to forward message to node {}: {}", self.node_id, e);
+                SinkError::Unavailable(e.to_string())
+            })
+    }
+}
+
+#[async_trait]
+impl Sink for RemoteNodeSink {
+    async fn deliver(&self, message: Message, _persist: bool) -> Result<(), SinkError> {
+        self.forward(message)
+    }
+
+    /// forwarded right away with the client id of the publisher, the cluster link queues the
+    /// message
+    fn deliver_shared(&self, client_id: &str, publish: SharedPublish, _persist: bool) {
+        let publisher = publish.publisher.clone();
+        let _ = self.forward(super::message(
+            publisher.as_deref().unwrap_or(client_id),
+            &publish,
+        ));
+    }
+
+    fn remote_node(&self) -> Option<&str> {
+        Some(&self.node_id)
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use arrow_array::{
+    ArrayRef, BinaryArray, BooleanArray, RecordBatch, StringArray, TimestampMillisecondArray,
+    UInt8Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use base64::Engine as _;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use flate2::{Compression, write::GzEncoder};
+use minijinja::{Environment, Value, context};
+use object_store::{ObjectStore, PutPayload, aws::AmazonS3, aws::AmazonS3Builder, path::Path};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression as ParquetCompression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn};
+
+use crate::CONFIG;
+use crate::processor::message::Message;
+use crate::utils::time::now_milliseconds;
+
+use super::{
+    Sink,
+    config::{ArchiveFormat, SinkConfig},
+    error::SinkError,
+};
+
+const DEFAULT_PREFIX: &str = "axonmq";
+const DEFAULT_PARTITION: &str = "{{ year }}/{{ month }}/{{ day }}";
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 300;
+const DEFAULT_MAX_BATCH: usize = 10000;
+const DEFAULT_MAX_SEGMENT_BYTES: usize = 64 * 1024 * 1024;
+// how often segments are checked against flush_interval_secs
+const SEGMENT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    client_id: &'a str,
+    topic: &'a str,
+    qos: u8,
+    retain: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_base64: Option<String>,
+}
+
+struct Entry {
+    partition: String,
+    timestamp: u64,
+    client_id: String,
+    topic: String,
+    qos: u8,
+    retain: bool,
+    payload: Bytes,
+}
+
+impl Entry {
+    fn new(message: &Message, timestamp: u64, partition: String) -> Self {
+        Entry {
+            partition,
+            timestamp,
+            client_id: message.client_id.clone(),
+            topic: message.topic.clone(),
+            qos: message.qos as u8,
+            retain: message.retain,
+            payload: message.payload.clone(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.client_id.len() + self.topic.len() + self.payload.len()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let text = std::str::from_utf8(&self.payload).ok();
+        let record = Record {
+            timestamp: self.timestamp,
+            client_id: &self.client_id,
+            topic: &self.topic,
+            qos: self.qos,
+            retain: self.retain,
+            payload: text,
+            payload_base64: text
+                .is_none()
+                .then(|| base64::engine::general_purpose::STANDARD.encode(&self.payload)),
+        };
+
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        line
+    }
+}
+
+enum SegmentBody {
+    Jsonl(GzEncoder<Vec<u8>>),
+    // columns are built when the segment is closed
+    Parquet(Vec<Entry>),
+}
+
+/// messages of one partition waiting to be uploaded as one object
+struct Segment {
+    body: SegmentBody,
+    opened: Instant,
+    messages: usize,
+    bytes: usize,
+}
+
+impl Segment {
+    fn new(format: ArchiveFormat) -> Self {
+        let body = match format {
+            ArchiveFormat::Jsonl => {
+                SegmentBody::Jsonl(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            ArchiveFormat::Parquet => SegmentBody::Parquet(Vec::new()),
+        };
+        Segment {
+            body,
+            opened: Instant::now(),
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.messages += 1;
+        self.bytes += entry.size();
+        match &mut self.body {
+            SegmentBody::Jsonl(encoder) => {
+                encoder.write_all(&entry.encode()).ok();
+            }
+            SegmentBody::Parquet(entries) => entries.push(entry),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, String> {
+        match self.body {
+            SegmentBody::Jsonl(encoder) => encoder.finish().map_err(|e| e.to_string()),
+            SegmentBody::Parquet(entries) => Self::to_parquet(&entries),
+        }
+    }
+
+    fn to_parquet(entries: &[Entry]) -> Result<Vec<u8>, String> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "timestamp",
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                false,
+            ),
+            Field::new("client_id", DataType::Utf8, false),
+            Field::new("topic", DataType::Utf8, false),
+            Field::new("qos", DataType::UInt8, false),
+            Field::new("retain", DataType::Boolean, false),
+            Field::new("payload", DataType::Binary, false),
+        ]));
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(
+                TimestampMillisecondArray::from_iter_values(
+                    entries.iter().map(|e| e.timestamp as i64),
+                )
+                .with_timezone("UTC"),
+            ),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.client_id.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                entries.iter().map(|e| e.topic.as_str()),
+            )),
+            Arc::new(UInt8Array::from_iter_values(entries.iter().map(|e| e.qos))),
+            Arc::new(BooleanArray::from(
+                entries.iter().map(|e| e.retain).collect::<Vec<_>>(),
+            )),
+            Arc::new(BinaryArray::from_iter_values(
+                entries.iter().map(|e| e.payload.as_ref()),
+            )),
+        ];
+        let batch = RecordBatch::try_new(schema.clone(), columns).map_err(|e| e.to_string())?;
+
+        let properties = WriterProperties::builder()
+            .set_compression(ParquetCompression::SNAPPY)
+            .build();
+        let mut writer = ArrowWriter::try_new(Vec::new(), schema, Some(properties))
+            .map_err(|e| e.to_string())?;
+        writer.write(&batch).map_err(|e| e.to_string())?;
+        writer.into_inner().map_err(|e| e.to_string())
+    }
+}
+
+struct Limits {
+    interval: Duration,
+    max_batch: usize,
+    max_segment_bytes: usize,
+}
+
+/// archives messages into size and time bounded JSONL or Parquet segments,
+/// one per partition, uploaded to S3 compatible storage
+#[derive(Clone)]
+pub struct S3Sink {
+    sender: mpsc::Sender<Entry>,
+    env: Arc<Environment<'static>>,
+    condition: Option<String>,
+    partition: String,
+    sample_every: u64,
+    seen: Arc<AtomicU64>,
+}
+
+impl S3Sink {
+    pub fn new(config: SinkConfig, env: Arc<Environment<'static>>) -> Result<Box<Self>, String> {
+        let SinkConfig::S3 {
+            bucket,
+            endpoint,
+            region,
+            access_key_id,
+            secret_access_key,
+            allow_http,
+            prefix,
+            sample_every,
+            condition,
+            flush_interval_secs,
+            max_batch,
+            max_segment_bytes,
+            format,
+            partition,
+        } = config
+        else {
+            return Err("invalid configuration for S3Sink".to_string());
+        };
+
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&bucket);
+        if let Some(endpoint) = endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(region) = region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        let store = builder
+            .with_allow_http(allow_http.unwrap_or(false))
+            .build()
+            .map_err(|e| format!("failed to create s3 client: {}", e))?;
+
+        let prefix = format!(
+            "{}/{}",
+            prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_PREFIX)
+                .trim_end_matches('/'),
+            CONFIG.get().unwrap().node.id
+        );
+        let limits = Limits {
+            interval: Duration::from_secs(
+                flush_interval_secs
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL_SECS)
+                    .max(1),
+            ),
+            max_batch: max_batch.unwrap_or(DEFAULT_MAX_BATCH).max(1),
+            max_segment_bytes: max_segment_bytes
+                .unwrap_or(DEFAULT_MAX_SEGMENT_BYTES)
+                .max(1),
+        };
+
+        let (sender, receiver) = mpsc::channel(limits.max_batch);
+        info!(
+            "s3 sink archiving {:?} segments to bucket {} every {:?}",
+            format, bucket, limits.interval
+        );
+        tokio::spawn(Self::upload(store, prefix, format, receiver, limits));
+
+        Ok(Box::new(S3Sink {
+            sender,
+            env,
+            condition,
+            partition: partition.unwrap_or_else(|| DEFAULT_PARTITION.to_string()),
+            sample_every: sample_every.unwrap_or(1).max(1),
+            seen: Arc::new(AtomicU64::new(0)),
+        }))
+    }
+
+    fn context(message: &Message, timestamp: u64) -> Value {
+        let payload = serde_json::from_slice::<JsonValue>(&message.payload).unwrap_or_default();
+        let date = DateTime::<Utc>::from_timestamp_millis(timestamp as i64).unwrap_or_default();
+        context! {
+            topic => message.topic.clone(),
+            levels => message.topic.split('/').collect::<Vec<_>>(),
+            client_id => message.client_id.clone(),
+            qos => message.qos as u8,
+            retain => message.retain,
+            payload => payload,
+            metadata => message.metadata.clone(),
+            year => date.format("%Y").to_string(),
+            month => date.format("%m").to_string(),
+            day => date.format("%d").to_string(),
+            hour => date.format("%H").to_string(),
+        }
+    }
+
+    fn matches(&self, ctx: &Value) -> bool {
+        let Some(condition) = self.condition.as_ref() else {
+            return true;
+        };
+
+        match self.env.render_str(condition, ctx) {
+            Ok(rendered) => {
+                let rendered = rendered.trim();
+                !rendered.is_empty() && !rendered.eq_ignore_ascii_case("false") && rendered != "0"
+            }
+            Err(e) => {
+                warn!("failed to render s3 sink condition: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn upload(
+        store: AmazonS3,
+        prefix: String,
+        format: ArchiveFormat,
+        mut receiver: mpsc::Receiver<Entry>,
+        limits: Limits,
+    ) {
+        let mut ticker = tokio::time::interval(SEGMENT_CHECK_INTERVAL);
+        let mut segments: HashMap<String, Segment> = HashMap::new();
+        let mut seq = 0u64;
+
+        loop {
+            let (closing, closed) = tokio::select! {
+                entry = receiver.recv() => match entry {
+                    Some(entry) => {
+                        let partition = entry.partition.clone();
+                        let segment = segments
+                            .entry(partition.clone())
+                            .or_insert_with(|| Segment::new(format));
+                        segment.push(entry);
+                        if segment.messages < limits.max_batch
+                            && segment.bytes < limits.max_segment_bytes
+                        {
+                            continue;
+                        }
+                        (vec![partition], false)
+                    }
+                    None => (segments.keys().cloned().collect(), true),
+                },
+                _ = ticker.tick() => {
+                    let expired = segments
+                        .iter()
+                        .filter(|(_, segment)| segment.opened.elapsed() >= limits.interval)
+                        .map(|(partition, _)| partition.clone())
+                        .collect();
+                    (expired, false)
+                }
+            };
+
+            for partition in closing {
+                let Some(segment) = segments.remove(&partition) else {
+                    continue;
+                };
+                let messages = segment.messages;
+                match segment.finish() {
+                    Ok(body) => {
+                        let key = Self::object_key(&prefix, &partition, format, seq);
+                        match store.put(&key, PutPayload::from(body)).await {
+                            Ok(_) => debug!("s3 sink uploaded {} messages to {}", messages, key),
+                            Err(e) => warn!("s3 sink failed to upload {}: {}", key, e),
+                        }
+                    }
+                    Err(e) => warn!("s3 sink failed to encode segment: {}", e),
+                }
+                seq += 1;
+            }
+
+            if closed {
+                break;
+            }
+        }
+    }
+
+    fn object_key(prefix: &str, partition: &str, format: ArchiveFormat, seq: u64) -> Path {
+        let extension = match format {
+            ArchiveFormat::Jsonl => "ndjson.gz",
+            ArchiveFormat::Parquet => "parquet",
+        };
+        Path::from(format!(
+            "{}/{}/{}-{}.{}",
+            prefix,
+            partition.trim_matches('/'),
+            Utc::now().format("%H%M%S%3f"),
+            seq,
+            extension
+        ))
+    }
+}
+
+#[async_trait]
+impl Sink for S3Sink {
+    /// done once the message is in a segment, upload failures are logged
+    async fn deliver(&self, message: Message, _persist: bool) -> Result<(), SinkError> {
+        let timestamp = now_milliseconds();
+        let ctx = Self::context(&message, timestamp);
+        if !self.matches(&ctx) {
+            return Ok(());
+        }
+        if !self
+            .seen
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample_every)
+        {
+            return Ok(());
+        }
+
+        let partition = self
+            .env
+            .render_str(&self.partition, &ctx)
+            .map_err(|e| SinkError::TemplateError(e.to_string()))?;
+        self.sender
+            .try_send(Entry::new(&message, timestamp, partition))
+            .map_err(|_| {
+                trace!("s3 sink queue full");
+                SinkError::QueueFull
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::{reader::FileReader, serialized_reader::SerializedFileReader};
+
+    use super::*;
+    use crate::mqtt::QoS;
+
+    #[test]
+    fn test_encode_record() {
+        let text = Message::new(
+            "c1".into(),
+            "a/b".into(),
+            QoS::AtLeastOnce,
+            false,
+            Bytes::from_static(b"{\"t\":1}"),
+            vec![],
+        );
+        let line = Entry::new(&text, 7, String::new()).encode();
+        assert_eq!(
+            line,
+            b"{\"timestamp\":7,\"client_id\":\"c1\",\"topic\":\"a/b\",\"qos\":1,\"retain\":false,\"payload\":\"{\\\"t\\\":1}\"}\n"
+        );
+
+        let binary = Message::new(
+            "c1".into(),
+            "a/b".into(),
+            QoS::AtMostOnce,
+            false,
+            Bytes::from_static(&[0xff, 0x00]),
+            vec![],
+        );
+        let value: JsonValue =
+            serde_json::from_slice(&Entry::new(&binary, 7, String::new()).encode()).unwrap();
+        assert_eq!(value["payload_base64"], "/wA=");
+        assert!(value.get("payload").is_none());
+    }
+
+    #[test]
+    fn test_parquet_segment() {
+        let message = Message::new(
+            "c1".into(),
+            "plant/line1/temp".into(),
+            QoS::AtLeastOnce,
+            false,
+            Bytes::from_static(b"21.5"),
+            vec![],
+        );
+        let mut segment = Segment::new(ArchiveFormat::Parquet);
+        segment.push(Entry::new(&message, 7, "plant".into()));
+        segment.push(Entry::new(&message, 8, "plant".into()));
+        assert_eq!(segment.messages, 2);
+
+        let body = Bytes::from(segment.finish().unwrap());
+        let reader = SerializedFileReader::new(body).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        assert_eq!(
+            reader
+                .metadata()
+                .file_metadata()
+                .schema_descr()
+                .num_columns(),
+            6
+        );
+
+        let ctx = S3Sink::context(&message, 1_718_000_000_000);
+        let env = Environment::new();
+        assert_eq!(
+            env.render_str("{{ levels[0] }}/{{ year }}-{{ month }}-{{ day }}", ctx)
+                .unwrap(),
+            "plant/2024-06-10"
+        );
+    }
+}
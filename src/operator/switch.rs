@@ -0,0 +1,171 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, Utc, Weekday};
+use serde::Serialize;
+
+use crate::config::schedule::Schedule as ScheduleConfig;
+
+use super::error::OperatorError;
+
+#[derive(Clone)]
+struct Schedule {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+    config: ScheduleConfig,
+}
+
+impl Schedule {
+    fn parse(config: ScheduleConfig) -> Result<Self, OperatorError> {
+        let invalid = |what: &str, value: &str| {
+            OperatorError::InvalidSchedule(format!("invalid {}: {}", what, value))
+        };
+
+        let days = config
+            .days
+            .iter()
+            .map(|day| day.parse::<Weekday>().map_err(|_| invalid("day", day)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let start = NaiveTime::parse_from_str(&config.start, "%H:%M")
+            .map_err(|_| invalid("start", &config.start))?;
+        let end = NaiveTime::parse_from_str(&config.end, "%H:%M")
+            .map_err(|_| invalid("end", &config.end))?;
+        if start == end {
+            return Err(OperatorError::InvalidSchedule(
+                "start and end are equal".to_string(),
+            ));
+        }
+        let offset = match config.utc_offset.as_deref() {
+            Some(offset) => offset
+                .parse::<FixedOffset>()
+                .map_err(|_| invalid("utc_offset", offset))?,
+            None => FixedOffset::east_opt(0).unwrap(),
+        };
+
+        Ok(Schedule {
+            days,
+            start,
+            end,
+            offset,
+            config,
+        })
+    }
+
+    fn on_day(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.offset);
+        let time = local.time();
+        let day = local.weekday();
+
+        if self.start < self.end {
+            self.on_day(day) && self.start <= time && time < self.end
+        } else {
+            // the part after midnight belongs to the window started the day before
+            (self.on_day(day) && time >= self.start) || (self.on_day(day.pred()) && time < self.end)
+        }
+    }
+}
+
+/// administrative state of a route or chain
+#[derive(Clone)]
+pub struct Switch {
+    enabled: bool,
+    schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SwitchInfo {
+    pub enabled: bool,
+    pub schedule: Option<ScheduleConfig>,
+    // enabled and inside the schedule window
+    pub active: bool,
+}
+
+impl Default for Switch {
+    fn default() -> Self {
+        Switch {
+            enabled: true,
+            schedule: None,
+        }
+    }
+}
+
+impl Switch {
+    pub fn new(enabled: bool, schedule: Option<ScheduleConfig>) -> Result<Self, OperatorError> {
+        Ok(Switch {
+            enabled,
+            schedule: schedule.map(Schedule::parse).transpose()?,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(Utc::now())
+    }
+
+    fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        self.enabled && self.schedule.as_ref().is_none_or(|s| s.is_active(now))
+    }
+
+    pub fn info(&self) -> SwitchInfo {
+        SwitchInfo {
+            enabled: self.enabled,
+            schedule: self.schedule.as_ref().map(|s| s.config.clone()),
+            active: self.is_active(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn schedule(days: &[&str], start: &str, end: &str, offset: Option<&str>) -> Switch {
+        Switch::new(
+            true,
+            Some(ScheduleConfig {
+                days: days.iter().map(|d| d.to_string()).collect(),
+                start: start.to_string(),
+                end: end.to_string(),
+                utc_offset: offset.map(|o| o.to_string()),
+            }),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_schedule_window() {
+        // 2026-10-16 is a friday
+        let office = schedule(&["mon", "fri"], "08:00", "18:00", None);
+        assert!(office.is_active_at(at("2026-10-16T08:00:00Z")));
+        assert!(!office.is_active_at(at("2026-10-16T18:00:00Z")));
+        assert!(!office.is_active_at(at("2026-10-15T12:00:00Z")));
+
+        let night = schedule(&["fri"], "22:00", "02:00", Some("+02:00"));
+        assert!(night.is_active_at(at("2026-10-16T20:30:00Z")));
+        assert!(night.is_active_at(at("2026-10-16T23:30:00Z")));
+        assert!(!night.is_active_at(at("2026-10-17T00:30:00Z")));
+        assert!(!night.is_active_at(at("2026-10-15T23:30:00Z")));
+
+        let disabled = Switch::new(false, None).unwrap();
+        assert!(!disabled.is_active());
+        assert!(Switch::new(true, None).unwrap().is_active());
+        assert!(
+            Switch::new(
+                true,
+                Some(ScheduleConfig {
+                    days: vec!["someday".into()],
+                    start: "08:00".into(),
+                    end: "09:00".into(),
+                    utc_offset: None,
+                })
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+const MIN_PRUNE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Literal(Arc<str>),
+    // +
+    SingleWildcard,
+    // #
+    MultiWildcard,
+}
+
+/// A topic filter split into segments once, literal segments are interned and
+/// shared between all filters and trie nodes using them.
+#[derive(Debug, Default, PartialEq, Eq, Hash)]
+pub struct TopicFilter {
+    raw: Arc<str>,
+    segments: Box<[Segment]>,
+}
+
+impl TopicFilter {
+    pub fn compile(filter: &str, interner: &mut Interner) -> Arc<TopicFilter> {
+        let segments = filter
+            .split('/')
+            .map(|part| match part {
+                "+" => Segment::SingleWildcard,
+                "#" => Segment::MultiWildcard,
+                _ => Segment::Literal(interner.intern(part)),
+            })
+            .collect();
+
+        Arc::new(TopicFilter {
+            raw: interner.intern(filter),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+impl std::fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<Arc<str>>,
+    prune_at: usize,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Interner {
+            strings: HashSet::new(),
+            prune_at: MIN_PRUNE_SIZE,
+        }
+    }
+
+    pub fn intern(&mut self, s: &str) -> Arc<str> {
+        if let Some(v) = self.strings.get(s) {
+            return v.clone();
+        }
+        let v: Arc<str> = Arc::from(s);
+        self.strings.insert(v.clone());
+        v
+    }
+
+    /// drop strings no longer referenced by any filter, amortized over insertions
+    pub fn maybe_prune(&mut self) {
+        if self.strings.len() < self.prune_at {
+            return;
+        }
+        self.strings.retain(|s| Arc::strong_count(s) > 1);
+        self.prune_at = (self.strings.len() * 2).max(MIN_PRUNE_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Interner, Segment, TopicFilter};
+
+    #[test]
+    fn test_compile_and_prune() {
+        let mut interner = Interner::new();
+        let filter = TopicFilter::compile("sport/+/player1", &mut interner);
+        let other = TopicFilter::compile("sport/#", &mut interner);
+
+        assert_eq!(filter.segments().len(), 3);
+        assert_eq!(filter.segments()[1], Segment::SingleWildcard);
+        if let (Segment::Literal(a), Segment::Literal(b)) =
+            (&filter.segments()[0], &other.segments()[0])
+        {
+            assert!(Arc::ptr_eq(a, b));
+        } else {
+            panic!("literal segment expected");
+        }
+        assert_eq!(filter.as_str(), "sport/+/player1");
+
+        drop(filter);
+        drop(other);
+        interner.prune_at = 0;
+        interner.maybe_prune();
+        assert!(interner.strings.is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+//! the trace topic debug facility: the messages published on a traced topic filter record
+//! the hops they go through, from the listener to the router, chains, sinks and matcher, in
+//! a ring buffer read through `/api/v1/trace`
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, RwLock};
+
+use serde::Serialize;
+
+use crate::mqtt::utils::sub_topic_valid;
+use crate::utils::time::now_milliseconds;
+
+use super::utils::topic_match;
+
+// messages kept, the oldest trace goes first
+const CAPACITY: usize = 1000;
+// hops kept per message, a message looping through republishes stops recording
+const MAX_HOPS: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceFilter {
+    pub filter: String,
+    pub created_at: u64,
+    // milliseconds since the epoch, traced until removed when not set
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Hop {
+    // listener, router, chain, matcher
+    pub stage: &'static str,
+    pub detail: String,
+    // milliseconds since the epoch
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageTrace {
+    pub id: u64,
+    pub client_id: String,
+    pub topic: String,
+    pub hops: Vec<Hop>,
+}
+
+// checked by every publish, the filters are only read while tracing is on
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static FILTERS: LazyLock<RwLock<Vec<TraceFilter>>> = LazyLock::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static TRACES: LazyLock<Mutex<VecDeque<MessageTrace>>> = LazyLock::new(Default::default);
+
+fn traced(topic: &str) -> bool {
+    let now = now_milliseconds();
+    let filters = FILTERS.read().unwrap();
+    filters
+        .iter()
+        .any(|f| f.expires_at.is_none_or(|at| at > now) && topic_match(&f.filter, topic))
+}
+
+/// called for every message a client publishes, the id to carry along when it is traced
+pub fn start(client_id: &str, topic: &str) -> Option<u64> {
+    if !ACTIVE.load(Ordering::Relaxed) || !traced(topic) {
+        return None;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let trace = MessageTrace {
+        id,
+        client_id: client_id.to_string(),
+        topic: topic.to_string(),
+        hops: vec![Hop {
+            stage: "listener",
+            detail: format!("published by {}", client_id),
+            at: now_milliseconds(),
+        }],
+    };
+    let mut traces = TRACES.lock().unwrap();
+    if traces.len() == CAPACITY {
+        traces.pop_front();
+    }
+    traces.push_back(trace);
+    Some(id)
+}
+
+/// add a hop to the trace of a message, nothing when it is not traced
+pub fn hop(id: Option<u64>, stage: &'static str, detail: impl FnOnce() -> String) {
+    let Some(id) = id else {
+        return;
+    };
+    let mut traces = TRACES.lock().unwrap();
+    // ids are handed out in order, a message still traced after a clear is found anyway
+    let Some(trace) = traces.iter_mut().rev().find(|t| t.id == id) else {
+        return;
+    };
+    if trace.hops.len() < MAX_HOPS {
+        trace.hops.push(Hop {
+            stage,
+            detail: detail(),
+            at: now_milliseconds(),
+        });
+    }
+}
+
+pub fn filters() -> Vec<TraceFilter> {
+    let now = now_milliseconds();
+    let mut filters = FILTERS.write().unwrap();
+    filters.retain(|f| f.expires_at.is_none_or(|at| at > now));
+    ACTIVE.store(!filters.is_empty(), Ordering::Relaxed);
+    filters.clone()
+}
+
+/// trace the messages published on a topic filter, for `ttl_secs` when set. A filter traced
+/// already gets the new expiry
+pub fn add(filter: String, ttl_secs: Option<u64>) -> Result<TraceFilter, String> {
+    if !sub_topic_valid(&filter) {
+        return Err(filter);
+    }
+    let now = now_milliseconds();
+    let filter = TraceFilter {
+        filter,
+        created_at: now,
+        expires_at: ttl_secs.map(|ttl| now + ttl * 1000),
+    };
+    let mut filters = FILTERS.write().unwrap();
+    filters.retain(|f| f.filter != filter.filter);
+    filters.push(filter.clone());
+    ACTIVE.store(true, Ordering::Relaxed);
+    tracing::info!("tracing messages on {}", filter.filter);
+    Ok(filter)
+}
+
+/// stop tracing a topic filter, or every filter when not set, the recorded traces are kept
+pub fn remove(filter: Option<&str>) -> Vec<TraceFilter> {
+    let mut filters = FILTERS.write().unwrap();
+    let (removed, kept) = filters
+        .drain(..)
+        .partition(|f| filter.is_none_or(|filter| f.filter == filter));
+    *filters = kept;
+    ACTIVE.store(!filters.is_empty(), Ordering::Relaxed);
+    removed
+}
+
+/// the last `limit` traces, oldest first
+pub fn messages(limit: Option<usize>) -> Vec<MessageTrace> {
+    let traces = TRACES.lock().unwrap();
+    let skip = traces.len().saturating_sub(limit.unwrap_or(CAPACITY));
+    traces.iter().skip(skip).cloned().collect()
+}
+
+pub fn clear() {
+    TRACES.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        assert!(add("trace/#/x".to_string(), None).is_err());
+        add("trace/+/temp".to_string(), Some(60)).unwrap();
+
+        assert_eq!(start("c1", "trace/a/humidity"), None);
+        let id = start("c1", "trace/a/temp");
+        assert!(id.is_some());
+        hop(id, "router", || "no route".to_string());
+        hop(None, "router", || unreachable!());
+
+        let trace = messages(None)
+            .into_iter()
+            .find(|t| Some(t.id) == id)
+            .unwrap();
+        let stages: Vec<&str> = trace.hops.iter().map(|h| h.stage).collect();
+        assert_eq!(stages, ["listener", "router"]);
+
+        let removed = remove(Some("trace/+/temp"));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(start("c1", "trace/a/temp"), None);
+    }
+}
@@ -1,19 +1,33 @@
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::sync::Arc;
 
+use super::topic_filter::{Segment, TopicFilter};
+
+/// Nodes are shared between the trie and its snapshots, a change copies the nodes on the
+/// path to the change only, the snapshots keep seeing the nodes they were taken with.
 #[derive(Debug, Clone)]
 struct TrieNode<T> {
     // +
-    pub single_wildcard_child: Option<Box<TrieNode<T>>>,
+    pub single_wildcard_child: Option<Arc<TrieNode<T>>>,
     // #
     pub multi_wildcard_matches: Vec<T>,
 
     // a, b, sensors
-    pub literal_children: HashMap<String, TrieNode<T>>,
+    pub literal_children: HashMap<Arc<str>, Arc<TrieNode<T>>>,
     pub exact_matches: Vec<T>,
 }
 
 impl<T> TrieNode<T> {
+    fn new() -> Self {
+        TrieNode {
+            single_wildcard_child: None,
+            multi_wildcard_matches: Vec::new(),
+            literal_children: HashMap::new(),
+            exact_matches: Vec::new(),
+        }
+    }
+
     fn is_empty(&self) -> bool {
         self.exact_matches.is_empty()
             && self.multi_wildcard_matches.is_empty()
@@ -26,9 +40,17 @@ pub trait ClientId {
     fn client_id(&self) -> &str;
 }
 
-#[derive(Debug)]
+impl<T: ClientId> ClientId for Arc<T> {
+    fn client_id(&self) -> &str {
+        (**self).client_id()
+    }
+}
+
+/// cloning is cheap, the clone is an immutable snapshot which can be matched against
+/// on other tasks while the original keeps changing
+#[derive(Debug, Clone)]
 pub struct TopicTrie<T> {
-    root: TrieNode<T>,
+    root: Arc<TrieNode<T>>,
 }
 
 impl<T> TopicTrie<T>
@@ -37,51 +59,38 @@ where
 {
     pub fn new() -> Self {
         TopicTrie {
-            root: TrieNode {
-                single_wildcard_child: None,
-                multi_wildcard_matches: Vec::new(),
-                literal_children: HashMap::new(),
-                exact_matches: Vec::new(),
-            },
+            root: Arc::new(TrieNode::new()),
         }
     }
 
-    fn default() -> Box<TrieNode<T>> {
-        Box::new(TrieNode {
-            single_wildcard_child: None,
-            multi_wildcard_matches: Vec::new(),
-            literal_children: HashMap::new(),
-            exact_matches: Vec::new(),
-        })
-    }
-
-    pub fn insert(&mut self, topic: &str, value: T) {
-        let mut current_node = &mut self.root;
-        let parts: Vec<&str> = topic.split('/').collect();
-        let last_index = parts.len().saturating_sub(1);
-
-        for (i, part) in parts.iter().enumerate() {
-            let part_string = part.to_string();
+    pub fn insert(&mut self, filter: &TopicFilter, value: T) {
+        let mut current_node = Arc::make_mut(&mut self.root);
+        let segments = filter.segments();
+        let last_index = segments.len().saturating_sub(1);
 
-            if part_string == "#" {
-                if i == last_index {
-                    current_node.multi_wildcard_matches.push(value.clone());
+        for (i, segment) in segments.iter().enumerate() {
+            match segment {
+                Segment::MultiWildcard => {
+                    if i == last_index {
+                        current_node.multi_wildcard_matches.push(value.clone());
+                    }
+                    return;
+                }
+                Segment::SingleWildcard => {
+                    current_node = Arc::make_mut(
+                        current_node
+                            .single_wildcard_child
+                            .get_or_insert_with(|| Arc::new(TrieNode::new())),
+                    );
+                }
+                Segment::Literal(part) => {
+                    current_node = Arc::make_mut(
+                        current_node
+                            .literal_children
+                            .entry(part.clone())
+                            .or_insert_with(|| Arc::new(TrieNode::new())),
+                    );
                 }
-                return;
-            } else if part_string == "+" {
-                current_node = current_node
-                    .single_wildcard_child
-                    .get_or_insert_with(Self::default);
-            } else {
-                current_node = current_node
-                    .literal_children
-                    .entry(part_string)
-                    .or_insert_with(|| TrieNode {
-                        single_wildcard_child: None,
-                        multi_wildcard_matches: Vec::new(),
-                        literal_children: HashMap::new(),
-                        exact_matches: Vec::new(),
-                    });
             }
         }
 
@@ -91,7 +100,14 @@ where
     pub fn find_matches(&self, topic: &str) -> Vec<&T> {
         let mut results = HashSet::new();
         let topic_parts: Vec<&str> = topic.split('/').collect();
-        self.recursive_match(&self.root, &topic_parts, &mut results);
+        if topic.starts_with('$') {
+            // the filters starting with a wildcard leave out $SYS, $events..., [MQTT-4.7.2-1]
+            if let Some(child) = self.root.literal_children.get(topic_parts[0]) {
+                self.recursive_match(child, &topic_parts[1..], &mut results);
+            }
+        } else {
+            self.recursive_match(&self.root, &topic_parts, &mut results);
+        }
         results.into_iter().collect()
     }
 
@@ -124,26 +140,29 @@ where
         }
     }
 
-    pub fn remove(&mut self, topic: &str, value: &T) {
-        let parts: Vec<&str> = topic.split('/').collect();
-        Self::recursive_remove(&mut self.root, &parts, value);
+    pub fn remove(&mut self, filter: &TopicFilter, value: &T) {
+        Self::recursive_remove(&mut self.root, filter.segments(), value);
     }
 
-    fn recursive_remove(node: &mut TrieNode<T>, topic_parts: &[&str], value: &T) -> bool {
-        if let Some((current_part, remaining_parts)) = topic_parts.split_first() {
-            if *current_part == "#" {
-                node.multi_wildcard_matches.retain(|v| v != value);
-            } else if *current_part == "+" {
-                if let Some(mut child) = node.single_wildcard_child.take() {
-                    if !Self::recursive_remove(&mut child, remaining_parts, value) {
+    fn recursive_remove(node: &mut Arc<TrieNode<T>>, segments: &[Segment], value: &T) -> bool {
+        let node = Arc::make_mut(node);
+        if let Some((current_segment, remaining_segments)) = segments.split_first() {
+            match current_segment {
+                Segment::MultiWildcard => {
+                    node.multi_wildcard_matches.retain(|v| v != value);
+                }
+                Segment::SingleWildcard => {
+                    if let Some(mut child) = node.single_wildcard_child.take()
+                        && !Self::recursive_remove(&mut child, remaining_segments, value)
+                    {
                         node.single_wildcard_child = Some(child);
                     }
                 }
-            } else {
-                let part_string = current_part.to_string();
-                if let Some(mut child) = node.literal_children.remove(&part_string) {
-                    if !Self::recursive_remove(&mut child, remaining_parts, value) {
-                        node.literal_children.insert(part_string, child);
+                Segment::Literal(part) => {
+                    if let Some(child) = node.literal_children.get_mut(part)
+                        && Self::recursive_remove(child, remaining_segments, value)
+                    {
+                        node.literal_children.remove(part);
                     }
                 }
             }
@@ -158,7 +177,12 @@ where
         Self::recursive_remove_client(&mut self.root, client_id);
     }
 
-    fn recursive_remove_client(node: &mut TrieNode<T>, client_id: &str) -> bool {
+    fn recursive_remove_client(node: &mut Arc<TrieNode<T>>, client_id: &str) -> bool {
+        // the branches without the client stay shared with the snapshots
+        if !Self::has_client(node, client_id) {
+            return node.is_empty();
+        }
+        let node = Arc::make_mut(node);
         node.exact_matches.retain(|v| v.client_id() != client_id);
         node.multi_wildcard_matches
             .retain(|v| v.client_id() != client_id);
@@ -174,10 +198,25 @@ where
 
         node.is_empty()
     }
+
+    fn has_client(node: &TrieNode<T>, client_id: &str) -> bool {
+        node.exact_matches
+            .iter()
+            .chain(&node.multi_wildcard_matches)
+            .any(|v| v.client_id() == client_id)
+            || node
+                .literal_children
+                .values()
+                .chain(&node.single_wildcard_child)
+                .any(|child| Self::has_client(child, client_id))
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use super::super::topic_filter::{Interner, TopicFilter};
     use super::{ClientId, TopicTrie};
 
     #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -193,27 +232,28 @@ mod tests {
 
     #[test]
     fn test_insert_and_match() {
+        let mut interner = Interner::new();
         let mut trie = TopicTrie::new();
         trie.insert(
-            "a/b/c",
+            &TopicFilter::compile("a/b/c", &mut interner),
             ClientInfo {
                 id: "client1".to_string(),
             },
         );
         trie.insert(
-            "a/+/c",
+            &TopicFilter::compile("a/+/c", &mut interner),
             ClientInfo {
                 id: "client2".to_string(),
             },
         );
         trie.insert(
-            "a/b/#",
+            &TopicFilter::compile("a/b/#", &mut interner),
             ClientInfo {
                 id: "client3".to_string(),
             },
         );
         trie.insert(
-            "#",
+            &TopicFilter::compile("#", &mut interner),
             ClientInfo {
                 id: "client4".to_string(),
             },
@@ -242,19 +282,33 @@ mod tests {
             .collect();
         matches.sort();
         assert_eq!(matches, vec!["client3", "client4"]);
+
+        trie.insert(
+            &TopicFilter::compile("$events/#", &mut interner),
+            ClientInfo {
+                id: "client5".to_string(),
+            },
+        );
+        let matches: Vec<&str> = trie
+            .find_matches("$events/client/connected")
+            .iter()
+            .map(|c| c.client_id())
+            .collect();
+        assert_eq!(matches, vec!["client5"]);
     }
 
     #[test]
     fn test_remove_exact() {
+        let mut interner = Interner::new();
         let mut trie = TopicTrie::new();
         trie.insert(
-            "a/b/c",
+            &TopicFilter::compile("a/b/c", &mut interner),
             ClientInfo {
                 id: "client1".to_string(),
             },
         );
         trie.insert(
-            "a/b/c",
+            &TopicFilter::compile("a/b/c", &mut interner),
             ClientInfo {
                 id: "client2".to_string(),
             },
@@ -264,7 +318,7 @@ mod tests {
         assert_eq!(matches.len(), 2);
 
         trie.remove(
-            "a/b/c",
+            &TopicFilter::compile("a/b/c", &mut interner),
             &ClientInfo {
                 id: "client1".to_string(),
             },
@@ -280,15 +334,16 @@ mod tests {
 
     #[test]
     fn test_remove_wildcard() {
+        let mut interner = Interner::new();
         let mut trie = TopicTrie::new();
         trie.insert(
-            "a/#",
+            &TopicFilter::compile("a/#", &mut interner),
             ClientInfo {
                 id: "client1".to_string(),
             },
         );
         trie.insert(
-            "a/b",
+            &TopicFilter::compile("a/b", &mut interner),
             ClientInfo {
                 id: "client2".to_string(),
             },
@@ -303,7 +358,7 @@ mod tests {
         assert_eq!(matches, vec!["client1", "client2"]);
 
         trie.remove(
-            "a/#",
+            &TopicFilter::compile("a/#", &mut interner),
             &ClientInfo {
                 id: "client1".to_string(),
             },
@@ -319,16 +374,52 @@ mod tests {
 
     #[test]
     fn test_remove_and_prune() {
+        let mut interner = Interner::new();
         let mut trie = TopicTrie::<ClientInfo>::new();
         let client = ClientInfo {
             id: "client1".to_string(),
         };
-        trie.insert("a/b/c", client.clone());
+        trie.insert(
+            &TopicFilter::compile("a/b/c", &mut interner),
+            client.clone(),
+        );
 
         assert!(!trie.find_matches("a/b/c").is_empty());
 
-        trie.remove("a/b/c", &client);
+        trie.remove(&TopicFilter::compile("a/b/c", &mut interner), &client);
 
         assert!(trie.find_matches("a/b/c").is_empty());
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mut interner = Interner::new();
+        let mut trie = TopicTrie::new();
+        let client = |id: &str| ClientInfo { id: id.to_string() };
+        trie.insert(&TopicFilter::compile("a/+/c", &mut interner), client("c1"));
+        trie.insert(&TopicFilter::compile("b/#", &mut interner), client("c2"));
+
+        let snapshot = trie.clone();
+        trie.insert(&TopicFilter::compile("a/b/c", &mut interner), client("c3"));
+        trie.remove(&TopicFilter::compile("a/+/c", &mut interner), &client("c1"));
+        trie.remove_client("c2");
+
+        let ids = |trie: &TopicTrie<ClientInfo>, topic: &str| {
+            let mut ids: Vec<String> = trie
+                .find_matches(topic)
+                .iter()
+                .map(|c| c.id.clone())
+                .collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&snapshot, "a/b/c"), ["c1"]);
+        assert_eq!(ids(&snapshot, "b/x"), ["c2"]);
+        assert_eq!(ids(&trie, "a/b/c"), ["c3"]);
+        assert!(ids(&trie, "b/x").is_empty());
+        // the branch nobody changed is still shared
+        let mut other = trie.clone();
+        other.remove_client("nobody");
+        assert!(Arc::ptr_eq(&trie.root, &other.root));
+    }
 }
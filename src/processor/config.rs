@@ -2,17 +2,57 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use minijinja::Environment;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use wasmtime::Engine;
 
 use super::{
     Processor,
-    processors::{anomaly_detector, filter, json_transform, logger, republish, webhook},
+    processors::{
+        aggregate, anomaly_detector, compress, convert, enrich, filter, json_transform, logger,
+        rate_limit, republish, webhook,
+    },
     wasm::WasmProcessor,
 };
 
-#[derive(Debug, Deserialize, Clone)]
+/// what one call into a WASM processor may use, unset fields are unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct WasmLimits {
+    // linear memory of an instance, in bytes
+    pub max_memory_bytes: Option<usize>,
+    // fuel units, about one per WASM instruction
+    pub max_fuel: Option<u64>,
+    // wall time, checked every 10 milliseconds
+    pub timeout_ms: Option<u64>,
+}
+
+/// WASI capabilities granted to a WASM processor, stdio and args by default
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct WasiCapabilities {
+    pub stdio: bool,
+    pub args: bool,
+    pub env: bool,
+    // outgoing sockets and name lookups
+    pub network: bool,
+    // "host_dir:guest_dir", with ":ro" appended for read only access
+    pub dirs: Vec<String>,
+}
+
+impl Default for WasiCapabilities {
+    fn default() -> Self {
+        WasiCapabilities {
+            stdio: true,
+            args: true,
+            env: false,
+            network: false,
+            dirs: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum AnomalyStrategy {
     #[serde(rename = "threshold")]
@@ -26,7 +66,139 @@ pub enum AnomalyStrategy {
     Ewma { alpha: f64, deviation_factor: f64 },
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// what an aggregate processor reports for a window
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFunction {
+    Count,
+    Min,
+    Max,
+    Avg,
+    Sum,
+    Last,
+}
+
+/// where an enrich processor looks its keys up
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LookupSource {
+    // a CSV file keyed by its first column, the header row names the fields, or a JSON object
+    File {
+        path: String,
+    },
+    // GET of the url with {{ key }} rendered in, 404 is a miss
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        timeout_ms: Option<u64>,
+    },
+    // GET of key_prefix + key, redis://[:password@]host[:port][/db]
+    Redis {
+        url: String,
+        key_prefix: Option<String>,
+        timeout_ms: Option<u64>,
+    },
+}
+
+/// where an enrich processor puts what it looked up
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrichTarget {
+    #[default]
+    Payload,
+    UserProperties,
+}
+
+/// a payload encoding a convert processor reads or writes
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    Json,
+    Cbor,
+    Msgpack,
+}
+
+impl PayloadFormat {
+    /// the MQTT 5 content type of the format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "application/json",
+            PayloadFormat::Cbor => "application/cbor",
+            PayloadFormat::Msgpack => "application/msgpack",
+        }
+    }
+
+    /// the format a content type names, parameters like charset are ignored
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+        match mime.as_str() {
+            "application/json" | "text/json" => Some(PayloadFormat::Json),
+            "application/cbor" => Some(PayloadFormat::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(PayloadFormat::Msgpack)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PayloadFormat::Json => "json",
+            PayloadFormat::Cbor => "cbor",
+            PayloadFormat::Msgpack => "msgpack",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// how the compress processor packs a payload
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadCompression {
+    Gzip,
+    Zstd,
+    // encryption only
+    None,
+}
+
+impl PayloadCompression {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadCompression::Gzip => "application/gzip",
+            PayloadCompression::Zstd => "application/zstd",
+            PayloadCompression::None => "application/octet-stream",
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PayloadCompression::Gzip => "gzip",
+            PayloadCompression::Zstd => "zstd",
+            PayloadCompression::None => "none",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for PayloadCompression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(PayloadCompression::Gzip),
+            "zstd" => Ok(PayloadCompression::Zstd),
+            "none" => Ok(PayloadCompression::None),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum ProcessorConfig {
     #[serde(rename = "logger")]
@@ -60,8 +232,79 @@ pub enum ProcessorConfig {
         series_id: String,
         strategy: AnomalyStrategy,
     },
+    #[serde(rename = "aggregate")]
+    Aggregate {
+        value_selector: String,
+        // template of the group a message belongs to, the topic when unset
+        key: Option<String>,
+        window_secs: u64,
+        // sliding windows advance by this much, tumbling windows when unset
+        slide_secs: Option<u64>,
+        functions: Option<Vec<AggregateFunction>>,
+        // template of the topic the aggregates are published to
+        topic: String,
+    },
+    #[serde(rename = "rate_limit")]
+    RateLimit {
+        // template of the stream a message belongs to, the topic when unset
+        key: Option<String>,
+        // messages of a stream passed per second, fractions allow one every few seconds
+        max_per_sec: Option<f64>,
+        // messages passed at once after a quiet period, max_per_sec and at least 1 when unset
+        burst: Option<f64>,
+        // only every Nth message of a stream is considered
+        sample_every: Option<u64>,
+    },
+    #[serde(rename = "enrich")]
+    Enrich {
+        // template of the lookup key, a topic segment or a payload field
+        key: String,
+        source: LookupSource,
+        #[serde(default)]
+        target: EnrichTarget,
+        // the name the result is put under, the members of an object result are merged when unset
+        field: Option<String>,
+        // how long http and redis results are cached, misses included
+        cache_ttl_secs: Option<u64>,
+        // how many http and redis results are cached, the least recently used goes first
+        cache_size: Option<usize>,
+        // pass messages without a result, true when unset
+        on_miss_pass: Option<bool>,
+    },
+    #[serde(rename = "convert")]
+    Convert {
+        // the format of a payload whose content type names none
+        from: PayloadFormat,
+        to: PayloadFormat,
+    },
+    #[serde(rename = "compress")]
+    Compress {
+        algorithm: PayloadCompression,
+        // gzip 0 to 9, zstd 1 to 22, the default of the algorithm when unset
+        level: Option<i32>,
+        // base64 of a 16 or 32 byte key, the compressed payload is AES-GCM encrypted when set,
+        // left out of what the API lists
+        #[serde(skip_serializing)]
+        key: Option<String>,
+    },
+    #[serde(rename = "decompress")]
+    Decompress {
+        // for payloads without the user properties of a compress processor, none when unset
+        algorithm: Option<PayloadCompression>,
+        #[serde(skip_serializing)]
+        key: Option<String>,
+        // bytes a payload may expand to, 16 MiB when unset
+        max_size: Option<usize>,
+    },
     #[serde(rename = "wasm")]
-    Wasm { path: String, cfg: String },
+    Wasm {
+        path: String,
+        cfg: String,
+        #[serde(default)]
+        limits: WasmLimits,
+        #[serde(default)]
+        wasi: WasiCapabilities,
+    },
     #[serde(other)]
     Other,
 }
@@ -97,11 +340,44 @@ impl ProcessorConfig {
                 anomaly_detector::AnomalyDetectorProcessor::new_with_id(id, self.clone(), env)
                     .map_err(|e| e.to_string())
             }
-            ProcessorConfig::Wasm { path, cfg } => {
-                WasmProcessor::new(engine, path.clone(), id, cfg.to_string())
-                    .await
+            ProcessorConfig::Aggregate { .. } => {
+                aggregate::AggregateProcessor::new_with_id(id, self.clone(), env)
+                    .map_err(|e| e.to_string())
+            }
+            ProcessorConfig::RateLimit { .. } => {
+                rate_limit::RateLimitProcessor::new_with_id(id, self.clone(), env)
+                    .map_err(|e| e.to_string())
+            }
+            ProcessorConfig::Enrich { .. } => {
+                enrich::EnrichProcessor::new_with_id(id, self.clone(), env)
+                    .map_err(|e| e.to_string())
+            }
+            ProcessorConfig::Convert { .. } => {
+                convert::ConvertProcessor::new_with_id(id, self.clone()).map_err(|e| e.to_string())
+            }
+            ProcessorConfig::Compress { .. } => {
+                compress::CompressProcessor::new_with_id(id, self.clone())
+                    .map_err(|e| e.to_string())
+            }
+            ProcessorConfig::Decompress { .. } => {
+                compress::DecompressProcessor::new_with_id(id, self.clone())
                     .map_err(|e| e.to_string())
             }
+            ProcessorConfig::Wasm {
+                path,
+                cfg,
+                limits,
+                wasi,
+            } => WasmProcessor::new(
+                engine,
+                path.clone(),
+                id,
+                cfg.to_string(),
+                limits.clone(),
+                wasi.clone(),
+            )
+            .await
+            .map_err(|e| e.to_string()),
             ProcessorConfig::Other => Err("Unsupported processor type".to_string()),
         }
     }
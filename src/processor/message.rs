@@ -132,4 +132,59 @@ impl Message {
             .with_subscription_identifier(subscription_identifier);
         self
     }
+
+    /// set the metadata of the given keys as user properties, replacing the properties of the
+    /// same key, so they reach the subscribers
+    pub fn metadata_to_properties(&mut self, keys: &[String]) {
+        for key in keys {
+            if let Some(value) = self.metadata.get(key) {
+                let value = value.to_string();
+                self.user_properties.retain(|p| &p.key != key);
+                self.user_properties.push(PropertyUser {
+                    key: key.clone(),
+                    value,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_to_properties() {
+        let mut message = Message::new(
+            "c".to_string(),
+            "t".to_string(),
+            QoS::AtMostOnce,
+            false,
+            Bytes::new(),
+            vec![PropertyUser {
+                key: "site".to_string(),
+                value: "old".to_string(),
+            }],
+        );
+        message.metadata.insert(
+            "site".to_string(),
+            MetadataValue::String("lyon".to_string()),
+        );
+        message.metadata.insert(
+            "asset".to_string(),
+            MetadataValue::Json(serde_json::json!({"line": 3})),
+        );
+        message
+            .metadata
+            .insert("secret".to_string(), MetadataValue::Int(1));
+
+        let keys = ["site".to_string(), "asset".to_string(), "zone".to_string()];
+        message.metadata_to_properties(&keys);
+        let properties: Vec<(&str, &str)> = message
+            .user_properties
+            .iter()
+            .map(|p| (p.key.as_str(), p.value.as_str()))
+            .collect();
+        assert_eq!(properties, [("site", "lyon"), ("asset", r#"{"line":3}"#)]);
+    }
 }
@@ -5,11 +5,15 @@ pub mod processors;
 mod wasm;
 
 use std::any::Any;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use dyn_clone::DynClone;
 use uuid::Uuid;
 
+/// the epoch of the WASM engine advances this often, the granularity of `timeout_ms`
+pub const WASM_EPOCH_TICK: Duration = Duration::from_millis(10);
+
 #[async_trait]
 pub trait Processor: Send + Sync + DynClone {
     fn id(&self) -> Uuid;
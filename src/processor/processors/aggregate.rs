@@ -0,0 +1,322 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use minijinja::{Environment, Value, context};
+use serde_json::{Map, Value as JsonValue, json};
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use crate::processor::message::{MetadataKey, MetadataPayloadFormat, MetadataValue};
+use crate::utils::time::now_milliseconds;
+
+use super::super::{
+    Processor,
+    config::{AggregateFunction, ProcessorConfig},
+    error::ProcessorError,
+    message::Message,
+};
+
+const ALL_FUNCTIONS: [AggregateFunction; 6] = [
+    AggregateFunction::Count,
+    AggregateFunction::Min,
+    AggregateFunction::Max,
+    AggregateFunction::Avg,
+    AggregateFunction::Sum,
+    AggregateFunction::Last,
+];
+
+#[derive(Debug)]
+struct WindowState {
+    // (received at in milliseconds, value), oldest first
+    samples: VecDeque<(u64, f64)>,
+    window_end: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct Aggregate {
+    window_start: u64,
+    window_end: u64,
+    count: usize,
+    min: f64,
+    max: f64,
+    sum: f64,
+    last: f64,
+}
+
+#[derive(Clone)]
+pub struct AggregateProcessor {
+    id: Uuid,
+    env: Arc<Environment<'static>>,
+    value_selector: String,
+    key: Option<String>,
+    window: u64,
+    slide: u64,
+    functions: Vec<AggregateFunction>,
+    topic_template: String,
+    state: Arc<DashMap<String, WindowState>>,
+}
+
+impl AggregateProcessor {
+    pub fn new_with_id(
+        id: Uuid,
+        config: ProcessorConfig,
+        env: Arc<Environment<'static>>,
+    ) -> Result<Box<dyn Processor>, ProcessorError> {
+        if let ProcessorConfig::Aggregate {
+            value_selector,
+            key,
+            window_secs,
+            slide_secs,
+            functions,
+            topic,
+        } = config
+        {
+            let slide_secs = slide_secs.unwrap_or(window_secs);
+            if window_secs == 0 || slide_secs == 0 || slide_secs > window_secs {
+                return Err(ProcessorError::InvalidConfiguration(
+                    "window_secs and slide_secs must be positive, slide_secs at most window_secs"
+                        .to_string(),
+                ));
+            }
+            Ok(Box::new(AggregateProcessor {
+                id,
+                env,
+                value_selector,
+                key,
+                window: window_secs * 1000,
+                slide: slide_secs * 1000,
+                functions: functions.unwrap_or_else(|| ALL_FUNCTIONS.to_vec()),
+                topic_template: topic,
+                state: Arc::new(DashMap::new()),
+            }))
+        } else {
+            Err(ProcessorError::InvalidConfiguration(
+                "Invalid configuration for AggregateProcessor".to_string(),
+            ))
+        }
+    }
+
+    /// adds a value of `key` received at `now`, the aggregate of the window it closed if any
+    fn fold(&self, key: &str, now: u64, value: f64) -> Option<Aggregate> {
+        // windows end on multiples of the slide, the same boundaries for every key
+        let next_end = (now / self.slide + 1) * self.slide;
+        let mut state = self
+            .state
+            .entry(key.to_string())
+            .or_insert_with(|| WindowState {
+                samples: VecDeque::new(),
+                window_end: next_end,
+            });
+
+        let mut aggregate = None;
+        if now >= state.window_end {
+            let window_start = state.window_end.saturating_sub(self.window);
+            let window_end = state.window_end;
+            let mut values = state
+                .samples
+                .iter()
+                .filter(|(at, _)| *at >= window_start && *at < window_end)
+                .map(|(_, v)| *v)
+                .peekable();
+            if let Some(first) = values.peek().copied() {
+                let mut a = Aggregate {
+                    window_start,
+                    window_end,
+                    count: 0,
+                    min: first,
+                    max: first,
+                    sum: 0.0,
+                    last: first,
+                };
+                for v in values {
+                    a.count += 1;
+                    a.min = a.min.min(v);
+                    a.max = a.max.max(v);
+                    a.sum += v;
+                    a.last = v;
+                }
+                aggregate = Some(a);
+            }
+
+            // windows without a message in between are skipped
+            state.window_end = next_end;
+            let keep_from = next_end.saturating_sub(self.window);
+            while state.samples.front().is_some_and(|(at, _)| *at < keep_from) {
+                state.samples.pop_front();
+            }
+        }
+        state.samples.push_back((now, value));
+        aggregate
+    }
+
+    fn to_json(&self, key: &str, aggregate: &Aggregate) -> JsonValue {
+        let mut out = Map::new();
+        out.insert("key".to_string(), json!(key));
+        out.insert("window_start".to_string(), json!(aggregate.window_start));
+        out.insert("window_end".to_string(), json!(aggregate.window_end));
+        for function in &self.functions {
+            let (name, value) = match function {
+                AggregateFunction::Count => ("count", json!(aggregate.count)),
+                AggregateFunction::Min => ("min", json!(aggregate.min)),
+                AggregateFunction::Max => ("max", json!(aggregate.max)),
+                AggregateFunction::Avg => ("avg", json!(aggregate.sum / aggregate.count as f64)),
+                AggregateFunction::Sum => ("sum", json!(aggregate.sum)),
+                AggregateFunction::Last => ("last", json!(aggregate.last)),
+            };
+            out.insert(name.to_string(), value);
+        }
+        JsonValue::Object(out)
+    }
+}
+
+#[async_trait]
+impl Processor for AggregateProcessor {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    /// the message is consumed, the aggregate of the window it closed goes down the chain instead
+    #[instrument(skip(self, message), fields(id = %self.id))]
+    async fn on_message(&self, mut message: Message) -> Result<Option<Message>, ProcessorError> {
+        let payload_json: Value = match serde_json::from_slice(&message.payload) {
+            Ok(v) => v,
+            Err(e) => {
+                debug!(error = %e, "Failed to parse payload as JSON, skipping aggregation.");
+                return Ok(None);
+            }
+        };
+
+        let ctx = context! {
+            topic => message.topic.clone(),
+            client_id => message.client_id.clone(),
+            payload => payload_json,
+            metadata => message.metadata.clone(),
+        };
+
+        let key = match &self.key {
+            Some(template) => match self.env.render_str(template, ctx.clone()) {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!(error = %e, "Failed to render aggregate key template.");
+                    return Err(ProcessorError::TemplateError(e.to_string()));
+                }
+            },
+            None => message.topic.clone(),
+        };
+
+        let value_template = format!("{{{{ {} }}}}", self.value_selector);
+        let value = match self.env.render_str(&value_template, ctx) {
+            Ok(s) => match s.parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => {
+                    debug!(selector = %self.value_selector, value = %s, "Selected value is not a valid f64.");
+                    return Ok(None);
+                }
+            },
+            Err(e) => return Err(ProcessorError::TemplateError(e.to_string())),
+        };
+
+        let Some(aggregate) = self.fold(&key, now_milliseconds(), value) else {
+            return Ok(None);
+        };
+        let aggregate = self.to_json(&key, &aggregate);
+
+        let ctx = context! {
+            topic => message.topic.clone(),
+            client_id => message.client_id.clone(),
+            key => key,
+            payload => aggregate.clone(),
+        };
+        message.topic = self
+            .env
+            .render_str(&self.topic_template, ctx)
+            .map_err(|e| {
+                warn!("Failed to render aggregate topic template: {}", e);
+                ProcessorError::TemplateError(e.to_string())
+            })?;
+        message.payload = Bytes::from(aggregate.to_string());
+        message.retain = false;
+        message.metadata.insert(
+            MetadataKey::ParsedPayloadJson.as_str().to_string(),
+            MetadataValue::Json(aggregate),
+        );
+        message.metadata.insert(
+            MetadataKey::PayloadFormat.as_str().to_string(),
+            MetadataValue::String(MetadataPayloadFormat::Json.as_str().to_string()),
+        );
+
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processor(window_secs: u64, slide_secs: Option<u64>) -> AggregateProcessor {
+        let config = ProcessorConfig::Aggregate {
+            value_selector: "payload.v".to_string(),
+            key: None,
+            window_secs,
+            slide_secs,
+            functions: Some(vec![AggregateFunction::Count, AggregateFunction::Avg]),
+            topic: "{{ topic }}/agg".to_string(),
+        };
+        let processor =
+            AggregateProcessor::new_with_id(Uuid::new_v4(), config, Arc::new(Environment::new()))
+                .unwrap();
+        processor
+            .as_any()
+            .downcast_ref::<AggregateProcessor>()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_fold() {
+        // tumbling, [10000, 20000) closes with the first value at or after 20000
+        let p = processor(10, None);
+        assert_eq!(p.fold("a", 10_500, 1.0), None);
+        assert_eq!(p.fold("a", 15_000, 3.0), None);
+        assert_eq!(p.fold("b", 19_999, 7.0), None);
+        let a = p.fold("a", 20_000, 5.0).unwrap();
+        assert_eq!((a.window_start, a.window_end, a.count), (10_000, 20_000, 2));
+        assert_eq!((a.min, a.max, a.sum, a.last), (1.0, 3.0, 4.0, 3.0));
+        assert_eq!(
+            p.to_json("a", &a),
+            json!({"key": "a", "window_start": 10_000, "window_end": 20_000, "count": 2, "avg": 2.0})
+        );
+        let a = p.fold("a", 45_000, 6.0).unwrap();
+        assert_eq!((a.window_start, a.count, a.last), (20_000, 1, 5.0));
+
+        // sliding, 10 second windows every 5 seconds
+        let p = processor(10, Some(5));
+        assert_eq!(p.fold("a", 1_000, 1.0), None);
+        assert_eq!(p.fold("a", 6_000, 2.0).unwrap().count, 1);
+        let a = p.fold("a", 11_000, 3.0).unwrap();
+        assert_eq!((a.window_start, a.window_end, a.count), (0, 10_000, 2));
+        let a = p.fold("a", 16_000, 4.0).unwrap();
+        assert_eq!((a.window_start, a.window_end, a.count), (5_000, 15_000, 2));
+
+        let config = ProcessorConfig::Aggregate {
+            value_selector: "payload.v".to_string(),
+            key: None,
+            window_secs: 5,
+            slide_secs: Some(10),
+            functions: None,
+            topic: "agg".to_string(),
+        };
+        assert!(
+            AggregateProcessor::new_with_id(Uuid::new_v4(), config, Arc::new(Environment::new()))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use std::any::Any;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::Engine as _;
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::mqtt::protocol::property::PropertyUser;
+use crate::processor::config::PayloadCompression;
+use crate::processor::message::MetadataKey;
+
+use super::super::{Processor, config::ProcessorConfig, error::ProcessorError, message::Message};
+
+// user properties telling the decompress processor what was done to a payload
+const COMPRESSION_PROPERTY: &str = "axonmq-compression";
+const ENCRYPTION_PROPERTY: &str = "axonmq-encryption";
+// the content type of the payload before it was compressed
+const CONTENT_TYPE_PROPERTY: &str = "axonmq-content-type";
+const ENCRYPTION: &str = "aes-gcm";
+
+const NONCE_LEN: usize = 12;
+const DEFAULT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// AES-GCM with a 128 or 256 bit key, the nonce is put before the ciphertext
+enum Cipher {
+    Aes128(Box<Aes128Gcm>),
+    Aes256(Box<Aes256Gcm>),
+}
+
+impl Cipher {
+    /// a base64 key of 16 or 32 bytes
+    fn new(key: &str) -> Result<Self, ProcessorError> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|e| ProcessorError::InvalidConfiguration(format!("key: {}", e)))?;
+        match key.len() {
+            16 => Ok(Cipher::Aes128(Box::new(
+                Aes128Gcm::new_from_slice(&key).unwrap(),
+            ))),
+            32 => Ok(Cipher::Aes256(Box::new(
+                Aes256Gcm::new_from_slice(&key).unwrap(),
+            ))),
+            n => Err(ProcessorError::InvalidConfiguration(format!(
+                "key: {} bytes, 16 or 32 expected",
+                n
+            ))),
+        }
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, ProcessorError> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let sealed = match self {
+            Cipher::Aes128(c) => c.encrypt(Nonce::from_slice(&nonce), plain),
+            Cipher::Aes256(c) => c.encrypt(Nonce::from_slice(&nonce), plain),
+        }
+        .map_err(|_| ProcessorError::ProcessorError("encryption failed".to_string()))?;
+        Ok([&nonce[..], &sealed].concat())
+    }
+
+    fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, ProcessorError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(ProcessorError::ProcessorError(
+                "payload too short to be encrypted".to_string(),
+            ));
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        match self {
+            Cipher::Aes128(c) => c.decrypt(Nonce::from_slice(nonce), sealed),
+            Cipher::Aes256(c) => c.decrypt(Nonce::from_slice(nonce), sealed),
+        }
+        // a wrong key and a tampered payload look the same
+        .map_err(|_| ProcessorError::ProcessorError("decryption failed".to_string()))
+    }
+}
+
+fn property(message: &Message, key: &str) -> Option<String> {
+    message
+        .user_properties
+        .iter()
+        .rev()
+        .find(|p| p.key == key)
+        .map(|p| p.value.clone())
+}
+
+fn set_property(message: &mut Message, key: &str, value: Option<String>) {
+    message.user_properties.retain(|p| p.key != key);
+    if let Some(value) = value {
+        message.user_properties.push(PropertyUser {
+            key: key.to_string(),
+            value,
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct CompressProcessor {
+    id: Uuid,
+    algorithm: PayloadCompression,
+    level: Option<i32>,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl CompressProcessor {
+    pub fn new_with_id(
+        id: Uuid,
+        config: ProcessorConfig,
+    ) -> Result<Box<dyn Processor>, ProcessorError> {
+        if let ProcessorConfig::Compress {
+            algorithm,
+            level,
+            key,
+        } = config
+        {
+            let cipher = key.as_deref().map(Cipher::new).transpose()?.map(Arc::new);
+            Ok(Box::new(CompressProcessor {
+                id,
+                algorithm,
+                level,
+                cipher,
+            }))
+        } else {
+            Err(ProcessorError::InvalidConfiguration(
+                "Invalid configuration for CompressProcessor".to_string(),
+            ))
+        }
+    }
+
+    fn compress(&self, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self.algorithm {
+            PayloadCompression::Gzip => {
+                let level = self.level.map_or(flate2::Compression::default(), |level| {
+                    flate2::Compression::new(level.clamp(0, 9) as u32)
+                });
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            // 0 is the default level of zstd
+            PayloadCompression::Zstd => zstd::encode_all(payload, self.level.unwrap_or(0)),
+            PayloadCompression::None => Ok(payload.to_vec()),
+        }
+    }
+}
+
+#[async_trait]
+impl Processor for CompressProcessor {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[instrument(skip(self, message), fields(id = %self.id))]
+    async fn on_message(&self, mut message: Message) -> Result<Option<Message>, ProcessorError> {
+        let mut payload = self
+            .compress(&message.payload)
+            .map_err(|e| ProcessorError::ProcessorError(format!("compression failed: {}", e)))?;
+        if let Some(cipher) = &self.cipher {
+            payload = cipher.encrypt(&payload)?;
+        }
+        message.payload = Bytes::from(payload);
+        message
+            .metadata
+            .remove(MetadataKey::ParsedPayloadJson.as_str());
+
+        let content_type = message.options.content_type.take();
+        set_property(&mut message, CONTENT_TYPE_PROPERTY, content_type);
+        let compression = (self.algorithm != PayloadCompression::None).then(|| self.algorithm);
+        set_property(
+            &mut message,
+            COMPRESSION_PROPERTY,
+            compression.map(|c| c.to_string()),
+        );
+        set_property(
+            &mut message,
+            ENCRYPTION_PROPERTY,
+            self.cipher.as_ref().map(|_| ENCRYPTION.to_string()),
+        );
+        message.options.content_type = match (&self.cipher, compression) {
+            (None, Some(compression)) => Some(compression.content_type().to_string()),
+            _ => Some("application/octet-stream".to_string()),
+        };
+        message.options.payload_format_indicator = Some(0);
+        Ok(Some(message))
+    }
+}
+
+#[derive(Clone)]
+pub struct DecompressProcessor {
+    id: Uuid,
+    algorithm: PayloadCompression,
+    cipher: Option<Arc<Cipher>>,
+    max_size: usize,
+}
+
+impl DecompressProcessor {
+    pub fn new_with_id(
+        id: Uuid,
+        config: ProcessorConfig,
+    ) -> Result<Box<dyn Processor>, ProcessorError> {
+        if let ProcessorConfig::Decompress {
+            algorithm,
+            key,
+            max_size,
+        } = config
+        {
+            let cipher = key.as_deref().map(Cipher::new).transpose()?.map(Arc::new);
+            Ok(Box::new(DecompressProcessor {
+                id,
+                algorithm: algorithm.unwrap_or(PayloadCompression::None),
+                cipher,
+                max_size: max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            }))
+        } else {
+            Err(ProcessorError::InvalidConfiguration(
+                "Invalid configuration for DecompressProcessor".to_string(),
+            ))
+        }
+    }
+
+    /// stops past max_size, so a small payload cannot expand without bounds
+    fn decompress(&self, algorithm: PayloadCompression, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let reader: Box<dyn Read + '_> = match algorithm {
+            PayloadCompression::Gzip => Box::new(GzDecoder::new(payload)),
+            PayloadCompression::Zstd => {
+                Box::new(zstd::stream::read::Decoder::new(payload).map_err(|e| e.to_string())?)
+            }
+            PayloadCompression::None => return Ok(payload.to_vec()),
+        };
+        let mut out = vec![];
+        reader
+            .take(self.max_size as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|e| e.to_string())?;
+        if out.len() > self.max_size {
+            return Err(format!("more than {} bytes", self.max_size));
+        }
+        Ok(out)
+    }
+}
+
+#[async_trait]
+impl Processor for DecompressProcessor {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[instrument(skip(self, message), fields(id = %self.id))]
+    async fn on_message(&self, mut message: Message) -> Result<Option<Message>, ProcessorError> {
+        // the user properties of the compress processor win over the configuration
+        let encrypted = match property(&message, ENCRYPTION_PROPERTY) {
+            Some(encryption) if encryption == ENCRYPTION => true,
+            Some(encryption) => {
+                return Err(ProcessorError::ProcessorError(format!(
+                    "unsupported encryption {}",
+                    encryption
+                )));
+            }
+            None => self.cipher.is_some(),
+        };
+        let algorithm = match property(&message, COMPRESSION_PROPERTY) {
+            Some(name) => name.parse::<PayloadCompression>().map_err(|_| {
+                ProcessorError::ProcessorError(format!("unsupported compression {}", name))
+            })?,
+            None => self.algorithm,
+        };
+
+        let mut payload = message.payload.to_vec();
+        if encrypted {
+            let cipher = self.cipher.as_ref().ok_or_else(|| {
+                ProcessorError::ProcessorError("payload is encrypted and no key is set".to_string())
+            })?;
+            payload = cipher.decrypt(&payload)?;
+        }
+        let payload = self
+            .decompress(algorithm, &payload)
+            .map_err(|e| ProcessorError::ProcessorError(format!("decompression failed: {}", e)))?;
+        message.payload = Bytes::from(payload);
+        message
+            .metadata
+            .remove(MetadataKey::ParsedPayloadJson.as_str());
+
+        message.options.content_type = property(&message, CONTENT_TYPE_PROPERTY);
+        message.options.payload_format_indicator = None;
+        for key in [
+            COMPRESSION_PROPERTY,
+            ENCRYPTION_PROPERTY,
+            CONTENT_TYPE_PROPERTY,
+        ] {
+            set_property(&mut message, key, None);
+        }
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::QoS;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn message(payload: &[u8]) -> Message {
+        let mut message = Message::new(
+            "c".to_string(),
+            "t".to_string(),
+            QoS::AtMostOnce,
+            false,
+            Bytes::copy_from_slice(payload),
+            vec![],
+        );
+        message.options.content_type = Some("application/json".to_string());
+        message
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let payload = br#"{"temp": 21.5, "temp_min": 20.0, "temp_max": 23.0}"#.repeat(20);
+        for algorithm in [PayloadCompression::Gzip, PayloadCompression::Zstd] {
+            for key in [None, Some(KEY.to_string())] {
+                let compress = CompressProcessor::new_with_id(
+                    Uuid::nil(),
+                    ProcessorConfig::Compress {
+                        algorithm,
+                        level: None,
+                        key: key.clone(),
+                    },
+                )
+                .unwrap();
+                // the algorithm comes from the user properties
+                let decompress = DecompressProcessor::new_with_id(
+                    Uuid::nil(),
+                    ProcessorConfig::Decompress {
+                        algorithm: None,
+                        key,
+                        max_size: None,
+                    },
+                )
+                .unwrap();
+
+                let packed = compress
+                    .on_message(message(&payload))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(packed.payload.len() < payload.len());
+                let unpacked = decompress.on_message(packed).await.unwrap().unwrap();
+                assert_eq!(&unpacked.payload[..], &payload[..]);
+                assert_eq!(
+                    unpacked.options.content_type.as_deref(),
+                    Some("application/json")
+                );
+                assert!(unpacked.user_properties.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decompress_errors() {
+        let compress = CompressProcessor::new_with_id(
+            Uuid::nil(),
+            ProcessorConfig::Compress {
+                algorithm: PayloadCompression::Gzip,
+                level: Some(9),
+                key: Some(KEY.to_string()),
+            },
+        )
+        .unwrap();
+        let packed = compress
+            .on_message(message(&[0; 4096]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // no key
+        let decompress = DecompressProcessor::new_with_id(
+            Uuid::nil(),
+            ProcessorConfig::Decompress {
+                algorithm: None,
+                key: None,
+                max_size: None,
+            },
+        )
+        .unwrap();
+        assert!(decompress.on_message(packed.clone()).await.is_err());
+
+        // expands past max_size
+        let decompress = DecompressProcessor::new_with_id(
+            Uuid::nil(),
+            ProcessorConfig::Decompress {
+                algorithm: None,
+                key: Some(KEY.to_string()),
+                max_size: Some(1024),
+            },
+        )
+        .unwrap();
+        assert!(decompress.on_message(packed).await.is_err());
+
+        let config = ProcessorConfig::Decompress {
+            algorithm: None,
+            key: Some("c2hvcnQ=".to_string()),
+            max_size: None,
+        };
+        assert!(DecompressProcessor::new_with_id(Uuid::nil(), config).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use std::any::Any;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde_json::Value as JsonValue;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::processor::config::PayloadFormat;
+use crate::processor::message::{MetadataKey, MetadataValue};
+
+use super::super::{Processor, config::ProcessorConfig, error::ProcessorError, message::Message};
+
+#[derive(Clone)]
+pub struct ConvertProcessor {
+    id: Uuid,
+    from: PayloadFormat,
+    to: PayloadFormat,
+}
+
+impl ConvertProcessor {
+    pub fn new_with_id(
+        id: Uuid,
+        config: ProcessorConfig,
+    ) -> Result<Box<dyn Processor>, ProcessorError> {
+        if let ProcessorConfig::Convert { from, to } = config {
+            Ok(Box::new(ConvertProcessor { id, from, to }))
+        } else {
+            Err(ProcessorError::InvalidConfiguration(
+                "Invalid configuration for ConvertProcessor".to_string(),
+            ))
+        }
+    }
+
+    fn decode(format: PayloadFormat, payload: &[u8]) -> Result<JsonValue, String> {
+        match format {
+            PayloadFormat::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            PayloadFormat::Cbor => ciborium::from_reader(payload).map_err(|e| e.to_string()),
+            PayloadFormat::Msgpack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn encode(format: PayloadFormat, value: &JsonValue) -> Result<Vec<u8>, String> {
+        match format {
+            PayloadFormat::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            PayloadFormat::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(value, &mut buf).map_err(|e| e.to_string())?;
+                Ok(buf)
+            }
+            // maps keep their keys, not the positions of a struct
+            PayloadFormat::Msgpack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl Processor for ConvertProcessor {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[instrument(skip(self, message), fields(id = %self.id))]
+    async fn on_message(&self, mut message: Message) -> Result<Option<Message>, ProcessorError> {
+        // the content type of the publisher wins over the configured format
+        let from = message
+            .options
+            .content_type
+            .as_deref()
+            .and_then(PayloadFormat::from_content_type)
+            .unwrap_or(self.from);
+
+        if from != self.to {
+            let value = Self::decode(from, &message.payload).map_err(|e| {
+                ProcessorError::ProcessorError(format!("payload is not {}: {}", from, e))
+            })?;
+            let payload = Self::encode(self.to, &value).map_err(|e| {
+                ProcessorError::ProcessorError(format!("payload cannot be {}: {}", self.to, e))
+            })?;
+            message.payload = Bytes::from(payload);
+            if self.to == PayloadFormat::Json {
+                message.metadata.insert(
+                    MetadataKey::ParsedPayloadJson.as_str().to_string(),
+                    MetadataValue::Json(value),
+                );
+            } else {
+                message
+                    .metadata
+                    .remove(MetadataKey::ParsedPayloadJson.as_str());
+            }
+        }
+
+        message.options.content_type = Some(self.to.content_type().to_string());
+        message.options.payload_format_indicator = Some(u8::from(self.to == PayloadFormat::Json));
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::QoS;
+
+    fn message(payload: Vec<u8>, content_type: Option<&str>) -> Message {
+        let mut message = Message::new(
+            "c".to_string(),
+            "t".to_string(),
+            QoS::AtMostOnce,
+            false,
+            Bytes::from(payload),
+            vec![],
+        );
+        message.options.content_type = content_type.map(str::to_string);
+        message
+    }
+
+    fn convert(from: PayloadFormat, to: PayloadFormat) -> Box<dyn Processor> {
+        ConvertProcessor::new_with_id(Uuid::nil(), ProcessorConfig::Convert { from, to }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let json = serde_json::json!({"temp": 21.5, "ok": true, "tags": ["a", "b"]});
+        let to_cbor = convert(PayloadFormat::Json, PayloadFormat::Cbor);
+        let to_json = convert(PayloadFormat::Cbor, PayloadFormat::Json);
+
+        let msg = message(serde_json::to_vec(&json).unwrap(), None);
+        let cbor = to_cbor.on_message(msg).await.unwrap().unwrap();
+        assert_eq!(
+            cbor.options.content_type.as_deref(),
+            Some("application/cbor")
+        );
+        let back = to_json.on_message(cbor).await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<JsonValue>(&back.payload).unwrap(),
+            json
+        );
+        assert_eq!(back.options.payload_format_indicator, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_content_type() {
+        let json = serde_json::json!({"id": 7});
+        let packed = rmp_serde::to_vec_named(&json).unwrap();
+        // configured for CBOR, the content type says MessagePack
+        let to_json = convert(PayloadFormat::Cbor, PayloadFormat::Json);
+        let msg = message(packed, Some("application/msgpack"));
+        let out = to_json.on_message(msg).await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<JsonValue>(&out.payload).unwrap(),
+            json
+        );
+        assert_eq!(
+            out.options.content_type.as_deref(),
+            Some("application/json")
+        );
+
+        // already in the target format
+        let msg = message(
+            b"{\"id\": 7}".to_vec(),
+            Some("application/json; charset=utf-8"),
+        );
+        let out = to_json.on_message(msg).await.unwrap().unwrap();
+        assert_eq!(&out.payload[..], b"{\"id\": 7}");
+
+        let msg = message(b"not cbor".to_vec(), None);
+        assert!(to_json.on_message(msg).await.is_err());
+    }
+}
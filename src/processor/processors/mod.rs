@@ -1,6 +1,11 @@
+pub mod aggregate;
 pub mod anomaly_detector;
+pub mod compress;
+pub mod convert;
+pub mod enrich;
 pub mod filter;
 pub mod json_transform;
 pub mod logger;
+pub mod rate_limit;
 pub mod republish;
 pub mod webhook;
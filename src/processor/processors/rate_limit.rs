@@ -0,0 +1,216 @@
+use std::any::Any;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use minijinja::{Environment, context};
+use serde_json::Value as JsonValue;
+use tracing::{instrument, trace, warn};
+use uuid::Uuid;
+
+use crate::processor::message::{MetadataKey, MetadataValue};
+use crate::utils::time::now_milliseconds;
+
+use super::super::{Processor, config::ProcessorConfig, error::ProcessorError, message::Message};
+
+#[derive(Debug)]
+struct StreamState {
+    tokens: f64,
+    refilled_at: u64,
+    seen: u64,
+}
+
+#[derive(Clone)]
+pub struct RateLimitProcessor {
+    id: Uuid,
+    env: Arc<Environment<'static>>,
+    key: Option<String>,
+    max_per_sec: Option<f64>,
+    burst: f64,
+    sample_every: u64,
+    state: Arc<DashMap<String, StreamState>>,
+}
+
+impl RateLimitProcessor {
+    pub fn new_with_id(
+        id: Uuid,
+        config: ProcessorConfig,
+        env: Arc<Environment<'static>>,
+    ) -> Result<Box<dyn Processor>, ProcessorError> {
+        if let ProcessorConfig::RateLimit {
+            key,
+            max_per_sec,
+            burst,
+            sample_every,
+        } = config
+        {
+            if max_per_sec.is_none() && sample_every.is_none() {
+                return Err(ProcessorError::InvalidConfiguration(
+                    "rate_limit needs max_per_sec, sample_every or both".to_string(),
+                ));
+            }
+            if max_per_sec.is_some_and(|r| r <= 0.0) || sample_every == Some(0) {
+                return Err(ProcessorError::InvalidConfiguration(
+                    "max_per_sec and sample_every must be positive".to_string(),
+                ));
+            }
+            let burst = burst.unwrap_or_else(|| max_per_sec.unwrap_or(1.0).max(1.0));
+            Ok(Box::new(RateLimitProcessor {
+                id,
+                env,
+                key,
+                max_per_sec,
+                burst,
+                sample_every: sample_every.unwrap_or(1),
+                state: Arc::new(DashMap::new()),
+            }))
+        } else {
+            Err(ProcessorError::InvalidConfiguration(
+                "Invalid configuration for RateLimitProcessor".to_string(),
+            ))
+        }
+    }
+
+    /// whether a message of `key` received at `now` goes on, sampling first then the rate
+    fn allow(&self, key: &str, now: u64) -> bool {
+        let mut state = self
+            .state
+            .entry(key.to_string())
+            .or_insert_with(|| StreamState {
+                tokens: self.burst,
+                refilled_at: now,
+                seen: 0,
+            });
+
+        state.seen += 1;
+        // the first message of a stream is always sampled
+        if !(state.seen - 1).is_multiple_of(self.sample_every) {
+            return false;
+        }
+
+        let Some(max_per_sec) = self.max_per_sec else {
+            return true;
+        };
+        let elapsed = now.saturating_sub(state.refilled_at) as f64 / 1000.0;
+        state.tokens = (state.tokens + elapsed * max_per_sec).min(self.burst);
+        state.refilled_at = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[async_trait]
+impl Processor for RateLimitProcessor {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    #[instrument(skip(self, message), fields(id = %self.id))]
+    async fn on_message(&self, mut message: Message) -> Result<Option<Message>, ProcessorError> {
+        let key = match &self.key {
+            Some(template) => {
+                let payload_json = if let Some(MetadataValue::Json(val)) = message
+                    .metadata
+                    .get(MetadataKey::ParsedPayloadJson.as_str())
+                {
+                    val.clone()
+                } else {
+                    let parsed_val: JsonValue =
+                        serde_json::from_slice(&message.payload).unwrap_or(JsonValue::Null);
+                    message.metadata.insert(
+                        MetadataKey::ParsedPayloadJson.as_str().to_string(),
+                        MetadataValue::Json(parsed_val.clone()),
+                    );
+                    parsed_val
+                };
+                let ctx = context! {
+                    topic => message.topic.clone(),
+                    client_id => message.client_id.clone(),
+                    payload => payload_json,
+                    metadata => message.metadata.clone(),
+                };
+                self.env.render_str(template, ctx).map_err(|e| {
+                    warn!("Failed to render rate limit key template: {}", e);
+                    ProcessorError::TemplateError(e.to_string())
+                })?
+            }
+            None => message.topic.clone(),
+        };
+
+        if self.allow(&key, now_milliseconds()) {
+            Ok(Some(message))
+        } else {
+            trace!(key = %key, "rate limited");
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn processor(
+        max_per_sec: Option<f64>,
+        burst: Option<f64>,
+        sample_every: Option<u64>,
+    ) -> RateLimitProcessor {
+        let config = ProcessorConfig::RateLimit {
+            key: None,
+            max_per_sec,
+            burst,
+            sample_every,
+        };
+        RateLimitProcessor::new_with_id(Uuid::new_v4(), config, Arc::new(Environment::new()))
+            .unwrap()
+            .as_any()
+            .downcast_ref::<RateLimitProcessor>()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn test_allow() {
+        // one message per second, streams are limited independently
+        let p = processor(Some(1.0), None, None);
+        assert!(p.allow("a", 1_000));
+        assert!(!p.allow("a", 1_400));
+        assert!(p.allow("b", 1_400));
+        assert!(!p.allow("a", 1_900));
+        assert!(p.allow("a", 2_000));
+
+        // one every five seconds after a burst of three
+        let p = processor(Some(0.2), Some(3.0), None);
+        let passed = (0..10).filter(|i| p.allow("a", 1_000 + i * 10)).count();
+        assert_eq!(passed, 3);
+        assert!(!p.allow("a", 5_000));
+        assert!(p.allow("a", 6_100));
+
+        // 1 in 3, then the rate on the sampled ones
+        let p = processor(None, None, Some(3));
+        let passed: Vec<bool> = (0..7).map(|i| p.allow("a", i)).collect();
+        assert_eq!(passed, [true, false, false, true, false, false, true]);
+        let p = processor(Some(1.0), None, Some(2));
+        let passed: Vec<bool> = (0..4).map(|i| p.allow("a", 1_000 + i * 300)).collect();
+        assert_eq!(passed, [true, false, false, false]);
+
+        let config = ProcessorConfig::RateLimit {
+            key: None,
+            max_per_sec: None,
+            burst: None,
+            sample_every: None,
+        };
+        assert!(
+            RateLimitProcessor::new_with_id(Uuid::new_v4(), config, Arc::new(Environment::new()))
+                .is_err()
+        );
+    }
+}
@@ -5,14 +5,15 @@ use async_trait::async_trait;
 use tracing::{debug, error, info, instrument, trace, warn};
 use uuid::Uuid;
 use wasmtime::component::{Component, HasSelf, Linker, ResourceTable, bindgen};
-use wasmtime::{Engine, Store};
-use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView};
+use wasmtime::{Engine, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtx, WasiCtxView, WasiView};
 
 use crate::mqtt::protocol::publish::PublishOptions;
 
-use super::Processor;
+use super::config::{WasiCapabilities, WasmLimits};
 use super::error::ProcessorError;
 use super::message::Message;
+use super::{Processor, WASM_EPOCH_TICK};
 
 bindgen!("axonmq-processor" in "wit/processor.wit");
 //bindgen!({
@@ -28,6 +29,8 @@ pub struct WasmProcessor {
     linker: Linker<WasmProcessorState>,
 
     uuid: Uuid,
+    limits: WasmLimits,
+    wasi: WasiCapabilities,
 
     #[allow(dead_code)]
     name: String,
@@ -43,6 +46,7 @@ pub struct WasmProcessor {
 pub struct WasmProcessorState {
     pub wasi_ctx: WasiCtx,
     pub resource_table: ResourceTable,
+    pub store_limits: StoreLimits,
 
     pub uuid: Uuid,
 }
@@ -56,7 +60,7 @@ impl WasiView for WasmProcessorState {
     }
 }
 
-use axonmq::processor::logging;
+use self::axonmq::processor::logging;
 
 impl logging::Host for WasmProcessorState {
     #[instrument(name="on_message", skip(self, message, level, target), fields(id=%self.uuid, name=%target))]
@@ -81,12 +85,78 @@ impl logging::Host for WasmProcessorState {
     }
 }
 
+/// the WASI context of one store, with only the capabilities the processor is granted
+fn wasi_ctx(wasi: &WasiCapabilities) -> Result<WasiCtx> {
+    let mut builder = WasiCtx::builder();
+    if wasi.stdio {
+        builder.inherit_stdio();
+    }
+    if wasi.args {
+        builder.inherit_args();
+    }
+    if wasi.env {
+        builder.inherit_env();
+    }
+    if wasi.network {
+        builder.inherit_network().allow_ip_name_lookup(true);
+    }
+    for dir in &wasi.dirs {
+        let (dir, read_only) = match dir.strip_suffix(":ro") {
+            Some(dir) => (dir, true),
+            None => (dir.as_str(), false),
+        };
+        let Some((host, guest)) = dir.split_once(':') else {
+            anyhow::bail!("invalid WASI dir {}, expected host_dir:guest_dir", dir);
+        };
+        if read_only {
+            builder.preopened_dir(host, guest, DirPerms::READ, FilePerms::READ)?;
+        } else {
+            builder.preopened_dir(host, guest, DirPerms::all(), FilePerms::all())?;
+        }
+    }
+    Ok(builder.build())
+}
+
+/// a store for one call, the limits apply to everything run in it
+fn new_store(
+    engine: &Engine,
+    uuid: Uuid,
+    limits: &WasmLimits,
+    wasi: &WasiCapabilities,
+) -> Result<Store<WasmProcessorState>> {
+    let mut store_limits = StoreLimitsBuilder::new();
+    if let Some(max_memory_bytes) = limits.max_memory_bytes {
+        store_limits = store_limits.memory_size(max_memory_bytes);
+    }
+    let state = WasmProcessorState {
+        wasi_ctx: wasi_ctx(wasi)?,
+        resource_table: ResourceTable::new(),
+        store_limits: store_limits.build(),
+        uuid,
+    };
+
+    let mut store = Store::new(engine, state);
+    store.limiter(|state| &mut state.store_limits);
+    // only fails on an engine without fuel, the default one used when the configured engine fails
+    store.set_fuel(limits.max_fuel.unwrap_or(u64::MAX)).ok();
+    let ticks = match limits.timeout_ms {
+        Some(timeout_ms) => timeout_ms
+            .div_ceil(WASM_EPOCH_TICK.as_millis() as u64)
+            .max(1),
+        None => u64::MAX / 2,
+    };
+    store.set_epoch_deadline(ticks);
+    Ok(store)
+}
+
 impl WasmProcessor {
     pub async fn new(
         engine: Arc<Engine>,
         wasm_file: String,
         uuid: Uuid,
         cfg: String,
+        limits: WasmLimits,
+        wasi: WasiCapabilities,
     ) -> Result<Box<dyn Processor>> {
         debug!("Loading WASM Processor from file: {}", wasm_file);
 
@@ -94,14 +164,7 @@ impl WasmProcessor {
         wasmtime_wasi::p2::add_to_linker_sync(&mut linker)?;
         AxonmqProcessor::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| state)?;
 
-        let wasi = WasiCtx::builder().inherit_stdio().inherit_args().build();
-        let state = WasmProcessorState {
-            wasi_ctx: wasi,
-            resource_table: ResourceTable::new(),
-            uuid,
-        };
-
-        let mut store = Store::new(&engine, state);
+        let mut store = new_store(&engine, uuid, &limits, &wasi)?;
         let component = Component::from_file(&engine, wasm_file)?;
         let bindings = AxonmqProcessor::instantiate(&mut store, &component, &linker)?;
 
@@ -131,6 +194,8 @@ impl WasmProcessor {
             version,
             description,
             uuid,
+            limits,
+            wasi,
             cfg,
         }))
     }
@@ -266,13 +331,8 @@ impl Processor for WasmProcessor {
     }
 
     async fn on_message(&self, message: Message) -> Result<Option<Message>, ProcessorError> {
-        let wasi = WasiCtx::builder().inherit_stdio().inherit_args().build();
-        let state = WasmProcessorState {
-            wasi_ctx: wasi,
-            resource_table: ResourceTable::new(),
-            uuid: self.uuid,
-        };
-        let mut store = Store::new(&self.engine, state);
+        // a call over its limits traps, the message fails like any processor error
+        let mut store = new_store(&self.engine, self.uuid, &self.limits, &self.wasi)?;
         let bindings = AxonmqProcessor::instantiate(&mut store, &self.component, &self.linker)?;
 
         let result = bindings
@@ -283,3 +343,67 @@ impl Processor for WasmProcessor {
         Ok(result.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Config, Instance, Module};
+
+    use super::*;
+
+    #[test]
+    fn test_store_limits() {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).unwrap();
+        let spin =
+            Module::new(&engine, r#"(module (func (export "spin") (loop (br 0))))"#).unwrap();
+        let big = Module::new(&engine, r#"(module (memory 17))"#).unwrap();
+        let uuid = Uuid::new_v4();
+        let wasi = WasiCapabilities::default();
+
+        let limits = WasmLimits {
+            max_fuel: Some(10_000),
+            ..Default::default()
+        };
+        let mut store = new_store(&engine, uuid, &limits, &wasi).unwrap();
+        let instance = Instance::new(&mut store, &spin, &[]).unwrap();
+        let spin_fn = instance
+            .get_typed_func::<(), ()>(&mut store, "spin")
+            .unwrap();
+        assert!(spin_fn.call(&mut store, ()).is_err());
+
+        let limits = WasmLimits {
+            timeout_ms: Some(20),
+            ..Default::default()
+        };
+        let mut store = new_store(&engine, uuid, &limits, &wasi).unwrap();
+        let instance = Instance::new(&mut store, &spin, &[]).unwrap();
+        let spin_fn = instance
+            .get_typed_func::<(), ()>(&mut store, "spin")
+            .unwrap();
+        let ticker = engine.clone();
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                std::thread::sleep(WASM_EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        });
+        assert!(spin_fn.call(&mut store, ()).is_err());
+
+        let limits = WasmLimits {
+            max_memory_bytes: Some(16 * 65536),
+            ..Default::default()
+        };
+        let mut store = new_store(&engine, uuid, &limits, &wasi).unwrap();
+        assert!(Instance::new(&mut store, &big, &[]).is_err());
+        let mut store = new_store(&engine, uuid, &WasmLimits::default(), &wasi).unwrap();
+        assert!(Instance::new(&mut store, &big, &[]).is_ok());
+
+        let wasi = WasiCapabilities {
+            dirs: vec!["no-guest-dir".to_string()],
+            ..Default::default()
+        };
+        assert!(new_store(&engine, uuid, &WasmLimits::default(), &wasi).is_err());
+    }
+}
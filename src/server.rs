@@ -0,0 +1,553 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::future::{self, FutureExt};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+use crate::CONFIG;
+use crate::cluster::{Cluster, replicated};
+use crate::config::{Config, ListenerCommonConfig, RestfulConfig};
+use crate::features::Feature;
+use crate::logging::LogControl;
+use crate::mqtt::{QoS, helper::BrokerHelper, listener, server::Broker};
+use crate::operator::{Operator, helper::Helper as OperatorHelper};
+use crate::service::{replica::Replica, restful::RESTful, sparkplug_b::SparkPlugBApplication};
+use crate::supervisor::{SubsystemInfo, Supervisor};
+
+/// client id used for messages published through [`Server::publish`]
+const EMBEDDED_CLIENT_ID: &str = "$embedded";
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 5;
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_CERT_RELOAD_INTERVAL: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listener {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+impl Listener {
+    fn name(&self) -> &'static str {
+        match self {
+            Listener::Tcp => "listener.tcp",
+            Listener::Tls => "listener.tls",
+            Listener::Ws => "listener.ws",
+            Listener::Wss => "listener.wss",
+        }
+    }
+
+    /// the settings its section shares with the other listeners, only for configured ones
+    fn common<'a>(&self, config: &'a Config) -> &'a ListenerCommonConfig {
+        let listeners = &config.mqtt.listener;
+        match self {
+            Listener::Tcp => &listeners.tcp.as_ref().unwrap().common,
+            Listener::Tls => &listeners.tcp_tls.as_ref().unwrap().common,
+            Listener::Ws => &listeners.ws.as_ref().unwrap().common,
+            Listener::Wss => &listeners.wss.as_ref().unwrap().common,
+        }
+    }
+
+    /// its section is in the configuration and not turned off
+    fn configured(&self, config: &Config) -> bool {
+        let listeners = &config.mqtt.listener;
+        match self {
+            Listener::Tcp => listeners.tcp.as_ref().is_some_and(|l| l.enable),
+            Listener::Tls => listeners.tcp_tls.as_ref().is_some_and(|l| l.enable),
+            Listener::Ws => listeners.ws.as_ref().is_some_and(|l| l.enable),
+            Listener::Wss => listeners.wss.as_ref().is_some_and(|l| l.enable),
+        }
+    }
+}
+
+/// builds a broker from a configuration, the selected parts override the configuration
+pub struct ServerBuilder {
+    config: Config,
+    listeners: Vec<Listener>,
+    restful: bool,
+    log_control: Option<LogControl>,
+}
+
+impl ServerBuilder {
+    fn new(config: Config) -> Self {
+        ServerBuilder {
+            config,
+            listeners: vec![Listener::Tcp, Listener::Tls, Listener::Ws, Listener::Wss],
+            restful: true,
+            log_control: None,
+        }
+    }
+
+    /// only start these MQTT listeners, all four are started by default
+    pub fn listeners(mut self, listeners: &[Listener]) -> Self {
+        self.listeners = listeners.to_vec();
+        self
+    }
+
+    pub fn restful(mut self, enable: bool) -> Self {
+        self.restful = enable;
+        self
+    }
+
+    /// the log filter `PUT /api/v1/admin/log-level` changes, the endpoint answers 501 without it
+    pub fn log_control(mut self, control: LogControl) -> Self {
+        self.log_control = Some(control);
+        self
+    }
+
+    pub fn sparkplug_b(mut self, enable: bool) -> Self {
+        self.config.service.sparkplug_b.enable = enable;
+        self
+    }
+
+    pub fn cluster(mut self, enable: bool) -> Self {
+        if !enable {
+            self.config.node.cluster = None;
+        }
+        self
+    }
+
+    /// persist retained messages in this file, None keeps them in memory
+    pub fn retain_store(mut self, path: Option<String>) -> Self {
+        self.config.mqtt.settings.retain_store_path = path;
+        self
+    }
+
+    /// spill offline session queues to this directory, None keeps them in memory
+    pub fn spill_dir(mut self, dir: Option<String>) -> Self {
+        self.config.mqtt.settings.spill_dir = dir;
+        self
+    }
+
+    /// whether to start a subsystem the configuration turns on
+    fn wanted(feature: Feature, configured: bool) -> bool {
+        if !configured {
+            return false;
+        }
+        if let Some(reason) = feature.unavailable() {
+            warn!("{}, not starting it", reason);
+            return false;
+        }
+        true
+    }
+
+    /// start the broker on the current tokio runtime, one server can run per process
+    pub async fn start(self) -> Result<Server> {
+        CONFIG
+            .set(self.config)
+            .map_err(|_| anyhow::anyhow!("an AxonMQ server is already running in this process"))?;
+        let config = CONFIG.get().unwrap();
+
+        coarsetime::Updater::new(100)
+            .start()
+            .map_err(|e| anyhow::anyhow!(e))?;
+
+        // the S3 client enables ring next to aws-lc-rs, rustls cannot pick one on its own,
+        // an application that already installed a provider keeps it
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let mut supervisor = Supervisor::default();
+
+        if Self::wanted(
+            Feature::Replica,
+            config.service.replica.as_ref().is_some_and(|r| r.enable),
+        ) {
+            let replica = Replica::new()?;
+            let replica_helper = replica.helper();
+            supervisor.add_once("replica", &[], move || {
+                future::ready(vec![replica.run()]).boxed()
+            });
+
+            if self.restful
+                && let Some(restful) = config.service.restful.as_ref()
+            {
+                let restful = Self::restful_service(restful)?;
+                let supervisor_helper = supervisor.helper();
+                supervisor.add_restartable("restful", &["replica"], move || {
+                    let task = tokio::spawn(
+                        restful.run_replica(replica_helper.clone(), supervisor_helper.clone()),
+                    );
+                    future::ready(vec![task]).boxed()
+                });
+            }
+
+            supervisor.start().await?;
+            info!("AxonMQ replica started.");
+
+            return Ok(Server {
+                helpers: None,
+                listeners: vec![],
+                sparkplug_b: false,
+                supervisor,
+            });
+        }
+
+        let mut spb_service =
+            if Self::wanted(Feature::SparkplugB, config.service.sparkplug_b.enable) {
+                Some(SparkPlugBApplication::new())
+            } else {
+                None
+            };
+        let sparkplug_b = spb_service.is_some();
+        let spb_helper = spb_service.as_ref().map(|s| s.helper());
+        if let Some(helper) = &spb_helper {
+            replicated::set_sparkplug_b(helper.clone());
+        }
+        let spb_in_helper = spb_service.as_mut().map(|s| s.in_helper().clone());
+
+        let cluster = if Self::wanted(Feature::Cluster, config.node.cluster.is_some()) {
+            Some(Cluster::new()?)
+        } else {
+            None
+        };
+        let cluster_helper = cluster.as_ref().map(|c| c.helper());
+
+        let mut operator = Operator::new(cluster_helper).await?;
+        let operator_helper = operator.helper();
+        supervisor.add_once("operator", &[], move || {
+            future::ready(operator.run(spb_helper)).boxed()
+        });
+
+        let mut broker = Broker::new().await;
+        let broker_helper = broker.get_helper();
+        {
+            let operator_helper = operator_helper.clone();
+            supervisor.add_once("broker", &["operator"], move || {
+                async move { vec![broker.run(operator_helper).await] }.boxed()
+            });
+        }
+
+        if let Some(mut spb_service) = spb_service {
+            let operator_helper = operator_helper.clone();
+            let broker_helper = broker_helper.clone();
+            supervisor.add_once("sparkplug_b", &["operator", "broker"], move || {
+                async move { vec![spb_service.run(operator_helper, broker_helper).await] }.boxed()
+            });
+        }
+
+        if let Some(cluster) = cluster {
+            let operator_helper = operator_helper.clone();
+            let broker_helper = broker_helper.clone();
+            supervisor.add_once("cluster", &["operator", "broker"], move || {
+                future::ready(cluster.run(operator_helper, broker_helper)).boxed()
+            });
+        }
+
+        #[cfg(feature = "telemetry")]
+        if Self::wanted(
+            Feature::Telemetry,
+            config
+                .telemetry
+                .as_ref()
+                .is_some_and(|t| t.enable && t.metrics.unwrap_or(true)),
+        ) {
+            let operator_helper = operator_helper.clone();
+            supervisor.add_once("telemetry", &["operator"], move || {
+                let task = tokio::spawn(crate::telemetry::export_metrics(operator_helper));
+                future::ready(vec![task]).boxed()
+            });
+        }
+
+        if config.audit.as_ref().is_some_and(|audit| audit.enable) {
+            let operator_helper = operator_helper.clone();
+            supervisor.add_once("audit", &["operator"], move || {
+                let task = tokio::spawn(crate::audit::run(operator_helper));
+                future::ready(vec![task]).boxed()
+            });
+        }
+
+        if !config.hook.is_empty() || config.events.as_ref().is_some_and(|events| events.enable) {
+            let operator_helper = operator_helper.clone();
+            supervisor.add_once("hook", &["operator"], move || {
+                let task = tokio::spawn(crate::hook::run(operator_helper));
+                future::ready(vec![task]).boxed()
+            });
+        }
+
+        let listeners: Vec<Listener> = self
+            .listeners
+            .iter()
+            .copied()
+            .filter(|listener| listener.configured(config))
+            .collect();
+        for listener in listeners.iter().copied() {
+            let broker_helper = broker_helper.clone();
+            let operator_helper = operator_helper.clone();
+            supervisor.add_restartable(listener.name(), &["operator", "broker"], move || {
+                let task = Self::spawn_listener(listener, config, &broker_helper, &operator_helper);
+                future::ready(vec![task]).boxed()
+            });
+        }
+
+        if self.restful
+            && let Some(restful) = config.service.restful.as_ref()
+        {
+            let restful = Self::restful_service(restful)?;
+            let broker_helper = broker_helper.clone();
+            let operator_helper = operator_helper.clone();
+            let supervisor_helper = supervisor.helper();
+            let log_control = self.log_control.clone();
+            supervisor.add_restartable(
+                "restful",
+                &["operator", "sparkplug_b", "broker"],
+                move || {
+                    let task = tokio::spawn(restful.run(
+                        broker_helper.clone(),
+                        operator_helper.clone(),
+                        spb_in_helper.clone(),
+                        supervisor_helper.clone(),
+                        log_control.clone(),
+                    ));
+                    future::ready(vec![task]).boxed()
+                },
+            );
+        }
+
+        supervisor.start().await?;
+        info!("AxonMQ started.");
+
+        Ok(Server {
+            helpers: Some((broker_helper, operator_helper)),
+            listeners,
+            sparkplug_b,
+            supervisor,
+        })
+    }
+
+    fn restful_service(config: &RestfulConfig) -> Result<RESTful> {
+        let restful = RESTful::new(&config.ip, config.port).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(match &config.tls {
+            Some(tls) => restful.with_tls(listener::TlsOptions {
+                cert_path: tls.cert_path.clone(),
+                key_path: tls.key_path.clone(),
+                ca_path: tls.ca_path.clone(),
+                require_client_cert: tls.require_client_cert.unwrap_or(false),
+                cert_as_client_id: false,
+                reload_interval: tls
+                    .cert_reload_interval
+                    .unwrap_or(DEFAULT_CERT_RELOAD_INTERVAL),
+            }),
+            None => restful,
+        })
+    }
+
+    fn spawn_listener(
+        listener: Listener,
+        config: &Config,
+        broker_helper: &BrokerHelper,
+        operator_helper: &OperatorHelper,
+    ) -> JoinHandle<()> {
+        // only configured listeners are spawned
+        let listeners = &config.mqtt.listener;
+        let admission = listener::Admission::from_config(
+            listener.name(),
+            listener.common(config),
+            &config.mqtt.settings.client_limits,
+        );
+        match listener {
+            Listener::Tcp => {
+                let tcp = listeners.tcp.as_ref().unwrap();
+                listener::spawn_tcp_listener(
+                    tcp.host.clone(),
+                    tcp.port,
+                    admission,
+                    broker_helper.clone(),
+                    operator_helper.clone(),
+                )
+            }
+            Listener::Tls => {
+                let tls = listeners.tcp_tls.as_ref().unwrap();
+                listener::spawn_tls_listener(
+                    tls.host.clone(),
+                    tls.port,
+                    listener::TlsOptions {
+                        cert_path: tls.cert_path.clone(),
+                        key_path: tls.key_path.clone(),
+                        ca_path: tls.ca_path.clone(),
+                        require_client_cert: tls.require_client_cert.unwrap_or(false),
+                        cert_as_client_id: tls.cert_as_client_id.unwrap_or(false),
+                        reload_interval: tls
+                            .cert_reload_interval
+                            .unwrap_or(DEFAULT_CERT_RELOAD_INTERVAL),
+                    },
+                    admission,
+                    broker_helper.clone(),
+                    operator_helper.clone(),
+                )
+            }
+            Listener::Ws => {
+                let ws = listeners.ws.as_ref().unwrap();
+                listener::spawn_ws_listener(
+                    ws.host.clone(),
+                    ws.port,
+                    ws.path.clone(),
+                    admission,
+                    broker_helper.clone(),
+                    operator_helper.clone(),
+                )
+            }
+            Listener::Wss => {
+                let wss = listeners.wss.as_ref().unwrap();
+                listener::spawn_wss_listener(
+                    wss.host.clone(),
+                    wss.port,
+                    wss.path.clone(),
+                    listener::TlsOptions {
+                        cert_path: wss.cert_path.clone(),
+                        key_path: wss.key_path.clone(),
+                        ca_path: wss.ca_path.clone(),
+                        require_client_cert: wss.require_client_cert.unwrap_or(false),
+                        cert_as_client_id: wss.cert_as_client_id.unwrap_or(false),
+                        reload_interval: wss
+                            .cert_reload_interval
+                            .unwrap_or(DEFAULT_CERT_RELOAD_INTERVAL),
+                    },
+                    admission,
+                    broker_helper.clone(),
+                    operator_helper.clone(),
+                )
+            }
+        }
+    }
+}
+
+/// a running broker, embedded in the application that started it
+pub struct Server {
+    helpers: Option<(BrokerHelper, OperatorHelper)>,
+    listeners: Vec<Listener>,
+    sparkplug_b: bool,
+    supervisor: Supervisor,
+}
+
+impl Server {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder::new(config)
+    }
+
+    /// None when the node runs as a read-only replica
+    pub fn broker(&self) -> Option<&BrokerHelper> {
+        self.helpers.as_ref().map(|(broker, _)| broker)
+    }
+
+    /// None when the node runs as a read-only replica
+    pub fn operator(&self) -> Option<&OperatorHelper> {
+        self.helpers.as_ref().map(|(_, operator)| operator)
+    }
+
+    /// state of the supervised subsystems, in the order they were registered
+    pub fn subsystems(&self) -> Vec<SubsystemInfo> {
+        self.supervisor.helper().subsystems()
+    }
+
+    /// publish a message as if a client had sent it, retained messages are stored
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<()> {
+        let (broker_helper, operator_helper) = self
+            .helpers
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("a read-only replica does not accept publishes"))?;
+        let topic = topic.into();
+        let payload = payload.into();
+
+        if retain {
+            broker_helper
+                .retain_message(
+                    Some(EMBEDDED_CLIENT_ID.to_string()),
+                    topic.clone(),
+                    qos,
+                    payload.clone(),
+                    vec![],
+                    Default::default(),
+                )
+                .await?;
+        }
+        operator_helper
+            .publish(
+                EMBEDDED_CLIENT_ID.to_string(),
+                None,
+                false,
+                qos,
+                topic,
+                payload,
+                vec![],
+                Default::default(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// wait until Ctrl+C is pressed, then shut the broker down
+    pub async fn wait(self) -> Result<()> {
+        tokio::signal::ctrl_c().await?;
+        self.shutdown().await;
+        Ok(())
+    }
+
+    /// stop accepting connections, disconnect the clients with Server Shutting Down, let the
+    /// messages in flight drain for `shutdown_grace_secs`, sync the retained message store and
+    /// stop the subsystems in reverse dependency order
+    pub async fn shutdown(self) {
+        let grace = Duration::from_secs(
+            CONFIG
+                .get()
+                .unwrap()
+                .common
+                .shutdown_grace_secs
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+        );
+        let deadline = Instant::now() + grace;
+        info!("AxonMQ shutting down, grace period {:?}", grace);
+
+        let listeners: Vec<&str> = self.listeners.iter().map(|l| l.name()).collect();
+        self.supervisor.stop_only(&listeners);
+
+        if let Some((broker_helper, operator_helper)) = self.helpers.as_ref() {
+            if self.sparkplug_b
+                && let Err(e) = operator_helper.sparkplug_b_state_offline().await
+            {
+                warn!(
+                    "shutdown: failed to publish the sparkplug b offline state: {}",
+                    e
+                );
+            }
+
+            match broker_helper.shutdown_clients().await {
+                Ok(clients) => info!("shutdown: {} clients disconnected", clients),
+                Err(e) => warn!("shutdown: failed to disconnect the clients: {}", e),
+            }
+            // a connection is counted by its listener until its task ends
+            while Instant::now() < deadline
+                && listener::stats().iter().any(|stats| stats.active > 0)
+            {
+                time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+
+            while Instant::now() < deadline && operator_helper.pending() > 0 {
+                time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+            let pending = operator_helper.pending();
+            if pending > 0 {
+                warn!(
+                    "shutdown: grace period over, {} messages still in flight",
+                    pending
+                );
+            }
+
+            if let Err(e) = broker_helper.sync_retained().await {
+                warn!("shutdown: failed to sync retained messages: {}", e);
+            }
+        }
+
+        self.supervisor.stop();
+        info!("AxonMQ stopped.");
+    }
+}
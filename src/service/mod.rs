@@ -1,2 +1,3 @@
+pub mod replica;
 pub mod restful;
 pub mod sparkplug_b;
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::Client;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::CONFIG;
+use crate::error::AxonError;
+use crate::service::sparkplug_b::error::SpbError;
+use crate::utils::time::now_milliseconds;
+
+const DEFAULT_SYNC_INTERVAL_SECS: u64 = 5;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Default)]
+struct Snapshot {
+    groups: Vec<String>,
+    nodes: HashMap<String, Vec<JsonValue>>,
+    devices: HashMap<(String, String), Vec<JsonValue>>,
+    clients: Vec<JsonValue>,
+    client_details: HashMap<String, JsonValue>,
+    sessions: HashMap<String, JsonValue>,
+
+    synced_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ReplicaStatus {
+    pub source: String,
+    pub synced_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub groups: usize,
+    pub nodes: usize,
+    pub devices: usize,
+    pub clients: usize,
+}
+
+/// Read-only replica, mirrors the Sparkplug B state of another node through its RESTful API.
+pub struct Replica {
+    source: String,
+    sync_interval: u64,
+    client: Client,
+    snapshot: Arc<RwLock<Snapshot>>,
+}
+
+#[derive(Clone)]
+pub struct ReplicaHelper {
+    source: String,
+    snapshot: Arc<RwLock<Snapshot>>,
+}
+
+impl Replica {
+    pub fn new() -> Result<Self, AxonError> {
+        let config = CONFIG.get().unwrap().service.replica.as_ref().unwrap();
+        let client = Client::builder()
+            .timeout(Duration::from_secs(DEFAULT_TIMEOUT_SECS))
+            .build()?;
+
+        Ok(Replica {
+            source: config.source.trim_end_matches('/').to_string(),
+            sync_interval: config.sync_interval.unwrap_or(DEFAULT_SYNC_INTERVAL_SECS),
+            client,
+            snapshot: Arc::new(RwLock::new(Snapshot::default())),
+        })
+    }
+
+    pub fn helper(&self) -> ReplicaHelper {
+        ReplicaHelper {
+            source: self.source.clone(),
+            snapshot: self.snapshot.clone(),
+        }
+    }
+
+    pub fn run(&self) -> JoinHandle<()> {
+        let source = self.source.clone();
+        let client = self.client.clone();
+        let snapshot = self.snapshot.clone();
+        let mut sync_tk = tokio::time::interval(Duration::from_secs(self.sync_interval));
+        sync_tk.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        info!(
+            "replica of {}, sync interval {}s",
+            source, self.sync_interval
+        );
+
+        tokio::spawn(async move {
+            loop {
+                sync_tk.tick().await;
+                let synced = {
+                    let previous = snapshot.read().await;
+                    Self::sync(&client, &source, &previous).await
+                };
+                match synced {
+                    Ok(mut new_snapshot) => {
+                        debug!(
+                            "replica synced, groups: {}, nodes: {}, clients: {}",
+                            new_snapshot.groups.len(),
+                            new_snapshot.nodes.values().map(|n| n.len()).sum::<usize>(),
+                            new_snapshot.clients.len()
+                        );
+                        new_snapshot.synced_at = Some(now_milliseconds());
+                        *snapshot.write().await = new_snapshot;
+                    }
+                    Err(e) => {
+                        warn!("replica sync from {} failed: {}", source, e);
+                        snapshot.write().await.last_error = Some(e.to_string());
+                    }
+                }
+            }
+        })
+    }
+
+    /// pulls a new snapshot, what a group, node or client fails to answer is kept from the
+    /// previous one so that one failing request does not empty the replica
+    async fn sync(
+        client: &Client,
+        source: &str,
+        previous: &Snapshot,
+    ) -> Result<Snapshot, AxonError> {
+        let base = format!("{}/api/v1/services/sparkplug_b/groups", source);
+        let mut snapshot = Snapshot::default();
+
+        let groups: Vec<String> = get(client, &base).await?;
+
+        for group_id in groups.iter() {
+            let group_url = format!("{}/{}", base, encode(group_id));
+            let nodes: Vec<JsonValue> = match get(client, &format!("{}/nodes", group_url)).await {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    warn!("replica failed to sync group {}: {}", group_id, e);
+                    snapshot.last_error = Some(e.to_string());
+                    if let Some(nodes) = previous.nodes.get(group_id) {
+                        snapshot.nodes.insert(group_id.clone(), nodes.clone());
+                    }
+                    snapshot.devices.extend(
+                        previous
+                            .devices
+                            .iter()
+                            .filter(|((group, _), _)| group == group_id)
+                            .map(|(key, devices)| (key.clone(), devices.clone())),
+                    );
+                    continue;
+                }
+            };
+
+            for node_id in nodes.iter().filter_map(|n| n["node_id"].as_str()) {
+                let key = (group_id.clone(), node_id.to_string());
+                let url = format!("{}/nodes/{}/devices", group_url, encode(node_id));
+                match get::<Vec<JsonValue>>(client, &url).await {
+                    Ok(devices) => {
+                        snapshot.devices.insert(key, devices);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "replica failed to sync node {}/{}: {}",
+                            group_id, node_id, e
+                        );
+                        snapshot.last_error = Some(e.to_string());
+                        if let Some(devices) = previous.devices.get(&key) {
+                            snapshot.devices.insert(key, devices.clone());
+                        }
+                    }
+                }
+            }
+
+            snapshot.nodes.insert(group_id.clone(), nodes);
+        }
+        snapshot.groups = groups;
+
+        Self::sync_clients(client, source, previous, &mut snapshot).await;
+
+        Ok(snapshot)
+    }
+
+    /// the connected clients and the persisted sessions of the source
+    async fn sync_clients(
+        client: &Client,
+        source: &str,
+        previous: &Snapshot,
+        snapshot: &mut Snapshot,
+    ) {
+        let base = format!("{}/api/v1/clients", source);
+        let clients: Vec<JsonValue> = match get(client, &base).await {
+            Ok(clients) => clients,
+            Err(e) => {
+                warn!("replica failed to sync the clients: {}", e);
+                snapshot.last_error = Some(e.to_string());
+                snapshot.clients = previous.clients.clone();
+                snapshot.client_details = previous.client_details.clone();
+                snapshot.sessions = previous.sessions.clone();
+                return;
+            }
+        };
+
+        for client_id in clients.iter().filter_map(|c| c["client_id"].as_str()) {
+            let client_url = format!("{}/{}", base, encode(client_id));
+            let synced = async {
+                let detail: JsonValue = get(client, &client_url).await?;
+                let session: JsonValue = get(client, &format!("{}/session", client_url)).await?;
+                Ok::<_, AxonError>((detail, session))
+            }
+            .await;
+            match synced {
+                Ok((detail, session)) => {
+                    snapshot
+                        .client_details
+                        .insert(client_id.to_string(), detail);
+                    snapshot.sessions.insert(client_id.to_string(), session);
+                }
+                Err(e) => {
+                    debug!("replica failed to sync client {}: {}", client_id, e);
+                    if let (Some(detail), Some(session)) = (
+                        previous.client_details.get(client_id),
+                        previous.sessions.get(client_id),
+                    ) {
+                        snapshot
+                            .client_details
+                            .insert(client_id.to_string(), detail.clone());
+                        snapshot
+                            .sessions
+                            .insert(client_id.to_string(), session.clone());
+                    }
+                }
+            }
+        }
+        snapshot.clients = clients;
+    }
+}
+
+impl ReplicaHelper {
+    pub async fn status(&self) -> ReplicaStatus {
+        let snapshot = self.snapshot.read().await;
+        ReplicaStatus {
+            source: self.source.clone(),
+            synced_at: snapshot.synced_at,
+            last_error: snapshot.last_error.clone(),
+            groups: snapshot.groups.len(),
+            nodes: snapshot.nodes.values().map(|n| n.len()).sum(),
+            devices: snapshot.devices.values().map(|d| d.len()).sum(),
+            clients: snapshot.clients.len(),
+        }
+    }
+
+    pub async fn get_groups(&self, group: Option<String>) -> Result<Vec<String>, AxonError> {
+        let snapshot = self.snapshot.read().await;
+        match group {
+            Some(group) => snapshot
+                .groups
+                .iter()
+                .find(|g| **g == group)
+                .map(|g| vec![g.clone()])
+                .ok_or(SpbError::GroupNotFound.into()),
+            None => Ok(snapshot.groups.clone()),
+        }
+    }
+
+    pub async fn get_nodes(
+        &self,
+        group_id: String,
+        node_id: Option<String>,
+    ) -> Result<Vec<JsonValue>, AxonError> {
+        let snapshot = self.snapshot.read().await;
+        let nodes = snapshot
+            .nodes
+            .get(&group_id)
+            .ok_or(SpbError::GroupNotFound)?;
+        match node_id {
+            Some(node_id) => nodes
+                .iter()
+                .find(|n| n["node_id"].as_str() == Some(node_id.as_str()))
+                .map(|n| vec![n.clone()])
+                .ok_or(SpbError::NodeNotFound.into()),
+            None => Ok(nodes.clone()),
+        }
+    }
+
+    pub async fn get_devices(
+        &self,
+        group_id: String,
+        node_id: String,
+        device: Option<String>,
+    ) -> Result<Vec<JsonValue>, AxonError> {
+        let snapshot = self.snapshot.read().await;
+        let devices = snapshot
+            .devices
+            .get(&(group_id, node_id))
+            .ok_or(SpbError::NodeNotFound)?;
+        match device {
+            Some(device) => devices
+                .iter()
+                .find(|d| d["device"].as_str() == Some(device.as_str()))
+                .map(|d| vec![d.clone()])
+                .ok_or(SpbError::DeviceNotFound.into()),
+            None => Ok(devices.clone()),
+        }
+    }
+
+    pub async fn get_clients(&self, group: Option<&String>) -> Vec<JsonValue> {
+        let snapshot = self.snapshot.read().await;
+        snapshot
+            .clients
+            .iter()
+            .filter(|c| {
+                group.is_none_or(|group| {
+                    c["groups"]
+                        .as_array()
+                        .is_some_and(|groups| groups.iter().any(|g| g.as_str() == Some(group)))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub async fn get_client(&self, client_id: &str) -> Option<JsonValue> {
+        self.snapshot
+            .read()
+            .await
+            .client_details
+            .get(client_id)
+            .cloned()
+    }
+
+    pub async fn get_session(&self, client_id: &str) -> Option<JsonValue> {
+        self.snapshot.read().await.sessions.get(client_id).cloned()
+    }
+}
+
+async fn get<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T, AxonError> {
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string()
+}
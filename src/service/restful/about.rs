@@ -0,0 +1,23 @@
+use serde_json::json;
+use warp::Filter;
+
+use crate::CONFIG;
+use crate::features;
+
+pub async fn get_about() -> Result<impl warp::Reply, warp::Rejection> {
+    let config = CONFIG.get().unwrap();
+    Ok(warp::reply::json(&json!({
+        "name": "AxonMQ",
+        "version": env!("CARGO_PKG_VERSION"),
+        "node_id": config.node.id,
+        "profile": config.profile,
+        "features": features::list(),
+    })))
+}
+
+pub(crate) fn about_routers()
+-> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "about"))
+        .and_then(get_about)
+}
@@ -0,0 +1,53 @@
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::logging::LogControl;
+
+use super::error::ApiError;
+
+/// body of `PUT /api/v1/admin/log-level`, a target without a level drops its override
+#[derive(Debug, Deserialize)]
+pub struct LogLevelUpdate {
+    pub target: Option<String>,
+    pub level: Option<String>,
+}
+
+fn control(log_control: Option<LogControl>) -> Result<LogControl, warp::Rejection> {
+    // an embedding application may not have installed a reloadable filter
+    log_control.ok_or_else(|| warp::reject::custom(ApiError::LogControlUnavailable))
+}
+
+pub async fn get_log_level(
+    log_control: Option<LogControl>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&control(log_control)?.levels()))
+}
+
+pub async fn put_log_level(
+    update: LogLevelUpdate,
+    log_control: Option<LogControl>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let levels = control(log_control)?
+        .set(update.target, update.level)
+        .map_err(ApiError::InvalidLogLevel)?;
+    Ok(warp::reply::json(&levels))
+}
+
+pub(crate) fn admin_routers(
+    log_control: Option<LogControl>,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let with_log_control = warp::any().map(move || log_control.clone());
+
+    let api_get_log_level = warp::get()
+        .and(warp::path!("api" / "v1" / "admin" / "log-level"))
+        .and(with_log_control.clone())
+        .and_then(get_log_level);
+
+    let api_put_log_level = warp::put()
+        .and(warp::path!("api" / "v1" / "admin" / "log-level"))
+        .and(warp::body::json())
+        .and(with_log_control)
+        .and_then(put_log_level);
+
+    api_get_log_level.or(api_put_log_level)
+}
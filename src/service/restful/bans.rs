@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::mqtt::ban::{self, BanTarget};
+use crate::mqtt::helper::BrokerHelper;
+
+use super::error::ApiError;
+
+use super::with_broker_helper;
+
+/// body of `POST /api/v1/bans`, `{"ip": "10.0.0.0/24"}` or `{"client_id": "sensor-01"}`
+#[derive(Debug, Deserialize)]
+pub struct BanCreate {
+    #[serde(flatten)]
+    pub target: BanTarget,
+    // banned until removed when not set
+    pub ttl_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+fn target_param(query: &HashMap<String, String>) -> Result<BanTarget, ApiError> {
+    match (query.get("ip"), query.get("client_id")) {
+        (Some(ip), None) => ban::parse_ip_or_net(ip)
+            .map(BanTarget::Ip)
+            .map_err(ApiError::InvalidBan),
+        (None, Some(client_id)) => Ok(BanTarget::ClientId(client_id.clone())),
+        _ => Err(ApiError::InvalidBan(
+            "one of ip or client_id is required".to_string(),
+        )),
+    }
+}
+
+pub async fn get_bans() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&ban::list()))
+}
+
+pub async fn create_ban(
+    create: BanCreate,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let ban = ban::ban(
+        create.target,
+        create.ttl_secs,
+        create.reason.unwrap_or_else(|| "api".to_string()),
+    );
+    // connected clients of a banned address are left to the kick endpoint
+    if let BanTarget::ClientId(client_id) = &ban.target {
+        broker_helper.kick_client(client_id).await.ok();
+    }
+    Ok(warp::reply::json(&ban))
+}
+
+pub async fn delete_ban(
+    query: HashMap<String, String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let target = target_param(&query)?;
+    if !ban::unban(&target) {
+        return Err(ApiError::BanNotFound.into());
+    }
+    Ok(warp::reply::json(&target))
+}
+
+pub(crate) fn bans_routers(
+    broker_helper: BrokerHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_bans = warp::get()
+        .and(warp::path!("api" / "v1" / "bans"))
+        .and_then(get_bans);
+
+    let api_create_ban = warp::post()
+        .and(warp::path!("api" / "v1" / "bans"))
+        .and(warp::body::json())
+        .and(with_broker_helper(broker_helper))
+        .and_then(create_ban);
+
+    let api_delete_ban = warp::delete()
+        .and(warp::path!("api" / "v1" / "bans"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(delete_ban);
+
+    api_get_bans.or(api_create_ban).or(api_delete_ban)
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use warp::Filter;
+
+use crate::config::chain::{ErrorPolicy, ProcessorStep};
+use crate::operator::chain::{ChainInfo, ProcessorStatsInfo};
+use crate::operator::helper::Helper as OperatorHelper;
+
+use super::error::ApiError;
+use super::routes::SwitchState;
+
+use super::{decode_param, with_operator_helper};
+
+#[derive(Debug, Deserialize)]
+pub struct ChainUpdate {
+    // a uuid, or a list of uuids run together
+    pub processors: Vec<ProcessorStep>,
+    #[serde(default = "default_delivery")]
+    pub delivery: bool,
+    // the policy of an existing chain is kept when not set
+    pub on_error: Option<ErrorPolicy>,
+    pub canary_percent: Option<u8>,
+}
+
+fn default_delivery() -> bool {
+    true
+}
+
+/// a processor of a chain version, as listed by `/api/v1/chains/stats`
+#[derive(Debug, Serialize)]
+pub struct ProcessorStatsRow<'a> {
+    pub chain: &'a str,
+    pub version: u32,
+    pub canary: bool,
+    #[serde(flatten)]
+    pub stats: &'a ProcessorStatsInfo,
+}
+
+/// the processors of the stable and canary versions of every chain
+pub fn processor_stats(chains: &[ChainInfo]) -> Vec<ProcessorStatsRow<'_>> {
+    let mut rows = vec![];
+    for chain in chains {
+        let versions = std::iter::once((&chain.stable, false))
+            .chain(chain.canary.iter().map(|canary| (&canary.version, true)));
+        for (version, canary) in versions {
+            rows.extend(
+                version
+                    .processor_stats
+                    .iter()
+                    .map(|stats| ProcessorStatsRow {
+                        chain: &chain.name,
+                        version: version.version,
+                        canary,
+                        stats,
+                    }),
+            );
+        }
+    }
+    rows
+}
+
+pub async fn get_chains(
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .list_chains()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_chain_stats(
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let chains = operator_helper
+        .list_chains()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&processor_stats(&chains)))
+}
+
+pub async fn update_chain(
+    name: String,
+    update: ChainUpdate,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .update_chain(
+            name,
+            update.processors,
+            update.delivery,
+            update.on_error,
+            update.canary_percent,
+        )
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn delete_chain(
+    name: String,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .delete_chain(name)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn promote_canary(
+    name: String,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .promote_canary(name)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn rollback_canary(
+    name: String,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .rollback_canary(name)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn switch_chain(
+    name: String,
+    state: SwitchState,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .switch_chain(name, state.enabled, state.schedule)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub(crate) fn chains_routers(
+    operator_helper: OperatorHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_chains = warp::get()
+        .and(warp::path!("api" / "v1" / "chains"))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(get_chains);
+
+    let api_get_chain_stats = warp::get()
+        .and(warp::path!("api" / "v1" / "chains" / "stats"))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(get_chain_stats);
+
+    let api_update_chain = warp::put()
+        .and(warp::path!("api" / "v1" / "chains" / String))
+        .map(|name: String| decode_param(&name))
+        .and(warp::body::json())
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(update_chain);
+
+    let api_delete_chain = warp::delete()
+        .and(warp::path!("api" / "v1" / "chains" / String))
+        .map(|name: String| decode_param(&name))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(delete_chain);
+
+    let api_promote_canary = warp::post()
+        .and(warp::path!("api" / "v1" / "chains" / String / "promote"))
+        .map(|name: String| decode_param(&name))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(promote_canary);
+
+    let api_rollback_canary = warp::delete()
+        .and(warp::path!("api" / "v1" / "chains" / String / "canary"))
+        .map(|name: String| decode_param(&name))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(rollback_canary);
+
+    let api_switch_chain = warp::put()
+        .and(warp::path!("api" / "v1" / "chains" / String / "state"))
+        .map(|name: String| decode_param(&name))
+        .and(warp::body::json())
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(switch_chain);
+
+    api_get_chains
+        .or(api_get_chain_stats)
+        .or(api_update_chain)
+        .or(api_delete_chain)
+        .or(api_promote_canary)
+        .or(api_rollback_canary)
+        .or(api_switch_chain)
+}
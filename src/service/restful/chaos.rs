@@ -0,0 +1,41 @@
+use warp::Filter;
+
+use crate::chaos::{self, ChaosConfig};
+use crate::features::Feature;
+
+use super::error::ApiError;
+
+pub async fn get_chaos() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&chaos::info()))
+}
+
+pub async fn set_chaos(config: ChaosConfig) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(reason) = Feature::Chaos.unavailable() {
+        return Err(ApiError::FeatureUnavailable(reason).into());
+    }
+    let info = chaos::set(config).map_err(ApiError::InvalidChaos)?;
+    Ok(warp::reply::json(&info))
+}
+
+pub async fn reset_chaos() -> Result<impl warp::Reply, warp::Rejection> {
+    let info = chaos::set(ChaosConfig::default()).map_err(ApiError::InvalidChaos)?;
+    Ok(warp::reply::json(&info))
+}
+
+pub(crate) fn chaos_routers()
+-> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_chaos = warp::get()
+        .and(warp::path!("api" / "v1" / "chaos"))
+        .and_then(get_chaos);
+
+    let api_set_chaos = warp::put()
+        .and(warp::path!("api" / "v1" / "chaos"))
+        .and(warp::body::json())
+        .and_then(set_chaos);
+
+    let api_reset_chaos = warp::delete()
+        .and(warp::path!("api" / "v1" / "chaos"))
+        .and_then(reset_chaos);
+
+    api_get_chaos.or(api_set_chaos).or(api_reset_chaos)
+}
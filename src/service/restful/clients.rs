@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use warp::Filter;
+
+use crate::mqtt::command::KickError;
+use crate::mqtt::helper::BrokerHelper;
+
+use super::error::ApiError;
+
+use super::{decode_param, with_broker_helper};
+
+pub async fn get_clients(
+    query: HashMap<String, String>,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut result = broker_helper.list_clients().await.map_err(ApiError::from)?;
+    if let Some(group) = query.get("group") {
+        result.retain(|client| client.groups.contains(group));
+    }
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_client(
+    client_id: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = broker_helper
+        .get_client(&client_id)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::ClientNotFound)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_client_session(
+    client_id: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = broker_helper
+        .get_session(&client_id)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::ClientNotFound)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn purge_client_queue(
+    client_id: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let purged = broker_helper
+        .purge_queue(&client_id)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::ClientNotFound)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "client_id": client_id,
+        "purged": purged,
+    })))
+}
+
+pub async fn resend_client_inflight(
+    client_id: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let resent = broker_helper
+        .resend_inflight(&client_id)
+        .await
+        .map_err(ApiError::from)?
+        .map_err(|e| match e {
+            KickError::NotFound => ApiError::ClientNotFound,
+            KickError::NotConnected => ApiError::ClientNotConnected,
+        })?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "client_id": client_id,
+        "resent": resent,
+    })))
+}
+
+pub async fn kick_client(
+    client_id: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    broker_helper
+        .kick_client(&client_id)
+        .await
+        .map_err(ApiError::from)?
+        .map_err(|e| match e {
+            KickError::NotFound => ApiError::ClientNotFound,
+            KickError::NotConnected => ApiError::ClientNotConnected,
+        })?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "client_id": client_id,
+    })))
+}
+
+pub async fn tag_client(
+    client_id: String,
+    group: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !broker_helper
+        .tag_client(&client_id, &group, true)
+        .await
+        .map_err(ApiError::from)?
+    {
+        return Err(ApiError::ClientNotFound.into());
+    }
+    Ok(warp::reply::json(&serde_json::json!({
+        "client_id": client_id,
+        "group": group,
+    })))
+}
+
+pub async fn untag_client(
+    client_id: String,
+    group: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !broker_helper
+        .tag_client(&client_id, &group, false)
+        .await
+        .map_err(ApiError::from)?
+    {
+        return Err(ApiError::ClientNotFound.into());
+    }
+    Ok(warp::reply::json(&serde_json::json!({
+        "client_id": client_id,
+        "group": group,
+    })))
+}
+
+pub(crate) fn clients_routers(
+    broker_helper: BrokerHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_clients = warp::get()
+        .and(warp::path!("api" / "v1" / "clients"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_clients);
+
+    let api_get_client = warp::get()
+        .and(warp::path!("api" / "v1" / "clients" / String))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_client);
+
+    let api_get_client_session = warp::get()
+        .and(warp::path!("api" / "v1" / "clients" / String / "session"))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_client_session);
+
+    let api_purge_client_queue = warp::delete()
+        .and(warp::path!("api" / "v1" / "clients" / String / "queue"))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(purge_client_queue);
+
+    let api_resend_client_inflight = warp::post()
+        .and(warp::path!("api" / "v1" / "clients" / String / "resend"))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(resend_client_inflight);
+
+    let api_kick_client = warp::post()
+        .and(warp::path!("api" / "v1" / "clients" / String / "kick"))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(kick_client);
+
+    let api_tag_client = warp::put()
+        .and(warp::path!(
+            "api" / "v1" / "clients" / String / "groups" / String
+        ))
+        .map(|client_id: String, group: String| (decode_param(&client_id), decode_param(&group)))
+        .untuple_one()
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(tag_client);
+
+    let api_untag_client = warp::delete()
+        .and(warp::path!(
+            "api" / "v1" / "clients" / String / "groups" / String
+        ))
+        .map(|client_id: String, group: String| (decode_param(&client_id), decode_param(&group)))
+        .untuple_one()
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(untag_client);
+
+    api_get_clients
+        .or(api_get_client)
+        .or(api_get_client_session)
+        .or(api_purge_client_queue)
+        .or(api_resend_client_inflight)
+        .or(api_kick_client)
+        .or(api_tag_client)
+        .or(api_untag_client)
+}
@@ -1,12 +1,38 @@
 use warp::http::StatusCode;
 
 use crate::error::AxonError;
+use crate::mqtt::error::MqttProtocolError;
+use crate::operator::error::OperatorError;
 use crate::service::sparkplug_b::error::SpbError;
 
 #[derive(Debug)]
 pub enum ApiError {
     InternalError(String),
     SparkPlugBError(StatusCode, String),
+    ReadOnly,
+    ClientNotFound,
+    ClientNotConnected,
+    InvalidBan(String),
+    BanNotFound,
+    InvalidTopicFilter,
+    RetainedNotFound,
+    ChainNotFound,
+    ProcessorNotFound(String),
+    ProcessorExists(String),
+    ProcessorInUse(String),
+    InvalidProcessor(String),
+    CanaryNotFound,
+    InvalidCanaryPercent,
+    InvalidErrorPolicy(String),
+    RouteNotFound,
+    InvalidSchedule(String),
+    InvalidRouteCondition(String),
+    FeatureUnavailable(String),
+    WebSocketRequired,
+    InvalidLogLevel(String),
+    LogControlUnavailable,
+    #[cfg(feature = "chaos")]
+    InvalidChaos(String),
 }
 
 impl warp::reject::Reject for ApiError {}
@@ -16,7 +42,7 @@ impl From<AxonError> for ApiError {
         use SpbError::*;
         match err {
             AxonError::SparkPlugBError(e) => match e {
-                NodeNotFound | GroupNotFound => {
+                NodeNotFound | GroupNotFound | DeviceNotFound | MetricNotFound => {
                     ApiError::SparkPlugBError(StatusCode::NOT_FOUND, format!("{}", e))
                 }
                 _ => ApiError::SparkPlugBError(StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)),
@@ -25,3 +51,32 @@ impl From<AxonError> for ApiError {
         }
     }
 }
+
+impl From<MqttProtocolError> for ApiError {
+    fn from(err: MqttProtocolError) -> Self {
+        match err {
+            MqttProtocolError::InvalidTopicFilter => ApiError::InvalidTopicFilter,
+            _ => ApiError::InternalError(format!("{}", err)),
+        }
+    }
+}
+
+impl From<OperatorError> for ApiError {
+    fn from(err: OperatorError) -> Self {
+        match err {
+            OperatorError::ChainNotFound => ApiError::ChainNotFound,
+            OperatorError::ProcessorNotFound(id) => ApiError::ProcessorNotFound(id),
+            OperatorError::ProcessorExists(id) => ApiError::ProcessorExists(id),
+            OperatorError::ProcessorInUse(chains) => ApiError::ProcessorInUse(chains),
+            OperatorError::InvalidProcessor(msg) => ApiError::InvalidProcessor(msg),
+            OperatorError::CanaryNotFound => ApiError::CanaryNotFound,
+            OperatorError::InvalidCanaryPercent(_) => ApiError::InvalidCanaryPercent,
+            OperatorError::InvalidErrorPolicy(msg) => ApiError::InvalidErrorPolicy(msg),
+            OperatorError::RouteNotFound => ApiError::RouteNotFound,
+            OperatorError::InvalidSchedule(msg) => ApiError::InvalidSchedule(msg),
+            OperatorError::InvalidTopicFilter(_) => ApiError::InvalidTopicFilter,
+            OperatorError::InvalidRouteCondition(msg) => ApiError::InvalidRouteCondition(msg),
+            _ => ApiError::InternalError(format!("{}", err)),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::mqtt::helper::BrokerHelper;
+
+use super::error::ApiError;
+
+use super::{decode_param, with_broker_helper};
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimit {
+    pub publish_rate: Option<u32>,
+}
+
+pub async fn get_groups(broker_helper: BrokerHelper) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = broker_helper.list_groups().await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn kick_group(
+    group: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let kicked = broker_helper
+        .kick_group(&group)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "group": group,
+        "kicked": kicked,
+    })))
+}
+
+pub async fn set_rate_limit(
+    group: String,
+    limit: RateLimit,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let updated = broker_helper
+        .set_group_rate(&group, limit.publish_rate)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "group": group,
+        "publish_rate": limit.publish_rate,
+        "updated": updated,
+    })))
+}
+
+pub(crate) fn groups_routers(
+    broker_helper: BrokerHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_groups = warp::get()
+        .and(warp::path!("api" / "v1" / "client_groups"))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_groups);
+
+    let api_kick_group = warp::post()
+        .and(warp::path!(
+            "api" / "v1" / "client_groups" / String / "kick"
+        ))
+        .map(|group: String| decode_param(&group))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(kick_group);
+
+    let api_set_rate_limit = warp::put()
+        .and(warp::path!(
+            "api" / "v1" / "client_groups" / String / "rate_limit"
+        ))
+        .map(|group: String| decode_param(&group))
+        .and(warp::body::json())
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(set_rate_limit);
+
+    api_get_groups.or(api_kick_group).or(api_set_rate_limit)
+}
@@ -0,0 +1,14 @@
+use warp::Filter;
+
+use crate::mqtt::listener;
+
+pub async fn get_listeners() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&listener::stats()))
+}
+
+pub(crate) fn listeners_routers()
+-> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "listeners"))
+        .and_then(get_listeners)
+}
@@ -0,0 +1,232 @@
+//! Prometheus text exposition of the chain, processor, offline queue and spool counters,
+//! `GET /metrics`
+
+use std::fmt::Write;
+
+use warp::Filter;
+
+use crate::mqtt::{listener, offline_queue, slow_consumer};
+use crate::operator::chain::ChainInfo;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::spool::{self, SpoolDepth};
+
+use super::chains::processor_stats;
+use super::error::ApiError;
+use super::with_operator_helper;
+
+// name, type, help and value of a series by spool
+type SpoolSeries = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&SpoolDepth) -> u64,
+);
+
+// a label value with backslashes, quotes and newlines escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(chains: &[ChainInfo]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP axonmq_chain_messages_total Messages that went through a chain version, by outcome.\n");
+    out.push_str("# TYPE axonmq_chain_messages_total counter\n");
+    for chain in chains {
+        let versions = std::iter::once((&chain.stable, false))
+            .chain(chain.canary.iter().map(|canary| (&canary.version, true)));
+        for (version, canary) in versions {
+            let stats = &version.stats;
+            for (outcome, value) in [
+                ("passed", stats.passed),
+                ("dropped", stats.dropped),
+                ("failed", stats.failed),
+            ] {
+                writeln!(
+                    out,
+                    "axonmq_chain_messages_total{{chain=\"{}\",version=\"{}\",canary=\"{}\",outcome=\"{}\"}} {}",
+                    label(&chain.name),
+                    version.version,
+                    canary,
+                    outcome,
+                    value
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    let rows = processor_stats(chains);
+    out.push_str(
+        "# HELP axonmq_processor_calls_total Processor calls in a chain version, by outcome.\n",
+    );
+    out.push_str("# TYPE axonmq_processor_calls_total counter\n");
+    for row in &rows {
+        let labels = format!(
+            "chain=\"{}\",version=\"{}\",canary=\"{}\",processor=\"{}\"",
+            label(row.chain),
+            row.version,
+            row.canary,
+            row.stats.processor
+        );
+        for (outcome, value) in [
+            ("passed", row.stats.passed),
+            ("dropped", row.stats.dropped),
+            ("failed", row.stats.failed),
+        ] {
+            writeln!(
+                out,
+                "axonmq_processor_calls_total{{{},outcome=\"{}\"}} {}",
+                labels, outcome, value
+            )
+            .unwrap();
+        }
+    }
+
+    out.push_str("# HELP axonmq_processor_latency_seconds Time a processor took for a message.\n");
+    out.push_str("# TYPE axonmq_processor_latency_seconds histogram\n");
+    for row in &rows {
+        let labels = format!(
+            "chain=\"{}\",version=\"{}\",canary=\"{}\",processor=\"{}\"",
+            label(row.chain),
+            row.version,
+            row.canary,
+            row.stats.processor
+        );
+        for bucket in &row.stats.latency {
+            let le = bucket
+                .le_us
+                .map_or("+Inf".to_string(), |us| (us as f64 / 1e6).to_string());
+            writeln!(
+                out,
+                "axonmq_processor_latency_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, bucket.count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "axonmq_processor_latency_seconds_sum{{{}}} {}",
+            labels,
+            row.stats.total_latency_us as f64 / 1e6
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "axonmq_processor_latency_seconds_count{{{}}} {}",
+            labels, row.stats.invocations
+        )
+        .unwrap();
+    }
+
+    out.push_str("# HELP axonmq_offline_messages_dropped_total Messages for offline clients dropped by the store overflow policy.\n");
+    out.push_str("# TYPE axonmq_offline_messages_dropped_total counter\n");
+    writeln!(
+        out,
+        "axonmq_offline_messages_dropped_total {}",
+        offline_queue::dropped_total()
+    )
+    .unwrap();
+    out.push_str("# HELP axonmq_offline_messages_expired_total Messages for offline clients removed when their expiry interval elapsed.\n");
+    out.push_str("# TYPE axonmq_offline_messages_expired_total counter\n");
+    writeln!(
+        out,
+        "axonmq_offline_messages_expired_total {}",
+        offline_queue::expired_total()
+    )
+    .unwrap();
+    for (name, help, value) in [
+        (
+            "axonmq_slow_consumer_events_total",
+            "Messages delivered to a client whose queue was full.",
+            slow_consumer::events_total(),
+        ),
+        (
+            "axonmq_slow_consumer_dropped_total",
+            "Messages for slow clients dropped by the slow consumer policy.",
+            slow_consumer::dropped_total(),
+        ),
+        (
+            "axonmq_slow_consumer_buffered_total",
+            "Messages for slow clients kept in their store queue.",
+            slow_consumer::buffered_total(),
+        ),
+        (
+            "axonmq_slow_consumer_disconnects_total",
+            "Slow clients disconnected by the slow consumer policy.",
+            slow_consumer::disconnects_total(),
+        ),
+        (
+            "axonmq_spill_messages_dropped_total",
+            "Queued messages dropped because the spill segment of their connection was full.",
+            listener::spill_dropped_total(),
+        ),
+    ] {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+
+    let spools = spool::depths();
+    let series: [SpoolSeries; 3] = [
+        (
+            "axonmq_spool_messages",
+            "gauge",
+            "Messages kept on disk until their remote is reachable again.",
+            |depth| depth.messages,
+        ),
+        (
+            "axonmq_spool_bytes",
+            "gauge",
+            "Bytes kept on disk until their remote is reachable again.",
+            |depth| depth.bytes,
+        ),
+        (
+            "axonmq_spool_dropped_total",
+            "counter",
+            "Messages dropped because their spool was full.",
+            |depth| depth.dropped,
+        ),
+    ];
+    for (name, kind, help, value) in series {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for depth in &spools {
+            writeln!(
+                out,
+                "{}{{spool=\"{}\"}} {}",
+                name,
+                label(&depth.name),
+                value(depth)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+pub async fn get_metrics(
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let chains = operator_helper
+        .list_chains()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::with_header(
+        render(&chains),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+pub(crate) fn metrics_routers(
+    operator_helper: OperatorHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("metrics"))
+        .and(with_operator_helper(operator_helper))
+        .and_then(get_metrics)
+}
@@ -1,19 +1,72 @@
+mod about;
+mod admin;
+mod bans;
+mod chains;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod clients;
 mod error;
+mod groups;
+mod listeners;
+mod metrics;
+mod openapi;
+mod processors;
+mod readyz;
 mod rejection;
+mod replica;
+mod retained;
+mod routes;
 mod spb;
+mod spb_stream;
+mod trace;
 
+use std::convert::Infallible;
 use std::net::SocketAddr;
 
+use futures::future::{BoxFuture, FutureExt};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 use percent_encoding::percent_decode_str;
-use warp::{Filter, http::Uri};
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
+use warp::{Filter, Reply, http::Uri};
 
+use crate::features::Feature;
+use crate::logging::LogControl;
+use crate::mqtt::helper::BrokerHelper;
+use crate::mqtt::listener::TlsOptions;
+use crate::mqtt::listener::tcp::{TlsReloader, accept_tls, client_auth_mode};
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::service::replica::ReplicaHelper;
 use crate::service::sparkplug_b::in_helper::InHelper as SpbInHelper;
+use crate::supervisor::SupervisorHelper;
 
+use about::about_routers;
+use admin::admin_routers;
+use bans::bans_routers;
+use chains::chains_routers;
+#[cfg(feature = "chaos")]
+use chaos::chaos_routers;
+use clients::clients_routers;
+use error::ApiError;
+use groups::groups_routers;
+use listeners::listeners_routers;
+use metrics::metrics_routers;
+use openapi::openapi_routers;
+use processors::processors_routers;
+use readyz::readyz_routers;
 use rejection::handle_rejection;
+use replica::replica_routers;
+use retained::retained_routers;
+use routes::routes_routers;
 use spb::spb_routers;
+use spb_stream::spb_stream_routers;
+use trace::trace_routers;
 
 pub struct RESTful {
     server: SocketAddr,
+    tls: Option<TlsOptions>,
 }
 
 impl RESTful {
@@ -22,24 +75,24 @@ impl RESTful {
             .parse::<SocketAddr>()
             .map_err(|e| format!("invalid RESTful server address: {}", e))?;
 
-        Ok(Self { server })
+        Ok(Self { server, tls: None })
     }
 
-    pub async fn run(&self, spb_in_helper: Option<SpbInHelper>) {
-        let cors = warp::cors()
-            .allow_any_origin()
-            .allow_headers(vec![
-                "Origin",
-                "Access-Control-Request-Method",
-                "Access-Control-Request-Headers",
-                "Refer",
-                "User-Agent",
-                "X-Requested-With",
-            ])
-            .allow_header("Content-Type")
-            .allow_header("Cache-Control")
-            .expose_header("Access-Control-Allow-Origin")
-            .allow_methods(vec!["POST", "GET", "PUT", "DELETE"]);
+    /// serve over HTTPS
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn run(
+        &self,
+        broker_helper: BrokerHelper,
+        operator_helper: OperatorHelper,
+        spb_in_helper: Option<SpbInHelper>,
+        supervisor_helper: SupervisorHelper,
+        log_control: Option<LogControl>,
+    ) -> BoxFuture<'static, ()> {
+        let cors = Self::cors();
 
         let redirect_dashboard = warp::path::end().map(|| warp::redirect(Uri::from_static("/dh")));
         let dashboard = warp::path("dh").and(warp::fs::dir("dist"));
@@ -47,26 +100,240 @@ impl RESTful {
         if let Some(spb_in_helper) = spb_in_helper {
             let routers = redirect_dashboard
                 .or(dashboard)
+                .or(readyz_routers(supervisor_helper))
+                .or(about_routers())
+                .or(openapi_routers())
+                .or(admin_routers(log_control.clone()))
+                .or(clients_routers(broker_helper.clone()))
+                .or(bans_routers(broker_helper.clone()))
+                .or(groups_routers(broker_helper.clone()))
+                .or(listeners_routers())
+                .or(retained_routers(broker_helper))
+                .or(chains_routers(operator_helper.clone()))
+                .or(processors_routers(operator_helper.clone()))
+                .or(metrics_routers(operator_helper.clone()))
+                .or(routes_routers(operator_helper))
+                .or(chaos_routers())
+                .or(trace_routers())
+                .or(spb_stream_routers(spb_in_helper.clone()))
                 .or(spb_routers(spb_in_helper))
                 .with(cors)
                 .with(warp::log("axonmq::service::restful"))
+                .with(warp::log::custom(audit_call))
                 .recover(handle_rejection);
-            warp::serve(routers).run(self.server).await;
+            self.serve(routers)
         } else {
             let routers = redirect_dashboard
                 .or(dashboard)
+                .or(readyz_routers(supervisor_helper))
+                .or(about_routers())
+                .or(openapi_routers())
+                .or(admin_routers(log_control.clone()))
+                .or(clients_routers(broker_helper.clone()))
+                .or(bans_routers(broker_helper.clone()))
+                .or(groups_routers(broker_helper.clone()))
+                .or(listeners_routers())
+                .or(retained_routers(broker_helper))
+                .or(chains_routers(operator_helper.clone()))
+                .or(processors_routers(operator_helper.clone()))
+                .or(metrics_routers(operator_helper.clone()))
+                .or(routes_routers(operator_helper))
+                .or(chaos_routers())
+                .or(trace_routers())
+                .or(unavailable(
+                    warp::path!("api" / "v1" / "services" / "sparkplug_b" / ..),
+                    Feature::SparkplugB,
+                ))
+                .or(unavailable(
+                    warp::path!("api" / "v1" / "spb" / ..),
+                    Feature::SparkplugB,
+                ))
                 .with(cors)
                 .with(warp::log("axonmq::service::restful"))
+                .with(warp::log::custom(audit_call))
                 .recover(handle_rejection);
-            warp::serve(routers).run(self.server).await;
+            self.serve(routers)
         }
     }
+
+    /// serve the dashboard and read-only api from a replica snapshot
+    pub fn run_replica(
+        &self,
+        replica_helper: ReplicaHelper,
+        supervisor_helper: SupervisorHelper,
+    ) -> BoxFuture<'static, ()> {
+        let redirect_dashboard = warp::path::end().map(|| warp::redirect(Uri::from_static("/dh")));
+        let dashboard = warp::path("dh").and(warp::fs::dir("dist"));
+
+        let routers = redirect_dashboard
+            .or(dashboard)
+            .or(readyz_routers(supervisor_helper))
+            .or(about_routers())
+            .or(replica_routers(replica_helper))
+            .with(Self::cors())
+            .with(warp::log("axonmq::service::restful"))
+            .recover(handle_rejection);
+        self.serve(routers)
+    }
+
+    fn serve<F, R>(&self, routers: F) -> BoxFuture<'static, ()>
+    where
+        F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+    {
+        match self.tls.clone() {
+            Some(tls) => Self::serve_tls(self.server, tls, routers).boxed(),
+            None => warp::serve(routers).run(self.server).boxed(),
+        }
+    }
+
+    async fn serve_tls<F, R>(server: SocketAddr, tls: TlsOptions, routers: F)
+    where
+        F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+    {
+        let mut tls_config = match TlsReloader::new(tls.clone()) {
+            Ok(reloader) => reloader,
+            Err(e) => {
+                error!("failed to load RESTful TLS config: {}", e);
+                return;
+            }
+        };
+        let listener = match TcpListener::bind(server).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to bind RESTful server on {}: {}", server, e);
+                return;
+            }
+        };
+        info!(
+            "RESTful listening on https://{}, client certificate: {}",
+            server,
+            client_auth_mode(&tls)
+        );
+
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("RESTful accept error: {}", e);
+                    continue;
+                }
+            };
+            let tls_config = tls_config.config(coarsetime::Clock::now_since_epoch().as_secs());
+            let service = TowerToHyperService::new(warp::service(routers.clone()));
+            tokio::spawn(async move {
+                let tls_stream = match accept_tls(tls_config, stream).await {
+                    Ok((tls_stream, _)) => tls_stream,
+                    Err(e) => {
+                        info!(
+                            target: "axonmq::audit",
+                            event = "tls_handshake_failed",
+                            address = %addr,
+                            error = %e,
+                            "RESTful TLS handshake error"
+                        );
+                        return;
+                    }
+                };
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(tls_stream), service)
+                    .await
+                {
+                    debug!("RESTful connection error from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    fn cors() -> warp::cors::Builder {
+        warp::cors()
+            .allow_any_origin()
+            .allow_headers(vec![
+                "Origin",
+                "Access-Control-Request-Method",
+                "Access-Control-Request-Headers",
+                "Refer",
+                "User-Agent",
+                "X-Requested-With",
+            ])
+            .allow_header("Content-Type")
+            .allow_header("Cache-Control")
+            .expose_header("Access-Control-Allow-Origin")
+            .allow_methods(vec!["POST", "GET", "PUT", "DELETE"])
+    }
+}
+
+/// the calls changing something, for the audit trail
+fn audit_call(info: warp::log::Info) {
+    if matches!(
+        *info.method(),
+        warp::http::Method::GET | warp::http::Method::HEAD | warp::http::Method::OPTIONS
+    ) {
+        return;
+    }
+    tracing::info!(
+        target: "axonmq::audit",
+        event = "admin_call",
+        method = %info.method(),
+        path = info.path(),
+        status = info.status().as_u16(),
+        user_agent = info.user_agent().unwrap_or("-"),
+        "RESTful API call"
+    );
+}
+
+/// builds without the chaos feature explain why the chaos endpoints are missing
+#[cfg(not(feature = "chaos"))]
+fn chaos_routers() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+{
+    unavailable(warp::path!("api" / "v1" / "chaos"), Feature::Chaos)
+}
+
+/// answers the endpoints of a subsystem that is not running with the reason
+fn unavailable<F>(
+    path: F,
+    feature: Feature,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (), Error = warp::Rejection> + Clone,
+{
+    path.and_then(move || async move {
+        let reason = feature
+            .unavailable()
+            .unwrap_or_else(|| format!("{} is not enabled in the configuration", feature.name()));
+        Err::<String, _>(warp::reject::custom(ApiError::FeatureUnavailable(reason)))
+    })
 }
 
 pub fn decode_param(param: &str) -> String {
     percent_decode_str(param).decode_utf8().unwrap().to_string()
 }
 
+pub fn with_broker_helper(
+    broker_helper: BrokerHelper,
+) -> impl Filter<Extract = (BrokerHelper,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || broker_helper.clone())
+}
+
+pub fn with_operator_helper(
+    operator_helper: OperatorHelper,
+) -> impl Filter<Extract = (OperatorHelper,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || operator_helper.clone())
+}
+
+pub fn with_replica_helper(
+    replica_helper: ReplicaHelper,
+) -> impl Filter<Extract = (ReplicaHelper,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || replica_helper.clone())
+}
+
+pub fn with_supervisor_helper(
+    supervisor_helper: SupervisorHelper,
+) -> impl Filter<Extract = (SupervisorHelper,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || supervisor_helper.clone())
+}
+
 pub fn with_spb_in_helper(
     spb_in_helper: SpbInHelper,
 ) -> impl Filter<Extract = (SpbInHelper,), Error = std::convert::Infallible> + Clone {
@@ -0,0 +1,652 @@
+use std::sync::OnceLock;
+
+use serde_json::{Map, Value, json};
+use warp::Filter;
+
+/// one endpoint of the RESTful API, `{name}` in the path is a path parameter
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    // name and whether it is required
+    query: &'static [(&'static str, bool)],
+    // takes a JSON body
+    body: bool,
+}
+
+const fn op(
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+) -> Operation {
+    Operation {
+        method,
+        path,
+        tag,
+        summary,
+        query: &[],
+        body: false,
+    }
+}
+
+const fn with_body(mut operation: Operation) -> Operation {
+    operation.body = true;
+    operation
+}
+
+const fn with_query(mut operation: Operation, query: &'static [(&'static str, bool)]) -> Operation {
+    operation.query = query;
+    operation
+}
+
+// a path under the Sparkplug B groups
+macro_rules! spb {
+    ($path:literal) => {
+        concat!("/api/v1/services/sparkplug_b/groups", $path)
+    };
+}
+
+const HISTORY_QUERY: &[(&str, bool)] = &[
+    ("metric", true),
+    ("template", false),
+    ("since", false),
+    ("limit", false),
+];
+
+/// the endpoints served by `RESTful::run` and `RESTful::run_replica`, in the order of
+/// docs/http-api.md
+const OPERATIONS: &[Operation] = &[
+    op("get", spb!(""), "sparkplug_b", "Get all groups"),
+    op("get", spb!("/{group_id}"), "sparkplug_b", "Get a group"),
+    op(
+        "get",
+        spb!("/{group_id}/nodes"),
+        "sparkplug_b",
+        "Get the nodes of a group",
+    ),
+    op(
+        "get",
+        spb!("/{group_id}/nodes/{node_id}"),
+        "sparkplug_b",
+        "Get a node",
+    ),
+    with_body(op(
+        "put",
+        spb!("/{group_id}/nodes/{node_id}"),
+        "sparkplug_b",
+        "Set metrics on a node",
+    )),
+    op(
+        "get",
+        spb!("/{group_id}/nodes/{node_id}/devices"),
+        "sparkplug_b",
+        "Get the devices of a node",
+    ),
+    op(
+        "get",
+        spb!("/{group_id}/nodes/{node_id}/devices/{device_id}"),
+        "sparkplug_b",
+        "Get a device",
+    ),
+    with_body(op(
+        "put",
+        spb!("/{group_id}/nodes/{node_id}/devices/{device_id}"),
+        "sparkplug_b",
+        "Set metrics on a device",
+    )),
+    op(
+        "post",
+        spb!("/{group_id}/nodes/{node_id}/rebirth"),
+        "sparkplug_b",
+        "Ask a node to rebirth",
+    ),
+    with_query(
+        op(
+            "get",
+            spb!("/{group_id}/nodes/{node_id}/history"),
+            "sparkplug_b",
+            "Get the history of a node metric",
+        ),
+        HISTORY_QUERY,
+    ),
+    with_query(
+        op(
+            "get",
+            spb!("/{group_id}/nodes/{node_id}/devices/{device_id}/history"),
+            "sparkplug_b",
+            "Get the history of a device metric",
+        ),
+        HISTORY_QUERY,
+    ),
+    with_query(
+        op(
+            "get",
+            "/api/v1/spb/stream",
+            "sparkplug_b",
+            "Stream Sparkplug B events over a WebSocket",
+        ),
+        &[
+            ("group_id", false),
+            ("node_id", false),
+            ("device_id", false),
+            ("metric", false),
+        ],
+    ),
+    with_query(
+        op("get", "/api/v1/clients", "clients", "Get all clients"),
+        &[("group", false)],
+    ),
+    op(
+        "get",
+        "/api/v1/clients/{client_id}",
+        "clients",
+        "Get a client",
+    ),
+    op(
+        "get",
+        "/api/v1/clients/{client_id}/session",
+        "clients",
+        "Get the session of a client, with its queued and inflight messages",
+    ),
+    op(
+        "delete",
+        "/api/v1/clients/{client_id}/queue",
+        "clients",
+        "Drop the messages queued for a client",
+    ),
+    op(
+        "post",
+        "/api/v1/clients/{client_id}/resend",
+        "clients",
+        "Send the inflight messages of a client again",
+    ),
+    op(
+        "get",
+        "/api/v1/bans",
+        "clients",
+        "Get the banned addresses and clients",
+    ),
+    with_body(op(
+        "post",
+        "/api/v1/bans",
+        "clients",
+        "Ban an address, a network or a client id",
+    )),
+    with_query(
+        op("delete", "/api/v1/bans", "clients", "Lift a ban"),
+        &[("ip", false), ("client_id", false)],
+    ),
+    op(
+        "post",
+        "/api/v1/clients/{client_id}/kick",
+        "clients",
+        "Kick a client",
+    ),
+    op(
+        "put",
+        "/api/v1/clients/{client_id}/groups/{group}",
+        "clients",
+        "Add a client to a group",
+    ),
+    op(
+        "delete",
+        "/api/v1/clients/{client_id}/groups/{group}",
+        "clients",
+        "Remove a client from a group",
+    ),
+    op(
+        "get",
+        "/api/v1/client_groups",
+        "clients",
+        "Get all client groups",
+    ),
+    op(
+        "post",
+        "/api/v1/client_groups/{group}/kick",
+        "clients",
+        "Kick a client group",
+    ),
+    with_body(op(
+        "put",
+        "/api/v1/client_groups/{group}/rate_limit",
+        "clients",
+        "Set the publish rate limit of a group",
+    )),
+    op(
+        "get",
+        "/api/v1/listeners",
+        "broker",
+        "Get the listener counters",
+    ),
+    with_query(
+        op(
+            "get",
+            "/api/v1/retained",
+            "retained",
+            "List retained messages",
+        ),
+        &[("filter", false)],
+    ),
+    with_query(
+        op(
+            "delete",
+            "/api/v1/retained",
+            "retained",
+            "Delete the retained messages matching a filter",
+        ),
+        &[("filter", true)],
+    ),
+    op(
+        "get",
+        "/api/v1/retained/{topic}",
+        "retained",
+        "Get a retained message, the topic may span several path segments",
+    ),
+    op(
+        "delete",
+        "/api/v1/retained/{topic}",
+        "retained",
+        "Delete a retained message, the topic may span several path segments",
+    ),
+    op("get", "/api/v1/chains", "chains", "Get all chains"),
+    op(
+        "get",
+        "/api/v1/chains/stats",
+        "chains",
+        "Get the processor stats",
+    ),
+    with_body(op(
+        "put",
+        "/api/v1/chains/{name}",
+        "chains",
+        "Update a chain",
+    )),
+    op(
+        "delete",
+        "/api/v1/chains/{name}",
+        "chains",
+        "Delete a chain",
+    ),
+    op(
+        "post",
+        "/api/v1/chains/{name}/promote",
+        "chains",
+        "Promote the canary of a chain",
+    ),
+    op(
+        "delete",
+        "/api/v1/chains/{name}/canary",
+        "chains",
+        "Roll back the canary of a chain",
+    ),
+    with_body(op(
+        "put",
+        "/api/v1/chains/{name}/state",
+        "chains",
+        "Switch a chain on or off",
+    )),
+    op(
+        "get",
+        "/api/v1/processors",
+        "processors",
+        "Get all processors",
+    ),
+    with_body(op(
+        "post",
+        "/api/v1/processors",
+        "processors",
+        "Create a processor",
+    )),
+    op(
+        "delete",
+        "/api/v1/processors/{uuid}",
+        "processors",
+        "Delete a processor",
+    ),
+    op("get", "/api/v1/routes", "routes", "Get all routes"),
+    with_body(op(
+        "put",
+        "/api/v1/routes/{name}/state",
+        "routes",
+        "Switch a route on or off",
+    )),
+    with_body(op(
+        "put",
+        "/api/v1/routes/{name}",
+        "routes",
+        "Create or replace a route",
+    )),
+    op(
+        "delete",
+        "/api/v1/routes/{name}",
+        "routes",
+        "Delete a route",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/log-level",
+        "admin",
+        "Get the log levels",
+    ),
+    with_body(op(
+        "put",
+        "/api/v1/admin/log-level",
+        "admin",
+        "Set a log level",
+    )),
+    op("get", "/api/v1/chaos", "admin", "Get the injected faults"),
+    with_body(op(
+        "put",
+        "/api/v1/chaos",
+        "admin",
+        "Set the injected faults",
+    )),
+    op(
+        "delete",
+        "/api/v1/chaos",
+        "admin",
+        "Clear the injected faults",
+    ),
+    op(
+        "get",
+        "/api/v1/trace/filters",
+        "admin",
+        "List the traced topic filters",
+    ),
+    with_body(op(
+        "post",
+        "/api/v1/trace/filters",
+        "admin",
+        "Trace the messages published on a topic filter",
+    )),
+    with_query(
+        op(
+            "delete",
+            "/api/v1/trace/filters",
+            "admin",
+            "Stop tracing a topic filter, or all of them",
+        ),
+        &[("filter", false)],
+    ),
+    with_query(
+        op(
+            "get",
+            "/api/v1/trace/messages",
+            "admin",
+            "Get the hops of the traced messages",
+        ),
+        &[("limit", false)],
+    ),
+    op(
+        "delete",
+        "/api/v1/trace/messages",
+        "admin",
+        "Clear the traced messages",
+    ),
+    op(
+        "get",
+        "/api/about",
+        "broker",
+        "Identify the broker and list its features",
+    ),
+    op(
+        "get",
+        "/readyz",
+        "broker",
+        "Get the readiness of the subsystems",
+    ),
+    op(
+        "get",
+        "/metrics",
+        "broker",
+        "Get the metrics in the Prometheus text format",
+    ),
+    op(
+        "get",
+        "/api/v1/openapi.json",
+        "broker",
+        "Get this OpenAPI document",
+    ),
+    op(
+        "get",
+        "/api/v1/replica",
+        "broker",
+        "Get the replication status of a read-only replica",
+    ),
+];
+
+fn path_parameters(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+}
+
+fn operation(operation: &Operation) -> Value {
+    let mut parameters: Vec<Value> = path_parameters(operation.path)
+        .map(|name| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect();
+    parameters.extend(operation.query.iter().map(|(name, required)| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": required,
+            "schema": { "type": "string" },
+        })
+    }));
+
+    let content = if operation.path == "/metrics" {
+        json!({ "text/plain": { "schema": { "type": "string" } } })
+    } else {
+        json!({ "application/json": { "schema": { "type": "object" } } })
+    };
+    let mut value = json!({
+        "tags": [operation.tag],
+        "summary": operation.summary,
+        "operationId": operation_id(operation),
+        "parameters": parameters,
+        "responses": {
+            "200": { "description": "OK", "content": content },
+            "default": {
+                "description": "Error",
+                "content": {
+                    "application/json": { "schema": { "$ref": "#/components/schemas/Error" } }
+                },
+            },
+        },
+    });
+    if operation.body {
+        value["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": { "type": "object" } } },
+        });
+    }
+    value
+}
+
+// get /api/v1/clients/{client_id}/kick is post_clients_client_id_kick
+fn operation_id(operation: &Operation) -> String {
+    let path = operation
+        .path
+        .trim_start_matches("/api")
+        .trim_start_matches("/v1")
+        .replace("/services/sparkplug_b", "/spb");
+    let mut id = operation.method.to_string();
+    for word in path.split(|c: char| !c.is_ascii_alphanumeric()) {
+        if !word.is_empty() {
+            id.push('_');
+            id.push_str(word);
+        }
+    }
+    id
+}
+
+/// the OpenAPI 3 document of the RESTful API
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for op in OPERATIONS {
+        let item = paths
+            .entry(op.path.to_string())
+            .or_insert_with(|| json!({}));
+        item[op.method] = operation(op);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "AxonMQ RESTful API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "tags": [
+            { "name": "sparkplug_b", "description": "Sparkplug B application state and commands" },
+            { "name": "clients", "description": "Connected clients and client groups" },
+            { "name": "retained", "description": "Retained messages" },
+            { "name": "chains", "description": "Processor chains" },
+            { "name": "processors", "description": "Processors the chains are built from" },
+            { "name": "routes", "description": "Routes to the processor chains" },
+            { "name": "admin", "description": "Log levels, fault injection and message tracing" },
+            { "name": "broker", "description": "Broker identity, health and metrics" },
+        ],
+        "paths": paths,
+        "components": {
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                    "required": ["error"],
+                },
+            },
+        },
+    })
+}
+
+pub async fn get_openapi() -> Result<impl warp::Reply, warp::Rejection> {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    Ok(warp::reply::json(DOCUMENT.get_or_init(document)))
+}
+
+pub(crate) fn openapi_routers()
+-> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "openapi.json"))
+        .and_then(get_openapi)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let kick = &document["paths"]["/api/v1/clients/{client_id}/kick"]["post"];
+        assert_eq!(kick["operationId"], "post_clients_client_id_kick");
+        assert_eq!(kick["parameters"][0]["name"], "client_id");
+        assert!(kick.get("requestBody").is_none());
+
+        let history = &document["paths"][spb!("/{group_id}/nodes/{node_id}/history")]["get"];
+        assert_eq!(history["parameters"].as_array().unwrap().len(), 6);
+        assert_eq!(history["parameters"][2]["required"], true);
+
+        let log_level = &document["paths"]["/api/v1/admin/log-level"];
+        assert!(log_level["get"].is_object() && log_level["put"]["requestBody"].is_object());
+
+        // operation ids are unique
+        let mut ids: Vec<String> = OPERATIONS.iter().map(operation_id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), OPERATIONS.len());
+    }
+
+    /// the `warp::<method>().and(warp::path!(..))` routes of a module, path parameters as `{}`
+    fn mounted(source: &str) -> Vec<(String, String)> {
+        let mut routes = vec![];
+        for method in ["get", "post", "put", "delete", "patch"] {
+            let filter = format!("warp::{}()", method);
+            for (at, _) in source.match_indices(&filter) {
+                let Some(rest) = source[at + filter.len()..]
+                    .trim_start()
+                    .strip_prefix(".and(warp::path!(")
+                else {
+                    continue;
+                };
+                let path: Vec<&str> = rest[..rest.find("))").unwrap()]
+                    .split('/')
+                    .map(|segment| {
+                        let segment = segment.trim().trim_end_matches(',').trim_end();
+                        segment
+                            .strip_prefix('"')
+                            .and_then(|s| s.strip_suffix('"'))
+                            .unwrap_or("{}")
+                    })
+                    .collect();
+                routes.push((method.to_string(), format!("/{}", path.join("/"))));
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_mounted_routes() {
+        // the modules whose routers `RESTful::run` and `RESTful::run_replica` mount
+        let sources = [
+            ("about", include_str!("about.rs")),
+            ("admin", include_str!("admin.rs")),
+            ("bans", include_str!("bans.rs")),
+            ("chains", include_str!("chains.rs")),
+            ("chaos", include_str!("chaos.rs")),
+            ("clients", include_str!("clients.rs")),
+            ("groups", include_str!("groups.rs")),
+            ("listeners", include_str!("listeners.rs")),
+            ("metrics", include_str!("metrics.rs")),
+            ("openapi", include_str!("openapi.rs")),
+            ("processors", include_str!("processors.rs")),
+            ("readyz", include_str!("readyz.rs")),
+            ("replica", include_str!("replica.rs")),
+            ("retained", include_str!("retained.rs")),
+            ("routes", include_str!("routes.rs")),
+            ("spb", include_str!("spb.rs")),
+            ("spb_stream", include_str!("spb_stream.rs")),
+            ("trace", include_str!("trace.rs")),
+        ];
+        // a new module of mod.rs is listed here
+        let modules: BTreeSet<&str> = include_str!("mod.rs")
+            .lines()
+            .filter_map(|line| line.strip_prefix("mod ")?.strip_suffix(';'))
+            .filter(|module| !["error", "rejection"].contains(module))
+            .collect();
+        assert_eq!(modules, sources.iter().map(|(name, _)| *name).collect());
+
+        let mounted: BTreeSet<(String, String)> = sources
+            .iter()
+            .flat_map(|(_, source)| mounted(source))
+            .collect();
+
+        let documented: BTreeSet<(String, String)> = OPERATIONS
+            .iter()
+            .map(|op| {
+                let path: Vec<&str> = op
+                    .path
+                    .split('/')
+                    .map(|segment| {
+                        if segment.starts_with('{') {
+                            "{}"
+                        } else {
+                            segment
+                        }
+                    })
+                    .collect();
+                (op.method.to_string(), path.join("/"))
+            })
+            .collect();
+        assert_eq!(mounted, documented);
+    }
+}
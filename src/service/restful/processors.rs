@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+
+use base64::Engine as _;
+use serde::Deserialize;
+use serde_json::Value;
+use warp::Filter;
+
+use crate::get_default_log_dir;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::processor::config::{LookupSource, ProcessorConfig};
+
+use super::error::ApiError;
+
+use super::{decode_param, with_operator_helper};
+
+/// body of `POST /api/v1/processors`
+#[derive(Debug, Deserialize)]
+pub struct ProcessorCreate {
+    // a new one when not set
+    pub uuid: Option<String>,
+    // a processor config of the configuration file, `{"type": "logger", "level": "info"}`
+    pub config: Value,
+    // base64 of a WASM module, saved for a wasm processor which then needs no path
+    pub module: Option<String>,
+}
+
+/// where the WASM modules uploaded through the API are saved
+fn module_dir() -> PathBuf {
+    Path::new(get_default_log_dir()).join("wasm_modules")
+}
+
+async fn save_module(uuid: &str, module: &str) -> Result<String, ApiError> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(module)
+        .map_err(|e| ApiError::InvalidProcessor(format!("module: {}", e)))?;
+    let dir = module_dir();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    let path = dir.join(format!("{}.wasm", uuid));
+    tokio::fs::write(&path, bytes)
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// the API is reached without credentials, a processor it creates gets no access to the host:
+/// no WASI env, network or dirs, no module but an uploaded one, no file or http lookups
+fn check_api_config(config: &ProcessorConfig, uploaded: bool) -> Result<(), ApiError> {
+    let refused = match config {
+        ProcessorConfig::Wasm { .. } if !uploaded => {
+            Some("a wasm processor takes an uploaded module")
+        }
+        ProcessorConfig::Wasm { wasi, .. } if wasi.env || wasi.network || !wasi.dirs.is_empty() => {
+            Some("wasi env, network and dirs are not granted through the API")
+        }
+        ProcessorConfig::Enrich {
+            source: LookupSource::File { .. } | LookupSource::Http { .. },
+            ..
+        } => Some("file and http lookups are not taken through the API"),
+        _ => None,
+    };
+    match refused {
+        Some(msg) => Err(ApiError::InvalidProcessor(msg.to_string())),
+        None => Ok(()),
+    }
+}
+
+pub async fn get_processors(
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .list_processors()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn create_processor(
+    create: ProcessorCreate,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // checked before it names the module file
+    let uuid = match create.uuid {
+        Some(uuid) => uuid::Uuid::parse_str(&uuid)
+            .map_err(|e| ApiError::InvalidProcessor(format!("{}: {}", uuid, e)))?,
+        None => uuid::Uuid::new_v4(),
+    }
+    .to_string();
+    let mut config = create.config;
+
+    let mut module = None;
+    if let Some(bytes) = create.module {
+        if config["type"] != "wasm" {
+            return Err(ApiError::InvalidProcessor(
+                "module is only taken by a wasm processor".to_string(),
+            )
+            .into());
+        }
+        let path = save_module(&uuid, &bytes).await?;
+        config["path"] = Value::String(path.clone());
+        module = Some(path);
+    }
+
+    let result = match serde_json::from_value::<ProcessorConfig>(config) {
+        Ok(config) => match check_api_config(&config, module.is_some()) {
+            Ok(()) => operator_helper
+                .create_processor(uuid, config)
+                .await
+                .map_err(ApiError::from),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(ApiError::InvalidProcessor(e.to_string())),
+    };
+    // the module of a processor that was not created is not kept
+    if result.is_err()
+        && let Some(path) = module
+    {
+        tokio::fs::remove_file(path).await.ok();
+    }
+    Ok(warp::reply::json(&result?))
+}
+
+pub async fn delete_processor(
+    uuid: String,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .delete_processor(uuid)
+        .await
+        .map_err(ApiError::from)?;
+    if let ProcessorConfig::Wasm { path, .. } = &result.config
+        && Path::new(path).starts_with(module_dir())
+    {
+        tokio::fs::remove_file(path).await.ok();
+    }
+    Ok(warp::reply::json(&result))
+}
+
+pub(crate) fn processors_routers(
+    operator_helper: OperatorHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_processors = warp::get()
+        .and(warp::path!("api" / "v1" / "processors"))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(get_processors);
+
+    let api_create_processor = warp::post()
+        .and(warp::path!("api" / "v1" / "processors"))
+        .and(warp::body::json())
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(create_processor);
+
+    let api_delete_processor = warp::delete()
+        .and(warp::path!("api" / "v1" / "processors" / String))
+        .map(|uuid: String| decode_param(&uuid))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(delete_processor);
+
+    api_get_processors
+        .or(api_create_processor)
+        .or(api_delete_processor)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn check(config: Value, uploaded: bool) -> Result<(), ApiError> {
+        check_api_config(&serde_json::from_value(config).unwrap(), uploaded)
+    }
+
+    #[test]
+    fn test_check_api_config() {
+        assert!(check(json!({"type": "logger", "level": "info"}), false).is_ok());
+        assert!(check(json!({"type": "wasm", "path": "m.wasm", "cfg": "{}"}), true).is_ok());
+        assert!(
+            check(
+                json!({"type": "enrich", "key": "{{ topic[1] }}", "source": {"type": "redis", "url": "redis://127.0.0.1"}}),
+                false
+            )
+            .is_ok()
+        );
+
+        // a module of the host
+        assert!(
+            check(
+                json!({"type": "wasm", "path": "/tmp/m.wasm", "cfg": "{}"}),
+                false
+            )
+            .is_err()
+        );
+        for wasi in [
+            json!({"env": true}),
+            json!({"network": true}),
+            json!({"dirs": ["/:/host"]}),
+        ] {
+            assert!(
+                check(
+                    json!({"type": "wasm", "path": "m.wasm", "cfg": "{}", "wasi": wasi}),
+                    true
+                )
+                .is_err()
+            );
+        }
+        for source in [
+            json!({"type": "file", "path": "/etc/passwd"}),
+            json!({"type": "http", "url": "http://169.254.169.254/{{ key }}"}),
+        ] {
+            assert!(
+                check(
+                    json!({"type": "enrich", "key": "{{ topic[1] }}", "source": source}),
+                    false
+                )
+                .is_err()
+            );
+        }
+    }
+}
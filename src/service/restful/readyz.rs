@@ -0,0 +1,34 @@
+use serde_json::json;
+use warp::Filter;
+use warp::http::StatusCode;
+
+use crate::supervisor::SupervisorHelper;
+
+use super::with_supervisor_helper;
+
+pub async fn get_readyz(
+    supervisor_helper: SupervisorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let ready = supervisor_helper.is_ready();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "ready": ready,
+            "subsystems": supervisor_helper.subsystems(),
+        })),
+        status,
+    ))
+}
+
+pub(crate) fn readyz_routers(
+    supervisor_helper: SupervisorHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("readyz"))
+        .and(with_supervisor_helper(supervisor_helper))
+        .and_then(get_readyz)
+}
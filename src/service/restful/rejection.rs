@@ -21,6 +21,99 @@ pub(crate) async fn handle_rejection(
                 code = StatusCode::INTERNAL_SERVER_ERROR;
                 message = msg.clone();
             }
+            ApiError::ReadOnly => {
+                code = StatusCode::FORBIDDEN;
+                message = "READ_ONLY_REPLICA".to_string();
+            }
+            ApiError::ClientNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "CLIENT_NOT_FOUND".to_string();
+            }
+            ApiError::ClientNotConnected => {
+                code = StatusCode::CONFLICT;
+                message = "CLIENT_NOT_CONNECTED".to_string();
+            }
+            ApiError::InvalidBan(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_BAN: {}", msg);
+            }
+            ApiError::BanNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "BAN_NOT_FOUND".to_string();
+            }
+            ApiError::InvalidTopicFilter => {
+                code = StatusCode::BAD_REQUEST;
+                message = "INVALID_TOPIC_FILTER".to_string();
+            }
+            ApiError::RetainedNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "RETAINED_MESSAGE_NOT_FOUND".to_string();
+            }
+            ApiError::ChainNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "CHAIN_NOT_FOUND".to_string();
+            }
+            ApiError::ProcessorNotFound(id) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("PROCESSOR_NOT_FOUND: {}", id);
+            }
+            ApiError::ProcessorExists(id) => {
+                code = StatusCode::CONFLICT;
+                message = format!("PROCESSOR_EXISTS: {}", id);
+            }
+            ApiError::ProcessorInUse(chains) => {
+                code = StatusCode::CONFLICT;
+                message = format!("PROCESSOR_IN_USE: {}", chains);
+            }
+            ApiError::InvalidProcessor(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_PROCESSOR: {}", msg);
+            }
+            ApiError::CanaryNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "CANARY_NOT_FOUND".to_string();
+            }
+            ApiError::InvalidCanaryPercent => {
+                code = StatusCode::BAD_REQUEST;
+                message = "INVALID_CANARY_PERCENT".to_string();
+            }
+            ApiError::InvalidErrorPolicy(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_ERROR_POLICY: {}", msg);
+            }
+            ApiError::RouteNotFound => {
+                code = StatusCode::NOT_FOUND;
+                message = "ROUTE_NOT_FOUND".to_string();
+            }
+            ApiError::InvalidSchedule(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_SCHEDULE: {}", msg);
+            }
+            ApiError::InvalidRouteCondition(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_ROUTE_CONDITION: {}", msg);
+            }
+            ApiError::FeatureUnavailable(msg) => {
+                code = StatusCode::NOT_IMPLEMENTED;
+                message = format!("FEATURE_UNAVAILABLE: {}", msg);
+            }
+            ApiError::WebSocketRequired => {
+                code = StatusCode::UPGRADE_REQUIRED;
+                message = "WEBSOCKET_REQUIRED".to_string();
+            }
+            ApiError::InvalidLogLevel(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_LOG_LEVEL: {}", msg);
+            }
+            ApiError::LogControlUnavailable => {
+                code = StatusCode::NOT_IMPLEMENTED;
+                message = "LOG_CONTROL_UNAVAILABLE".to_string();
+            }
+            #[cfg(feature = "chaos")]
+            ApiError::InvalidChaos(msg) => {
+                code = StatusCode::BAD_REQUEST;
+                message = format!("INVALID_CHAOS: {}", msg);
+            }
         }
     } else {
         code = StatusCode::INTERNAL_SERVER_ERROR;
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use warp::Filter;
+
+use crate::service::replica::ReplicaHelper;
+
+use super::error::ApiError;
+
+use super::{decode_param, with_replica_helper};
+
+pub async fn get_status(
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&replica_helper.status().await))
+}
+
+pub async fn get_groups(
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_groups(None)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_group(
+    group_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_groups(Some(group_id))
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result[0]))
+}
+
+pub async fn get_nodes(
+    group_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_nodes(group_id, None)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_node(
+    group_id: String,
+    node_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_nodes(group_id, Some(node_id))
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result[0]))
+}
+
+pub async fn get_devices(
+    group_id: String,
+    node_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_devices(group_id, node_id, None)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_device(
+    group_id: String,
+    node_id: String,
+    device: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_devices(group_id, node_id, Some(device))
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result[0]))
+}
+
+pub async fn get_clients(
+    query: HashMap<String, String>,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper.get_clients(query.get("group")).await;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_client(
+    client_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_client(&client_id)
+        .await
+        .ok_or(ApiError::ClientNotFound)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_client_session(
+    client_id: String,
+    replica_helper: ReplicaHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = replica_helper
+        .get_session(&client_id)
+        .await
+        .ok_or(ApiError::ClientNotFound)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub(crate) fn replica_routers(
+    replica_helper: ReplicaHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_status = warp::get()
+        .and(warp::path!("api" / "v1" / "replica"))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_status);
+
+    let api_get_groups = warp::get()
+        .and(warp::path!(
+            "api" / "v1" / "services" / "sparkplug_b" / "groups"
+        ))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_groups);
+
+    let api_get_group = warp::get()
+        .and(warp::path!(
+            "api" / "v1" / "services" / "sparkplug_b" / "groups" / String
+        ))
+        .map(|group_id: String| decode_param(&group_id))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_group);
+
+    let api_get_nodes = warp::get()
+        .and(warp::path!(
+            "api" / "v1" / "services" / "sparkplug_b" / "groups" / String / "nodes"
+        ))
+        .map(|group_id: String| decode_param(&group_id))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_nodes);
+
+    let api_get_node = warp::get()
+        .and(warp::path!(
+            "api" / "v1" / "services" / "sparkplug_b" / "groups" / String / "nodes" / String
+        ))
+        .map(|group_id: String, node_id: String| (decode_param(&group_id), decode_param(&node_id)))
+        .untuple_one()
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_node);
+
+    let api_get_devices = warp::get()
+        .and(warp::path!(
+            "api"
+                / "v1"
+                / "services"
+                / "sparkplug_b"
+                / "groups"
+                / String
+                / "nodes"
+                / String
+                / "devices"
+        ))
+        .map(|group_id: String, node_id: String| (decode_param(&group_id), decode_param(&node_id)))
+        .untuple_one()
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_devices);
+
+    let api_get_device = warp::get()
+        .and(warp::path!(
+            "api"
+                / "v1"
+                / "services"
+                / "sparkplug_b"
+                / "groups"
+                / String
+                / "nodes"
+                / String
+                / "devices"
+                / String
+        ))
+        .map(|group_id: String, node_id: String, device: String| {
+            (
+                decode_param(&group_id),
+                decode_param(&node_id),
+                decode_param(&device),
+            )
+        })
+        .untuple_one()
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_device);
+
+    let api_get_clients = warp::get()
+        .and(warp::path!("api" / "v1" / "clients"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_clients);
+
+    let api_get_client = warp::get()
+        .and(warp::path!("api" / "v1" / "clients" / String))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_client);
+
+    let api_get_client_session = warp::get()
+        .and(warp::path!("api" / "v1" / "clients" / String / "session"))
+        .map(|client_id: String| decode_param(&client_id))
+        .and(with_replica_helper(replica_helper.clone()))
+        .and_then(get_client_session);
+
+    // a replica never writes, reject every modifying request on the api
+    let api_read_only = warp::path("api")
+        .and(
+            warp::put()
+                .or(warp::post())
+                .unify()
+                .or(warp::delete())
+                .unify(),
+        )
+        .and_then(|| async {
+            Err::<warp::reply::Json, _>(warp::reject::custom(ApiError::ReadOnly))
+        });
+
+    api_get_status
+        .or(api_get_groups)
+        .or(api_get_group)
+        .or(api_get_nodes)
+        .or(api_get_node)
+        .or(api_get_devices)
+        .or(api_get_device)
+        .or(api_get_clients)
+        .or(api_get_client)
+        .or(api_get_client_session)
+        .or(api_read_only)
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use warp::Filter;
+
+use crate::mqtt::helper::BrokerHelper;
+
+use super::error::ApiError;
+
+use super::{decode_param, with_broker_helper};
+
+fn filter_param(query: &HashMap<String, String>) -> String {
+    query
+        .get("filter")
+        .cloned()
+        .unwrap_or_else(|| "#".to_string())
+}
+
+pub async fn get_retained_list(
+    query: HashMap<String, String>,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = broker_helper
+        .list_retained(filter_param(&query))
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_retained(
+    topic: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = broker_helper
+        .get_retained(topic)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::RetainedNotFound)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn delete_retained(
+    filter: String,
+    broker_helper: BrokerHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let removed = broker_helper
+        .remove_retained(filter)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "removed": removed,
+    })))
+}
+
+pub(crate) fn retained_routers(
+    broker_helper: BrokerHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_retained_list = warp::get()
+        .and(warp::path!("api" / "v1" / "retained"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_retained_list);
+
+    // the topic takes the rest of the path, levels are separated by '/'
+    let api_get_retained = warp::get()
+        .and(warp::path!("api" / "v1" / "retained" / ..))
+        .and(warp::path::tail())
+        .map(|tail: warp::path::Tail| decode_param(tail.as_str()))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(get_retained);
+
+    let api_delete_retained_filter = warp::delete()
+        .and(warp::path!("api" / "v1" / "retained"))
+        .and(warp::query::<HashMap<String, String>>())
+        // no default here, deleting everything takes an explicit '#'
+        .map(|query: HashMap<String, String>| query.get("filter").cloned().unwrap_or_default())
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(delete_retained);
+
+    let api_delete_retained = warp::delete()
+        .and(warp::path!("api" / "v1" / "retained" / ..))
+        .and(warp::path::tail())
+        .map(|tail: warp::path::Tail| decode_param(tail.as_str()))
+        .and(with_broker_helper(broker_helper.clone()))
+        .and_then(delete_retained);
+
+    api_get_retained_list
+        .or(api_get_retained)
+        .or(api_delete_retained_filter)
+        .or(api_delete_retained)
+}
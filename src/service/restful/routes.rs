@@ -0,0 +1,117 @@
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::config::schedule::Schedule;
+use crate::operator::helper::Helper as OperatorHelper;
+
+use super::error::ApiError;
+
+use super::{decode_param, with_operator_helper};
+
+/// body of the state endpoints of routes and chains
+#[derive(Debug, Deserialize)]
+pub struct SwitchState {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub schedule: Option<Schedule>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// body of `PUT /api/v1/routes/{name}`, the fields of a `[[router]]` entry
+#[derive(Debug, Deserialize)]
+pub struct RouteUpdate {
+    pub topic: String,
+    pub client_id: Option<String>,
+    pub server_name: Option<String>,
+    pub condition: Option<String>,
+    pub chains: Vec<String>,
+}
+
+pub async fn get_routes(
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .list_routes()
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn switch_route(
+    name: String,
+    state: SwitchState,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .switch_route(name, state.enabled, state.schedule)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn update_route(
+    name: String,
+    update: RouteUpdate,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .update_route(
+            name,
+            update.topic,
+            update.client_id,
+            update.server_name,
+            update.condition,
+            update.chains,
+        )
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn delete_route(
+    name: String,
+    operator_helper: OperatorHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = operator_helper
+        .delete_route(name)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub(crate) fn routes_routers(
+    operator_helper: OperatorHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_routes = warp::get()
+        .and(warp::path!("api" / "v1" / "routes"))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(get_routes);
+
+    let api_switch_route = warp::put()
+        .and(warp::path!("api" / "v1" / "routes" / String / "state"))
+        .map(|name: String| decode_param(&name))
+        .and(warp::body::json())
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(switch_route);
+
+    let api_update_route = warp::put()
+        .and(warp::path!("api" / "v1" / "routes" / String))
+        .map(|name: String| decode_param(&name))
+        .and(warp::body::json())
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(update_route);
+
+    let api_delete_route = warp::delete()
+        .and(warp::path!("api" / "v1" / "routes" / String))
+        .map(|name: String| decode_param(&name))
+        .and(with_operator_helper(operator_helper.clone()))
+        .and_then(delete_route);
+
+    api_get_routes
+        .or(api_switch_route)
+        .or(api_update_route)
+        .or(api_delete_route)
+}
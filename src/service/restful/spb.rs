@@ -1,6 +1,6 @@
 use warp::Filter;
 
-use crate::service::sparkplug_b::in_helper::{InHelper as SpbInHelper, KV};
+use crate::service::sparkplug_b::in_helper::{HistoryQuery, InHelper as SpbInHelper, KV};
 
 use super::error::ApiError;
 
@@ -57,7 +57,7 @@ pub async fn get_devices(
         .get_devices(group_id, node_id, None)
         .await
         .map_err(|e| ApiError::from(e))?;
-    Ok(warp::reply::json(&result[0]))
+    Ok(warp::reply::json(&result))
 }
 
 pub async fn get_device(
@@ -100,6 +100,48 @@ pub async fn set_device(
     Ok(warp::reply::json(&result))
 }
 
+pub async fn rebirth_node(
+    group_id: String,
+    node_id: String,
+    spb_in_helper: SpbInHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    spb_in_helper
+        .rebirth(group_id.clone(), node_id.clone())
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&serde_json::json!({
+        "group_id": group_id,
+        "node_id": node_id,
+    })))
+}
+
+pub async fn get_node_history(
+    group_id: String,
+    node_id: String,
+    query: HistoryQuery,
+    spb_in_helper: SpbInHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = spb_in_helper
+        .get_history(group_id, node_id, None, query)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
+pub async fn get_device_history(
+    group_id: String,
+    node_id: String,
+    device: String,
+    query: HistoryQuery,
+    spb_in_helper: SpbInHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = spb_in_helper
+        .get_history(group_id, node_id, Some(device), query)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&result))
+}
+
 pub(crate) fn spb_routers(
     spb_in_helper: SpbInHelper,
 ) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
@@ -210,6 +252,68 @@ pub(crate) fn spb_routers(
         .and(with_spb_in_helper(spb_in_helper.clone()))
         .and_then(set_device);
 
+    // metric names may contain '/', the metric is a query parameter
+    let api_get_node_history = warp::get()
+        .and(warp::path!(
+            "api"
+                / "v1"
+                / "services"
+                / "sparkplug_b"
+                / "groups"
+                / String
+                / "nodes"
+                / String
+                / "history"
+        ))
+        .map(|group_id: String, node_id: String| (decode_param(&group_id), decode_param(&node_id)))
+        .untuple_one()
+        .and(warp::query::<HistoryQuery>())
+        .and(with_spb_in_helper(spb_in_helper.clone()))
+        .and_then(get_node_history);
+
+    let api_get_device_history = warp::get()
+        .and(warp::path!(
+            "api"
+                / "v1"
+                / "services"
+                / "sparkplug_b"
+                / "groups"
+                / String
+                / "nodes"
+                / String
+                / "devices"
+                / String
+                / "history"
+        ))
+        .map(|group_id: String, node_id: String, device: String| {
+            (
+                decode_param(&group_id),
+                decode_param(&node_id),
+                decode_param(&device),
+            )
+        })
+        .untuple_one()
+        .and(warp::query::<HistoryQuery>())
+        .and(with_spb_in_helper(spb_in_helper.clone()))
+        .and_then(get_device_history);
+
+    let api_rebirth_node = warp::post()
+        .and(warp::path!(
+            "api"
+                / "v1"
+                / "services"
+                / "sparkplug_b"
+                / "groups"
+                / String
+                / "nodes"
+                / String
+                / "rebirth"
+        ))
+        .map(|group_id: String, node_id: String| (decode_param(&group_id), decode_param(&node_id)))
+        .untuple_one()
+        .and(with_spb_in_helper(spb_in_helper.clone()))
+        .and_then(rebirth_node);
+
     api_get_groups
         .or(api_get_group)
         .or(api_get_nodes)
@@ -218,4 +322,7 @@ pub(crate) fn spb_routers(
         .or(api_get_device)
         .or(api_set_node)
         .or(api_set_device)
+        .or(api_rebirth_node)
+        .or(api_get_node_history)
+        .or(api_get_device_history)
 }
@@ -0,0 +1,137 @@
+use futures_util::{SinkExt, StreamExt};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{
+    Message as WsMessage, handshake::derive_accept_key, protocol::Role,
+};
+use tracing::debug;
+use warp::Filter;
+use warp::http::{HeaderMap, Response, StatusCode, header};
+
+use crate::service::sparkplug_b::event::Event;
+use crate::service::sparkplug_b::in_helper::InHelper as SpbInHelper;
+
+use super::error::ApiError;
+use super::with_spb_in_helper;
+
+/// events are only pushed when they match every filter given
+#[derive(Clone, Deserialize)]
+pub struct StreamFilter {
+    group_id: Option<String>,
+    node_id: Option<String>,
+    device_id: Option<String>,
+    // online/offline events have no metric and always pass
+    metric: Option<String>,
+}
+
+impl StreamFilter {
+    fn matches(&self, event: &Event) -> bool {
+        let metric = match (self.metric.as_deref(), event) {
+            (Some(metric), Event::Metric { name, .. }) => metric == name,
+            _ => true,
+        };
+        metric
+            && self
+                .group_id
+                .as_deref()
+                .is_none_or(|g| g == event.group_id())
+            && self.node_id.as_deref().is_none_or(|n| n == event.node_id())
+            && self
+                .device_id
+                .as_deref()
+                .is_none_or(|d| Some(d) == event.device_id())
+    }
+}
+
+pub async fn stream(
+    filter: StreamFilter,
+    headers: HeaderMap,
+    on_upgrade: Option<OnUpgrade>,
+    spb_in_helper: SpbInHelper,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let websocket = headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let (Some(key), Some(on_upgrade), true) = (
+        headers.get(header::SEC_WEBSOCKET_KEY),
+        on_upgrade,
+        websocket,
+    ) else {
+        return Err(warp::reject::custom(ApiError::WebSocketRequired));
+    };
+
+    // subscribe before answering, no event is lost while the connection upgrades
+    let events = spb_in_helper.subscribe();
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let ws =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await;
+                push_events(ws, events, filter).await;
+            }
+            Err(e) => debug!("sparkplug b stream upgrade failed: {}", e),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        )
+        .body(String::new())
+        .unwrap())
+}
+
+async fn push_events(
+    mut ws: WebSocketStream<TokioIo<Upgraded>>,
+    mut events: broadcast::Receiver<Event>,
+    filter: StreamFilter,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let text = match event {
+                    Ok(event) if filter.matches(&event) => match serde_json::to_string(&event) {
+                        Ok(text) => text,
+                        Err(_) => continue,
+                    },
+                    Ok(_) => continue,
+                    // a slow client misses events rather than holding the service back
+                    Err(RecvError::Lagged(missed)) => {
+                        serde_json::json!({ "type": "lagged", "missed": missed }).to_string()
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if ws.send(WsMessage::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws.next() => match msg {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered by the stream itself
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+    let _ = ws.close(None).await;
+}
+
+pub(crate) fn spb_stream_routers(
+    spb_in_helper: SpbInHelper,
+) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("api" / "v1" / "spb" / "stream"))
+        .and(warp::query::<StreamFilter>())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<OnUpgrade>())
+        .and(with_spb_in_helper(spb_in_helper))
+        .and_then(stream)
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use warp::Filter;
+
+use crate::operator::trace;
+
+use super::error::ApiError;
+
+/// body of `POST /api/v1/trace/filters`
+#[derive(Debug, Deserialize)]
+pub struct TraceFilterCreate {
+    pub filter: String,
+    // traced until removed when not set
+    pub ttl_secs: Option<u64>,
+}
+
+pub async fn get_trace_filters() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&trace::filters()))
+}
+
+pub async fn add_trace_filter(
+    create: TraceFilterCreate,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let filter =
+        trace::add(create.filter, create.ttl_secs).map_err(|_| ApiError::InvalidTopicFilter)?;
+    Ok(warp::reply::json(&filter))
+}
+
+pub async fn delete_trace_filters(
+    query: HashMap<String, String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let removed = trace::remove(query.get("filter").map(String::as_str));
+    Ok(warp::reply::json(&serde_json::json!({
+        "removed": removed.len(),
+    })))
+}
+
+pub async fn get_trace_messages(
+    query: HashMap<String, String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let limit = query.get("limit").and_then(|limit| limit.parse().ok());
+    Ok(warp::reply::json(&trace::messages(limit)))
+}
+
+pub async fn clear_trace_messages() -> Result<impl warp::Reply, warp::Rejection> {
+    trace::clear();
+    Ok(warp::reply::json(&serde_json::json!({})))
+}
+
+pub(crate) fn trace_routers()
+-> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let api_get_filters = warp::get()
+        .and(warp::path!("api" / "v1" / "trace" / "filters"))
+        .and_then(get_trace_filters);
+
+    let api_add_filter = warp::post()
+        .and(warp::path!("api" / "v1" / "trace" / "filters"))
+        .and(warp::body::json())
+        .and_then(add_trace_filter);
+
+    // every filter without `?filter=`
+    let api_delete_filters = warp::delete()
+        .and(warp::path!("api" / "v1" / "trace" / "filters"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(delete_trace_filters);
+
+    let api_get_messages = warp::get()
+        .and(warp::path!("api" / "v1" / "trace" / "messages"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(get_trace_messages);
+
+    let api_clear_messages = warp::delete()
+        .and(warp::path!("api" / "v1" / "trace" / "messages"))
+        .and_then(clear_trace_messages);
+
+    api_get_filters
+        .or(api_add_filter)
+        .or(api_delete_filters)
+        .or(api_get_messages)
+        .or(api_clear_messages)
+}
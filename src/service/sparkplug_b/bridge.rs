@@ -0,0 +1,653 @@
+//! `[service.sparkplug_b.bridge]`: the edge node traffic of the tracked groups is forwarded to a
+//! central broker, AxonMQ standing for its edge nodes as a site level infrastructure node
+//!
+//! the births are kept, up to date with the DATA values, and the seq of each node is numbered
+//! again on the way up: after the connection to the central broker comes back, the nodes which
+//! died meanwhile get their NDEATH with the bdSeq of their last birth there, and the nodes still
+//! online are born again from seq 0
+//!
+//! the STATE of the central host applications is passed down, retained, and turned offline when
+//! the central broker is lost, so the edge nodes follow their primary host as if connected to
+//! it, the NCMD and DCMD of the central broker are passed down too
+//!
+//! with a `spool`, the DATA messages of the nodes received while the central broker is away are
+//! kept on disk and sent after the births once it is back, their metrics flagged historical
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use bytes::Bytes;
+use prost::Message;
+use rumqttc::TlsConfiguration;
+use rumqttc::Transport;
+use rumqttc::v5::mqttbytes::QoS as BridgeQoS;
+use rumqttc::v5::mqttbytes::v5::{Filter, Packet};
+use rumqttc::v5::{AsyncClient, Event, MqttOptions};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::CONFIG;
+use crate::config::SpbBridgeConfig;
+use crate::mqtt::QoS;
+use crate::mqtt::helper::BrokerHelper;
+use crate::mqtt::protocol::publish::PublishOptions;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::spool::{AsyncSpool, Spool};
+use crate::utils::time::now_milliseconds;
+
+use super::namespace::GroupFilter;
+use super::proto::Payload;
+use super::proto::payload::{Metric, metric::Value};
+
+const QUEUE_SIZE: usize = 1024;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// how often the spool is sent on while the client queue has room
+const REPLAY_INTERVAL: Duration = Duration::from_millis(100);
+// the client identifier of the messages passed down to the local broker
+const PUBLISHER: &str = "axonmq-bridge";
+
+/// a Sparkplug B message published on the local broker
+pub struct Local {
+    pub(crate) topic: String,
+    pub(crate) payload: Bytes,
+}
+
+/// a message for the central broker
+#[derive(Debug)]
+struct Uplink {
+    topic: String,
+    payload: Vec<u8>,
+    // NDEATH is QoS 1, the others QoS 0
+    death: bool,
+}
+
+struct Node {
+    bd_seq: u64,
+    // the seq of the last message sent up
+    seq: u8,
+    birth: Payload,
+    devices: BTreeMap<String, Payload>,
+}
+
+impl Node {
+    /// the payload with the next seq, a birth starts over from 0
+    fn stamp(&mut self, mut payload: Payload, birth: bool) -> Vec<u8> {
+        self.seq = if birth { 0 } else { self.seq.wrapping_add(1) };
+        payload.seq = Some(self.seq as u64);
+        payload.encode_to_vec()
+    }
+}
+
+fn bd_seq(payload: &Payload) -> Option<u64> {
+    let metric = payload
+        .metrics
+        .iter()
+        .find(|m| m.name.as_deref() == Some("bdSeq"))?;
+    match metric.value {
+        Some(Value::LongValue(v)) => Some(v),
+        Some(Value::IntValue(v)) => Some(v as u64),
+        _ => None,
+    }
+}
+
+/// the birth metrics take the values of a DATA message, by alias or by name
+fn refresh(birth: &mut Payload, data: &Payload) {
+    for metric in data
+        .metrics
+        .iter()
+        .filter(|m| m.is_historical != Some(true))
+    {
+        let found = birth.metrics.iter_mut().find(|m| match metric.alias {
+            Some(alias) => m.alias == Some(alias),
+            None => metric.name.is_some() && m.name == metric.name,
+        });
+        if let Some(found) = found {
+            found.timestamp = metric.timestamp.or(data.timestamp);
+            found.is_null = metric.is_null;
+            found.value = metric.value.clone();
+        }
+    }
+}
+
+fn ndeath(bd_seq: u64) -> Vec<u8> {
+    Payload {
+        timestamp: Some(now_milliseconds()),
+        metrics: vec![Metric {
+            name: Some("bdSeq".to_string()),
+            alias: None,
+            timestamp: Some(now_milliseconds()),
+            datatype: Some(super::proto::DataType::UInt64 as u32),
+            is_historical: None,
+            is_transient: None,
+            is_null: None,
+            metadata: None,
+            properties: None,
+            value: Some(Value::LongValue(bd_seq)),
+        }],
+        seq: None,
+        uuid: None,
+        body: None,
+    }
+    .encode_to_vec()
+}
+
+/// what the central broker was told and what it is owed
+#[derive(Default)]
+struct BridgeState {
+    nodes: HashMap<(String, String), Node>,
+    // the nodes born on the central broker, with the bdSeq of their birth there
+    announced: HashMap<(String, String), u64>,
+}
+
+impl BridgeState {
+    /// the messages for the central broker of a local one, none while it is not connected
+    fn local(&mut self, topic: &str, payload: Bytes, connected: bool) -> Vec<Uplink> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        if parts.len() < 4 || parts[0] != "spBv1.0" {
+            return vec![];
+        }
+        let Ok(payload) = Payload::decode(payload) else {
+            return vec![];
+        };
+        let key = (parts[1].to_string(), parts[3].to_string());
+        let device = parts.get(4).map(|d| d.to_string());
+        let announced = connected && self.announced.contains_key(&key);
+
+        match (parts[2], device) {
+            ("NBIRTH", None) => {
+                let Some(bd_seq) = bd_seq(&payload) else {
+                    return vec![];
+                };
+                let mut node = Node {
+                    bd_seq,
+                    seq: 0,
+                    birth: payload.clone(),
+                    devices: BTreeMap::new(),
+                };
+                let mut up = vec![];
+                if connected {
+                    up.push(Uplink {
+                        topic: topic.to_string(),
+                        payload: node.stamp(payload, true),
+                        death: false,
+                    });
+                    self.announced.insert(key.clone(), bd_seq);
+                }
+                self.nodes.insert(key, node);
+                up
+            }
+            ("NDEATH", None) => {
+                let bd_seq = bd_seq(&payload);
+                // the death of an older birth
+                if self
+                    .nodes
+                    .get(&key)
+                    .is_some_and(|n| Some(n.bd_seq) != bd_seq)
+                {
+                    return vec![];
+                }
+                self.nodes.remove(&key);
+                if connected && self.announced.remove(&key).is_some() {
+                    return vec![Uplink {
+                        topic: topic.to_string(),
+                        payload: payload.encode_to_vec(),
+                        death: true,
+                    }];
+                }
+                vec![]
+            }
+            (kind @ ("DBIRTH" | "DDEATH" | "NDATA" | "DDATA"), device) => {
+                let Some(node) = self.nodes.get_mut(&key) else {
+                    return vec![];
+                };
+                match (kind, device) {
+                    ("DBIRTH", Some(device)) => {
+                        node.devices.insert(device, payload.clone());
+                    }
+                    ("DDEATH", Some(device)) => {
+                        node.devices.remove(&device);
+                    }
+                    ("NDATA", None) => refresh(&mut node.birth, &payload),
+                    ("DDATA", Some(device)) => match node.devices.get_mut(&device) {
+                        Some(birth) => refresh(birth, &payload),
+                        None => return vec![],
+                    },
+                    _ => return vec![],
+                }
+                if !announced {
+                    return vec![];
+                }
+                vec![Uplink {
+                    topic: topic.to_string(),
+                    payload: node.stamp(payload, false),
+                    death: false,
+                }]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// a DATA message of a node born here, kept for the central broker while it is away with
+    /// the bdSeq of the node
+    fn spooled(&self, topic: &str, payload: &[u8]) -> Option<Vec<u8>> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        if parts.len() < 4 || !matches!(parts[2], "NDATA" | "DDATA") {
+            return None;
+        }
+        let node = self
+            .nodes
+            .get(&(parts[1].to_string(), parts[3].to_string()))?;
+        let mut record = Vec::with_capacity(10 + topic.len() + payload.len());
+        record.extend_from_slice(&node.bd_seq.to_be_bytes());
+        record.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        record.extend_from_slice(topic.as_bytes());
+        record.extend_from_slice(payload);
+        Some(record)
+    }
+
+    /// a spooled DATA message for the central broker, after the births, with its metrics
+    /// flagged historical; the nodes which died or were born again meanwhile are left out
+    fn historical(&mut self, record: &[u8]) -> Option<Uplink> {
+        let bd_seq = u64::from_be_bytes(record.get(..8)?.try_into().ok()?);
+        let topic_len = u16::from_be_bytes(record.get(8..10)?.try_into().ok()?) as usize;
+        let topic = std::str::from_utf8(record.get(10..10 + topic_len)?).ok()?;
+        let parts: Vec<&str> = topic.split('/').collect();
+        let key = (parts.get(1)?.to_string(), parts.get(3)?.to_string());
+        if self.announced.get(&key) != Some(&bd_seq) {
+            return None;
+        }
+        let node = self.nodes.get_mut(&key)?;
+        let mut payload = Payload::decode(&record[10 + topic_len..]).ok()?;
+        for metric in payload.metrics.iter_mut() {
+            metric.is_historical = Some(true);
+        }
+        Some(Uplink {
+            topic: topic.to_string(),
+            payload: node.stamp(payload, false),
+            death: false,
+        })
+    }
+
+    /// the central broker is back: the deaths it missed and the births of the nodes online
+    fn reconnected(&mut self) -> Vec<Uplink> {
+        let mut up = vec![];
+        for ((group_id, node_id), bd_seq) in self.announced.drain() {
+            let key = (group_id, node_id);
+            if self.nodes.get(&key).is_none_or(|n| n.bd_seq != bd_seq) {
+                up.push(Uplink {
+                    topic: format!("spBv1.0/{}/NDEATH/{}", key.0, key.1),
+                    payload: ndeath(bd_seq),
+                    death: true,
+                });
+            }
+        }
+        for ((group_id, node_id), node) in self.nodes.iter_mut() {
+            let mut birth = node.birth.clone();
+            birth.timestamp = Some(now_milliseconds());
+            up.push(Uplink {
+                topic: format!("spBv1.0/{}/NBIRTH/{}", group_id, node_id),
+                payload: node.stamp(birth, true),
+                death: false,
+            });
+            let devices: Vec<_> = node
+                .devices
+                .iter()
+                .map(|(device_id, birth)| (device_id.clone(), birth.clone()))
+                .collect();
+            for (device_id, mut birth) in devices {
+                birth.timestamp = Some(now_milliseconds());
+                up.push(Uplink {
+                    topic: format!("spBv1.0/{}/DBIRTH/{}/{}", group_id, node_id, device_id),
+                    payload: node.stamp(birth, false),
+                    death: false,
+                });
+            }
+            self.announced
+                .insert((group_id.clone(), node_id.clone()), node.bd_seq);
+        }
+        up
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct State {
+    online: bool,
+}
+
+fn options(config: &SpbBridgeConfig) -> anyhow::Result<MqttOptions> {
+    let client_id = config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("axonmq-bridge-{}", CONFIG.get().unwrap().node.id));
+    let port = config.port.unwrap_or(if config.tls { 8883 } else { 1883 });
+    let mut options = MqttOptions::new(client_id, config.host.clone(), port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    if config.tls {
+        let tls = match &config.ca_path {
+            Some(ca_path) => TlsConfiguration::Simple {
+                ca: std::fs::read(ca_path)?,
+                alpn: None,
+                client_auth: None,
+            },
+            None => TlsConfiguration::default(),
+        };
+        options.set_transport(Transport::tls_with_config(tls));
+    }
+    Ok(options)
+}
+
+async fn publish_local(
+    operator_helper: &OperatorHelper,
+    broker_helper: &BrokerHelper,
+    retain: bool,
+    qos: QoS,
+    topic: String,
+    payload: Bytes,
+) {
+    if retain {
+        let _ = broker_helper
+            .retain_message(
+                None,
+                topic.clone(),
+                qos,
+                payload.clone(),
+                vec![],
+                Default::default(),
+            )
+            .await;
+    }
+    let _ = operator_helper
+        .publish(
+            PUBLISHER.to_string(),
+            None,
+            retain,
+            qos,
+            topic,
+            payload,
+            vec![],
+            PublishOptions::default(),
+        )
+        .await;
+}
+
+/// sends the spooled DATA messages on in order, true when the client queue filled up before
+/// the spool was through
+async fn replay(client: &AsyncClient, state: &mut BridgeState, spool: &AsyncSpool) -> bool {
+    loop {
+        let record = match spool.front().await {
+            Ok(Some(record)) => record,
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("sparkplug b bridge failed to read its spool: {}", e);
+                return false;
+            }
+        };
+        if let Some(up) = state.historical(&record)
+            && client
+                .try_publish(up.topic, BridgeQoS::AtMostOnce, false, up.payload)
+                .is_err()
+        {
+            return true;
+        }
+        if let Err(e) = spool.pop().await {
+            warn!("sparkplug b bridge failed to read its spool: {}", e);
+            return false;
+        }
+    }
+}
+
+/// forwards the local messages until the server stops
+pub(crate) async fn run(
+    config: &'static SpbBridgeConfig,
+    mut rx: mpsc::Receiver<Local>,
+    groups: GroupFilter,
+    operator_helper: OperatorHelper,
+    broker_helper: BrokerHelper,
+) {
+    let options = match options(config) {
+        Ok(options) => options,
+        Err(e) => {
+            warn!("sparkplug b bridge disabled: {}", e);
+            return;
+        }
+    };
+    let (client, mut eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+    let spool = match &config.spool {
+        Some(spool) => match Spool::open("sparkplug_b_bridge", spool) {
+            Ok(spool) => Some(AsyncSpool::new(spool)),
+            Err(e) => {
+                warn!(
+                    "sparkplug b bridge failed to open its spool at {}: {}",
+                    spool.path, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    // the spool is sent on a queue full at a time, so that the connection is polled meanwhile
+    let mut replaying = false;
+    let mut replay_ticker = tokio::time::interval(REPLAY_INTERVAL);
+
+    // the connection is polled on its own so that the local messages never wait on it
+    let (events_tx, mut events) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let event = eventloop.poll().await;
+            let failed = event.is_err();
+            if events_tx.send(event).await.is_err() {
+                return;
+            }
+            if failed {
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    });
+
+    let mut state = BridgeState::default();
+    let mut connected = false;
+    // the central host applications online, by host id
+    let mut hosts: HashMap<String, bool> = HashMap::new();
+    let send = |up: Vec<Uplink>| {
+        for up in up {
+            let qos = if up.death {
+                BridgeQoS::AtLeastOnce
+            } else {
+                BridgeQoS::AtMostOnce
+            };
+            if let Err(e) = client.try_publish(up.topic, qos, false, up.payload) {
+                debug!("sparkplug b bridge dropped a message: {}", e);
+            }
+        }
+    };
+
+    loop {
+        tokio::select! {
+            Some(local) = rx.recv() => {
+                if !connected
+                    && let Some(spool) = &spool
+                    && let Some(record) = state.spooled(&local.topic, &local.payload)
+                {
+                    match spool.push(record).await {
+                        Ok(true) => {}
+                        Ok(false) => debug!("sparkplug b bridge spool full, message dropped"),
+                        Err(e) => warn!("sparkplug b bridge failed to spool a message: {}", e),
+                    }
+                }
+                send(state.local(&local.topic, local.payload, connected));
+            }
+            _ = replay_ticker.tick(), if connected && replaying => {
+                if let Some(spool) = &spool {
+                    replaying = replay(&client, &mut state, spool).await;
+                }
+            }
+            Some(event) = events.recv() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("sparkplug b bridge connected to {}", config.host);
+                    connected = true;
+                    let filters = [
+                        "spBv1.0/STATE/+",
+                        "spBv1.0/+/NCMD/+",
+                        "spBv1.0/+/DCMD/+/+",
+                    ];
+                    let _ = client.try_subscribe_many(
+                        filters.map(|f| Filter::new(f, BridgeQoS::AtLeastOnce)),
+                    );
+                    send(state.reconnected());
+                    if let Some(spool) = spool.as_ref().filter(|spool| !spool.is_empty()) {
+                        info!("sparkplug b bridge sending {} spooled messages", spool.len());
+                        replaying = replay(&client, &mut state, spool).await;
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    let topic = String::from_utf8_lossy(&message.topic).to_string();
+                    let parts: Vec<&str> = topic.split('/').collect();
+                    if parts.len() == 3 && parts[1] == "STATE" {
+                        if let Ok(host) = serde_json::from_slice::<State>(&message.payload) {
+                            hosts.insert(parts[2].to_string(), host.online);
+                        }
+                        publish_local(&operator_helper, &broker_helper, true, QoS::AtLeastOnce, topic, message.payload).await;
+                    } else if parts.len() >= 4 && groups.tracks(parts[1]) {
+                        publish_local(&operator_helper, &broker_helper, false, QoS::AtMostOnce, topic, message.payload).await;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if connected {
+                        warn!("sparkplug b bridge lost {}: {}", config.host, e);
+                    } else {
+                        debug!("sparkplug b bridge failed to connect to {}: {}", config.host, e);
+                    }
+                    connected = false;
+                    // the edge nodes lose their primary host with the central broker
+                    for (host_id, online) in hosts.iter_mut().filter(|(_, online)| **online) {
+                        *online = false;
+                        let payload = serde_json::json!({ "online": false, "timestamp": now_milliseconds() });
+                        publish_local(
+                            &operator_helper,
+                            &broker_helper,
+                            true,
+                            QoS::AtLeastOnce,
+                            format!("spBv1.0/STATE/{}", host_id),
+                            Bytes::from(payload.to_string()),
+                        )
+                        .await;
+                    }
+                }
+            },
+            else => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(name: Option<&str>, alias: Option<u64>, value: Value) -> Metric {
+        Metric {
+            name: name.map(str::to_string),
+            alias,
+            timestamp: Some(1),
+            datatype: None,
+            is_historical: None,
+            is_transient: None,
+            is_null: None,
+            metadata: None,
+            properties: None,
+            value: Some(value),
+        }
+    }
+
+    fn payload(seq: u64, metrics: Vec<Metric>) -> Bytes {
+        Payload {
+            timestamp: Some(1),
+            metrics,
+            seq: Some(seq),
+            uuid: None,
+            body: None,
+        }
+        .encode_to_vec()
+        .into()
+    }
+
+    fn decoded(up: &Uplink) -> Payload {
+        Payload::decode(up.payload.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_bridge_state() {
+        let mut state = BridgeState::default();
+        let birth = payload(
+            7,
+            vec![
+                metric(Some("bdSeq"), None, Value::LongValue(3)),
+                metric(Some("temperature"), Some(1), Value::DoubleValue(20.0)),
+            ],
+        );
+        let up = state.local("spBv1.0/g/NBIRTH/n1", birth, true);
+        assert_eq!(decoded(&up[0]).seq, Some(0));
+
+        // numbered again after the birth
+        let data = payload(9, vec![metric(None, Some(1), Value::DoubleValue(21.5))]);
+        let up = state.local("spBv1.0/g/NDATA/n1", data, true);
+        assert_eq!(decoded(&up[0]).seq, Some(1));
+
+        // n2 is born while the central broker is away, n1 dies
+        let birth = payload(0, vec![metric(Some("bdSeq"), None, Value::LongValue(0))]);
+        assert!(state.local("spBv1.0/g/NBIRTH/n2", birth, false).is_empty());
+        let data = payload(10, vec![metric(None, Some(1), Value::DoubleValue(22.0))]);
+        assert!(state.local("spBv1.0/g/NDATA/n1", data, false).is_empty());
+
+        let mut up = state.reconnected();
+        up.sort_by(|a, b| a.topic.cmp(&b.topic));
+        let topics: Vec<&str> = up.iter().map(|up| up.topic.as_str()).collect();
+        assert_eq!(topics, ["spBv1.0/g/NBIRTH/n1", "spBv1.0/g/NBIRTH/n2"]);
+        let n1 = decoded(&up[0]);
+        assert_eq!(n1.seq, Some(0));
+        assert_eq!(n1.metrics[1].value, Some(Value::DoubleValue(22.0)));
+
+        // a stale death is not the death of the current birth
+        let death = payload(0, vec![metric(Some("bdSeq"), None, Value::LongValue(2))]);
+        assert!(state.local("spBv1.0/g/NDEATH/n1", death, false).is_empty());
+        let death = payload(0, vec![metric(Some("bdSeq"), None, Value::LongValue(3))]);
+        assert!(state.local("spBv1.0/g/NDEATH/n1", death, false).is_empty());
+
+        // the death missed by the central broker, with the bdSeq of the birth it saw
+        let up = state.reconnected();
+        assert_eq!(up.len(), 2);
+        let death = up.iter().find(|up| up.death).unwrap();
+        assert_eq!(death.topic, "spBv1.0/g/NDEATH/n1");
+        assert_eq!(bd_seq(&decoded(death)), Some(3));
+    }
+
+    #[test]
+    fn test_spooled() {
+        let mut state = BridgeState::default();
+        let birth = payload(0, vec![metric(Some("bdSeq"), None, Value::LongValue(1))]);
+        state.local("spBv1.0/g/NBIRTH/n1", birth, true);
+
+        // kept while the central broker is away, unknown nodes are not
+        let data = payload(1, vec![metric(None, Some(1), Value::DoubleValue(21.5))]);
+        let record = state.spooled("spBv1.0/g/NDATA/n1", &data).unwrap();
+        assert!(state.spooled("spBv1.0/g/NDATA/n2", &data).is_none());
+        assert!(state.spooled("spBv1.0/g/NBIRTH/n1", &data).is_none());
+
+        // after the births, numbered on and flagged historical
+        state.reconnected();
+        let up = state.historical(&record).unwrap();
+        assert_eq!(up.topic, "spBv1.0/g/NDATA/n1");
+        let sent = decoded(&up);
+        assert_eq!(sent.seq, Some(1));
+        assert_eq!(sent.metrics[0].is_historical, Some(true));
+
+        // not for a node born again meanwhile
+        let birth = payload(0, vec![metric(Some("bdSeq"), None, Value::LongValue(2))]);
+        state.local("spBv1.0/g/NBIRTH/n1", birth, true);
+        assert!(state.historical(&record).is_none());
+    }
+}
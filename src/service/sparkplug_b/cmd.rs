@@ -28,8 +28,19 @@ impl Cmd {
         use prost::Message as _;
 
         let topic = format!("spBv1.0/{}/NCMD/{}", group_id, node_id);
+        let payload = self.node_rebirth_payload(alias).encode_to_vec();
+
+        (topic, Bytes::from(payload))
+    }
+
+    /// the NCMD payload setting `Node Control/Rebirth`, by alias when the node gave one
+    pub fn node_rebirth_payload(&mut self, alias: Option<u64>) -> proto::Payload {
         let metric = proto::payload::Metric {
-            name: Some("Node Control/Rebirth".to_string()),
+            name: if alias.is_some() {
+                None
+            } else {
+                Some("Node Control/Rebirth".to_string())
+            },
             alias,
             datatype: Some(proto::DataType::Boolean as u32),
             value: Some(proto::payload::metric::Value::BooleanValue(true)),
@@ -41,15 +52,12 @@ impl Cmd {
             metadata: None,
         };
 
-        let payload = proto::Payload {
+        proto::Payload {
             uuid: None,
             body: None,
             timestamp: Some(now_milliseconds()),
             seq: Some(self.next_seq()),
             metrics: vec![metric],
         }
-        .encode_to_vec();
-
-        (topic, Bytes::from(payload))
     }
 }
@@ -0,0 +1,60 @@
+use prost::Message as _;
+
+use super::error::SpbError;
+use super::model::value::Value;
+use super::proto::Payload;
+
+/// scalar metric value, other Sparkplug B types are not decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarValue {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodedMetric {
+    pub name: Option<String>,
+    pub alias: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub value: ScalarValue,
+}
+
+/// decode the scalar metrics of a Sparkplug B payload without tracking the edge node state
+pub fn decode_metrics(payload: &[u8]) -> Result<(Option<u64>, Vec<DecodedMetric>), SpbError> {
+    let payload = Payload::decode(payload).map_err(|_| SpbError::InvalidPayload)?;
+
+    let metrics = payload
+        .metrics
+        .into_iter()
+        .filter(|m| !m.is_null.unwrap_or(false))
+        .filter_map(|m| {
+            let value = Value::try_from((m.value?, m.datatype)).ok()?;
+            let value = match value {
+                Value::Int8(v) => ScalarValue::Int(v as i64),
+                Value::Int16(v) => ScalarValue::Int(v as i64),
+                Value::Int32(v) => ScalarValue::Int(v as i64),
+                Value::Int64(v) => ScalarValue::Int(v),
+                Value::UInt8(v) => ScalarValue::UInt(v as u64),
+                Value::UInt16(v) => ScalarValue::UInt(v as u64),
+                Value::UInt32(v) => ScalarValue::UInt(v as u64),
+                Value::UInt64(v) | Value::DateTime(v) => ScalarValue::UInt(v),
+                Value::Float(v) => ScalarValue::Float(v as f64),
+                Value::Double(v) => ScalarValue::Float(v),
+                Value::Boolean(v) => ScalarValue::Boolean(v),
+                Value::String(v) | Value::Text(v) | Value::UUID(v) => ScalarValue::String(v),
+                _ => return None,
+            };
+            Some(DecodedMetric {
+                name: m.name,
+                alias: m.alias,
+                timestamp: m.timestamp,
+                value,
+            })
+        })
+        .collect();
+
+    Ok((payload.timestamp, metrics))
+}
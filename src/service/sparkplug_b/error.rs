@@ -12,6 +12,8 @@ pub enum SpbError {
     InvalidMetricProperty,
     #[error("Invalid Sequence")]
     InvalidSeq,
+    #[error("Sequence Mismatch")]
+    SeqMismatch,
     #[error("Invalid bdSeq")]
     InvalidbdSeq,
     #[error("bdSeq Is None")]
@@ -36,14 +38,16 @@ pub enum SpbError {
     InvalidTemplateInstance,
     #[error("Template Not Found")]
     TemplateNotFound,
+    #[error("Invalid DataSet")]
+    InvalidDataSet,
     #[error("Invalid PropertySet")]
     InvalidPropertySet,
     #[error("Template Version Mismatch")]
     TemplateVersionMismatch,
     #[error("Exceeded")]
     Exceeded,
-    #[error("Not Support Command")]
-    NotSupportCommand,
+    #[error("Command Not Published")]
+    CommandNotPublished,
     #[error("Node Death Not Match")]
     NDeathNotMatch,
     #[error("Node Not Found")]
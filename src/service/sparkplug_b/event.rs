@@ -0,0 +1,137 @@
+//! changes of the edge node state, pushed to the stream subscribers and the projection
+
+use serde::Serialize;
+
+use crate::hook;
+
+use super::model::metric::Metric;
+use super::model::value::Value;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Online {
+        group_id: String,
+        node_id: String,
+        device_id: Option<String>,
+        timestamp: u64,
+    },
+    Offline {
+        group_id: String,
+        node_id: String,
+        device_id: Option<String>,
+        timestamp: u64,
+    },
+    Metric {
+        group_id: String,
+        node_id: String,
+        device_id: Option<String>,
+        name: String,
+        timestamp: u64,
+        datatype: u32,
+        // None when the metric was reported null
+        value: Option<Box<Value>>,
+    },
+}
+
+impl Event {
+    pub fn group_id(&self) -> &str {
+        match self {
+            Event::Online { group_id, .. }
+            | Event::Offline { group_id, .. }
+            | Event::Metric { group_id, .. } => group_id,
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        match self {
+            Event::Online { node_id, .. }
+            | Event::Offline { node_id, .. }
+            | Event::Metric { node_id, .. } => node_id,
+        }
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            Event::Online { device_id, .. }
+            | Event::Offline { device_id, .. }
+            | Event::Metric { device_id, .. } => device_id.as_deref(),
+        }
+    }
+
+    /// the node birth or death told to the `[[hook]]` webhooks
+    pub fn hook(&self) -> Option<hook::Event> {
+        match self {
+            Event::Online {
+                group_id,
+                node_id,
+                device_id: None,
+                ..
+            } => Some(hook::Event::NodeBirth {
+                group_id: group_id.clone(),
+                node_id: node_id.clone(),
+            }),
+            Event::Offline {
+                group_id,
+                node_id,
+                device_id: None,
+                ..
+            } => Some(hook::Event::NodeDeath {
+                group_id: group_id.clone(),
+                node_id: node_id.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// the metric events of a node, or of one of its devices
+pub fn metrics<'a>(
+    group_id: &str,
+    node_id: &str,
+    device_id: Option<&str>,
+    metrics: impl IntoIterator<Item = &'a Metric>,
+    out: &mut Vec<Event>,
+) {
+    for metric in metrics {
+        metric_event(group_id, node_id, device_id, &metric.name, metric, out);
+    }
+}
+
+fn metric_event(
+    group_id: &str,
+    node_id: &str,
+    device_id: Option<&str>,
+    name: &str,
+    metric: &Metric,
+    out: &mut Vec<Event>,
+) {
+    // the members of a template instance are events of their own, named under the instance
+    if let Some(Value::TemplateInstance(instance)) = &metric.value {
+        for member in instance.metrics.values() {
+            let name = format!("{}/{}", name, member.name);
+            metric_event(group_id, node_id, device_id, &name, member, out);
+        }
+        return;
+    }
+    if matches!(
+        metric.value,
+        Some(Value::Template(_) | Value::TemplateDataInstance(_))
+    ) {
+        return;
+    }
+
+    out.push(Event::Metric {
+        group_id: group_id.to_string(),
+        node_id: node_id.to_string(),
+        device_id: device_id.map(str::to_string),
+        name: name.to_string(),
+        timestamp: metric.timestamp,
+        datatype: metric.datatype,
+        value: if metric.is_null {
+            None
+        } else {
+            metric.value.clone().map(Box::new)
+        },
+    });
+}
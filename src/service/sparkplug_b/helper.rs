@@ -1,10 +1,14 @@
 use bytes::Bytes;
 use tokio::sync::mpsc::Sender;
+use tracing::debug;
 
+use crate::cluster::replicated;
 use crate::mqtt::QoS;
 
+use super::bridge::Local;
 use super::error::SpbError;
 use super::message::Message;
+use super::namespace::GroupFilter;
 use super::proto;
 
 pub(crate) struct Publish {
@@ -183,11 +187,13 @@ impl Publish {
 #[derive(Clone)]
 pub struct SparkPlugBApplicationHelper {
     tx: Sender<Publish>,
+    groups: GroupFilter,
+    bridge: Option<Sender<Local>>,
 }
 
 impl SparkPlugBApplicationHelper {
-    pub fn new(tx: Sender<Publish>) -> Self {
-        SparkPlugBApplicationHelper { tx }
+    pub fn new(tx: Sender<Publish>, groups: GroupFilter, bridge: Option<Sender<Local>>) -> Self {
+        SparkPlugBApplicationHelper { tx, groups, bridge }
     }
 
     pub fn is_sparkplug_b_topic(&self, topic: &str) -> bool {
@@ -196,6 +202,10 @@ impl SparkPlugBApplicationHelper {
             if parts.len() < 3 || parts.len() > 5 {
                 return false;
             }
+            // the application state of the groups left out is never built
+            if parts[1] != "STATE" && !self.groups.tracks(parts[1]) {
+                return false;
+            }
             if parts[2] == "NBIRTH"
                 || parts[2] == "NDEATH"
                 || parts[2] == "NDATA"
@@ -220,7 +230,25 @@ impl SparkPlugBApplicationHelper {
         topic: String,
         payload: Bytes,
     ) {
+        if let Some(bridge) = &self.bridge {
+            let local = Local {
+                topic: topic.clone(),
+                payload: payload.clone(),
+            };
+            // a bridge which does not keep up loses messages, the router never waits on it
+            if bridge.try_send(local).is_err() {
+                debug!("sparkplug b bridge queue full, message dropped");
+            }
+        }
+        replicated::sparkplug_b(&topic, qos, &payload);
         let publish = Publish::new(client_id, retain, qos, topic, payload);
         self.tx.send(publish).await.ok();
     }
+
+    /// a birth or death another member of the replicated store received, neither bridged nor
+    /// replicated again
+    pub async fn replay(&self, topic: String, qos: QoS, payload: Bytes) {
+        let publish = Publish::new(String::new(), false, qos, topic, payload);
+        self.tx.send(publish).await.ok();
+    }
 }
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize, de::Visitor};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time::{Duration, timeout};
 
 use crate::error::AxonError;
 
-use crate::service::sparkplug_b::model::{metric::Metric, template::Template};
+use crate::service::sparkplug_b::event::Event;
+use crate::service::sparkplug_b::model::{history::Sample, metric::Metric, template::Template};
 
 #[derive(Clone, Serialize)]
 pub struct GetNodeResponse {
@@ -30,6 +31,24 @@ pub struct GetDeviceResponse {
     pub metrics: Vec<Metric>,
 }
 
+#[derive(Clone, Serialize)]
+pub struct MetricHistoryResponse {
+    pub name: String,
+    pub datatype: u32,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct HistoryQuery {
+    pub metric: String,
+    // instance name when the metric is a member of a template instance
+    pub template: Option<String>,
+    // only values with a newer timestamp, in milliseconds
+    pub since: Option<u64>,
+    // only the most recent values
+    pub limit: Option<usize>,
+}
+
 #[derive(Clone)]
 pub enum FlattenValue {
     Bool(bool),
@@ -269,6 +288,13 @@ pub enum InMessage {
         device: Option<String>,
         resp: oneshot::Sender<Result<Vec<GetDeviceResponse>, AxonError>>,
     },
+    GetHistory {
+        group_id: String,
+        node_id: String,
+        device: Option<String>,
+        query: Box<HistoryQuery>,
+        resp: oneshot::Sender<Result<MetricHistoryResponse, AxonError>>,
+    },
     SetNodeRequest {
         req: SetNodeRequest,
         resp: oneshot::Sender<Result<Vec<(String, String)>, AxonError>>,
@@ -277,16 +303,27 @@ pub enum InMessage {
         req: SetDeviceRequest,
         resp: oneshot::Sender<Result<Vec<(String, String)>, AxonError>>,
     },
+    Rebirth {
+        group_id: String,
+        node_id: String,
+        resp: oneshot::Sender<Result<Vec<(String, String)>, AxonError>>,
+    },
 }
 
 #[derive(Clone)]
 pub struct InHelper {
     tx: mpsc::Sender<InMessage>,
+    events: broadcast::Sender<Event>,
 }
 
 impl InHelper {
-    pub fn new(tx: mpsc::Sender<InMessage>) -> Self {
-        InHelper { tx }
+    pub fn new(tx: mpsc::Sender<InMessage>, events: broadcast::Sender<Event>) -> Self {
+        InHelper { tx, events }
+    }
+
+    /// node online/offline and metric updates from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
     }
 
     pub async fn get_groups(&self, group: Option<String>) -> Result<Vec<String>, AxonError> {
@@ -343,6 +380,47 @@ impl InHelper {
         .await??
     }
 
+    /// recent values of a node metric, or of a device metric when device is set
+    pub async fn get_history(
+        &self,
+        group_id: String,
+        node_id: String,
+        device: Option<String>,
+        query: HistoryQuery,
+    ) -> Result<MetricHistoryResponse, AxonError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let msg = InMessage::GetHistory {
+            group_id,
+            node_id,
+            device,
+            query: Box::new(query),
+            resp: resp_tx,
+        };
+
+        timeout(Duration::from_secs(5), {
+            self.tx.send(msg).await?;
+            resp_rx
+        })
+        .await??
+    }
+
+    /// sends an NCMD setting `Node Control/Rebirth`, answered once it is published
+    pub async fn rebirth(&self, group_id: String, node_id: String) -> Result<(), AxonError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        let msg = InMessage::Rebirth {
+            group_id,
+            node_id,
+            resp: resp_tx,
+        };
+
+        timeout(Duration::from_secs(5), {
+            self.tx.send(msg).await?;
+            resp_rx
+        })
+        .await???;
+        Ok(())
+    }
+
     pub async fn set_node(
         &self,
         group_id: String,
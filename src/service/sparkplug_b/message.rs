@@ -58,6 +58,19 @@ pub struct Message {
     pub msg: MessageType,
 }
 
+impl MessageType {
+    /// the seq checked against the node birth, None for births, deaths and commands
+    pub fn seq(&self) -> Option<u8> {
+        match self {
+            MessageType::NodeData { seq, .. }
+            | MessageType::DeviceBirth { seq, .. }
+            | MessageType::DeviceDeath { seq, .. }
+            | MessageType::DeviceData { seq, .. } => Some(*seq),
+            _ => None,
+        }
+    }
+}
+
 impl Message {
     pub fn parse_nbirth(
         group_id: impl ToString,
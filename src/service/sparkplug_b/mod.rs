@@ -1,31 +1,63 @@
+mod bridge;
 mod cmd;
+pub mod decode;
 pub mod error;
+pub mod event;
 pub mod helper;
 pub mod in_helper;
 mod message;
 mod model;
+mod namespace;
+mod offline;
+mod projection;
 mod proto;
+mod rebirth;
+mod seq;
 mod utils;
 
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use prost::Message;
-use tokio::sync::mpsc;
-use tracing::{debug, info, info_span};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, warn};
 
+use crate::error::AxonError;
+use crate::hook;
+use crate::mqtt::QoS;
+use crate::mqtt::helper::BrokerHelper;
 use crate::service::sparkplug_b::model::device::Device;
+use crate::utils::time::now_milliseconds;
 use crate::{CONFIG, operator::helper::Helper as OperatorHelper};
 
 use error::SpbError;
-use in_helper::{GetDeviceResponse, GetNodeResponse, InHelper, InMessage};
-use message::MessageType;
-use model::{group::Group, node::Node};
+use event::Event;
+use in_helper::{
+    GetDeviceResponse, GetNodeResponse, HistoryQuery, InHelper, InMessage, MetricHistoryResponse,
+};
+use message::{Message as SpbMessage, MessageType};
+use model::{group::Group, history, node::Node};
+use offline::OfflineDelay;
+use projection::Projection;
 use proto::Payload;
+use rebirth::{Rebirth, RebirthPolicy};
+use seq::Order;
+
+/// an NCMD/DCMD built from a write request, answered once it is published
+struct Command {
+    topic: String,
+    payload: Payload,
+    result: Vec<(String, String)>,
+    resp: oneshot::Sender<Result<Vec<(String, String)>, AxonError>>,
+}
 
 pub struct SparkPlugBApplication {
     rx: Option<mpsc::Receiver<helper::Publish>>,
+    bridge_rx: Option<mpsc::Receiver<bridge::Local>>,
     in_rx: Option<mpsc::Receiver<InMessage>>,
+    events_tx: broadcast::Sender<Event>,
     helper: helper::SparkPlugBApplicationHelper,
     in_helper: InHelper,
 }
@@ -33,13 +65,28 @@ pub struct SparkPlugBApplication {
 impl SparkPlugBApplication {
     pub fn new() -> Self {
         let (tx, rx) = mpsc::channel(128);
-        let helper = helper::SparkPlugBApplicationHelper::new(tx.clone());
+        let config = &CONFIG.get().unwrap().service.sparkplug_b;
+        let (bridge_tx, bridge_rx) = match config.bridge.as_ref().filter(|b| b.enable) {
+            Some(_) => {
+                let (tx, rx) = mpsc::channel(1024);
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+        let helper = helper::SparkPlugBApplicationHelper::new(
+            tx.clone(),
+            namespace::GroupFilter::new(&config.groups),
+            bridge_tx,
+        );
         let (in_tx, in_rx) = mpsc::channel(16);
-        let in_helper = InHelper::new(in_tx);
+        let (events_tx, _) = broadcast::channel(1024);
+        let in_helper = InHelper::new(in_tx, events_tx.clone());
 
         SparkPlugBApplication {
             rx: Some(rx),
+            bridge_rx,
             in_rx: Some(in_rx),
+            events_tx,
             helper,
             in_helper,
         }
@@ -53,66 +100,180 @@ impl SparkPlugBApplication {
         self.in_helper.clone()
     }
 
-    pub async fn run(&mut self, operator_helper: OperatorHelper) {
+    pub async fn run(
+        &mut self,
+        operator_helper: OperatorHelper,
+        broker_helper: BrokerHelper,
+    ) -> JoinHandle<()> {
         let rx = self.rx.take().unwrap();
         let in_rx = self.in_rx.take().unwrap();
+        let config = &CONFIG.get().unwrap().service.sparkplug_b;
+        if let (Some(bridge_rx), Some(bridge)) = (self.bridge_rx.take(), config.bridge.as_ref()) {
+            tokio::spawn(bridge::run(
+                bridge,
+                bridge_rx,
+                namespace::GroupFilter::new(&config.groups),
+                operator_helper.clone(),
+                broker_helper.clone(),
+            ));
+        }
+        let events_tx = self.events_tx.clone();
         let mut groups = HashMap::<String, Group>::new();
-        let rebirth_on_error = CONFIG.get().unwrap().service.sparkplug_b.rebirth_on_error;
+        let rebirth_on_error = &CONFIG.get().unwrap().service.sparkplug_b.rebirth_on_error;
+        let mut rebirth = RebirthPolicy::new(rebirth_on_error);
+        let mut offline =
+            OfflineDelay::new(CONFIG.get().unwrap().service.sparkplug_b.offline_delay_secs);
 
         tokio::spawn(async move {
             let mut rx = rx;
             let mut in_rx = in_rx;
             let mut cmd = cmd::Cmd::new();
             let _ = operator_helper.sparkplug_b_state_online().await;
+            let mut offline_tick = tokio::time::interval(Duration::from_secs(1));
 
             loop {
                 tokio::select! {
                     Some(mut publish) = rx.recv() => {
-                        if let Err(e) = Self::on_message(&mut publish, &mut groups) {
+                        let mut events = Vec::new();
+                        let result = Self::on_message(&mut publish, &mut groups, &mut offline, &mut events);
+                        Self::dispatch(&operator_helper, &broker_helper, &events_tx, events).await;
+                        if let Err(e) = result {
                             if let Some(gn) = publish.gn.as_ref() {
                                 debug!("message error: {} for group: {}, node: {}", e, gn.0, gn.1);
                             } else {
                                 debug!("message error: {}", e);
                             }
-                            match e {
+                            let wanted = match e {
                                 SpbError::NodeNotBirth
                                 | SpbError::DeviceNotBirth
                                 | SpbError::MetricNotFound
-                                | SpbError::MetricNotMatch => {
-                                    if let Some(gn) = publish.gn {
-                                         let (topic, payload) = cmd.node_rebirth(
-                                            gn.0,
-                                            gn.1,
-                                            None,
-                                        );
-                                        if rebirth_on_error.on_malformed_payload {
-                                            let _ = operator_helper.sparkplug_b_publish(
-                                                topic, payload
-                                            ).await;
-                                        }
-                                    }
-                                }
-                                SpbError::InvalidTopic => {}
-                                _ => {}
+                                | SpbError::MetricNotMatch => rebirth_on_error.on_malformed_payload,
+                                SpbError::SeqMismatch => rebirth_on_error.on_seq_mismatch,
+                                _ => false,
+                            };
+                            if let (true, Some(gn)) = (wanted, publish.gn) {
+                                Self::rebirth(&mut rebirth, &mut cmd, &operator_helper, gn, e).await;
                             }
                         }
                     }
+                    _ = offline_tick.tick(), if offline.enabled() => {
+                        let mut events = Vec::new();
+                        for death in offline.due(Instant::now()) {
+                            let span = info_span!("spb_message", group_id = %death.group_id, node_id = %death.node_id);
+                            match Self::node_death(&mut groups, &death.group_id, &death.node_id, death.timestamp, death.bd_seq, &mut events) {
+                                Ok(()) => info!(parent: &span, "Node died"),
+                                Err(e) => debug!(parent: &span, "delayed death error: {}", e),
+                            }
+                        }
+                        Self::dispatch(&operator_helper, &broker_helper, &events_tx, events).await;
+                    }
                     Some(in_msg) = in_rx.recv() => {
-                        if let Some((topic, payload)) = Self::in_message(in_msg, &mut groups) {
-                            let _ = operator_helper.sparkplug_b_publish(
-                                topic, Bytes::from(payload.encode_to_vec())
+                        if let Some(command) = Self::in_message(in_msg, &mut groups, &mut cmd) {
+                            let result = operator_helper.sparkplug_b_publish(
+                                command.topic, Bytes::from(command.payload.encode_to_vec())
                             ).await;
+                            let _ = match result {
+                                Ok(()) => command.resp.send(Ok(command.result)),
+                                Err(e) => {
+                                    debug!("command publish error: {}", e);
+                                    command.resp.send(Err(SpbError::CommandNotPublished.into()))
+                                }
+                            };
                         }
                     }
                 }
             }
-        });
+        })
+    }
+
+    /// hands the events to the projection, the hooks and the stream subscribers
+    async fn dispatch(
+        operator_helper: &OperatorHelper,
+        broker_helper: &BrokerHelper,
+        events_tx: &broadcast::Sender<Event>,
+        events: Vec<Event>,
+    ) {
+        for projection in projection::project(&events) {
+            Self::project(operator_helper, broker_helper, projection).await;
+        }
+        for event in events {
+            if let Some(hook_event) = event.hook() {
+                hook::emit(hook_event);
+            }
+            // no stream subscriber is not an error
+            let _ = events_tx.send(event);
+        }
+    }
+
+    async fn project(
+        operator_helper: &OperatorHelper,
+        broker_helper: &BrokerHelper,
+        projection: Projection,
+    ) {
+        if projection.retain {
+            let _ = broker_helper
+                .retain_message(
+                    None,
+                    projection.topic.clone(),
+                    QoS::AtMostOnce,
+                    projection.payload.clone(),
+                    vec![],
+                    Default::default(),
+                )
+                .await;
+        }
+        let _ = operator_helper
+            .sparkplug_b_publish(projection.topic, projection.payload)
+            .await;
+    }
+
+    /// ask the node to rebirth unless the policy throttles it, escalate a node that keeps failing
+    async fn rebirth(
+        policy: &mut RebirthPolicy,
+        cmd: &mut cmd::Cmd,
+        operator_helper: &OperatorHelper,
+        (group_id, node_id): (String, String),
+        error: SpbError,
+    ) {
+        match policy.request(&group_id, &node_id, Instant::now()) {
+            Rebirth::Send => {
+                let (topic, payload) = cmd.node_rebirth(group_id, node_id, None);
+                let _ = operator_helper.sparkplug_b_publish(topic, payload).await;
+            }
+            Rebirth::Throttled => {
+                debug!(
+                    "rebirth throttled for group: {}, node: {}",
+                    group_id, node_id
+                );
+            }
+            Rebirth::Escalate(attempts) => {
+                warn!(
+                    "group: {}, node: {} still failing after {} rebirths: {}, no more rebirths requested",
+                    group_id, node_id, attempts, error
+                );
+                let config = &CONFIG.get().unwrap().service.sparkplug_b.rebirth_on_error;
+                if let Some(topic) = config.alert_topic.clone() {
+                    let alert = serde_json::json!({
+                        "group_id": group_id,
+                        "node_id": node_id,
+                        "attempts": attempts,
+                        "error": error.to_string(),
+                        "timestamp": now_milliseconds(),
+                    });
+                    let _ = operator_helper
+                        .sparkplug_b_publish(topic, Bytes::from(alert.to_string()))
+                        .await;
+                }
+            }
+            Rebirth::GaveUp => {}
+        }
     }
 
     fn in_message(
         msg: InMessage,
         groups: &mut HashMap<String, Group>,
-    ) -> Option<(String, Payload)> {
+        cmd: &mut cmd::Cmd,
+    ) -> Option<Command> {
         use InMessage::*;
         match msg {
             GetGroups { group, resp } => {
@@ -191,6 +352,16 @@ impl SparkPlugBApplication {
                 }
                 None
             }
+            GetHistory {
+                group_id,
+                node_id,
+                device,
+                query,
+                resp,
+            } => {
+                let _ = resp.send(Self::history(groups, &group_id, &node_id, device, *query));
+                None
+            }
             SetNodeRequest { req, resp } => {
                 let group = groups.get(&req.group_id).ok_or(SpbError::GroupNotFound);
                 if group.is_err() {
@@ -208,13 +379,36 @@ impl SparkPlugBApplication {
                 }
 
                 let (payload, result) = node.unwrap().command(req.kvs);
-                let _ = resp.send(Ok(result));
                 if let Some(payload) = payload {
-                    Some((utils::ncmd_topic(&req.group_id, &req.node_id), payload))
+                    Some(Command {
+                        topic: utils::ncmd_topic(&req.group_id, &req.node_id),
+                        payload,
+                        result,
+                        resp,
+                    })
                 } else {
+                    let _ = resp.send(Ok(result));
                     None
                 }
             }
+            Rebirth {
+                group_id,
+                node_id,
+                resp,
+            } => {
+                // a node the application has not seen is asked too, that is how it gets known
+                let alias = groups
+                    .get(&group_id)
+                    .and_then(|group| group.nodes.get(&node_id))
+                    .and_then(|node| node.metrics.get("Node Control/Rebirth"))
+                    .and_then(|metric| metric.alias);
+                Some(Command {
+                    topic: utils::ncmd_topic(&group_id, &node_id),
+                    payload: cmd.node_rebirth_payload(alias),
+                    result: vec![],
+                    resp,
+                })
+            }
             SetDeviceRequest { req, resp } => {
                 let group = groups.get(&req.group_id).ok_or(SpbError::GroupNotFound);
                 if group.is_err() {
@@ -241,25 +435,139 @@ impl SparkPlugBApplication {
                 }
 
                 let (payload, result) = device.unwrap().command(req.kvs);
-                let _ = resp.send(Ok(result));
                 if let Some(payload) = payload {
-                    Some((
-                        utils::dcmd_topic(&req.group_id, &req.node_id, &req.device),
+                    Some(Command {
+                        topic: utils::dcmd_topic(&req.group_id, &req.node_id, &req.device),
                         payload,
-                    ))
+                        result,
+                        resp,
+                    })
                 } else {
+                    let _ = resp.send(Ok(result));
                     None
                 }
             }
         }
     }
 
+    fn history(
+        groups: &HashMap<String, Group>,
+        group_id: &str,
+        node_id: &str,
+        device: Option<String>,
+        query: HistoryQuery,
+    ) -> Result<MetricHistoryResponse, AxonError> {
+        let node = groups
+            .get(group_id)
+            .ok_or(SpbError::GroupNotFound)?
+            .nodes
+            .get(node_id)
+            .ok_or(SpbError::NodeNotFound)?;
+        let metrics = match device {
+            Some(device) => {
+                &node
+                    .devices
+                    .get(&device)
+                    .ok_or(SpbError::DeviceNotFound)?
+                    .metrics
+            }
+            None => &node.metrics,
+        };
+
+        let metric = history::lookup(metrics, query.template.as_deref(), &query.metric)?;
+        Ok(MetricHistoryResponse {
+            name: metric.name.clone(),
+            datatype: metric.datatype,
+            samples: metric.history.query(query.since, query.limit),
+        })
+    }
+
     fn on_message(
         publish: &mut helper::Publish,
         groups: &mut HashMap<String, Group>,
+        offline: &mut OfflineDelay,
+        events: &mut Vec<Event>,
     ) -> Result<(), SpbError> {
-        use MessageType::*;
         let message = publish.parse()?;
+        let (messages, order) = Self::sequence(message, groups);
+        for message in messages {
+            Self::apply(message, groups, offline, events)?;
+        }
+        match order {
+            Order::Sequential | Order::Gap => Ok(()),
+            Order::GapExceeded => Err(SpbError::SeqMismatch),
+        }
+    }
+
+    /// hold or release a message by its seq, `service.sparkplug_b.seq`
+    fn sequence(
+        message: SpbMessage,
+        groups: &mut HashMap<String, Group>,
+    ) -> (Vec<SpbMessage>, Order) {
+        let config = &CONFIG.get().unwrap().service.sparkplug_b.seq;
+        let Some(seq) = message.msg.seq().filter(|_| config.validate) else {
+            return (vec![message], Order::Sequential);
+        };
+        // a message of a node that is not born is left for apply to reject
+        let Some(node) = groups
+            .get_mut(&message.group_id)
+            .and_then(|g| g.nodes.get_mut(&message.node_id))
+            .filter(|node| node.online)
+        else {
+            return (vec![message], Order::Sequential);
+        };
+
+        let (group_id, node_id) = (message.group_id.clone(), message.node_id.clone());
+        let (messages, order) =
+            node.seq
+                .accept(seq, message, config.reorder_window, config.gap_threshold);
+        if order != Order::Sequential {
+            debug!(
+                "seq gap at {} for group: {}, node: {}",
+                seq, group_id, node_id
+            );
+        }
+        (messages, order)
+    }
+
+    fn node_death(
+        groups: &mut HashMap<String, Group>,
+        group_id: &str,
+        node_id: &str,
+        timestamp: u64,
+        bd_seq: u64,
+        events: &mut Vec<Event>,
+    ) -> Result<(), SpbError> {
+        let node = groups
+            .get_mut(group_id)
+            .and_then(|g| g.nodes.get_mut(node_id))
+            .ok_or(SpbError::NodeNotFound)?;
+        // the devices die with their node
+        let online = node
+            .devices
+            .iter()
+            .filter(|(_, device)| device.online)
+            .map(|(device_id, _)| Some(device_id.clone()))
+            .collect::<Vec<_>>();
+        node.death(timestamp, bd_seq)?;
+        for device_id in online.into_iter().chain([None]) {
+            events.push(Event::Offline {
+                group_id: group_id.to_string(),
+                node_id: node_id.to_string(),
+                device_id,
+                timestamp,
+            });
+        }
+        Ok(())
+    }
+
+    fn apply(
+        message: SpbMessage,
+        groups: &mut HashMap<String, Group>,
+        offline: &mut OfflineDelay,
+        events: &mut Vec<Event>,
+    ) -> Result<(), SpbError> {
+        use MessageType::*;
         let span = if let Some(ref device) = message.device_id {
             info_span!("spb_message", group_id = %message.group_id, node_id = %message.node_id, device_id = %device)
         } else {
@@ -268,7 +576,7 @@ impl SparkPlugBApplication {
 
         match message.msg {
             NodeBirth {
-                seq: _,
+                seq,
                 timestamp,
                 bd_seq,
                 metrics,
@@ -284,24 +592,67 @@ impl SparkPlugBApplication {
 
                 let mut node = Node::new(&message.node_id, timestamp, bd_seq);
                 node.birth_with_metrics(timestamp, metrics)?;
+                node.seq.birth(seq);
 
                 let group = groups
                     .entry(message.group_id.clone())
                     .or_insert_with(|| Group {
                         nodes: HashMap::new(),
                     });
+                if let Some(old) = group.nodes.remove(&message.node_id) {
+                    history::inherit(&mut node.metrics, old.metrics);
+                    node.previous_devices = old.devices;
+                }
+                // back within the offline delay, the node was never seen offline
+                if !offline.cancel(&message.group_id, &message.node_id) {
+                    events.push(Event::Online {
+                        group_id: message.group_id.clone(),
+                        node_id: message.node_id.clone(),
+                        device_id: None,
+                        timestamp,
+                    });
+                }
+                event::metrics(
+                    &message.group_id,
+                    &message.node_id,
+                    None,
+                    node.metrics.values(),
+                    events,
+                );
                 group.nodes.insert(message.node_id.clone(), node);
                 info!(parent: &span, "Node born");
             }
             NodeDeath { timestamp, bd_seq } => {
-                if let Some(node) = groups
-                    .get_mut(&message.group_id)
-                    .and_then(|g| g.nodes.get_mut(&message.node_id))
-                {
-                    node.death(timestamp, bd_seq)?;
-                    info!(parent: &span, "Node died");
+                if offline.enabled() {
+                    // checked now, applied once the delay is over
+                    let node = groups
+                        .get(&message.group_id)
+                        .and_then(|g| g.nodes.get(&message.node_id))
+                        .ok_or(SpbError::NodeNotFound)?;
+                    if timestamp < node.timestamp {
+                        return Err(SpbError::Exceeded);
+                    }
+                    if bd_seq != node.bd_seq {
+                        return Err(SpbError::NDeathNotMatch);
+                    }
+                    offline.hold(
+                        &message.group_id,
+                        &message.node_id,
+                        timestamp,
+                        bd_seq,
+                        Instant::now(),
+                    );
+                    info!(parent: &span, "Node death delayed");
                 } else {
-                    return Err(SpbError::NodeNotFound);
+                    Self::node_death(
+                        groups,
+                        &message.group_id,
+                        &message.node_id,
+                        timestamp,
+                        bd_seq,
+                        events,
+                    )?;
+                    info!(parent: &span, "Node died");
                 }
             }
             NodeData {
@@ -313,13 +664,22 @@ impl SparkPlugBApplication {
                     .get_mut(&message.group_id)
                     .and_then(|g| g.nodes.get_mut(&message.node_id))
                 {
+                    let names = utils::metric_names(&metrics, &node.aliases);
                     node.update_metrics(timestamp, metrics)?;
+                    event::metrics(
+                        &message.group_id,
+                        &message.node_id,
+                        None,
+                        names.iter().filter_map(|name| node.metrics.get(name)),
+                        events,
+                    );
                 } else {
                     return Err(SpbError::NodeNotBirth);
                 }
             }
+            // commands from other host applications, the node answers with NDATA
             NodeCommand { .. } => {
-                return Err(SpbError::NotSupportCommand);
+                debug!(parent: &span, "Node command");
             }
             DeviceBirth {
                 seq: _,
@@ -343,8 +703,28 @@ impl SparkPlugBApplication {
                     let mut device = Device::new(message.device_id.clone().unwrap(), timestamp);
                     device.birth_with_metrics(node, timestamp, metrics)?;
 
-                    node.devices
-                        .insert(message.device_id.clone().unwrap(), device);
+                    let device_id = message.device_id.clone().unwrap();
+                    if let Some(old) = node
+                        .devices
+                        .remove(&device_id)
+                        .or_else(|| node.previous_devices.remove(&device_id))
+                    {
+                        history::inherit(&mut device.metrics, old.metrics);
+                    }
+                    events.push(Event::Online {
+                        group_id: message.group_id.clone(),
+                        node_id: message.node_id.clone(),
+                        device_id: Some(device_id.clone()),
+                        timestamp,
+                    });
+                    event::metrics(
+                        &message.group_id,
+                        &message.node_id,
+                        Some(&device_id),
+                        device.metrics.values(),
+                        events,
+                    );
+                    node.devices.insert(device_id, device);
                     info!(parent: &span, "Device born");
                 } else {
                     return Err(SpbError::NodeNotBirth);
@@ -358,8 +738,15 @@ impl SparkPlugBApplication {
                     if node.online == false {
                         return Err(SpbError::NodeNotBirth);
                     }
-                    if let Some(device) = node.devices.get_mut(&message.device_id.unwrap()) {
+                    if let Some(device) = node.devices.get_mut(message.device_id.as_ref().unwrap())
+                    {
                         device.death(timestamp)?;
+                        events.push(Event::Offline {
+                            group_id: message.group_id.clone(),
+                            node_id: message.node_id.clone(),
+                            device_id: message.device_id.clone(),
+                            timestamp,
+                        });
                         info!(parent: &span, "Device died");
                     } else {
                         return Err(SpbError::DeviceNotBirth);
@@ -386,6 +773,7 @@ impl SparkPlugBApplication {
                 };
 
                 if let Some(mut device) = device {
+                    let names = utils::metric_names(&metrics, &device.aliases);
                     device.update_metrics(
                         groups
                             .get(&message.group_id)
@@ -394,6 +782,13 @@ impl SparkPlugBApplication {
                         timestamp,
                         metrics,
                     )?;
+                    event::metrics(
+                        &message.group_id,
+                        &message.node_id,
+                        message.device_id.as_deref(),
+                        names.iter().filter_map(|name| device.metrics.get(name)),
+                        events,
+                    );
                     groups
                         .get_mut(&message.group_id)
                         .and_then(|g| g.nodes.get_mut(&message.node_id))
@@ -405,7 +800,7 @@ impl SparkPlugBApplication {
                 }
             }
             DeviceCommand { .. } => {
-                return Err(SpbError::NotSupportCommand);
+                debug!(parent: &span, "Device command");
             }
         }
 
@@ -0,0 +1,169 @@
+use serde::Serialize;
+
+use super::super::error::SpbError;
+use super::super::proto::payload;
+use super::value::Value;
+
+use payload::data_set::data_set_value::Value as DV;
+
+#[derive(Clone, Serialize)]
+pub struct DataSet {
+    pub columns: Vec<String>,
+    // scalar Sparkplug B datatype of each column
+    pub types: Vec<u32>,
+    // a None element is a cell the edge node left empty
+    pub rows: Vec<Vec<Option<Value>>>,
+}
+
+fn is_scalar(tp: u32) -> bool {
+    // Int8 to UUID, a cell cannot hold a dataset, bytes, a file or a template
+    (1..=15).contains(&tp)
+}
+
+impl TryFrom<payload::DataSet> for DataSet {
+    type Error = SpbError;
+
+    fn try_from(ds: payload::DataSet) -> Result<Self, Self::Error> {
+        let num_of_columns = ds.num_of_columns.ok_or(SpbError::InvalidDataSet)? as usize;
+        if ds.columns.len() != num_of_columns || ds.types.len() != num_of_columns {
+            return Err(SpbError::InvalidDataSet);
+        }
+        if !ds.types.iter().all(|tp| is_scalar(*tp)) {
+            return Err(SpbError::InvalidDataSet);
+        }
+
+        let mut rows = Vec::with_capacity(ds.rows.len());
+        for row in ds.rows {
+            if row.elements.len() != num_of_columns {
+                return Err(SpbError::InvalidDataSet);
+            }
+            let elements = row
+                .elements
+                .into_iter()
+                .zip(ds.types.iter())
+                .map(|(element, tp)| match element.value {
+                    Some(v) => Ok(Some(cell((v, *tp))?)),
+                    None => Ok(None),
+                })
+                .collect::<Result<Vec<Option<Value>>, SpbError>>()?;
+            rows.push(elements);
+        }
+
+        Ok(DataSet {
+            columns: ds.columns,
+            types: ds.types,
+            rows,
+        })
+    }
+}
+
+fn cell((value, tp): (DV, u32)) -> Result<Value, SpbError> {
+    use payload::metric::Value as MV;
+
+    let value = match value {
+        DV::IntValue(v) => MV::IntValue(v),
+        DV::LongValue(v) => MV::LongValue(v),
+        DV::FloatValue(v) => MV::FloatValue(v),
+        DV::DoubleValue(v) => MV::DoubleValue(v),
+        DV::BooleanValue(v) => MV::BooleanValue(v),
+        DV::StringValue(v) => MV::StringValue(v),
+        DV::ExtensionValue(_) => return Err(SpbError::InvalidDataSet),
+    };
+    Value::try_from((value, Some(tp))).map_err(|_| SpbError::InvalidDataSet)
+}
+
+impl From<DataSet> for payload::DataSet {
+    fn from(ds: DataSet) -> Self {
+        use payload::metric::Value as MV;
+
+        let rows = ds
+            .rows
+            .into_iter()
+            .map(|row| payload::data_set::Row {
+                elements: row
+                    .into_iter()
+                    .map(|element| payload::data_set::DataSetValue {
+                        // decoding only keeps scalar cells, so every cell maps back
+                        value: element.and_then(|v| match MV::from(v) {
+                            MV::IntValue(v) => Some(DV::IntValue(v)),
+                            MV::LongValue(v) => Some(DV::LongValue(v)),
+                            MV::FloatValue(v) => Some(DV::FloatValue(v)),
+                            MV::DoubleValue(v) => Some(DV::DoubleValue(v)),
+                            MV::BooleanValue(v) => Some(DV::BooleanValue(v)),
+                            MV::StringValue(v) => Some(DV::StringValue(v)),
+                            _ => None,
+                        }),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        payload::DataSet {
+            num_of_columns: Some(ds.columns.len() as u64),
+            columns: ds.columns,
+            types: ds.types,
+            rows,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(value: Option<DV>) -> payload::data_set::DataSetValue {
+        payload::data_set::DataSetValue { value }
+    }
+
+    #[test]
+    fn test_dataset() {
+        let pds = payload::DataSet {
+            num_of_columns: Some(3),
+            columns: vec!["id".to_string(), "temp".to_string(), "name".to_string()],
+            types: vec![7, 10, 12],
+            rows: vec![
+                payload::data_set::Row {
+                    elements: vec![
+                        element(Some(DV::IntValue(1))),
+                        element(Some(DV::DoubleValue(21.5))),
+                        element(Some(DV::StringValue("a".to_string()))),
+                    ],
+                },
+                payload::data_set::Row {
+                    elements: vec![
+                        element(Some(DV::IntValue(2))),
+                        element(None),
+                        element(Some(DV::StringValue("b".to_string()))),
+                    ],
+                },
+            ],
+        };
+
+        let ds = DataSet::try_from(pds.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&ds).unwrap(),
+            serde_json::json!({
+                "columns": ["id", "temp", "name"],
+                "types": [7, 10, 12],
+                "rows": [[1, 21.5, "a"], [2, null, "b"]],
+            })
+        );
+        assert_eq!(payload::DataSet::from(ds), pds);
+
+        // a row shorter than the columns
+        let mut short = pds.clone();
+        short.rows[0].elements.pop();
+        assert!(matches!(
+            DataSet::try_from(short),
+            Err(SpbError::InvalidDataSet)
+        ));
+
+        // a cell that does not match its column type
+        let mut mismatch = pds;
+        mismatch.rows[0].elements[1] = element(Some(DV::StringValue("hot".to_string())));
+        assert!(matches!(
+            DataSet::try_from(mismatch),
+            Err(SpbError::InvalidDataSet)
+        ));
+    }
+}
@@ -190,6 +190,7 @@ impl Device {
                         datatype: Some(l_metric.datatype),
                         timestamp: now_milliseconds(),
                         is_null: None,
+                        is_historical: false,
                         properties: vec![],
                         value: Some(value.unwrap()),
                     };
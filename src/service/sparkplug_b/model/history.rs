@@ -0,0 +1,184 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+
+use crate::CONFIG;
+use crate::utils::time::now_milliseconds;
+
+use super::super::error::SpbError;
+use super::metric::Metric;
+use super::value::Value;
+
+const DEFAULT_DEPTH: usize = 100;
+const DEFAULT_MAX_AGE_SECS: u64 = 3600;
+
+#[derive(Clone, Serialize)]
+pub struct Sample {
+    pub timestamp: u64,
+    // None when the metric was reported null
+    pub value: Option<Value>,
+}
+
+/// recent values of a metric, bounded by `service.sparkplug_b.history`
+#[derive(Clone, Default)]
+pub struct History {
+    samples: VecDeque<Sample>,
+}
+
+fn limits() -> (usize, u64) {
+    CONFIG
+        .get()
+        .map(|config| {
+            let history = &config.service.sparkplug_b.history;
+            (history.depth, history.max_age_secs)
+        })
+        .unwrap_or((DEFAULT_DEPTH, DEFAULT_MAX_AGE_SECS))
+}
+
+impl History {
+    /// keeps the samples in timestamp order, the historical values of a store and forward
+    /// replay arrive after newer ones
+    pub fn record(&mut self, timestamp: u64, value: Option<&Value>) {
+        // a template instance has no value of its own, its member metrics keep their history
+        if matches!(
+            value,
+            Some(Value::Template(_) | Value::TemplateInstance(_) | Value::TemplateDataInstance(_))
+        ) {
+            return;
+        }
+
+        let (depth, max_age_secs) = limits();
+        if depth == 0 || timestamp < now_milliseconds().saturating_sub(max_age_secs * 1000) {
+            return;
+        }
+        let sample = Sample {
+            timestamp,
+            value: value.cloned(),
+        };
+        if self
+            .samples
+            .back()
+            .is_none_or(|last| last.timestamp <= timestamp)
+        {
+            self.samples.push_back(sample);
+        } else {
+            let index = self.samples.partition_point(|s| s.timestamp <= timestamp);
+            self.samples.insert(index, sample);
+        }
+        self.trim();
+    }
+
+    /// keep the samples of the metric this one replaces on rebirth
+    pub fn inherit(&mut self, older: History) {
+        let mut samples = older.samples;
+        samples.extend(self.samples.drain(..));
+        self.samples = samples;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let (depth, max_age_secs) = limits();
+        let oldest = now_milliseconds().saturating_sub(max_age_secs * 1000);
+        while self.samples.len() > depth
+            || self.samples.front().is_some_and(|s| s.timestamp < oldest)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// samples newer than `since`, oldest first, the last `limit` of them when set
+    pub fn query(&self, since: Option<u64>, limit: Option<usize>) -> Vec<Sample> {
+        let (_, max_age_secs) = limits();
+        let oldest = now_milliseconds()
+            .saturating_sub(max_age_secs * 1000)
+            .max(since.unwrap_or(0));
+        let samples = self
+            .samples
+            .iter()
+            .filter(|s| s.timestamp >= oldest)
+            .cloned()
+            .collect::<Vec<_>>();
+        match limit {
+            Some(limit) if limit < samples.len() => samples[samples.len() - limit..].to_vec(),
+            _ => samples,
+        }
+    }
+}
+
+/// the metrics of a rebirth keep the history of the metrics they replace
+pub fn inherit(metrics: &mut HashMap<String, Metric>, mut older: HashMap<String, Metric>) {
+    for (name, metric) in metrics.iter_mut() {
+        let Some(old) = older.remove(name) else {
+            continue;
+        };
+        if old.datatype != metric.datatype {
+            continue;
+        }
+        if let (
+            Some(Value::TemplateInstance(instance)),
+            Some(Value::TemplateInstance(old_instance)),
+        ) = (metric.value.as_mut(), old.value)
+        {
+            inherit(&mut instance.metrics, old_instance.metrics);
+        }
+        metric.history.inherit(old.history);
+    }
+}
+
+/// a metric by name, or a member of the template instance metric `template`
+pub fn lookup<'a>(
+    metrics: &'a HashMap<String, Metric>,
+    template: Option<&str>,
+    name: &str,
+) -> Result<&'a Metric, SpbError> {
+    let Some(template) = template else {
+        return metrics.get(name).ok_or(SpbError::MetricNotFound);
+    };
+    match metrics.get(template).and_then(|m| m.value.as_ref()) {
+        Some(Value::TemplateInstance(instance)) => {
+            instance.metrics.get(name).ok_or(SpbError::MetricNotFound)
+        }
+        _ => Err(SpbError::MetricNotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let now = now_milliseconds();
+        let mut history = History::default();
+        for i in 0..150 {
+            history.record(now - 150 + i, Some(&Value::Int32(i as i32)));
+        }
+        // bounded by the default depth
+        let samples = history.query(None, None);
+        assert_eq!(samples.len(), DEFAULT_DEPTH);
+        assert!(matches!(samples[0].value, Some(Value::Int32(50))));
+
+        // too old to keep
+        history.record(now - (DEFAULT_MAX_AGE_SECS + 1) * 1000, None);
+        assert_eq!(history.query(None, None).len(), DEFAULT_DEPTH);
+
+        let samples = history.query(Some(now - 10), Some(3));
+        assert_eq!(samples.len(), 3);
+        assert!(matches!(samples[2].value, Some(Value::Int32(149))));
+
+        // a historical value goes in its place
+        history.record(now - 5, Some(&Value::Int32(-1)));
+        let samples = history.query(Some(now - 6), None);
+        assert!(matches!(samples[0].value, Some(Value::Int32(144))));
+        assert!(matches!(samples[1].value, Some(Value::Int32(145))));
+        assert!(matches!(samples[2].value, Some(Value::Int32(-1))));
+        assert!(matches!(samples[3].value, Some(Value::Int32(146))));
+
+        let mut reborn = History::default();
+        reborn.record(now, None);
+        reborn.inherit(history);
+        let samples = reborn.query(None, None);
+        assert_eq!(samples.len(), DEFAULT_DEPTH);
+        assert!(samples.last().unwrap().value.is_none());
+    }
+}
@@ -4,6 +4,7 @@ use crate::utils::time::now_milliseconds;
 
 use super::super::error::SpbError;
 use super::super::proto::payload;
+use super::history::History;
 use super::value::Value;
 
 #[derive(Clone, Serialize)]
@@ -62,6 +63,8 @@ pub struct Metric {
     #[serde(skip)]
     pub in_property: Vec<MetricProperty>,
     pub properties: Vec<Property>,
+    #[serde(skip)]
+    pub history: History,
 }
 
 impl Metric {
@@ -75,6 +78,9 @@ impl Metric {
     }
 
     pub fn update(&mut self, other: DataMetric) -> Result<(), SpbError> {
+        if other.is_historical {
+            return self.record_historical(other);
+        }
         self.timestamp = other.timestamp;
         self.is_null = other.is_null.unwrap_or(false);
 
@@ -99,9 +105,34 @@ impl Metric {
         }
 
         self.stale = false;
+        self.history.record(
+            self.timestamp,
+            if self.is_null {
+                None
+            } else {
+                self.value.as_ref()
+            },
+        );
 
         Ok(())
     }
+
+    /// only goes to the history, at its own timestamp, the current value stays
+    fn record_historical(&mut self, other: DataMetric) -> Result<(), SpbError> {
+        if other
+            .datatype
+            .is_some_and(|datatype| datatype != self.datatype)
+        {
+            return Err(SpbError::MetricNotMatch);
+        }
+        let value = if other.is_null.unwrap_or(false) {
+            None
+        } else {
+            other.value.as_ref()
+        };
+        self.history.record(other.timestamp, value);
+        Ok(())
+    }
 }
 
 impl TryFrom<&payload::Metric> for Metric {
@@ -133,7 +164,7 @@ impl TryFrom<&payload::Metric> for Metric {
             })
             .collect::<Vec<MetricProperty>>();
 
-        Ok(Metric {
+        let mut metric = Metric {
             name: pm.name.clone().ok_or(SpbError::InvalidMetric)?,
             alias: pm.alias,
             timestamp: pm.timestamp.unwrap_or(now_milliseconds()),
@@ -143,7 +174,17 @@ impl TryFrom<&payload::Metric> for Metric {
             value,
             in_property: in_property,
             properties: new_properties,
-        })
+            history: History::default(),
+        };
+        metric.history.record(
+            metric.timestamp,
+            if metric.is_null {
+                None
+            } else {
+                metric.value.as_ref()
+            },
+        );
+        Ok(metric)
     }
 }
 
@@ -154,6 +195,8 @@ pub struct DataMetric {
     pub timestamp: u64,
     pub datatype: Option<u32>,
     pub is_null: Option<bool>,
+    // a store and forward value, older than the current one
+    pub is_historical: bool,
     pub value: Option<Value>,
     pub properties: Vec<Property>,
 }
@@ -188,6 +231,7 @@ impl TryFrom<&payload::Metric> for DataMetric {
             timestamp: pm.timestamp.unwrap_or(now_milliseconds()),
             datatype: pm.datatype,
             is_null: pm.is_null,
+            is_historical: pm.is_historical.unwrap_or(false),
             value: if let Some(ref v) = pm.value {
                 Some(Value::try_from((v.clone(), pm.datatype))?)
             } else {
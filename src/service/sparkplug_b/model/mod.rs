@@ -1,5 +1,7 @@
+pub mod dataset;
 pub mod device;
 pub mod group;
+pub mod history;
 pub mod metric;
 pub mod node;
 pub mod template;
@@ -4,7 +4,9 @@ use crate::utils::time::now_milliseconds;
 
 use super::super::error::SpbError;
 use super::super::in_helper::{GetNodeResponse, KV};
+use super::super::message::Message;
 use super::super::proto;
+use super::super::seq::Sequence;
 use super::device::Device;
 use super::metric::{DataMetric, Metric};
 use super::template::{Template, TemplateDataInstance, TemplateInstance};
@@ -22,6 +24,9 @@ pub struct Node {
     pub templates: HashMap<String, Template>,
 
     pub devices: HashMap<String, Device>,
+    // devices of the previous birth, their metric history goes to the next DBIRTH
+    pub previous_devices: HashMap<String, Device>,
+    pub seq: Sequence<Message>,
 }
 
 impl From<&Node> for GetNodeResponse {
@@ -50,6 +55,8 @@ impl Node {
             bd_seq,
             timestamp,
             devices: HashMap::new(),
+            previous_devices: HashMap::new(),
+            seq: Sequence::default(),
             metrics: HashMap::new(),
             aliases: HashMap::new(),
             templates: HashMap::new(),
@@ -280,6 +287,7 @@ impl Node {
                         datatype: Some(l_metric.datatype),
                         timestamp: now_milliseconds(),
                         is_null: None,
+                        is_historical: false,
                         properties: vec![],
                         value: Some(value.unwrap()),
                     };
@@ -6,6 +6,7 @@ use uuid::Uuid;
 use super::super::error::SpbError;
 use super::super::in_helper::FlattenValue;
 use super::super::proto::payload;
+use super::dataset::DataSet;
 use super::metric::{DataMetric, Metric};
 use super::template::{Template, TemplateDataInstance, TemplateInstance};
 
@@ -27,7 +28,7 @@ pub enum Value {
     Text(String),  // UTF-8 encoded text
 
     UUID(String), // UTF-8 string
-    //DataSet(DataSetMetric),
+    DataSet(DataSet),
     Bytes(Vec<u8>), // Raw byte array
     File(Vec<u8>),  // Raw byte array representing a file
 
@@ -71,6 +72,7 @@ impl Serialize for Value {
             Value::DateTime(v) => serializer.serialize_u64(*v),
             Value::Text(v) => serializer.serialize_str(v),
             Value::UUID(v) => serializer.serialize_str(v),
+            Value::DataSet(v) => v.serialize(serializer),
             Value::Bytes(v) => serializer.serialize_bytes(v),
             Value::File(v) => serializer.serialize_bytes(v),
             Value::Template(_) => Err(serde::ser::Error::custom(
@@ -119,7 +121,7 @@ impl TryFrom<(payload::metric::Value, Option<u32>)> for Value {
             MV::LongValue(v) => match tp {
                 Some(4) => Ok(Value::Int64(v as i64)),
                 Some(8) => Ok(Value::UInt64(v)),
-                Some(11) => Ok(Value::DateTime(v)),
+                Some(13) => Ok(Value::DateTime(v)),
                 None => Ok(Value::UInt64(v)),
                 _ => Err(SpbError::InvalidDataType),
             },
@@ -297,9 +299,11 @@ impl TryFrom<(payload::metric::Value, Option<u32>)> for Value {
                 }
                 _ => Err(SpbError::InvalidDataType),
             },
-            _ => {
-                unimplemented!("conversion for complex types not implemented yet");
-            }
+            MV::DatasetValue(v) => match tp {
+                Some(16) | None => Ok(Value::DataSet(DataSet::try_from(v)?)),
+                _ => Err(SpbError::InvalidDataType),
+            },
+            MV::ExtensionValue(_) => Err(SpbError::InvalidDataType),
         }
     }
 }
@@ -326,6 +330,7 @@ impl From<Value> for payload::metric::Value {
             Value::UUID(v) => MV::StringValue(v),
             Value::Bytes(v) => MV::BytesValue(v),
             Value::File(v) => MV::BytesValue(v),
+            Value::DataSet(v) => MV::DatasetValue(v.into()),
             Value::BooleanArray(v) => {
                 let mut bytes = Vec::new();
                 let len = v.len() as u32;
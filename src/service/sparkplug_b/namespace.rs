@@ -0,0 +1,75 @@
+//! the group ids the application tracks, `[service.sparkplug_b.groups]`: the messages of the
+//! other groups are not handed to the application, they are still routed to the subscribers
+
+use crate::config::SpbGroupsConfig;
+
+/// `*` matches any run of characters and `?` a single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the last `*` and the text position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // the `*` takes one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Clone, Default)]
+pub struct GroupFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl GroupFilter {
+    pub fn new(config: &SpbGroupsConfig) -> Self {
+        GroupFilter {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        }
+    }
+
+    /// every group without an allow list, the deny list wins over the allow list
+    pub fn tracks(&self, group_id: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, group_id)))
+            && !self.deny.iter().any(|p| glob_match(p, group_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracks() {
+        assert!(glob_match("plant-*", "plant-a"));
+        assert!(glob_match("*-test", "plant-a-test"));
+        assert!(glob_match("line?", "line1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("line?", "line12"));
+        assert!(!glob_match("plant-*", "site-a"));
+
+        let filter = GroupFilter::new(&SpbGroupsConfig {
+            allow: vec!["plant-*".to_string()],
+            deny: vec!["*-test".to_string()],
+        });
+        assert!(filter.tracks("plant-a"));
+        assert!(!filter.tracks("plant-a-test"));
+        assert!(!filter.tracks("site-b"));
+        assert!(GroupFilter::default().tracks("anything"));
+    }
+}
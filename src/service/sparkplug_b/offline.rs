@@ -0,0 +1,115 @@
+//! NDEATHs held back by `service.sparkplug_b.offline_delay_secs`: the node stays online until
+//! the delay is over, and an NBIRTH within the delay cancels the death, so a connection flap
+//! does not show as an offline and online pair
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Death {
+    due: Instant,
+    timestamp: u64,
+    bd_seq: u64,
+}
+
+/// a held NDEATH whose delay is over
+#[derive(Debug, PartialEq)]
+pub struct DueDeath {
+    pub group_id: String,
+    pub node_id: String,
+    pub timestamp: u64,
+    pub bd_seq: u64,
+}
+
+pub struct OfflineDelay {
+    delay: Duration,
+    deaths: HashMap<(String, String), Death>,
+}
+
+impl OfflineDelay {
+    pub fn new(delay_secs: u64) -> Self {
+        OfflineDelay {
+            delay: Duration::from_secs(delay_secs),
+            deaths: HashMap::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.delay.is_zero()
+    }
+
+    /// holds an NDEATH, the first one of the node counts
+    pub fn hold(
+        &mut self,
+        group_id: &str,
+        node_id: &str,
+        timestamp: u64,
+        bd_seq: u64,
+        now: Instant,
+    ) {
+        self.deaths
+            .entry((group_id.to_string(), node_id.to_string()))
+            .or_insert(Death {
+                due: now + self.delay,
+                timestamp,
+                bd_seq,
+            });
+    }
+
+    /// drops the held NDEATH of a node born again, whether there was one
+    pub fn cancel(&mut self, group_id: &str, node_id: &str) -> bool {
+        !self.deaths.is_empty()
+            && self
+                .deaths
+                .remove(&(group_id.to_string(), node_id.to_string()))
+                .is_some()
+    }
+
+    pub fn due(&mut self, now: Instant) -> Vec<DueDeath> {
+        let mut due = vec![];
+        self.deaths.retain(|(group_id, node_id), death| {
+            if death.due > now {
+                return true;
+            }
+            due.push(DueDeath {
+                group_id: group_id.clone(),
+                node_id: node_id.clone(),
+                timestamp: death.timestamp,
+                bd_seq: death.bd_seq,
+            });
+            false
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_delay() {
+        let mut delay = OfflineDelay::new(5);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        delay.hold("g", "n1", 100, 1, at(0));
+        // the first NDEATH counts
+        delay.hold("g", "n1", 200, 2, at(3));
+        delay.hold("g", "n2", 300, 7, at(1));
+        assert!(delay.due(at(4)).is_empty());
+
+        // n2 came back
+        assert!(delay.cancel("g", "n2"));
+        assert!(!delay.cancel("g", "n2"));
+        assert_eq!(
+            delay.due(at(5)),
+            vec![DueDeath {
+                group_id: "g".to_string(),
+                node_id: "n1".to_string(),
+                timestamp: 100,
+                bd_seq: 1,
+            }]
+        );
+        assert!(delay.due(at(10)).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+//! republish decoded metrics as JSON on plain MQTT topics, one topic per metric, for consumers
+//! that do not speak Sparkplug B, `service.sparkplug_b.projection`
+
+use bytes::Bytes;
+
+use crate::CONFIG;
+
+use super::event::Event;
+
+pub struct Projection {
+    pub topic: String,
+    pub payload: Bytes,
+    pub retain: bool,
+}
+
+fn render(
+    template: &str,
+    group_id: &str,
+    node_id: &str,
+    device_id: Option<&str>,
+    metric: &str,
+) -> String {
+    template
+        .replace("{group_id}", group_id)
+        .replace("{node_id}", node_id)
+        .replace("{device_id}", device_id.unwrap_or_default())
+        .replace("{metric}", metric)
+}
+
+/// the projections of the metric events
+pub fn project(events: &[Event]) -> Vec<Projection> {
+    let config = &CONFIG.get().unwrap().service.sparkplug_b.projection;
+    if !config.enable {
+        return vec![];
+    }
+
+    events
+        .iter()
+        .filter_map(|event| {
+            let Event::Metric {
+                group_id,
+                node_id,
+                device_id,
+                name,
+                timestamp,
+                datatype,
+                value,
+            } = event
+            else {
+                return None;
+            };
+            let template = if device_id.is_some() {
+                &config.device_topic
+            } else {
+                &config.node_topic
+            };
+            let payload = serde_json::json!({
+                "timestamp": timestamp,
+                "datatype": datatype,
+                "value": value,
+            });
+            Some(Projection {
+                topic: render(template, group_id, node_id, device_id.as_deref(), name),
+                payload: Bytes::from(payload.to_string()),
+                retain: config.retain,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                "spb/json/{group_id}/{node_id}/{device_id}/{metric}",
+                "g",
+                "n",
+                Some("d"),
+                "a/temp"
+            ),
+            "spb/json/g/n/d/a/temp"
+        );
+        assert_eq!(
+            render(
+                "spb/json/{group_id}/{node_id}/{metric}",
+                "g",
+                "n",
+                None,
+                "Node Control/Rebirth"
+            ),
+            "spb/json/g/n/Node Control/Rebirth"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! rebirth requests per edge node: each one waits twice as long as the previous one, and a node
+//! still failing after `max_attempts` rebirths is escalated once instead of being asked again
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::SpbRebirthConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rebirth {
+    Send,
+    // the previous request is still within its backoff
+    Throttled,
+    // the node kept failing after this many rebirths, reported once
+    Escalate(u32),
+    GaveUp,
+}
+
+struct Attempts {
+    count: u32,
+    next: Instant,
+    last_error: Instant,
+    escalated: bool,
+}
+
+pub struct RebirthPolicy {
+    min_interval: Duration,
+    max_interval: Duration,
+    max_attempts: u32,
+    nodes: HashMap<(String, String), Attempts>,
+}
+
+impl RebirthPolicy {
+    pub fn new(config: &SpbRebirthConfig) -> Self {
+        let min_interval = Duration::from_secs(config.min_interval_secs);
+        RebirthPolicy {
+            min_interval,
+            max_interval: Duration::from_secs(config.max_interval_secs).max(min_interval),
+            max_attempts: config.max_attempts,
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub fn request(&mut self, group_id: &str, node_id: &str, now: Instant) -> Rebirth {
+        let attempts = self
+            .nodes
+            .entry((group_id.to_string(), node_id.to_string()))
+            .or_insert(Attempts {
+                count: 0,
+                next: now,
+                last_error: now,
+                escalated: false,
+            });
+
+        // a node without errors for the longest interval starts over
+        if now.duration_since(attempts.last_error) >= self.max_interval {
+            attempts.count = 0;
+            attempts.next = now;
+            attempts.escalated = false;
+        }
+        attempts.last_error = now;
+
+        if now < attempts.next {
+            return Rebirth::Throttled;
+        }
+        if self.max_attempts > 0 && attempts.count >= self.max_attempts {
+            if attempts.escalated {
+                return Rebirth::GaveUp;
+            }
+            attempts.escalated = true;
+            return Rebirth::Escalate(attempts.count);
+        }
+
+        attempts.count += 1;
+        let backoff = self
+            .min_interval
+            .saturating_mul(2u32.saturating_pow(attempts.count - 1))
+            .min(self.max_interval);
+        attempts.next = now + backoff;
+        Rebirth::Send
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let mut policy = RebirthPolicy {
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(10),
+            max_attempts: 3,
+            nodes: HashMap::new(),
+        };
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        assert_eq!(policy.request("g", "n", at(0)), Rebirth::Send);
+        assert_eq!(policy.request("g", "n", at(500)), Rebirth::Throttled);
+        // other nodes have their own backoff
+        assert_eq!(policy.request("g", "n2", at(500)), Rebirth::Send);
+        assert_eq!(policy.request("g", "n", at(1000)), Rebirth::Send);
+        assert_eq!(policy.request("g", "n", at(2500)), Rebirth::Throttled);
+        assert_eq!(policy.request("g", "n", at(3000)), Rebirth::Send);
+        assert_eq!(policy.request("g", "n", at(7000)), Rebirth::Escalate(3));
+        assert_eq!(policy.request("g", "n", at(8000)), Rebirth::GaveUp);
+
+        // quiet for the longest interval
+        assert_eq!(policy.request("g", "n", at(18000)), Rebirth::Send);
+    }
+}
@@ -0,0 +1,115 @@
+//! the seq of an edge node: NBIRTH starts it, every NDATA, DBIRTH, DDEATH and DDATA after it
+//! carries the next one, modulo 256
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Sequential,
+    // messages went missing, the ones after them are processed anyway
+    Gap,
+    // gaps reached `gap_threshold`, the node should rebirth
+    GapExceeded,
+}
+
+pub struct Sequence<T> {
+    expected: u8,
+    // messages ahead of `expected` waiting for the missing ones, at most `reorder_window`
+    pending: Vec<(u8, T)>,
+    gaps: u32,
+}
+
+impl<T> Default for Sequence<T> {
+    fn default() -> Self {
+        Sequence {
+            expected: 0,
+            pending: Vec::new(),
+            gaps: 0,
+        }
+    }
+}
+
+impl<T> Sequence<T> {
+    pub fn birth(&mut self, seq: u8) {
+        self.expected = seq.wrapping_add(1);
+        self.pending.clear();
+        self.gaps = 0;
+    }
+
+    /// the messages to process now, in seq order, and whether any went missing
+    pub fn accept(
+        &mut self,
+        seq: u8,
+        message: T,
+        reorder_window: u8,
+        gap_threshold: u32,
+    ) -> (Vec<T>, Order) {
+        let distance = seq.wrapping_sub(self.expected);
+
+        if distance == 0 {
+            self.expected = seq.wrapping_add(1);
+            let mut messages = vec![message];
+            while let Some(i) = self.pending.iter().position(|(s, _)| *s == self.expected) {
+                messages.push(self.pending.remove(i).1);
+                self.expected = self.expected.wrapping_add(1);
+            }
+            return (messages, Order::Sequential);
+        }
+
+        // behind, a duplicate or a message that arrived after its gap was given up
+        if distance >= 128 {
+            return (vec![], self.gap(gap_threshold));
+        }
+
+        if distance <= reorder_window && self.pending.len() < reorder_window as usize {
+            self.pending.push((seq, message));
+            return (vec![], Order::Sequential);
+        }
+
+        // too far ahead to wait for the missing ones
+        self.pending.push((seq, message));
+        let expected = self.expected;
+        self.pending.sort_by_key(|(s, _)| s.wrapping_sub(expected));
+        let last = self.pending.last().map(|(s, _)| *s).unwrap_or(seq);
+        self.expected = last.wrapping_add(1);
+        let messages = self.pending.drain(..).map(|(_, m)| m).collect();
+        (messages, self.gap(gap_threshold))
+    }
+
+    fn gap(&mut self, gap_threshold: u32) -> Order {
+        self.gaps += 1;
+        if self.gaps >= gap_threshold.max(1) {
+            self.gaps = 0;
+            Order::GapExceeded
+        } else {
+            Order::Gap
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let mut seq = Sequence::default();
+        seq.birth(255);
+        assert_eq!(seq.accept(0, 0, 2, 2), (vec![0], Order::Sequential));
+
+        // 2 waits for 1, then both are processed in order
+        assert_eq!(seq.accept(2, 2, 2, 2), (vec![], Order::Sequential));
+        assert_eq!(seq.accept(1, 1, 2, 2), (vec![1, 2], Order::Sequential));
+
+        // 4 and 5 wait for 3, 6 overflows the window and gives 3 up
+        assert_eq!(seq.accept(4, 4, 2, 2), (vec![], Order::Sequential));
+        assert_eq!(seq.accept(5, 5, 2, 2), (vec![], Order::Sequential));
+        assert_eq!(seq.accept(6, 6, 2, 2), (vec![4, 5, 6], Order::Gap));
+
+        // 3 is late, the second gap reaches the threshold
+        assert_eq!(seq.accept(3, 3, 2, 2), (vec![], Order::GapExceeded));
+        assert_eq!(seq.accept(7, 7, 2, 2), (vec![7], Order::Sequential));
+
+        // without a window every skipped seq is a gap
+        assert_eq!(seq.accept(9, 9, 0, 1), (vec![9], Order::GapExceeded));
+        assert_eq!(seq.accept(10, 10, 0, 1), (vec![10], Order::Sequential));
+    }
+}
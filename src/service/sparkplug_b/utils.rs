@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+
+use super::model::metric::DataMetric;
+
 pub fn ncmd_topic(group_id: &str, node_id: &str) -> String {
     format!("spBv1.0/{}/NCMD/{}", group_id, node_id)
 }
@@ -5,3 +9,16 @@ pub fn ncmd_topic(group_id: &str, node_id: &str) -> String {
 pub fn dcmd_topic(group_id: &str, node_id: &str, device_id: &str) -> String {
     format!("spBv1.0/{}/DCMD/{}/{}", group_id, node_id, device_id)
 }
+
+/// the names of the metrics of a DATA message, by name or by the alias of the birth
+pub fn metric_names(metrics: &[DataMetric], aliases: &HashMap<u64, String>) -> Vec<String> {
+    metrics
+        .iter()
+        // the historical values leave the current one as it is
+        .filter(|m| !m.is_historical)
+        .filter_map(|m| match m.alias {
+            Some(alias) => aliases.get(&alias).cloned(),
+            None => m.name.clone(),
+        })
+        .collect()
+}
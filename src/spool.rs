@@ -0,0 +1,405 @@
+//! store and forward, the `spool` of a sink or of `[service.sparkplug_b.bridge]`: what could
+//! not be handed to an unreachable remote is kept on disk, up to `max_bytes`, and replayed in
+//! order once it is back
+//!
+//! a spool is a directory of segment files holding length-prefixed records, read from the
+//! oldest and appended to the newest. The read position is kept in the `offset` file so that a
+//! restart does not replay what was forwarded already
+//!
+//! appends are synced to disk before `push` returns, the read position is not: after a power
+//! loss the last records forwarded may be forwarded again, none is lost. The file I/O blocks,
+//! async code goes through `AsyncSpool`
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use byteorder::{BigEndian, ReadBytesExt as _, WriteBytesExt as _};
+use tokio::task;
+
+use crate::config::SpoolConfig;
+
+// size a segment grows to before the next one is started
+const SEGMENT_BYTES: u64 = 4 * 1024 * 1024;
+const OFFSET_FILE: &str = "offset";
+
+#[derive(Default)]
+struct Depth {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+}
+
+// the spools open, by name, for the metrics
+static SPOOLS: LazyLock<Mutex<BTreeMap<String, Arc<Depth>>>> = LazyLock::new(Default::default);
+
+#[derive(Debug, Clone)]
+pub struct SpoolDepth {
+    pub name: String,
+    // records waiting for the remote
+    pub messages: u64,
+    pub bytes: u64,
+    // records refused because the spool was full
+    pub dropped: u64,
+}
+
+/// the depth of every spool open
+pub fn depths() -> Vec<SpoolDepth> {
+    SPOOLS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, depth)| SpoolDepth {
+            name: name.clone(),
+            messages: depth.messages.load(Ordering::Relaxed),
+            bytes: depth.bytes.load(Ordering::Relaxed),
+            dropped: depth.dropped.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// a FIFO of records on disk, see the module documentation
+pub struct Spool {
+    name: String,
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    // ids of the segment files, oldest first
+    segments: VecDeque<u64>,
+    // the oldest segment, read from read_pos
+    reader: Option<File>,
+    read_pos: u64,
+    // the newest segment, write_len long
+    writer: Option<File>,
+    write_len: u64,
+    offset: File,
+    depth: Arc<Depth>,
+}
+
+// the records of a segment from `start`, a torn record at the end is cut off
+fn scan(path: &PathBuf, start: u64) -> io::Result<(u64, u64)> {
+    let data = fs::read(path)?;
+    let mut pos = (start as usize).min(data.len());
+    let mut messages = 0;
+    while pos + 4 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        if pos + 4 + len > data.len() {
+            break;
+        }
+        pos += 4 + len;
+        messages += 1;
+    }
+    if pos < data.len() {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(pos as u64)?;
+    }
+    Ok((messages, pos as u64 - start.min(pos as u64)))
+}
+
+impl Spool {
+    /// open the spool at `config.path`, with the records left by the last run
+    pub fn open(name: &str, config: &SpoolConfig) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.path);
+        fs::create_dir_all(&dir)?;
+        let mut segments = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "seg")
+                && let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                segments.push(id);
+            }
+        }
+        segments.sort_unstable();
+
+        let mut offset = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(dir.join(OFFSET_FILE))?;
+        let (read_segment, mut read_pos) = match (
+            offset.read_u64::<BigEndian>(),
+            offset.read_u64::<BigEndian>(),
+        ) {
+            (Ok(segment), Ok(pos)) => (segment, pos),
+            _ => (0, 0),
+        };
+        // the segments forwarded already, left by a stop before they were removed
+        while segments.len() > 1 && segments[0] < read_segment {
+            fs::remove_file(dir.join(format!("{}.seg", segments.remove(0))))?;
+        }
+        if segments.first() != Some(&read_segment) {
+            read_pos = 0;
+        }
+
+        let depth = Arc::new(Depth::default());
+        let mut write_len = 0;
+        for (i, id) in segments.iter().enumerate() {
+            let path = dir.join(format!("{}.seg", id));
+            let (messages, bytes) = scan(&path, if i == 0 { read_pos } else { 0 })?;
+            depth.messages.fetch_add(messages, Ordering::Relaxed);
+            depth.bytes.fetch_add(bytes, Ordering::Relaxed);
+            write_len = fs::metadata(&path)?.len();
+            if i == 0 {
+                read_pos = read_pos.min(write_len);
+            }
+        }
+
+        let writer = match segments.last() {
+            Some(id) => Some(
+                OpenOptions::new()
+                    .write(true)
+                    .open(dir.join(format!("{}.seg", id)))?,
+            ),
+            None => None,
+        };
+        SPOOLS
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), depth.clone());
+        let mut spool = Spool {
+            name: name.to_string(),
+            dir,
+            max_bytes: config.max_bytes,
+            segment_bytes: SEGMENT_BYTES,
+            segments: segments.into(),
+            reader: None,
+            read_pos,
+            writer,
+            write_len,
+            offset,
+            depth,
+        };
+        spool.save_offset()?;
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> u64 {
+        self.depth.messages.load(Ordering::Relaxed)
+    }
+
+    fn segment(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.seg", id))
+    }
+
+    fn save_offset(&mut self) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16);
+        buf.write_u64::<BigEndian>(self.segments.front().copied().unwrap_or(0))?;
+        buf.write_u64::<BigEndian>(self.read_pos)?;
+        self.offset.seek(SeekFrom::Start(0))?;
+        self.offset.write_all(&buf)
+    }
+
+    /// append a record, false when the spool is full
+    pub fn push(&mut self, record: &[u8]) -> io::Result<bool> {
+        let size = 4 + record.len() as u64;
+        if self.depth.bytes.load(Ordering::Relaxed) + size > self.max_bytes {
+            self.depth.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
+        }
+        if self.writer.is_none()
+            || (self.write_len > 0 && self.write_len + size > self.segment_bytes)
+        {
+            let id = self.segments.back().map_or(0, |id| id + 1);
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(self.segment(id))?;
+            // the new file outlives a power loss only once the directory is synced
+            File::open(&self.dir)?.sync_all()?;
+            self.segments.push_back(id);
+            self.writer = Some(file);
+            self.write_len = 0;
+            if self.segments.len() == 1 {
+                self.save_offset()?;
+            }
+        }
+
+        let mut buf = Vec::with_capacity(size as usize);
+        buf.write_u32::<BigEndian>(record.len() as u32)?;
+        buf.write_all(record)?;
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.write_len))?;
+        writer.write_all(&buf)?;
+        writer.sync_data()?;
+        self.write_len += size;
+        self.depth.messages.fetch_add(1, Ordering::Relaxed);
+        self.depth.bytes.fetch_add(size, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// the oldest record, kept until popped
+    pub fn front(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.is_empty() {
+            let Some(&id) = self.segments.front() else {
+                break;
+            };
+            if self.reader.is_none() {
+                self.reader = Some(File::open(self.segment(id))?);
+            }
+            let reader = self.reader.as_mut().unwrap();
+            if self.read_pos >= reader.metadata()?.len() {
+                // the oldest segment is through, the next one is read from
+                fs::remove_file(self.segment(id))?;
+                self.segments.pop_front();
+                self.reader = None;
+                self.read_pos = 0;
+                self.save_offset()?;
+                continue;
+            }
+            reader.seek(SeekFrom::Start(self.read_pos))?;
+            let len = reader.read_u32::<BigEndian>()?;
+            let mut record = vec![0u8; len as usize];
+            reader.read_exact(&mut record)?;
+            return Ok(Some(record));
+        }
+        Ok(None)
+    }
+
+    /// remove the oldest record
+    pub fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(record) = self.front()? else {
+            return Ok(None);
+        };
+        let size = 4 + record.len() as u64;
+        self.read_pos += size;
+        self.depth.messages.fetch_sub(1, Ordering::Relaxed);
+        self.depth.bytes.fetch_sub(size, Ordering::Relaxed);
+        if self.is_empty()
+            && let Some(writer) = self.writer.as_mut()
+        {
+            // drained, the last segment starts over
+            writer.set_len(0)?;
+            self.write_len = 0;
+            self.read_pos = 0;
+        }
+        self.save_offset()?;
+        Ok(Some(record))
+    }
+}
+
+/// a spool shared with the blocking threads its file I/O runs on
+#[derive(Clone)]
+pub struct AsyncSpool {
+    spool: Arc<Mutex<Spool>>,
+    depth: Arc<Depth>,
+}
+
+impl AsyncSpool {
+    pub fn new(spool: Spool) -> Self {
+        AsyncSpool {
+            depth: spool.depth.clone(),
+            spool: Arc::new(Mutex::new(spool)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> u64 {
+        self.depth.messages.load(Ordering::Relaxed)
+    }
+
+    /// run `f` on the spool from a blocking thread
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Spool) -> T + Send + 'static,
+    {
+        let spool = self.spool.clone();
+        task::spawn_blocking(move || f(&mut spool.lock().unwrap()))
+            .await
+            .expect("spool task panicked")
+    }
+
+    pub async fn push(&self, record: Vec<u8>) -> io::Result<bool> {
+        self.run(move |spool| spool.push(&record)).await
+    }
+
+    pub async fn front(&self) -> io::Result<Option<Vec<u8>>> {
+        self.run(|spool| spool.front()).await
+    }
+
+    pub async fn pop(&self) -> io::Result<Option<Vec<u8>>> {
+        self.run(|spool| spool.pop()).await
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let mut spools = SPOOLS.lock().unwrap();
+        if spools
+            .get(&self.name)
+            .is_some_and(|depth| Arc::ptr_eq(depth, &self.depth))
+        {
+            spools.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spool() {
+        let dir = std::env::temp_dir().join(format!("axonmq-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SpoolConfig {
+            path: dir.to_str().unwrap().to_string(),
+            max_bytes: 64,
+        };
+
+        let mut spool = Spool::open("test", &config).unwrap();
+        spool.segment_bytes = 16;
+        for record in [b"one".as_slice(), b"two", b"three"] {
+            assert!(spool.push(record).unwrap());
+        }
+        // a segment per record past segment_bytes
+        assert_eq!(spool.segments.len(), 2);
+        assert_eq!(spool.front().unwrap().unwrap(), b"one");
+        assert_eq!(spool.pop().unwrap().unwrap(), b"one");
+        let depth = depths().into_iter().find(|d| d.name == "test").unwrap();
+        assert_eq!((depth.messages, depth.bytes), (2, 4 + 3 + 4 + 5));
+
+        // what was popped is not replayed after a restart, a torn record is cut off
+        drop(spool);
+        let mut torn = OpenOptions::new()
+            .append(true)
+            .open(dir.join("1.seg"))
+            .unwrap();
+        torn.write_all(&[0, 0, 0, 9, b'x']).unwrap();
+        let mut spool = Spool::open("test", &config).unwrap();
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.pop().unwrap().unwrap(), b"two");
+        assert_eq!(spool.pop().unwrap().unwrap(), b"three");
+        assert!(spool.pop().unwrap().is_none());
+        assert_eq!(spool.segments.len(), 1);
+
+        // full
+        assert!(spool.push(&[0; 40]).unwrap());
+        assert!(!spool.push(&[0; 40]).unwrap());
+        assert_eq!(
+            depths().iter().find(|d| d.name == "test").unwrap().dropped,
+            1
+        );
+
+        drop(spool);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,334 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::future::{BoxFuture, select_all};
+use serde::Serialize;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{error, info, warn};
+
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// a subsystem that ran this long before crashing restarts with the minimum backoff again
+const RESTART_BACKOFF_RESET: Duration = Duration::from_secs(60);
+
+type Tasks = Vec<JoinHandle<()>>;
+type StartOnce = Box<dyn FnOnce() -> BoxFuture<'static, Tasks> + Send>;
+type StartAgain = Box<dyn FnMut() -> BoxFuture<'static, Tasks> + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsystemState {
+    Pending,
+    Starting,
+    Running,
+    Restarting,
+    Failed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemInfo {
+    pub name: String,
+    pub state: SubsystemState,
+    pub depends_on: Vec<String>,
+    pub restarts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+enum Start {
+    // the actors own the broker state behind their channels, they cannot be started twice
+    Once(StartOnce),
+    Restartable(StartAgain),
+}
+
+struct Subsystem {
+    name: String,
+    depends_on: Vec<String>,
+    start: Option<Start>,
+}
+
+struct Slot {
+    info: SubsystemInfo,
+    tasks: Vec<AbortHandle>,
+    watcher: Option<AbortHandle>,
+}
+
+/// read side of the supervisor, reports the state of every subsystem
+#[derive(Clone, Default)]
+pub struct SupervisorHelper {
+    slots: Arc<RwLock<Vec<Slot>>>,
+}
+
+impl SupervisorHelper {
+    pub fn subsystems(&self) -> Vec<SubsystemInfo> {
+        self.slots
+            .read()
+            .unwrap()
+            .iter()
+            .map(|slot| slot.info.clone())
+            .collect()
+    }
+
+    /// ready once every subsystem is running
+    pub fn is_ready(&self) -> bool {
+        let slots = self.slots.read().unwrap();
+        !slots.is_empty()
+            && slots
+                .iter()
+                .all(|slot| slot.info.state == SubsystemState::Running)
+    }
+
+    fn name(&self, idx: usize) -> String {
+        self.slots.read().unwrap()[idx].info.name.clone()
+    }
+
+    fn set_state(&self, idx: usize, state: SubsystemState, error: Option<String>) {
+        let mut slots = self.slots.write().unwrap();
+        let info = &mut slots[idx].info;
+        if info.state != state {
+            info!("subsystem {}: {:?} -> {:?}", info.name, info.state, state);
+        }
+        info.state = state;
+        if error.is_some() {
+            info.last_error = error;
+        }
+    }
+
+    fn set_tasks(&self, idx: usize, tasks: &Tasks) {
+        self.slots.write().unwrap()[idx].tasks = tasks.iter().map(|t| t.abort_handle()).collect();
+    }
+
+    fn failed_dependency(&self, idx: usize) -> Option<String> {
+        let slots = self.slots.read().unwrap();
+        slots[idx].info.depends_on.iter().find_map(|dep| {
+            slots
+                .iter()
+                .find(|slot| &slot.info.name == dep)
+                .filter(|slot| slot.info.state == SubsystemState::Failed)
+                .map(|slot| slot.info.name.clone())
+        })
+    }
+}
+
+/// starts the subsystems in dependency order, restarts the restartable ones when they crash
+/// and stops them in reverse order
+#[derive(Default)]
+pub struct Supervisor {
+    subsystems: Vec<Subsystem>,
+    order: Vec<usize>,
+    helper: SupervisorHelper,
+}
+
+impl Supervisor {
+    pub fn helper(&self) -> SupervisorHelper {
+        self.helper.clone()
+    }
+
+    /// a subsystem started once, its crash leaves the node not ready
+    pub fn add_once<F>(&mut self, name: &str, depends_on: &[&str], start: F)
+    where
+        F: FnOnce() -> BoxFuture<'static, Tasks> + Send + 'static,
+    {
+        self.add(name, depends_on, Start::Once(Box::new(start)));
+    }
+
+    /// a subsystem started again with backoff whenever one of its tasks ends
+    pub fn add_restartable<F>(&mut self, name: &str, depends_on: &[&str], start: F)
+    where
+        F: FnMut() -> BoxFuture<'static, Tasks> + Send + 'static,
+    {
+        self.add(name, depends_on, Start::Restartable(Box::new(start)));
+    }
+
+    fn add(&mut self, name: &str, depends_on: &[&str], start: Start) {
+        let depends_on: Vec<String> = depends_on.iter().map(|d| d.to_string()).collect();
+        self.helper.slots.write().unwrap().push(Slot {
+            info: SubsystemInfo {
+                name: name.to_string(),
+                state: SubsystemState::Pending,
+                depends_on: depends_on.clone(),
+                restarts: 0,
+                last_error: None,
+            },
+            tasks: Vec::new(),
+            watcher: None,
+        });
+        self.subsystems.push(Subsystem {
+            name: name.to_string(),
+            depends_on,
+            start: Some(start),
+        });
+    }
+
+    /// dependencies that are not registered are ignored, they belong to disabled parts
+    fn start_order(&self) -> Result<Vec<usize>> {
+        let known = |name: &String| self.subsystems.iter().any(|s| &s.name == name);
+        let mut order: Vec<usize> = Vec::with_capacity(self.subsystems.len());
+
+        while order.len() < self.subsystems.len() {
+            let next = self.subsystems.iter().enumerate().find(|(idx, s)| {
+                !order.contains(idx)
+                    && s.depends_on.iter().filter(|d| known(d)).all(|d| {
+                        order
+                            .iter()
+                            .any(|started| &self.subsystems[*started].name == d)
+                    })
+            });
+            match next {
+                Some((idx, _)) => order.push(idx),
+                None => {
+                    let left: Vec<&str> = self
+                        .subsystems
+                        .iter()
+                        .enumerate()
+                        .filter(|(idx, _)| !order.contains(idx))
+                        .map(|(_, s)| s.name.as_str())
+                        .collect();
+                    anyhow::bail!("dependency cycle between subsystems: {}", left.join(", "));
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        self.order = self.start_order()?;
+
+        for &idx in &self.order {
+            let helper = self.helper.clone();
+            helper.set_state(idx, SubsystemState::Starting, None);
+
+            let (tasks, restart) = match self.subsystems[idx].start.take().unwrap() {
+                Start::Once(start) => (start().await, None),
+                Start::Restartable(mut start) => (start().await, Some(start)),
+            };
+            helper.set_tasks(idx, &tasks);
+            helper.set_state(idx, SubsystemState::Running, None);
+
+            let watcher = tokio::spawn(Self::watch(helper.clone(), idx, tasks, restart));
+            helper.slots.write().unwrap()[idx].watcher = Some(watcher.abort_handle());
+        }
+        Ok(())
+    }
+
+    /// stop the subsystems in reverse start order
+    pub fn stop(&self) {
+        for &idx in self.order.iter().rev() {
+            self.stop_slot(idx);
+        }
+    }
+
+    /// stop the named subsystems only, the others keep running
+    pub fn stop_only(&self, names: &[&str]) {
+        for &idx in self.order.iter().rev() {
+            if names.contains(&self.subsystems[idx].name.as_str()) {
+                self.stop_slot(idx);
+            }
+        }
+    }
+
+    fn stop_slot(&self, idx: usize) {
+        {
+            let mut slots = self.helper.slots.write().unwrap();
+            let slot = &mut slots[idx];
+            if let Some(watcher) = slot.watcher.take() {
+                watcher.abort();
+            }
+            for task in slot.tasks.drain(..) {
+                task.abort();
+            }
+        }
+        self.helper.set_state(idx, SubsystemState::Stopped, None);
+    }
+
+    async fn watch(
+        helper: SupervisorHelper,
+        idx: usize,
+        mut tasks: Tasks,
+        mut restart: Option<StartAgain>,
+    ) {
+        let name = helper.name(idx);
+        let mut backoff = RESTART_BACKOFF_MIN;
+
+        loop {
+            let started = Instant::now();
+            let reason = Self::wait_exit(tasks).await;
+
+            let Some(start) = restart.as_mut() else {
+                error!("subsystem {} {}, it cannot be restarted", name, reason);
+                helper.set_state(idx, SubsystemState::Failed, Some(reason));
+                return;
+            };
+            if let Some(dep) = helper.failed_dependency(idx) {
+                error!("subsystem {} {}, dependency {} failed", name, reason, dep);
+                helper.set_state(
+                    idx,
+                    SubsystemState::Failed,
+                    Some(format!("dependency {} failed", dep)),
+                );
+                return;
+            }
+
+            if started.elapsed() >= RESTART_BACKOFF_RESET {
+                backoff = RESTART_BACKOFF_MIN;
+            }
+            warn!("subsystem {} {}, restarting in {:?}", name, reason, backoff);
+            helper.set_state(idx, SubsystemState::Restarting, Some(reason));
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+
+            tasks = start().await;
+            helper.set_tasks(idx, &tasks);
+            helper.slots.write().unwrap()[idx].info.restarts += 1;
+            helper.set_state(idx, SubsystemState::Running, None);
+        }
+    }
+
+    /// wait for the first task to end and abort the others
+    async fn wait_exit(tasks: Tasks) -> String {
+        if tasks.is_empty() {
+            return futures::future::pending().await;
+        }
+        let (result, _, others) = select_all(tasks).await;
+        for task in others {
+            task.abort();
+        }
+        match result {
+            Ok(()) => "exited".to_string(),
+            Err(e) if e.is_panic() => "panicked".to_string(),
+            Err(e) => format!("stopped: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, FutureExt};
+
+    use super::*;
+
+    #[test]
+    fn test_start_order() {
+        let mut supervisor = Supervisor::default();
+        supervisor.add_restartable("listener", &["broker"], || future::ready(vec![]).boxed());
+        supervisor.add_restartable("restful", &["sparkplug_b", "broker"], || {
+            future::ready(vec![]).boxed()
+        });
+        supervisor.add_once("broker", &["operator"], || future::ready(vec![]).boxed());
+        supervisor.add_once("operator", &[], || future::ready(vec![]).boxed());
+
+        let order: Vec<&str> = supervisor
+            .start_order()
+            .unwrap()
+            .into_iter()
+            .map(|idx| supervisor.subsystems[idx].name.as_str())
+            .collect();
+        assert_eq!(order, vec!["operator", "broker", "listener", "restful"]);
+
+        supervisor.add_once("a", &["b"], || future::ready(vec![]).boxed());
+        supervisor.add_once("b", &["a"], || future::ready(vec![]).boxed());
+        assert!(supervisor.start_order().is_err());
+    }
+}
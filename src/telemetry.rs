@@ -0,0 +1,407 @@
+//! OTLP export of traces and metrics, `[telemetry]`
+//!
+//! every publish gets a `publish` span, the router adds a `chains` span with one `chain` span
+//! per processor chain under it, the matcher a `match` span, a publish carrying a `traceparent`
+//! user property joins the trace of its publisher
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use tracing::{Level, Span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, Registry, filter};
+
+use crate::config::{Config, OtlpProtocol, TelemetryConfig};
+use crate::features::Feature;
+use crate::mqtt::protocol::property::PropertyUser;
+use crate::mqtt::{offline_queue, slow_consumer};
+use crate::operator::chain::ChainInfo;
+use crate::operator::helper::Helper as OperatorHelper;
+use crate::spool;
+
+const DEFAULT_METRICS_INTERVAL: u64 = 60;
+
+pub type TelemetryLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// name, description and value of a counter kept in a static
+type StaticCounter = (&'static str, &'static str, fn() -> u64);
+
+/// the exporters of the process, `shutdown` sends what is left
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl Telemetry {
+    /// starts the exporters `[telemetry]` asks for and the tracing layer feeding the traces,
+    /// call it in the tokio runtime of the server, None when telemetry is not enabled
+    pub fn init(config: &Config) -> Result<Option<(Telemetry, Option<TelemetryLayer>)>> {
+        let Some(telemetry) = config.telemetry.as_ref().filter(|t| t.enable) else {
+            return Ok(None);
+        };
+        // CONFIG is not set yet, Feature::enabled cannot tell
+        if config
+            .common
+            .disabled_features
+            .iter()
+            .any(|name| name == Feature::Telemetry.name())
+        {
+            return Ok(None);
+        }
+
+        let resource = Resource::builder()
+            .with_service_name(
+                telemetry
+                    .service_name
+                    .clone()
+                    .unwrap_or_else(|| "axonmq".to_string()),
+            )
+            .with_attribute(KeyValue::new("service.instance.id", config.node.id.clone()))
+            .build();
+
+        let mut layer = None;
+        let tracer_provider = if telemetry.traces.unwrap_or(true) {
+            let exporter = match telemetry.protocol.unwrap_or_default() {
+                OtlpProtocol::Grpc => SpanExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(endpoint(telemetry, ""))
+                    .build()?,
+                OtlpProtocol::HttpProtobuf => SpanExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary)
+                    .with_endpoint(endpoint(telemetry, "/v1/traces"))
+                    .build()?,
+            };
+            let ratio = telemetry.sample_ratio.unwrap_or(1.0).clamp(0.0, 1.0);
+            let provider = SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    ratio,
+                ))))
+                .with_resource(resource.clone())
+                .build();
+            // the pipeline spans and the events in them, nothing of the dependencies
+            let filter = filter::filter_fn(|meta| {
+                meta.target().starts_with("axonmq")
+                    && if meta.is_span() {
+                        *meta.level() <= Level::DEBUG
+                    } else {
+                        *meta.level() <= Level::INFO
+                    }
+            });
+            layer = Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer("axonmq"))
+                    .with_filter(filter)
+                    .boxed(),
+            );
+            Some(provider)
+        } else {
+            None
+        };
+
+        let meter_provider = if telemetry.metrics.unwrap_or(true) {
+            let exporter = match telemetry.protocol.unwrap_or_default() {
+                OtlpProtocol::Grpc => MetricExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(endpoint(telemetry, ""))
+                    .build()?,
+                OtlpProtocol::HttpProtobuf => MetricExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary)
+                    .with_endpoint(endpoint(telemetry, "/v1/metrics"))
+                    .build()?,
+            };
+            let reader = PeriodicReader::builder(exporter)
+                .with_interval(metrics_interval(telemetry))
+                .build();
+            let provider = SdkMeterProvider::builder()
+                .with_reader(reader)
+                .with_resource(resource)
+                .build();
+            global::set_meter_provider(provider.clone());
+            Some(provider)
+        } else {
+            None
+        };
+
+        Ok(Some((
+            Telemetry {
+                tracer_provider,
+                meter_provider,
+            },
+            layer,
+        )))
+    }
+
+    pub fn shutdown(self) {
+        if let Some(provider) = self.tracer_provider
+            && let Err(e) = provider.shutdown()
+        {
+            warn!("failed to export the last traces: {}", e);
+        }
+        if let Some(provider) = self.meter_provider
+            && let Err(e) = provider.shutdown()
+        {
+            warn!("failed to export the last metrics: {}", e);
+        }
+    }
+}
+
+fn endpoint(telemetry: &TelemetryConfig, path: &str) -> String {
+    match (&telemetry.endpoint, telemetry.protocol.unwrap_or_default()) {
+        (Some(endpoint), OtlpProtocol::Grpc) => endpoint.clone(),
+        (Some(endpoint), OtlpProtocol::HttpProtobuf) => {
+            format!("{}{}", endpoint.trim_end_matches('/'), path)
+        }
+        (None, OtlpProtocol::Grpc) => "http://localhost:4317".to_string(),
+        (None, OtlpProtocol::HttpProtobuf) => format!("http://localhost:4318{}", path),
+    }
+}
+
+fn metrics_interval(telemetry: &TelemetryConfig) -> Duration {
+    Duration::from_secs(
+        telemetry
+            .metrics_interval_secs
+            .unwrap_or(DEFAULT_METRICS_INTERVAL)
+            .max(1),
+    )
+}
+
+struct UserProperties<'a>(&'a [PropertyUser]);
+
+impl Extractor for UserProperties<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|prop| prop.key.eq_ignore_ascii_case(key))
+            .map(|prop| prop.value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|prop| prop.key.as_str()).collect()
+    }
+}
+
+/// makes `span` a child of the span in the `traceparent` user property of a publish
+pub(crate) fn link_remote(span: &Span, user_properties: &[PropertyUser]) {
+    if span.is_disabled() || user_properties.is_empty() {
+        return;
+    }
+    let cx = TraceContextPropagator::new().extract(&UserProperties(user_properties));
+    if cx.span().span_context().is_valid() {
+        let _ = span.set_parent(cx);
+    }
+}
+
+/// registers the broker metrics with the global meter provider and refreshes the chain
+/// counters every `metrics_interval_secs`, the task runs until the server stops
+pub(crate) async fn export_metrics(operator_helper: OperatorHelper) {
+    let meter = global::meter("axonmq");
+    let chains: Arc<Mutex<Vec<ChainInfo>>> = Arc::default();
+
+    let counters: [StaticCounter; 6] = [
+        (
+            "axonmq.offline.messages.dropped",
+            "Messages for offline clients dropped by the store overflow policy.",
+            offline_queue::dropped_total,
+        ),
+        (
+            "axonmq.offline.messages.expired",
+            "Messages for offline clients removed when their expiry interval elapsed.",
+            offline_queue::expired_total,
+        ),
+        (
+            "axonmq.slow_consumer.events",
+            "Messages delivered to a client whose queue was full.",
+            slow_consumer::events_total,
+        ),
+        (
+            "axonmq.slow_consumer.dropped",
+            "Messages for slow clients dropped by the slow consumer policy.",
+            slow_consumer::dropped_total,
+        ),
+        (
+            "axonmq.slow_consumer.buffered",
+            "Messages for slow clients kept in their store queue.",
+            slow_consumer::buffered_total,
+        ),
+        (
+            "axonmq.slow_consumer.disconnects",
+            "Slow clients disconnected by the slow consumer policy.",
+            slow_consumer::disconnects_total,
+        ),
+    ];
+    for (name, description, value) in counters {
+        meter
+            .u64_observable_counter(name)
+            .with_description(description)
+            .with_callback(move |observer| observer.observe(value(), &[]))
+            .build();
+    }
+
+    {
+        let operator_helper = operator_helper.clone();
+        meter
+            .u64_observable_gauge("axonmq.operator.pending")
+            .with_description("Messages queued in the operator or going through processor chains.")
+            .with_callback(move |observer| observer.observe(operator_helper.pending() as u64, &[]))
+            .build();
+    }
+
+    meter
+        .u64_observable_gauge("axonmq.spool.messages")
+        .with_description("Messages kept on disk until their remote is reachable again.")
+        .with_callback(|observer| {
+            for depth in spool::depths() {
+                observer.observe(depth.messages, &[KeyValue::new("spool", depth.name)]);
+            }
+        })
+        .build();
+    meter
+        .u64_observable_counter("axonmq.spool.dropped")
+        .with_description("Messages dropped because their spool was full.")
+        .with_callback(|observer| {
+            for depth in spool::depths() {
+                observer.observe(depth.dropped, &[KeyValue::new("spool", depth.name)]);
+            }
+        })
+        .build();
+
+    {
+        let chains = chains.clone();
+        meter
+            .u64_observable_counter("axonmq.chain.messages")
+            .with_description("Messages that went through a chain version, by outcome.")
+            .with_callback(move |observer| {
+                for chain in chains.lock().unwrap().iter() {
+                    let versions = std::iter::once((&chain.stable, false))
+                        .chain(chain.canary.iter().map(|canary| (&canary.version, true)));
+                    for (version, canary) in versions {
+                        let stats = &version.stats;
+                        for (outcome, value) in [
+                            ("passed", stats.passed),
+                            ("dropped", stats.dropped),
+                            ("failed", stats.failed),
+                        ] {
+                            observer.observe(
+                                value,
+                                &[
+                                    KeyValue::new("chain", chain.name.clone()),
+                                    KeyValue::new("version", version.version as i64),
+                                    KeyValue::new("canary", canary),
+                                    KeyValue::new("outcome", outcome),
+                                ],
+                            );
+                        }
+                    }
+                }
+            })
+            .build();
+    }
+
+    {
+        let chains = chains.clone();
+        meter
+            .u64_observable_counter("axonmq.processor.calls")
+            .with_description("Processor calls in a chain version, by outcome.")
+            .with_callback(move |observer| {
+                for chain in chains.lock().unwrap().iter() {
+                    let versions = std::iter::once((&chain.stable, false))
+                        .chain(chain.canary.iter().map(|canary| (&canary.version, true)));
+                    for (version, canary) in versions {
+                        for stats in &version.processor_stats {
+                            for (outcome, value) in [
+                                ("passed", stats.passed),
+                                ("dropped", stats.dropped),
+                                ("failed", stats.failed),
+                            ] {
+                                observer.observe(
+                                    value,
+                                    &[
+                                        KeyValue::new("chain", chain.name.clone()),
+                                        KeyValue::new("version", version.version as i64),
+                                        KeyValue::new("canary", canary),
+                                        KeyValue::new("processor", stats.processor.clone()),
+                                        KeyValue::new("outcome", outcome),
+                                    ],
+                                );
+                            }
+                        }
+                    }
+                }
+            })
+            .build();
+    }
+
+    // the chains live in the router, their stats are fetched before each export
+    let interval = crate::CONFIG
+        .get()
+        .and_then(|config| config.telemetry.as_ref())
+        .map_or(
+            Duration::from_secs(DEFAULT_METRICS_INTERVAL),
+            metrics_interval,
+        );
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match operator_helper.list_chains().await {
+            Ok(list) => *chains.lock().unwrap() = list,
+            Err(e) => {
+                warn!("failed to read the chain stats for telemetry: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TraceId;
+
+    use super::*;
+
+    #[test]
+    fn test_trace_context() {
+        let props = vec![
+            PropertyUser {
+                key: "unit".to_string(),
+                value: "C".to_string(),
+            },
+            PropertyUser {
+                key: "traceparent".to_string(),
+                value: "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string(),
+            },
+        ];
+        let cx = TraceContextPropagator::new().extract(&UserProperties(&props));
+        assert_eq!(
+            cx.span().span_context().trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        let cx = TraceContextPropagator::new().extract(&UserProperties(&props[..1]));
+        assert!(!cx.span().span_context().is_valid());
+
+        let mut config: TelemetryConfig =
+            toml::from_str("endpoint = \"http://otel:4318/\"").unwrap();
+        assert_eq!(endpoint(&config, "/v1/traces"), "http://otel:4318/");
+        config.protocol = Some(OtlpProtocol::HttpProtobuf);
+        assert_eq!(
+            endpoint(&config, "/v1/traces"),
+            "http://otel:4318/v1/traces"
+        );
+        config.endpoint = None;
+        assert_eq!(
+            endpoint(&config, "/v1/metrics"),
+            "http://localhost:4318/v1/metrics"
+        );
+    }
+}